@@ -0,0 +1,8 @@
+fn main() {
+    // Only generate the gRPC service when it is enabled
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_build::compile_protos("proto/control.proto").unwrap();
+    }
+}
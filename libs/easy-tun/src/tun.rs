@@ -1,12 +1,15 @@
 use std::{
     fs::{File, OpenOptions},
     mem::size_of,
-    os::fd::AsRawFd,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Mutex,
 };
 
 use ioctl_gen::{ioc, iow};
 use libc::{
-    __c_anonymous_ifr_ifru, ifreq, ioctl, IFF_MULTI_QUEUE, IFF_NO_PI, IFF_TUN, IF_NAMESIZE,
+    __c_anonymous_ifr_ifru, ifreq, ioctl, socket, AF_INET, IFF_ATTACH_QUEUE, IFF_DETACH_QUEUE,
+    IFF_MULTI_QUEUE, IFF_NO_PI, IFF_TUN, IF_NAMESIZE, SIOCGIFMTU, SIOCSIFMTU, SOCK_CLOEXEC,
+    SOCK_DGRAM,
 };
 
 /// Architecture / target environment specific definitions
@@ -25,6 +28,8 @@ mod arch {
 pub struct Tun {
     /// All internal file descriptors
     fds: Vec<File>,
+    /// Whether each queue is currently attached to the device
+    attached: Mutex<Vec<bool>>,
     /// Device name
     name: String,
 }
@@ -100,7 +105,52 @@ impl Tun {
         log::debug!("Created TUN device: {}", name);
 
         // Build the TUN struct
-        Ok(Self { fds, name })
+        Ok(Self {
+            attached: Mutex::new(vec![true; fds.len()]),
+            fds,
+            name,
+        })
+    }
+
+    /// Wraps the queues of an existing TUN device, such as ones handed over by another process.
+    ///
+    /// Each of `fds` must already be attached to the device named `name`.
+    #[must_use]
+    pub fn from_files(name: String, fds: Vec<File>) -> Self {
+        log::debug!("Attached to TUN device: {} ({} queues)", name, fds.len());
+        Self {
+            attached: Mutex::new(vec![true; fds.len()]),
+            fds,
+            name,
+        }
+    }
+
+    /// Get the number of queues on the TUN device
+    #[must_use]
+    pub fn num_queues(&self) -> usize {
+        self.fds.len()
+    }
+
+    /// Get the number of queues currently attached to the TUN device
+    #[must_use]
+    pub fn num_attached_queues(&self) -> usize {
+        self.attached
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|attached| **attached)
+            .count()
+    }
+
+    /// Check if a queue is currently attached to the TUN device. Queues that don't exist are never attached.
+    #[must_use]
+    pub fn is_queue_attached(&self, queue_id: usize) -> bool {
+        self.attached
+            .lock()
+            .unwrap()
+            .get(queue_id)
+            .copied()
+            .unwrap_or(false)
     }
 
     /// Get the name of the TUN device
@@ -109,6 +159,83 @@ impl Tun {
         &self.name
     }
 
+    /// Get the MTU of the TUN device
+    pub fn mtu(&self) -> Result<u32, std::io::Error> {
+        let mut ifr = self.ifreq(__c_anonymous_ifr_ifru { ifru_mtu: 0 });
+        interface_ioctl(SIOCGIFMTU, &mut ifr)?;
+        Ok(u32::try_from(unsafe { ifr.ifr_ifru.ifru_mtu }).unwrap_or_default())
+    }
+
+    /// Set the MTU of the TUN device
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), std::io::Error> {
+        log::debug!("Setting MTU of {} to {}", self.name, mtu);
+        let mtu = libc::c_int::try_from(mtu)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        let mut ifr = self.ifreq(__c_anonymous_ifr_ifru { ifru_mtu: mtu });
+        interface_ioctl(SIOCSIFMTU, &mut ifr)
+    }
+
+    /// Attach or detach one of the device's queues.
+    ///
+    /// The kernel only hands packets to attached queues, so a detached queue sits idle until it is attached again.
+    /// Reading from a detached queue fails with `EBADFD`, and any packets still waiting in it are dropped. Attaching
+    /// an attached queue, or detaching a detached one, does nothing.
+    ///
+    /// All queues start out attached.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_lossless)]
+    pub fn set_queue_enabled(&self, queue_id: usize, enabled: bool) -> Result<(), std::io::Error> {
+        log::debug!(
+            "{} queue {} of {}",
+            if enabled { "Attaching" } else { "Detaching" },
+            queue_id,
+            self.name
+        );
+        let fd = self
+            .fd(queue_id)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+
+        // The kernel rejects requests that wouldn't change anything, so those are answered here
+        let mut attached = self.attached.lock().unwrap();
+        if attached[queue_id] == enabled {
+            return Ok(());
+        }
+
+        let flags = if enabled {
+            IFF_ATTACH_QUEUE
+        } else {
+            IFF_DETACH_QUEUE
+        };
+        let mut ifr = self.ifreq(__c_anonymous_ifr_ifru {
+            ifru_flags: flags as i16,
+        });
+        let err = unsafe {
+            ioctl(
+                fd.as_raw_fd(),
+                iow!('T', 217, size_of::<libc::c_int>()) as arch::IoctlRequestType,
+                &mut ifr,
+            )
+        };
+        if err < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        attached[queue_id] = enabled;
+        Ok(())
+    }
+
+    /// Build an `ifreq` struct addressed to this device
+    #[allow(clippy::cast_possible_wrap)]
+    fn ifreq(&self, ifr_ifru: __c_anonymous_ifr_ifru) -> ifreq {
+        let mut ifr_name: [libc::c_char; IF_NAMESIZE] = [0; IF_NAMESIZE];
+        for (dest, byte) in ifr_name
+            .iter_mut()
+            .zip(self.name.bytes().take(IF_NAMESIZE - 1))
+        {
+            *dest = byte as libc::c_char;
+        }
+        ifreq { ifr_name, ifr_ifru }
+    }
+
     /// Get the underlying file descriptor
     #[must_use]
     pub fn fd(&self, queue_id: usize) -> Option<&File> {
@@ -121,3 +248,19 @@ impl Tun {
         self.fds.get_mut(queue_id).map(|fd| &mut *fd)
     }
 }
+
+/// Make an interface configuration ioctl call. These go through any socket, rather than the device itself.
+fn interface_ioctl(request: libc::c_ulong, ifr: &mut ifreq) -> Result<(), std::io::Error> {
+    let fd = unsafe { socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    #[allow(clippy::cast_possible_truncation)]
+    let err = unsafe { ioctl(socket.as_raw_fd(), request as arch::IoctlRequestType, ifr) };
+    if err < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -4,17 +4,23 @@ use std::{
 };
 
 use ipnet::Ipv4Net;
-use rustc_hash::FxHashMap;
 
-use crate::{bimap::BiHashMap, error::Error, timeout::MaybeTimeout};
+use crate::{
+    error::Error,
+    event::{EventHandler, MappingEvent},
+    recent::RecentMappings,
+    table::MappingTable,
+};
 
 /// A table of network address mappings across IPv4 and IPv6
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CrossProtocolNetworkAddressTable {
     /// Internal address map
-    addr_map: BiHashMap<u32, u128>,
-    /// Secondary map used to keep track of timeouts
-    timeouts: FxHashMap<(u32, u128), MaybeTimeout>,
+    addr_map: MappingTable<u32, u128>,
+    /// Optional callback to notify of mapping changes
+    event_handler: Option<EventHandler>,
+    /// Mappings that recently expired
+    recent: RecentMappings,
 }
 
 impl CrossProtocolNetworkAddressTable {
@@ -24,76 +30,98 @@ impl CrossProtocolNetworkAddressTable {
         Self::default()
     }
 
+    /// Register a callback that will be notified whenever a mapping is created or expires
+    pub fn set_event_handler(&mut self, handler: impl FnMut(MappingEvent) + Send + 'static) {
+        self.event_handler = Some(EventHandler::new(handler));
+    }
+
     /// Prune all old mappings
     #[profiling::function]
     pub fn prune(&mut self) {
         log::trace!("Pruning old network address mappings");
-
-        // Compare all mappings against a common timestamp
-        let now = std::time::Instant::now();
-
-        // Remove all old mappings from both the bimap and the timeouts map
-        self.timeouts.retain(|(left, right), timeout| {
-            match timeout {
-                // Retain all indefinite mappings
-                MaybeTimeout::Never => true,
-                // Only retain mappings that haven't timed out yet
-                MaybeTimeout::After { duration, start } => {
-                    let should_retain = now.duration_since(*start) < *duration;
-                    if !should_retain {
-                        log::trace!(
-                            "Mapping {:?} -> {:?} has timed out and will be removed",
-                            left,
-                            right
-                        );
-                        self.addr_map.remove(left, right);
-                    }
-                    should_retain
-                }
-            }
-        });
+        let (recent, event_handler) = (&mut self.recent, &mut self.event_handler);
+        self.addr_map
+            .prune_with(|ipv4, ipv6| expired(recent, event_handler, ipv4, ipv6));
     }
 
     /// Insert a new indefinite mapping
     #[profiling::function]
     pub fn insert_indefinite(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) {
-        self.prune();
+        self.emit_created(ipv4, ipv6, true);
+        self.recent.forget(ipv4, ipv6);
         let (ipv4, ipv6) = (ipv4.into(), ipv6.into());
-        self.addr_map.insert(ipv4, ipv6);
-        self.timeouts.insert((ipv4, ipv6), MaybeTimeout::Never);
+        self.prune_conflicting(ipv4, ipv6);
+        self.addr_map.insert_indefinite(ipv4, ipv6);
     }
 
     /// Insert a new mapping with a finite time-to-live
     #[profiling::function]
     pub fn insert(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr, duration: Duration) {
-        self.prune();
+        self.emit_created(ipv4, ipv6, false);
+        self.recent.forget(ipv4, ipv6);
         let (ipv4, ipv6) = (ipv4.into(), ipv6.into());
-        self.addr_map.insert(ipv4, ipv6);
-        self.timeouts.insert(
-            (ipv4, ipv6),
-            MaybeTimeout::After {
-                duration,
-                start: std::time::Instant::now(),
-            },
-        );
+        self.prune_conflicting(ipv4, ipv6);
+        self.addr_map.insert(ipv4, ipv6, duration);
+    }
+
+    /// Prune expired mappings using either address, so that they don't linger alongside a new mapping.
+    ///
+    /// Unlike a full prune, this only looks at the two affected mappings.
+    fn prune_conflicting(&mut self, ipv4: u32, ipv6: u128) {
+        let (recent, event_handler) = (&mut self.recent, &mut self.event_handler);
+        self.addr_map.prune_conflicting(ipv4, ipv6, |ipv4, ipv6| {
+            expired(recent, event_handler, ipv4, ipv6);
+        });
+    }
+
+    /// Notify the event handler (if any) of a new mapping
+    fn emit_created(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr, indefinite: bool) {
+        if let Some(handler) = &mut self.event_handler {
+            handler.emit(MappingEvent::Created {
+                ipv4,
+                ipv6,
+                indefinite,
+            });
+        }
     }
 
     /// Get the IPv6 address for a given IPv4 address
     #[must_use]
     #[profiling::function]
     pub fn get_ipv6(&self, ipv4: &Ipv4Addr) -> Option<Ipv6Addr> {
-        self.addr_map
-            .get_right(&(*ipv4).into())
-            .map(|addr| (*addr).into())
+        self.addr_map.get_right(&(*ipv4).into()).map(Ipv6Addr::from)
     }
 
     /// Get the IPv4 address for a given IPv6 address
     #[must_use]
     #[profiling::function]
     pub fn get_ipv4(&self, ipv6: &Ipv6Addr) -> Option<Ipv4Addr> {
+        self.addr_map.get_left(&(*ipv6).into()).map(Ipv4Addr::from)
+    }
+
+    /// Remove the mapping for a given IPv4 address, returning the IPv6 address it was mapped to.
+    ///
+    /// The event handler (if any) is notified as if the mapping had expired.
+    #[profiling::function]
+    pub fn remove_ipv4(&mut self, ipv4: &Ipv4Addr) -> Option<Ipv6Addr> {
+        let ipv6 = self.addr_map.remove_left(&(*ipv4).into())?.into();
+        if let Some(handler) = &mut self.event_handler {
+            handler.emit(MappingEvent::Expired { ipv4: *ipv4, ipv6 });
+        }
+        Some(ipv6)
+    }
+
+    /// Get how long ago the mapping for a given IPv4 address was created, if it is a dynamic mapping
+    #[must_use]
+    pub fn age(&self, ipv4: &Ipv4Addr) -> Option<Duration> {
+        self.addr_map.age(&(*ipv4).into())
+    }
+
+    /// Iterate over all mappings in the table, along with the time remaining until they expire (if ever)
+    pub fn mappings(&self) -> impl Iterator<Item = (Ipv4Addr, Ipv6Addr, Option<Duration>)> + '_ {
         self.addr_map
-            .get_left(&(*ipv6).into())
-            .map(|addr| (*addr).into())
+            .mappings()
+            .map(|(ipv4, ipv6, remaining)| (ipv4.into(), ipv6.into(), remaining))
     }
 
     /// Get the number of mappings in the table
@@ -111,23 +139,50 @@ impl CrossProtocolNetworkAddressTable {
     }
 }
 
-impl Default for CrossProtocolNetworkAddressTable {
-    fn default() -> Self {
-        Self {
-            addr_map: BiHashMap::new(),
-            timeouts: FxHashMap::default(),
-        }
+/// Remember a mapping that expired, and notify the event handler (if any)
+fn expired(
+    recent: &mut RecentMappings,
+    event_handler: &mut Option<EventHandler>,
+    ipv4: u32,
+    ipv6: u128,
+) {
+    let (ipv4, ipv6) = (ipv4.into(), ipv6.into());
+    recent.remember(ipv4, ipv6);
+    if let Some(handler) = event_handler {
+        handler.emit(MappingEvent::Expired { ipv4, ipv6 });
     }
 }
 
+/// How a new dynamic mapping picks its address from the pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressSelection {
+    /// Take the first free address
+    #[default]
+    First,
+    /// Start from an address picked by hashing the IPv6 address, moving on to the next one when it is taken.
+    ///
+    /// Clients tend to get the same address every time, even after a restart or from another table with the same
+    /// pool, without any state being shared.
+    Hashed,
+}
+
 #[derive(Debug)]
 pub struct CrossProtocolNetworkAddressTableWithIpv4Pool {
     /// Internal table
     table: CrossProtocolNetworkAddressTable,
     /// Internal pool of IPv4 prefixes to assign new mappings from
     pool: Vec<Ipv4Net>,
+    /// Number of addresses in the pool, including excluded ones. Counted whenever the pool changes, rather than for
+    /// every new mapping.
+    pool_hosts: usize,
+    /// Prefixes within the pool that must never be dynamically assigned
+    excluded: Vec<Ipv4Net>,
     /// The timeout to use for new entries
     timeout: Duration,
+    /// How new dynamic mappings are given addresses
+    selection: AddressSelection,
+    /// Most mappings the table may hold before new dynamic mappings are refused
+    max_mappings: Option<usize>,
 }
 
 impl CrossProtocolNetworkAddressTableWithIpv4Pool {
@@ -137,10 +192,79 @@ impl CrossProtocolNetworkAddressTableWithIpv4Pool {
         Self {
             table: CrossProtocolNetworkAddressTable::default(),
             pool: pool.to_vec(),
+            pool_hosts: count_hosts(pool),
+            excluded: Vec::new(),
             timeout,
+            selection: AddressSelection::default(),
+            max_mappings: None,
+        }
+    }
+
+    /// Register a callback that will be notified whenever a mapping is created or expires
+    pub fn set_event_handler(&mut self, handler: impl FnMut(MappingEvent) + Send + 'static) {
+        self.table.set_event_handler(handler);
+    }
+
+    /// Prune all old mappings.
+    ///
+    /// Lookups and insertions never scan the whole table, so this should be called periodically.
+    #[profiling::function]
+    pub fn prune(&mut self) {
+        self.table.prune();
+    }
+
+    /// Prevent addresses in a prefix from being dynamically assigned.
+    ///
+    /// Static mappings may still use excluded addresses.
+    pub fn exclude(&mut self, prefix: Ipv4Net) {
+        self.excluded.push(prefix);
+    }
+
+    /// Add a prefix to the pool, making its addresses assignable immediately
+    pub fn add_pool_prefix(&mut self, prefix: Ipv4Net) {
+        if !self.pool.contains(&prefix) {
+            self.pool.push(prefix);
+            self.pool_hosts = count_hosts(&self.pool);
         }
     }
 
+    /// Remove a prefix from the pool, returning whether it was part of it.
+    ///
+    /// Existing mappings in the prefix are left alone, so the prefix should be drained first (see [`Self::exclude`]).
+    pub fn remove_pool_prefix(&mut self, prefix: Ipv4Net) -> bool {
+        let len = self.pool.len();
+        self.pool.retain(|existing| *existing != prefix);
+        self.excluded.retain(|existing| *existing != prefix);
+        self.pool_hosts = count_hosts(&self.pool);
+        self.pool.len() != len
+    }
+
+    /// Remember up to `capacity` expired mappings for `ttl`, so that returning clients are given their old address
+    pub fn remember_expired(&mut self, capacity: usize, ttl: Duration) {
+        self.table.recent = RecentMappings::new(capacity, ttl);
+    }
+
+    /// Choose how new dynamic mappings are given addresses
+    pub fn set_address_selection(&mut self, selection: AddressSelection) {
+        self.selection = selection;
+    }
+
+    /// Refuse new dynamic mappings while the table holds `max_mappings` or more (including static ones)
+    pub fn set_mapping_limit(&mut self, max_mappings: Option<usize>) {
+        self.max_mappings = max_mappings;
+    }
+
+    /// Check if the table is too full for another dynamic mapping
+    fn at_mapping_limit(&self) -> bool {
+        self.max_mappings
+            .is_some_and(|max_mappings| self.table.len() >= max_mappings)
+    }
+
+    /// Check if an address may be dynamically assigned
+    fn is_assignable(&self, ipv4: Ipv4Addr) -> bool {
+        !self.excluded.iter().any(|prefix| prefix.contains(&ipv4))
+    }
+
     /// Insert a new static mapping
     #[profiling::function]
     pub fn insert_static(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<(), Error> {
@@ -151,6 +275,88 @@ impl CrossProtocolNetworkAddressTableWithIpv4Pool {
         Ok(())
     }
 
+    /// Insert a dynamic mapping that expires after `ttl`, such as one copied from another table
+    #[profiling::function]
+    pub fn insert_with_ttl(
+        &mut self,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        if !self.pool.iter().any(|prefix| prefix.contains(&ipv4)) {
+            return Err(Error::InvalidIpv4Address(ipv4));
+        }
+        self.table.insert(ipv4, ipv6, ttl);
+        Ok(())
+    }
+
+    /// Insert a dynamic mapping to a chosen IPv4 address, using the table's timeout.
+    ///
+    /// Unlike the other insertions, this refuses to take over an address that is already mapped to someone else.
+    #[profiling::function]
+    pub fn insert_dynamic(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<(), Error> {
+        if !self.pool.iter().any(|prefix| prefix.contains(&ipv4)) {
+            return Err(Error::InvalidIpv4Address(ipv4));
+        }
+        if self
+            .table
+            .get_ipv6(&ipv4)
+            .is_some_and(|existing| existing != ipv6)
+        {
+            return Err(Error::Ipv4AddressInUse(ipv4));
+        }
+        if self.table.get_ipv4(&ipv6).is_none() && self.at_mapping_limit() {
+            return Err(Error::MappingLimitReached);
+        }
+        self.table.insert(ipv4, ipv6, self.timeout);
+        log::info!("New cross-protocol address mapping: {} -> {}", ipv6, ipv4);
+        Ok(())
+    }
+
+    /// Find the address a new dynamic mapping for `ipv6` would be given, without creating it
+    #[profiling::function]
+    pub fn next_free_ipv4(&self, ipv6: &Ipv6Addr) -> Result<Ipv4Addr, Error> {
+        if self.at_mapping_limit() {
+            return Err(Error::MappingLimitReached);
+        }
+        let is_free =
+            |addr: &Ipv4Addr| self.is_assignable(*addr) && self.table.get_ipv6(addr).is_none();
+
+        // Give returning clients their previous address if nobody else has taken it
+        if let Some(addr) =
+            self.table.recent.get_ipv4(ipv6).filter(|addr| {
+                is_free(addr) && self.pool.iter().any(|prefix| prefix.contains(addr))
+            })
+        {
+            return Ok(addr);
+        }
+
+        // Pick where to start looking. Excluded addresses are still counted, so that excluding one doesn't move everyone.
+        let hosts = || self.pool.iter().flat_map(Ipv4Net::hosts);
+        let start = match self.selection {
+            AddressSelection::First => 0,
+            AddressSelection::Hashed => match self.pool_hosts {
+                0 => 0,
+                // The remainder is smaller than `count`, so it always fits
+                count => usize::try_from(stable_hash(ipv6) % count as u64).unwrap(),
+            },
+        };
+
+        // Prefer addresses that aren't being held for someone else
+        let mut held = None;
+        for addr in hosts()
+            .skip(start)
+            .chain(hosts().take(start))
+            .filter(is_free)
+        {
+            if !self.table.recent.is_held(addr) {
+                return Ok(addr);
+            }
+            held.get_or_insert(addr);
+        }
+        held.ok_or(Error::Ipv4PoolExhausted)
+    }
+
     /// Gets the IPv4 address for a given IPv6 address or inserts a new mapping if one does not exist (if possible)
     #[profiling::function]
     pub fn get_or_create_ipv4(&mut self, ipv6: &Ipv6Addr) -> Result<Ipv4Addr, Error> {
@@ -159,13 +365,15 @@ impl CrossProtocolNetworkAddressTableWithIpv4Pool {
             return Ok(ipv4);
         }
 
-        // Find the next available IPv4 address in the pool
-        let new_address = self
-            .pool
-            .iter()
-            .flat_map(Ipv4Net::hosts)
-            .find(|addr| self.table.get_ipv6(addr).is_none())
-            .ok_or(Error::Ipv4PoolExhausted)?;
+        // Find the next available IPv4 address in the pool.
+        // Expired mappings are pruned periodically, but still count towards the mapping limit until then
+        let new_address = match self.next_free_ipv4(ipv6) {
+            Err(Error::Ipv4PoolExhausted | Error::MappingLimitReached) => {
+                self.table.prune();
+                self.next_free_ipv4(ipv6)?
+            }
+            result => result?,
+        };
 
         // Insert the new mapping
         self.table.insert(new_address, *ipv6, self.timeout);
@@ -179,10 +387,522 @@ impl CrossProtocolNetworkAddressTableWithIpv4Pool {
         Ok(new_address)
     }
 
+    /// Gets the IPv4 address for a given IPv6 address, or maps it to the first free address inside `prefix`
+    #[profiling::function]
+    pub fn get_or_create_ipv4_in(
+        &mut self,
+        ipv6: &Ipv6Addr,
+        prefix: Ipv4Net,
+    ) -> Result<Ipv4Addr, Error> {
+        if let Some(ipv4) = self.table.get_ipv4(ipv6) {
+            return Ok(ipv4);
+        }
+        if self.at_mapping_limit() {
+            return Err(Error::MappingLimitReached);
+        }
+
+        let new_address = prefix
+            .hosts()
+            .filter(|addr| self.pool.iter().any(|pool| pool.contains(addr)))
+            .find(|addr| self.is_assignable(*addr) && self.table.get_ipv6(addr).is_none())
+            .ok_or(Error::Ipv4PoolExhausted)?;
+        self.table.insert(new_address, *ipv6, self.timeout);
+        log::info!(
+            "New cross-protocol address mapping: {} -> {}",
+            ipv6,
+            new_address
+        );
+        Ok(new_address)
+    }
+
     /// Gets the IPv6 address for a given IPv4 address if it exists
     #[must_use]
     #[profiling::function]
     pub fn get_ipv6(&self, ipv4: &Ipv4Addr) -> Option<Ipv6Addr> {
         self.table.get_ipv6(ipv4)
     }
+
+    /// Gets the IPv4 address for a given IPv6 address if it exists
+    #[must_use]
+    #[profiling::function]
+    pub fn get_ipv4(&self, ipv6: &Ipv6Addr) -> Option<Ipv4Addr> {
+        self.table.get_ipv4(ipv6)
+    }
+
+    /// Remove the mapping for a given IPv4 address (static or dynamic), returning the IPv6 address it was mapped to
+    #[profiling::function]
+    pub fn remove_ipv4(&mut self, ipv4: &Ipv4Addr) -> Option<Ipv6Addr> {
+        self.table.remove_ipv4(ipv4)
+    }
+
+    /// Get how long ago the mapping for a given IPv4 address was created, if it is a dynamic mapping
+    #[must_use]
+    pub fn age(&self, ipv4: &Ipv4Addr) -> Option<Duration> {
+        self.table.age(ipv4)
+    }
+
+    /// Iterate over all mappings in the table, along with the time remaining until they expire (if ever)
+    pub fn mappings(&self) -> impl Iterator<Item = (Ipv4Addr, Ipv6Addr, Option<Duration>)> + '_ {
+        self.table.mappings()
+    }
+
+    /// Get the prefixes making up the IPv4 pool
+    #[must_use]
+    pub fn pool(&self) -> &[Ipv4Net] {
+        &self.pool
+    }
+
+    /// Get the total number of assignable addresses in the IPv4 pool
+    #[must_use]
+    pub fn pool_size(&self) -> usize {
+        self.pool
+            .iter()
+            .flat_map(Ipv4Net::hosts)
+            .filter(|addr| self.is_assignable(*addr))
+            .count()
+    }
+
+    /// Get the number of mappings in the table
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Check if the table is empty
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+}
+
+/// Hash an IPv6 address (with 64-bit FNV-1a), giving the same result on every machine and in every version
+fn stable_hash(ipv6: &Ipv6Addr) -> u64 {
+    ipv6.octets()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Count the addresses in a pool, the same way they are walked when looking for a free one
+fn count_hosts(pool: &[Ipv4Net]) -> usize {
+    pool.iter().map(|prefix| prefix.hosts().count()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_mapping_events() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::ZERO,
+        );
+        {
+            let events = Arc::clone(&events);
+            table.set_event_handler(move |event| events.lock().unwrap().push(event));
+        }
+
+        // A new mapping should be reported
+        let ipv6 = "2001:db8::1".parse().unwrap();
+        let ipv4 = table.get_or_create_ipv4(&ipv6).unwrap();
+        assert_eq!(
+            events.lock().unwrap().pop(),
+            Some(MappingEvent::Created {
+                ipv4,
+                ipv6,
+                indefinite: false
+            })
+        );
+
+        // The zero-length timeout should cause the mapping to expire on the next prune
+        table.prune();
+        assert_eq!(
+            events.lock().unwrap().pop(),
+            Some(MappingEvent::Expired { ipv4, ipv6 })
+        );
+        assert_eq!(table.get_ipv6(&ipv4), None);
+    }
+
+    #[test]
+    fn test_returning_client() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::ZERO,
+        );
+        table.remember_expired(16, Duration::from_secs(3600));
+        let returning = "2001:db8::1".parse().unwrap();
+        let other = "2001:db8::2".parse().unwrap();
+
+        // Once expired, the address is held back from new clients
+        let first = table.get_or_create_ipv4(&returning).unwrap();
+        table.prune();
+        assert_ne!(table.get_or_create_ipv4(&other).unwrap(), first);
+
+        // And given back when the client returns
+        assert_eq!(table.get_or_create_ipv4(&returning).unwrap(), first);
+    }
+
+    #[test]
+    fn test_mappings() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/30".parse().unwrap()],
+            Duration::from_secs(60),
+        );
+        assert_eq!(table.pool_size(), 2);
+
+        let static_v6 = "2001:db8::1".parse().unwrap();
+        table
+            .insert_static("192.0.2.1".parse().unwrap(), static_v6)
+            .unwrap();
+        let dynamic_v6 = "2001:db8::2".parse().unwrap();
+        let dynamic_v4 = table.get_or_create_ipv4(&dynamic_v6).unwrap();
+        assert_eq!(table.len(), 2);
+
+        let mut mappings: Vec<_> = table.mappings().collect();
+        mappings.sort_by_key(|(ipv4, ..)| *ipv4);
+        assert_eq!(mappings[0], ("192.0.2.1".parse().unwrap(), static_v6, None));
+        assert_eq!(mappings[1].0, dynamic_v4);
+        assert_eq!(mappings[1].1, dynamic_v6);
+        assert!(mappings[1].2.unwrap() <= Duration::from_secs(60));
+
+        // Only dynamic mappings have an age
+        assert_eq!(table.age(&"192.0.2.1".parse().unwrap()), None);
+        assert!(table.age(&dynamic_v4).unwrap() < Duration::from_secs(60));
+        assert_eq!(table.age(&"192.0.2.3".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_remove_mapping() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::from_secs(60),
+        );
+        let ipv6 = "2001:db8::1".parse().unwrap();
+        let ipv4 = table.get_or_create_ipv4(&ipv6).unwrap();
+        assert_eq!(table.get_ipv4(&ipv6), Some(ipv4));
+
+        // Removing the mapping should free up both addresses
+        assert_eq!(table.remove_ipv4(&ipv4), Some(ipv6));
+        assert_eq!(table.get_ipv6(&ipv4), None);
+        assert_eq!(table.get_ipv4(&ipv6), None);
+        assert!(table.is_empty());
+
+        // Removing it again is a no-op
+        assert_eq!(table.remove_ipv4(&ipv4), None);
+    }
+
+    #[test]
+    fn test_expired_mappings_not_translated() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::ZERO,
+        );
+        let ipv6 = "2001:db8::1".parse().unwrap();
+        let ipv4 = table.get_or_create_ipv4(&ipv6).unwrap();
+
+        // The mapping has expired, so neither direction finds it before the next prune
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.get_ipv4(&ipv6), None);
+        assert_eq!(table.get_ipv6(&ipv4), None);
+
+        // Static mappings never expire
+        table.insert_static(ipv4, ipv6).unwrap();
+        assert_eq!(table.get_ipv4(&ipv6), Some(ipv4));
+        assert_eq!(table.get_ipv6(&ipv4), Some(ipv6));
+    }
+
+    #[test]
+    fn test_insert_with_ttl() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::from_secs(7200),
+        );
+        let ipv6 = "2001:db8::1".parse().unwrap();
+
+        // The given TTL should be used instead of the table's timeout
+        table
+            .insert_with_ttl("192.0.2.10".parse().unwrap(), ipv6, Duration::ZERO)
+            .unwrap();
+        assert_eq!(table.get_ipv4(&ipv6), None);
+        table.prune();
+        assert!(table.is_empty());
+
+        // Addresses outside of the pool are rejected
+        assert!(table
+            .insert_with_ttl("198.51.100.1".parse().unwrap(), ipv6, Duration::ZERO)
+            .is_err());
+    }
+
+    #[test]
+    fn test_insert_dynamic() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::from_secs(60),
+        );
+        let ipv4 = "192.0.2.10".parse().unwrap();
+        let ipv6 = "2001:db8::1".parse().unwrap();
+        table.insert_dynamic(ipv4, ipv6).unwrap();
+        assert_eq!(table.get_ipv4(&ipv6), Some(ipv4));
+
+        // The same mapping may be inserted again, but the address can't be handed to anyone else
+        table.insert_dynamic(ipv4, ipv6).unwrap();
+        assert!(matches!(
+            table.insert_dynamic(ipv4, "2001:db8::2".parse().unwrap()),
+            Err(Error::Ipv4AddressInUse(_))
+        ));
+        assert_eq!(table.get_ipv6(&ipv4), Some(ipv6));
+
+        // Taken addresses are skipped when looking for a free one
+        let ipv4 = table
+            .next_free_ipv4(&"2001:db8::4".parse().unwrap())
+            .unwrap();
+        table
+            .insert_dynamic(ipv4, "2001:db8::3".parse().unwrap())
+            .unwrap();
+        assert_ne!(
+            table
+                .next_free_ipv4(&"2001:db8::4".parse().unwrap())
+                .unwrap(),
+            ipv4
+        );
+    }
+
+    #[test]
+    fn test_excluded_addresses() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/29".parse().unwrap()],
+            Duration::from_secs(60),
+        );
+        table.exclude("192.0.2.1/32".parse().unwrap());
+        table.exclude("192.0.2.4/31".parse().unwrap());
+        assert_eq!(table.pool_size(), 3);
+
+        // Only non-excluded addresses should be handed out
+        let assigned: Vec<_> = (1..=3)
+            .map(|i| {
+                table
+                    .get_or_create_ipv4(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            assigned,
+            vec![
+                Ipv4Addr::new(192, 0, 2, 2),
+                Ipv4Addr::new(192, 0, 2, 3),
+                Ipv4Addr::new(192, 0, 2, 6)
+            ]
+        );
+        assert!(matches!(
+            table.get_or_create_ipv4(&"2001:db8::4".parse().unwrap()),
+            Err(Error::Ipv4PoolExhausted)
+        ));
+
+        // Excluded addresses can still be statically mapped
+        table
+            .insert_static("192.0.2.1".parse().unwrap(), "2001:db8::5".parse().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pool_changes() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/31".parse().unwrap()],
+            Duration::from_secs(60),
+        );
+        let first = table
+            .get_or_create_ipv4(&"2001:db8::1".parse().unwrap())
+            .unwrap();
+        assert!(table
+            .get_or_create_ipv4(&"2001:db8::2".parse().unwrap())
+            .is_ok());
+        assert!(table
+            .get_or_create_ipv4(&"2001:db8::3".parse().unwrap())
+            .is_err());
+
+        // New prefixes can be assigned from straight away
+        table.add_pool_prefix("198.51.100.0/31".parse().unwrap());
+        let added = table
+            .get_or_create_ipv4(&"2001:db8::3".parse().unwrap())
+            .unwrap();
+        assert!("198.51.100.0/31"
+            .parse::<Ipv4Net>()
+            .unwrap()
+            .contains(&added));
+
+        // Draining a prefix only stops new assignments
+        let original: Ipv4Net = "192.0.2.0/31".parse().unwrap();
+        table.exclude(original);
+        assert_eq!(table.get_ipv6(&first), Some("2001:db8::1".parse().unwrap()));
+        table.remove_ipv4(&first);
+        assert!(table
+            .get_or_create_ipv4(&"2001:db8::4".parse().unwrap())
+            .is_ok_and(|address| !original.contains(&address)));
+
+        // Removed prefixes can no longer be used at all
+        assert!(table.remove_pool_prefix(original));
+        assert!(!table.remove_pool_prefix(original));
+        assert!(table
+            .insert_static(first, "2001:db8::1".parse().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_create_in_prefix() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::from_secs(60),
+        );
+        let upper: Ipv4Net = "192.0.2.128/25".parse().unwrap();
+        let first = table
+            .get_or_create_ipv4_in(&"2001:db8::1".parse().unwrap(), upper)
+            .unwrap();
+        assert_eq!(first, "192.0.2.129".parse::<Ipv4Addr>().unwrap());
+
+        // Existing mappings are kept, wherever they are
+        assert_eq!(
+            table
+                .get_or_create_ipv4_in(
+                    &"2001:db8::1".parse().unwrap(),
+                    "192.0.2.0/25".parse().unwrap()
+                )
+                .unwrap(),
+            first
+        );
+
+        // Prefixes outside of the pool have nothing to give
+        assert!(table
+            .get_or_create_ipv4_in(
+                &"2001:db8::2".parse().unwrap(),
+                "198.51.100.0/24".parse().unwrap()
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_hashed_selection() {
+        let pool: [Ipv4Net; 1] = ["192.0.2.0/24".parse().unwrap()];
+        let client: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let hashed_table = || {
+            let mut table =
+                CrossProtocolNetworkAddressTableWithIpv4Pool::new(&pool, Duration::from_secs(60));
+            table.set_address_selection(AddressSelection::Hashed);
+            table
+        };
+
+        // Separate tables agree on the address, no matter who else is mapped first
+        let mut first = hashed_table();
+        let address = first.get_or_create_ipv4(&client).unwrap();
+        let mut second = hashed_table();
+        second
+            .get_or_create_ipv4(&"2001:db8::2".parse().unwrap())
+            .unwrap();
+        assert_eq!(second.get_or_create_ipv4(&client).unwrap(), address);
+
+        // When the address is taken, the next free one is used
+        let mut third = hashed_table();
+        third
+            .insert_static(address, "2001:db8::3".parse().unwrap())
+            .unwrap();
+        let next = third.get_or_create_ipv4(&client).unwrap();
+        assert_ne!(next, address);
+        assert!(pool[0].contains(&next));
+    }
+
+    #[test]
+    fn test_hashed_selection_follows_pool_changes() {
+        let client: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let pool: Vec<Ipv4Net> = vec![
+            "192.0.2.0/24".parse().unwrap(),
+            "198.51.100.0/24".parse().unwrap(),
+        ];
+        let hashed_table = |pool: &[Ipv4Net]| {
+            let mut table =
+                CrossProtocolNetworkAddressTableWithIpv4Pool::new(pool, Duration::from_secs(60));
+            table.set_address_selection(AddressSelection::Hashed);
+            table
+        };
+
+        // A pool grown at runtime picks the same address as one configured that way from the start
+        let mut grown = hashed_table(&pool[..1]);
+        grown.add_pool_prefix(pool[1]);
+        assert_eq!(
+            grown.next_free_ipv4(&client).unwrap(),
+            hashed_table(&pool).next_free_ipv4(&client).unwrap()
+        );
+
+        // And likewise once it shrinks again
+        assert!(grown.remove_pool_prefix(pool[1]));
+        assert_eq!(
+            grown.next_free_ipv4(&client).unwrap(),
+            hashed_table(&pool[..1]).next_free_ipv4(&client).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_expired_addresses_reused() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/30".parse().unwrap()],
+            Duration::ZERO,
+        );
+        let first = table
+            .get_or_create_ipv4(&"2001:db8::1".parse().unwrap())
+            .unwrap();
+
+        // Expired mappings don't hold on to their addresses, even before they are pruned
+        let second = table
+            .get_or_create_ipv4(&"2001:db8::2".parse().unwrap())
+            .unwrap();
+        assert_eq!(second, first);
+        assert_eq!(table.get_ipv4(&"2001:db8::1".parse().unwrap()), None);
+        assert_eq!(table.len(), 1);
+
+        // Reusing the address of an expired mapping replaces it
+        let ipv6 = "2001:db8::4".parse().unwrap();
+        table
+            .insert_with_ttl(second, ipv6, Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(table.get_ipv6(&second), Some(ipv6));
+        assert_eq!(table.get_ipv4(&"2001:db8::2".parse().unwrap()), None);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_mapping_limit() {
+        let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::from_secs(7200),
+        );
+        table.set_mapping_limit(Some(2));
+        let first = "2001:db8::1".parse().unwrap();
+        table.get_or_create_ipv4(&first).unwrap();
+        table
+            .get_or_create_ipv4(&"2001:db8::2".parse().unwrap())
+            .unwrap();
+
+        // A third client is turned away, but existing ones keep their mappings
+        assert!(matches!(
+            table.get_or_create_ipv4(&"2001:db8::3".parse().unwrap()),
+            Err(Error::MappingLimitReached)
+        ));
+        assert!(matches!(
+            table.insert_dynamic(
+                "192.0.2.100".parse().unwrap(),
+                "2001:db8::3".parse().unwrap()
+            ),
+            Err(Error::MappingLimitReached)
+        ));
+        assert!(table.get_or_create_ipv4(&first).is_ok());
+
+        // Room is made when a mapping goes away
+        let ipv4 = table.get_ipv4(&first).unwrap();
+        table.remove_ipv4(&ipv4);
+        assert!(table
+            .get_or_create_ipv4(&"2001:db8::3".parse().unwrap())
+            .is_ok());
+    }
 }
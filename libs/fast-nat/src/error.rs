@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -6,4 +6,18 @@ pub enum Error {
     InvalidIpv4Address(Ipv4Addr),
     #[error("IPv4 pool exhausted")]
     Ipv4PoolExhausted,
+    #[error("Ipv4 address is already mapped to another IPv6 address: {0}")]
+    Ipv4AddressInUse(Ipv4Addr),
+    #[error("Not enough port blocks for every subscriber: {needed} needed, {available} available")]
+    NotEnoughPortBlocks { needed: u128, available: u128 },
+    #[error("Port blocks must hold at least one port, and fit at least once above the first port")]
+    EmptyPortBlocks,
+    #[error("IPv6 address does not belong to a subscriber: {0}")]
+    UnknownSubscriber(Ipv6Addr),
+    #[error("No free ports left for: {0}")]
+    PortsExhausted(Ipv6Addr),
+    #[error("Mapping limit reached")]
+    MappingLimitReached,
+    #[error("Session limit reached for: {0}")]
+    SessionLimitReached(Ipv6Addr),
 }
@@ -0,0 +1,57 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Describes a change to the set of mappings held by a cross-protocol table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingEvent {
+    /// A new mapping was inserted into the table
+    Created {
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        /// Indicates that this mapping will never time out
+        indefinite: bool,
+    },
+    /// A mapping timed out and was removed from the table
+    Expired { ipv4: Ipv4Addr, ipv6: Ipv6Addr },
+}
+
+/// Describes a change to the set of sessions held by a session table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// A host's port was given a port of a shared address
+    Created {
+        ipv6: Ipv6Addr,
+        protocol: u8,
+        port: u16,
+        ipv4: Ipv4Addr,
+        translated_port: u16,
+    },
+    /// A session went idle, or was replaced, and was removed from the table
+    Expired {
+        ipv6: Ipv6Addr,
+        protocol: u8,
+        port: u16,
+        ipv4: Ipv4Addr,
+        translated_port: u16,
+    },
+}
+
+/// A callback that is notified of every event of a table
+pub(crate) struct EventHandler<E = MappingEvent>(Box<dyn FnMut(E) + Send>);
+
+impl<E> EventHandler<E> {
+    /// Wrap a callback function
+    pub fn new(handler: impl FnMut(E) + Send + 'static) -> Self {
+        Self(Box::new(handler))
+    }
+
+    /// Pass an event to the callback
+    pub fn emit(&mut self, event: E) {
+        (self.0)(event);
+    }
+}
+
+impl<E> std::fmt::Debug for EventHandler<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EventHandler")
+    }
+}
@@ -7,8 +7,20 @@
 mod bimap;
 mod cpnat;
 pub mod error;
+mod event;
 mod nat;
+mod port_blocks;
+mod recent;
+mod sessions;
+mod table;
 mod timeout;
 
-pub use cpnat::{CrossProtocolNetworkAddressTable, CrossProtocolNetworkAddressTableWithIpv4Pool};
+pub use cpnat::{
+    AddressSelection, CrossProtocolNetworkAddressTable,
+    CrossProtocolNetworkAddressTableWithIpv4Pool,
+};
+pub use event::{MappingEvent, SessionEvent};
 pub use nat::NetworkAddressTable;
+pub use port_blocks::{PortBlockLayout, PortBlockTable};
+pub use sessions::{SessionLimit, SessionTable, SessionTimeouts};
+pub use table::{MappingKey, MappingTable};
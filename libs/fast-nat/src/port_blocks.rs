@@ -0,0 +1,335 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    ops::RangeInclusive,
+};
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    error::Error,
+    event::SessionEvent,
+    sessions::{SessionTable, SessionTimeouts},
+};
+
+/// Shape of the port blocks handed out to subscribers
+#[derive(Debug, Clone, Copy)]
+pub struct PortBlockLayout {
+    /// Every IPv6 address inside a prefix of this length is the same subscriber
+    pub subscriber_prefix_len: u8,
+    /// Lowest port handed out (ports below it are left to the system)
+    pub first_port: u16,
+    /// Number of ports in each block
+    pub block_size: u16,
+}
+
+/// Deterministic NAT ([RFC7422](https://datatracker.ietf.org/doc/html/rfc7422)) with per-subscriber port blocks.
+///
+/// Subscribers are numbered in order through each of the subscriber prefixes, and subscriber `n` is given block
+/// `n % blocks_per_address` of pool address `n / blocks_per_address`. Because this is fixed, the subscriber behind any
+/// IPv4 address and port can be worked out from the configuration alone, without logging every session.
+///
+/// Within its block, each of a subscriber's sessions is given a port of its own (see [`SessionTable`]).
+#[derive(Debug)]
+pub struct PortBlockTable {
+    subscribers: Vec<Ipv6Net>,
+    layout: PortBlockLayout,
+    /// Pool addresses, in the order blocks are handed out from them
+    addresses: Vec<Ipv4Addr>,
+    /// Position of each pool address in `addresses`
+    address_index: FxHashMap<Ipv4Addr, usize>,
+    blocks_per_address: u32,
+    sessions: SessionTable,
+}
+
+impl PortBlockTable {
+    /// Construct a new table. Fails if the blocks are empty, or the pool doesn't have a block for every subscriber.
+    pub fn new(
+        subscribers: &[Ipv6Net],
+        pool: &[Ipv4Net],
+        excluded: &[Ipv4Net],
+        layout: PortBlockLayout,
+        timeouts: SessionTimeouts,
+    ) -> Result<Self, Error> {
+        let addresses: Vec<_> = pool
+            .iter()
+            .flat_map(Ipv4Net::hosts)
+            .filter(|address| !excluded.iter().any(|prefix| prefix.contains(address)))
+            .collect();
+        let blocks_per_address = match layout.block_size {
+            0 => 0,
+            block_size => (0x10000 - u32::from(layout.first_port)) / u32::from(block_size),
+        };
+        if blocks_per_address == 0 {
+            return Err(Error::EmptyPortBlocks);
+        }
+
+        // Make sure every subscriber can be given a block
+        let needed = subscribers
+            .iter()
+            .map(|prefix| subscriber_count(*prefix, layout.subscriber_prefix_len))
+            .fold(0u128, u128::saturating_add);
+        let available = addresses.len() as u128 * u128::from(blocks_per_address);
+        if needed > available {
+            return Err(Error::NotEnoughPortBlocks { needed, available });
+        }
+
+        Ok(Self {
+            subscribers: subscribers.to_vec(),
+            layout,
+            address_index: addresses
+                .iter()
+                .enumerate()
+                .map(|(index, address)| (*address, index))
+                .collect(),
+            addresses,
+            blocks_per_address,
+            sessions: SessionTable::new(timeouts),
+        })
+    }
+
+    /// Get the pool address and port block of the subscriber an IPv6 address belongs to
+    #[must_use]
+    pub fn port_block(&self, ipv6: Ipv6Addr) -> Option<(Ipv4Addr, RangeInclusive<u16>)> {
+        let index = self.subscriber_index(ipv6)?;
+        let address = *self
+            .addresses
+            .get(usize::try_from(index / u128::from(self.blocks_per_address)).ok()?)?;
+        #[allow(clippy::cast_possible_truncation)]
+        let block = (index % u128::from(self.blocks_per_address)) as u32;
+        let start = u32::from(self.layout.first_port) + block * u32::from(self.layout.block_size);
+        let end = start + u32::from(self.layout.block_size) - 1;
+        Some((
+            address,
+            u16::try_from(start).ok()?..=u16::try_from(end).ok()?,
+        ))
+    }
+
+    /// Find the subscriber that a pool address and port were given to
+    #[must_use]
+    pub fn subscriber(&self, ipv4: Ipv4Addr, port: u16) -> Option<Ipv6Net> {
+        let address_index = *self.address_index.get(&ipv4)?;
+        let block = u32::from(port.checked_sub(self.layout.first_port)?)
+            / u32::from(self.layout.block_size);
+        if block >= self.blocks_per_address {
+            return None;
+        }
+        let mut index =
+            address_index as u128 * u128::from(self.blocks_per_address) + u128::from(block);
+
+        // Walk through the subscriber prefixes until the index falls inside one
+        for prefix in &self.subscribers {
+            let count = subscriber_count(*prefix, self.layout.subscriber_prefix_len);
+            if index < count {
+                let host_bits = 128 - u32::from(self.layout.subscriber_prefix_len);
+                let offset = index.checked_shl(host_bits).unwrap_or(0);
+                return Ipv6Net::new(
+                    Ipv6Addr::from(u128::from(prefix.network()) + offset),
+                    self.layout.subscriber_prefix_len,
+                )
+                .ok();
+            }
+            index -= count;
+        }
+        None
+    }
+
+    /// Register a callback that will be notified whenever a session is created or removed
+    pub fn set_event_handler(&mut self, handler: impl FnMut(SessionEvent) + Send + 'static) {
+        self.sessions.set_event_handler(handler);
+    }
+
+    /// Translate an outgoing packet's source, creating a session for it if needed.
+    ///
+    /// Returns the pool address and port to use.
+    #[profiling::function]
+    pub fn translate_outbound(
+        &mut self,
+        ipv6: Ipv6Addr,
+        protocol: u8,
+        port: u16,
+    ) -> Result<(Ipv4Addr, u16), Error> {
+        let (address, block) = self
+            .port_block(ipv6)
+            .ok_or(Error::UnknownSubscriber(ipv6))?;
+
+        let port = self
+            .sessions
+            .translate_outbound(ipv6, protocol, port, address, &block)?;
+        Ok((address, port))
+    }
+
+    /// Translate an incoming packet's destination back to the subscriber's address and port
+    pub fn translate_inbound(
+        &mut self,
+        ipv4: Ipv4Addr,
+        protocol: u8,
+        port: u16,
+    ) -> Option<(Ipv6Addr, u16)> {
+        self.sessions.translate_inbound(ipv4, protocol, port)
+    }
+
+    /// Remove all idle sessions
+    pub fn prune(&mut self) {
+        self.sessions.prune();
+    }
+
+    /// Get the number of active sessions
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Check if there are no sessions
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Number the subscriber an IPv6 address belongs to
+    fn subscriber_index(&self, ipv6: Ipv6Addr) -> Option<u128> {
+        let mut base = 0u128;
+        for prefix in &self.subscribers {
+            if prefix.contains(&ipv6) {
+                let host_bits = 128 - u32::from(self.layout.subscriber_prefix_len);
+                let offset = u128::from(ipv6) - u128::from(prefix.network());
+                return Some(base + offset.checked_shr(host_bits).unwrap_or(0));
+            }
+            base =
+                base.saturating_add(subscriber_count(*prefix, self.layout.subscriber_prefix_len));
+        }
+        None
+    }
+}
+
+/// Count the subscribers in a subscriber prefix
+fn subscriber_count(prefix: Ipv6Net, subscriber_prefix_len: u8) -> u128 {
+    1u128
+        .checked_shl(u32::from(
+            subscriber_prefix_len.saturating_sub(prefix.prefix_len()),
+        ))
+        .unwrap_or(u128::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn table() -> PortBlockTable {
+        PortBlockTable::new(
+            &["2001:db8::/48".parse().unwrap()],
+            &["192.0.2.0/28".parse().unwrap()],
+            &[],
+            PortBlockLayout {
+                subscriber_prefix_len: 56,
+                first_port: 1024,
+                block_size: 2048,
+            },
+            SessionTimeouts {
+                tcp: Duration::from_secs(7440),
+                other: Duration::from_secs(300),
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_port_blocks() {
+        let table = table();
+
+        // 31 blocks fit in each address, so the 256 /56s need 9 addresses
+        assert!(matches!(
+            PortBlockTable::new(
+                &["2001:db8::/48".parse().unwrap()],
+                &["192.0.2.0/29".parse().unwrap()],
+                &[],
+                table.layout,
+                SessionTimeouts {
+                    tcp: Duration::from_secs(7440),
+                    other: Duration::from_secs(300),
+                },
+            ),
+            Err(Error::NotEnoughPortBlocks {
+                needed: 256,
+                available: 186
+            })
+        ));
+
+        // Blocks are handed out in order, and can be traced back to their subscriber
+        assert_eq!(
+            table.port_block("2001:db8::1".parse().unwrap()),
+            Some(("192.0.2.1".parse().unwrap(), 1024..=3071))
+        );
+        assert_eq!(
+            table.port_block("2001:db8:0:100::1".parse().unwrap()),
+            Some(("192.0.2.1".parse().unwrap(), 3072..=5119))
+        );
+        assert_eq!(
+            table.port_block("2001:db8:0:1f00::1".parse().unwrap()),
+            Some(("192.0.2.2".parse().unwrap(), 1024..=3071))
+        );
+        assert_eq!(
+            table.subscriber("192.0.2.2".parse().unwrap(), 2000),
+            Some("2001:db8:0:1f00::/56".parse().unwrap())
+        );
+        assert_eq!(table.port_block("2001:db9::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_empty_blocks() {
+        let new = |subscribers: &[Ipv6Net], block_size| {
+            PortBlockTable::new(
+                subscribers,
+                &["192.0.2.0/24".parse().unwrap()],
+                &[],
+                PortBlockLayout {
+                    subscriber_prefix_len: 56,
+                    first_port: 1024,
+                    block_size,
+                },
+                SessionTimeouts {
+                    tcp: Duration::from_secs(7440),
+                    other: Duration::from_secs(300),
+                },
+            )
+        };
+        let subscribers = ["2001:db8::/48".parse().unwrap()];
+
+        // Neither empty blocks nor ones too big to fit above the first port are accepted, even without subscribers
+        assert!(matches!(new(&subscribers, 0), Err(Error::EmptyPortBlocks)));
+        assert!(matches!(new(&[], 0), Err(Error::EmptyPortBlocks)));
+        assert!(matches!(
+            new(&subscribers, 65000),
+            Err(Error::EmptyPortBlocks)
+        ));
+        assert!(new(&subscribers, 32256).is_ok());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut table = table();
+        let device_a = "2001:db8::a".parse().unwrap();
+        let device_b = "2001:db8::b".parse().unwrap();
+        let pool_address = "192.0.2.1".parse().unwrap();
+
+        // Two devices of the same subscriber using the same port get different ports in the same block
+        let (address_a, port_a) = table.translate_outbound(device_a, 17, 5000).unwrap();
+        let (address_b, port_b) = table.translate_outbound(device_b, 17, 5000).unwrap();
+        assert_eq!((address_a, address_b), (pool_address, pool_address));
+        assert_ne!(port_a, port_b);
+        assert!((1024..=3071).contains(&port_a) && (1024..=3071).contains(&port_b));
+
+        // Sessions are reused, and replies find their way back
+        assert_eq!(
+            table.translate_outbound(device_a, 17, 5000).unwrap(),
+            (pool_address, port_a)
+        );
+        assert_eq!(
+            table.translate_inbound(pool_address, 17, port_b),
+            Some((device_b, 5000))
+        );
+        assert_eq!(table.translate_inbound(pool_address, 6, port_b), None);
+        assert_eq!(table.len(), 2);
+    }
+}
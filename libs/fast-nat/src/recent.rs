@@ -0,0 +1,105 @@
+use std::{
+    collections::VecDeque,
+    net::{Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use rustc_hash::FxHashMap;
+
+/// An aging cache of mappings that recently expired, so that returning clients can be given their old address
+#[derive(Debug)]
+pub struct RecentMappings {
+    capacity: usize,
+    ttl: Duration,
+    by_ipv6: FxHashMap<Ipv6Addr, (Ipv4Addr, Instant)>,
+    by_ipv4: FxHashMap<Ipv4Addr, Ipv6Addr>,
+    /// Order entries were remembered in, oldest first. May contain entries that have since been forgotten.
+    order: VecDeque<(Ipv6Addr, Instant)>,
+}
+
+impl RecentMappings {
+    /// Construct a cache holding up to `capacity` mappings for `ttl` each. A capacity of zero disables the cache.
+    #[must_use]
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            by_ipv6: FxHashMap::default(),
+            by_ipv4: FxHashMap::default(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remember a mapping that just expired, evicting the oldest one if the cache is full
+    pub fn remember(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.forget(ipv4, ipv6);
+        let now = Instant::now();
+        self.by_ipv6.insert(ipv6, (ipv4, now));
+        self.by_ipv4.insert(ipv4, ipv6);
+        self.order.push_back((ipv6, now));
+
+        // Drop the oldest entries, along with any that were forgotten or have aged out
+        while let Some(&(ipv6, remembered)) = self.order.front() {
+            let live = self
+                .by_ipv6
+                .get(&ipv6)
+                .is_some_and(|(_, since)| *since == remembered);
+            if live && self.by_ipv6.len() <= self.capacity && remembered.elapsed() < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            if live {
+                if let Some((ipv4, _)) = self.by_ipv6.remove(&ipv6) {
+                    self.by_ipv4.remove(&ipv4);
+                }
+            }
+        }
+
+        // Forgotten entries in the middle are only dropped when they reach the front, so compact now and then
+        if self.order.len() > self.capacity * 2 {
+            let by_ipv6 = &self.by_ipv6;
+            self.order.retain(|(ipv6, remembered)| {
+                by_ipv6
+                    .get(ipv6)
+                    .is_some_and(|(_, since)| since == remembered)
+            });
+        }
+    }
+
+    /// Forget anything remembered about either address, such as when one of them is mapped again
+    pub fn forget(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) {
+        if let Some((old_ipv4, _)) = self.by_ipv6.remove(&ipv6) {
+            self.by_ipv4.remove(&old_ipv4);
+        }
+        if let Some(old_ipv6) = self.by_ipv4.remove(&ipv4) {
+            self.by_ipv6.remove(&old_ipv6);
+        }
+    }
+
+    /// Get the address an IPv6 address was recently mapped to
+    #[must_use]
+    pub fn get_ipv4(&self, ipv6: &Ipv6Addr) -> Option<Ipv4Addr> {
+        self.by_ipv6
+            .get(ipv6)
+            .filter(|(_, since)| since.elapsed() < self.ttl)
+            .map(|(ipv4, _)| *ipv4)
+    }
+
+    /// Check if an IPv4 address is being held for the client that recently had it
+    #[must_use]
+    pub fn is_held(&self, ipv4: Ipv4Addr) -> bool {
+        self.by_ipv4
+            .get(&ipv4)
+            .and_then(|ipv6| self.by_ipv6.get(ipv6))
+            .is_some_and(|(_, since)| since.elapsed() < self.ttl)
+    }
+}
+
+impl Default for RecentMappings {
+    fn default() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+}
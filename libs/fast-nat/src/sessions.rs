@@ -0,0 +1,386 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+use ipnet::Ipv6Net;
+
+use crate::{
+    error::Error,
+    event::{EventHandler, SessionEvent},
+    table::MappingTable,
+};
+
+/// TCP's protocol number, which gets its own session timeout
+const PROTOCOL_TCP: u8 = 6;
+
+/// How long idle sessions are kept
+#[derive(Debug, Clone, Copy)]
+pub struct SessionTimeouts {
+    pub tcp: Duration,
+    /// UDP and ICMP
+    pub other: Duration,
+}
+
+/// Caps the number of sessions open at once by hosts in a set of IPv6 prefixes
+#[derive(Debug, Clone)]
+pub struct SessionLimit {
+    pub sources: Vec<Ipv6Net>,
+    pub max_sessions: usize,
+}
+
+/// A host's address, protocol, and port
+type Inside = (Ipv6Addr, u8, u16);
+
+/// A shared address, protocol, and translated port
+type Outside = (Ipv4Addr, u8, u16);
+
+/// Port translation for IPv6 hosts that share an IPv4 address.
+///
+/// Each session (protocol and port of a host) is given a port of its own from a range of the shared address. The
+/// original port is kept (modulo the size of the range) when possible.
+#[derive(Debug)]
+pub struct SessionTable {
+    timeouts: SessionTimeouts,
+    /// Sessions, which time out once they have been idle for too long
+    sessions: MappingTable<Inside, Outside>,
+    limits: Vec<SessionLimit>,
+    /// Number of sessions counting against each limit
+    limit_usage: Vec<usize>,
+    /// Callback notified whenever a session is created or removed
+    events: Option<EventHandler<SessionEvent>>,
+}
+
+impl SessionTable {
+    /// Construct a new empty session table
+    #[must_use]
+    pub fn new(timeouts: SessionTimeouts) -> Self {
+        Self {
+            timeouts,
+            sessions: MappingTable::new(),
+            limits: Vec::new(),
+            limit_usage: Vec::new(),
+            events: None,
+        }
+    }
+
+    /// Register a callback that will be notified whenever a session is created or removed
+    pub fn set_event_handler(&mut self, handler: impl FnMut(SessionEvent) + Send + 'static) {
+        self.events = Some(EventHandler::new(handler));
+    }
+
+    /// Limit the number of sessions hosts may have open at once. A host counts against the first limit covering it.
+    pub fn set_limits(&mut self, limits: Vec<SessionLimit>) {
+        self.prune();
+        self.limit_usage = vec![0; limits.len()];
+        for (inside, _, _) in self.sessions.mappings() {
+            if let Some(limit) = limit_of(&limits, inside.0) {
+                self.limit_usage[limit] += 1;
+            }
+        }
+        self.limits = limits;
+    }
+
+    /// Translate an outgoing packet's source port, creating a session for it if needed.
+    ///
+    /// New sessions are given a free port from `ports` of `address`.
+    #[profiling::function]
+    pub fn translate_outbound(
+        &mut self,
+        ipv6: Ipv6Addr,
+        protocol: u8,
+        port: u16,
+        address: Ipv4Addr,
+        ports: &RangeInclusive<u16>,
+    ) -> Result<u16, Error> {
+        // Keep using an existing session, unless the host has since moved to another address
+        let inside = (ipv6, protocol, port);
+        if let Some(outside) = self.sessions.get_right(&inside) {
+            if outside.0 == address {
+                self.sessions.refresh(&inside);
+                return Ok(outside.2);
+            }
+            self.sessions.remove_left(&inside);
+            self.release(ipv6);
+            emit_expired(&mut self.events, inside, outside);
+        }
+
+        // Find a free port, starting at the one matching the original port
+        let start = u32::from(*ports.start());
+        let size = u32::from(*ports.end()) + 1 - start;
+        let preferred = if ports.contains(&port) {
+            u32::from(port) - start
+        } else {
+            u32::from(port) % size
+        };
+        #[allow(clippy::cast_possible_truncation)]
+        let translated_port = (0..size)
+            .map(|i| (start + (preferred + i) % size) as u16)
+            .find(|candidate| {
+                self.sessions
+                    .get_left(&(address, protocol, *candidate))
+                    .is_none()
+            })
+            .ok_or(Error::PortsExhausted(ipv6))?;
+
+        // Clear out any idle session still holding either end
+        let outside = (address, protocol, translated_port);
+        let (limits, limit_usage, events) = (&self.limits, &mut self.limit_usage, &mut self.events);
+        self.sessions
+            .prune_conflicting(inside, outside, |inside, outside| {
+                release(limits, limit_usage, inside.0);
+                emit_expired(events, inside, outside);
+            });
+
+        // Hosts at their limit can't open new sessions until old ones are pruned
+        let limit = limit_of(&self.limits, ipv6);
+        if let Some(limit) = limit {
+            if self.limit_usage[limit] >= self.limits[limit].max_sessions {
+                return Err(Error::SessionLimitReached(ipv6));
+            }
+            self.limit_usage[limit] += 1;
+        }
+
+        // Record the new session
+        let timeout = match protocol {
+            PROTOCOL_TCP => self.timeouts.tcp,
+            _ => self.timeouts.other,
+        };
+        self.sessions.insert(inside, outside, timeout);
+        if let Some(events) = &mut self.events {
+            events.emit(SessionEvent::Created {
+                ipv6,
+                protocol,
+                port,
+                ipv4: address,
+                translated_port,
+            });
+        }
+        Ok(translated_port)
+    }
+
+    /// Translate an incoming packet's destination back to the host's address and port
+    #[profiling::function]
+    pub fn translate_inbound(
+        &mut self,
+        ipv4: Ipv4Addr,
+        protocol: u8,
+        port: u16,
+    ) -> Option<(Ipv6Addr, u16)> {
+        let inside = self.sessions.get_left(&(ipv4, protocol, port))?;
+        self.sessions.refresh(&inside);
+        Some((inside.0, inside.2))
+    }
+
+    /// Remove all idle sessions
+    #[profiling::function]
+    pub fn prune(&mut self) {
+        let (limits, limit_usage, events) = (&self.limits, &mut self.limit_usage, &mut self.events);
+        self.sessions.prune_with(|inside, outside| {
+            release(limits, limit_usage, inside.0);
+            emit_expired(events, inside, outside);
+        });
+    }
+
+    /// Get the number of active sessions
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Check if there are no sessions
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Stop counting a removed session of a host against its limit
+    fn release(&mut self, ipv6: Ipv6Addr) {
+        release(&self.limits, &mut self.limit_usage, ipv6);
+    }
+}
+
+/// Tell the event handler, if any, that a session was removed
+fn emit_expired(events: &mut Option<EventHandler<SessionEvent>>, inside: Inside, outside: Outside) {
+    if let Some(events) = events {
+        events.emit(SessionEvent::Expired {
+            ipv6: inside.0,
+            protocol: inside.1,
+            port: inside.2,
+            ipv4: outside.0,
+            translated_port: outside.2,
+        });
+    }
+}
+
+/// Find the limit a host's sessions count against
+fn limit_of(limits: &[SessionLimit], ipv6: Ipv6Addr) -> Option<usize> {
+    limits
+        .iter()
+        .position(|limit| limit.sources.iter().any(|net| net.contains(&ipv6)))
+}
+
+/// Stop counting a removed session of a host against its limit
+fn release(limits: &[SessionLimit], limit_usage: &mut [usize], ipv6: Ipv6Addr) {
+    if let Some(limit) = limit_of(limits, ipv6) {
+        // Sessions that timed out just as the limits were set were never counted
+        limit_usage[limit] = limit_usage[limit].saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_shared_address() {
+        let mut table = SessionTable::new(SessionTimeouts {
+            tcp: Duration::from_secs(7440),
+            other: Duration::from_secs(300),
+        });
+        let laptop = "2001:db8::a".parse().unwrap();
+        let phone = "2001:db8::b".parse().unwrap();
+        let address = "192.0.2.1".parse().unwrap();
+
+        // The first device keeps its port, and the second is moved to the next free one
+        assert_eq!(
+            table
+                .translate_outbound(laptop, 6, 5000, address, &(1024..=u16::MAX))
+                .ok(),
+            Some(5000)
+        );
+        assert_eq!(
+            table
+                .translate_outbound(phone, 6, 5000, address, &(1024..=u16::MAX))
+                .ok(),
+            Some(5001)
+        );
+        assert_eq!(
+            table.translate_inbound(address, 6, 5001),
+            Some((phone, 5000))
+        );
+
+        // Moving a device to another address replaces its sessions
+        let other_address = "192.0.2.2".parse().unwrap();
+        assert_eq!(
+            table
+                .translate_outbound(laptop, 6, 5000, other_address, &(1024..=u16::MAX))
+                .ok(),
+            Some(5000)
+        );
+        assert_eq!(table.translate_inbound(address, 6, 5000), None);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn test_session_limit() {
+        let mut table = SessionTable::new(SessionTimeouts {
+            tcp: Duration::from_secs(7440),
+            other: Duration::from_secs(300),
+        });
+        table.set_limits(vec![SessionLimit {
+            sources: vec!["2001:db8:1::/48".parse().unwrap()],
+            max_sessions: 2,
+        }]);
+        let limited = "2001:db8:1::a".parse().unwrap();
+        let unlimited = "2001:db8:2::a".parse().unwrap();
+        let address = "192.0.2.1".parse().unwrap();
+
+        // Only the limited host is turned away once it has enough sessions
+        for port in [5000, 5001] {
+            assert!(table
+                .translate_outbound(limited, 17, port, address, &(1024..=u16::MAX))
+                .is_ok());
+        }
+        assert!(matches!(
+            table.translate_outbound(limited, 17, 5002, address, &(1024..=u16::MAX)),
+            Err(Error::SessionLimitReached(_))
+        ));
+        for port in [5000, 5001, 5002] {
+            assert!(table
+                .translate_outbound(unlimited, 17, port, address, &(1024..=u16::MAX))
+                .is_ok());
+        }
+
+        // Existing sessions keep working
+        assert!(table
+            .translate_outbound(limited, 17, 5001, address, &(1024..=u16::MAX))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_idle_sessions() {
+        let mut table = SessionTable::new(SessionTimeouts {
+            tcp: Duration::from_secs(7440),
+            other: Duration::ZERO,
+        });
+        table.set_limits(vec![SessionLimit {
+            sources: vec!["2001:db8::/48".parse().unwrap()],
+            max_sessions: 1,
+        }]);
+        let host = "2001:db8::a".parse().unwrap();
+        let address = "192.0.2.1".parse().unwrap();
+
+        // Idle sessions can't be reached, and no longer count against the limit once their port is reused
+        assert_eq!(
+            table
+                .translate_outbound(host, 17, 5000, address, &(1024..=u16::MAX))
+                .ok(),
+            Some(5000)
+        );
+        assert_eq!(table.translate_inbound(address, 17, 5000), None);
+        assert!(table
+            .translate_outbound(host, 17, 5000, address, &(1024..=u16::MAX))
+            .is_ok());
+        assert_eq!(table.len(), 1);
+
+        // Pruning releases them too
+        table.prune();
+        assert!(table.is_empty());
+        assert!(table
+            .translate_outbound(host, 6, 5001, address, &(1024..=u16::MAX))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_session_events() {
+        let mut table = SessionTable::new(SessionTimeouts {
+            tcp: Duration::from_secs(7440),
+            other: Duration::ZERO,
+        });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = Arc::clone(&events);
+            table.set_event_handler(move |event| events.lock().unwrap().push(event));
+        }
+        let laptop = "2001:db8::a".parse().unwrap();
+        let address = "192.0.2.1".parse().unwrap();
+
+        // Sessions are reported with both their original and translated ports
+        table
+            .translate_outbound(laptop, 17, 53, address, &(1024..=2047))
+            .unwrap();
+        table.prune();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                SessionEvent::Created {
+                    ipv6: laptop,
+                    protocol: 17,
+                    port: 53,
+                    ipv4: address,
+                    translated_port: 1077,
+                },
+                SessionEvent::Expired {
+                    ipv6: laptop,
+                    protocol: 17,
+                    port: 53,
+                    ipv4: address,
+                    translated_port: 1077,
+                },
+            ]
+        );
+    }
+}
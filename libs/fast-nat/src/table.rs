@@ -0,0 +1,313 @@
+use std::{fmt::Debug, hash::Hash, time::Duration};
+
+use rustc_hash::FxHashMap;
+
+use crate::{bimap::BiHashMap, timeout::MaybeTimeout};
+
+/// A value that can be stored on either side of a `MappingTable`.
+///
+/// Anything small, hashable, and copyable qualifies, such as raw addresses or `(address, port)` pairs.
+pub trait MappingKey: Copy + Eq + Hash + Debug {}
+
+impl<T> MappingKey for T where T: Copy + Eq + Hash + Debug {}
+
+/// A bi-directional table of one-to-one mappings, each of which either lasts indefinitely or times out
+#[derive(Debug)]
+pub struct MappingTable<Left, Right> {
+    /// Internal map
+    map: BiHashMap<Left, Right>,
+    /// Secondary map used to keep track of timeouts
+    timeouts: FxHashMap<(Left, Right), MaybeTimeout>,
+}
+
+impl<Left, Right> MappingTable<Left, Right>
+where
+    Left: MappingKey,
+    Right: MappingKey,
+{
+    /// Construct a new empty `MappingTable`
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prune all mappings that have timed out
+    #[profiling::function]
+    pub fn prune(&mut self) {
+        self.prune_with(|_, _| {});
+    }
+
+    /// Prune all mappings that have timed out, passing each one to `on_expired` as it is removed
+    #[profiling::function]
+    pub fn prune_with(&mut self, mut on_expired: impl FnMut(Left, Right)) {
+        log::trace!("Pruning old mappings");
+
+        // Compare all mappings against a common timestamp
+        let now = std::time::Instant::now();
+
+        // Remove all old mappings from both the bimap and the timeouts map
+        self.timeouts.retain(|(left, right), timeout| {
+            match timeout {
+                // Retain all indefinite mappings
+                MaybeTimeout::Never => true,
+                // Only retain mappings that haven't timed out yet
+                MaybeTimeout::After { duration, start } => {
+                    let should_retain = now.duration_since(*start) < *duration;
+                    if !should_retain {
+                        log::trace!(
+                            "Mapping {:?} -> {:?} has timed out and will be removed",
+                            left,
+                            right
+                        );
+                        self.map.remove(left, right);
+                        on_expired(*left, *right);
+                    }
+                    should_retain
+                }
+            }
+        });
+    }
+
+    /// Prune mappings that have timed out using either value, passing each one to `on_expired` as it is removed.
+    ///
+    /// Unlike a full prune, this only looks at the two mappings that would conflict with mapping `left` to `right`.
+    pub fn prune_conflicting(
+        &mut self,
+        left: Left,
+        right: Right,
+        mut on_expired: impl FnMut(Left, Right),
+    ) {
+        let now = std::time::Instant::now();
+        let conflicting = [
+            self.map.get_right(&left).map(|other| (left, *other)),
+            self.map.get_left(&right).map(|other| (*other, right)),
+        ];
+        for key in conflicting.into_iter().flatten() {
+            if let Some(MaybeTimeout::After { duration, start }) = self.timeouts.get(&key) {
+                if now.duration_since(*start) >= *duration {
+                    self.map.remove(&key.0, &key.1);
+                    self.timeouts.remove(&key);
+                    on_expired(key.0, key.1);
+                }
+            }
+        }
+    }
+
+    /// Insert a new indefinite mapping
+    #[profiling::function]
+    pub fn insert_indefinite(&mut self, left: Left, right: Right) {
+        self.map.insert(left, right);
+        self.timeouts.insert((left, right), MaybeTimeout::Never);
+    }
+
+    /// Insert a new mapping with a finite time-to-live
+    #[profiling::function]
+    pub fn insert(&mut self, left: Left, right: Right, duration: Duration) {
+        self.map.insert(left, right);
+        self.timeouts.insert(
+            (left, right),
+            MaybeTimeout::After {
+                duration,
+                start: std::time::Instant::now(),
+            },
+        );
+    }
+
+    /// Restart the timeout of the mapping for a given left value, so that it lasts as long as it is being used
+    #[profiling::function]
+    pub fn refresh(&mut self, left: &Left) {
+        if let Some(right) = self.map.get_right(left) {
+            if let Some(MaybeTimeout::After { start, .. }) = self.timeouts.get_mut(&(*left, *right))
+            {
+                *start = std::time::Instant::now();
+            }
+        }
+    }
+
+    /// Get the right value for a given left value.
+    ///
+    /// Mappings that have timed out are treated as absent, even if they haven't been pruned yet.
+    #[must_use]
+    #[profiling::function]
+    pub fn get_right(&self, left: &Left) -> Option<Right> {
+        let right = *self.map.get_right(left)?;
+        self.is_live(*left, right).then_some(right)
+    }
+
+    /// Get the left value for a given right value.
+    ///
+    /// Mappings that have timed out are treated as absent, even if they haven't been pruned yet.
+    #[must_use]
+    #[profiling::function]
+    pub fn get_left(&self, right: &Right) -> Option<Left> {
+        let left = *self.map.get_left(right)?;
+        self.is_live(left, *right).then_some(left)
+    }
+
+    /// Check if a mapping in the table has not timed out yet
+    fn is_live(&self, left: Left, right: Right) -> bool {
+        match self.timeouts.get(&(left, right)) {
+            Some(MaybeTimeout::After { duration, start }) => start.elapsed() < *duration,
+            _ => true,
+        }
+    }
+
+    /// Remove the mapping for a given left value (even if it has timed out), returning the right value it was mapped to
+    #[profiling::function]
+    pub fn remove_left(&mut self, left: &Left) -> Option<Right> {
+        let right = *self.map.get_right(left)?;
+        self.map.remove(left, &right);
+        self.timeouts.remove(&(*left, right));
+        Some(right)
+    }
+
+    /// Get how long ago the mapping for a given left value was created, if it is one that times out
+    #[must_use]
+    pub fn age(&self, left: &Left) -> Option<Duration> {
+        let right = self.get_right(left)?;
+        match self.timeouts.get(&(*left, right))? {
+            MaybeTimeout::Never => None,
+            MaybeTimeout::After { start, .. } => Some(start.elapsed()),
+        }
+    }
+
+    /// Iterate over all mappings in the table, along with the time remaining until they expire (if ever).
+    ///
+    /// Like lookups, this skips mappings that have timed out but haven't been pruned yet.
+    pub fn mappings(&self) -> impl Iterator<Item = (Left, Right, Option<Duration>)> + '_ {
+        let now = std::time::Instant::now();
+        self.timeouts
+            .iter()
+            .filter_map(move |((left, right), timeout)| {
+                let remaining = match timeout {
+                    MaybeTimeout::Never => None,
+                    MaybeTimeout::After { duration, start } => {
+                        let remaining = duration.saturating_sub(now.duration_since(*start));
+                        if remaining.is_zero() {
+                            return None;
+                        }
+                        Some(remaining)
+                    }
+                };
+                Some((*left, *right, remaining))
+            })
+    }
+
+    /// Get the number of mappings in the table
+    #[must_use]
+    #[profiling::function]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Check if the table is empty
+    #[must_use]
+    #[profiling::function]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl<Left, Right> Default for MappingTable<Left, Right> {
+    fn default() -> Self {
+        Self {
+            map: BiHashMap::default(),
+            timeouts: FxHashMap::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_port_pair_keys() {
+        let mut table = MappingTable::new();
+        let inside = (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 5000);
+        let outside = (Ipv4Addr::new(192, 0, 2, 1), 1024);
+        table.insert_indefinite(inside, outside);
+
+        assert_eq!(table.get_right(&inside), Some(outside));
+        assert_eq!(table.get_left(&outside), Some(inside));
+        assert_eq!(table.age(&inside), None);
+        assert_eq!(table.remove_left(&inside), Some(outside));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_prune_with() {
+        let mut table = MappingTable::new();
+        table.insert(1u32, 10u128, Duration::ZERO);
+        table.insert_indefinite(2u32, 20u128);
+
+        let mut expired = Vec::new();
+        table.prune_with(|left, right| expired.push((left, right)));
+        assert_eq!(expired, vec![(1, 10)]);
+        assert_eq!(table.get_right(&1), None);
+        assert_eq!(table.get_right(&2), Some(20));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_expired_lookup() {
+        let mut table = MappingTable::new();
+        table.insert(1u32, 10u32, Duration::ZERO);
+        table.insert(2u32, 20u32, Duration::from_secs(60));
+
+        // The expired mapping is hidden before it is pruned
+        assert_eq!(table.get_right(&1), None);
+        assert_eq!(table.get_left(&10), None);
+        assert_eq!(table.get_right(&2), Some(20));
+        assert_eq!(table.get_left(&20), Some(2));
+        assert_eq!(table.len(), 2);
+
+        // But it can still be removed explicitly
+        assert_eq!(table.remove_left(&1), Some(10));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_refresh_and_mappings() {
+        let mut table = MappingTable::new();
+        table.insert(1u32, 10u32, Duration::ZERO);
+        table.insert(2u32, 20u32, Duration::from_secs(60));
+        table.insert_indefinite(3u32, 30u32);
+
+        // Expired mappings are left out until they are pruned
+        let mut mappings: Vec<_> = table
+            .mappings()
+            .map(|(left, right, remaining)| (left, right, remaining.is_some()))
+            .collect();
+        mappings.sort_unstable();
+        assert_eq!(mappings, vec![(2, 20, true), (3, 30, false)]);
+
+        // Refreshing restarts the timeout of live mappings
+        std::thread::sleep(Duration::from_millis(10));
+        table.refresh(&2);
+        let remaining = table
+            .mappings()
+            .find_map(|(left, _, remaining)| (left == 2).then_some(remaining))
+            .flatten()
+            .unwrap();
+        assert!(remaining > Duration::from_millis(59_995));
+        table.refresh(&3);
+        assert_eq!(table.age(&3), None);
+    }
+
+    #[test]
+    fn test_prune_conflicting() {
+        let mut table = MappingTable::new();
+        table.insert(1u32, 10u32, Duration::ZERO);
+        table.insert(2u32, 20u32, Duration::from_secs(60));
+
+        // Only the expired mapping using one of the values is removed
+        let mut expired = Vec::new();
+        table.prune_conflicting(1, 20, |left, right| expired.push((left, right)));
+        assert_eq!(expired, vec![(1, 10)]);
+        assert_eq!(table.get_right(&2), Some(20));
+        assert_eq!(table.len(), 1);
+    }
+}
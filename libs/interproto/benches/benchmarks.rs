@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use interproto::protocols::*;
-use pnet::packet::{
+use pnet_packet::{
     tcp::{MutableTcpPacket, TcpPacket},
     udp::{MutableUdpPacket, UdpPacket},
 };
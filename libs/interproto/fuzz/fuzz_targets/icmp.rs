@@ -0,0 +1,30 @@
+#![no_main]
+
+use interproto::protocols::icmp::{
+    generate::{answer_ipv4, answer_ipv6, time_exceeded_ipv4, time_exceeded_ipv6},
+    translate_icmp_to_icmpv6, translate_icmpv6_to_icmp,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks which path to exercise, so one corpus covers every ICMP message containing a quoted packet
+    let Some((selector, data)) = data.split_first() else {
+        return;
+    };
+    let _ = match selector % 6 {
+        0 => translate_icmp_to_icmpv6(
+            data,
+            "64:ff9b::c000:201".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        ),
+        1 => translate_icmpv6_to_icmp(
+            data,
+            "192.0.2.1".parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+        ),
+        2 => time_exceeded_ipv4(data, "203.0.113.1".parse().unwrap()).map(|_| Vec::new()),
+        3 => time_exceeded_ipv6(data, "2001:db8::ffff".parse().unwrap()).map(|_| Vec::new()),
+        4 => answer_ipv4(data).map(|_| Vec::new()),
+        _ => answer_ipv6(data).map(|_| Vec::new()),
+    };
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use interproto::protocols::ip::translate_ipv4_to_ipv6;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Malformed packets must be rejected with an error, never a panic
+    let _ = translate_ipv4_to_ipv6(
+        data,
+        "64:ff9b::c000:201".parse().unwrap(),
+        "2001:db8::1".parse().unwrap(),
+    );
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use interproto::protocols::ip::translate_ipv6_to_ipv4;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Malformed packets must be rejected with an error, never a panic
+    let _ = translate_ipv6_to_ipv4(
+        data,
+        "192.0.2.1".parse().unwrap(),
+        "198.51.100.1".parse().unwrap(),
+    );
+});
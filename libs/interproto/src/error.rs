@@ -3,10 +3,14 @@
 pub enum Error {
     #[error("Packet too short. Expected at least {expected} bytes, got {actual}")]
     PacketTooShort { expected: usize, actual: usize },
+    #[error("Packet too long. Expected at most {maximum} bytes, got {actual}")]
+    PacketTooLong { maximum: usize, actual: usize },
     #[error("Unsupported ICMP type: {0}")]
     UnsupportedIcmpType(u8),
     #[error("Unsupported ICMPv6 type: {0}")]
     UnsupportedIcmpv6Type(u8),
+    #[error("Fragmented IPv6 packets are not supported")]
+    FragmentedPacket,
 }
 
 /// Result type for `interproto`
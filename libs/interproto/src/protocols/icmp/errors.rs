@@ -0,0 +1,355 @@
+//! Construction of ICMP and ICMPv6 error messages sent by the translator itself.
+//!
+//! Every error the translator originates (whether a hop ran out, a packet was too big, or a destination was
+//! refused) is built here, so that they all follow the same rules:
+//!
+//! - As much of the offending packet is quoted as fits in 576 bytes for ICMP ([RFC1812 section
+//!   4.3.2.3](https://datatracker.ietf.org/doc/html/rfc1812#section-4.3.2.3)), or 1280 bytes for ICMPv6
+//!   ([RFC4443 section 2.4](https://datatracker.ietf.org/doc/html/rfc4443#section-2.4)).
+//! - No error is sent about an error, a non-initial fragment, a packet from an address that can't be replied to, or
+//!   (except for Packet Too Big in IPv6) a packet sent to a broadcast or multicast address.
+//! - The error is addressed to the source of the offending packet, and sent from whichever address the caller picked.
+
+use super::IPV6_MINIMUM_MTU;
+use crate::error::{Error, Result};
+use pnet_packet::{
+    icmp::{self, IcmpCode, IcmpPacket, IcmpType, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{self, Icmpv6Code, Icmpv6Packet, Icmpv6Type, Icmpv6Types, MutableIcmpv6Packet},
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    ipv6::{Ipv6Packet, MutableIpv6Packet},
+    Packet,
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Largest ICMP error to send, as recommended by RFC1812 section 4.3.2.3
+const MAX_ICMP_ERROR_LEN: usize = 576;
+
+/// Largest ICMPv6 error to send, as required by RFC4443 section 2.4
+const MAX_ICMPV6_ERROR_LEN: usize = 1280;
+
+/// TTL and hop limit of generated packets
+const DEFAULT_TTL: u8 = 64;
+
+/// ICMPv6 Redirect, which is answered like an error
+const ICMPV6_REDIRECT: u8 = 137;
+
+/// Why a packet is being refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// There is no route to the destination's network
+    NetworkUnreachable,
+    /// The destination itself can't be reached
+    HostUnreachable,
+    /// Nothing is listening on the destination port
+    PortUnreachable,
+    /// Traffic to the destination is forbidden by policy
+    AdministrativelyProhibited,
+    /// The TTL or hop limit ran out
+    TimeExceeded,
+    /// The packet is larger than the next hop's MTU
+    PacketTooBig { mtu: u16 },
+}
+
+impl ErrorKind {
+    /// Get the ICMP type, code, and rest-of-header word for this error
+    fn icmp(self) -> (IcmpType, IcmpCode, u32) {
+        match self {
+            Self::NetworkUnreachable => (IcmpTypes::DestinationUnreachable, IcmpCode(0), 0),
+            Self::HostUnreachable => (IcmpTypes::DestinationUnreachable, IcmpCode(1), 0),
+            Self::PortUnreachable => (IcmpTypes::DestinationUnreachable, IcmpCode(3), 0),
+            Self::AdministrativelyProhibited => {
+                (IcmpTypes::DestinationUnreachable, IcmpCode(13), 0)
+            }
+            Self::TimeExceeded => (IcmpTypes::TimeExceeded, IcmpCode(0), 0),
+            // The next-hop MTU is in the low half of the word (RFC1191)
+            Self::PacketTooBig { mtu } => (
+                IcmpTypes::DestinationUnreachable,
+                IcmpCode(4),
+                u32::from(mtu),
+            ),
+        }
+    }
+
+    /// Get the ICMPv6 type, code, and rest-of-header word for this error
+    fn icmpv6(self) -> (Icmpv6Type, Icmpv6Code, u32) {
+        match self {
+            Self::NetworkUnreachable => (Icmpv6Types::DestinationUnreachable, Icmpv6Code(0), 0),
+            Self::HostUnreachable => (Icmpv6Types::DestinationUnreachable, Icmpv6Code(3), 0),
+            Self::PortUnreachable => (Icmpv6Types::DestinationUnreachable, Icmpv6Code(4), 0),
+            Self::AdministrativelyProhibited => {
+                (Icmpv6Types::DestinationUnreachable, Icmpv6Code(1), 0)
+            }
+            Self::TimeExceeded => (Icmpv6Types::TimeExceeded, Icmpv6Code(0), 0),
+            Self::PacketTooBig { mtu } => (
+                Icmpv6Types::PacketTooBig,
+                Icmpv6Code(0),
+                u32::from(mtu).max(IPV6_MINIMUM_MTU),
+            ),
+        }
+    }
+}
+
+/// Build an ICMP error from `source` in response to an IPv4 packet.
+///
+/// Returns `None` if no error may be sent about this packet.
+#[profiling::function]
+pub fn icmp_error(original: &[u8], source: Ipv4Addr, kind: ErrorKind) -> Result<Option<Vec<u8>>> {
+    let original_packet = parse_ipv4(original)?;
+    if !may_answer_ipv4(&original_packet) {
+        return Ok(None);
+    }
+
+    // Type, code, checksum, and the rest-of-header word come before the quoted packet
+    let (icmp_type, icmp_code, rest_of_header) = kind.icmp();
+    let quoted_len = original
+        .len()
+        .min(MAX_ICMP_ERROR_LEN - Ipv4Packet::minimum_packet_size() - 8);
+    let mut buffer = vec![0u8; 8 + quoted_len];
+    buffer[4..8].copy_from_slice(&rest_of_header.to_be_bytes());
+    buffer[8..].copy_from_slice(&original[..quoted_len]);
+
+    let mut icmp_packet = unsafe { MutableIcmpPacket::new(&mut buffer).unwrap_unchecked() };
+    icmp_packet.set_icmp_type(icmp_type);
+    icmp_packet.set_icmp_code(icmp_code);
+    icmp_packet.set_checksum(icmp::checksum(&icmp_packet.to_immutable()));
+
+    Ok(Some(build_ipv4(
+        source,
+        original_packet.get_source(),
+        IpNextHeaderProtocols::Icmp,
+        &buffer,
+    )))
+}
+
+/// Build an ICMPv6 error from `source` in response to an IPv6 packet.
+///
+/// Returns `None` if no error may be sent about this packet.
+#[profiling::function]
+pub fn icmpv6_error(original: &[u8], source: Ipv6Addr, kind: ErrorKind) -> Result<Option<Vec<u8>>> {
+    let original_packet = parse_ipv6(original)?;
+    if !may_answer_ipv6(&original_packet, kind) {
+        return Ok(None);
+    }
+
+    // Type, code, checksum, and the rest-of-header word come before the quoted packet
+    let (icmpv6_type, icmpv6_code, rest_of_header) = kind.icmpv6();
+    let quoted_len = original
+        .len()
+        .min(MAX_ICMPV6_ERROR_LEN - Ipv6Packet::minimum_packet_size() - 8);
+    let mut buffer = vec![0u8; 8 + quoted_len];
+    buffer[4..8].copy_from_slice(&rest_of_header.to_be_bytes());
+    buffer[8..].copy_from_slice(&original[..quoted_len]);
+
+    let destination = original_packet.get_source();
+    let mut icmpv6_packet = unsafe { MutableIcmpv6Packet::new(&mut buffer).unwrap_unchecked() };
+    icmpv6_packet.set_icmpv6_type(icmpv6_type);
+    icmpv6_packet.set_icmpv6_code(icmpv6_code);
+    icmpv6_packet.set_checksum(icmpv6::checksum(
+        &icmpv6_packet.to_immutable(),
+        &source,
+        &destination,
+    ));
+
+    Ok(Some(build_ipv6(
+        source,
+        destination,
+        IpNextHeaderProtocols::Icmpv6,
+        &buffer,
+    )))
+}
+
+/// Check if an error may be sent about an IPv4 packet (RFC1812 section 4.3.2.7)
+pub(crate) fn may_answer_ipv4(packet: &Ipv4Packet) -> bool {
+    let source = packet.get_source();
+    let destination = packet.get_destination();
+    let unanswerable_source = source.is_unspecified()
+        || source.is_loopback()
+        || source.is_multicast()
+        || source.is_broadcast()
+        || source.octets()[0] >= 240;
+    let group_destination = destination.is_multicast() || destination.is_broadcast();
+    packet.get_fragment_offset() == 0
+        && !unanswerable_source
+        && !group_destination
+        && !is_icmp_error(packet)
+}
+
+/// Check if an error may be sent about an IPv6 packet (RFC4443 section 2.4)
+fn may_answer_ipv6(packet: &Ipv6Packet, kind: ErrorKind) -> bool {
+    let source = packet.get_source();
+    let unanswerable_source = source.is_unspecified() || source.is_multicast();
+    let group_destination =
+        packet.get_destination().is_multicast() && !matches!(kind, ErrorKind::PacketTooBig { .. });
+    !unanswerable_source && !group_destination && !is_icmpv6_error(packet)
+}
+
+/// Check if a packet carries an ICMP error message
+fn is_icmp_error(packet: &Ipv4Packet) -> bool {
+    packet.get_next_level_protocol() == IpNextHeaderProtocols::Icmp
+        && IcmpPacket::new(packet.payload()).is_none_or(|icmp_packet| {
+            matches!(
+                icmp_packet.get_icmp_type(),
+                IcmpTypes::DestinationUnreachable
+                    | IcmpTypes::SourceQuench
+                    | IcmpTypes::RedirectMessage
+                    | IcmpTypes::TimeExceeded
+                    | IcmpTypes::ParameterProblem
+            )
+        })
+}
+
+/// Check if a packet carries an ICMPv6 error message (types 0 through 127) or a redirect
+fn is_icmpv6_error(packet: &Ipv6Packet) -> bool {
+    packet.get_next_header() == IpNextHeaderProtocols::Icmpv6
+        && Icmpv6Packet::new(packet.payload()).is_none_or(|icmpv6_packet| {
+            let icmpv6_type = icmpv6_packet.get_icmpv6_type().0;
+            icmpv6_type < 128 || icmpv6_type == ICMPV6_REDIRECT
+        })
+}
+
+pub(crate) fn parse_ipv4(packet: &[u8]) -> Result<Ipv4Packet<'_>> {
+    Ipv4Packet::new(packet).ok_or(Error::PacketTooShort {
+        expected: Ipv4Packet::minimum_packet_size(),
+        actual: packet.len(),
+    })
+}
+
+pub(super) fn parse_ipv6(packet: &[u8]) -> Result<Ipv6Packet<'_>> {
+    Ipv6Packet::new(packet).ok_or(Error::PacketTooShort {
+        expected: Ipv6Packet::minimum_packet_size(),
+        actual: packet.len(),
+    })
+}
+
+/// Wrap a payload in an IPv4 header
+pub(crate) fn build_ipv4(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut buffer = vec![0u8; Ipv4Packet::minimum_packet_size() + payload.len()];
+
+    // NOTE: There is no way this can fail since we are creating the buffer with explicitly enough space.
+    let mut ipv4_packet = unsafe { MutableIpv4Packet::new(&mut buffer).unwrap_unchecked() };
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_ttl(DEFAULT_TTL);
+    ipv4_packet.set_next_level_protocol(protocol);
+    ipv4_packet.set_source(source);
+    ipv4_packet.set_destination(destination);
+    ipv4_packet.set_total_length(buffer_len_u16(
+        Ipv4Packet::minimum_packet_size() + payload.len(),
+    ));
+    ipv4_packet.set_payload(payload);
+    ipv4_packet.set_checksum(ipv4::checksum(&ipv4_packet.to_immutable()));
+    buffer
+}
+
+/// Wrap a payload in an IPv6 header
+pub(super) fn build_ipv6(
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    next_header: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut buffer = vec![0u8; Ipv6Packet::minimum_packet_size() + payload.len()];
+
+    // NOTE: There is no way this can fail since we are creating the buffer with explicitly enough space.
+    let mut ipv6_packet = unsafe { MutableIpv6Packet::new(&mut buffer).unwrap_unchecked() };
+    ipv6_packet.set_version(6);
+    ipv6_packet.set_next_header(next_header);
+    ipv6_packet.set_hop_limit(DEFAULT_TTL);
+    ipv6_packet.set_source(source);
+    ipv6_packet.set_destination(destination);
+    ipv6_packet.set_payload_length(buffer_len_u16(payload.len()));
+    ipv6_packet.set_payload(payload);
+    buffer
+}
+
+/// Generated packets are always far smaller than 64KiB
+fn buffer_len_u16(len: usize) -> u16 {
+    len.try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a UDP packet inside IPv6, padded to `len` bytes
+    fn udp_ipv6(destination: Ipv6Addr, len: usize) -> Vec<u8> {
+        build_ipv6(
+            "2001:db8::1".parse().unwrap(),
+            destination,
+            IpNextHeaderProtocols::Udp,
+            &vec![0u8; len - Ipv6Packet::minimum_packet_size()],
+        )
+    }
+
+    #[test]
+    fn test_packet_too_big() {
+        let source: Ipv6Addr = "64:ff9b::c000:201".parse().unwrap();
+        let original = udp_ipv6("64:ff9b::cb00:7101".parse().unwrap(), 1500);
+        let error = icmpv6_error(&original, source, ErrorKind::PacketTooBig { mtu: 1400 })
+            .unwrap()
+            .unwrap();
+
+        // The error is truncated to the IPv6 minimum MTU
+        assert_eq!(error.len(), MAX_ICMPV6_ERROR_LEN);
+        let error_packet = Ipv6Packet::new(&error).unwrap();
+        assert_eq!(
+            error_packet.get_destination(),
+            "2001:db8::1".parse::<Ipv6Addr>().unwrap()
+        );
+        let icmpv6_packet = Icmpv6Packet::new(error_packet.payload()).unwrap();
+        assert_eq!(icmpv6_packet.get_icmpv6_type(), Icmpv6Types::PacketTooBig);
+        assert_eq!(&icmpv6_packet.payload()[..4], &1400u32.to_be_bytes());
+        assert_eq!(
+            icmpv6_packet.get_checksum(),
+            icmpv6::checksum(&icmpv6_packet, &source, &error_packet.get_destination())
+        );
+
+        // Packet Too Big is the only error sent about multicast packets
+        let multicast = udp_ipv6("ff0e::1".parse().unwrap(), 100);
+        assert!(
+            icmpv6_error(&multicast, source, ErrorKind::PacketTooBig { mtu: 1280 })
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            icmpv6_error(&multicast, source, ErrorKind::HostUnreachable).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_refused_sources() {
+        let source = Ipv4Addr::new(203, 0, 113, 1);
+        for (from, to) in [
+            (Ipv4Addr::UNSPECIFIED, Ipv4Addr::new(198, 51, 100, 1)),
+            (Ipv4Addr::new(224, 0, 0, 1), Ipv4Addr::new(198, 51, 100, 1)),
+            (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::BROADCAST),
+        ] {
+            let original = build_ipv4(from, to, IpNextHeaderProtocols::Udp, &[0u8; 8]);
+            assert_eq!(
+                icmp_error(&original, source, ErrorKind::PortUnreachable).unwrap(),
+                None
+            );
+        }
+
+        let original = build_ipv4(
+            Ipv4Addr::new(192, 0, 2, 1),
+            Ipv4Addr::new(198, 51, 100, 1),
+            IpNextHeaderProtocols::Udp,
+            &[0u8; 8],
+        );
+        let error = icmp_error(&original, source, ErrorKind::PacketTooBig { mtu: 1480 })
+            .unwrap()
+            .unwrap();
+        let icmp_packet = IcmpPacket::new(&error[20..]).unwrap();
+        assert_eq!(icmp_packet.get_icmp_code(), IcmpCode(4));
+        assert_eq!(&error[24..28], &[0, 0, 0x05, 0xc8]);
+        assert_eq!(icmp_packet.get_checksum(), icmp::checksum(&icmp_packet));
+    }
+}
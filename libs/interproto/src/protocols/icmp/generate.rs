@@ -0,0 +1,189 @@
+//! Functions for generating ICMP messages on behalf of the translator itself, rather than translating them.
+
+use super::errors::{
+    build_ipv4, build_ipv6, icmp_error, icmpv6_error, parse_ipv4, parse_ipv6, ErrorKind,
+};
+use crate::error::Result;
+use pnet_packet::{
+    icmp::{self, IcmpPacket, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{self, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet},
+    ip::IpNextHeaderProtocols,
+    Packet,
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Build an ICMP Time Exceeded message in response to an IPv4 packet.
+///
+/// Returns `None` if no error may be sent about the packet (for example, because it is itself an ICMP error).
+#[profiling::function]
+pub fn time_exceeded_ipv4(original: &[u8], source: Ipv4Addr) -> Result<Option<Vec<u8>>> {
+    icmp_error(original, source, ErrorKind::TimeExceeded)
+}
+
+/// Build an ICMPv6 Time Exceeded message in response to an IPv6 packet.
+///
+/// Returns `None` if no error may be sent about the packet (for example, because it is itself an ICMPv6 error).
+#[profiling::function]
+pub fn time_exceeded_ipv6(original: &[u8], source: Ipv6Addr) -> Result<Option<Vec<u8>>> {
+    icmpv6_error(original, source, ErrorKind::TimeExceeded)
+}
+
+/// Answer an IPv4 packet addressed to the translator itself.
+///
+/// Echo requests get echo replies, and UDP gets a port unreachable error (which is how traceroute knows it has
+/// arrived). Anything else goes unanswered, and `None` is returned.
+#[profiling::function]
+pub fn answer_ipv4(request: &[u8]) -> Result<Option<Vec<u8>>> {
+    let request_packet = parse_ipv4(request)?;
+    match request_packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Icmp => {
+            let Some(icmp_packet) = IcmpPacket::new(request_packet.payload()) else {
+                return Ok(None);
+            };
+            if icmp_packet.get_icmp_type() != IcmpTypes::EchoRequest {
+                return Ok(None);
+            }
+
+            // Echo the request back with only its type changed
+            let mut reply = request_packet.payload().to_vec();
+            let mut reply_packet = unsafe { MutableIcmpPacket::new(&mut reply).unwrap_unchecked() };
+            reply_packet.set_icmp_type(IcmpTypes::EchoReply);
+            reply_packet.set_checksum(icmp::checksum(&reply_packet.to_immutable()));
+            Ok(Some(build_ipv4(
+                request_packet.get_destination(),
+                request_packet.get_source(),
+                IpNextHeaderProtocols::Icmp,
+                &reply,
+            )))
+        }
+        IpNextHeaderProtocols::Udp => icmp_error(
+            request,
+            request_packet.get_destination(),
+            ErrorKind::PortUnreachable,
+        ),
+        _ => Ok(None),
+    }
+}
+
+/// Answer an IPv6 packet addressed to the translator itself.
+///
+/// Echo requests get echo replies, and UDP gets a port unreachable error (which is how traceroute knows it has
+/// arrived). Anything else goes unanswered, and `None` is returned.
+#[profiling::function]
+pub fn answer_ipv6(request: &[u8]) -> Result<Option<Vec<u8>>> {
+    let request_packet = parse_ipv6(request)?;
+    match request_packet.get_next_header() {
+        IpNextHeaderProtocols::Icmpv6 => {
+            let Some(icmpv6_packet) = Icmpv6Packet::new(request_packet.payload()) else {
+                return Ok(None);
+            };
+            if icmpv6_packet.get_icmpv6_type() != Icmpv6Types::EchoRequest {
+                return Ok(None);
+            }
+
+            // Echo the request back with only its type changed
+            let mut reply = request_packet.payload().to_vec();
+            let mut reply_packet =
+                unsafe { MutableIcmpv6Packet::new(&mut reply).unwrap_unchecked() };
+            reply_packet.set_icmpv6_type(Icmpv6Types::EchoReply);
+            reply_packet.set_checksum(icmpv6::checksum(
+                &reply_packet.to_immutable(),
+                &request_packet.get_destination(),
+                &request_packet.get_source(),
+            ));
+            Ok(Some(build_ipv6(
+                request_packet.get_destination(),
+                request_packet.get_source(),
+                IpNextHeaderProtocols::Icmpv6,
+                &reply,
+            )))
+        }
+        IpNextHeaderProtocols::Udp => icmpv6_error(
+            request,
+            request_packet.get_destination(),
+            ErrorKind::PortUnreachable,
+        ),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::{
+        ipv4::{self, Ipv4Packet},
+        ipv6::Ipv6Packet,
+        udp::MutableUdpPacket,
+    };
+
+    /// Build a UDP packet inside IPv4
+    fn udp_ipv4(ttl: u8) -> Vec<u8> {
+        let mut udp = vec![0u8; 8 + 4];
+        let mut udp_packet = MutableUdpPacket::new(&mut udp).unwrap();
+        udp_packet.set_source(1234);
+        udp_packet.set_destination(33434);
+        udp_packet.set_length(12);
+        let mut packet = build_ipv4(
+            "192.0.2.1".parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+            IpNextHeaderProtocols::Udp,
+            &udp,
+        );
+        packet[8] = ttl;
+        packet
+    }
+
+    #[test]
+    fn test_time_exceeded_ipv4() {
+        let original = udp_ipv4(1);
+        let reply = time_exceeded_ipv4(&original, "203.0.113.1".parse().unwrap())
+            .unwrap()
+            .unwrap();
+
+        let reply_packet = Ipv4Packet::new(&reply).unwrap();
+        assert_eq!(reply_packet.get_source(), Ipv4Addr::new(203, 0, 113, 1));
+        assert_eq!(reply_packet.get_destination(), Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(reply_packet.get_checksum(), ipv4::checksum(&reply_packet));
+
+        let icmp_packet = IcmpPacket::new(reply_packet.payload()).unwrap();
+        assert_eq!(icmp_packet.get_icmp_type(), IcmpTypes::TimeExceeded);
+        assert_eq!(icmp_packet.get_checksum(), icmp::checksum(&icmp_packet));
+        assert_eq!(&icmp_packet.payload()[4..], &original[..]);
+
+        // Errors are never sent about errors
+        assert_eq!(
+            time_exceeded_ipv4(&reply, "203.0.113.2".parse().unwrap()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_answer_ipv6_echo_request() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "64:ff9b::cb00:7101".parse().unwrap();
+        let mut icmpv6 = vec![0u8; 8 + 4];
+        let mut icmpv6_packet = MutableIcmpv6Packet::new(&mut icmpv6).unwrap();
+        icmpv6_packet.set_icmpv6_type(Icmpv6Types::EchoRequest);
+        icmpv6_packet.set_payload(&[0, 1, 0, 2, 0xde, 0xad, 0xbe, 0xef]);
+        let request = build_ipv6(source, destination, IpNextHeaderProtocols::Icmpv6, &icmpv6);
+
+        let reply = answer_ipv6(&request).unwrap().unwrap();
+        let reply_packet = Ipv6Packet::new(&reply).unwrap();
+        assert_eq!(reply_packet.get_source(), destination);
+        assert_eq!(reply_packet.get_destination(), source);
+
+        let icmpv6_packet = Icmpv6Packet::new(reply_packet.payload()).unwrap();
+        assert_eq!(icmpv6_packet.get_icmpv6_type(), Icmpv6Types::EchoReply);
+        assert_eq!(
+            icmpv6_packet.get_checksum(),
+            icmpv6::checksum(&icmpv6_packet, &destination, &source)
+        );
+        assert_eq!(
+            icmpv6_packet.payload(),
+            &[0, 1, 0, 2, 0xde, 0xad, 0xbe, 0xef]
+        );
+
+        // Replies are not answered
+        assert_eq!(answer_ipv6(&reply).unwrap(), None);
+    }
+}
@@ -2,8 +2,8 @@ use crate::{
     error::{Error, Result},
     protocols::ip::translate_ipv4_to_ipv6,
 };
-use pnet::packet::{
-    icmp::{self, IcmpPacket, IcmpTypes, MutableIcmpPacket},
+use pnet_packet::{
+    icmp::{self, destination_unreachable, IcmpPacket, IcmpTypes, MutableIcmpPacket},
     icmpv6::{self, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet},
     Packet,
 };
@@ -11,8 +11,15 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 
 use super::ip::translate_ipv6_to_ipv4;
 
+pub mod errors;
+pub mod generate;
 mod type_code;
 
+/// Smallest MTU an IPv6 link may have
+const IPV6_MINIMUM_MTU: u32 = 1280;
+
+pub use type_code::{set_type_code_overrides, TypeCodeOverride};
+
 /// Translate an ICMP packet to ICMPv6. This will make a best guess at the ICMPv6 type and code since there is no 1:1 mapping.
 #[allow(clippy::deprecated_cfg_attr)]
 #[profiling::function]
@@ -50,15 +57,26 @@ pub fn translate_icmp_to_icmpv6(
             Icmpv6Types::TimeExceeded => {
                 // Time exceeded messages contain the original IPv4 header and part of the payload. (with 4 bytes of forward padding)
                 // We need to translate the IPv4 header and the payload, but keep the padding
-                let mut output = vec![0u8; 4];
-                output.copy_from_slice(&icmp_packet.payload()[..4]);
+                let (padding, original) =
+                    split_padding(icmp_packet.payload(), IcmpPacket::minimum_packet_size())?;
+                let mut output = padding.to_vec();
                 output.extend_from_slice(&translate_ipv4_to_ipv6(
-                    &icmp_packet.payload()[4..],
+                    original,
                     new_source,
                     new_destination,
                 )?);
                 output
             }
+            Icmpv6Types::PacketTooBig => {
+                // The IPv6 header is 20 bytes larger. IPv6 hosts are never told to go below the minimum MTU, so
+                // they don't fall back to sending atomic fragments (RFC8021).
+                let (mtu, original) =
+                    split_padding(icmp_packet.payload(), IcmpPacket::minimum_packet_size())?;
+                let mtu = u32::from(u16::from_be_bytes([mtu[2], mtu[3]])) + 20;
+                let mut output = mtu.max(IPV6_MINIMUM_MTU).to_be_bytes().to_vec();
+                output.extend_from_slice(original);
+                output
+            }
             _ => icmp_packet.payload().to_vec(),
         };
 
@@ -137,15 +155,29 @@ pub fn translate_icmpv6_to_icmp(
             IcmpTypes::TimeExceeded => {
                 // Time exceeded messages contain the original IPv6 header and part of the payload. (with 4 bytes of forward padding)
                 // We need to translate the IPv6 header and the payload, but keep the padding
-                let mut output = vec![0u8; 4];
-                output.copy_from_slice(&icmpv6_packet.payload()[..4]);
+                let (padding, original) =
+                    split_padding(icmpv6_packet.payload(), Icmpv6Packet::minimum_packet_size())?;
+                let mut output = padding.to_vec();
                 output.extend_from_slice(&translate_ipv6_to_ipv4(
-                    &icmpv6_packet.payload()[4..],
+                    original,
                     new_source,
                     new_destination,
                 )?);
                 output
             }
+            IcmpTypes::DestinationUnreachable
+                if icmp_code
+                    == destination_unreachable::IcmpCodes::FragmentationRequiredAndDFFlagSet =>
+            {
+                // The IPv4 header is 20 bytes smaller, and the MTU field only half as wide
+                let (mtu, original) =
+                    split_padding(icmpv6_packet.payload(), Icmpv6Packet::minimum_packet_size())?;
+                let mtu = u32::from_be_bytes(mtu.try_into().unwrap()).saturating_sub(20);
+                let mut output = vec![0, 0];
+                output.extend_from_slice(&u16::try_from(mtu).unwrap_or(u16::MAX).to_be_bytes());
+                output.extend_from_slice(original);
+                output
+            }
             _ => icmpv6_packet.payload().to_vec(),
         };
 
@@ -178,3 +210,75 @@ pub fn translate_icmpv6_to_icmp(
         error
     })
 }
+
+/// Split the 4 bytes of padding off the front of an ICMP error's payload, leaving the quoted packet.
+/// `header_len` is the size of the header before the payload, so errors can report the size of the whole message.
+fn split_padding(payload: &[u8], header_len: usize) -> Result<(&[u8], &[u8])> {
+    if payload.len() < 4 {
+        return Err(Error::PacketTooShort {
+            expected: header_len + 4,
+            actual: header_len + payload.len(),
+        });
+    }
+    Ok(payload.split_at(4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_too_big_mtu() {
+        // Fragmentation Needed with a next-hop MTU of 576, quoting nothing
+        let icmp = [3u8, 4, 0, 0, 0, 0, 0x02, 0x40];
+        let icmpv6 = translate_icmp_to_icmpv6(
+            &icmp,
+            "64:ff9b::c000:201".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(icmpv6[0], Icmpv6Types::PacketTooBig.0);
+        assert_eq!(&icmpv6[4..8], &1280u32.to_be_bytes());
+
+        // Going the other way, the MTU shrinks by the difference in header sizes
+        let icmpv6 = [2u8, 0, 0, 0, 0, 0, 0x05, 0xdc];
+        let icmp = translate_icmpv6_to_icmp(
+            &icmpv6,
+            "192.0.2.1".parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+        )
+        .unwrap();
+        assert_eq!((icmp[0], icmp[1]), (3, 4));
+        assert_eq!(&icmp[4..8], &[0, 0, 0x05, 0xc8]);
+    }
+
+    #[test]
+    fn test_truncated_time_exceeded() {
+        // A Time Exceeded message that ends before its padding does
+        let icmp = [11u8, 0, 0, 0, 0, 0];
+        assert_eq!(
+            translate_icmp_to_icmpv6(
+                &icmp,
+                "64:ff9b::c000:201".parse().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+            ),
+            Err(Error::PacketTooShort {
+                expected: 8,
+                actual: 6
+            })
+        );
+
+        let icmpv6 = [3u8, 0, 0, 0, 0];
+        assert_eq!(
+            translate_icmpv6_to_icmp(
+                &icmpv6,
+                "192.0.2.1".parse().unwrap(),
+                "198.51.100.1".parse().unwrap(),
+            ),
+            Err(Error::PacketTooShort {
+                expected: 8,
+                actual: 5
+            })
+        );
+    }
+}
@@ -1,11 +1,55 @@
 //! Look-up-tables for translating between ICMP (type,code) tuples and ICMPv6 (type,code) tuples.
 
-use pnet::packet::{
+use pnet_packet::{
     icmp::{destination_unreachable, IcmpCode, IcmpType, IcmpTypes},
     icmpv6::{Icmpv6Code, Icmpv6Type, Icmpv6Types},
 };
 
 use crate::error::{Error, Result};
+use std::sync::RwLock;
+
+/// A (type, code) translation that replaces the built-in one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeCodeOverride {
+    /// Type of the message being translated
+    pub from_type: u8,
+    /// Code of the message being translated. Applies to every code of the type if not set.
+    pub from_code: Option<u8>,
+    /// Type to translate it to
+    pub to_type: u8,
+    /// Code to translate it to
+    pub to_code: u8,
+}
+
+/// Overrides for ICMP to ICMPv6 translation
+static OVERRIDES_4_TO_6: RwLock<Vec<TypeCodeOverride>> = RwLock::new(Vec::new());
+
+/// Overrides for ICMPv6 to ICMP translation
+static OVERRIDES_6_TO_4: RwLock<Vec<TypeCodeOverride>> = RwLock::new(Vec::new());
+
+/// Replace the overrides applied on top of the built-in (type, code) translations, for ICMP to ICMPv6 and ICMPv6 to
+/// ICMP respectively. An override for a specific code takes precedence over one for the whole type.
+pub fn set_type_code_overrides(
+    icmp_to_icmpv6: Vec<TypeCodeOverride>,
+    icmpv6_to_icmp: Vec<TypeCodeOverride>,
+) {
+    *OVERRIDES_4_TO_6.write().unwrap() = icmp_to_icmpv6;
+    *OVERRIDES_6_TO_4.write().unwrap() = icmpv6_to_icmp;
+}
+
+/// Find the override for a (type, code), if there is one
+fn find_override(overrides: &RwLock<Vec<TypeCodeOverride>>, from: (u8, u8)) -> Option<(u8, u8)> {
+    let overrides = overrides.read().unwrap();
+    overrides
+        .iter()
+        .find(|o| o.from_type == from.0 && o.from_code == Some(from.1))
+        .or_else(|| {
+            overrides
+                .iter()
+                .find(|o| o.from_type == from.0 && o.from_code.is_none())
+        })
+        .map(|o| (o.to_type, o.to_code))
+}
 
 /// Best effort translation from an ICMP type and code to an ICMPv6 type and code
 #[allow(clippy::deprecated_cfg_attr)]
@@ -14,6 +58,10 @@ pub fn translate_type_and_code_4_to_6(
     icmp_type: IcmpType,
     icmp_code: IcmpCode,
 ) -> Result<(Icmpv6Type, Icmpv6Code)> {
+    if let Some((to_type, to_code)) = find_override(&OVERRIDES_4_TO_6, (icmp_type.0, icmp_code.0)) {
+        return Ok((Icmpv6Type(to_type), Icmpv6Code(to_code)));
+    }
+
     match (icmp_type, icmp_code) {
         // Echo Request
         (IcmpTypes::EchoRequest, _) => Ok((Icmpv6Types::EchoRequest, Icmpv6Code(0))),
@@ -64,6 +112,10 @@ pub fn translate_type_and_code_6_to_4(
     icmp_type: Icmpv6Type,
     icmp_code: Icmpv6Code,
 ) -> Result<(IcmpType, IcmpCode)> {
+    if let Some((to_type, to_code)) = find_override(&OVERRIDES_6_TO_4, (icmp_type.0, icmp_code.0)) {
+        return Ok((IcmpType(to_type), IcmpCode(to_code)));
+    }
+
     match (icmp_type, icmp_code) {
         // Echo Request
         (Icmpv6Types::EchoRequest, _) => Ok((IcmpTypes::EchoRequest, IcmpCode(0))),
@@ -101,3 +153,39 @@ pub fn translate_type_and_code_6_to_4(
         (icmp_type, _) => Err(Error::UnsupportedIcmpv6Type(icmp_type.0)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_override() {
+        // Tests run in parallel, so these are kept out of the global tables
+        let overrides = RwLock::new(vec![
+            TypeCodeOverride {
+                from_type: 3,
+                from_code: None,
+                to_type: 1,
+                to_code: 3,
+            },
+            TypeCodeOverride {
+                from_type: 3,
+                from_code: Some(13),
+                to_type: 1,
+                to_code: 4,
+            },
+        ]);
+
+        // Specific codes win over whole types
+        assert_eq!(find_override(&overrides, (3, 13)), Some((1, 4)));
+        assert_eq!(find_override(&overrides, (3, 0)), Some((1, 3)));
+
+        // Everything else is left to the built-in translations
+        assert_eq!(find_override(&overrides, (11, 0)), None);
+        assert_eq!(
+            translate_type_and_code_4_to_6(IcmpTypes::DestinationUnreachable, IcmpCode(13))
+                .unwrap(),
+            (Icmpv6Types::DestinationUnreachable, Icmpv6Code(1))
+        );
+    }
+}
@@ -6,15 +6,21 @@ use super::{
     udp::{recalculate_udp_checksum_ipv4, recalculate_udp_checksum_ipv6},
 };
 use crate::error::{Error, Result};
-use pnet::packet::{
+use pnet_packet::{
+    ip::IpNextHeaderProtocol,
     ip::IpNextHeaderProtocols,
     ipv4::{self, Ipv4Packet, MutableIpv4Packet},
-    ipv6::{Ipv6Packet, MutableIpv6Packet},
+    ipv6::{FragmentPacket, Ipv6Packet, MutableIpv6Packet},
     Packet,
 };
 use std::net::{Ipv4Addr, Ipv6Addr};
 
+/// Bits of a fragment header's offset and flags that are set in any real fragment (everything but the reserved bits)
+const FRAGMENT_OFFSET_AND_MORE_FRAGMENTS: u16 = 0xfff9;
+
 /// Translates an IPv4 packet into an IPv6 packet. The packet payload will be translated recursively as needed.
+///
+/// No fragment header is ever added, since atomic fragments are deprecated (RFC8021).
 #[profiling::function]
 pub fn translate_ipv4_to_ipv6(
     ipv4_packet: &[u8],
@@ -54,6 +60,13 @@ pub fn translate_ipv4_to_ipv6(
             }
         };
 
+        // Translation can grow the payload (for example, when an ICMP error quotes a packet)
+        let payload_length =
+            u16::try_from(new_payload.len()).map_err(|_| Error::PacketTooLong {
+                maximum: u16::MAX.into(),
+                actual: new_payload.len(),
+            })?;
+
         // Build a buffer to store the new IPv6 packet
         let mut output_buffer = vec![0u8; Ipv6Packet::minimum_packet_size() + new_payload.len()];
 
@@ -70,7 +83,7 @@ pub fn translate_ipv4_to_ipv6(
         ipv6_packet.set_hop_limit(ipv4_packet.get_ttl());
         ipv6_packet.set_source(new_source);
         ipv6_packet.set_destination(new_destination);
-        ipv6_packet.set_payload_length(new_payload.len().try_into().unwrap());
+        ipv6_packet.set_payload_length(payload_length);
 
         // Copy the payload to the buffer
         ipv6_packet.set_payload(&new_payload);
@@ -107,33 +120,44 @@ pub fn translate_ipv6_to_ipv4(
             actual: ipv6_packet.len(),
         })?;
 
+        // Atomic fragments (RFC6946) have a fragment header without being fragmented, so it is simply removed
+        let (next_header, payload) = skip_atomic_fragment_header(&ipv6_packet)?;
+
         // Perform recursive translation to determine the new payload
-        let new_payload = match ipv6_packet.get_next_header() {
+        let new_payload = match next_header {
             // Pass ICMP packets to the icmpv6-to-icmp translator
             IpNextHeaderProtocols::Icmpv6 => {
-                translate_icmpv6_to_icmp(ipv6_packet.payload(), new_source, new_destination)?
+                translate_icmpv6_to_icmp(payload, new_source, new_destination)?
             }
 
             // Pass TCP packets to the tcp translator
             IpNextHeaderProtocols::Tcp => {
-                recalculate_tcp_checksum_ipv4(ipv6_packet.payload(), new_source, new_destination)?
+                recalculate_tcp_checksum_ipv4(payload, new_source, new_destination)?
             }
 
             // Pass UDP packets to the udp translator
             IpNextHeaderProtocols::Udp => {
-                recalculate_udp_checksum_ipv4(ipv6_packet.payload(), new_source, new_destination)?
+                recalculate_udp_checksum_ipv4(payload, new_source, new_destination)?
             }
 
             // If the next header is not something we know how to translate,
             // just assume the payload can be passed through as-is
             protocol => {
                 log::warn!("Unsupported next header: {:?}", protocol);
-                ipv6_packet.payload().to_vec()
+                payload.to_vec()
             }
         };
 
+        // The IPv6 payload may be too large to fit behind an IPv4 header
+        let total_length: u16 = (Ipv4Packet::minimum_packet_size() + new_payload.len())
+            .try_into()
+            .map_err(|_| Error::PacketTooLong {
+                maximum: u16::MAX.into(),
+                actual: Ipv4Packet::minimum_packet_size() + new_payload.len(),
+            })?;
+
         // Build a buffer to store the new IPv4 packet
-        let mut output_buffer = vec![0u8; Ipv4Packet::minimum_packet_size() + new_payload.len()];
+        let mut output_buffer = vec![0u8; total_length.into()];
 
         // NOTE: There is no way this can fail since we are creating the buffer with explicitly enough space.
         let mut ipv4_packet =
@@ -143,17 +167,13 @@ pub fn translate_ipv6_to_ipv4(
         ipv4_packet.set_version(4);
         ipv4_packet.set_header_length(5);
         ipv4_packet.set_ttl(ipv6_packet.get_hop_limit());
-        ipv4_packet.set_next_level_protocol(match ipv6_packet.get_next_header() {
+        ipv4_packet.set_next_level_protocol(match next_header {
             IpNextHeaderProtocols::Icmpv6 => IpNextHeaderProtocols::Icmp,
             proto => proto,
         });
         ipv4_packet.set_source(new_source);
         ipv4_packet.set_destination(new_destination);
-        ipv4_packet.set_total_length(
-            (Ipv4Packet::minimum_packet_size() + new_payload.len())
-                .try_into()
-                .unwrap(),
-        );
+        ipv4_packet.set_total_length(total_length);
 
         // Copy the payload to the buffer
         ipv4_packet.set_payload(&new_payload);
@@ -177,3 +197,447 @@ pub fn translate_ipv6_to_ipv4(
         error
     })
 }
+
+/// Get the next header and payload of an IPv6 packet, looking past the fragment header of an atomic fragment.
+/// Packets that are really fragmented can't be translated.
+fn skip_atomic_fragment_header<'a>(
+    ipv6_packet: &'a Ipv6Packet,
+) -> Result<(IpNextHeaderProtocol, &'a [u8])> {
+    if ipv6_packet.get_next_header() != IpNextHeaderProtocols::Ipv6Frag {
+        return Ok((ipv6_packet.get_next_header(), ipv6_packet.payload()));
+    }
+
+    let payload = ipv6_packet.payload();
+    let fragment = FragmentPacket::new(payload).ok_or(Error::PacketTooShort {
+        expected: Ipv6Packet::minimum_packet_size() + FragmentPacket::minimum_packet_size(),
+        actual: Ipv6Packet::minimum_packet_size() + payload.len(),
+    })?;
+    if fragment.get_fragment_offset_with_flags() & FRAGMENT_OFFSET_AND_MORE_FRAGMENTS != 0 {
+        return Err(Error::FragmentedPacket);
+    }
+    Ok((
+        fragment.get_next_header(),
+        &payload[FragmentPacket::minimum_packet_size()..],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::{
+        icmp::{self, IcmpPacket},
+        icmpv6::{self, Icmpv6Packet},
+        ip::IpNextHeaderProtocol,
+        tcp::{self, MutableTcpPacket, TcpPacket},
+        udp::{self, MutableUdpPacket, UdpPacket},
+    };
+    use proptest::prelude::*;
+
+    /// Addresses of a generated packet, which also decide its IP version
+    #[derive(Debug, Clone, Copy)]
+    enum Addresses {
+        V4(Ipv4Addr, Ipv4Addr),
+        V6(Ipv6Addr, Ipv6Addr),
+    }
+
+    /// Transport layer of a generated packet
+    #[derive(Debug, Clone)]
+    enum Transport {
+        Tcp {
+            ports: (u16, u16),
+            sequence: u32,
+            acknowledgement: u32,
+            flags: u8,
+            window: u16,
+            payload: Vec<u8>,
+        },
+        Udp {
+            ports: (u16, u16),
+            payload: Vec<u8>,
+        },
+        Echo {
+            reply: bool,
+            rest_of_header: u32,
+            payload: Vec<u8>,
+        },
+        /// A Time Exceeded error quoting another packet between the same addresses
+        TimeExceeded {
+            code: u8,
+            unused: u32,
+            ttl: u8,
+            quoted: Box<Transport>,
+        },
+    }
+
+    /// Build a complete IP packet with valid checksums
+    fn build_packet(addresses: Addresses, ttl: u8, transport: &Transport) -> Vec<u8> {
+        let (protocol, segment) = build_transport(addresses, transport);
+        match addresses {
+            Addresses::V4(source, destination) => {
+                let mut buffer = vec![0u8; Ipv4Packet::minimum_packet_size() + segment.len()];
+                let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+                packet.set_version(4);
+                packet.set_header_length(5);
+                packet.set_total_length(u16::try_from(20 + segment.len()).unwrap());
+                packet.set_ttl(ttl);
+                packet.set_next_level_protocol(protocol);
+                packet.set_source(source);
+                packet.set_destination(destination);
+                packet.set_payload(&segment);
+                packet.set_checksum(ipv4::checksum(&packet.to_immutable()));
+                buffer
+            }
+            Addresses::V6(source, destination) => {
+                let mut buffer = vec![0u8; Ipv6Packet::minimum_packet_size() + segment.len()];
+                let mut packet = MutableIpv6Packet::new(&mut buffer).unwrap();
+                packet.set_version(6);
+                packet.set_payload_length(u16::try_from(segment.len()).unwrap());
+                packet.set_next_header(protocol);
+                packet.set_hop_limit(ttl);
+                packet.set_source(source);
+                packet.set_destination(destination);
+                packet.set_payload(&segment);
+                buffer
+            }
+        }
+    }
+
+    /// Build a transport layer segment, with a checksum covering the pseudo-header where needed
+    fn build_transport(
+        addresses: Addresses,
+        transport: &Transport,
+    ) -> (IpNextHeaderProtocol, Vec<u8>) {
+        match transport {
+            Transport::Tcp {
+                ports,
+                sequence,
+                acknowledgement,
+                flags,
+                window,
+                payload,
+            } => {
+                let mut buffer = vec![0u8; TcpPacket::minimum_packet_size() + payload.len()];
+                let mut packet = MutableTcpPacket::new(&mut buffer).unwrap();
+                packet.set_source(ports.0);
+                packet.set_destination(ports.1);
+                packet.set_sequence(*sequence);
+                packet.set_acknowledgement(*acknowledgement);
+                packet.set_data_offset(5);
+                packet.set_flags(*flags);
+                packet.set_window(*window);
+                packet.set_payload(payload);
+                packet.set_checksum(match addresses {
+                    Addresses::V4(source, destination) => {
+                        tcp::ipv4_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                    Addresses::V6(source, destination) => {
+                        tcp::ipv6_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                });
+                (IpNextHeaderProtocols::Tcp, buffer)
+            }
+            Transport::Udp { ports, payload } => {
+                let mut buffer = vec![0u8; UdpPacket::minimum_packet_size() + payload.len()];
+                let mut packet = MutableUdpPacket::new(&mut buffer).unwrap();
+                packet.set_source(ports.0);
+                packet.set_destination(ports.1);
+                packet.set_length(u16::try_from(8 + payload.len()).unwrap());
+                packet.set_payload(payload);
+                packet.set_checksum(match addresses {
+                    Addresses::V4(source, destination) => {
+                        udp::ipv4_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                    Addresses::V6(source, destination) => {
+                        udp::ipv6_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                });
+                (IpNextHeaderProtocols::Udp, buffer)
+            }
+            Transport::Echo {
+                reply,
+                rest_of_header,
+                payload,
+            } => {
+                let icmp_type = match (addresses, reply) {
+                    (Addresses::V4(..), false) => 8,
+                    (Addresses::V4(..), true) => 0,
+                    (Addresses::V6(..), false) => 128,
+                    (Addresses::V6(..), true) => 129,
+                };
+                build_icmp(addresses, icmp_type, 0, *rest_of_header, payload)
+            }
+            Transport::TimeExceeded {
+                code,
+                unused,
+                ttl,
+                quoted,
+            } => {
+                let icmp_type = match addresses {
+                    Addresses::V4(..) => 11,
+                    Addresses::V6(..) => 3,
+                };
+                let quoted = build_packet(addresses, *ttl, quoted);
+                build_icmp(addresses, icmp_type, *code, *unused, &quoted)
+            }
+        }
+    }
+
+    /// Build an ICMP or ICMPv6 message
+    fn build_icmp(
+        addresses: Addresses,
+        icmp_type: u8,
+        code: u8,
+        rest_of_header: u32,
+        payload: &[u8],
+    ) -> (IpNextHeaderProtocol, Vec<u8>) {
+        let mut buffer = [
+            &[icmp_type, code, 0, 0][..],
+            &rest_of_header.to_be_bytes(),
+            payload,
+        ]
+        .concat();
+        let (protocol, checksum) = match addresses {
+            Addresses::V4(..) => (
+                IpNextHeaderProtocols::Icmp,
+                icmp::checksum(&IcmpPacket::new(&buffer).unwrap()),
+            ),
+            Addresses::V6(source, destination) => (
+                IpNextHeaderProtocols::Icmpv6,
+                icmpv6::checksum(&Icmpv6Packet::new(&buffer).unwrap(), &source, &destination),
+            ),
+        };
+        buffer[2..4].copy_from_slice(&checksum.to_be_bytes());
+        (protocol, buffer)
+    }
+
+    /// Check that the one's complement sum of some data (including its checksum) is all ones
+    fn checksum_is_valid(data: &[u8]) -> bool {
+        let mut sum = data
+            .chunks(2)
+            .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+            .sum::<u32>();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum == 0xffff
+    }
+
+    /// Check every checksum in a translated packet independently of the code that calculated it
+    fn assert_checksums_valid(packet: &[u8]) {
+        let is_ipv4 = packet[0] >> 4 == 4;
+        let (pseudo_header, segment) = if is_ipv4 {
+            assert!(
+                checksum_is_valid(&packet[..20]),
+                "Invalid IPv4 header checksum"
+            );
+            let pseudo_header = [
+                &packet[12..20],
+                &[0, packet[9]],
+                &u16::try_from(packet.len() - 20).unwrap().to_be_bytes(),
+            ]
+            .concat();
+            (pseudo_header, &packet[20..])
+        } else {
+            let pseudo_header = [
+                &packet[8..40],
+                &u32::try_from(packet.len() - 40).unwrap().to_be_bytes(),
+                &[0, 0, 0, packet[6]],
+            ]
+            .concat();
+            (pseudo_header, &packet[40..])
+        };
+
+        // ICMP is the only transport protocol without a pseudo-header
+        if is_ipv4 && packet[9] == 1 {
+            assert!(checksum_is_valid(segment), "Invalid ICMP checksum");
+        } else {
+            assert!(
+                checksum_is_valid(&[&pseudo_header, segment].concat()),
+                "Invalid transport checksum"
+            );
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 0..512)
+    }
+
+    /// Payloads that only fit through jumbo frames
+    fn jumbo_payload() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 1500..9000)
+    }
+
+    fn jumbo_transport() -> impl Strategy<Value = Transport> {
+        prop_oneof![
+            (any::<(u16, u16)>(), any::<u32>(), jumbo_payload()).prop_map(
+                |(ports, sequence, payload)| Transport::Tcp {
+                    ports,
+                    sequence,
+                    acknowledgement: 0,
+                    flags: 0x10,
+                    window: 0xffff,
+                    payload,
+                }
+            ),
+            (any::<(u16, u16)>(), jumbo_payload())
+                .prop_map(|(ports, payload)| Transport::Udp { ports, payload }),
+            (any::<bool>(), any::<u32>(), jumbo_payload()).prop_map(
+                |(reply, rest_of_header, payload)| Transport::Echo {
+                    reply,
+                    rest_of_header,
+                    payload,
+                }
+            ),
+        ]
+    }
+
+    fn tcp_or_udp() -> impl Strategy<Value = Transport> {
+        prop_oneof![
+            (
+                any::<(u16, u16)>(),
+                any::<u32>(),
+                any::<u32>(),
+                any::<u8>(),
+                any::<u16>(),
+                payload()
+            )
+                .prop_map(
+                    |(ports, sequence, acknowledgement, flags, window, payload)| Transport::Tcp {
+                        ports,
+                        sequence,
+                        acknowledgement,
+                        flags,
+                        window,
+                        payload,
+                    }
+                ),
+            (any::<(u16, u16)>(), payload())
+                .prop_map(|(ports, payload)| Transport::Udp { ports, payload }),
+        ]
+    }
+
+    fn transport() -> impl Strategy<Value = Transport> {
+        prop_oneof![
+            tcp_or_udp(),
+            (any::<bool>(), any::<u32>(), payload()).prop_map(
+                |(reply, rest_of_header, payload)| {
+                    Transport::Echo {
+                        reply,
+                        rest_of_header,
+                        payload,
+                    }
+                }
+            ),
+            (0..=1u8, any::<u32>(), 1..=255u8, tcp_or_udp()).prop_map(
+                |(code, unused, ttl, quoted)| {
+                    Transport::TimeExceeded {
+                        code,
+                        unused,
+                        ttl,
+                        quoted: Box::new(quoted),
+                    }
+                }
+            ),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip_4_6_4(
+            ipv4: (Ipv4Addr, Ipv4Addr),
+            ipv6: (Ipv6Addr, Ipv6Addr),
+            ttl in 1..=255u8,
+            transport in transport(),
+        ) {
+            let original = build_packet(Addresses::V4(ipv4.0, ipv4.1), ttl, &transport);
+
+            // The IPv6 packet should be exactly what the same traffic would look like natively
+            let translated = translate_ipv4_to_ipv6(&original, ipv6.0, ipv6.1).unwrap();
+            assert_checksums_valid(&translated);
+            prop_assert_eq!(&translated, &build_packet(Addresses::V6(ipv6.0, ipv6.1), ttl, &transport));
+
+            // Translating back should give the original packet
+            let restored = translate_ipv6_to_ipv4(&translated, ipv4.0, ipv4.1).unwrap();
+            assert_checksums_valid(&restored);
+            prop_assert_eq!(restored, original);
+        }
+
+        #[test]
+        fn test_round_trip_6_4_6(
+            ipv6: (Ipv6Addr, Ipv6Addr),
+            ipv4: (Ipv4Addr, Ipv4Addr),
+            hop_limit in 1..=255u8,
+            transport in transport(),
+        ) {
+            let original = build_packet(Addresses::V6(ipv6.0, ipv6.1), hop_limit, &transport);
+
+            // The IPv4 packet should be exactly what the same traffic would look like natively
+            let translated = translate_ipv6_to_ipv4(&original, ipv4.0, ipv4.1).unwrap();
+            assert_checksums_valid(&translated);
+            prop_assert_eq!(&translated, &build_packet(Addresses::V4(ipv4.0, ipv4.1), hop_limit, &transport));
+
+            // Translating back should give the original packet
+            let restored = translate_ipv4_to_ipv6(&translated, ipv6.0, ipv6.1).unwrap();
+            assert_checksums_valid(&restored);
+            prop_assert_eq!(restored, original);
+        }
+
+        #[test]
+        fn test_round_trip_jumbo(
+            ipv4: (Ipv4Addr, Ipv4Addr),
+            ipv6: (Ipv6Addr, Ipv6Addr),
+            ttl in 1..=255u8,
+            transport in jumbo_transport(),
+        ) {
+            // Nothing about translation depends on packets fitting a 1500 byte MTU
+            let original = build_packet(Addresses::V4(ipv4.0, ipv4.1), ttl, &transport);
+            prop_assert!(original.len() > 1500);
+            let translated = translate_ipv4_to_ipv6(&original, ipv6.0, ipv6.1).unwrap();
+            assert_checksums_valid(&translated);
+            prop_assert_eq!(translated.len(), original.len() + 20);
+            prop_assert_eq!(&translated, &build_packet(Addresses::V6(ipv6.0, ipv6.1), ttl, &transport));
+
+            let restored = translate_ipv6_to_ipv4(&translated, ipv4.0, ipv4.1).unwrap();
+            assert_checksums_valid(&restored);
+            prop_assert_eq!(restored, original);
+        }
+
+        #[test]
+        fn test_atomic_fragment(
+            ipv6: (Ipv6Addr, Ipv6Addr),
+            ipv4: (Ipv4Addr, Ipv4Addr),
+            hop_limit in 1..=255u8,
+            transport in transport(),
+            id: u32,
+        ) {
+            // An atomic fragment translates exactly like the same packet without a fragment header
+            let original = build_packet(Addresses::V6(ipv6.0, ipv6.1), hop_limit, &transport);
+            let fragment = add_fragment_header(&original, 0, id);
+            prop_assert_eq!(
+                translate_ipv6_to_ipv4(&fragment, ipv4.0, ipv4.1).unwrap(),
+                translate_ipv6_to_ipv4(&original, ipv4.0, ipv4.1).unwrap()
+            );
+
+            // Real fragments are refused rather than being passed on with the wrong protocol
+            prop_assert_eq!(
+                translate_ipv6_to_ipv4(&add_fragment_header(&original, 1, id), ipv4.0, ipv4.1),
+                Err(Error::FragmentedPacket)
+            );
+        }
+    }
+
+    /// Insert a fragment header into an IPv6 packet, with the given fragment offset (in 8-byte units) and flags
+    fn add_fragment_header(packet: &[u8], offset_with_flags: u16, id: u32) -> Vec<u8> {
+        let mut header = vec![packet[6], 0];
+        header.extend_from_slice(&offset_with_flags.to_be_bytes());
+        header.extend_from_slice(&id.to_be_bytes());
+
+        let mut output = packet[..40].to_vec();
+        output[6] = IpNextHeaderProtocols::Ipv6Frag.0;
+        let payload_length = u16::from_be_bytes([output[4], output[5]]) + 8;
+        output[4..6].copy_from_slice(&payload_length.to_be_bytes());
+        output.extend_from_slice(&header);
+        output.extend_from_slice(&packet[40..]);
+        output
+    }
+}
@@ -1,4 +1,6 @@
 pub mod icmp;
 pub mod ip;
+pub mod ports;
 pub mod tcp;
+pub mod tcp_options;
 pub mod udp;
@@ -0,0 +1,309 @@
+//! Access to the ports (and ICMP echo identifiers) of IPv4 packets, for translators that also translate ports.
+//!
+//! ICMP errors are identified by the packet they quote, so that they can be matched up with the flow they are about.
+//! Checksums are updated incrementally ([RFC1624](https://datatracker.ietf.org/doc/html/rfc1624)) when a port changes.
+
+use pnet_packet::ip::IpNextHeaderProtocols;
+
+/// ICMP message types
+const ECHO_REPLY: u8 = 0;
+const DESTINATION_UNREACHABLE: u8 = 3;
+const ECHO_REQUEST: u8 = 8;
+const TIME_EXCEEDED: u8 = 11;
+const PARAMETER_PROBLEM: u8 = 12;
+
+/// Which way a packet is travelling through a port-translating NAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From an internal host. The source port (or echo request identifier) is the translated one.
+    Outbound,
+    /// Towards an internal host. The destination port (or echo reply identifier) is the translated one.
+    Inbound,
+}
+
+/// A checksum that covers a port
+#[derive(Debug, Clone, Copy)]
+struct Checksum {
+    offset: usize,
+    /// UDP checksums of zero mean "no checksum", so they are left alone (and never computed as zero)
+    udp: bool,
+}
+
+/// Where the translated port of a packet is, and what needs updating when it changes
+#[derive(Debug)]
+struct Location {
+    /// Protocol of the flow the port belongs to
+    protocol: u8,
+    /// Offset of the port within the packet
+    offset: usize,
+    /// Checksum of the transport header the port is in (if it is present)
+    checksum: Option<Checksum>,
+    /// When the port is inside a quoted packet, the checksum of the ICMP error quoting it
+    icmp_checksum: Option<usize>,
+}
+
+/// Get the protocol and translated port of an IPv4 packet. ICMP errors are reported with the protocol and port of
+/// the packet they quote.
+///
+/// Returns `None` for packets without ports, such as non-initial fragments and unsupported protocols.
+#[must_use]
+pub fn get_ipv4_port(packet: &[u8], direction: Direction) -> Option<(u8, u16)> {
+    let location = locate(packet, direction)?;
+    Some((location.protocol, read_u16(packet, location.offset)))
+}
+
+/// Replace the translated port of an IPv4 packet (see [`get_ipv4_port`]), updating any affected checksums.
+///
+/// Packets without ports are left alone.
+pub fn set_ipv4_port(packet: &mut [u8], direction: Direction, port: u16) {
+    let Some(location) = locate(packet, direction) else {
+        return;
+    };
+    let old_port = read_u16(packet, location.offset);
+    packet[location.offset..location.offset + 2].copy_from_slice(&port.to_be_bytes());
+
+    if let Some(checksum) = location
+        .checksum
+        .filter(|checksum| !checksum.udp || read_u16(packet, checksum.offset) != 0)
+    {
+        let old_checksum = read_u16(packet, checksum.offset);
+        adjust_checksum(packet, checksum, old_port, port);
+
+        // A quoted checksum is itself covered by the ICMP checksum
+        if let Some(icmp_checksum) = location.icmp_checksum {
+            let new_checksum = read_u16(packet, checksum.offset);
+            adjust_checksum(
+                packet,
+                Checksum {
+                    offset: icmp_checksum,
+                    udp: false,
+                },
+                old_checksum,
+                new_checksum,
+            );
+        }
+    }
+    if let Some(icmp_checksum) = location.icmp_checksum {
+        adjust_checksum(
+            packet,
+            Checksum {
+                offset: icmp_checksum,
+                udp: false,
+            },
+            old_port,
+            port,
+        );
+    }
+}
+
+/// Find the translated port of an IPv4 packet
+fn locate(packet: &[u8], direction: Direction) -> Option<Location> {
+    let (protocol, l4) = transport(packet)?;
+    match protocol {
+        // TCP and UDP start with the source port, followed by the destination port
+        6 | 17 => Some(Location {
+            protocol,
+            offset: l4
+                + match direction {
+                    Direction::Outbound => 0,
+                    Direction::Inbound => 2,
+                },
+            checksum: transport_checksum(packet, protocol, l4),
+            icmp_checksum: None,
+        })
+        .filter(|location| packet.len() >= location.offset + 2),
+        1 => {
+            let icmp_type = *packet.get(l4)?;
+            match (icmp_type, direction) {
+                (ECHO_REQUEST, Direction::Outbound) | (ECHO_REPLY, Direction::Inbound) => {
+                    (packet.len() >= l4 + 8).then_some(Location {
+                        protocol,
+                        offset: l4 + 4,
+                        checksum: Some(Checksum {
+                            offset: l4 + 2,
+                            udp: false,
+                        }),
+                        icmp_checksum: None,
+                    })
+                }
+                (DESTINATION_UNREACHABLE | TIME_EXCEEDED | PARAMETER_PROBLEM, _) => {
+                    locate_quoted(packet, l4, direction)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Find the translated port of the packet quoted by an ICMP error.
+///
+/// The quoted packet travelled the other way, so its ports are swapped.
+fn locate_quoted(packet: &[u8], l4: usize, direction: Direction) -> Option<Location> {
+    let inner = l4 + 8;
+    let (protocol, inner_l4) = transport(packet.get(inner..)?)?;
+    let inner_l4 = inner + inner_l4;
+    let (offset, checksum) = match protocol {
+        6 | 17 => (
+            inner_l4
+                + match direction {
+                    Direction::Outbound => 2,
+                    Direction::Inbound => 0,
+                },
+            transport_checksum(packet, protocol, inner_l4),
+        ),
+        1 => {
+            let inner_type = *packet.get(inner_l4)?;
+            match (inner_type, direction) {
+                (ECHO_REPLY, Direction::Outbound) | (ECHO_REQUEST, Direction::Inbound) => (
+                    inner_l4 + 4,
+                    (packet.len() >= inner_l4 + 4).then_some(Checksum {
+                        offset: inner_l4 + 2,
+                        udp: false,
+                    }),
+                ),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    (packet.len() >= offset + 2).then_some(Location {
+        protocol,
+        offset,
+        checksum,
+        icmp_checksum: Some(l4 + 2),
+    })
+}
+
+/// Get the protocol and transport header offset of an IPv4 packet, unless it is a non-initial fragment
+fn transport(packet: &[u8]) -> Option<(u8, usize)> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let fragment_offset = read_u16(packet, 6) & 0x1fff;
+    if fragment_offset != 0 {
+        return None;
+    }
+    let header_len = usize::from(packet[0] & 0x0f) * 4;
+    (header_len >= 20 && packet.len() > header_len).then_some((packet[9], header_len))
+}
+
+/// Find the checksum of a TCP or UDP header, if it is present
+fn transport_checksum(packet: &[u8], protocol: u8, l4: usize) -> Option<Checksum> {
+    let (offset, udp) = if protocol == IpNextHeaderProtocols::Udp.0 {
+        (l4 + 6, true)
+    } else {
+        (l4 + 16, false)
+    };
+    (packet.len() >= offset + 2).then_some(Checksum { offset, udp })
+}
+
+/// Update a checksum for one 16-bit word of the data it covers changing (RFC1624, equation 3)
+fn adjust_checksum(packet: &mut [u8], checksum: Checksum, old: u16, new: u16) {
+    let mut sum = u32::from(!read_u16(packet, checksum.offset)) + u32::from(!old) + u32::from(new);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    let mut result = !(sum as u16);
+    if checksum.udp && result == 0 {
+        result = 0xffff;
+    }
+    packet[checksum.offset..checksum.offset + 2].copy_from_slice(&result.to_be_bytes());
+}
+
+/// Read a big-endian 16-bit value
+fn read_u16(packet: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([packet[offset], packet[offset + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::{
+        icmp::{self, IcmpPacket, MutableIcmpPacket},
+        ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+        udp::{self, MutableUdpPacket, UdpPacket},
+        Packet,
+    };
+    use std::net::Ipv4Addr;
+
+    /// Build a UDP packet inside IPv4, with correct checksums
+    fn udp_ipv4(source: Ipv4Addr, destination: Ipv4Addr, ports: (u16, u16)) -> Vec<u8> {
+        let mut buffer = vec![0u8; 20 + 8 + 4];
+        {
+            let mut udp_packet = MutableUdpPacket::new(&mut buffer[20..]).unwrap();
+            udp_packet.set_source(ports.0);
+            udp_packet.set_destination(ports.1);
+            udp_packet.set_length(12);
+            udp_packet.set_payload(&[1, 2, 3, 4]);
+            let checksum = udp::ipv4_checksum(&udp_packet.to_immutable(), &source, &destination);
+            udp_packet.set_checksum(checksum);
+        }
+        let mut ipv4_packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        ipv4_packet.set_version(4);
+        ipv4_packet.set_header_length(5);
+        ipv4_packet.set_total_length(32);
+        ipv4_packet.set_ttl(64);
+        ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4_packet.set_source(source);
+        ipv4_packet.set_destination(destination);
+        ipv4_packet.set_checksum(ipv4::checksum(&ipv4_packet.to_immutable()));
+        buffer
+    }
+
+    #[test]
+    fn test_rewrite_udp_port() {
+        let (source, destination) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(198, 51, 100, 1));
+        let mut packet = udp_ipv4(source, destination, (40000, 53));
+        assert_eq!(
+            get_ipv4_port(&packet, Direction::Outbound),
+            Some((17, 40000))
+        );
+        assert_eq!(get_ipv4_port(&packet, Direction::Inbound), Some((17, 53)));
+
+        // The checksum should match one calculated from scratch
+        set_ipv4_port(&mut packet, Direction::Outbound, 2048);
+        let udp_packet = UdpPacket::new(&packet[20..]).unwrap();
+        assert_eq!(udp_packet.get_source(), 2048);
+        assert_eq!(
+            udp_packet.get_checksum(),
+            udp::ipv4_checksum(&udp_packet, &source, &destination)
+        );
+    }
+
+    #[test]
+    fn test_rewrite_quoted_port() {
+        // A Port Unreachable about a UDP packet sent from 192.0.2.1:2048
+        let (source, destination) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(198, 51, 100, 1));
+        let quoted = udp_ipv4(source, destination, (2048, 53));
+        let mut icmp = vec![0u8; 8 + quoted.len()];
+        {
+            let mut icmp_packet = MutableIcmpPacket::new(&mut icmp).unwrap();
+            icmp_packet.set_icmp_type(icmp::IcmpType(DESTINATION_UNREACHABLE));
+            icmp_packet.set_payload(&[&[0u8; 4][..], &quoted].concat());
+            let checksum = icmp::checksum(&icmp_packet.to_immutable());
+            icmp_packet.set_checksum(checksum);
+        }
+        let mut packet = [&udp_ipv4(destination, source, (0, 0))[..20], &icmp].concat();
+        let total_length = u16::try_from(packet.len()).unwrap();
+        let mut ipv4_packet = MutableIpv4Packet::new(&mut packet).unwrap();
+        ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        ipv4_packet.set_total_length(total_length);
+
+        // The error belongs to the flow of the quoted packet
+        assert_eq!(get_ipv4_port(&packet, Direction::Inbound), Some((17, 2048)));
+        set_ipv4_port(&mut packet, Direction::Inbound, 40000);
+
+        // Both the quoted UDP checksum and the ICMP checksum should still be correct
+        let ipv4_packet = Ipv4Packet::new(&packet).unwrap();
+        let icmp_packet = IcmpPacket::new(ipv4_packet.payload()).unwrap();
+        assert_eq!(icmp_packet.get_checksum(), icmp::checksum(&icmp_packet));
+        let quoted_udp = UdpPacket::new(&icmp_packet.payload()[4 + 20..]).unwrap();
+        assert_eq!(quoted_udp.get_source(), 40000);
+        assert_eq!(
+            quoted_udp.get_checksum(),
+            udp::ipv4_checksum(&quoted_udp, &source, &destination)
+        );
+    }
+}
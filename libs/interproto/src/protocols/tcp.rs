@@ -1,8 +1,15 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use pnet::packet::tcp::{self, MutableTcpPacket, TcpPacket};
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket},
+    Packet,
+};
 
-use crate::error::{Error, Result};
+use crate::{
+    error::{Error, Result},
+    protocols::icmp::errors::{build_ipv4, may_answer_ipv4, parse_ipv4},
+};
 
 /// Re-calculates a TCP packet's checksum with a new IPv6 pseudo-header.
 #[profiling::function]
@@ -92,9 +99,94 @@ pub fn recalculate_tcp_checksum_ipv4(
     })
 }
 
+/// Build a TCP RST refusing an IPv4 TCP SYN, sent back from the address the SYN was sent to (RFC9293 section
+/// 3.10.7.1).
+///
+/// Returns `None` unless the packet opens a connection (SYN without ACK or RST) and may be answered at all.
+#[profiling::function]
+pub fn tcp_reset_ipv4(original: &[u8]) -> Result<Option<Vec<u8>>> {
+    let original_packet = parse_ipv4(original)?;
+    if original_packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp
+        || !may_answer_ipv4(&original_packet)
+    {
+        return Ok(None);
+    }
+    let Some(syn) = TcpPacket::new(original_packet.payload()) else {
+        return Ok(None);
+    };
+    let flags = syn.get_flags();
+    if flags & TcpFlags::SYN == 0 || flags & (TcpFlags::ACK | TcpFlags::RST) != 0 {
+        return Ok(None);
+    }
+
+    // Acknowledge the SYN, and any data sent along with it
+    let data_len = original_packet
+        .payload()
+        .len()
+        .saturating_sub(usize::from(syn.get_data_offset()) * 4);
+    #[allow(clippy::cast_possible_truncation)]
+    let acknowledgement = syn
+        .get_sequence()
+        .wrapping_add(1)
+        .wrapping_add(data_len as u32);
+
+    let mut buffer = vec![0u8; TcpPacket::minimum_packet_size()];
+    let mut reset = unsafe { MutableTcpPacket::new(&mut buffer).unwrap_unchecked() };
+    reset.set_source(syn.get_destination());
+    reset.set_destination(syn.get_source());
+    reset.set_acknowledgement(acknowledgement);
+    reset.set_data_offset(5);
+    reset.set_flags(TcpFlags::RST | TcpFlags::ACK);
+    reset.set_checksum(tcp::ipv4_checksum(
+        &reset.to_immutable(),
+        &original_packet.get_destination(),
+        &original_packet.get_source(),
+    ));
+
+    Ok(Some(build_ipv4(
+        original_packet.get_destination(),
+        original_packet.get_source(),
+        IpNextHeaderProtocols::Tcp,
+        &buffer,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pnet_packet::ipv4::Ipv4Packet;
+
+    #[test]
+    fn test_tcp_reset_ipv4() {
+        let client: Ipv4Addr = "198.51.100.1".parse().unwrap();
+        let server: Ipv4Addr = "192.0.2.1".parse().unwrap();
+        let mut segment = vec![0u8; TcpPacket::minimum_packet_size()];
+        let mut syn_packet = MutableTcpPacket::new(&mut segment).unwrap();
+        syn_packet.set_source(40000);
+        syn_packet.set_destination(22);
+        syn_packet.set_sequence(1000);
+        syn_packet.set_data_offset(5);
+        syn_packet.set_flags(TcpFlags::SYN);
+        let syn = build_ipv4(client, server, IpNextHeaderProtocols::Tcp, &segment);
+
+        let reply = tcp_reset_ipv4(&syn).unwrap().unwrap();
+        let reply_packet = Ipv4Packet::new(&reply).unwrap();
+        assert_eq!(reply_packet.get_source(), server);
+        assert_eq!(reply_packet.get_destination(), client);
+        let reset = TcpPacket::new(reply_packet.payload()).unwrap();
+        assert_eq!(reset.get_source(), 22);
+        assert_eq!(reset.get_destination(), 40000);
+        assert_eq!(reset.get_flags(), TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(reset.get_sequence(), 0);
+        assert_eq!(reset.get_acknowledgement(), 1001);
+        assert_eq!(
+            reset.get_checksum(),
+            tcp::ipv4_checksum(&reset, &server, &client)
+        );
+
+        // Only connection attempts are refused, and resets are never answered
+        assert_eq!(tcp_reset_ipv4(&reply).unwrap(), None);
+    }
 
     #[test]
     fn test_checksum_recalculate_ipv6() {
@@ -0,0 +1,281 @@
+//! Sanitization of TCP options that don't survive address translation.
+//!
+//! Some options are bound to the addresses of a connection, such as TCP MD5 signatures (RFC2385) and TCP-AO
+//! (RFC5925), which cover the IP pseudo-header. Once translated, every segment carrying them fails verification and
+//! the connection hangs. Experimental and unrecognised options may also upset middleboxes on the far side.
+//!
+//! Options are stripped by overwriting them with NOPs, so that the header keeps its length and nothing else in the
+//! segment moves. The TCP checksum is updated incrementally ([RFC1624](https://datatracker.ietf.org/doc/html/rfc1624)).
+
+use pnet_packet::ip::IpNextHeaderProtocols;
+
+/// Option kinds
+const END_OF_OPTIONS: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const MD5_SIGNATURE: u8 = 19;
+const AUTHENTICATION: u8 = 29;
+
+/// Option kinds that are understood and safe to pass through a translator
+const KNOWN_KINDS: [u8; 9] = [
+    2,  // Maximum segment size
+    3,  // Window scale
+    4,  // SACK permitted
+    5,  // SACK
+    8,  // Timestamps
+    27, // Quick-Start response
+    28, // User timeout
+    30, // Multipath TCP
+    34, // Fast open cookie
+];
+
+/// What to do with a class of TCP options
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OptionAction {
+    /// Pass it through untouched
+    #[default]
+    Keep,
+    /// Pass it through, but report it
+    Flag,
+    /// Remove it
+    Strip,
+}
+
+/// How each class of TCP options is handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptionPolicy {
+    /// Options that sign the segment along with its addresses (MD5 signatures and TCP-AO)
+    pub signatures: OptionAction,
+    /// Experimental (RFC4727) and unrecognised options
+    pub experimental: OptionAction,
+}
+
+impl OptionPolicy {
+    /// Check if this policy would leave every option alone
+    #[must_use]
+    pub fn is_noop(self) -> bool {
+        self.signatures == OptionAction::Keep && self.experimental == OptionAction::Keep
+    }
+
+    /// Decide what to do with an option kind
+    fn action(self, kind: u8) -> OptionAction {
+        match kind {
+            MD5_SIGNATURE | AUTHENTICATION => self.signatures,
+            kind if KNOWN_KINDS.contains(&kind) => OptionAction::Keep,
+            _ => self.experimental,
+        }
+    }
+}
+
+/// What was done to the options of a segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptionReport {
+    /// Options that were left in place, but should be reported
+    pub flagged: usize,
+    /// Options that were removed
+    pub stripped: usize,
+}
+
+/// Apply a policy to the TCP options of an IPv4 or IPv6 packet.
+///
+/// Packets that aren't TCP (including non-initial fragments, and IPv6 packets with extension headers) are left alone,
+/// as are any options after one that is malformed.
+pub fn sanitize_tcp_options(packet: &mut [u8], policy: OptionPolicy) -> OptionReport {
+    let mut report = OptionReport::default();
+    if policy.is_noop() {
+        return report;
+    }
+    let Some(l4) = tcp_header(packet) else {
+        return report;
+    };
+    let options_end = l4 + usize::from(packet[l4 + 12] >> 4) * 4;
+    if options_end > packet.len() {
+        return report;
+    }
+
+    let mut offset = l4 + 20;
+    while offset < options_end {
+        let kind = packet[offset];
+        match kind {
+            END_OF_OPTIONS => break,
+            NO_OPERATION => {
+                offset += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let Some(&len) = packet.get(offset + 1) else {
+            break;
+        };
+        let len = usize::from(len);
+        if len < 2 || offset + len > options_end {
+            break;
+        }
+
+        match policy.action(kind) {
+            OptionAction::Keep => {}
+            OptionAction::Flag => report.flagged += 1,
+            OptionAction::Strip => {
+                strip(packet, l4, offset, len);
+                report.stripped += 1;
+            }
+        }
+        offset += len;
+    }
+    report
+}
+
+/// Find the start of the TCP header of a packet
+fn tcp_header(packet: &[u8]) -> Option<usize> {
+    let l4 = match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+            if packet[9] != IpNextHeaderProtocols::Tcp.0 || fragment_offset != 0 {
+                return None;
+            }
+            usize::from(packet[0] & 0x0f) * 4
+        }
+        6 if packet.len() >= 40 => {
+            if packet[6] != IpNextHeaderProtocols::Tcp.0 {
+                return None;
+            }
+            40
+        }
+        _ => return None,
+    };
+    (packet.len() >= l4 + 20).then_some(l4)
+}
+
+/// Overwrite an option with NOPs, updating the TCP checksum to match
+fn strip(packet: &mut [u8], l4: usize, offset: usize, len: usize) {
+    // Options start on an even offset into the header, but may end on an odd one
+    let first_word = l4 + (offset - l4) / 2 * 2;
+    let last_word = l4 + (offset + len - l4).div_ceil(2) * 2;
+    let old: Vec<u8> = packet[first_word..last_word].to_vec();
+    packet[offset..offset + len].fill(NO_OPERATION);
+
+    let mut sum = u32::from(!u16::from_be_bytes([packet[l4 + 16], packet[l4 + 17]]));
+    for (old, new) in old
+        .chunks_exact(2)
+        .zip(packet[first_word..last_word].chunks_exact(2))
+    {
+        sum += u32::from(!u16::from_be_bytes([old[0], old[1]]));
+        sum += u32::from(u16::from_be_bytes([new[0], new[1]]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    let checksum = !(sum as u16);
+    packet[l4 + 16..l4 + 18].copy_from_slice(&checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::{
+        ipv4::{self, MutableIpv4Packet},
+        tcp::{self, MutableTcpPacket, TcpPacket},
+        MutablePacket,
+    };
+    use std::net::Ipv4Addr;
+
+    /// Build a TCP segment inside IPv4 carrying the given options, with correct checksums
+    fn tcp_ipv4(options: &[u8]) -> Vec<u8> {
+        let source = Ipv4Addr::new(192, 0, 2, 1);
+        let destination = Ipv4Addr::new(198, 51, 100, 1);
+        let mut buffer = vec![0u8; 20 + 20 + options.len() + 3];
+        {
+            let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+            packet.set_version(4);
+            packet.set_header_length(5);
+            packet.set_total_length(u16::try_from(20 + 20 + options.len() + 3).unwrap());
+            packet.set_ttl(64);
+            packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            packet.set_source(source);
+            packet.set_destination(destination);
+            packet.set_checksum(ipv4::checksum(&packet.to_immutable()));
+        }
+        {
+            let mut segment = MutableTcpPacket::new(&mut buffer[20..]).unwrap();
+            segment.set_source(12345);
+            segment.set_destination(80);
+            segment.set_data_offset(u8::try_from(5 + options.len() / 4).unwrap());
+            segment.set_flags(tcp::TcpFlags::SYN);
+            segment.set_window(65535);
+            segment.packet_mut()[20..20 + options.len()].copy_from_slice(options);
+            segment.set_payload(b"abc");
+            let checksum = tcp::ipv4_checksum(&segment.to_immutable(), &source, &destination);
+            segment.set_checksum(checksum);
+        }
+        buffer
+    }
+
+    /// Check that the TCP checksum of an IPv4 packet is correct
+    fn checksum_is_valid(packet: &[u8]) -> bool {
+        let segment = TcpPacket::new(&packet[20..]).unwrap();
+        segment.get_checksum()
+            == tcp::ipv4_checksum(
+                &segment,
+                &Ipv4Addr::new(192, 0, 2, 1),
+                &Ipv4Addr::new(198, 51, 100, 1),
+            )
+    }
+
+    #[test]
+    fn test_strip_signature() {
+        // MSS, an MD5 signature, two NOPs for alignment, then an experimental option
+        let mut options = vec![2, 4, 0x05, 0xb4, 19, 18];
+        options.extend_from_slice(&[0xaa; 16]);
+        options.extend_from_slice(&[1, 1, 254, 4, 0xbe, 0xef]);
+        let mut packet = tcp_ipv4(&options);
+
+        let report = sanitize_tcp_options(
+            &mut packet,
+            OptionPolicy {
+                signatures: OptionAction::Strip,
+                experimental: OptionAction::Flag,
+            },
+        );
+        assert_eq!(
+            report,
+            OptionReport {
+                flagged: 1,
+                stripped: 1
+            }
+        );
+        assert_eq!(&packet[40..44], &[2, 4, 0x05, 0xb4]);
+        assert!(packet[44..62].iter().all(|byte| *byte == NO_OPERATION));
+        assert_eq!(&packet[62..68], &[1, 1, 254, 4, 0xbe, 0xef]);
+        assert!(checksum_is_valid(&packet));
+    }
+
+    #[test]
+    fn test_strip_odd_offset() {
+        // An experimental option that starts after a single NOP, so its words are shared with its neighbours
+        let mut packet = tcp_ipv4(&[1, 253, 4, 0xbe, 0xef, 2, 4, 0x05, 0xb4, 0, 0, 0]);
+        let report = sanitize_tcp_options(
+            &mut packet,
+            OptionPolicy {
+                signatures: OptionAction::Keep,
+                experimental: OptionAction::Strip,
+            },
+        );
+        assert_eq!(report.stripped, 1);
+        assert_eq!(&packet[40..49], &[1, 1, 1, 1, 1, 2, 4, 0x05, 0xb4]);
+        assert!(checksum_is_valid(&packet));
+    }
+
+    #[test]
+    fn test_keep_by_default() {
+        let mut options = vec![19, 18];
+        options.extend_from_slice(&[0xaa; 16]);
+        options.extend_from_slice(&[0, 0]);
+        let original = tcp_ipv4(&options);
+        let mut packet = original.clone();
+        assert_eq!(
+            sanitize_tcp_options(&mut packet, OptionPolicy::default()),
+            OptionReport::default()
+        );
+        assert_eq!(packet, original);
+    }
+}
@@ -1,6 +1,6 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet_packet::udp::{self, MutableUdpPacket, UdpPacket};
 
 use crate::error::{Error, Result};
 
@@ -0,0 +1,138 @@
+//! Process health tracking for liveness and readiness probes
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Set once the TUN interface has been brought up
+static TUN_UP: AtomicBool = AtomicBool::new(false);
+
+/// Set once all routes towards the TUN interface have been installed
+static ROUTES_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Cleared while upstream health probes are failing
+static UPSTREAM_HEALTHY: AtomicBool = AtomicBool::new(true);
+
+/// Set once the process has begun shutting down gracefully
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Number of worker threads that are currently running
+static WORKERS_ALIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of worker threads that have exited (workers are never expected to exit)
+static WORKERS_EXITED: AtomicUsize = AtomicUsize::new(0);
+
+/// Record that the TUN interface is up
+pub fn set_tun_up() {
+    TUN_UP.store(true, Ordering::Relaxed);
+}
+
+/// Record that all routes have been installed
+pub fn set_routes_installed() {
+    ROUTES_INSTALLED.store(true, Ordering::Relaxed);
+}
+
+/// Record that routes have been withdrawn, such as when demoted to standby
+pub fn clear_routes_installed() {
+    ROUTES_INSTALLED.store(false, Ordering::Relaxed);
+}
+
+/// Record whether upstream health probes are passing
+pub fn set_upstream_healthy(healthy: bool) {
+    UPSTREAM_HEALTHY.store(healthy, Ordering::Relaxed);
+}
+
+/// Record that the process is shutting down and should no longer receive new traffic
+pub fn set_draining() {
+    DRAINING.store(true, Ordering::Relaxed);
+}
+
+/// Check if the process is shutting down
+#[must_use]
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Marks a worker thread as alive for as long as it is held
+///
+/// The guard should be created at the top of a worker thread. If the thread exits (including by panicking),
+/// the guard is dropped and the process is reported as unhealthy.
+#[derive(Debug)]
+pub struct WorkerGuard {
+    _private: (),
+}
+
+impl WorkerGuard {
+    /// Register the current thread as a running worker
+    #[must_use]
+    pub fn new() -> Self {
+        WORKERS_ALIVE.fetch_add(1, Ordering::Relaxed);
+        Self { _private: () }
+    }
+}
+
+impl Default for WorkerGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        WORKERS_ALIVE.fetch_sub(1, Ordering::Relaxed);
+        WORKERS_EXITED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A point-in-time view of process health
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct HealthReport {
+    pub tun_up: bool,
+    pub routes_installed: bool,
+    pub upstream_healthy: bool,
+    pub draining: bool,
+    pub workers_alive: usize,
+    pub workers_exited: usize,
+}
+
+impl HealthReport {
+    /// Read the current health state
+    #[must_use]
+    pub fn current() -> Self {
+        Self {
+            tun_up: TUN_UP.load(Ordering::Relaxed),
+            routes_installed: ROUTES_INSTALLED.load(Ordering::Relaxed),
+            upstream_healthy: UPSTREAM_HEALTHY.load(Ordering::Relaxed),
+            draining: DRAINING.load(Ordering::Relaxed),
+            workers_alive: WORKERS_ALIVE.load(Ordering::Relaxed),
+            workers_exited: WORKERS_EXITED.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The process is live as long as none of its workers have died
+    #[must_use]
+    pub fn is_live(&self) -> bool {
+        self.workers_exited == 0
+    }
+
+    /// The process is ready once it is fully set up and translating packets with a working upstream, until it starts draining
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.is_live()
+            && self.tun_up
+            && self.routes_installed
+            && self.upstream_healthy
+            && !self.draining
+            && self.workers_alive > 0
+    }
+}
+
+impl std::fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "tun_up: {}", self.tun_up)?;
+        writeln!(f, "routes_installed: {}", self.routes_installed)?;
+        writeln!(f, "upstream_healthy: {}", self.upstream_healthy)?;
+        writeln!(f, "draining: {}", self.draining)?;
+        writeln!(f, "workers_alive: {}", self.workers_alive)?;
+        writeln!(f, "workers_exited: {}", self.workers_exited)
+    }
+}
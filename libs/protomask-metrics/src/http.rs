@@ -5,11 +5,32 @@ use hyper::{
 use prometheus::{Encoder, TextEncoder};
 use std::{convert::Infallible, net::SocketAddr};
 
+use crate::health::HealthReport;
+
 /// Handle an HTTP request
 #[allow(clippy::unused_async)]
-async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+async fn handle_request(
+    request: Request<Body>,
+    serve_metrics: bool,
+) -> Result<Response<Body>, Infallible> {
+    // If the request is targeting one of the health endpoints
+    if request.method() == Method::GET {
+        let report = HealthReport::current();
+        let healthy = match request.uri().path() {
+            "/healthz" => Some(report.is_live()),
+            "/readyz" => Some(report.is_ready()),
+            _ => None,
+        };
+        if let Some(healthy) = healthy {
+            return Ok(Response::builder()
+                .status(if healthy { 200 } else { 503 })
+                .body(Body::from(report.to_string()))
+                .unwrap());
+        }
+    }
+
     // If the request is targeting the metrics endpoint
-    if request.method() == Method::GET && request.uri().path() == "/metrics" {
+    if serve_metrics && request.method() == Method::GET && request.uri().path() == "/metrics" {
         // Gather metrics
         let metric_families = prometheus::gather();
         let body = {
@@ -23,6 +44,15 @@ async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infall
         return Ok(Response::new(Body::from(body)));
     }
 
+    // If the request is for a CPU profile
+    #[cfg(feature = "pprof")]
+    if serve_metrics
+        && request.method() == Method::GET
+        && request.uri().path() == "/debug/pprof/profile"
+    {
+        return Ok(crate::pprof::profile(request.uri().query()).await);
+    }
+
     // Otherwise, just return a 404
     Ok(Response::builder()
         .status(404)
@@ -30,11 +60,24 @@ async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infall
         .unwrap())
 }
 
-/// Bring up an HTTP server that listens for metrics requests
+/// Bring up an HTTP server that listens for metrics and health requests
 pub async fn serve_metrics(bind_addr: SocketAddr) {
+    serve(bind_addr, true).await;
+}
+
+/// Bring up an HTTP server that only answers health requests
+pub async fn serve_health(bind_addr: SocketAddr) {
+    serve(bind_addr, false).await;
+}
+
+/// Run an HTTP server, optionally exposing the metrics endpoint
+async fn serve(bind_addr: SocketAddr, serve_metrics: bool) {
     // Set up the server
-    let make_service =
-        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request| {
+            handle_request(request, serve_metrics)
+        }))
+    });
     let server = Server::bind(&bind_addr).serve(make_service);
 
     // Run the server
@@ -5,8 +5,11 @@
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::doc_markdown)]
 
+pub mod health;
 pub mod http;
 pub mod metrics;
+#[cfg(feature = "pprof")]
+pub mod pprof;
 
 #[macro_use]
 pub mod macros;
@@ -13,11 +13,48 @@ pub mod label_values {
     pub const PROTOCOL_TCP: &str = "tcp";
     /// UDP protocol
     pub const PROTOCOL_UDP: &str = "udp";
+    /// Any other transport protocol
+    pub const PROTOCOL_OTHER: &str = "other";
 
     /// Dropped status
     pub const STATUS_DROPPED: &str = "dropped";
     /// Translated status
     pub const STATUS_TRANSLATED: &str = "translated";
+
+    /// Traffic from an IPv6 client towards the IPv4 internet
+    pub const DIRECTION_OUTBOUND: &str = "outbound";
+    /// Traffic from the IPv4 internet towards an IPv6 client
+    pub const DIRECTION_INBOUND: &str = "inbound";
+
+    /// Checking that a packet is well-formed before handling it
+    pub const STAGE_PARSE: &str = "parse";
+    /// Answering or dropping packets on behalf of a router hop
+    pub const STAGE_HOP: &str = "hop";
+    /// Address mapping and protocol translation
+    pub const STAGE_TRANSLATE: &str = "translate";
+    /// Traffic accounting and flow export
+    pub const STAGE_ACCOUNTING: &str = "accounting";
+    /// Writing the translated packet out
+    pub const STAGE_WRITE: &str = "write";
+
+    /// Mapping events waiting to be written to the session log
+    pub const CHANNEL_SESSION_LOG: &str = "session_log";
+    /// Mapping events waiting to be sent to standbys
+    pub const CHANNEL_REPLICATION: &str = "replication";
+    /// Packets read from the TUN interface, waiting to be translated
+    pub const CHANNEL_INGRESS: &str = "ingress";
+    /// Translated packets waiting to be written to the TUN interface
+    pub const CHANNEL_EGRESS: &str = "egress";
+
+    /// A probe that was answered
+    pub const RESULT_ANSWERED: &str = "answered";
+    /// A probe that was not answered in time
+    pub const RESULT_FAILED: &str = "failed";
+
+    /// A tenant's limit on mappings
+    pub const LIMIT_MAPPINGS: &str = "mappings";
+    /// A tenant's limit on sessions of shared addresses
+    pub const LIMIT_SESSIONS: &str = "sessions";
 }
 
 lazy_static! {
@@ -28,10 +65,160 @@ lazy_static! {
         &["protocol", "status"]
     ).unwrap();
 
+    /// Counter for the number of packets translated for each subscriber (when addresses are aggregated by subscriber)
+    pub static ref SUBSCRIBER_PACKET_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_subscriber_packets",
+        "Number of packets translated for each subscriber",
+        &["subscriber", "protocol"]
+    ).unwrap();
+
+    /// Counter for the number of bytes translated for each subscriber (when addresses are aggregated by subscriber)
+    pub static ref SUBSCRIBER_BYTE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_subscriber_bytes",
+        "Number of bytes translated for each subscriber",
+        &["subscriber", "protocol"]
+    ).unwrap();
+
+    /// Counter for the number of packets translated for each tenant
+    pub static ref TENANT_PACKET_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_tenant_packets",
+        "Number of packets translated for each tenant",
+        &["tenant", "direction"]
+    ).unwrap();
+
+    /// Counter for the number of bytes translated for each tenant
+    pub static ref TENANT_BYTE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_tenant_bytes",
+        "Number of bytes translated for each tenant",
+        &["tenant", "direction"]
+    ).unwrap();
+
+    /// Gauge for the number of mappings held by each tenant
+    pub static ref TENANT_MAPPINGS: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "protomask_tenant_mappings",
+        "Number of mappings held by each tenant",
+        &["tenant"]
+    ).unwrap();
+
+    /// Gauge for the number of addresses in each tenant's pool
+    pub static ref TENANT_POOL_ADDRESSES: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "protomask_tenant_pool_addresses",
+        "Number of addresses in each tenant's pool",
+        &["tenant"]
+    ).unwrap();
+
+    /// Counter for the number of packets dropped because a tenant reached one of its limits
+    pub static ref TENANT_LIMITED: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_tenant_limited",
+        "Number of packets dropped because a tenant reached one of its limits",
+        &["tenant", "limit"]
+    ).unwrap();
+
+    /// Counter for the number of packets translated for each mapping (when enabled)
+    pub static ref MAPPING_PACKET_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_mapping_packets",
+        "Number of packets translated for each mapping",
+        &["ipv4", "ipv6", "direction"]
+    ).unwrap();
+
+    /// Counter for the number of bytes translated for each mapping (when enabled)
+    pub static ref MAPPING_BYTE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_mapping_bytes",
+        "Number of bytes translated for each mapping",
+        &["ipv4", "ipv6", "direction"]
+    ).unwrap();
+
     /// Counter for the number of different types of ICMP packets received
     pub static ref ICMP_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
         "protomask_icmp_packets_recv",
         "Number of ICMP packets received",
         &["protocol", "icmp_type", "icmp_code"]
     ).unwrap();
+
+    /// Histogram of the time spent in each stage of processing a packet (for sampled packets)
+    pub static ref PACKET_STAGE_SECONDS: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+        "protomask_packet_stage_seconds",
+        "Time spent in each stage of processing a packet",
+        &["stage"],
+        prometheus::exponential_buckets(0.000_001, 2.0, 16).unwrap()
+    ).unwrap();
+
+    /// Histogram of the size of translated packets, as written out
+    pub static ref PACKET_SIZE_BYTES: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+        "protomask_packet_size_bytes",
+        "Size of translated packets",
+        &["direction", "protocol"],
+        vec![64.0, 128.0, 256.0, 512.0, 576.0, 1024.0, 1280.0, 1400.0, 1480.0, 1500.0, 2048.0, 4096.0, 9000.0]
+    ).unwrap();
+
+    /// Gauge for the number of tokio worker threads
+    pub static ref TOKIO_WORKERS: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "protomask_tokio_workers",
+        "Number of tokio worker threads"
+    ).unwrap();
+
+    /// Gauge for the number of tasks alive on the tokio runtime
+    pub static ref TOKIO_ALIVE_TASKS: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "protomask_tokio_alive_tasks",
+        "Number of tasks alive on the tokio runtime"
+    ).unwrap();
+
+    /// Gauge for the number of tasks waiting in the tokio runtime's global queue
+    pub static ref TOKIO_GLOBAL_QUEUE_DEPTH: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "protomask_tokio_global_queue_depth",
+        "Number of tasks waiting in the tokio runtime's global queue"
+    ).unwrap();
+
+    /// Gauge for the total time tokio worker threads have spent busy
+    pub static ref TOKIO_BUSY_SECONDS: prometheus::Gauge = prometheus::register_gauge!(
+        "protomask_tokio_busy_seconds",
+        "Total time tokio worker threads have spent busy"
+    ).unwrap();
+
+    /// Gauge for the number of times tokio worker threads have parked
+    pub static ref TOKIO_PARK_COUNT: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "protomask_tokio_park_count",
+        "Number of times tokio worker threads have parked"
+    ).unwrap();
+
+    /// Histogram of how late a periodic task is woken up, which grows when the runtime is stalled
+    pub static ref TOKIO_SCHEDULER_DELAY_SECONDS: prometheus::Histogram = prometheus::register_histogram!(
+        "protomask_tokio_scheduler_delay_seconds",
+        "How late a periodic task is woken up by the tokio runtime",
+        prometheus::exponential_buckets(0.000_01, 2.0, 16).unwrap()
+    ).unwrap();
+
+    /// Gauge for the number of items waiting in each internal channel
+    pub static ref CHANNEL_DEPTH: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "protomask_channel_depth",
+        "Number of items waiting in each internal channel",
+        &["channel"]
+    ).unwrap();
+
+    /// Counter for the number of items dropped because an internal channel was full
+    pub static ref CHANNEL_DROPPED: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_channel_dropped",
+        "Number of items dropped because an internal channel was full",
+        &["channel"]
+    ).unwrap();
+
+    /// Gauge for the number of TUN queues currently attached when workers are scaled with load
+    pub static ref ACTIVE_QUEUES: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "protomask_active_queues",
+        "Number of TUN queues currently attached"
+    ).unwrap();
+
+    /// Counter for the number of PLAT reachability probes sent by a CLAT
+    pub static ref PLAT_PROBE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "protomask_plat_probes",
+        "Number of PLAT reachability probes sent",
+        &["result"]
+    ).unwrap();
+
+    /// Histogram of the round-trip time of answered PLAT reachability probes
+    pub static ref PLAT_PROBE_RTT_SECONDS: prometheus::Histogram = prometheus::register_histogram!(
+        "protomask_plat_probe_rtt_seconds",
+        "Round-trip time of answered PLAT reachability probes",
+        prometheus::exponential_buckets(0.001, 2.0, 12).unwrap()
+    ).unwrap();
 }
@@ -0,0 +1,67 @@
+//! CPU profiles in the pprof format, so flamegraphs can be captured with standard tooling (eg. `go tool pprof`)
+
+use hyper::{Body, Response};
+use pprof::protos::Message;
+use std::time::Duration;
+
+/// Samples taken per second. Slightly off from 100 so that sampling doesn't line up with periodic work.
+const FREQUENCY: i32 = 99;
+
+/// Length of a profile when none is requested
+const DEFAULT_SECONDS: u64 = 30;
+
+/// Longest profile that may be requested
+const MAX_SECONDS: u64 = 300;
+
+/// Profile the whole process for `seconds=N` (from the query string), then respond with the encoded profile
+pub async fn profile(query: Option<&str>) -> Response<Body> {
+    // Figure out how long to profile for
+    let seconds = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("seconds="))
+        .map_or(Ok(DEFAULT_SECONDS), str::parse);
+    let seconds = match seconds {
+        Ok(seconds) if (1..=MAX_SECONDS).contains(&seconds) => seconds,
+        _ => {
+            return error_response(
+                400,
+                format!("seconds must be between 1 and {}", MAX_SECONDS),
+            )
+        }
+    };
+
+    // Only one profile can be taken at a time
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(error) => return error_response(409, format!("Could not start profiler: {}", error)),
+    };
+    log::info!("Capturing a {} second CPU profile", seconds);
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+    // Encode the profile
+    let profile = match guard.report().build().and_then(|report| report.pprof()) {
+        Ok(profile) => profile,
+        Err(error) => return error_response(500, format!("Could not build profile: {}", error)),
+    };
+    drop(guard);
+    let mut body = Vec::new();
+    profile.encode(&mut body).unwrap();
+    Response::builder()
+        .header("Content-Type", "application/octet-stream")
+        .header("Content-Disposition", "attachment; filename=\"profile.pb\"")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Build a plain text error response
+fn error_response(status: u16, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
@@ -0,0 +1,175 @@
+#![doc = include_str!("../README.md")]
+#![deny(clippy::pedantic)]
+#![allow(clippy::module_name_repetitions)]
+#![allow(clippy::needless_pass_by_value)]
+// Triggered by code generated by pyo3's macros
+#![allow(clippy::useless_conversion)]
+
+use fast_nat::CrossProtocolNetworkAddressTableWithIpv4Pool;
+use ipnet::Ipv4Net;
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Read an IPv4 address from a string or `ipaddress.IPv4Address`
+fn ipv4_arg(value: &Bound<PyAny>) -> PyResult<Ipv4Addr> {
+    match value.extract()? {
+        IpAddr::V4(address) => Ok(address),
+        IpAddr::V6(address) => Err(PyValueError::new_err(format!(
+            "Expected an IPv4 address, got {}",
+            address
+        ))),
+    }
+}
+
+/// Read an IPv6 address from a string or `ipaddress.IPv6Address`
+fn ipv6_arg(value: &Bound<PyAny>) -> PyResult<Ipv6Addr> {
+    match value.extract()? {
+        IpAddr::V6(address) => Ok(address),
+        IpAddr::V4(address) => Err(PyValueError::new_err(format!(
+            "Expected an IPv6 address, got {}",
+            address
+        ))),
+    }
+}
+
+/// Read a prefix from a string or `ipaddress` network
+fn prefix_arg<T: FromStr>(value: &Bound<PyAny>) -> PyResult<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .str()?
+        .to_cow()?
+        .parse()
+        .map_err(|error: T::Err| PyValueError::new_err(error.to_string()))
+}
+
+/// Turn any library error into a Python `ValueError`
+fn value_error(error: impl std::fmt::Display) -> PyErr {
+    PyValueError::new_err(error.to_string())
+}
+
+/// Embed an IPv4 address in an RFC6052 prefix
+#[pyfunction]
+fn embed_ipv4_addr(py: Python, ipv4: &Bound<PyAny>, prefix: &Bound<PyAny>) -> PyResult<PyObject> {
+    rfc6052::embed_ipv4_addr(ipv4_arg(ipv4)?, prefix_arg(prefix)?)
+        .map(|address| address.to_object(py))
+        .map_err(value_error)
+}
+
+/// Extract the IPv4 address embedded in an IPv6 address with the given prefix length
+#[pyfunction]
+fn extract_ipv4_addr(py: Python, ipv6: &Bound<PyAny>, prefix_length: u8) -> PyResult<PyObject> {
+    rfc6052::extract_ipv4_addr(ipv6_arg(ipv6)?, prefix_length)
+        .map(|address| address.to_object(py))
+        .map_err(value_error)
+}
+
+/// A NAT64 mapping table, handing out addresses from an IPv4 pool to IPv6 clients
+#[pyclass]
+struct AddressTable {
+    table: CrossProtocolNetworkAddressTableWithIpv4Pool,
+}
+
+#[pymethods]
+impl AddressTable {
+    /// Create an empty table. Dynamic mappings expire after `timeout` seconds.
+    #[new]
+    #[pyo3(signature = (pool, timeout = 7200.0))]
+    fn new(pool: Vec<Bound<PyAny>>, timeout: f64) -> PyResult<Self> {
+        let pool = pool
+            .into_iter()
+            .map(|prefix| prefix_arg::<Ipv4Net>(&prefix))
+            .collect::<PyResult<Vec<_>>>()?;
+        let timeout = Duration::try_from_secs_f64(timeout).map_err(value_error)?;
+        Ok(Self {
+            table: CrossProtocolNetworkAddressTableWithIpv4Pool::new(&pool, timeout),
+        })
+    }
+
+    /// Prevent addresses in a prefix from being dynamically assigned
+    fn exclude(&mut self, prefix: &Bound<PyAny>) -> PyResult<()> {
+        self.table.exclude(prefix_arg(prefix)?);
+        Ok(())
+    }
+
+    /// Insert a mapping that never expires
+    fn insert_static(&mut self, ipv4: &Bound<PyAny>, ipv6: &Bound<PyAny>) -> PyResult<()> {
+        self.table
+            .insert_static(ipv4_arg(ipv4)?, ipv6_arg(ipv6)?)
+            .map_err(value_error)
+    }
+
+    /// Get the IPv4 address of a client, assigning one from the pool if needed
+    fn get_or_create_ipv4(&mut self, py: Python, ipv6: &Bound<PyAny>) -> PyResult<PyObject> {
+        self.table
+            .get_or_create_ipv4(&ipv6_arg(ipv6)?)
+            .map(|address| address.to_object(py))
+            .map_err(value_error)
+    }
+
+    /// Get the IPv4 address of a client, if it has one
+    fn get_ipv4(&self, py: Python, ipv6: &Bound<PyAny>) -> PyResult<Option<PyObject>> {
+        Ok(self
+            .table
+            .get_ipv4(&ipv6_arg(ipv6)?)
+            .map(|address| address.to_object(py)))
+    }
+
+    /// Get the client an IPv4 address is assigned to, if any
+    fn get_ipv6(&self, py: Python, ipv4: &Bound<PyAny>) -> PyResult<Option<PyObject>> {
+        Ok(self
+            .table
+            .get_ipv6(&ipv4_arg(ipv4)?)
+            .map(|address| address.to_object(py)))
+    }
+
+    /// Remove the mapping of an IPv4 address, returning the client it was assigned to
+    fn remove_ipv4(&mut self, py: Python, ipv4: &Bound<PyAny>) -> PyResult<Option<PyObject>> {
+        Ok(self
+            .table
+            .remove_ipv4(&ipv4_arg(ipv4)?)
+            .map(|address| address.to_object(py)))
+    }
+
+    /// Remove expired mappings
+    fn prune(&mut self) {
+        self.table.prune();
+    }
+
+    /// List every mapping as `(ipv4, ipv6, seconds remaining)`, where static mappings have no time remaining
+    fn mappings(&self, py: Python) -> Vec<(PyObject, PyObject, Option<f64>)> {
+        self.table
+            .mappings()
+            .map(|(ipv4, ipv6, remaining)| {
+                (
+                    ipv4.to_object(py),
+                    ipv6.to_object(py),
+                    remaining.map(|remaining| remaining.as_secs_f64()),
+                )
+            })
+            .collect()
+    }
+
+    /// Get the number of assignable addresses in the pool
+    fn pool_size(&self) -> usize {
+        self.table.pool_size()
+    }
+
+    fn __len__(&self) -> usize {
+        self.table.len()
+    }
+}
+
+/// The address logic used by protomask
+#[pymodule]
+fn protomask(module: &Bound<PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(embed_ipv4_addr, module)?)?;
+    module.add_function(wrap_pyfunction!(extract_ipv4_addr, module)?)?;
+    module.add_class::<AddressTable>()?;
+    Ok(())
+}
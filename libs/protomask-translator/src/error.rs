@@ -0,0 +1,25 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// All possible errors thrown by `protomask-translator` functions
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Not an IP packet")]
+    NotIp,
+    #[error("Unknown IP version: {0}")]
+    UnknownVersion(u8),
+    #[error("Packet is shorter than its IP header")]
+    TruncatedHeader,
+    #[error("Address is not inside the translation prefix: {0}")]
+    OutsidePrefix(Ipv6Addr),
+    #[error("No mapping for destination address: {0}")]
+    NoMapping(Ipv4Addr),
+    #[error(transparent)]
+    Prefix(#[from] rfc6052::Error),
+    #[error(transparent)]
+    Table(#[from] fast_nat::error::Error),
+    #[error(transparent)]
+    Translation(#[from] interproto::error::Error),
+}
+
+/// Result type for `protomask-translator`
+pub type Result<T> = std::result::Result<T, Error>;
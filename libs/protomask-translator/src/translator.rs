@@ -0,0 +1,260 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use fast_nat::CrossProtocolNetworkAddressTableWithIpv4Pool;
+use interproto::protocols::ip::{translate_ipv4_to_ipv6, translate_ipv6_to_ipv4};
+use ipnet::{Ipv4Net, Ipv6Net};
+use rfc6052::{embed_ipv4_addr, extract_ipv4_addr, ALLOWED_PREFIX_LENS};
+
+use crate::error::{Error, Result};
+
+/// Translates raw IP packets between IPv4 and IPv6
+#[derive(Debug)]
+pub struct Translator {
+    /// The prefix IPv4 addresses are embedded in
+    prefix: Ipv6Net,
+    /// Mappings between IPv6 clients and pool addresses (only when translating statefully)
+    table: Option<CrossProtocolNetworkAddressTableWithIpv4Pool>,
+}
+
+impl Translator {
+    /// Construct a stateless translator, where both addresses of every packet are embedded in `prefix`
+    pub fn stateless(prefix: Ipv6Net) -> Result<Self> {
+        check_prefix(prefix)?;
+        Ok(Self {
+            prefix,
+            table: None,
+        })
+    }
+
+    /// Construct a stateful translator (NAT64), where IPv6 clients are mapped to addresses from `pool` for `timeout`
+    pub fn stateful(prefix: Ipv6Net, pool: &[Ipv4Net], timeout: Duration) -> Result<Self> {
+        check_prefix(prefix)?;
+        Ok(Self {
+            prefix,
+            table: Some(CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+                pool, timeout,
+            )),
+        })
+    }
+
+    /// Get the prefix IPv4 addresses are embedded in
+    #[must_use]
+    pub fn prefix(&self) -> Ipv6Net {
+        self.prefix
+    }
+
+    /// Get the address table, if translating statefully
+    #[must_use]
+    pub fn table(&self) -> Option<&CrossProtocolNetworkAddressTableWithIpv4Pool> {
+        self.table.as_ref()
+    }
+
+    /// Get the address table mutably (eg. to add static mappings), if translating statefully
+    pub fn table_mut(&mut self) -> Option<&mut CrossProtocolNetworkAddressTableWithIpv4Pool> {
+        self.table.as_mut()
+    }
+
+    /// Translate a single IPv4 or IPv6 packet
+    pub fn translate(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        match packet.first().map(|byte| byte >> 4) {
+            Some(4) => self.translate_ipv4(packet),
+            Some(6) => self.translate_ipv6(packet),
+            Some(version) => Err(Error::UnknownVersion(version)),
+            None => Err(Error::NotIp),
+        }
+    }
+
+    /// Translate an IPv4 packet to IPv6
+    fn translate_ipv4(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        if packet.len() < 20 {
+            return Err(Error::TruncatedHeader);
+        }
+        let source = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap());
+        let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap());
+
+        // Traffic towards the pool is destined for whichever client holds the address
+        let new_destination = match &self.table {
+            Some(table) => table
+                .get_ipv6(&destination)
+                .ok_or(Error::NoMapping(destination))?,
+            None => embed_ipv4_addr(destination, self.prefix)?,
+        };
+        Ok(translate_ipv4_to_ipv6(
+            packet,
+            embed_ipv4_addr(source, self.prefix)?,
+            new_destination,
+        )?)
+    }
+
+    /// Translate an IPv6 packet to IPv4
+    fn translate_ipv6(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        if packet.len() < 40 {
+            return Err(Error::TruncatedHeader);
+        }
+        let source = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+        let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap());
+        if !self.prefix.contains(&destination) {
+            return Err(Error::OutsidePrefix(destination));
+        }
+
+        // Clients are given an address from the pool, unless their address has one embedded
+        let new_source = match &mut self.table {
+            Some(table) => table.get_or_create_ipv4(&source)?,
+            None if self.prefix.contains(&source) => {
+                extract_ipv4_addr(source, self.prefix.prefix_len())?
+            }
+            None => return Err(Error::OutsidePrefix(source)),
+        };
+        Ok(translate_ipv6_to_ipv4(
+            packet,
+            new_source,
+            extract_ipv4_addr(destination, self.prefix.prefix_len())?,
+        )?)
+    }
+}
+
+/// Make sure IPv4 addresses can be embedded in a prefix
+fn check_prefix(prefix: Ipv6Net) -> Result<()> {
+    if ALLOWED_PREFIX_LENS.contains(&prefix.prefix_len()) {
+        Ok(())
+    } else {
+        Err(rfc6052::Error::InvalidPrefixLength(prefix.prefix_len()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::{
+        ip::IpNextHeaderProtocols,
+        ipv4::{Ipv4Packet, MutableIpv4Packet},
+        ipv6::{Ipv6Packet, MutableIpv6Packet},
+        udp::MutableUdpPacket,
+    };
+
+    /// Build an IPv4 UDP packet
+    fn udp_ipv4(source: Ipv4Addr, destination: Ipv4Addr) -> Vec<u8> {
+        let mut buffer = vec![0u8; 20 + 8];
+        let mut udp = MutableUdpPacket::new(&mut buffer[20..]).unwrap();
+        udp.set_source(1234);
+        udp.set_destination(53);
+        udp.set_length(8);
+        let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(28);
+        packet.set_ttl(64);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        packet.set_checksum(pnet_packet::ipv4::checksum(&packet.to_immutable()));
+        buffer
+    }
+
+    /// Build an IPv6 UDP packet
+    fn udp_ipv6(source: Ipv6Addr, destination: Ipv6Addr) -> Vec<u8> {
+        let mut buffer = vec![0u8; 40 + 8];
+        let mut udp = MutableUdpPacket::new(&mut buffer[40..]).unwrap();
+        udp.set_source(1234);
+        udp.set_destination(53);
+        udp.set_length(8);
+        let mut packet = MutableIpv6Packet::new(&mut buffer).unwrap();
+        packet.set_version(6);
+        packet.set_payload_length(8);
+        packet.set_hop_limit(64);
+        packet.set_next_header(IpNextHeaderProtocols::Udp);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        buffer
+    }
+
+    #[test]
+    fn test_stateless() {
+        let mut translator = Translator::stateless("64:ff9b::/96".parse().unwrap()).unwrap();
+
+        // Both addresses are embedded in the prefix
+        let output = translator
+            .translate(&udp_ipv4(
+                "192.0.2.1".parse().unwrap(),
+                "198.51.100.1".parse().unwrap(),
+            ))
+            .unwrap();
+        let output = Ipv6Packet::new(&output).unwrap();
+        assert_eq!(
+            output.get_source(),
+            "64:ff9b::192.0.2.1".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            output.get_destination(),
+            "64:ff9b::198.51.100.1".parse::<Ipv6Addr>().unwrap()
+        );
+
+        // And must be inside it to be translated back
+        assert!(matches!(
+            translator.translate(&udp_ipv6(
+                "2001:db8::1".parse().unwrap(),
+                "64:ff9b::198.51.100.1".parse().unwrap(),
+            )),
+            Err(Error::OutsidePrefix(_))
+        ));
+    }
+
+    #[test]
+    fn test_stateful() {
+        let mut translator = Translator::stateful(
+            "64:ff9b::/96".parse().unwrap(),
+            &["192.0.2.0/24".parse().unwrap()],
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        let client = "2001:db8::1".parse().unwrap();
+
+        // Nothing can reach the pool until a client is mapped
+        let reply = udp_ipv4(
+            "198.51.100.1".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+        );
+        assert!(matches!(
+            translator.translate(&reply),
+            Err(Error::NoMapping(_))
+        ));
+
+        // Clients are given a pool address
+        let output = translator
+            .translate(&udp_ipv6(client, "64:ff9b::198.51.100.1".parse().unwrap()))
+            .unwrap();
+        let output = Ipv4Packet::new(&output).unwrap();
+        assert_eq!(output.get_source(), Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(output.get_destination(), Ipv4Addr::new(198, 51, 100, 1));
+        assert_eq!(
+            translator.table().unwrap().get_ipv4(&client),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+
+        // So replies make it back to them
+        let output = translator.translate(&reply).unwrap();
+        assert_eq!(Ipv6Packet::new(&output).unwrap().get_destination(), client);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(matches!(
+            Translator::stateless("64:ff9b::/100".parse().unwrap()),
+            Err(Error::Prefix(_))
+        ));
+
+        let mut translator = Translator::stateless("64:ff9b::/96".parse().unwrap()).unwrap();
+        assert!(matches!(translator.translate(&[]), Err(Error::NotIp)));
+        assert!(matches!(
+            translator.translate(&[0x50]),
+            Err(Error::UnknownVersion(5))
+        ));
+        assert!(matches!(
+            translator.translate(&[0x45, 0]),
+            Err(Error::TruncatedHeader)
+        ));
+    }
+}
@@ -25,6 +25,27 @@ pub async fn addr_add(
         })
 }
 
+/// Add an IP address to a link like [`addr_add`], replacing it if the link already has it
+pub async fn addr_replace(
+    ip_addr: IpAddr,
+    prefix_len: u8,
+    rt_handle: &Handle,
+    link_index: u32,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Replacing address {} on link {}", ip_addr, link_index);
+    rt_handle
+        .address()
+        .add(link_index, ip_addr, prefix_len)
+        .replace()
+        .execute()
+        .await
+        .map_err(|err| {
+            log::error!("Failed to add address {} to link {}", ip_addr, link_index);
+            log::error!("{}", err);
+            err
+        })
+}
+
 /// Remove an IP address from a link
 pub async fn addr_del(
     ip_addr: IpAddr,
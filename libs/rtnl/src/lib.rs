@@ -7,7 +7,11 @@
 
 pub mod ip;
 pub mod link;
+pub mod monitor;
+pub mod neighbor;
 pub mod route;
+pub mod route_set;
+pub mod rule;
 
 /// Get a handle on a new rtnetlink connection
 #[cfg(feature = "tokio")]
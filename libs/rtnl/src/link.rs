@@ -3,6 +3,9 @@
 use futures::TryStreamExt;
 use rtnetlink::Handle;
 
+/// Receive all multicast packets (from `<linux/if.h>`)
+const IFF_ALLMULTI: u32 = 0x200;
+
 /// Bring up a link by its link index
 pub async fn link_up(rt_handle: &Handle, link_index: u32) -> Result<(), rtnetlink::Error> {
     log::trace!("Bringing up link {}", link_index);
@@ -15,6 +18,24 @@ pub async fn link_down(rt_handle: &Handle, link_index: u32) -> Result<(), rtnetl
     rt_handle.link().set(link_index).down().execute().await
 }
 
+/// Enable or disable reception of all multicast packets on a link by its link index
+pub async fn set_allmulticast(
+    rt_handle: &Handle,
+    link_index: u32,
+    enable: bool,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Setting allmulticast on link {} to {}", link_index, enable);
+    let mut request = rt_handle.link().set(link_index);
+    let header = &mut request.message_mut().header;
+    if enable {
+        header.flags |= IFF_ALLMULTI;
+    } else {
+        header.flags &= !IFF_ALLMULTI;
+    }
+    header.change_mask |= IFF_ALLMULTI;
+    request.execute().await
+}
+
 /// Get the link index of a link by its name
 pub async fn get_link_index(
     rt_handle: &Handle,
@@ -0,0 +1,52 @@
+//! Utilities for watching the kernel's network configuration
+
+use futures::{channel::mpsc::UnboundedReceiver, StreamExt};
+use netlink_packet_route::{
+    RtnlMessage, RTNLGRP_IPV4_IFADDR, RTNLGRP_IPV4_ROUTE, RTNLGRP_IPV6_IFADDR, RTNLGRP_IPV6_ROUTE,
+    RTNLGRP_LINK,
+};
+use netlink_sys::{AsyncSocket, SocketAddr};
+
+/// Notifies of changes to links, addresses, and routes
+pub struct ChangeWatcher {
+    /// The connection shuts down once every handle on it is dropped, so one is kept for as long as we watch
+    _handle: rtnetlink::Handle,
+    messages: UnboundedReceiver<(netlink_packet_core::NetlinkMessage<RtnlMessage>, SocketAddr)>,
+}
+
+impl ChangeWatcher {
+    /// Start watching for changes
+    #[cfg(feature = "tokio")]
+    pub fn new() -> Result<Self, std::io::Error> {
+        let (mut rt_connection, handle, messages) = rtnetlink::new_connection().map_err(|err| {
+            log::error!("Failed to open rtnetlink connection");
+            log::error!("{}", err);
+            err
+        })?;
+
+        // Subscribe to everything that could affect connectivity
+        // Multicast messages are only delivered to sockets that have been bound to an address
+        let socket = rt_connection.socket_mut().socket_mut();
+        socket.bind(&SocketAddr::new(0, 0))?;
+        for group in [
+            RTNLGRP_LINK,
+            RTNLGRP_IPV4_IFADDR,
+            RTNLGRP_IPV4_ROUTE,
+            RTNLGRP_IPV6_IFADDR,
+            RTNLGRP_IPV6_ROUTE,
+        ] {
+            socket.add_membership(group)?;
+        }
+
+        tokio::spawn(rt_connection);
+        Ok(Self {
+            _handle: handle,
+            messages,
+        })
+    }
+
+    /// Wait for the next change. Returns `false` if the watch has ended.
+    pub async fn changed(&mut self) -> bool {
+        self.messages.next().await.is_some()
+    }
+}
@@ -0,0 +1,30 @@
+//! Utilities for interacting with the neighbor (ARP and NDP) tables
+
+use rtnetlink::Handle;
+use std::net::IpAddr;
+
+/// Marks a neighbor entry as a proxy entry (from `<linux/neighbour.h>`)
+const NTF_PROXY: u8 = 0x08;
+
+/// Answer ARP requests (or Neighbor Solicitations) for an address on a link by its link index
+///
+/// The kernel only answers for addresses it has a route to through some other link.
+pub async fn proxy_add(
+    rt_handle: &Handle,
+    link_index: u32,
+    address: IpAddr,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Adding proxy neighbor {} to link {}", address, link_index);
+    rt_handle
+        .neighbours()
+        .add(link_index, address)
+        .flags(NTF_PROXY)
+        .replace()
+        .execute()
+        .await
+        .map_err(|err| {
+            log::error!("Failed to add proxy neighbor {} to link", address);
+            log::error!("{}", err);
+            err
+        })
+}
@@ -1,41 +1,329 @@
 //! Utilities for interacting with the routing table
 
-use ipnet::IpNet;
-use rtnetlink::Handle;
+use futures::TryStreamExt;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use netlink_packet_route::{
+    route::Nla, RouteMessage, RTN_BLACKHOLE, RTN_UNICAST, RTN_UNREACHABLE, RT_SCOPE_LINK,
+};
+use rtnetlink::{Handle, IpVersion};
+use std::net::IpAddr;
 
-/// Add a route to a link
+/// Route metric holding the path MTU (from `<linux/rtnetlink.h>`)
+const RTAX_MTU: u16 = 2;
+
+/// Add a route to a link, optionally with its own MTU and metric
 pub async fn route_add(
     destination: IpNet,
     rt_handle: &Handle,
     link_index: u32,
+    mtu: Option<u32>,
+    metric: Option<u32>,
 ) -> Result<(), rtnetlink::Error> {
     log::trace!("Adding route {} to link {}", destination, link_index);
+    add_link_route(destination, rt_handle, link_index, mtu, metric, false).await
+}
+
+/// Add a route to a link like [`route_add`], replacing any route to the same destination that already exists
+pub async fn route_replace(
+    destination: IpNet,
+    rt_handle: &Handle,
+    link_index: u32,
+    mtu: Option<u32>,
+    metric: Option<u32>,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Replacing route {} to link {}", destination, link_index);
+    add_link_route(destination, rt_handle, link_index, mtu, metric, true).await
+}
+
+/// Send a request adding a route to a link
+async fn add_link_route(
+    destination: IpNet,
+    rt_handle: &Handle,
+    link_index: u32,
+    mtu: Option<u32>,
+    metric: Option<u32>,
+    replace: bool,
+) -> Result<(), rtnetlink::Error> {
     match destination {
+        IpNet::V4(destination) => {
+            let mut request = rt_handle
+                .route()
+                .add()
+                .v4()
+                .output_interface(link_index)
+                .destination_prefix(destination.addr(), destination.prefix_len());
+            if replace {
+                request = request.replace();
+            }
+            if let Some(mtu) = mtu {
+                set_mtu(request.message_mut(), mtu);
+            }
+            if let Some(metric) = metric {
+                request.message_mut().nlas.push(Nla::Priority(metric));
+            }
+            request.execute().await.map_err(|err| {
+                log::error!("Failed to add route {} to link", destination);
+                log::error!("{}", err);
+                err
+            })
+        }
+        IpNet::V6(destination) => {
+            let mut request = rt_handle
+                .route()
+                .add()
+                .v6()
+                .output_interface(link_index)
+                .destination_prefix(destination.addr(), destination.prefix_len());
+            if replace {
+                request = request.replace();
+            }
+            if let Some(mtu) = mtu {
+                set_mtu(request.message_mut(), mtu);
+            }
+            if let Some(metric) = metric {
+                request.message_mut().nlas.push(Nla::Priority(metric));
+            }
+            request.execute().await.map_err(|err| {
+                log::error!("Failed to add route {} to link", destination);
+                log::error!("{}", err);
+                err
+            })
+        }
+    }
+}
+
+/// Attach an MTU to a route message
+fn set_mtu(message: &mut RouteMessage, mtu: u32) {
+    // Metrics are nested attributes, each of which is a 4 byte header followed by its value
+    let mut metrics = Vec::with_capacity(8);
+    metrics.extend_from_slice(&8u16.to_ne_bytes());
+    metrics.extend_from_slice(&RTAX_MTU.to_ne_bytes());
+    metrics.extend_from_slice(&mtu.to_ne_bytes());
+    message.nlas.push(Nla::Metrics(metrics));
+}
+
+/// How a route that doesn't lead anywhere gets rid of packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscardKind {
+    /// Silently drop packets
+    Blackhole,
+    /// Drop packets and send an ICMP Destination Unreachable back to the sender
+    Unreachable,
+}
+
+/// Add a route that discards all traffic to a prefix, replacing any such route that already exists
+///
+/// Routes with a lower `metric` take priority, so the discard route only applies while there is no other route.
+pub async fn route_add_discard(
+    destination: Ipv4Net,
+    rt_handle: &Handle,
+    kind: DiscardKind,
+    metric: u32,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Adding {:?} route for {}", kind, destination);
+    let mut request = rt_handle
+        .route()
+        .add()
+        .kind(match kind {
+            DiscardKind::Blackhole => RTN_BLACKHOLE,
+            DiscardKind::Unreachable => RTN_UNREACHABLE,
+        })
+        .v4()
+        .destination_prefix(destination.addr(), destination.prefix_len())
+        .replace();
+    request.message_mut().nlas.push(Nla::Priority(metric));
+    request.execute().await.map_err(|err| {
+        log::error!("Failed to add {:?} route for {}", kind, destination);
+        log::error!("{}", err);
+        err
+    })
+}
+
+/// Remove a route added by [`route_add_discard`]
+pub async fn route_del_discard(
+    destination: Ipv4Net,
+    rt_handle: &Handle,
+    kind: DiscardKind,
+    metric: u32,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Removing {:?} route for {}", kind, destination);
+
+    // Describe the route the same way it was added
+    let mut request = rt_handle
+        .route()
+        .add()
+        .kind(match kind {
+            DiscardKind::Blackhole => RTN_BLACKHOLE,
+            DiscardKind::Unreachable => RTN_UNREACHABLE,
+        })
+        .v4()
+        .destination_prefix(destination.addr(), destination.prefix_len());
+    request.message_mut().nlas.push(Nla::Priority(metric));
+    let message = request.message_mut().clone();
+
+    rt_handle
+        .route()
+        .del(message)
+        .execute()
+        .await
+        .map_err(|err| {
+            log::error!("Failed to remove {:?} route for {}", kind, destination);
+            log::error!("{}", err);
+            err
+        })
+}
+
+/// Remove a route from a link
+pub async fn route_del(
+    destination: IpNet,
+    rt_handle: &Handle,
+    link_index: u32,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Removing route {} from link {}", destination, link_index);
+
+    // Describe the route the same way it was added
+    let message = match destination {
         IpNet::V4(destination) => rt_handle
             .route()
             .add()
             .v4()
             .output_interface(link_index)
             .destination_prefix(destination.addr(), destination.prefix_len())
-            .execute()
-            .await
-            .map_err(|err| {
-                log::error!("Failed to add route {} to link", destination);
-                log::error!("{}", err);
-                err
-            }),
+            .message_mut()
+            .clone(),
         IpNet::V6(destination) => rt_handle
             .route()
             .add()
             .v6()
             .output_interface(link_index)
             .destination_prefix(destination.addr(), destination.prefix_len())
-            .execute()
-            .await
-            .map_err(|err| {
-                log::error!("Failed to add route {} to link", destination);
-                log::error!("{}", err);
-                err
-            }),
+            .message_mut()
+            .clone(),
+    };
+
+    rt_handle
+        .route()
+        .del(message)
+        .execute()
+        .await
+        .map_err(|err| {
+            log::error!("Failed to remove route {} from link", destination);
+            log::error!("{}", err);
+            err
+        })
+}
+
+/// A default route out of a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefaultRoute {
+    /// Either `0.0.0.0/0` or `::/0`
+    pub destination: IpNet,
+    /// Next hop, unless the link is point-to-point
+    pub gateway: Option<IpAddr>,
+}
+
+/// Get the default routes out of a link in a routing table
+pub async fn default_routes(
+    rt_handle: &Handle,
+    link_index: u32,
+    table: u32,
+) -> Result<Vec<DefaultRoute>, rtnetlink::Error> {
+    let mut routes = Vec::new();
+    for (version, destination) in [
+        (IpVersion::V4, IpNet::V4(Ipv4Net::default())),
+        (IpVersion::V6, IpNet::V6(Ipv6Net::default())),
+    ] {
+        let mut messages = rt_handle.route().get(version).execute();
+        while let Some(message) = messages.try_next().await? {
+            if message.header.destination_prefix_length == 0
+                && message.header.kind == RTN_UNICAST
+                && route_table(&message) == table
+                && message.output_interface() == Some(link_index)
+            {
+                routes.push(DefaultRoute {
+                    destination,
+                    gateway: message.gateway(),
+                });
+            }
+        }
     }
+    Ok(routes)
+}
+
+/// Get the routing table a route is in
+fn route_table(message: &RouteMessage) -> u32 {
+    // Tables above 255 don't fit in the header
+    message
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            Nla::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or_else(|| u32::from(message.header.table))
+}
+
+/// Describe a default route out of a link in a routing table
+fn default_route_message(
+    route: DefaultRoute,
+    rt_handle: &Handle,
+    link_index: u32,
+    table: u32,
+) -> RouteMessage {
+    let request = rt_handle
+        .route()
+        .add()
+        .output_interface(link_index)
+        .table_id(table);
+
+    // Without a gateway, everything is directly reachable through the link
+    let request = match route.gateway {
+        Some(_) => request,
+        None => request.scope(RT_SCOPE_LINK),
+    };
+    match (route.destination, route.gateway) {
+        (IpNet::V6(_), Some(IpAddr::V6(gateway))) => {
+            request.v6().gateway(gateway).message_mut().clone()
+        }
+        (IpNet::V6(_), _) => request.v6().message_mut().clone(),
+        (_, Some(IpAddr::V4(gateway))) => request.v4().gateway(gateway).message_mut().clone(),
+        (_, _) => request.v4().message_mut().clone(),
+    }
+}
+
+/// Add a default route out of a link to a routing table, replacing any that already exists
+pub async fn route_add_default(
+    route: DefaultRoute,
+    rt_handle: &Handle,
+    link_index: u32,
+    table: u32,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Adding {:?} to table {}", route, table);
+    let mut request = rt_handle.route().add().replace();
+    *request.message_mut() = default_route_message(route, rt_handle, link_index, table);
+    request.execute().await.map_err(|err| {
+        log::error!("Failed to add default route to table {}", table);
+        log::error!("{}", err);
+        err
+    })
+}
+
+/// Remove a route added by [`route_add_default`]
+pub async fn route_del_default(
+    route: DefaultRoute,
+    rt_handle: &Handle,
+    link_index: u32,
+    table: u32,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Removing {:?} from table {}", route, table);
+    rt_handle
+        .route()
+        .del(default_route_message(route, rt_handle, link_index, table))
+        .execute()
+        .await
+        .map_err(|err| {
+            log::error!("Failed to remove default route from table {}", table);
+            log::error!("{}", err);
+            err
+        })
 }
@@ -0,0 +1,417 @@
+//! Tracking of the routes and addresses a process installs
+//!
+//! A [`RouteSet`] installs routes and addresses on behalf of a process and remembers each of them, so they can be
+//! removed again later. When given a journal file, every entry is written to it *before* being installed, and
+//! forgotten only after being removed. A process that crashed or was restarted can then open the same journal to find
+//! everything it left behind, and either remove it or re-adopt it.
+//!
+//! Links are recorded by name, since a link (such as a TUN interface) that is recreated comes back with a new index.
+//! Routes and addresses on a link disappear along with it, so there is nothing to remove for links that no longer
+//! exist.
+
+use crate::route::DiscardKind;
+use ipnet::{IpNet, Ipv4Net};
+use rtnetlink::Handle;
+use std::{
+    fmt::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Error number for a route or address that doesn't exist (from `<errno.h>`)
+const ESRCH: i32 = 3;
+
+/// Something a [`RouteSet`] installed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Owned {
+    /// A route towards a link, optionally with its own MTU and metric
+    Route {
+        destination: IpNet,
+        link: String,
+        mtu: Option<u32>,
+        metric: Option<u32>,
+    },
+    /// A route that discards all traffic to a prefix
+    Discard {
+        destination: Ipv4Net,
+        kind: DiscardKind,
+        metric: u32,
+    },
+    /// An address assigned to a link, along with the length of its prefix
+    Address { address: IpNet, link: String },
+}
+
+impl fmt::Display for Owned {
+    /// Formats an entry as a single line of a journal
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Route {
+                destination,
+                link,
+                mtu,
+                metric,
+            } => {
+                write!(f, "route {} {}", destination, link)?;
+                if let Some(mtu) = mtu {
+                    write!(f, " mtu={}", mtu)?;
+                }
+                if let Some(metric) = metric {
+                    write!(f, " metric={}", metric)?;
+                }
+                Ok(())
+            }
+            Self::Discard {
+                destination,
+                kind,
+                metric,
+            } => write!(
+                f,
+                "discard {} {} metric={}",
+                destination,
+                match kind {
+                    DiscardKind::Blackhole => "blackhole",
+                    DiscardKind::Unreachable => "unreachable",
+                },
+                metric
+            ),
+            Self::Address { address, link } => write!(f, "address {} {}", address, link),
+        }
+    }
+}
+
+impl FromStr for Owned {
+    type Err = String;
+
+    /// Parses a single line of a journal
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [kind, destination, ref rest @ ..] = fields[..] else {
+            return Err(format!("Invalid journal entry: {}", s));
+        };
+
+        // Anything after the link or discard kind is an optional `key=value` attribute
+        let attribute = |name: &str| -> Result<Option<u32>, String> {
+            rest.iter()
+                .filter_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid {} in journal entry: {}", name, s))
+                })
+                .next()
+                .transpose()
+        };
+        let link = || {
+            rest.first()
+                .map(ToString::to_string)
+                .ok_or_else(|| format!("Journal entry has no link: {}", s))
+        };
+        let invalid_destination = |_| format!("Invalid destination in journal entry: {}", s);
+
+        match kind {
+            "route" => Ok(Self::Route {
+                destination: destination.parse().map_err(invalid_destination)?,
+                link: link()?,
+                mtu: attribute("mtu")?,
+                metric: attribute("metric")?,
+            }),
+            "discard" => Ok(Self::Discard {
+                destination: destination.parse().map_err(invalid_destination)?,
+                kind: match rest.first() {
+                    Some(&"blackhole") => DiscardKind::Blackhole,
+                    Some(&"unreachable") => DiscardKind::Unreachable,
+                    _ => return Err(format!("Invalid discard kind in journal entry: {}", s)),
+                },
+                metric: attribute("metric")?.unwrap_or(u32::MAX),
+            }),
+            "address" => Ok(Self::Address {
+                address: destination.parse().map_err(invalid_destination)?,
+                link: link()?,
+            }),
+            _ => Err(format!("Unknown journal entry: {}", s)),
+        }
+    }
+}
+
+/// Errors that can occur while installing or removing routes
+#[derive(Debug)]
+pub enum RouteSetError {
+    /// The kernel refused a request
+    Netlink(rtnetlink::Error),
+    /// The journal could not be read or written
+    Journal(std::io::Error),
+    /// A route or address belongs on a link that doesn't exist
+    LinkNotFound(String),
+}
+
+impl fmt::Display for RouteSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Netlink(error) => write!(f, "{}", error),
+            Self::Journal(error) => write!(f, "Failed to update route journal: {}", error),
+            Self::LinkNotFound(link) => write!(f, "{} does not exist", link),
+        }
+    }
+}
+
+impl std::error::Error for RouteSetError {}
+
+impl From<rtnetlink::Error> for RouteSetError {
+    fn from(error: rtnetlink::Error) -> Self {
+        Self::Netlink(error)
+    }
+}
+
+impl From<std::io::Error> for RouteSetError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Journal(error)
+    }
+}
+
+/// Routes and addresses installed by this process
+#[derive(Debug, Default)]
+pub struct RouteSet {
+    entries: Vec<Owned>,
+    journal: Option<PathBuf>,
+}
+
+impl RouteSet {
+    /// Track routes in memory only
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track routes in a journal file, starting with anything a previous process left in it
+    pub fn with_journal(path: &Path) -> Result<Self, RouteSetError> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(data) => data
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    line.parse().map_err(|error| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+        if !entries.is_empty() {
+            log::debug!(
+                "Found {} routes and addresses left in {}",
+                entries.len(),
+                path.display()
+            );
+        }
+        Ok(Self {
+            entries,
+            journal: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Get everything currently tracked, in the order it was installed
+    #[must_use]
+    pub fn entries(&self) -> &[Owned] {
+        &self.entries
+    }
+
+    /// Install a route or address and start tracking it. Installing something that already exists replaces it.
+    pub async fn add(&mut self, rt_handle: &Handle, entry: Owned) -> Result<(), RouteSetError> {
+        // Record the entry first, so that it can't be installed without being recorded
+        if !self.entries.contains(&entry) {
+            self.entries.push(entry.clone());
+            self.write_journal()?;
+        }
+        install(rt_handle, &entry).await
+    }
+
+    /// Remove a route or address and stop tracking it
+    pub async fn remove(&mut self, rt_handle: &Handle, entry: &Owned) -> Result<(), RouteSetError> {
+        uninstall(rt_handle, entry).await?;
+        self.entries.retain(|other| other != entry);
+        self.write_journal()
+    }
+
+    /// Install everything tracked again, such as after re-adopting a journal
+    pub async fn restore(&self, rt_handle: &Handle) -> Result<(), RouteSetError> {
+        for entry in &self.entries {
+            install(rt_handle, entry).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove everything tracked that `keep` returns false for, newest first
+    pub async fn retain(
+        &mut self,
+        rt_handle: &Handle,
+        keep: impl Fn(&Owned) -> bool,
+    ) -> Result<(), RouteSetError> {
+        let unwanted: Vec<_> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| !keep(entry))
+            .cloned()
+            .collect();
+        for entry in unwanted {
+            self.remove(rt_handle, &entry).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove everything tracked, newest first
+    pub async fn remove_all(&mut self, rt_handle: &Handle) -> Result<(), RouteSetError> {
+        self.retain(rt_handle, |_| false).await
+    }
+
+    /// Replace the journal's contents with the current entries
+    fn write_journal(&self) -> Result<(), RouteSetError> {
+        let Some(path) = &self.journal else {
+            return Ok(());
+        };
+        let mut data = String::new();
+        for entry in &self.entries {
+            writeln!(data, "{}", entry).unwrap();
+        }
+
+        // Write a new file and move it into place, so a crash can't leave the journal half written
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+/// Install a single route or address, replacing it if it already exists
+async fn install(rt_handle: &Handle, entry: &Owned) -> Result<(), RouteSetError> {
+    match entry {
+        Owned::Route {
+            destination,
+            link,
+            mtu,
+            metric,
+        } => {
+            let link_index = crate::link::get_link_index(rt_handle, link)
+                .await?
+                .ok_or_else(|| RouteSetError::LinkNotFound(link.clone()))?;
+            crate::route::route_replace(*destination, rt_handle, link_index, *mtu, *metric).await?;
+        }
+        Owned::Discard {
+            destination,
+            kind,
+            metric,
+        } => crate::route::route_add_discard(*destination, rt_handle, *kind, *metric).await?,
+        Owned::Address { address, link } => {
+            let link_index = crate::link::get_link_index(rt_handle, link)
+                .await?
+                .ok_or_else(|| RouteSetError::LinkNotFound(link.clone()))?;
+            crate::ip::addr_replace(address.addr(), address.prefix_len(), rt_handle, link_index)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove a single route or address. Anything that is already gone counts as removed.
+async fn uninstall(rt_handle: &Handle, entry: &Owned) -> Result<(), RouteSetError> {
+    let result = match entry {
+        Owned::Route {
+            destination, link, ..
+        } => match crate::link::get_link_index(rt_handle, link).await? {
+            Some(link_index) => crate::route::route_del(*destination, rt_handle, link_index).await,
+            None => Ok(()),
+        },
+        Owned::Discard {
+            destination,
+            kind,
+            metric,
+        } => crate::route::route_del_discard(*destination, rt_handle, *kind, *metric).await,
+        Owned::Address { address, link } => {
+            match crate::link::get_link_index(rt_handle, link).await? {
+                Some(link_index) => {
+                    crate::ip::addr_del(address.addr(), address.prefix_len(), rt_handle, link_index)
+                        .await
+                }
+                None => Ok(()),
+            }
+        }
+    };
+    match result {
+        Err(rtnetlink::Error::NetlinkError(message)) if message.raw_code().abs() == ESRCH => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_entry_round_trip() {
+        let entries = [
+            Owned::Route {
+                destination: "64:ff9b::/96".parse().unwrap(),
+                link: "nat64i0".to_string(),
+                mtu: Some(1500),
+                metric: None,
+            },
+            Owned::Route {
+                destination: "192.0.2.0/24".parse().unwrap(),
+                link: "nat64i0".to_string(),
+                mtu: None,
+                metric: Some(10),
+            },
+            Owned::Discard {
+                destination: "192.0.2.0/24".parse().unwrap(),
+                kind: DiscardKind::Unreachable,
+                metric: u32::MAX,
+            },
+            Owned::Address {
+                address: "192.0.2.1/24".parse().unwrap(),
+                link: "eth0".to_string(),
+            },
+        ];
+        for entry in entries {
+            assert_eq!(entry.to_string().parse::<Owned>(), Ok(entry));
+        }
+    }
+
+    #[test]
+    fn test_journal_entry_invalid() {
+        assert!("route".parse::<Owned>().is_err());
+        assert!("route 192.0.2.0/24".parse::<Owned>().is_err());
+        assert!("route 192.0.2.0/24 eth0 mtu=big".parse::<Owned>().is_err());
+        assert!("discard 2001:db8::/32 blackhole".parse::<Owned>().is_err());
+        assert!("discard 192.0.2.0/24 drop".parse::<Owned>().is_err());
+        assert!("rule 192.0.2.0/24 eth0".parse::<Owned>().is_err());
+    }
+
+    #[test]
+    fn test_with_journal() {
+        let path = std::env::temp_dir().join(format!("rtnl-route-set-{}", std::process::id()));
+
+        // A missing journal is empty
+        let _ = std::fs::remove_file(&path);
+        assert!(RouteSet::with_journal(&path).unwrap().entries().is_empty());
+
+        // Anything left in it is picked up again
+        std::fs::write(
+            &path,
+            "route 64:ff9b::/96 nat64i0 mtu=1500\n\ndiscard 192.0.2.0/24 blackhole metric=100\n",
+        )
+        .unwrap();
+        let set = RouteSet::with_journal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(set.entries().len(), 2);
+        assert_eq!(
+            set.entries()[1],
+            Owned::Discard {
+                destination: "192.0.2.0/24".parse().unwrap(),
+                kind: DiscardKind::Blackhole,
+                metric: 100,
+            }
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Utilities for interacting with policy routing rules
+
+use netlink_packet_route::{rule::Nla, RuleMessage, FR_ACT_TO_TBL};
+use rtnetlink::Handle;
+
+/// Returned when deleting a rule that doesn't exist
+const ENOENT: i32 = 2;
+
+/// Address family a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+/// A rule looking up a routing table for all traffic arriving on an interface, like
+/// `ip rule add iif <interface> lookup <table>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputInterfaceRule {
+    pub family: Family,
+    pub input_interface: String,
+    pub table: u32,
+    /// Rules with a lower priority are matched first
+    pub priority: u32,
+    /// Ignore routes in the table with this prefix length or shorter, moving on to the next rule instead.
+    /// `Some(0)` ignores only default routes.
+    pub suppress_prefix_len: Option<u32>,
+}
+
+impl InputInterfaceRule {
+    /// Build the message describing this rule
+    fn message(&self, rt_handle: &Handle) -> RuleMessage {
+        let request = rt_handle
+            .rule()
+            .add()
+            .input_interface(self.input_interface.clone())
+            .table_id(self.table)
+            .priority(self.priority)
+            .action(FR_ACT_TO_TBL);
+        let mut message = match self.family {
+            Family::Ipv4 => request.v4().message_mut().clone(),
+            Family::Ipv6 => request.v6().message_mut().clone(),
+        };
+        if let Some(prefix_len) = self.suppress_prefix_len {
+            message.nlas.push(Nla::SuppressPrefixLen(prefix_len));
+        }
+        message
+    }
+}
+
+/// Add a rule, replacing an identical one if it already exists
+///
+/// The kernel happily adds duplicate rules, so any existing copy is removed first.
+pub async fn rule_replace(
+    rule: &InputInterfaceRule,
+    rt_handle: &Handle,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Adding rule {:?}", rule);
+    match rule_del(rule, rt_handle).await {
+        Err(rtnetlink::Error::NetlinkError(message)) if message.raw_code().abs() == ENOENT => {}
+        result => result?,
+    }
+
+    let mut request = rt_handle.rule().add();
+    *request.message_mut() = rule.message(rt_handle);
+    request.execute().await.map_err(|err| {
+        log::error!("Failed to add rule for {}", rule.input_interface);
+        log::error!("{}", err);
+        err
+    })
+}
+
+/// Remove a rule added by [`rule_replace`]
+pub async fn rule_del(
+    rule: &InputInterfaceRule,
+    rt_handle: &Handle,
+) -> Result<(), rtnetlink::Error> {
+    log::trace!("Removing rule {:?}", rule);
+    rt_handle
+        .rule()
+        .del(rule.message(rt_handle))
+        .execute()
+        .await
+}
@@ -0,0 +1,411 @@
+//! Expansion of `${VAR}` references in config file values
+//!
+//! References are only expanded inside string values, after the file has been parsed. Comments are never looked at,
+//! and a variable's value is used exactly as it is, without needing to be escaped for the file's format. Expansion
+//! happens while the config is being deserialized, so that errors are reported at the location of the value.
+
+use serde::de::{
+    self, DeserializeSeed, Deserializer, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor,
+};
+use std::fmt;
+
+/// Replace every `${VAR}` in a value with the value of the environment variable `VAR`.
+///
+/// `$${` may be used to write a literal `${`. Referencing an unset variable is an error.
+pub fn expand(value: &str) -> Result<String, String> {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        // Handle escaped references
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        // Find the end of the variable name
+        let Some(length) = rest[start + 2..].find('}') else {
+            return Err(format!(
+                "Unterminated variable reference at character {}",
+                value.len() - rest.len() + start + 1
+            ));
+        };
+        let name = &rest[start + 2..start + 2 + length];
+
+        // Substitute in the value
+        let variable = std::env::var(name)
+            .map_err(|_| format!("Environment variable `{}` is not set", name))?;
+        output.push_str(&rest[..start]);
+        output.push_str(&variable);
+        rest = &rest[start + 2 + length + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Wraps a deserializer, expanding references in every string value it produces
+pub struct Expand<D>(pub D);
+
+/// Forward a `deserialize_*` method, expanding the strings its visitor is given
+macro_rules! forward_deserialize {
+    ($($method: ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.0.$method(ExpandVisitor(visitor))
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Expand<D> {
+    type Error = D::Error;
+
+    forward_deserialize!(
+        deserialize_any,
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_i128,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_u128,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_char,
+        deserialize_str,
+        deserialize_string,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_option,
+        deserialize_unit,
+        deserialize_seq,
+        deserialize_map,
+        deserialize_identifier,
+        deserialize_ignored_any,
+    );
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_unit_struct(name, ExpandVisitor(visitor))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0
+            .deserialize_newtype_struct(name, ExpandVisitor(visitor))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_tuple(len, ExpandVisitor(visitor))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0
+            .deserialize_tuple_struct(name, len, ExpandVisitor(visitor))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0
+            .deserialize_struct(name, fields, ExpandVisitor(visitor))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0
+            .deserialize_enum(name, variants, ExpandVisitor(visitor))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.0.is_human_readable()
+    }
+}
+
+/// Passes expanded strings on to the wrapped visitor, and wraps everything nested inside the value
+struct ExpandVisitor<V>(V);
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for ExpandVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.0.expecting(formatter)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        self.0.visit_string(expand(value).map_err(E::custom)?)
+    }
+
+    fn visit_borrowed_str<E: de::Error>(self, value: &'de str) -> Result<Self::Value, E> {
+        // Most values don't reference anything, and can be passed on without copying them
+        if value.contains("${") {
+            self.0.visit_string(expand(value).map_err(E::custom)?)
+        } else {
+            self.0.visit_borrowed_str(value)
+        }
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Self::Value, E> {
+        self.0.visit_string(expand(&value).map_err(E::custom)?)
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Self::Value, E> {
+        self.0.visit_bool(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        self.0.visit_i64(value)
+    }
+
+    fn visit_i128<E: de::Error>(self, value: i128) -> Result<Self::Value, E> {
+        self.0.visit_i128(value)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        self.0.visit_u64(value)
+    }
+
+    fn visit_u128<E: de::Error>(self, value: u128) -> Result<Self::Value, E> {
+        self.0.visit_u128(value)
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+        self.0.visit_f64(value)
+    }
+
+    fn visit_char<E: de::Error>(self, value: char) -> Result<Self::Value, E> {
+        self.0.visit_char(value)
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+        self.0.visit_bytes(value)
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, value: &'de [u8]) -> Result<Self::Value, E> {
+        self.0.visit_borrowed_bytes(value)
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+        self.0.visit_byte_buf(value)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.0.visit_none()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.0.visit_some(Expand(deserializer))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.0.visit_unit()
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        self.0.visit_newtype_struct(Expand(deserializer))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        self.0.visit_seq(Expand(seq))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        self.0.visit_map(Expand(map))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        self.0.visit_enum(Expand(data))
+    }
+}
+
+/// Deserializes a nested value through [`Expand`]
+struct ExpandSeed<S>(S);
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for ExpandSeed<S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<S::Value, D::Error> {
+        self.0.deserialize(Expand(deserializer))
+    }
+}
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for Expand<A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, A::Error> {
+        self.0.next_element_seed(ExpandSeed(seed))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.0.size_hint()
+    }
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for Expand<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        // Keys are field names, so they are left alone
+        self.0.next_key_seed(seed)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        self.0.next_value_seed(ExpandSeed(seed))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.0.size_hint()
+    }
+}
+
+impl<'de, A: EnumAccess<'de>> EnumAccess<'de> for Expand<A> {
+    type Error = A::Error;
+    type Variant = Expand<A::Variant>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), A::Error> {
+        let (value, variant) = self.0.variant_seed(seed)?;
+        Ok((value, Expand(variant)))
+    }
+}
+
+impl<'de, A: VariantAccess<'de>> VariantAccess<'de> for Expand<A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.0.unit_variant()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, A::Error> {
+        self.0.newtype_variant_seed(ExpandSeed(seed))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        self.0.tuple_variant(len, ExpandVisitor(visitor))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, A::Error> {
+        self.0.struct_variant(fields, ExpandVisitor(visitor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Parse a JSON document, expanding references in its values
+    fn from_json(data: &str) -> Result<HashMap<String, String>, serde_json::Error> {
+        let mut deserializer = serde_json::Deserializer::from_str(data);
+        serde::Deserialize::deserialize(Expand(&mut deserializer))
+    }
+
+    /// Parse a YAML document, expanding references in its values
+    fn from_yaml(data: &str) -> Result<HashMap<String, String>, serde_yaml::Error> {
+        serde::Deserialize::deserialize(Expand(serde_yaml::Deserializer::from_str(data)))
+    }
+
+    #[test]
+    fn test_expand() {
+        std::env::set_var("PROTOMASK_TEST_EXPAND", "eth0");
+        assert_eq!(
+            expand("dev ${PROTOMASK_TEST_EXPAND}!").unwrap(),
+            "dev eth0!"
+        );
+        assert_eq!(expand("no references").unwrap(), "no references");
+    }
+
+    #[test]
+    fn test_escaped_reference() {
+        assert_eq!(
+            expand("$${PROTOMASK_TEST_UNSET} costs $$5").unwrap(),
+            "${PROTOMASK_TEST_UNSET} costs $$5"
+        );
+    }
+
+    #[test]
+    fn test_unset_variable() {
+        assert_eq!(
+            expand("${PROTOMASK_TEST_UNSET}").unwrap_err(),
+            "Environment variable `PROTOMASK_TEST_UNSET` is not set"
+        );
+        assert!(expand("${PROTOMASK_TEST_UNSET").is_err());
+    }
+
+    #[test]
+    fn test_error_location() {
+        let error =
+            from_json("{\n  \"a\": \"b\",\n  \"c\": \"${PROTOMASK_TEST_UNSET}\"\n}").unwrap_err();
+        assert_eq!(error.line(), 3);
+        assert!(error.to_string().contains("PROTOMASK_TEST_UNSET"));
+
+        let error = from_yaml("a: b\nc: ${PROTOMASK_TEST_UNSET}\n").unwrap_err();
+        assert_eq!(error.location().unwrap().line(), 2);
+    }
+
+    #[test]
+    fn test_values_are_not_escaped() {
+        std::env::set_var("PROTOMASK_TEST_QUOTES", "a \"quoted\"\\ value\nb: c");
+        let expected = "a \"quoted\"\\ value\nb: c";
+        let parsed = from_json("{\"a\": \"${PROTOMASK_TEST_QUOTES}\"}").unwrap();
+        assert_eq!(
+            parsed,
+            HashMap::from([("a".to_string(), expected.to_string())])
+        );
+        let parsed = from_yaml("a: ${PROTOMASK_TEST_QUOTES}\n").unwrap();
+        assert_eq!(
+            parsed,
+            HashMap::from([("a".to_string(), expected.to_string())])
+        );
+    }
+
+    #[test]
+    fn test_comments_are_ignored() {
+        let parsed =
+            from_yaml("# Set ${PROTOMASK_TEST_UNSET} first\na: b # or ${PROTOMASK_TEST_UNSET}\n")
+                .unwrap();
+        assert_eq!(parsed, HashMap::from([("a".to_string(), "b".to_string())]));
+    }
+}
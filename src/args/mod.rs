@@ -1,9 +1,197 @@
 //! This module contains the definitions for each binary's CLI arguments and config file structure for the sake of readability.
 
 use cfg_if::cfg_if;
+use serde::de::DeserializeOwned;
+use std::path::Path;
 
+mod env_vars;
+pub mod multi;
 pub mod protomask;
 pub mod protomask_clat;
+pub mod protomask_nat46;
+pub mod protomask_replay;
+
+/// Parse CLI args, also returning the IDs of every argument that was explicitly set on the command line (including within subcommands)
+///
+/// Every option can also be set through a `PROTOMASK_<FLAG>` environment variable. These count as being set on the command line.
+pub fn parse_with_explicit_args<T: clap::Parser>() -> (T, Vec<String>) {
+    let matches = with_env_vars(T::command()).get_matches();
+    let mut explicit_args = Vec::new();
+    let mut current = Some(&matches);
+    while let Some(matches) = current {
+        explicit_args.extend(
+            matches
+                .ids()
+                .filter(|id| {
+                    matches!(
+                        matches.value_source(id.as_str()),
+                        Some(
+                            clap::parser::ValueSource::CommandLine
+                                | clap::parser::ValueSource::EnvVariable
+                        )
+                    )
+                })
+                .map(ToString::to_string),
+        );
+        current = matches.subcommand().map(|(_, matches)| matches);
+    }
+    match T::from_arg_matches(&matches) {
+        Ok(args) => (args, explicit_args),
+        Err(error) => error.exit(),
+    }
+}
+
+/// Allow every long option (in every subcommand) to be set through an environment variable named after it.
+///
+/// For example, `--pool-prefix` becomes `PROTOMASK_POOL_PREFIX`. Options that can be repeated take a comma-separated list.
+fn with_env_vars(command: clap::Command) -> clap::Command {
+    let mut command = command.mut_args(|arg| {
+        let Some(long) = arg.get_long() else {
+            return arg;
+        };
+        let name = format!("PROTOMASK_{}", long.to_uppercase().replace('-', "_"));
+        if matches!(arg.get_action(), clap::ArgAction::Append) {
+            arg.env(name).value_delimiter(',')
+        } else {
+            arg.env(name)
+        }
+    });
+
+    let subcommands: Vec<_> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    for subcommand in subcommands {
+        command = command.mut_subcommand(subcommand, with_env_vars);
+    }
+    command
+}
+
+/// Check if any of the args making up `T` were explicitly set on the command line
+pub fn any_explicitly_set<T: clap::Args>(explicit_args: &[String]) -> bool {
+    T::augment_args(clap::Command::new(""))
+        .get_arguments()
+        .any(|arg| explicit_args.iter().any(|id| id == arg.get_id().as_str()))
+}
+
+/// Copy the listed fields from `$source` into `$target`, but only if they were explicitly set on the command line
+macro_rules! apply_overrides {
+    ($explicit_args: expr, $target: expr, $source: expr, [$($field: ident),* $(,)?]) => {
+        $(
+            if $explicit_args.iter().any(|id| id == stringify!($field)) {
+                $target.$field = $source.$field.clone();
+            }
+        )*
+    };
+}
+pub(crate) use apply_overrides;
+
+/// Supported config file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Guess the format of a config file from its extension, falling back to JSON
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+}
+
+/// Read and parse a config file.
+///
+/// If no format is given, it is detected from the file extension.
+/// In JSON files, lines starting with `//` are treated as comments and ignored.
+/// They are blanked rather than removed so that error locations still line up with the file.
+///
+/// `${VAR}` references in string values are replaced with the value of the matching environment variable.
+pub fn read_config_file<T: DeserializeOwned>(
+    path: &Path,
+    format: Option<ConfigFormat>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let data = std::fs::read_to_string(path)?;
+    match format.unwrap_or_else(|| ConfigFormat::from_path(path)) {
+        ConfigFormat::Json => {
+            let data = data
+                .lines()
+                .map(|line| {
+                    if line.trim_start().starts_with("//") {
+                        ""
+                    } else {
+                        line
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let mut deserializer = serde_json::Deserializer::from_str(&data);
+            let config = T::deserialize(env_vars::Expand(&mut deserializer))?;
+            deserializer.end()?;
+            Ok(config)
+        }
+        ConfigFormat::Yaml => Ok(T::deserialize(env_vars::Expand(
+            serde_yaml::Deserializer::from_str(&data),
+        ))?),
+    }
+}
+
+/// Smallest MTU that IPv6 allows (RFC8200)
+pub const MIN_MTU: u32 = 1280;
+
+/// Largest MTU an IP packet can fill
+pub const MAX_MTU: u32 = 65535;
+
+fn default_mtu() -> u32 {
+    1500
+}
+
+/// Check if a routing table is one the kernel uses itself (unspecified, default, main, or local)
+fn is_reserved_table(table: u32) -> bool {
+    matches!(table, 0 | 253..=255)
+}
+
+/// What to do with packets sent to a group of hosts (IPv4 broadcast and multicast, and IPv6 multicast)
+#[derive(
+    Debug,
+    serde::Deserialize,
+    serde::Serialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum MulticastHandling {
+    /// Silently drop them
+    #[default]
+    Drop,
+    /// Send them through the usual translation path, like any other packet
+    Translate,
+}
+
+/// Crash reporting configuration. Nothing is reported unless a DSN is set.
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Report crashes to the Sentry project with this DSN (requires the `sentry` build feature)
+    #[clap(long = "sentry-dsn")]
+    #[serde(skip_serializing)]
+    pub dsn: Option<String>,
+
+    /// Environment to tag crash reports with (eg. `production`)
+    #[clap(long = "sentry-environment")]
+    pub environment: Option<String>,
+
+    /// Release to tag crash reports with (defaults to the protomask version)
+    #[clap(long = "sentry-release")]
+    pub release: Option<String>,
+}
 
 // Used to trick the build process into including a CLI argument based on a feature flag
 cfg_if! {
@@ -13,6 +201,14 @@ cfg_if! {
             /// Expose the puffin HTTP server on this endpoint
             #[clap(long)]
             pub puffin_endpoint: Option<std::net::SocketAddr>,
+
+            /// Write a puffin capture of recent and slow packets to this directory whenever SIGUSR2 is received
+            #[clap(long)]
+            pub puffin_capture_dir: Option<std::path::PathBuf>,
+
+            /// Also write a puffin capture every this many seconds
+            #[clap(long, requires = "puffin_capture_dir")]
+            pub puffin_capture_interval: Option<u64>,
         }
     } else {
         #[derive(Debug, clap::Args)]
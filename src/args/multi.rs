@@ -0,0 +1,238 @@
+//! Config file definitions for running several translators from a single `protomask` process
+
+use super::{protomask, protomask::ConfigIssue, protomask_clat, ConfigFormat, TelemetryConfig};
+use std::{net::SocketAddr, path::Path, path::PathBuf};
+
+/// A set of translator instances to run side by side
+#[derive(Debug, serde::Deserialize)]
+pub struct MultiConfig {
+    /// Enable prometheus metrics on a given address. Metrics from every instance are served together.
+    #[serde(rename = "prometheus_bind_addr", default)]
+    pub prom_bind_addr: Option<SocketAddr>,
+
+    /// Serve /healthz and /readyz on a dedicated address
+    #[serde(default)]
+    pub health_bind_addr: Option<SocketAddr>,
+
+    /// Crash reporting for the whole process
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+
+    /// Every translator to run. Each one gets its own TUN interface.
+    pub instances: Vec<InstanceConfig>,
+}
+
+/// A single translator instance
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+#[allow(clippy::large_enum_variant)]
+pub enum InstanceConfig {
+    Nat64 {
+        /// Name of the TUN interface to create
+        #[serde(default = "default_nat64_interface")]
+        interface: String,
+        /// Write packets that fail translation to this pcap file
+        #[serde(default)]
+        capture_drops: Option<PathBuf>,
+        #[serde(flatten)]
+        config: protomask::Config,
+    },
+    Clat {
+        /// Name of the TUN interface to create
+        #[serde(default = "default_clat_interface")]
+        interface: String,
+        /// Write packets that fail translation to this pcap file
+        #[serde(default)]
+        capture_drops: Option<PathBuf>,
+        #[serde(flatten)]
+        config: protomask_clat::Config,
+    },
+}
+
+fn default_nat64_interface() -> String {
+    "nat%d".to_string()
+}
+
+fn default_clat_interface() -> String {
+    "clat%d".to_string()
+}
+
+impl InstanceConfig {
+    /// Name of this instance's TUN interface
+    pub fn interface(&self) -> &str {
+        match self {
+            Self::Nat64 { interface, .. } | Self::Clat { interface, .. } => interface,
+        }
+    }
+}
+
+impl MultiConfig {
+    /// Read a multi-instance config file, exiting if it is invalid
+    pub fn load(path: &Path, format: Option<ConfigFormat>) -> Self {
+        if !path.exists() {
+            log::error!("Config file not found: {}", path.display());
+            std::process::exit(1)
+        }
+        let config: Self = match super::read_config_file(path, format) {
+            Ok(config) => config,
+            Err(error) => {
+                log::error!("{}: {}", path.display(), error);
+                std::process::exit(1)
+            }
+        };
+
+        let issues = config.validate();
+        if !issues.is_empty() {
+            for issue in issues {
+                log::error!("{}: {}", path.display(), issue);
+            }
+            std::process::exit(1);
+        }
+
+        config
+    }
+
+    /// Check the config for problems, both within each instance and between instances
+    fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if self.instances.is_empty() {
+            issues.push(ConfigIssue {
+                location: "instances".to_string(),
+                message: "at least one instance must be configured".to_string(),
+            });
+        }
+
+        for (i, instance) in self.instances.iter().enumerate() {
+            match instance {
+                InstanceConfig::Nat64 { config, .. } => {
+                    issues.extend(config.validate().into_iter().map(|issue| ConfigIssue {
+                        location: format!("instances[{}].{}", i, issue.location),
+                        message: issue.message,
+                    }));
+
+                    // These act on the whole process, so only make sense for a lone translator
+                    if config.translation_prefix_from.is_some() {
+                        issues.push(ConfigIssue {
+                            location: format!("instances[{}].translation_prefix_from", i),
+                            message: "is not supported in multi-instance configs".to_string(),
+                        });
+                    }
+                    if config.drain_timeout > 0 {
+                        issues.push(ConfigIssue {
+                            location: format!("instances[{}].drain_timeout", i),
+                            message: "is not supported in multi-instance configs".to_string(),
+                        });
+                    }
+                    if config.upgrade_socket.is_some() {
+                        issues.push(ConfigIssue {
+                            location: format!("instances[{}].upgrade_socket", i),
+                            message: "is not supported in multi-instance configs".to_string(),
+                        });
+                    }
+                }
+                InstanceConfig::Clat { config, .. } => {
+                    issues.extend(config.validate().into_iter().map(|issue| ConfigIssue {
+                        location: format!("instances[{}].{}", i, issue.location),
+                        message: issue.message,
+                    }));
+
+                    // The bus name can only be claimed once
+                    if config.dbus
+                        && self.instances[..i].iter().any(
+                            |other| matches!(other, InstanceConfig::Clat { config, .. } if config.dbus),
+                        )
+                    {
+                        issues.push(ConfigIssue {
+                            location: format!("instances[{}].dbus", i),
+                            message: "only one instance can be exposed over D-Bus".to_string(),
+                        });
+                    }
+                }
+            }
+
+            // Interface names are only required to be unique when they aren't templates
+            let interface = instance.interface();
+            if !interface.contains("%d")
+                && self.instances[..i]
+                    .iter()
+                    .any(|other| other.interface() == interface)
+            {
+                issues.push(ConfigIssue {
+                    location: format!("instances[{}].interface", i),
+                    message: format!("`{}` is used by more than one instance", interface),
+                });
+            }
+
+            // Instances share the process, so state dumps would overwrite each other
+            if let InstanceConfig::Nat64 { config, .. } = instance {
+                if self
+                    .nat64_configs(i)
+                    .any(|other| other.state_dump_path == config.state_dump_path)
+                {
+                    issues.push(ConfigIssue {
+                        location: format!("instances[{}].state_dump_path", i),
+                        message: format!(
+                            "{} is used by more than one instance",
+                            config.state_dump_path.display()
+                        ),
+                    });
+                }
+                if config.control_socket.is_some()
+                    && self
+                        .nat64_configs(i)
+                        .any(|other| other.control_socket == config.control_socket)
+                {
+                    issues.push(ConfigIssue {
+                        location: format!("instances[{}].control_socket", i),
+                        message: "the socket is used by more than one instance".to_string(),
+                    });
+                }
+                if config.replication.listen.is_some()
+                    && self
+                        .nat64_configs(i)
+                        .any(|other| other.replication.listen == config.replication.listen)
+                {
+                    issues.push(ConfigIssue {
+                        location: format!("instances[{}].replication.listen", i),
+                        message: "the address is used by more than one instance".to_string(),
+                    });
+                }
+                if config.agentx_master.is_some()
+                    && self
+                        .nat64_configs(i)
+                        .any(|other| other.agentx_master.is_some())
+                {
+                    issues.push(ConfigIssue {
+                        location: format!("instances[{}].agentx_master", i),
+                        message: "only one instance can register with an SNMP master agent"
+                            .to_string(),
+                    });
+                }
+                for pool in &config.pool_prefixes {
+                    if self
+                        .nat64_configs(i)
+                        .flat_map(|other| other.pool_prefixes.iter())
+                        .any(|other| other.contains(pool) || pool.contains(other))
+                    {
+                        issues.push(ConfigIssue {
+                            location: format!("instances[{}].pool", i),
+                            message: format!("{} overlaps with another instance's pool", pool),
+                        });
+                    }
+                }
+            }
+        }
+
+        issues
+    }
+
+    /// Iterate over the configs of all NAT64 instances before `index`
+    fn nat64_configs(&self, index: usize) -> impl Iterator<Item = &protomask::Config> {
+        self.instances[..index]
+            .iter()
+            .filter_map(|instance| match instance {
+                InstanceConfig::Nat64 { config, .. } => Some(config),
+                InstanceConfig::Clat { .. } => None,
+            })
+    }
+}
@@ -1,24 +1,168 @@
 use std::{
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
+use fast_nat::{PortBlockLayout, PortBlockTable, SessionTimeouts};
+use interproto::protocols::{
+    icmp::TypeCodeOverride,
+    tcp_options::{OptionAction, OptionPolicy},
+};
 use ipnet::{Ipv4Net, Ipv6Net};
 
-use crate::common::rfc6052::parse_network_specific_prefix;
+use crate::common::{
+    address_hook::AddressHook,
+    dscp::{DscpProtocol, DscpRule, MAX_DSCP},
+    http::HttpUrl,
+    icmp_rate_limit::{ErrorRateLimiter, Rate},
+    interface,
+    lease_store::LeaseStore,
+    numa::NumaPlacement,
+    packet_buffer::{BufferSize, InterfaceMtu},
+    packet_queue::{DropPolicy, PacketQueue},
+    policy::PolicyScript,
+    prefix_tables::{PrefixTables, Tenant},
+    rdns::HostnameTemplates,
+    rfc6052::{carve_translation_prefix, parse_network_specific_prefix},
+    session_log::SessionLogTarget,
+    static_map_file,
+    upstream_health::UpstreamProbes,
+};
 
-use super::ProfilerArgs;
+use super::{ConfigFormat, MulticastHandling, ProfilerArgs, TelemetryConfig};
 
 #[derive(clap::Parser)]
-#[clap(author, version, about="Fast and simple NAT64", long_about = None)]
+#[clap(author, version, about="Fast and simple NAT64 (and friends)", long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Running without a subcommand is the same as `protomask nat64`
+    #[command(flatten)]
+    pub nat64: Args,
+}
+
+impl Cli {
+    /// Parse the CLI args, keeping track of which config values were set on the command line
+    pub fn parse_cli() -> Self {
+        let (mut cli, explicit_args) = super::parse_with_explicit_args::<Self>();
+        match &mut cli.command {
+            Some(Command::Nat64(args)) => args.explicit_args = explicit_args,
+            Some(Command::Clat(args)) => args.explicit_args = explicit_args,
+            Some(Command::Nat46(args)) => args.explicit_args = explicit_args,
+            _ => cli.nat64.explicit_args = explicit_args,
+        }
+        cli
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    /// Run a NAT64
+    Nat64(Args),
+
+    /// Run a Customer-side transLATor (CLAT)
+    Clat(super::protomask_clat::Args),
+
+    /// Run a NAT46, giving IPv4-only clients access to IPv6-only servers
+    Nat46(super::protomask_nat46::Args),
+
+    /// Run several translators from a single config file, each on its own TUN interface
+    Multi {
+        /// Path to the config file describing every instance
+        #[clap(short, long)]
+        config: PathBuf,
+    },
+
+    /// Validate a config file without starting the translator
+    Check {
+        /// Which program the config file is for
+        #[clap(short, long, value_enum, default_value = "nat64")]
+        kind: ConfigKind,
+
+        /// Path to the config file to check
+        config: PathBuf,
+    },
+
+    /// Write a commented example config file
+    InitConfig {
+        /// Which program to generate a config for
+        #[clap(value_enum, default_value = "nat64")]
+        kind: ConfigKind,
+
+        /// Write the config to this file instead of STDOUT
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Check a running NAT64 end to end from this host, and print a report
+    Selftest {
+        /// Translation prefix to test (discovered via DNS64 by default)
+        #[clap(short, long)]
+        prefix: Option<Ipv6Net>,
+
+        /// IPv4 address to reach through the NAT64
+        #[clap(short, long, default_value = "8.8.8.8")]
+        target: Ipv4Addr,
+
+        /// Size of the packets used to check MTU handling
+        #[clap(long, default_value_t = 1500)]
+        mtu: u16,
+    },
+
+    /// Show how a single packet would be translated or dropped, without bringing up a translator
+    Inspect {
+        /// The packet, as hex, base64, or the path of a pcap file
+        packet: String,
+
+        /// Which packet of a pcap file to inspect, counting from 1
+        #[clap(long, default_value_t = 1)]
+        frame: usize,
+
+        /// NAT64 config file to translate with. Without one, translation is stateless through `--prefix`.
+        #[clap(short = 'c', long = "config")]
+        config: Option<PathBuf>,
+
+        /// Translation prefix used when no config file is given
+        #[clap(long, default_value = "64:ff9b::/96")]
+        prefix: Ipv6Net,
+    },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ConfigKind {
+    /// `protomask`
+    Nat64,
+    /// `protomask-clat`
+    Clat,
+    /// `protomask nat46`
+    Nat46,
+}
+
+/// NAT64 arguments
+#[derive(Debug, clap::Args)]
+#[command(group(
+    // The upgrade socket to take over from may be set in the config file instead
+    clap::ArgGroup::new("upgrade_source")
+        .args(["upgrade_socket", "config_file"])
+        .multiple(true)
+))]
 pub struct Args {
     #[command(flatten)]
-    config_data: Option<Config>,
+    config_data: Config,
 
-    /// Path to a config file to read
-    #[clap(short = 'c', long = "config", conflicts_with = "Config")]
+    /// Path to a config file to read. Any config values also passed as CLI args will override those in the file.
+    #[clap(short = 'c', long = "config")]
     config_file: Option<PathBuf>,
 
+    /// Format of the config file (detected from the file extension by default)
+    #[clap(long = "config-format", value_enum, global = true)]
+    pub config_format: Option<ConfigFormat>,
+
     /// Explicitly set the interface name to use
     #[clap(short, long, default_value_t = ("nat%d").to_string())]
     pub interface: String,
@@ -26,47 +170,114 @@ pub struct Args {
     #[command(flatten)]
     pub profiler_args: ProfilerArgs,
 
+    /// Write packets that fail translation to this pcap file (drop reasons are written to `<file>.reasons`)
+    #[clap(long = "capture-drops")]
+    pub capture_drops: Option<PathBuf>,
+
+    /// Take over the interface and mappings of the protomask process listening on `--upgrade-socket`
+    #[clap(long = "take-over", requires = "upgrade_source")]
+    pub take_over: bool,
+
+    /// Print what would become of each packet in `--input` instead of bringing up a translator
+    #[clap(long = "dry-run", requires = "input")]
+    pub dry_run: bool,
+
+    /// Capture to read packets from during a dry run
+    #[clap(long, requires = "dry_run")]
+    pub input: Option<PathBuf>,
+
     /// Enable verbose logging
     #[clap(short, long)]
     pub verbose: bool,
+
+    /// IDs of all args that were explicitly set on the command line
+    #[clap(skip)]
+    explicit_args: Vec<String>,
 }
 
 impl Args {
-    #[allow(dead_code)]
+    /// Get a way to re-read the config file later, if one was used
+    pub fn reloader(&self) -> Option<ConfigReloader> {
+        self.config_file.clone().map(|path| ConfigReloader {
+            path,
+            format: self.config_format,
+            overrides: self.config_data.clone(),
+            explicit_args: self.explicit_args.clone(),
+        })
+    }
+
     pub fn data(&self) -> Result<Config, Box<dyn std::error::Error>> {
-        match self.config_file {
+        let mut data = match self.config_file {
             Some(ref path) => {
                 // Read the data from the config file
-                let file = std::fs::File::open(path).map_err(|error| match error.kind() {
-                    std::io::ErrorKind::NotFound => {
-                        log::error!("Config file not found: {}", path.display());
-                        std::process::exit(1)
-                    }
-                    _ => error,
-                })?;
-                let data: Config = serde_json::from_reader(file)?;
-
-                // We need at least one pool prefix
-                if data.pool_prefixes.is_empty() {
-                    log::error!("No pool prefixes specified. At least one prefix must be specified in the `pool` property of the config file");
-                    std::process::exit(1);
+                if !path.exists() {
+                    log::error!("Config file not found: {}", path.display());
+                    std::process::exit(1)
                 }
+                let mut data: Config = super::read_config_file(path, self.config_format)?;
 
-                Ok(data)
+                // Anything set on the command line takes priority
+                data.apply_overrides(&self.config_data, &self.explicit_args);
+                data
             }
-            None => match &self.config_data {
-                Some(data) => Ok(data.clone()),
-                None => {
+            None => {
+                if !super::any_explicitly_set::<Config>(&self.explicit_args) {
                     log::error!("No configuration provided. Either use --config to specify a file or set the configuration via CLI args (see --help)");
                     std::process::exit(1)
                 }
-            },
+                self.config_data.clone()
+            }
+        };
+
+        // Fill in the translation prefix if it is to be detected
+        if let Err(error) = data.detect_translation_prefix() {
+            log::error!("Failed to detect translation prefix: {}", error);
+            std::process::exit(1);
+        }
+
+        // Refuse to start with a config that is known to be broken
+        let issues = data.validate();
+        if !issues.is_empty() {
+            for issue in issues {
+                log::error!("Invalid configuration: {}", issue);
+            }
+            std::process::exit(1);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Re-reads a config file, applying the same command line overrides as at startup
+#[derive(Debug)]
+pub struct ConfigReloader {
+    path: PathBuf,
+    format: Option<ConfigFormat>,
+    overrides: Config,
+    explicit_args: Vec<String>,
+}
+
+impl ConfigReloader {
+    /// Read and validate the current contents of the config file
+    pub fn reload(&self) -> Result<Config, String> {
+        let mut data: Config = super::read_config_file(&self.path, self.format)
+            .map_err(|error| format!("{}: {}", self.path.display(), error))?;
+        data.apply_overrides(&self.overrides, &self.explicit_args);
+
+        let issues = data.validate();
+        if !issues.is_empty() {
+            return Err(issues
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; "));
         }
+        Ok(data)
     }
 }
 
 /// Program configuration. Specifiable via either CLI args or a config file
-#[derive(Debug, clap::Args, serde::Deserialize, Clone)]
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
 #[group()]
 pub struct Config {
     /// IPv4 prefixes to use as NAT pool address space
@@ -74,15 +285,51 @@ pub struct Config {
     #[serde(rename = "pool")]
     pub pool_prefixes: Vec<Ipv4Net>,
 
-    /// Static mapping between IPv4 and IPv6 addresses
-    #[clap(skip)]
-    pub static_map: Vec<(Ipv4Addr, Ipv6Addr)>,
+    /// IPv4 addresses in the pool that must never be dynamically assigned
+    #[clap(long = "exclude-address")]
+    #[serde(default)]
+    pub excluded_addresses: Vec<Ipv4Addr>,
+
+    /// IPv4 prefixes in the pool that must never be dynamically assigned
+    #[clap(long = "exclude-prefix")]
+    #[serde(default)]
+    pub excluded_prefixes: Vec<Ipv4Net>,
+
+    /// Static mapping between IPv4 and IPv6 addresses, formatted as `<ipv4>=<ipv6>`
+    #[clap(long = "static-map")]
+    #[serde(default)]
+    pub static_map: Vec<StaticMap>,
+
+    /// JSON, YAML, or hosts-style `.txt` file listing more static mappings. It is watched for changes, which are applied without restarting.
+    #[clap(long = "static-map-file")]
+    #[serde(default)]
+    pub static_map_file: Option<PathBuf>,
 
     /// Enable prometheus metrics on a given address
     #[clap(long = "prometheus")]
     #[serde(rename = "prometheus_bind_addr")]
     pub prom_bind_addr: Option<SocketAddr>,
 
+    /// Also export packet and byte counters for every mapping, labelled with its addresses
+    #[clap(long = "mapping-metrics")]
+    #[serde(default)]
+    pub mapping_metrics: bool,
+
+    /// Time each stage of processing for this fraction of packets (0 to 1), exporting the results as prometheus histograms
+    #[clap(long = "stage-timing-sample-rate", default_value = "0")]
+    #[serde(default)]
+    pub stage_timing_sample_rate: f64,
+
+    /// Trace this fraction of packets (0 to 1), emitting a span with the timing of each stage and the packet's addresses
+    #[clap(long = "packet-trace-sample-rate", default_value = "0")]
+    #[serde(default)]
+    pub packet_trace_sample_rate: f64,
+
+    /// Serve /healthz and /readyz on a dedicated address (they are also served alongside prometheus metrics)
+    #[clap(long = "health")]
+    #[serde(rename = "health_bind_addr", default)]
+    pub health_bind_addr: Option<SocketAddr>,
+
     /// RFC6052 IPv6 translation prefix
     #[clap(long, default_value_t = ("64:ff9b::/96").parse().unwrap(), value_parser = parse_network_specific_prefix)]
     #[serde(
@@ -91,24 +338,2176 @@ pub struct Config {
     )]
     pub translation_prefix: Ipv6Net,
 
+    /// Only translate traffic through the main translation prefix for IPv6 sources in these prefixes (everyone may use it by default)
+    #[clap(long = "prefix-source")]
+    #[serde(default)]
+    pub prefix_sources: Vec<Ipv6Net>,
+
+    /// Carve the translation prefix out of the IPv6 prefix routed to this interface (eg. a Kubernetes pod's `eth0`)
+    #[clap(long = "translation-prefix-from", value_name = "INTERFACE")]
+    #[serde(default)]
+    pub translation_prefix_from: Option<String>,
+
+    /// Additional RFC6052 translation prefixes, formatted as `<prefix>[=<pool>,...][@<source>,...]`. Prefixes without their own pool share the main pool, and prefixes without sources may be used by anyone.
+    #[clap(long = "additional-prefix")]
+    #[serde(default)]
+    pub additional_prefixes: Vec<AdditionalPrefix>,
+
+    /// Customers with their own translation prefix and pool, formatted as `<name>:<prefix>=<pool>+...@<source>+...[#mappings:<count>+sessions:<count>]`. A tenant's sources may only use its prefix, and nobody else may.
+    #[clap(long = "tenant")]
+    #[serde(default)]
+    pub tenants: Vec<TenantDefinition>,
+
+    /// Answer Neighbor Solicitations on this interface for every address in the translation prefixes, for when they are on-link rather than routed
+    #[clap(long = "proxy-ndp", value_name = "INTERFACE")]
+    #[serde(default)]
+    pub ndp_proxy: Option<String>,
+
+    /// Answer ARP requests on this interface for every pool address, for when the pool is part of an on-link subnet rather than routed
+    #[clap(long = "proxy-arp", value_name = "INTERFACE")]
+    #[serde(default)]
+    pub arp_proxy: Option<String>,
+
+    /// Ask this shell command or http:// URL which IPv4 address to give each new IPv6 source, instead of always taking the first free pool address
+    #[clap(long = "address-hook", value_name = "COMMAND|URL")]
+    #[serde(default)]
+    pub address_hook: Option<String>,
+
+    /// Share leases with other NAT64s using the same pool through this store (`redis://[[user]:password@]host[:port][/db]`, needs the `redis` feature)
+    #[clap(long = "lease-store", value_name = "URL")]
+    #[serde(default, skip_serializing)]
+    pub lease_store: Option<String>,
+
+    /// Consult this Rhai script when deciding whether to map new IPv6 sources and translate packets (needs the `scripting` feature)
+    #[clap(long = "policy-script", value_name = "PATH")]
+    #[serde(default)]
+    pub policy_script: Option<PathBuf>,
+
+    /// Pool address the NAT64 uses for itself. When set, TTLs are decremented and Time Exceeded errors are sent from this address, so the NAT64 shows up in traceroute
+    #[clap(long = "translator-address")]
+    #[serde(default)]
+    pub translator_address: Option<Ipv4Addr>,
+
+    /// Send translated ICMPv6 errors from IPv6 routers without a mapping of their own from this IPv4 address (RFC6791). May be repeated to spread them over several addresses, which must be outside the pool.
+    #[clap(long = "icmp-error-source", value_name = "IPV4")]
+    #[serde(default)]
+    pub icmp_error_sources: Vec<Ipv4Addr>,
+
+    /// Translate an ICMP or ICMPv6 type (and code) differently than usual, formatted as `<icmp|icmpv6>:<type>[/<code>]=<type>/<code>`
+    #[clap(long = "icmp-override")]
+    #[serde(default)]
+    pub icmp_overrides: Vec<IcmpOverride>,
+
+    /// Set the DSCP of translated packets matching a rule, formatted as `[<condition>+...]=<dscp>`. Conditions are `protocol:<tcp|udp|icmp>`, `port:<port>`, `source:<prefix>`, and `dscp:<dscp>`, matched against the packet before translation. The first matching rule applies.
+    #[clap(long = "dscp-rule")]
+    #[serde(default)]
+    pub dscp_rules: Vec<DscpRule>,
+
+    /// What to do with packets sent to IPv4 broadcast or multicast addresses, or to IPv6 multicast groups
+    #[clap(long, value_enum, default_value = "drop")]
+    #[serde(default)]
+    pub multicast: MulticastHandling,
+
     /// NAT reservation timeout in seconds
     #[clap(long, default_value = "7200")]
     pub reservation_timeout: u64,
 
+    /// Number of expired mappings to remember, so that returning clients are given their previous IPv4 address (0 disables)
+    #[clap(long = "recent-mappings", default_value = "4096")]
+    #[serde(default = "default_recent_mappings")]
+    pub recent_mappings: usize,
+
+    /// Number of seconds an expired mapping is remembered for
+    #[clap(long = "recent-mapping-ttl", default_value = "86400")]
+    #[serde(default = "default_recent_mapping_ttl")]
+    pub recent_mapping_ttl: u64,
+
+    /// How new clients are given pool addresses. `hashed` starts from an address picked by hashing the client's IPv6 address, so clients keep their address across restarts and cluster members
+    #[clap(long = "address-selection", value_enum, default_value = "first")]
+    #[serde(default)]
+    pub address_selection: AddressSelection,
+
     /// Number of queues to create on the TUN device
     #[clap(long, default_value = "10")]
     #[serde(rename = "queues")]
     pub num_queues: usize,
+
+    /// MTU of the TUN device. Packet buffers are sized to match, and routes towards the device are given matching MTUs.
+    #[clap(long, default_value = "1500")]
+    #[serde(default = "super::default_mtu")]
+    pub mtu: u32,
+
+    /// Only create (or attach to) the TUN interface. Bringing it up and adding routes is left to the environment.
+    #[clap(long = "no-netlink", alias = "no-routes")]
+    #[serde(default)]
+    pub no_netlink: bool,
+
+    /// Carry IPv4 traffic on a second TUN interface with this name, leaving the main interface for IPv6 only
+    #[clap(long = "ipv4-interface", value_name = "NAME")]
+    #[serde(default)]
+    pub ipv4_interface: Option<String>,
+
+    /// Install a fallback route of this kind for each pool prefix, so that pool traffic is discarded rather than following the default route back upstream whenever the pool isn't routed to the NAT64 (such as on standby)
+    #[clap(long = "pool-fallback", value_enum)]
+    #[serde(default)]
+    pub pool_fallback: Option<PoolFallback>,
+
+    /// Record the routes installed outside of the TUN interface (such as fallback routes) in this file, so that any left behind by a previous run can be cleaned up
+    #[clap(long = "route-journal", value_name = "FILE")]
+    #[serde(default)]
+    pub route_journal: Option<PathBuf>,
+
+    /// Send translated traffic out of this uplink, rather than whichever the main routing table prefers, by copying the uplink's default routes into their own routing table and adding policy rules for traffic from the TUN interface
+    #[clap(long = "egress-interface", value_name = "UPLINK")]
+    #[serde(default)]
+    pub egress_interface: Option<String>,
+
+    /// Routing table to copy the egress uplink's default routes into
+    #[clap(long = "egress-table", default_value = "6464")]
+    #[serde(default = "default_egress_table")]
+    pub egress_table: u32,
+
+    /// How to answer IPv4 packets sent to pool addresses that have no mapping
+    #[clap(long = "unmapped-reject", value_enum, default_value = "drop")]
+    #[serde(default)]
+    pub unmapped_reject: UnmappedReject,
+
+    /// On SIGTERM, stop creating mappings and wait up to this many seconds for existing ones to expire before exiting
+    #[clap(long = "drain-timeout", default_value = "0")]
+    #[serde(default)]
+    pub drain_timeout: u64,
+
+    /// Start on standby, with routes withdrawn until promoted with `protomaskctl promote`
+    #[clap(long)]
+    #[serde(default)]
+    pub standby: bool,
+
+    /// Shell command to run after being promoted to active
+    #[clap(long = "on-promote", value_name = "COMMAND")]
+    #[serde(default)]
+    pub on_promote: Option<String>,
+
+    /// Shell command to run after being demoted to standby
+    #[clap(long = "on-demote", value_name = "COMMAND")]
+    #[serde(default)]
+    pub on_demote: Option<String>,
+
+    /// File to write a JSON dump of internal state to upon receiving SIGUSR1
+    #[clap(long, default_value = "/tmp/protomask-state.json")]
+    #[serde(default = "default_state_dump_path")]
+    pub state_dump_path: PathBuf,
+
+    /// Listen for `protomaskctl` requests on this unix socket
+    #[clap(long = "control-socket")]
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+
+    /// Number of recently dropped packets to keep in memory for `protomaskctl drops` (0 to keep none)
+    #[clap(long = "recent-drops", default_value = "256")]
+    #[serde(default = "default_recent_drops")]
+    pub recent_drops: usize,
+
+    /// Hand over to a new process started with `--take-over` when it connects to this unix socket
+    #[clap(long = "upgrade-socket")]
+    #[serde(default)]
+    pub upgrade_socket: Option<PathBuf>,
+
+    /// Serve the gRPC control API on a given address (requires the `grpc` build feature)
+    #[clap(long = "grpc")]
+    #[serde(rename = "grpc_bind_addr", default)]
+    pub grpc_bind_addr: Option<SocketAddr>,
+
+    /// Register with an SNMP master agent over AgentX (a unix socket path, or `tcp:<host>:<port>`)
+    #[clap(long = "agentx")]
+    #[serde(default)]
+    pub agentx_master: Option<String>,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub session_log: SessionLogConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub flow_export: FlowExportConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub tcp_options: TcpOptionsConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub icmp_rate_limit: IcmpRateLimitConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub numa: NumaConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub nftables: NftablesConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub upstream_health: UpstreamHealthConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub rdns: RdnsConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub aggregation: AggregationConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub deterministic_nat: DeterministicNatConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+}
+
+/// IPFIX flow export configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct FlowExportConfig {
+    /// Export IPFIX flow records to this collector (UDP)
+    #[clap(long = "ipfix-collector")]
+    pub collector: Option<SocketAddr>,
+
+    /// Number of seconds between IPFIX exports
+    #[clap(long = "ipfix-interval", default_value = "60")]
+    pub interval: u64,
 }
 
-#[derive(Debug, serde::Deserialize, Clone)]
+impl Default for FlowExportConfig {
+    fn default() -> Self {
+        Self {
+            collector: None,
+            interval: 60,
+        }
+    }
+}
+
+/// Mapping event webhook configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// POST batches of mapping creation and expiry events to this http:// URL as JSON
+    #[clap(long = "webhook-url")]
+    pub url: Option<String>,
+
+    /// Send a batch once this many events are waiting
+    #[clap(long = "webhook-batch-size", default_value = "100")]
+    pub batch_size: usize,
+
+    /// Number of seconds to wait for more events before sending a partial batch
+    #[clap(long = "webhook-flush-interval", default_value = "5")]
+    pub flush_interval: u64,
+
+    /// Most events waiting to be sent before new ones are dropped
+    #[clap(long = "webhook-queue-capacity", default_value = "65536")]
+    #[serde(rename = "queue_capacity")]
+    pub webhook_queue_capacity: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            batch_size: 100,
+            flush_interval: 5,
+            webhook_queue_capacity: 65536,
+        }
+    }
+}
+
+/// TCP option sanitization configuration
+#[derive(Debug, Default, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct TcpOptionsConfig {
+    /// What to do with TCP MD5 signature and TCP-AO options, which can't be verified once their segment is translated
+    #[clap(long = "tcp-signature-options", value_enum, default_value = "keep")]
+    pub signatures: TcpOptionAction,
+
+    /// What to do with experimental and unrecognised TCP options
+    #[clap(long = "tcp-experimental-options", value_enum, default_value = "keep")]
+    pub experimental: TcpOptionAction,
+}
+
+impl TcpOptionsConfig {
+    /// Get the policy to apply to translated TCP segments
+    pub fn policy(&self) -> OptionPolicy {
+        OptionPolicy {
+            signatures: self.signatures.into(),
+            experimental: self.experimental.into(),
+        }
+    }
+}
+
+/// Rate limits for ICMP errors generated by the NAT64
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct IcmpRateLimitConfig {
+    /// Most ICMP errors per second the NAT64 sends to any one destination (0 for no limit)
+    #[clap(long = "icmp-error-rate", default_value = "10")]
+    pub per_destination_rate: u32,
+
+    /// Most ICMP errors the NAT64 sends to any one destination in a burst
+    #[clap(long = "icmp-error-burst", default_value = "10")]
+    pub per_destination_burst: u32,
+
+    /// Most ICMP errors per second the NAT64 sends in total (0 for no limit)
+    #[clap(long = "icmp-error-global-rate", default_value = "1000")]
+    pub global_rate: u32,
+
+    /// Most ICMP errors the NAT64 sends in total in a burst
+    #[clap(long = "icmp-error-global-burst", default_value = "50")]
+    pub global_burst: u32,
+}
+
+impl Default for IcmpRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_destination_rate: 10,
+            per_destination_burst: 10,
+            global_rate: 1000,
+            global_burst: 50,
+        }
+    }
+}
+
+impl IcmpRateLimitConfig {
+    /// Build a rate limiter following this config
+    pub fn limiter(&self) -> ErrorRateLimiter {
+        ErrorRateLimiter::new(
+            Rate {
+                per_second: self.per_destination_rate,
+                burst: self.per_destination_burst,
+            },
+            Rate {
+                per_second: self.global_rate,
+                burst: self.global_burst,
+            },
+        )
+    }
+}
+
+/// Configuration of how packets are read, buffered, and queued between the stages of packet processing
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct PipelineConfig {
+    /// Read and write packets on separate threads, with queues of this many packets in between (0 handles each packet start to finish on one thread)
+    #[clap(long = "queue-capacity", default_value = "0")]
+    pub queue_capacity: usize,
+
+    /// Which packet to drop when a queue is full
+    #[clap(long = "queue-drop-policy", value_enum, default_value = "newest")]
+    pub drop_policy: DropPolicy,
+
+    /// Busy poll the TUN interface for up to this many microseconds before sleeping, trading CPU time for lower latency (0 sleeps until each packet arrives)
+    #[clap(long = "busy-poll-budget", default_value = "0")]
+    pub busy_poll_budget: u64,
+
+    /// Size of each packet buffer in bytes, dropping larger packets. By default, buffers fit the interface MTU and grow when it is raised.
+    #[clap(long = "packet-buffer-size")]
+    pub packet_buffer_size: Option<usize>,
+
+    /// Spare packet buffers each queue keeps for reuse, rather than allocating one for every queued packet
+    #[clap(long = "pooled-buffers", default_value = "256")]
+    pub pooled_buffers: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 0,
+            drop_policy: DropPolicy::default(),
+            busy_poll_budget: 0,
+            packet_buffer_size: None,
+            pooled_buffers: 256,
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Check if packets are queued between stages
+    pub fn is_enabled(&self) -> bool {
+        self.queue_capacity > 0
+    }
+
+    /// Build a queue following this config
+    pub fn queue(&self, name: &'static str) -> PacketQueue {
+        PacketQueue::new(
+            name,
+            self.queue_capacity,
+            self.drop_policy,
+            self.pooled_buffers,
+        )
+    }
+
+    /// Get how to size packet buffers, given the MTU of the interfaces being read
+    pub fn buffer_size(&self, mtu: &Arc<InterfaceMtu>) -> BufferSize {
+        match self.packet_buffer_size {
+            Some(size) => BufferSize::Fixed(size),
+            None => BufferSize::Mtu(Arc::clone(mtu)),
+        }
+    }
+
+    /// Get how long to busy poll for, if at all
+    pub fn busy_poll_budget(&self) -> Option<Duration> {
+        (self.busy_poll_budget > 0).then(|| Duration::from_micros(self.busy_poll_budget))
+    }
+}
+
+/// Configuration of where packet handling threads run on machines with several NUMA nodes
+#[derive(Debug, Default, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct NumaConfig {
+    /// Run packet handling threads on the CPUs of this NUMA node, and allocate their buffers from its memory
+    #[clap(long = "numa-node", conflicts_with = "nic")]
+    pub node: Option<usize>,
+
+    /// Run packet handling threads on the NUMA node this network interface is attached to
+    #[clap(long = "numa-nic")]
+    pub nic: Option<String>,
+}
+
+impl NumaConfig {
+    /// Work out where to place packet handling threads, if anywhere in particular
+    pub fn placement(&self) -> Result<Option<NumaPlacement>, std::io::Error> {
+        match (self.node, &self.nic) {
+            (Some(node), _) => NumaPlacement::for_node(node).map(Some),
+            (None, Some(nic)) => NumaPlacement::for_interface(nic),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Configuration of the nftables rules protecting the translator
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct NftablesConfig {
+    /// Install nftables rules dropping traffic for unmapped pool addresses and limiting ICMP before it reaches protomask (needs the `nftables` feature)
+    #[clap(long = "nftables")]
+    #[serde(rename = "enabled")]
+    pub nftables: bool,
+
+    /// ICMP and ICMPv6 packets per second let through to the translator by the nftables rules, or 0 for no limit
+    #[clap(long = "nftables-icmp-rate", default_value = "1000")]
+    #[serde(rename = "icmp_rate")]
+    pub nftables_icmp_rate: u32,
+}
+
+impl Default for NftablesConfig {
+    fn default() -> Self {
+        Self {
+            nftables: false,
+            nftables_icmp_rate: 1000,
+        }
+    }
+}
+
+/// Upstream health probe configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct UpstreamHealthConfig {
+    /// Ping this IPv4 address to check the upstream that pool traffic leaves through
+    #[clap(long = "upstream-probe-ipv4")]
+    pub ipv4_target: Option<Ipv4Addr>,
+
+    /// Ping this IPv6 address to check the upstream that clients' traffic arrives from
+    #[clap(long = "upstream-probe-ipv6")]
+    pub ipv6_target: Option<Ipv6Addr>,
+
+    /// Number of seconds between upstream probes
+    #[clap(long = "upstream-probe-interval", default_value = "5")]
+    #[serde(rename = "interval")]
+    pub probe_interval: u64,
+
+    /// Number of seconds to wait for an upstream probe to be answered
+    #[clap(long = "upstream-probe-timeout", default_value = "1")]
+    #[serde(rename = "timeout")]
+    pub probe_timeout: u64,
+
+    /// Consecutive failed upstream probes before routes are withdrawn
+    #[clap(long = "upstream-probe-failures", default_value = "3")]
+    #[serde(rename = "failures")]
+    pub probe_failures: u32,
+
+    /// Consecutive passed upstream probes before withdrawn routes are restored
+    #[clap(long = "upstream-probe-successes", default_value = "3")]
+    #[serde(rename = "successes")]
+    pub probe_successes: u32,
+}
+
+impl Default for UpstreamHealthConfig {
+    fn default() -> Self {
+        Self {
+            ipv4_target: None,
+            ipv6_target: None,
+            probe_interval: 5,
+            probe_timeout: 1,
+            probe_failures: 3,
+            probe_successes: 3,
+        }
+    }
+}
+
+impl UpstreamHealthConfig {
+    /// Get the probes to run, if any targets are configured
+    pub fn probes(&self) -> Option<UpstreamProbes> {
+        let targets: Vec<IpAddr> = self
+            .ipv4_target
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(self.ipv6_target.map(IpAddr::V6))
+            .collect();
+        (!targets.is_empty()).then(|| UpstreamProbes {
+            targets,
+            interval: Duration::from_secs(self.probe_interval),
+            timeout: Duration::from_secs(self.probe_timeout),
+            failures: self.probe_failures,
+            successes: self.probe_successes,
+        })
+    }
+}
+
+/// Reverse DNS zone generation configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct RdnsConfig {
+    /// Regularly write a zone fragment with a PTR record for every pool address to this file
+    #[clap(long = "rdns-zone-file")]
+    pub zone_file: Option<PathBuf>,
+
+    /// Hostname for mapped pool addresses. `{ipv4}` and `{ipv6}` are replaced with the mapping's addresses.
+    #[clap(long = "rdns-template")]
+    pub template: Option<String>,
+
+    /// Hostname for unmapped pool addresses, which may use `{ipv4}`. Unmapped addresses are left out if this isn't set.
+    #[clap(long = "rdns-unmapped-template")]
+    pub unmapped_template: Option<String>,
+
+    /// Number of seconds between rewrites of the zone fragment
+    #[clap(long = "rdns-interval", default_value = "60")]
+    #[serde(rename = "interval")]
+    pub rdns_interval: u64,
+}
+
+impl Default for RdnsConfig {
+    fn default() -> Self {
+        Self {
+            zone_file: None,
+            template: None,
+            unmapped_template: None,
+            rdns_interval: 60,
+        }
+    }
+}
+
+/// Subscriber aggregation configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct AggregationConfig {
+    /// Treat every IPv6 address inside a prefix of this length as one subscriber, sharing one IPv4 address (or port block) between its devices
+    #[clap(long = "subscriber-prefix-len")]
+    pub subscriber_prefix_len: Option<u8>,
+
+    /// Number of seconds an idle TCP session of a shared address keeps its port
+    #[clap(long = "tcp-session-timeout", default_value = "7440")]
+    pub tcp_session_timeout: u64,
+
+    /// Number of seconds an idle UDP or ICMP session of a shared address keeps its port
+    #[clap(long = "session-timeout", default_value = "300")]
+    pub session_timeout: u64,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        Self {
+            subscriber_prefix_len: None,
+            tcp_session_timeout: 7440,
+            session_timeout: 300,
+        }
+    }
+}
+
+impl AggregationConfig {
+    /// Get how long idle sessions of a shared address are kept
+    pub fn session_timeouts(&self) -> SessionTimeouts {
+        SessionTimeouts {
+            tcp: Duration::from_secs(self.tcp_session_timeout),
+            other: Duration::from_secs(self.session_timeout),
+        }
+    }
+}
+
+/// Subscriber prefix length used by deterministic NAT when none is configured
+const DEFAULT_DETERMINISTIC_SUBSCRIBER_PREFIX_LEN: u8 = 56;
+
+/// Deterministic NAT (RFC7422) configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct DeterministicNatConfig {
+    /// Give each subscriber inside these IPv6 prefixes a fixed pool address and port block, instead of a whole address
+    #[clap(long = "subscriber-prefix")]
+    pub subscribers: Vec<Ipv6Net>,
+
+    /// Number of ports in each subscriber's port block
+    #[clap(long = "port-block-size", default_value = "2048")]
+    pub port_block_size: u16,
+
+    /// First port handed out in port blocks. Lower ports are never used.
+    #[clap(long = "first-port", default_value = "1024")]
+    pub first_port: u16,
+}
+
+impl Default for DeterministicNatConfig {
+    fn default() -> Self {
+        Self {
+            subscribers: Vec::new(),
+            port_block_size: 2048,
+            first_port: 1024,
+        }
+    }
+}
+
+impl DeterministicNatConfig {
+    /// Check if deterministic NAT is in use
+    pub fn is_enabled(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+}
+
+/// Largest pool (by prefix length) that proxy ARP may be used with
+const MIN_PROXY_ARP_PREFIX_LEN: u8 = 20;
+
+/// Active-standby replication configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// Accept standby connections on this address and stream mapping changes to them
+    #[clap(long = "replication-listen")]
+    pub listen: Option<SocketAddr>,
+
+    /// Run as a standby, copying mappings from the primary at this address (`<host>:<port>`)
+    #[clap(long = "replicate-from")]
+    pub primary: Option<String>,
+
+    /// Secret shared between the primary and its standbys
+    #[clap(long = "replication-secret")]
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+
+    /// Number of seconds between full-table syncs sent to standbys
+    #[clap(long = "replication-sync-interval", default_value = "60")]
+    pub sync_interval: u64,
+
+    /// Most mapping events waiting to be sent to standbys. A standby that falls further behind is sent a full sync.
+    #[clap(long = "replication-queue-capacity", default_value = "65536")]
+    #[serde(rename = "queue_capacity")]
+    pub replication_queue_capacity: usize,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            primary: None,
+            secret: None,
+            sync_interval: 60,
+            replication_queue_capacity: 65536,
+        }
+    }
+}
+
+impl Config {
+    /// If configured, replace the translation prefix with one carved out of an interface's IPv6 prefix
+    pub fn detect_translation_prefix(&mut self) -> Result<(), String> {
+        if let Some(interface) = &self.translation_prefix_from {
+            let network = interface::global_ipv6_prefix(interface)?;
+            self.translation_prefix = carve_translation_prefix(network)?;
+            log::info!(
+                "Using translation prefix {} from {} on {}",
+                self.translation_prefix,
+                network,
+                interface
+            );
+        }
+        Ok(())
+    }
+
+    /// Get the prefix length of a single subscriber, if addresses are aggregated by subscriber
+    pub fn subscriber_prefix_len(&self) -> Option<u8> {
+        match self.aggregation.subscriber_prefix_len {
+            None if self.deterministic_nat.is_enabled() => {
+                Some(DEFAULT_DETERMINISTIC_SUBSCRIBER_PREFIX_LEN)
+            }
+            prefix_len => prefix_len,
+        }
+    }
+
+    /// Build the deterministic NAT port block table
+    pub fn port_block_table(&self) -> Result<PortBlockTable, fast_nat::error::Error> {
+        PortBlockTable::new(
+            &self.deterministic_nat.subscribers,
+            &self.pool_prefixes,
+            &self.excluded_networks(),
+            PortBlockLayout {
+                subscriber_prefix_len: self.subscriber_prefix_len().unwrap_or(128),
+                first_port: self.deterministic_nat.first_port,
+                block_size: self.deterministic_nat.port_block_size,
+            },
+            self.aggregation.session_timeouts(),
+        )
+    }
+
+    /// Get every part of the pool that must never be dynamically assigned
+    pub fn excluded_networks(&self) -> Vec<Ipv4Net> {
+        self.excluded_addresses
+            .iter()
+            .chain(&self.translator_address)
+            .map(|addr| Ipv4Net::from(*addr))
+            .chain(self.excluded_prefixes.iter().copied())
+            .collect()
+    }
+
+    /// Get every translation prefix other than the main one, along with its own pool (empty if it shares the main pool)
+    pub fn additional_prefix_pools(&self) -> Vec<(Ipv6Net, Vec<Ipv4Net>)> {
+        self.additional_prefixes
+            .iter()
+            .map(|additional| (additional.prefix, additional.pool.clone()))
+            .chain(
+                self.tenants
+                    .iter()
+                    .map(|tenant| (tenant.prefix, tenant.pool.clone())),
+            )
+            .collect()
+    }
+
+    /// Build the address tables for every translation prefix, keeping each to the sources allowed to use it
+    pub fn prefix_tables(&self) -> PrefixTables {
+        let mut prefix_tables = PrefixTables::new(
+            self.translation_prefix,
+            &self.pool_prefixes,
+            &self.additional_prefix_pools(),
+            Duration::from_secs(self.reservation_timeout),
+        );
+        prefix_tables.restrict_sources(self.translation_prefix, &self.prefix_sources);
+        for additional in &self.additional_prefixes {
+            prefix_tables.restrict_sources(additional.prefix, &additional.sources);
+        }
+        for tenant in &self.tenants {
+            prefix_tables.add_tenant(Tenant {
+                name: tenant.name.clone(),
+                prefix: tenant.prefix,
+                sources: tenant.sources.clone(),
+                max_mappings: tenant.max_mappings,
+                max_sessions: tenant.max_sessions,
+            });
+        }
+        prefix_tables
+    }
+
+    /// Get the ICMP type and code overrides, split into those for ICMP to ICMPv6 and those for ICMPv6 to ICMP
+    pub fn icmp_type_code_overrides(&self) -> (Vec<TypeCodeOverride>, Vec<TypeCodeOverride>) {
+        let (icmp, icmpv6): (Vec<_>, Vec<_>) = self
+            .icmp_overrides
+            .iter()
+            .partition(|o| o.from == IcmpVersion::Icmp);
+        let convert = |overrides: Vec<&IcmpOverride>| {
+            overrides
+                .into_iter()
+                .map(|o| TypeCodeOverride {
+                    from_type: o.icmp_type,
+                    from_code: o.code,
+                    to_type: o.to_type,
+                    to_code: o.to_code,
+                })
+                .collect()
+        };
+        (convert(icmp), convert(icmpv6))
+    }
+
+    /// Replace values with those from `overrides` that were explicitly set on the command line
+    fn apply_overrides(&mut self, overrides: &Config, explicit_args: &[String]) {
+        super::apply_overrides!(
+            explicit_args,
+            self,
+            overrides,
+            [
+                pool_prefixes,
+                excluded_addresses,
+                excluded_prefixes,
+                static_map,
+                static_map_file,
+                prom_bind_addr,
+                mapping_metrics,
+                stage_timing_sample_rate,
+                packet_trace_sample_rate,
+                health_bind_addr,
+                translation_prefix,
+                prefix_sources,
+                translation_prefix_from,
+                additional_prefixes,
+                tenants,
+                ndp_proxy,
+                arp_proxy,
+                address_hook,
+                lease_store,
+                policy_script,
+                translator_address,
+                icmp_error_sources,
+                multicast,
+                icmp_overrides,
+                dscp_rules,
+                reservation_timeout,
+                recent_mappings,
+                recent_mapping_ttl,
+                address_selection,
+                num_queues,
+                mtu,
+                no_netlink,
+                ipv4_interface,
+                pool_fallback,
+                route_journal,
+                egress_interface,
+                egress_table,
+                unmapped_reject,
+                standby,
+                on_promote,
+                on_demote,
+                state_dump_path,
+                control_socket,
+                recent_drops,
+                upgrade_socket,
+                grpc_bind_addr,
+                agentx_master,
+            ]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.session_log,
+            overrides.session_log,
+            [
+                file,
+                syslog,
+                max_size,
+                max_files,
+                session_log_queue_capacity
+            ]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.flow_export,
+            overrides.flow_export,
+            [collector, interval]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.webhook,
+            overrides.webhook,
+            [url, batch_size, flush_interval, webhook_queue_capacity]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.tcp_options,
+            overrides.tcp_options,
+            [signatures, experimental]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.pipeline,
+            overrides.pipeline,
+            [
+                queue_capacity,
+                drop_policy,
+                busy_poll_budget,
+                packet_buffer_size,
+                pooled_buffers
+            ]
+        );
+        super::apply_overrides!(explicit_args, self.numa, overrides.numa, [node, nic]);
+        super::apply_overrides!(
+            explicit_args,
+            self.upstream_health,
+            overrides.upstream_health,
+            [
+                ipv4_target,
+                ipv6_target,
+                probe_interval,
+                probe_timeout,
+                probe_failures,
+                probe_successes
+            ]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.nftables,
+            overrides.nftables,
+            [nftables, nftables_icmp_rate]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.icmp_rate_limit,
+            overrides.icmp_rate_limit,
+            [
+                per_destination_rate,
+                per_destination_burst,
+                global_rate,
+                global_burst
+            ]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.rdns,
+            overrides.rdns,
+            [zone_file, template, unmapped_template, rdns_interval]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.aggregation,
+            overrides.aggregation,
+            [subscriber_prefix_len, tcp_session_timeout, session_timeout]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.deterministic_nat,
+            overrides.deterministic_nat,
+            [subscribers, port_block_size, first_port]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.replication,
+            overrides.replication,
+            [
+                listen,
+                primary,
+                secret,
+                sync_interval,
+                replication_queue_capacity
+            ]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.telemetry,
+            overrides.telemetry,
+            [dsn, environment, release]
+        );
+    }
+
+    /// Check the config for mistakes that would prevent protomask from running correctly
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue = |location: String, message: String| {
+            issues.push(ConfigIssue { location, message });
+        };
+
+        // Gather up every translation prefix and pool prefix, along with where they came from
+        let prefixes: Vec<(String, Ipv6Net)> =
+            std::iter::once(("prefix".to_string(), self.translation_prefix))
+                .chain(
+                    self.additional_prefixes
+                        .iter()
+                        .enumerate()
+                        .map(|(i, additional)| {
+                            (
+                                format!("additional_prefixes[{}].prefix", i),
+                                additional.prefix,
+                            )
+                        }),
+                )
+                .chain(self.tenants.iter().enumerate().map(|(i, tenant)| {
+                    (format!("tenants[{}].prefix", i), tenant.prefix)
+                }))
+                .collect();
+        let pools: Vec<(String, Ipv4Net)> = self
+            .pool_prefixes
+            .iter()
+            .enumerate()
+            .map(|(i, prefix)| (format!("pool[{}]", i), *prefix))
+            .chain(
+                self.additional_prefixes
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, additional)| {
+                        additional.pool.iter().enumerate().map(move |(j, prefix)| {
+                            (format!("additional_prefixes[{}].pool[{}]", i, j), *prefix)
+                        })
+                    }),
+            )
+            .chain(self.tenants.iter().enumerate().flat_map(|(i, tenant)| {
+                tenant
+                    .pool
+                    .iter()
+                    .enumerate()
+                    .map(move |(j, prefix)| (format!("tenants[{}].pool[{}]", i, j), *prefix))
+            }))
+            .collect();
+
+        for (i, (location, prefix)) in prefixes.iter().enumerate() {
+            // The translation prefix must be one RFC6052 allows
+            if !rfc6052::ALLOWED_PREFIX_LENS.contains(&prefix.prefix_len()) {
+                issue(
+                    location.clone(),
+                    format!(
+                        "{} has an invalid length. Prefix length must be one of {:?}",
+                        prefix,
+                        rfc6052::ALLOWED_PREFIX_LENS
+                    ),
+                );
+            }
+
+            // Bits 64 through 71 (the "u" octet) must be zero (RFC6052 Section 2.2)
+            if prefix.prefix_len() > 64 && prefix.addr().octets()[8] != 0 {
+                issue(
+                    location.clone(),
+                    format!(
+                        "{} sets bits 64 through 71, which must be zero according to RFC6052",
+                        prefix
+                    ),
+                );
+            }
+
+            // Besides the Well-Known Prefix itself, 64:ff9b::/16 is reserved except for the local-use prefix (RFC8215)
+            let well_known =
+                Ipv6Net::new(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96).unwrap();
+            let local_use =
+                Ipv6Net::new(Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0), 48).unwrap();
+            if Ipv6Net::new(well_known.addr(), 32)
+                .unwrap()
+                .contains(&prefix.network())
+                && prefix.trunc() != well_known
+                && !local_use.contains(&prefix.trunc())
+            {
+                issue(
+                    location.clone(),
+                    format!(
+                        "{} is inside reserved space. Use {} or a prefix inside {} (RFC8215)",
+                        prefix, well_known, local_use
+                    ),
+                );
+            }
+
+            // Each prefix may only be used once
+            for (other_location, other) in prefixes.iter().take(i) {
+                if prefix.trunc() == other.trunc() {
+                    issue(
+                        location.clone(),
+                        format!("{} is already used by {}", prefix, other_location),
+                    );
+                }
+            }
+        }
+
+        // The address hook must be something we can call
+        if let Some(hook) = &self.address_hook {
+            if let Err(error) = AddressHook::new(hook) {
+                issue("address_hook".to_string(), error);
+            }
+        }
+
+        // The lease store must be one we know how to use, and it decides addresses on its own
+        if let Some(url) = &self.lease_store {
+            if let Err(error) = LeaseStore::check(url) {
+                issue("lease_store".to_string(), error);
+            }
+            if self.address_hook.is_some() {
+                issue(
+                    "lease_store".to_string(),
+                    "A lease store can't be used together with an address hook".to_string(),
+                );
+            }
+        }
+
+        // The policy script must compile
+        if let Some(path) = &self.policy_script {
+            if let Some(error) = PolicyScript::load(path).err() {
+                issue("policy_script".to_string(), error);
+            }
+        }
+
+        // Each type and code may only be overridden once
+        for (i, o) in self.icmp_overrides.iter().enumerate() {
+            if let Some(j) = self.icmp_overrides.iter().take(i).position(|other| {
+                (other.from, other.icmp_type, other.code) == (o.from, o.icmp_type, o.code)
+            }) {
+                issue(
+                    format!("icmp_overrides[{}]", i),
+                    format!("{} is already overridden by icmp_overrides[{}]", o.source(), j),
+                );
+            }
+        }
+
+        // DSCP values are six bits, and only TCP and UDP have ports
+        for (i, rule) in self.dscp_rules.iter().enumerate() {
+            for (field, value) in [("set", Some(rule.set)), ("dscp", rule.dscp)] {
+                if let Some(value) = value.filter(|value| *value > MAX_DSCP) {
+                    issue(
+                        format!("dscp_rules[{}].{}", i, field),
+                        format!("{} is not a valid DSCP (0 to {})", value, MAX_DSCP),
+                    );
+                }
+            }
+            if rule.port.is_some() && rule.protocol == Some(DscpProtocol::Icmp) {
+                issue(
+                    format!("dscp_rules[{}].port", i),
+                    "ICMP packets have no ports to match".to_string(),
+                );
+            }
+        }
+
+        // Tenants are told apart by name and source, and are kept to addresses of their own
+        for (i, tenant) in self.tenants.iter().enumerate() {
+            if tenant.name.is_empty() {
+                issue(
+                    format!("tenants[{}].name", i),
+                    "Tenants must be named".to_string(),
+                );
+            }
+            if tenant.pool.is_empty() {
+                issue(
+                    format!("tenants[{}].pool", i),
+                    "Tenants need a pool of their own".to_string(),
+                );
+            }
+            if tenant.sources.is_empty() {
+                issue(
+                    format!("tenants[{}].sources", i),
+                    "Tenants need at least one source prefix".to_string(),
+                );
+            }
+            if tenant.max_mappings == Some(0) {
+                issue(
+                    format!("tenants[{}].max_mappings", i),
+                    "Must allow at least one mapping".to_string(),
+                );
+            }
+            if let Some(max_sessions) = tenant.max_sessions {
+                if max_sessions == 0 {
+                    issue(
+                        format!("tenants[{}].max_sessions", i),
+                        "Must allow at least one session".to_string(),
+                    );
+                }
+                if self.subscriber_prefix_len().is_none() {
+                    issue(
+                        format!("tenants[{}].max_sessions", i),
+                        "Sessions are only tracked when aggregating by subscriber".to_string(),
+                    );
+                }
+            }
+            for (j, other) in self.tenants.iter().enumerate().take(i) {
+                if tenant.name == other.name {
+                    issue(
+                        format!("tenants[{}].name", i),
+                        format!("{} is already used by tenants[{}]", tenant.name, j),
+                    );
+                }
+                for (k, source) in tenant.sources.iter().enumerate() {
+                    if other.sources.iter().any(|other| {
+                        other.contains(&source.network()) || source.contains(&other.network())
+                    }) {
+                        issue(
+                            format!("tenants[{}].sources[{}]", i, k),
+                            format!("{} overlaps with the sources of tenants[{}]", source, j),
+                        );
+                    }
+                }
+            }
+        }
+        for (i, mapping) in self.static_map.iter().enumerate() {
+            let pool_tenant = self.tenants.iter().position(|tenant| {
+                tenant.pool.iter().any(|prefix| prefix.contains(&mapping.ipv4))
+            });
+            let source_tenant = self.tenants.iter().position(|tenant| {
+                tenant.sources.iter().any(|prefix| prefix.contains(&mapping.ipv6))
+            });
+            if pool_tenant != source_tenant {
+                issue(
+                    format!("static_map[{}]", i),
+                    format!(
+                        "{} and {} belong to different tenants",
+                        mapping.ipv4, mapping.ipv6
+                    ),
+                );
+            }
+        }
+
+        // Per-mapping metrics need somewhere to be served
+        if self.mapping_metrics && self.prom_bind_addr.is_none() {
+            issue(
+                "mapping_metrics".to_string(),
+                "Metrics are only served when prometheus_bind_addr is set".to_string(),
+            );
+        }
+
+        if !(0.0..=1.0).contains(&self.packet_trace_sample_rate) {
+            issue(
+                "packet_trace_sample_rate".to_string(),
+                format!("{} is not between 0 and 1", self.packet_trace_sample_rate),
+            );
+        }
+
+        // Stage timings are sampled, and only exported as metrics
+        if !(0.0..=1.0).contains(&self.stage_timing_sample_rate) {
+            issue(
+                "stage_timing_sample_rate".to_string(),
+                format!("{} is not between 0 and 1", self.stage_timing_sample_rate),
+            );
+        } else if self.stage_timing_sample_rate > 0.0 && self.prom_bind_addr.is_none() {
+            issue(
+                "stage_timing_sample_rate".to_string(),
+                "Metrics are only served when prometheus_bind_addr is set".to_string(),
+            );
+        }
+
+        // Webhooks are only sent to plain HTTP receivers
+        if let Some(Err(error)) = self.webhook.url.as_deref().map(HttpUrl::parse) {
+            issue("webhook.url".to_string(), error);
+        }
+        if self.webhook.batch_size == 0 {
+            issue(
+                "webhook.batch_size".to_string(),
+                "Batches must hold at least one event".to_string(),
+            );
+        }
+        if self.webhook.flush_interval == 0 {
+            issue(
+                "webhook.flush_interval".to_string(),
+                "The flush interval must be at least one second".to_string(),
+            );
+        }
+
+        // Internal queues must be able to hold something
+        for (location, capacity) in [
+            (
+                "session_log.queue_capacity",
+                self.session_log.session_log_queue_capacity,
+            ),
+            ("webhook.queue_capacity", self.webhook.webhook_queue_capacity),
+            (
+                "replication.queue_capacity",
+                self.replication.replication_queue_capacity,
+            ),
+        ] {
+            if capacity == 0 {
+                issue(
+                    location.to_string(),
+                    "Queues must hold at least one event".to_string(),
+                );
+            }
+        }
+        if let Some(size) = self
+            .pipeline
+            .packet_buffer_size
+            .filter(|size| *size < super::MIN_MTU as usize)
+        {
+            issue(
+                "pipeline.packet_buffer_size".to_string(),
+                format!(
+                    "{} bytes is smaller than the minimum IPv6 MTU of {}",
+                    size,
+                    super::MIN_MTU
+                ),
+            );
+        }
+
+        // A limited bucket that can't hold a token would silence every error
+        for (name, rate, burst) in [
+            (
+                "per_destination_burst",
+                self.icmp_rate_limit.per_destination_rate,
+                self.icmp_rate_limit.per_destination_burst,
+            ),
+            (
+                "global_burst",
+                self.icmp_rate_limit.global_rate,
+                self.icmp_rate_limit.global_burst,
+            ),
+        ] {
+            if rate > 0 && burst == 0 {
+                issue(
+                    format!("icmp_rate_limit.{}", name),
+                    "Bursts must allow at least one error".to_string(),
+                );
+            }
+        }
+
+        if self.numa.node.is_some() && self.numa.nic.is_some() {
+            issue(
+                "numa.nic".to_string(),
+                "Threads can't be placed by both NUMA node and interface".to_string(),
+            );
+        }
+
+        // Upstream probes must get a chance to be answered before the next round
+        let upstream = &self.upstream_health;
+        if upstream.probe_interval == 0 {
+            issue(
+                "upstream_health.interval".to_string(),
+                "Probes must be at least one second apart".to_string(),
+            );
+        }
+        if upstream.probe_timeout == 0 || upstream.probe_timeout > upstream.probe_interval {
+            issue(
+                "upstream_health.timeout".to_string(),
+                format!(
+                    "{} is not between 1 and the probe interval ({})",
+                    upstream.probe_timeout, upstream.probe_interval
+                ),
+            );
+        }
+        for (location, count) in [
+            ("upstream_health.failures", upstream.probe_failures),
+            ("upstream_health.successes", upstream.probe_successes),
+        ] {
+            if count == 0 {
+                issue(
+                    location.to_string(),
+                    "At least one probe is needed to change state".to_string(),
+                );
+            }
+        }
+
+        // The nftables rules can only tell that an address is in use from the address tables
+        if self.nftables.nftables {
+            if !cfg!(feature = "nftables") {
+                issue(
+                    "nftables.enabled".to_string(),
+                    "This build of protomask does not support nftables rules. Rebuild with the `nftables` feature to enable them.".to_string(),
+                );
+            }
+            for (location, in_use) in [
+                ("deterministic_nat", self.deterministic_nat.is_enabled()),
+                ("lease_store", self.lease_store.is_some()),
+            ] {
+                if in_use {
+                    issue(
+                        location.to_string(),
+                        "Can't be used together with nftables rules, which would drop traffic for addresses mapped outside of protomask's tables".to_string(),
+                    );
+                }
+            }
+        }
+
+        // Reverse DNS names must be usable in a zone
+        if self.rdns.zone_file.is_some() && self.rdns.template.is_none() {
+            issue(
+                "rdns.template".to_string(),
+                "A hostname template is required to write a reverse DNS zone".to_string(),
+            );
+        }
+        for (location, template, mapped) in [
+            ("rdns.template", &self.rdns.template, true),
+            ("rdns.unmapped_template", &self.rdns.unmapped_template, false),
+        ] {
+            if let Some(Err(error)) = template
+                .as_ref()
+                .map(|template| HostnameTemplates::validate(template, mapped))
+            {
+                issue(location.to_string(), error);
+            }
+        }
+        if self.rdns.rdns_interval == 0 {
+            issue(
+                "rdns.interval".to_string(),
+                "The interval must be at least one second".to_string(),
+            );
+        }
+
+        // Subscribers must fit inside an IPv6 address
+        let subscriber_prefix_len = self.subscriber_prefix_len();
+        if let Some(prefix_len) = subscriber_prefix_len.filter(|prefix_len| *prefix_len > 128) {
+            issue(
+                "aggregation.subscriber_prefix_len".to_string(),
+                format!("/{} is not a valid prefix length", prefix_len),
+            );
+        }
+
+        // Static mappings are made for whole subscribers
+        if let Some(prefix_len) = self
+            .aggregation
+            .subscriber_prefix_len
+            .filter(|prefix_len| *prefix_len <= 128)
+        {
+            for (i, mapping) in self.static_map.iter().enumerate() {
+                let subscriber = Ipv6Net::new(mapping.ipv6, prefix_len).unwrap().trunc();
+                if subscriber.network() != mapping.ipv6 {
+                    issue(
+                        format!("static_map[{}].ipv6", i),
+                        format!(
+                            "{} is inside subscriber {}. Map {} instead",
+                            mapping.ipv6,
+                            subscriber,
+                            subscriber.network()
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Deterministic NAT hands out addresses on its own, and needs a port block for every subscriber
+        if self.deterministic_nat.is_enabled() {
+            let nat = &self.deterministic_nat;
+            let subscriber_prefix_len = subscriber_prefix_len.unwrap_or(128);
+            for (i, prefix) in nat.subscribers.iter().enumerate() {
+                if prefix.prefix_len() > subscriber_prefix_len {
+                    issue(
+                        format!("deterministic_nat.subscribers[{}]", i),
+                        format!(
+                            "{} is smaller than a single subscriber (/{})",
+                            prefix, subscriber_prefix_len
+                        ),
+                    );
+                }
+            }
+            if nat.port_block_size == 0
+                || u32::from(nat.first_port) + u32::from(nat.port_block_size) > 0x10000
+            {
+                issue(
+                    "deterministic_nat.port_block_size".to_string(),
+                    format!(
+                        "A block of {} ports doesn't fit above port {}",
+                        nat.port_block_size, nat.first_port
+                    ),
+                );
+            } else if subscriber_prefix_len <= 128 {
+                if let Err(error) = self.port_block_table() {
+                    issue("deterministic_nat".to_string(), error.to_string());
+                }
+            }
+            for (location, in_use) in [
+                ("static_map", !self.static_map.is_empty()),
+                ("static_map_file", self.static_map_file.is_some()),
+                ("address_hook", self.address_hook.is_some()),
+                ("lease_store", self.lease_store.is_some()),
+            ] {
+                if in_use {
+                    issue(
+                        location.to_string(),
+                        "Can't be used together with deterministic NAT".to_string(),
+                    );
+                }
+            }
+            for (i, prefix) in self.additional_prefixes.iter().enumerate() {
+                if !prefix.pool.is_empty() {
+                    issue(
+                        format!("additional_prefixes[{}].pool", i),
+                        "Deterministic NAT only uses the main pool".to_string(),
+                    );
+                }
+            }
+            if !self.tenants.is_empty() {
+                issue(
+                    "tenants".to_string(),
+                    "Deterministic NAT only uses the main pool".to_string(),
+                );
+            }
+        }
+
+        // We need at least one pool prefix
+        if self.pool_prefixes.is_empty() {
+            issue(
+                "pool".to_string(),
+                "At least one pool prefix must be specified".to_string(),
+            );
+        }
+
+        for (i, (location, prefix)) in pools.iter().enumerate() {
+            // Catch typos such as `192.0.2.1/24`
+            if prefix.addr() != prefix.network() {
+                issue(
+                    location.clone(),
+                    format!(
+                        "{} has host bits set. Did you mean {}?",
+                        prefix,
+                        prefix.trunc()
+                    ),
+                );
+            }
+
+            // Pools may not overlap each other
+            for (other_location, other) in pools.iter().take(i) {
+                if prefix.contains(&other.network()) || other.contains(&prefix.network()) {
+                    issue(
+                        location.clone(),
+                        format!("{} overlaps with {} ({})", prefix, other_location, other),
+                    );
+                }
+            }
+        }
+
+        // Every pool address gets its own proxy ARP entry, so they must be kept small
+        if self.arp_proxy.is_some() {
+            for (location, prefix) in &pools {
+                if prefix.prefix_len() < MIN_PROXY_ARP_PREFIX_LEN {
+                    issue(
+                        location.clone(),
+                        format!(
+                            "{} is too large for proxy ARP. Pools must be /{} or smaller",
+                            prefix, MIN_PROXY_ARP_PREFIX_LEN
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Exclusions outside of the pool have no effect and are likely a typo
+        for (location, prefix) in self
+            .excluded_addresses
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| (format!("excluded_addresses[{}]", i), Ipv4Net::from(*addr)))
+            .chain(
+                self.excluded_prefixes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, prefix)| (format!("excluded_prefixes[{}]", i), *prefix)),
+            )
+        {
+            if !pools.iter().any(|(_, pool)| {
+                pool.contains(&prefix.network()) || prefix.contains(&pool.network())
+            }) {
+                issue(
+                    location,
+                    format!("{} does not overlap any pool prefix", prefix),
+                );
+            }
+        }
+
+        for (i, mapping) in self.static_map.iter().enumerate() {
+            // Static mappings must come out of the pool address space
+            if !pools
+                .iter()
+                .any(|(_, prefix)| prefix.contains(&mapping.ipv4))
+            {
+                issue(
+                    format!("static_map[{}].ipv4", i),
+                    format!("{} is not inside any pool prefix", mapping.ipv4),
+                );
+            }
+
+            // Each address may only be mapped once
+            for (j, other) in self.static_map.iter().enumerate().take(i) {
+                if mapping.ipv4 == other.ipv4 {
+                    issue(
+                        format!("static_map[{}].ipv4", i),
+                        format!("{} is already mapped by static_map[{}]", mapping.ipv4, j),
+                    );
+                }
+                if mapping.ipv6 == other.ipv6 {
+                    issue(
+                        format!("static_map[{}].ipv6", i),
+                        format!("{} is already mapped by static_map[{}]", mapping.ipv6, j),
+                    );
+                }
+            }
+        }
+
+        // The translator address must be reachable through a pool, and can't be handed out to clients
+        if let Some(translator_address) = self.translator_address {
+            if !pools
+                .iter()
+                .any(|(_, prefix)| prefix.contains(&translator_address))
+            {
+                issue(
+                    "translator_address".to_string(),
+                    format!("{} is not inside any pool prefix", translator_address),
+                );
+            }
+            if let Some(i) = self
+                .static_map
+                .iter()
+                .position(|mapping| mapping.ipv4 == translator_address)
+            {
+                issue(
+                    "translator_address".to_string(),
+                    format!(
+                        "{} is already mapped by static_map[{}]",
+                        translator_address, i
+                    ),
+                );
+            }
+        }
+
+        // Errors from IPv6 routers must not be mistaken for traffic to a client
+        for (i, source) in self.icmp_error_sources.iter().enumerate() {
+            if let Some((location, _)) = pools.iter().find(|(_, prefix)| prefix.contains(source)) {
+                issue(
+                    format!("icmp_error_sources[{}]", i),
+                    format!("{} is inside {}", source, location),
+                );
+            }
+        }
+
+        // We need somewhere to read packets from
+        if self.num_queues == 0 {
+            issue(
+                "queues".to_string(),
+                "At least one queue is required".to_string(),
+            );
+        }
+        if !(super::MIN_MTU..=super::MAX_MTU).contains(&self.mtu) {
+            issue(
+                "mtu".to_string(),
+                format!(
+                    "The MTU must be between {} and {}",
+                    super::MIN_MTU,
+                    super::MAX_MTU
+                ),
+            );
+        }
+
+        // Fallback routes are installed over netlink
+        if self.pool_fallback.is_some() && self.no_netlink {
+            issue(
+                "pool_fallback".to_string(),
+                "Fallback routes can't be installed when netlink is disabled".to_string(),
+            );
+        }
+        if self.route_journal.is_some() && self.no_netlink {
+            issue(
+                "route_journal".to_string(),
+                "Routes can't be cleaned up when netlink is disabled".to_string(),
+            );
+        }
+
+        // Egress rules and routes are installed over netlink, into a table of their own
+        if self.egress_interface.is_some() && self.no_netlink {
+            issue(
+                "egress_interface".to_string(),
+                "Traffic can't be bound to an uplink when netlink is disabled".to_string(),
+            );
+        }
+        if super::is_reserved_table(self.egress_table) {
+            issue(
+                "egress_table".to_string(),
+                format!(
+                    "Table {} is reserved by the kernel. Pick another table",
+                    self.egress_table
+                ),
+            );
+        }
+
+        // Upgrades hand over the queues of a single interface
+        if self.ipv4_interface.is_some() && self.upgrade_socket.is_some() {
+            issue(
+                "upgrade_socket".to_string(),
+                "In-place upgrades are not supported with a separate IPv4 interface".to_string(),
+            );
+        }
+
+        // Only address mappings are handed over, so the sessions of shared addresses would be lost
+        if self.upgrade_socket.is_some() {
+            for (location, in_use) in [
+                (
+                    "aggregation.subscriber_prefix_len",
+                    self.aggregation.subscriber_prefix_len.is_some(),
+                ),
+                ("deterministic_nat", self.deterministic_nat.is_enabled()),
+            ] {
+                if in_use {
+                    issue(
+                        location.to_string(),
+                        "Can't be used together with in-place upgrades, which don't hand over the sessions of shared addresses".to_string(),
+                    );
+                }
+            }
+        }
+
+        // Failover is driven over the control socket
+        if (self.standby || self.on_promote.is_some() || self.on_demote.is_some())
+            && self.control_socket.is_none()
+        {
+            issue(
+                "control_socket".to_string(),
+                "A control socket is required to promote and demote this instance".to_string(),
+            );
+        }
+
+        // Replication must be authenticated
+        if (self.replication.listen.is_some() || self.replication.primary.is_some())
+            && self.replication.secret.is_none()
+        {
+            issue(
+                "replication.secret".to_string(),
+                "A shared secret is required for replication".to_string(),
+            );
+        }
+        if self.replication.listen.is_some() && self.replication.primary.is_some() {
+            issue(
+                "replication".to_string(),
+                "An instance can be a primary or a standby, but not both".to_string(),
+            );
+        }
+        if self.replication.sync_interval == 0 {
+            issue(
+                "replication.sync_interval".to_string(),
+                "The sync interval must be at least one second".to_string(),
+            );
+        }
+
+        // Mappings kept in their own file are held to the same rules
+        if let Some(path) = &self.static_map_file {
+            match static_map_file::read(path) {
+                Ok(mappings) => issues.extend(static_map_file::check(self, &mappings)),
+                Err(message) => issues.push(ConfigIssue {
+                    location: "static_map_file".to_string(),
+                    message,
+                }),
+            }
+        }
+
+        issues
+    }
+}
+
+/// A problem found while validating a config
+#[derive(Debug)]
+pub struct ConfigIssue {
+    /// Where in the config file the problem is
+    pub location: String,
+    /// What the problem is
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn default_state_dump_path() -> PathBuf {
+    PathBuf::from("/tmp/protomask-state.json")
+}
+
+fn default_egress_table() -> u32 {
+    6464
+}
+
+fn default_recent_drops() -> usize {
+    256
+}
+
+fn default_recent_mappings() -> usize {
+    4096
+}
+
+fn default_recent_mapping_ttl() -> u64 {
+    86400
+}
+
+/// NAT session event logging configuration
+#[derive(Debug, clap::Args, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct SessionLogConfig {
+    /// Log NAT session creation and expiry events to this file
+    #[clap(long = "session-log-file")]
+    pub file: Option<PathBuf>,
+
+    /// Send NAT session events to this syslog server (UDP)
+    #[clap(long = "session-log-syslog", conflicts_with = "file")]
+    pub syslog: Option<SocketAddr>,
+
+    /// Rotate the session log file once it reaches this many bytes
+    #[clap(long = "session-log-max-size", default_value = "104857600")]
+    pub max_size: u64,
+
+    /// Number of rotated session log files to keep
+    #[clap(long = "session-log-max-files", default_value = "10")]
+    pub max_files: usize,
+
+    /// Most events waiting to be written before new ones are dropped
+    #[clap(long = "session-log-queue-capacity", default_value = "65536")]
+    #[serde(rename = "queue_capacity")]
+    pub session_log_queue_capacity: usize,
+}
+
+impl SessionLogConfig {
+    /// Get the configured session log destination, if any
+    pub fn target(&self) -> Option<SessionLogTarget> {
+        match (&self.file, self.syslog) {
+            (Some(path), _) => Some(SessionLogTarget::File {
+                path: path.clone(),
+                max_size: self.max_size,
+                max_files: self.max_files,
+            }),
+            (None, Some(server)) => Some(SessionLogTarget::Syslog(server)),
+            (None, None) => None,
+        }
+    }
+}
+
+impl Default for SessionLogConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            syslog: None,
+            max_size: 100 * 1024 * 1024,
+            max_files: 10,
+            session_log_queue_capacity: 65536,
+        }
+    }
+}
+
+/// An extra translation prefix, optionally bound to its own IPv4 pool and restricted to some IPv6 sources
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct AdditionalPrefix {
+    #[serde(serialize_with = "crate::common::rfc6052::serialize_network_specific_prefix")]
+    pub prefix: Ipv6Net,
+    #[serde(default)]
+    pub pool: Vec<Ipv4Net>,
+    #[serde(default)]
+    pub sources: Vec<Ipv6Net>,
+}
+
+impl FromStr for AdditionalPrefix {
+    type Err = String;
+
+    /// Parses `<prefix>[=<pool>,...][@<source>,...]`
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (string, sources) = match string.split_once('@') {
+            Some((string, sources)) => (
+                string,
+                sources
+                    .split(',')
+                    .map(|prefix| Ipv6Net::from_str(prefix.trim()).map_err(|err| err.to_string()))
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => (string, Vec::new()),
+        };
+        let (prefix, pool) = match string.split_once('=') {
+            Some((prefix, pool)) => (
+                prefix,
+                pool.split(',')
+                    .map(|prefix| Ipv4Net::from_str(prefix.trim()).map_err(|err| err.to_string()))
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => (string, Vec::new()),
+        };
+        Ok(Self {
+            prefix: parse_network_specific_prefix(prefix.trim())?,
+            pool,
+            sources,
+        })
+    }
+}
+
+/// A customer served through its own translation prefix and pool, by IPv6 source
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct TenantDefinition {
+    pub name: String,
+    #[serde(serialize_with = "crate::common::rfc6052::serialize_network_specific_prefix")]
+    pub prefix: Ipv6Net,
+    pub pool: Vec<Ipv4Net>,
+    pub sources: Vec<Ipv6Net>,
+    /// Most mappings the tenant may hold at once
+    #[serde(default)]
+    pub max_mappings: Option<usize>,
+    /// Most sessions of shared addresses the tenant may have open at once (when aggregating by subscriber)
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+}
+
+impl FromStr for TenantDefinition {
+    type Err = String;
+
+    /// Parses `<name>:<prefix>=<pool>+...@<source>+...[#<limit>+...]`, where each limit is one of `mappings:<count>`
+    /// or `sessions:<count>`. Lists are joined with `+`, since commas separate repeated options given through
+    /// environment variables.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            "Expected a tenant in the form <name>:<prefix>=<pool>+...@<source>+...[#mappings:<count>+sessions:<count>]"
+                .to_string()
+        };
+        let (name, string) = string.split_once(':').ok_or_else(error)?;
+        let (string, limits) = string.split_once('#').unwrap_or((string, ""));
+        let (string, sources) = string.split_once('@').ok_or_else(error)?;
+        let (prefix, pool) = string.split_once('=').ok_or_else(error)?;
+        let mut tenant = Self {
+            name: name.trim().to_string(),
+            prefix: parse_network_specific_prefix(prefix.trim())?,
+            pool: pool
+                .split('+')
+                .map(|prefix| Ipv4Net::from_str(prefix.trim()).map_err(|err| err.to_string()))
+                .collect::<Result<_, _>>()?,
+            sources: sources
+                .split('+')
+                .map(|prefix| Ipv6Net::from_str(prefix.trim()).map_err(|err| err.to_string()))
+                .collect::<Result<_, _>>()?,
+            max_mappings: None,
+            max_sessions: None,
+        };
+        for limit in limits.split('+').filter(|l| !l.trim().is_empty()) {
+            let (key, value) = limit.split_once(':').ok_or_else(error)?;
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|err| format!("{}: {}", value.trim(), err))?;
+            match key.trim() {
+                "mappings" => tenant.max_mappings = Some(value),
+                "sessions" => tenant.max_sessions = Some(value),
+                _ => return Err(error()),
+            }
+        }
+        Ok(tenant)
+    }
+}
+
+/// How new clients are given pool addresses
+#[derive(
+    Debug,
+    serde::Deserialize,
+    serde::Serialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressSelection {
+    /// The first free address
+    #[default]
+    First,
+    /// An address picked by hashing the client's IPv6 address, or the next free one after it
+    Hashed,
+}
+
+impl From<AddressSelection> for fast_nat::AddressSelection {
+    fn from(selection: AddressSelection) -> Self {
+        match selection {
+            AddressSelection::First => Self::First,
+            AddressSelection::Hashed => Self::Hashed,
+        }
+    }
+}
+
+/// What to do with a class of TCP options
+#[derive(
+    Debug,
+    serde::Deserialize,
+    serde::Serialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpOptionAction {
+    /// Copy the options as they are
+    #[default]
+    Keep,
+    /// Copy the options, but count and log them
+    Flag,
+    /// Replace the options with padding
+    Strip,
+}
+
+impl From<TcpOptionAction> for OptionAction {
+    fn from(action: TcpOptionAction) -> Self {
+        match action {
+            TcpOptionAction::Keep => Self::Keep,
+            TcpOptionAction::Flag => Self::Flag,
+            TcpOptionAction::Strip => Self::Strip,
+        }
+    }
+}
+
+/// How pool traffic is discarded while the pool isn't routed to the NAT64
+#[derive(
+    Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolFallback {
+    /// Silently drop it
+    Blackhole,
+    /// Drop it and send an ICMP Destination Unreachable back to the sender
+    Unreachable,
+}
+
+/// How IPv4 packets sent to pool addresses without a mapping are answered
+#[derive(
+    Debug,
+    serde::Deserialize,
+    serde::Serialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnmappedReject {
+    /// Silently drop them
+    #[default]
+    Drop,
+    /// Send an ICMP Destination Unreachable (host unreachable) back to the sender
+    Unreachable,
+    /// Send a TCP RST back for TCP connection attempts, and silently drop everything else
+    TcpReset,
+}
+
+/// Which protocol an ICMP override translates from
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IcmpVersion {
+    Icmp,
+    Icmpv6,
+}
+
+/// A replacement for the built-in translation of an ICMP or ICMPv6 type and code
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpOverride {
+    /// Protocol of the messages being translated
+    pub from: IcmpVersion,
+    /// Type of the messages being translated
+    #[serde(rename = "type")]
+    pub icmp_type: u8,
+    /// Code of the messages being translated. Every code of the type is overridden if not set.
+    #[serde(default)]
+    pub code: Option<u8>,
+    /// Type to translate them to
+    pub to_type: u8,
+    /// Code to translate them to
+    pub to_code: u8,
+}
+
+impl IcmpOverride {
+    /// Describe the messages this applies to
+    fn source(&self) -> String {
+        let protocol = match self.from {
+            IcmpVersion::Icmp => "ICMP",
+            IcmpVersion::Icmpv6 => "ICMPv6",
+        };
+        match self.code {
+            Some(code) => format!("{} type {} code {}", protocol, self.icmp_type, code),
+            None => format!("{} type {}", protocol, self.icmp_type),
+        }
+    }
+}
+
+impl FromStr for IcmpOverride {
+    type Err = String;
+
+    /// Parses `<icmp|icmpv6>:<type>[/<code>]=<type>/<code>`
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let error = || "Expected an override in the form <icmp|icmpv6>:<type>[/<code>]=<type>/<code>".to_string();
+        let (from, string) = string.split_once(':').ok_or_else(error)?;
+        let from = match from.trim() {
+            "icmp" => IcmpVersion::Icmp,
+            "icmpv6" => IcmpVersion::Icmpv6,
+            _ => return Err(error()),
+        };
+        let (source, target) = string.split_once('=').ok_or_else(error)?;
+        let number = |value: &str| {
+            value
+                .trim()
+                .parse::<u8>()
+                .map_err(|err| format!("{}: {}", value.trim(), err))
+        };
+        let (icmp_type, code) = match source.split_once('/') {
+            Some((icmp_type, code)) => (number(icmp_type)?, Some(number(code)?)),
+            None => (number(source)?, None),
+        };
+        let (to_type, to_code) = target.split_once('/').ok_or_else(error)?;
+        Ok(Self {
+            from,
+            icmp_type,
+            code,
+            to_type: number(to_type)?,
+            to_code: number(to_code)?,
+        })
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
 pub struct StaticMap {
     pub ipv4: Ipv4Addr,
     pub ipv6: Ipv6Addr,
 }
 
+impl FromStr for StaticMap {
+    type Err = String;
+
+    /// Parses `<ipv4>=<ipv6>`
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (ipv4, ipv6) = string
+            .split_once('=')
+            .ok_or_else(|| "Expected a mapping in the form <ipv4>=<ipv6>".to_string())?;
+        Ok(Self {
+            ipv4: ipv4
+                .trim()
+                .parse()
+                .map_err(|err: std::net::AddrParseError| err.to_string())?,
+            ipv6: ipv6
+                .trim()
+                .parse()
+                .map_err(|err: std::net::AddrParseError| err.to_string())?,
+        })
+    }
+}
+
 impl From<StaticMap> for (Ipv4Addr, Ipv6Addr) {
     fn from(val: StaticMap) -> Self {
         (val.ipv4, val.ipv6)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Get the locations of the issues with the config given by command line args that mention upgrades
+    fn upgrade_issues(args: &[&str]) -> Vec<String> {
+        let args = [
+            "protomask",
+            "--pool-prefix",
+            "192.0.2.0/24",
+            "--upgrade-socket",
+            "/run/protomask.sock",
+        ]
+        .iter()
+        .chain(args);
+        Cli::parse_from(args)
+            .nat64
+            .config_data
+            .validate()
+            .into_iter()
+            .filter(|issue| issue.message.contains("upgrades"))
+            .map(|issue| issue.location)
+            .collect()
+    }
+
+    #[test]
+    fn test_upgrades_refuse_shared_addresses() {
+        assert!(upgrade_issues(&[]).is_empty());
+        assert_eq!(
+            upgrade_issues(&["--subscriber-prefix-len", "64"]),
+            vec!["aggregation.subscriber_prefix_len"]
+        );
+        assert_eq!(
+            upgrade_issues(&["--subscriber-prefix", "2001:db8::/48"]),
+            vec!["deterministic_nat"]
+        );
+    }
+}
@@ -1,20 +1,29 @@
 //! Commandline arguments and config file definitions for `protomask-clat`
 
-use super::ProfilerArgs;
+use super::{
+    protomask::ConfigIssue, ConfigFormat, MulticastHandling, ProfilerArgs, TelemetryConfig,
+};
 use crate::common::rfc6052::parse_network_specific_prefix;
 use ipnet::{Ipv4Net, Ipv6Net};
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
 
 #[derive(Debug, clap::Parser)]
 #[clap(author, version, about="IPv4 to IPv6 Customer-side transLATor (CLAT)", long_about = None)]
 pub struct Args {
     #[command(flatten)]
-    config_data: Option<Config>,
+    config_data: Config,
 
-    /// Path to a config file to read
-    #[clap(short = 'c', long = "config", conflicts_with = "Config")]
+    /// Path to a config file to read. Any config values also passed as CLI args will override those in the file.
+    #[clap(short = 'c', long = "config")]
     config_file: Option<PathBuf>,
 
+    /// Format of the config file (detected from the file extension by default)
+    #[clap(long = "config-format", value_enum, global = true)]
+    pub config_format: Option<ConfigFormat>,
+
     /// Explicitly set the interface name to use
     #[clap(short, long, default_value_t = ("clat%d").to_string())]
     pub interface: String,
@@ -22,42 +31,68 @@ pub struct Args {
     #[command(flatten)]
     pub profiler_args: ProfilerArgs,
 
+    /// Write packets that fail translation to this pcap file (drop reasons are written to `<file>.reasons`)
+    #[clap(long = "capture-drops")]
+    pub capture_drops: Option<PathBuf>,
+
+    /// Print what would become of each packet in `--input` instead of bringing up a CLAT
+    #[clap(long = "dry-run", requires = "input")]
+    pub dry_run: bool,
+
+    /// Capture to read packets from during a dry run
+    #[clap(long, requires = "dry_run")]
+    pub input: Option<PathBuf>,
+
     /// Enable verbose logging
     #[clap(short, long)]
     pub verbose: bool,
+
+    /// IDs of all args that were explicitly set on the command line
+    #[clap(skip)]
+    pub(super) explicit_args: Vec<String>,
 }
 
 impl Args {
-    #[allow(dead_code)]
+    /// Parse the CLI args, keeping track of which config values were set on the command line
+    pub fn parse_cli() -> Self {
+        let (mut args, explicit_args) = super::parse_with_explicit_args::<Self>();
+        args.explicit_args = explicit_args;
+        args
+    }
+
     pub fn data(&self) -> Result<Config, Box<dyn std::error::Error>> {
-        match self.config_file {
+        let data = match self.config_file {
             Some(ref path) => {
                 // Read the data from the config file
-                let file = std::fs::File::open(path).map_err(|error| match error.kind() {
-                    std::io::ErrorKind::NotFound => {
-                        log::error!("Config file not found: {}", path.display());
-                        std::process::exit(1)
-                    }
-                    _ => error,
-                })?;
-                let data: Config = serde_json::from_reader(file)?;
-
-                // We need at least one customer prefix
-                if data.customer_pool.is_empty() {
-                    log::error!("No customer prefixes specified. At least one prefix must be specified in the `customer_pool` property of the config file");
-                    std::process::exit(1);
+                if !path.exists() {
+                    log::error!("Config file not found: {}", path.display());
+                    std::process::exit(1)
                 }
+                let mut data: Config = super::read_config_file(path, self.config_format)?;
 
-                Ok(data)
+                // Anything set on the command line takes priority
+                data.apply_overrides(&self.config_data, &self.explicit_args);
+                data
             }
-            None => match &self.config_data {
-                Some(data) => Ok(data.clone()),
-                None => {
+            None => {
+                if !super::any_explicitly_set::<Config>(&self.explicit_args) {
                     log::error!("No configuration provided. Either use --config to specify a file or set the configuration via CLI args (see --help)");
                     std::process::exit(1)
                 }
-            },
+                self.config_data.clone()
+            }
+        };
+
+        // Refuse to start with a config that is known to be broken
+        let issues = data.validate();
+        if !issues.is_empty() {
+            for issue in issues {
+                log::error!("Invalid configuration: {}", issue);
+            }
+            std::process::exit(1);
         }
+
+        Ok(data)
     }
 }
 
@@ -75,6 +110,21 @@ pub struct Config {
     #[serde(rename = "prometheus_bind_addr")]
     pub prom_bind_addr: Option<SocketAddr>,
 
+    /// Time each stage of processing for this fraction of packets (0 to 1), exporting the results as prometheus histograms
+    #[clap(long = "stage-timing-sample-rate", default_value = "0")]
+    #[serde(default)]
+    pub stage_timing_sample_rate: f64,
+
+    /// Trace this fraction of packets (0 to 1), emitting a span with the timing of each stage and the packet's addresses
+    #[clap(long = "packet-trace-sample-rate", default_value = "0")]
+    #[serde(default)]
+    pub packet_trace_sample_rate: f64,
+
+    /// Serve /healthz and /readyz on a dedicated address (they are also served alongside prometheus metrics)
+    #[clap(long = "health")]
+    #[serde(rename = "health_bind_addr", default)]
+    pub health_bind_addr: Option<SocketAddr>,
+
     /// RFC6052 IPv6 prefix to encapsulate IPv4 packets within
     #[clap(long="via", default_value_t = ("64:ff9b::/96").parse().unwrap(), value_parser = parse_network_specific_prefix)]
     #[serde(
@@ -83,8 +133,283 @@ pub struct Config {
     )]
     pub embed_prefix: Ipv6Net,
 
+    /// Only route these IPv4 prefixes towards the CLAT, instead of a default route
+    #[clap(long = "ipv4-route")]
+    #[serde(default)]
+    pub ipv4_routes: Vec<Ipv4Net>,
+
+    /// Don't route any IPv4 traffic towards the CLAT, leaving that to the environment
+    #[clap(long = "no-default-route", conflicts_with = "ipv4_routes")]
+    #[serde(default)]
+    pub no_default_route: bool,
+
+    /// Metric of the routes towards the CLAT. Use one higher than that of any DHCP-provided default route to only use the CLAT when there is no native IPv4.
+    #[clap(long = "route-metric")]
+    #[serde(default)]
+    pub route_metric: Option<u32>,
+
+    /// Only translate while the network has a NAT64 (found by looking up `ipv4only.arpa`) and no native IPv4, re-checking whenever the network changes
+    #[clap(long)]
+    #[serde(default)]
+    pub auto: bool,
+
+    /// Embed addresses in the NAT64 prefix discovered by looking up `ipv4only.arpa`, following it whenever it changes. The `--via` prefix is used until one is found.
+    #[clap(long = "discover-prefix")]
+    #[serde(default)]
+    pub discover_prefix: bool,
+
+    /// Embed addresses in the NAT64 prefix announced in PREF64 options (RFC8781) of Router Advertisements received on this uplink, following it whenever it changes. While no router announces one, the prefix is looked up as with `--discover-prefix`.
+    #[clap(long = "ra-interface", value_name = "UPLINK")]
+    #[serde(default)]
+    pub ra_interface: Option<String>,
+
+    /// Serve DNS on this address, forwarding queries to `--dns-upstream` and removing AAAA records synthesized by DNS64 so that clients use IPv4 through the CLAT
+    #[clap(long = "dns-proxy", requires = "dns_upstream")]
+    #[serde(default)]
+    pub dns_proxy: Option<SocketAddr>,
+
+    /// Resolver to forward DNS proxy queries to
+    #[clap(long = "dns-upstream")]
+    #[serde(default)]
+    pub dns_upstream: Option<SocketAddr>,
+
     /// Number of queues to create on the TUN device
     #[clap(long, default_value = "10")]
     #[serde(rename = "queues")]
     pub num_queues: usize,
+
+    /// Scale between this many and `--queues` active queues with load, rather than always keeping every queue active
+    #[clap(long = "min-workers")]
+    #[serde(default)]
+    pub min_workers: Option<usize>,
+
+    /// MTU of the TUN device. Packet buffers are sized to match, and routes towards the device are given matching MTUs.
+    #[clap(long, default_value = "1500")]
+    #[serde(default = "super::default_mtu")]
+    pub mtu: u32,
+
+    /// Size of each packet buffer in bytes, dropping larger packets. By default, buffers fit the interface MTU and grow when it is raised.
+    #[clap(long = "packet-buffer-size")]
+    #[serde(default)]
+    pub packet_buffer_size: Option<usize>,
+
+    /// Send translated ICMPv6 errors from routers outside of the PLAT prefix from this IPv4 address (RFC6791), rather than mangling their source. May be repeated to spread them over several addresses.
+    #[clap(long = "icmp-error-source", value_name = "IPV4")]
+    #[serde(default)]
+    pub icmp_error_sources: Vec<Ipv4Addr>,
+
+    /// IPv4 address of the CLAT itself (such as `192.0.0.1`, from RFC7335). It is assigned to the TUN interface along with its embedded IPv6 address, and pings to either are answered.
+    #[clap(long = "clat-address", value_name = "IPV4")]
+    #[serde(default)]
+    pub clat_address: Option<Ipv4Addr>,
+
+    /// Ping this IPv4 address through the PLAT prefix to check that the NAT64 is reachable, reporting the CLAT as not ready (and looking for the NAT64 prefix again, with `--discover-prefix`) while it isn't
+    #[clap(long = "plat-probe", value_name = "IPV4")]
+    #[serde(default)]
+    pub plat_probe: Option<Ipv4Addr>,
+
+    /// Number of seconds between PLAT probes
+    #[clap(long = "plat-probe-interval", default_value = "10")]
+    #[serde(default = "default_plat_probe_interval")]
+    pub plat_probe_interval: u64,
+
+    /// Number of seconds to wait for a PLAT probe to be answered
+    #[clap(long = "plat-probe-timeout", default_value = "1")]
+    #[serde(default = "default_plat_probe_timeout")]
+    pub plat_probe_timeout: u64,
+
+    /// Consecutive failed PLAT probes before the NAT64 is considered unreachable
+    #[clap(long = "plat-probe-failures", default_value = "3")]
+    #[serde(default = "default_plat_probe_failures")]
+    pub plat_probe_failures: u32,
+
+    /// What to do with packets sent to IPv4 broadcast or multicast addresses, or to IPv6 multicast groups
+    #[clap(long, value_enum, default_value = "drop")]
+    #[serde(default)]
+    pub multicast: MulticastHandling,
+
+    /// Only create (or attach to) the TUN interface. Bringing it up and adding routes is left to the environment.
+    #[clap(long = "no-netlink", alias = "no-routes")]
+    #[serde(default)]
+    pub no_netlink: bool,
+
+    /// Send translated traffic out of this uplink, rather than whichever the main routing table prefers, by copying the uplink's default routes into their own routing table and adding policy rules for traffic from the TUN interface
+    #[clap(long = "egress-interface", value_name = "UPLINK")]
+    #[serde(default)]
+    pub egress_interface: Option<String>,
+
+    /// Routing table to copy the egress uplink's default routes into
+    #[clap(long = "egress-table", default_value = "6464")]
+    #[serde(default = "default_egress_table")]
+    pub egress_table: u32,
+
+    /// Expose the CLAT's state on the system bus and allow it to be enabled and disabled (requires the `dbus` build feature)
+    #[clap(long)]
+    #[serde(default)]
+    pub dbus: bool,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+}
+
+impl Config {
+    /// Check the config for problems, returning every one found
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue = |location: &str, message: String| {
+            issues.push(ConfigIssue {
+                location: location.to_string(),
+                message,
+            });
+        };
+
+        // We need at least one customer prefix
+        if self.customer_pool.is_empty() {
+            issue(
+                "customer_pool",
+                "at least one prefix must be specified".to_string(),
+            );
+        }
+
+        // Specific routes only make sense if routes are being installed at all
+        if self.no_default_route && !self.ipv4_routes.is_empty() {
+            issue(
+                "ipv4_routes",
+                "can't be used together with no_default_route".to_string(),
+            );
+        }
+
+        // The DNS proxy needs somewhere to send queries
+        if self.dns_proxy.is_some() && self.dns_upstream.is_none() {
+            issue("dns_upstream", "is required by dns_proxy".to_string());
+        }
+
+        // Probes must have time to be answered before the next one is sent
+        if self.plat_probe.is_some() {
+            if self.plat_probe_failures == 0 {
+                issue("plat_probe_failures", "must be at least 1".to_string());
+            }
+            if self.plat_probe_timeout == 0 || self.plat_probe_timeout >= self.plat_probe_interval {
+                issue(
+                    "plat_probe_timeout",
+                    "must be at least 1, and shorter than plat_probe_interval".to_string(),
+                );
+            }
+        }
+
+        // Egress rules and routes are installed over netlink, into a table of their own
+        if self.egress_interface.is_some() && self.no_netlink {
+            issue(
+                "egress_interface",
+                "can't be used together with no_netlink".to_string(),
+            );
+        }
+        if super::is_reserved_table(self.egress_table) {
+            issue(
+                "egress_table",
+                format!("{} is reserved by the kernel", self.egress_table),
+            );
+        }
+
+        // Scaling needs at least one active queue, and can't go beyond the queues that exist
+        if let Some(min_workers) = self.min_workers {
+            if min_workers == 0 || min_workers > self.num_queues {
+                issue(
+                    "min_workers",
+                    format!(
+                        "must be between 1 and the number of queues ({})",
+                        self.num_queues
+                    ),
+                );
+            }
+        }
+
+        // The MTU must be usable by IPv6
+        if !(super::MIN_MTU..=super::MAX_MTU).contains(&self.mtu) {
+            issue(
+                "mtu",
+                format!("must be between {} and {}", super::MIN_MTU, super::MAX_MTU),
+            );
+        }
+
+        // Buffers must fit the smallest packets IPv6 allows to be sent unfragmented
+        if let Some(size) = self
+            .packet_buffer_size
+            .filter(|size| *size < super::MIN_MTU as usize)
+        {
+            issue(
+                "packet_buffer_size",
+                format!(
+                    "{} is too small. Buffers must hold at least {} bytes",
+                    size,
+                    super::MIN_MTU
+                ),
+            );
+        }
+
+        issues
+    }
+
+    /// Replace values with those from `overrides` that were explicitly set on the command line
+    fn apply_overrides(&mut self, overrides: &Config, explicit_args: &[String]) {
+        super::apply_overrides!(
+            explicit_args,
+            self,
+            overrides,
+            [
+                customer_pool,
+                prom_bind_addr,
+                stage_timing_sample_rate,
+                packet_trace_sample_rate,
+                health_bind_addr,
+                embed_prefix,
+                ipv4_routes,
+                no_default_route,
+                route_metric,
+                auto,
+                discover_prefix,
+                ra_interface,
+                dns_proxy,
+                dns_upstream,
+                num_queues,
+                min_workers,
+                mtu,
+                packet_buffer_size,
+                icmp_error_sources,
+                clat_address,
+                plat_probe,
+                plat_probe_interval,
+                plat_probe_timeout,
+                plat_probe_failures,
+                multicast,
+                no_netlink,
+                egress_interface,
+                egress_table,
+                dbus,
+            ]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.telemetry,
+            overrides.telemetry,
+            [dsn, environment, release]
+        );
+    }
+}
+
+fn default_egress_table() -> u32 {
+    6464
+}
+
+fn default_plat_probe_interval() -> u64 {
+    10
+}
+
+fn default_plat_probe_timeout() -> u64 {
+    1
+}
+
+fn default_plat_probe_failures() -> u32 {
+    3
 }
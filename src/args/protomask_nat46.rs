@@ -0,0 +1,247 @@
+//! Commandline arguments and config file definitions for `protomask nat46`
+
+use super::{
+    protomask::{ConfigIssue, StaticMap},
+    ConfigFormat, ProfilerArgs, TelemetryConfig,
+};
+use crate::common::rfc6052::parse_network_specific_prefix;
+use ipnet::{Ipv4Net, Ipv6Net};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+#[derive(Debug, clap::Parser)]
+#[clap(author, version, about="NAT46 for IPv4-only clients reaching IPv6-only servers", long_about = None)]
+pub struct Args {
+    #[command(flatten)]
+    config_data: Config,
+
+    /// Path to a config file to read. Any config values also passed as CLI args will override those in the file.
+    #[clap(short = 'c', long = "config")]
+    config_file: Option<PathBuf>,
+
+    /// Format of the config file (detected from the file extension by default)
+    #[clap(long = "config-format", value_enum, global = true)]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Explicitly set the interface name to use
+    #[clap(short, long, default_value_t = ("nat46_%d").to_string())]
+    pub interface: String,
+
+    #[command(flatten)]
+    pub profiler_args: ProfilerArgs,
+
+    /// Write packets that fail translation to this pcap file (drop reasons are written to `<file>.reasons`)
+    #[clap(long = "capture-drops")]
+    pub capture_drops: Option<PathBuf>,
+
+    /// Print what would become of each packet in `--input` instead of bringing up a NAT46
+    #[clap(long = "dry-run", requires = "input")]
+    pub dry_run: bool,
+
+    /// Capture to read packets from during a dry run
+    #[clap(long, requires = "dry_run")]
+    pub input: Option<PathBuf>,
+
+    /// Enable verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// IDs of all args that were explicitly set on the command line
+    #[clap(skip)]
+    pub(super) explicit_args: Vec<String>,
+}
+
+impl Args {
+    pub fn data(&self) -> Result<Config, Box<dyn std::error::Error>> {
+        let data = match self.config_file {
+            Some(ref path) => {
+                // Read the data from the config file
+                if !path.exists() {
+                    log::error!("Config file not found: {}", path.display());
+                    std::process::exit(1)
+                }
+                let mut data: Config = super::read_config_file(path, self.config_format)?;
+
+                // Anything set on the command line takes priority
+                data.apply_overrides(&self.config_data, &self.explicit_args);
+                data
+            }
+            None => {
+                if !super::any_explicitly_set::<Config>(&self.explicit_args) {
+                    log::error!("No configuration provided. Either use --config to specify a file or set the configuration via CLI args (see --help)");
+                    std::process::exit(1)
+                }
+                self.config_data.clone()
+            }
+        };
+
+        // Refuse to start with a config that is known to be broken
+        let issues = data.validate();
+        if !issues.is_empty() {
+            for issue in issues {
+                log::error!("Invalid configuration: {}", issue);
+            }
+            std::process::exit(1);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Program configuration. Specifiable via either CLI args or a config file
+#[derive(Debug, clap::Args, serde::Deserialize, Clone)]
+#[group()]
+pub struct Config {
+    /// IPv4 prefixes that IPv6 servers are mapped into. These must be routed to this machine.
+    #[clap(long = "server-pool")]
+    pub server_pool: Vec<Ipv4Net>,
+
+    /// RFC6052 prefix that client IPv4 addresses are embedded in, so that servers can answer them. It must be routed to this machine.
+    #[clap(long = "client-prefix", value_parser = parse_network_specific_prefix)]
+    #[serde(default)]
+    pub client_prefix: Option<Ipv6Net>,
+
+    /// Static mapping between an IPv4 address in the server pool and an IPv6 server, formatted as `<ipv4>=<ipv6>`
+    #[clap(long = "static-map")]
+    #[serde(default)]
+    pub static_map: Vec<StaticMap>,
+
+    /// Number of seconds a mapping made for a DNS answer is kept before its IPv4 address is returned to the pool
+    #[clap(long = "reservation-timeout", default_value = "7200")]
+    #[serde(default = "default_reservation_timeout")]
+    pub reservation_timeout: u64,
+
+    /// Serve DNS on this address, forwarding queries to `--dns-upstream` and answering A queries for IPv6-only names with addresses mapped to their AAAA records (DNS46)
+    #[clap(long = "dns-proxy", requires = "dns_upstream")]
+    #[serde(default)]
+    pub dns_proxy: Option<SocketAddr>,
+
+    /// Resolver to forward DNS proxy queries to
+    #[clap(long = "dns-upstream")]
+    #[serde(default)]
+    pub dns_upstream: Option<SocketAddr>,
+
+    /// Enable prometheus metrics on a given address
+    #[clap(long = "prometheus")]
+    #[serde(rename = "prometheus_bind_addr")]
+    pub prom_bind_addr: Option<SocketAddr>,
+
+    /// Serve /healthz and /readyz on a dedicated address (they are also served alongside prometheus metrics)
+    #[clap(long = "health")]
+    #[serde(rename = "health_bind_addr", default)]
+    pub health_bind_addr: Option<SocketAddr>,
+
+    /// Number of queues to create on the TUN device
+    #[clap(long, default_value = "10")]
+    #[serde(rename = "queues")]
+    pub num_queues: usize,
+
+    /// MTU of the TUN device. Packet buffers are sized to match, and routes towards the device are given matching MTUs.
+    #[clap(long, default_value = "1500")]
+    #[serde(default = "super::default_mtu")]
+    pub mtu: u32,
+
+    /// Only create (or attach to) the TUN interface. Bringing it up and adding routes is left to the environment.
+    #[clap(long = "no-netlink", alias = "no-routes")]
+    #[serde(default)]
+    pub no_netlink: bool,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+}
+
+fn default_reservation_timeout() -> u64 {
+    7200
+}
+
+impl Config {
+    /// Check the config for problems, returning every one found
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue = |location: String, message: String| {
+            issues.push(ConfigIssue { location, message });
+        };
+
+        // We need somewhere to map servers into
+        if self.server_pool.is_empty() {
+            issue(
+                "server_pool".to_string(),
+                "at least one prefix must be specified".to_string(),
+            );
+        }
+
+        // Clients need addresses that servers can answer. The Well-Known Prefix can't hold private IPv4 addresses
+        // (RFC6052), so there is no sensible default.
+        if self.client_prefix.is_none() {
+            issue("client_prefix".to_string(), "must be specified".to_string());
+        }
+
+        // Static mappings must stay inside the pool, so their return traffic is routed here
+        for (i, mapping) in self.static_map.iter().enumerate() {
+            if !self
+                .server_pool
+                .iter()
+                .any(|prefix| prefix.contains(&mapping.ipv4))
+            {
+                issue(
+                    format!("static_map[{}]", i),
+                    format!(
+                        "{} -> {} is outside of the server pool",
+                        mapping.ipv4, mapping.ipv6
+                    ),
+                );
+            }
+        }
+
+        // The DNS proxy needs somewhere to send queries
+        if self.dns_proxy.is_some() && self.dns_upstream.is_none() {
+            issue(
+                "dns_upstream".to_string(),
+                "is required by dns_proxy".to_string(),
+            );
+        }
+
+        // The MTU must be usable by IPv6
+        if !(super::MIN_MTU..=super::MAX_MTU).contains(&self.mtu) {
+            issue(
+                "mtu".to_string(),
+                format!("must be between {} and {}", super::MIN_MTU, super::MAX_MTU),
+            );
+        }
+
+        issues
+    }
+
+    /// Get how long mappings made for DNS answers are kept
+    pub fn reservation_timeout(&self) -> Duration {
+        Duration::from_secs(self.reservation_timeout)
+    }
+
+    /// Replace values with those from `overrides` that were explicitly set on the command line
+    fn apply_overrides(&mut self, overrides: &Config, explicit_args: &[String]) {
+        super::apply_overrides!(
+            explicit_args,
+            self,
+            overrides,
+            [
+                server_pool,
+                client_prefix,
+                static_map,
+                reservation_timeout,
+                dns_proxy,
+                dns_upstream,
+                prom_bind_addr,
+                health_bind_addr,
+                num_queues,
+                mtu,
+                no_netlink,
+            ]
+        );
+        super::apply_overrides!(
+            explicit_args,
+            self.telemetry,
+            overrides.telemetry,
+            [dsn, environment, release]
+        );
+    }
+}
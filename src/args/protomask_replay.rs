@@ -0,0 +1,40 @@
+//! Commandline arguments for `protomask-replay`
+
+use super::ConfigFormat;
+use ipnet::Ipv6Net;
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Parser)]
+#[clap(author, version, about="Replay a packet capture through the translator, for offline conformance testing", long_about = None)]
+pub struct Args {
+    /// pcap file of IPv4 and IPv6 packets to translate
+    pub input: PathBuf,
+
+    /// Write the translated packets to this pcap file. Dropped packets are written as empty records so that packet numbers line up with the input.
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// NAT64 config file to translate with. Without one, translation is stateless (both addresses are embedded in the prefix).
+    #[clap(short = 'c', long = "config")]
+    pub config_file: Option<PathBuf>,
+
+    /// Format of the config file (detected from the file extension by default)
+    #[clap(long = "config-format", value_enum)]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Translation prefix to use for stateless translation
+    #[clap(long, default_value = "64:ff9b::/96")]
+    pub prefix: Ipv6Net,
+
+    /// pcap file of the packets translation should produce, in the same layout as `--output`. Each output packet is compared with the expected one.
+    #[clap(short, long)]
+    pub expected: Option<PathBuf>,
+
+    /// Write the per-packet verdict report to this file instead of STDOUT
+    #[clap(short, long)]
+    pub report: Option<PathBuf>,
+
+    /// Enable verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
@@ -0,0 +1,200 @@
+//! External address assignment
+//!
+//! When configured, the hook is consulted whenever an IPv6 source without a mapping needs an IPv4 address, letting an
+//! external system (such as a RADIUS/AAA integration) pick the address. The hook is either a shell command, or a plain
+//! HTTP URL that is fetched with the source appended as the `ipv6` query parameter.
+//!
+//! A command that prints an IPv4 address, or a `200` response with one as its body, assigns that address. A command
+//! that prints nothing, or a `204` response, leaves the choice to the pool. Anything else refuses the source, and
+//! refusals are remembered for a while so that the hook isn't consulted for every packet. If the hook can't be run
+//! at all, the pool is used so that an outage of the external system doesn't take down the NAT64.
+
+use super::{
+    http::{self, HttpUrl},
+    prefix_tables::AddressTable,
+};
+use std::{
+    collections::HashMap,
+    io::Read,
+    net::{Ipv4Addr, Ipv6Addr},
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long the hook may take to answer
+const HOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a refused source is refused for before the hook is asked again
+const REFUSAL_TTL: Duration = Duration::from_secs(60);
+
+/// What the hook decided for a source
+#[derive(Debug, PartialEq, Eq)]
+enum Assignment {
+    Address(Ipv4Addr),
+    Pool,
+    Refuse,
+}
+
+/// Where the hook is
+enum Target {
+    Http(HttpUrl),
+    Command(String),
+}
+
+/// An external address-assignment hook
+pub struct AddressHook {
+    target: Target,
+    /// Sources that were refused, and when
+    refused: Mutex<HashMap<Ipv6Addr, Instant>>,
+}
+
+impl AddressHook {
+    /// Parse a hook. Anything other than an `http://` URL is treated as a shell command.
+    pub fn new(hook: &str) -> Result<Self, String> {
+        let target = if hook.starts_with("http://") || hook.starts_with("https://") {
+            Target::Http(HttpUrl::parse(hook)?)
+        } else {
+            Target::Command(hook.to_string())
+        };
+        Ok(Self {
+            target,
+            refused: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get the IPv4 address for an IPv6 source, asking the hook how to assign one if it has none yet
+    pub fn get_or_assign_ipv4(
+        &self,
+        table: &AddressTable,
+        source: Ipv6Addr,
+    ) -> Result<Ipv4Addr, String> {
+        if let Some(ipv4) = table.lock().unwrap().get_ipv4(&source) {
+            return Ok(ipv4);
+        }
+
+        // Don't keep asking about sources that were just refused
+        {
+            let mut refused = self.refused.lock().unwrap();
+            match refused.get(&source) {
+                Some(since) if since.elapsed() < REFUSAL_TTL => {
+                    return Err("Refused by the address hook".to_string())
+                }
+                Some(_) => {
+                    refused.remove(&source);
+                }
+                None => {}
+            }
+        }
+
+        // The table is left unlocked while waiting on the hook, so other traffic keeps flowing
+        let assignment = match self.ask(source) {
+            Ok(assignment) => assignment,
+            Err(error) => {
+                log::warn!(
+                    "Address hook failed for {}, using the pool: {}",
+                    source,
+                    error
+                );
+                Assignment::Pool
+            }
+        };
+        log::debug!("Address hook assigned {:?} to {}", assignment, source);
+
+        // Another worker may have mapped the source in the meantime
+        let mut table = table.lock().unwrap();
+        if let Some(ipv4) = table.get_ipv4(&source) {
+            return Ok(ipv4);
+        }
+        match assignment {
+            Assignment::Address(ipv4) => table
+                .insert_dynamic(ipv4, source)
+                .map(|()| ipv4)
+                .map_err(|error| format!("Can't use the address from the hook: {}", error)),
+            Assignment::Pool => table.get_or_create_ipv4(&source).map_err(|error| {
+                log::error!("Error getting IPv4 address: {}", error);
+                error.to_string()
+            }),
+            Assignment::Refuse => {
+                drop(table);
+                log::info!("Address hook refused {}", source);
+                self.refused.lock().unwrap().insert(source, Instant::now());
+                Err("Refused by the address hook".to_string())
+            }
+        }
+    }
+
+    /// Ask the hook what to assign to a source
+    fn ask(&self, source: Ipv6Addr) -> Result<Assignment, String> {
+        match &self.target {
+            Target::Http(url) => {
+                let separator = if url.path.contains('?') { '&' } else { '?' };
+                let (status, body) = http::request(
+                    url,
+                    "GET",
+                    &format!("{}{}ipv6={}", url.path, separator, source),
+                    None,
+                    HOOK_TIMEOUT,
+                )?;
+                match status {
+                    200 => parse_address(&body),
+                    204 => Ok(Assignment::Pool),
+                    _ => Ok(Assignment::Refuse),
+                }
+            }
+            Target::Command(command) => {
+                let (success, output) = run_command(command, source)?;
+                if success {
+                    parse_address(&output)
+                } else {
+                    Ok(Assignment::Refuse)
+                }
+            }
+        }
+    }
+}
+
+/// Parse an answer that should either be empty or contain an IPv4 address
+fn parse_address(answer: &str) -> Result<Assignment, String> {
+    match answer.trim() {
+        "" => Ok(Assignment::Pool),
+        address => address
+            .parse()
+            .map(Assignment::Address)
+            .map_err(|_| format!("{:?} is not an IPv4 address", address)),
+    }
+}
+
+/// Run a hook command with the source in `PROTOMASK_IPV6`, returning whether it succeeded along with its output
+fn run_command(command: &str, source: Ipv6Addr) -> Result<(bool, String), String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PROTOMASK_IPV6", source.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|error| error.to_string())?;
+
+    // Wait for it to finish, but not forever
+    let deadline = Instant::now() + HOOK_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|error| error.to_string())? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("Timed out after {:?}", HOOK_TIMEOUT));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        stdout
+            .read_to_string(&mut output)
+            .map_err(|error| error.to_string())?;
+    }
+    Ok((status.success(), output))
+}
@@ -0,0 +1,508 @@
+//! SNMP subagent ([AgentX, RFC2741](https://datatracker.ietf.org/doc/html/rfc2741))
+//!
+//! When configured, protomask registers a small read-only subtree with the system's SNMP master agent
+//! (for example, net-snmp's `snmpd` with `master agentx`) so pool utilization, packet counters, and active
+//! mapping counts can be polled by network management systems that don't speak Prometheus.
+
+use super::{counters::QueueCounters, state_dump::StateDumpSource};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+
+/// Root of the protomask subtree (inside `NET-SNMP-MIB::netSnmpPlaypen`, which is set aside for local use)
+pub const BASE_OID: [u32; 10] = [1, 3, 6, 1, 4, 1, 8072, 9999, 9999, 64];
+
+/// How long to wait before reconnecting to the master agent
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Seconds the master agent should wait for us to answer a request
+const TIMEOUT_SECS: u8 = 5;
+
+/// Registration priority (the RFC's default)
+const DEFAULT_PRIORITY: u8 = 127;
+
+/// Largest payload we are willing to read from the master agent
+const MAX_PAYLOAD_LEN: usize = 65536;
+
+/// AgentX protocol version number
+const AGENTX_VERSION: u8 = 1;
+
+/// Size of every PDU header
+const HEADER_LEN: usize = 20;
+
+/// Header flags
+const FLAG_NON_DEFAULT_CONTEXT: u8 = 0x08;
+const FLAG_NETWORK_BYTE_ORDER: u8 = 0x10;
+
+/// PDU types
+const PDU_OPEN: u8 = 1;
+const PDU_CLOSE: u8 = 2;
+const PDU_REGISTER: u8 = 3;
+const PDU_GET: u8 = 5;
+const PDU_GET_NEXT: u8 = 6;
+const PDU_GET_BULK: u8 = 7;
+const PDU_TEST_SET: u8 = 8;
+const PDU_COMMIT_SET: u8 = 9;
+const PDU_UNDO_SET: u8 = 10;
+const PDU_RESPONSE: u8 = 18;
+
+/// Variable binding types
+const TYPE_OCTET_STRING: u16 = 4;
+const TYPE_GAUGE32: u16 = 66;
+const TYPE_TIME_TICKS: u16 = 67;
+const TYPE_COUNTER64: u16 = 70;
+const TYPE_NO_SUCH_OBJECT: u16 = 128;
+const TYPE_END_OF_MIB_VIEW: u16 = 130;
+
+/// Error returned when the master agent tries to set one of our objects
+const ERROR_NOT_WRITABLE: u16 = 17;
+
+/// The value of a single object
+enum Value {
+    OctetString(String),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Counter64(u64),
+}
+
+/// Collect the current value of every object, sorted by OID
+fn objects(state: &StateDumpSource) -> Vec<(Vec<u32>, Value)> {
+    let mut pool_size = 0;
+    let mut mapped = 0;
+    let mut static_mappings = 0;
+    for table in state.prefix_tables.tables() {
+        let table = table.lock().unwrap();
+        pool_size += table.pool_size();
+        mapped += table.len();
+        static_mappings += table
+            .mappings()
+            .filter(|(.., remaining)| remaining.is_none())
+            .count();
+    }
+
+    // Sum a counter across all queues
+    let total = |counter: fn(&QueueCounters) -> &AtomicU64| {
+        state
+            .queue_counters
+            .iter()
+            .map(|queue| counter(queue).load(Ordering::Relaxed))
+            .sum()
+    };
+    let gauge = |value: usize| Value::Gauge32(u32::try_from(value).unwrap_or(u32::MAX));
+    let uptime_ticks = state.start_time.elapsed().as_millis() / 10;
+
+    [
+        (1, Value::OctetString(state.interface.clone())),
+        (2, gauge(pool_size)),
+        (3, gauge(mapped)),
+        (4, gauge(static_mappings)),
+        (5, Value::Counter64(total(|queue| &queue.packets_received))),
+        (6, Value::Counter64(total(|queue| &queue.packets_sent))),
+        (7, Value::Counter64(total(|queue| &queue.packets_dropped))),
+        (
+            8,
+            Value::TimeTicks(u32::try_from(uptime_ticks).unwrap_or(u32::MAX)),
+        ),
+    ]
+    .into_iter()
+    .map(|(id, value)| {
+        let mut oid = BASE_OID.to_vec();
+        oid.extend([id, 0]);
+        (oid, value)
+    })
+    .collect()
+}
+
+/// Register with the master agent and answer its requests, reconnecting whenever the session is lost
+///
+/// `master` is either the path of a unix socket or `tcp:<host>:<port>`, following net-snmp's `agentXSocket` syntax.
+pub async fn run_subagent(master: String, state: Arc<StateDumpSource>) {
+    loop {
+        if let Err(error) = session(&master, &state).await {
+            log::warn!(
+                "AgentX session with {} ended: {}. Reconnecting in {}s",
+                master,
+                error,
+                RECONNECT_DELAY.as_secs()
+            );
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connect to the master agent and run a single session
+async fn session(master: &str, state: &StateDumpSource) -> io::Result<()> {
+    if let Some(address) = master.strip_prefix("tcp:") {
+        serve(TcpStream::connect(address).await?, master, state).await
+    } else {
+        serve(UnixStream::connect(master).await?, master, state).await
+    }
+}
+
+/// Open a session, register our subtree, and answer requests until the connection fails
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    master: &str,
+    state: &StateDumpSource,
+) -> io::Result<()> {
+    // Open a session
+    let mut payload = vec![TIMEOUT_SECS, 0, 0, 0];
+    put_oid(&mut payload, &BASE_OID, false);
+    put_octet_string(&mut payload, b"protomask");
+    stream
+        .write_all(&encode_pdu(PDU_OPEN, 0, 0, 1, &payload))
+        .await?;
+    let session_id = expect_success(&mut stream).await?.session_id;
+
+    // Register our subtree
+    let mut payload = vec![TIMEOUT_SECS, DEFAULT_PRIORITY, 0, 0];
+    put_oid(&mut payload, &BASE_OID, false);
+    stream
+        .write_all(&encode_pdu(PDU_REGISTER, session_id, 0, 2, &payload))
+        .await?;
+    expect_success(&mut stream).await?;
+    log::info!(
+        "Registered with SNMP master agent at {} (session {})",
+        master,
+        session_id
+    );
+
+    loop {
+        let (header, payload) = read_pdu(&mut stream).await?;
+        let response = match header.pdu_type {
+            PDU_GET | PDU_GET_NEXT | PDU_GET_BULK => {
+                let varbinds = answer(&header, &payload, &objects(state))
+                    .ok_or_else(|| malformed("request"))?;
+                Some(response_payload(0, 0, &varbinds))
+            }
+            PDU_TEST_SET => Some(response_payload(ERROR_NOT_WRITABLE, 1, &[])),
+            PDU_COMMIT_SET | PDU_UNDO_SET => Some(response_payload(0, 0, &[])),
+            PDU_CLOSE => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "master agent closed the session",
+                ))
+            }
+            _ => None,
+        };
+
+        if let Some(response) = response {
+            stream
+                .write_all(&encode_pdu(
+                    PDU_RESPONSE,
+                    header.session_id,
+                    header.transaction_id,
+                    header.packet_id,
+                    &response,
+                ))
+                .await?;
+        }
+    }
+}
+
+/// Wait for the response to a request we sent, failing if the master agent reported an error
+async fn expect_success<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Header> {
+    let (header, payload) = read_pdu(stream).await?;
+    let mut decoder = Decoder::new(&payload, header.flags);
+    decoder.u32().ok_or_else(|| malformed("response"))?;
+    match decoder.u16().ok_or_else(|| malformed("response"))? {
+        0 => Ok(header),
+        error => Err(io::Error::other(format!(
+            "master agent returned error {}",
+            error
+        ))),
+    }
+}
+
+/// Build the variable bindings answering a Get, GetNext, or GetBulk request
+fn answer(header: &Header, payload: &[u8], objects: &[(Vec<u32>, Value)]) -> Option<Vec<u8>> {
+    let mut decoder = Decoder::new(payload, header.flags);
+    if header.flags & FLAG_NON_DEFAULT_CONTEXT != 0 {
+        decoder.octet_string()?;
+    }
+    let (non_repeaters, max_repetitions) = if header.pdu_type == PDU_GET_BULK {
+        (usize::from(decoder.u16()?), decoder.u16()?)
+    } else {
+        (0, 0)
+    };
+
+    // Every request is a list of search ranges
+    let mut ranges = Vec::new();
+    while !decoder.is_empty() {
+        let (start, include) = decoder.oid()?;
+        let (end, _) = decoder.oid()?;
+        ranges.push((start, include, end));
+    }
+
+    let mut varbinds = Vec::new();
+    match header.pdu_type {
+        PDU_GET => {
+            for (start, ..) in &ranges {
+                match objects.iter().find(|(oid, _)| oid == start) {
+                    Some((oid, value)) => put_varbind(&mut varbinds, oid, value),
+                    None => put_exception(&mut varbinds, start, TYPE_NO_SUCH_OBJECT),
+                }
+            }
+        }
+        PDU_GET_NEXT => {
+            for (start, include, end) in &ranges {
+                put_next(&mut varbinds, objects, start, *include, end);
+            }
+        }
+        _ => {
+            let non_repeaters = non_repeaters.min(ranges.len());
+            for (start, include, end) in &ranges[..non_repeaters] {
+                put_next(&mut varbinds, objects, start, *include, end);
+            }
+
+            // Walk each remaining range forward in lockstep
+            let mut cursors = ranges[non_repeaters..].to_vec();
+            for _ in 0..max_repetitions {
+                let mut progressed = false;
+                for (start, include, end) in &mut cursors {
+                    if let Some(oid) = put_next(&mut varbinds, objects, start, *include, end) {
+                        *start = oid;
+                        *include = false;
+                        progressed = true;
+                    }
+                }
+                if !progressed {
+                    break;
+                }
+            }
+        }
+    }
+    Some(varbinds)
+}
+
+/// Write the first object after `start` (and before `end`, if set), returning its OID if there was one
+fn put_next(
+    buffer: &mut Vec<u8>,
+    objects: &[(Vec<u32>, Value)],
+    start: &[u32],
+    include: bool,
+    end: &[u32],
+) -> Option<Vec<u32>> {
+    let next = objects.iter().find(|(oid, _)| {
+        (oid.as_slice() > start || (include && oid == start))
+            && (end.is_empty() || oid.as_slice() < end)
+    });
+    if let Some((oid, value)) = next {
+        put_varbind(buffer, oid, value);
+        Some(oid.clone())
+    } else {
+        put_exception(buffer, start, TYPE_END_OF_MIB_VIEW);
+        None
+    }
+}
+
+/// The fixed part of a PDU header
+struct Header {
+    pdu_type: u8,
+    flags: u8,
+    session_id: u32,
+    transaction_id: u32,
+    packet_id: u32,
+}
+
+/// Read a single PDU from the master agent
+async fn read_pdu<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(Header, Vec<u8>)> {
+    let mut raw_header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut raw_header).await?;
+    if raw_header[0] != AGENTX_VERSION {
+        return Err(malformed("header"));
+    }
+
+    let mut decoder = Decoder::new(&raw_header[4..], raw_header[2]);
+    let (Some(session_id), Some(transaction_id), Some(packet_id), Some(payload_len)) =
+        (decoder.u32(), decoder.u32(), decoder.u32(), decoder.u32())
+    else {
+        return Err(malformed("header"));
+    };
+    let payload_len = payload_len as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(malformed("header"));
+    }
+
+    let mut payload = vec![0u8; payload_len];
+    stream.read_exact(&mut payload).await?;
+    Ok((
+        Header {
+            pdu_type: raw_header[1],
+            flags: raw_header[2],
+            session_id,
+            transaction_id,
+            packet_id,
+        },
+        payload,
+    ))
+}
+
+/// Build a complete PDU. We always send in network byte order.
+fn encode_pdu(
+    pdu_type: u8,
+    session_id: u32,
+    transaction_id: u32,
+    packet_id: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut pdu = Vec::with_capacity(HEADER_LEN + payload.len());
+    pdu.extend([AGENTX_VERSION, pdu_type, FLAG_NETWORK_BYTE_ORDER, 0]);
+    pdu.extend(session_id.to_be_bytes());
+    pdu.extend(transaction_id.to_be_bytes());
+    pdu.extend(packet_id.to_be_bytes());
+    pdu.extend(u32::try_from(payload.len()).unwrap().to_be_bytes());
+    pdu.extend(payload);
+    pdu
+}
+
+/// Build the payload of a Response PDU
+fn response_payload(error: u16, index: u16, varbinds: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0, 0, 0, 0];
+    payload.extend(error.to_be_bytes());
+    payload.extend(index.to_be_bytes());
+    payload.extend(varbinds);
+    payload
+}
+
+/// Write an object identifier, compressing the common `1.3.6.1.x` prefix
+fn put_oid(buffer: &mut Vec<u8>, oid: &[u32], include: bool) {
+    let (prefix, subids) = match oid {
+        [1, 3, 6, 1, prefix, rest @ ..] if *prefix < 256 => (*prefix, rest),
+        _ => (0, oid),
+    };
+    buffer.extend([
+        u8::try_from(subids.len()).unwrap(),
+        u8::try_from(prefix).unwrap(),
+        u8::from(include),
+        0,
+    ]);
+    for subid in subids {
+        buffer.extend(subid.to_be_bytes());
+    }
+}
+
+/// Write a length-prefixed octet string, padded to a multiple of four bytes
+fn put_octet_string(buffer: &mut Vec<u8>, data: &[u8]) {
+    buffer.extend(u32::try_from(data.len()).unwrap().to_be_bytes());
+    buffer.extend(data);
+    buffer.resize(buffer.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+/// Write a variable binding
+fn put_varbind(buffer: &mut Vec<u8>, oid: &[u32], value: &Value) {
+    let value_type = match value {
+        Value::OctetString(_) => TYPE_OCTET_STRING,
+        Value::Gauge32(_) => TYPE_GAUGE32,
+        Value::TimeTicks(_) => TYPE_TIME_TICKS,
+        Value::Counter64(_) => TYPE_COUNTER64,
+    };
+    buffer.extend(value_type.to_be_bytes());
+    buffer.extend([0, 0]);
+    put_oid(buffer, oid, false);
+    match value {
+        Value::OctetString(value) => put_octet_string(buffer, value.as_bytes()),
+        Value::Gauge32(value) | Value::TimeTicks(value) => buffer.extend(value.to_be_bytes()),
+        Value::Counter64(value) => buffer.extend(value.to_be_bytes()),
+    }
+}
+
+/// Write a variable binding that carries one of the exception types instead of a value
+fn put_exception(buffer: &mut Vec<u8>, oid: &[u32], exception: u16) {
+    buffer.extend(exception.to_be_bytes());
+    buffer.extend([0, 0]);
+    put_oid(buffer, oid, false);
+}
+
+/// Reads fields from a PDU in whichever byte order the sender chose
+struct Decoder<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8], flags: u8) -> Self {
+        Self {
+            data,
+            big_endian: flags & FLAG_NETWORK_BYTE_ORDER != 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Read an object identifier and its `include` flag
+    fn oid(&mut self) -> Option<(Vec<u32>, bool)> {
+        let subid_count = self.u8()?;
+        let prefix = self.u8()?;
+        let include = self.u8()? != 0;
+        self.u8()?;
+
+        let mut oid = if prefix == 0 {
+            Vec::new()
+        } else {
+            vec![1, 3, 6, 1, u32::from(prefix)]
+        };
+        for _ in 0..subid_count {
+            oid.push(self.u32()?);
+        }
+        Some((oid, include))
+    }
+
+    /// Skip over an octet string
+    fn octet_string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let data = self.take(len)?;
+        self.take((4 - len % 4) % 4)?;
+        Some(data)
+    }
+}
+
+/// Error for a PDU that couldn't be parsed
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed AgentX {}", what),
+    )
+}
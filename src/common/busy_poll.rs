@@ -0,0 +1,104 @@
+//! Reading packets from TUN queues, optionally by busy polling
+//!
+//! Normally a worker sleeps in `read` until the kernel wakes it for the next packet, which costs a few microseconds of
+//! scheduling latency per wakeup. With busy polling, TUN queues are made non-blocking and workers spin on them instead.
+//! Once a worker has spun for its poll budget without finding a packet, it sleeps until the queue is readable again,
+//! so an idle translator doesn't keep every core busy forever.
+
+use super::packet_buffer::PacketBuffer;
+use easy_tun::Tun;
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    poll::{poll, PollFd, PollFlags},
+};
+use std::{
+    io::{ErrorKind, Read},
+    os::fd::AsRawFd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Make every queue of a TUN interface blocking or non-blocking.
+///
+/// This is always set explicitly, since queues handed over during an upgrade keep the flags of the old process.
+pub fn set_nonblocking(tun: &Tun, nonblocking: bool) -> nix::Result<()> {
+    for queue_id in 0..tun.num_queues() {
+        let fd = tun.fd(queue_id).unwrap().as_raw_fd();
+        let mut flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        flags.set(OFlag::O_NONBLOCK, nonblocking);
+        fcntl(fd, FcntlArg::F_SETFL(flags))?;
+    }
+    Ok(())
+}
+
+/// Reads packets from a single TUN queue
+#[derive(Clone)]
+pub struct TunReader {
+    tun: Arc<Tun>,
+    queue_id: usize,
+    /// How long to spin before sleeping, if busy polling
+    poll_budget: Option<Duration>,
+}
+
+impl TunReader {
+    /// Read from a queue, busy polling for up to `poll_budget` at a time if set. The queue must be non-blocking
+    /// exactly when busy polling.
+    pub fn new(tun: Arc<Tun>, queue_id: usize, poll_budget: Option<Duration>) -> Self {
+        Self {
+            tun,
+            queue_id,
+            poll_budget,
+        }
+    }
+
+    /// Get the TUN interface being read from
+    pub fn tun(&self) -> &Arc<Tun> {
+        &self.tun
+    }
+
+    /// Get the ID of the queue being read from
+    pub fn queue_id(&self) -> usize {
+        self.queue_id
+    }
+
+    /// Wait for the next packet, copying it into `buffer` and returning its length.
+    ///
+    /// A packet too large for `buffer` is dropped, and `buffer` grows so the next one fits, unless its size is fixed.
+    pub fn read(&self, buffer: &mut PacketBuffer) -> usize {
+        loop {
+            let len = self.read_once(buffer);
+            if len <= buffer.len() {
+                return len;
+            }
+            buffer.dropped_oversized(len);
+        }
+    }
+
+    /// Wait for the next packet, returning the length reported by the kernel, which may be larger than `buffer`
+    fn read_once(&self, buffer: &mut [u8]) -> usize {
+        let mut fd = self.tun.fd(self.queue_id).unwrap();
+        let Some(poll_budget) = self.poll_budget else {
+            return fd.read(buffer).unwrap();
+        };
+
+        let mut spinning_since = Instant::now();
+        loop {
+            match fd.read(buffer) {
+                Ok(len) => return len,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => panic!("Failed to read from queue {}: {}", self.queue_id, error),
+            }
+
+            // Out of budget, so sleep until there is something to read
+            if spinning_since.elapsed() >= poll_budget {
+                let mut fds = [PollFd::new(fd.as_raw_fd(), PollFlags::POLLIN)];
+                if let Err(error) = poll(&mut fds, -1) {
+                    log::warn!("Failed to wait for queue {}: {}", self.queue_id, error);
+                }
+                spinning_since = Instant::now();
+            } else {
+                std::hint::spin_loop();
+            }
+        }
+    }
+}
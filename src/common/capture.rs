@@ -0,0 +1,78 @@
+//! Capture of packets that could not be translated
+//!
+//! Dropped packets are written to a standard pcap file.
+//! Since classic pcap has no room for annotations, the reason for each drop is written to a sidecar index file
+//! next to the capture (`<file>.reasons`), where each line is `<packet number> <timestamp> <reason>`.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::pcap::PcapWriter;
+
+/// Open capture and index files
+struct CaptureFiles {
+    pcap: PcapWriter,
+    index: BufWriter<File>,
+    count: u64,
+}
+
+/// Writes dropped packets to a pcap file for offline analysis
+pub struct DropCapture {
+    files: Mutex<CaptureFiles>,
+}
+
+impl DropCapture {
+    /// Create (or truncate) a capture file at `path`, along with its drop reason index
+    pub fn new(path: &Path) -> std::io::Result<Self> {
+        let pcap = PcapWriter::create(path)?;
+        let index = BufWriter::new(File::create(index_path(path))?);
+
+        Ok(Self {
+            files: Mutex::new(CaptureFiles {
+                pcap,
+                index,
+                count: 0,
+            }),
+        })
+    }
+
+    /// Record a dropped packet along with the reason it was dropped
+    #[profiling::function]
+    pub fn record(&self, packet: &[u8], reason: &str) {
+        let mut files = self.files.lock().unwrap();
+        if let Err(error) = files.write(packet, reason) {
+            log::warn!("Failed to write dropped packet to capture: {}", error);
+        }
+    }
+}
+
+impl CaptureFiles {
+    /// Append a packet and its reason. Both files are flushed so the capture is usable while protomask is still running.
+    fn write(&mut self, packet: &[u8], reason: &str) -> std::io::Result<()> {
+        let now = SystemTime::now();
+        self.pcap
+            .write(packet, now.duration_since(UNIX_EPOCH).unwrap_or_default())?;
+        self.pcap.flush()?;
+
+        // Packet numbers are 1-indexed to match what Wireshark displays
+        self.count += 1;
+        writeln!(
+            self.index,
+            "{} {} {}",
+            self.count,
+            humantime::format_rfc3339_micros(now),
+            reason
+        )?;
+        self.index.flush()
+    }
+}
+
+/// Get the path of the drop reason index for a given capture file
+fn index_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.reasons", path.display()))
+}
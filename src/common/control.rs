@@ -0,0 +1,416 @@
+//! Control socket
+//!
+//! When configured, protomask listens on a unix socket for requests from `protomaskctl`.
+//! Every request and response is a single line of JSON.
+
+use super::{
+    counters::TrafficCount, failover::Failover, permissions::bind_private_socket,
+    state_dump::StateDumpSource,
+};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+
+/// A request sent over the control socket
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum Request {
+    /// Get a snapshot of the translator's state
+    Status,
+    /// Get the traffic of every mapping, busiest first
+    Traffic,
+    /// Get the most recently dropped packets, newest first
+    Drops { limit: Option<usize> },
+    /// Become active, installing routes
+    Promote,
+    /// Go on standby, withdrawing routes
+    Demote,
+    /// List mappings, optionally filtered
+    SessionsList {
+        /// Only list clients in this prefix
+        source: Option<Ipv6Net>,
+        /// Only list dynamic mappings created at least this many seconds ago
+        older_than: Option<u64>,
+        /// Only list mappings that have carried this protocol
+        protocol: Option<Protocol>,
+    },
+    /// Forcibly expire the mapping of an IPv4 or IPv6 address
+    SessionsDelete { address: IpAddr },
+    /// List the pool prefixes
+    PoolList,
+    /// Add a prefix to the main pool
+    PoolAdd { prefix: Ipv4Net },
+    /// Drain a prefix out of its pool, withdrawing its route once it has no mappings left
+    PoolRemove { prefix: Ipv4Net },
+    /// Add many mappings at once, such as ones exported from another instance
+    MappingsImport { mappings: Vec<ImportedMapping> },
+}
+
+/// A mapping to import. Mappings without an expiry are static.
+#[derive(Debug, serde::Deserialize)]
+struct ImportedMapping {
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    expires_in_secs: Option<u64>,
+}
+
+/// How often a draining pool prefix is checked for remaining mappings
+const POOL_DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A transport protocol that mappings can be filtered by
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl Protocol {
+    /// Get the bit marking this protocol in `TrafficCount::protocols`
+    fn bit(self) -> u8 {
+        match self {
+            Self::Tcp => TrafficCount::TCP,
+            Self::Udp => TrafficCount::UDP,
+            Self::Icmp => TrafficCount::ICMP,
+        }
+    }
+}
+
+/// Serve control requests on a unix socket until the process exits. Only returns if the socket can't be set up.
+pub async fn serve_control(
+    path: &Path,
+    state: Arc<StateDumpSource>,
+    failover: Arc<Failover>,
+) -> io::Result<()> {
+    // Only root may control the translator
+    let listener = bind_private_socket(path)?;
+    log::info!("Listening for control requests on {}", path.display());
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(
+                    stream,
+                    Arc::clone(&state),
+                    Arc::clone(&failover),
+                ));
+            }
+            Err(error) => log::warn!("Failed to accept control connection: {}", error),
+        }
+    }
+}
+
+/// Answer requests on a single connection until the client hangs up
+async fn handle_connection(
+    stream: UnixStream,
+    state: Arc<StateDumpSource>,
+    failover: Arc<Failover>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Status) => state.snapshot(),
+            Ok(Request::Traffic) => traffic_response(&state.snapshot()),
+            Ok(Request::Drops { limit }) => state.recent_drops.snapshot(limit),
+            Ok(Request::Promote) => role_response(failover.promote().await, &failover).await,
+            Ok(Request::Demote) => role_response(failover.demote().await, &failover).await,
+            Ok(Request::SessionsList {
+                source,
+                older_than,
+                protocol,
+            }) => sessions_list_response(
+                &state,
+                source,
+                older_than.map(Duration::from_secs),
+                protocol,
+            ),
+            Ok(Request::SessionsDelete { address }) => sessions_delete_response(&state, address),
+            Ok(Request::PoolList) => pool_list_response(&state),
+            Ok(Request::PoolAdd { prefix }) => pool_add_response(&state, &failover, prefix).await,
+            Ok(Request::PoolRemove { prefix }) => pool_remove_response(&state, &failover, prefix),
+            Ok(Request::MappingsImport { mappings }) => mappings_import_response(&state, &mappings),
+            Err(error) => serde_json::json!({ "error": error.to_string() }),
+        };
+
+        let mut response = response.to_string();
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Report the translator's role after a transition, or why it failed
+async fn role_response(result: Result<(), String>, failover: &Failover) -> serde_json::Value {
+    match result {
+        Ok(()) => serde_json::json!({ "role": failover.role().await }),
+        Err(error) => serde_json::json!({ "error": error }),
+    }
+}
+
+/// Pick the per-mapping traffic out of a status snapshot, sorted by bytes
+fn traffic_response(snapshot: &serde_json::Value) -> serde_json::Value {
+    let mut mappings = snapshot["mappings"].as_array().cloned().unwrap_or_default();
+    mappings.sort_by_key(|mapping| std::cmp::Reverse(mapping["bytes"].as_u64()));
+    serde_json::json!({ "mappings": mappings })
+}
+
+/// List the mappings matching every given filter
+fn sessions_list_response(
+    state: &StateDumpSource,
+    source: Option<Ipv6Net>,
+    older_than: Option<Duration>,
+    protocol: Option<Protocol>,
+) -> serde_json::Value {
+    let mut sessions = Vec::new();
+    for table in state.prefix_tables.tables() {
+        let table = table.lock().unwrap();
+        for (ipv4, ipv6, remaining) in table.mappings() {
+            let age = table.age(&ipv4);
+            let traffic = state.traffic.get(ipv4);
+            if source.is_some_and(|source| !source.contains(&ipv6))
+                || older_than.is_some_and(|older_than| age.is_none_or(|age| age < older_than))
+                || protocol.is_some_and(|protocol| traffic.protocols & protocol.bit() == 0)
+            {
+                continue;
+            }
+            sessions.push(serde_json::json!({
+                "ipv4": ipv4,
+                "ipv6": ipv6,
+                "static": remaining.is_none(),
+                "age_secs": age.map(|age| age.as_secs()),
+                "expires_in_secs": remaining.map(|remaining| remaining.as_secs()),
+                "protocols": traffic.protocol_names(),
+                "packets": traffic.packets(),
+                "bytes": traffic.bytes(),
+            }));
+        }
+    }
+    serde_json::json!({ "sessions": sessions })
+}
+
+/// Remove the mapping of an address, as if it had expired
+fn sessions_delete_response(state: &StateDumpSource, address: IpAddr) -> serde_json::Value {
+    // Find the table holding the mapping, and its IPv4 side
+    let found = match address {
+        IpAddr::V4(ipv4) => state
+            .prefix_tables
+            .table_for_ipv4(ipv4)
+            .map(|table| (table, ipv4)),
+        IpAddr::V6(ipv6) => state.prefix_tables.tables().find_map(|table| {
+            let ipv4 = table.lock().unwrap().get_ipv4(&ipv6)?;
+            Some((table, ipv4))
+        }),
+    };
+
+    match found.and_then(|(table, ipv4)| Some((ipv4, table.lock().unwrap().remove_ipv4(&ipv4)?))) {
+        Some((ipv4, ipv6)) => {
+            log::info!("Deleted mapping {} -> {} via control socket", ipv6, ipv4);
+            serde_json::json!({ "ipv4": ipv4, "ipv6": ipv6 })
+        }
+        None => serde_json::json!({ "error": format!("No mapping for {}", address) }),
+    }
+}
+
+/// Add every mapping in an import, replacing any dynamic mappings in the way.
+///
+/// Nothing is imported unless every mapping can be.
+fn mappings_import_response(
+    state: &StateDumpSource,
+    mappings: &[ImportedMapping],
+) -> serde_json::Value {
+    // Static mappings are only ever replaced on purpose
+    let existing_static: Vec<_> = state
+        .prefix_tables
+        .tables()
+        .flat_map(|table| {
+            table
+                .lock()
+                .unwrap()
+                .mappings()
+                .filter(|(.., remaining)| remaining.is_none())
+                .map(|(ipv4, ipv6, _)| (ipv4, ipv6))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let translator_address = state.config.lock().unwrap().translator_address;
+
+    let mut problems = Vec::new();
+    let mut seen_ipv4 = HashSet::new();
+    let mut seen_ipv6 = HashSet::new();
+    for mapping in mappings {
+        if state.prefix_tables.table_for_ipv4(mapping.ipv4).is_none() {
+            problems.push(format!("{} is not inside any pool prefix", mapping.ipv4));
+        }
+        if translator_address == Some(mapping.ipv4) {
+            problems.push(format!("{} is the translator address", mapping.ipv4));
+        }
+        if !seen_ipv4.insert(mapping.ipv4) {
+            problems.push(format!("{} is imported more than once", mapping.ipv4));
+        }
+        if !seen_ipv6.insert(mapping.ipv6) {
+            problems.push(format!("{} is imported more than once", mapping.ipv6));
+        }
+        if let Some((ipv4, ipv6)) = existing_static
+            .iter()
+            .find(|(ipv4, ipv6)| (*ipv4 == mapping.ipv4) != (*ipv6 == mapping.ipv6))
+        {
+            problems.push(format!(
+                "{} -> {} conflicts with the static mapping {} -> {}",
+                mapping.ipv6, mapping.ipv4, ipv6, ipv4
+            ));
+        }
+    }
+    if !problems.is_empty() {
+        return serde_json::json!({ "error": problems.join("; ") });
+    }
+
+    let mut imported = 0;
+    for mapping in mappings {
+        // Make way for the mapping on both sides
+        state
+            .prefix_tables
+            .remove_conflicting(mapping.ipv4, mapping.ipv6);
+        let mut table = state
+            .prefix_tables
+            .table_for_ipv4(mapping.ipv4)
+            .unwrap()
+            .lock()
+            .unwrap();
+        let result = match mapping.expires_in_secs {
+            Some(secs) => {
+                table.insert_with_ttl(mapping.ipv4, mapping.ipv6, Duration::from_secs(secs))
+            }
+            None => table.insert_static(mapping.ipv4, mapping.ipv6),
+        };
+        match result {
+            Ok(()) => imported += 1,
+            Err(error) => log::warn!(
+                "Failed to import mapping {} -> {}: {}",
+                mapping.ipv6,
+                mapping.ipv4,
+                error
+            ),
+        }
+    }
+    log::info!("Imported {} mappings via control socket", imported);
+    serde_json::json!({ "imported": imported })
+}
+
+/// List each pool prefix, whether it is being drained, and how many mappings it holds
+fn pool_list_response(state: &StateDumpSource) -> serde_json::Value {
+    let draining = state.prefix_tables.draining_pools();
+    let pools: Vec<_> = state
+        .prefix_tables
+        .pools()
+        .into_iter()
+        .map(|prefix| {
+            serde_json::json!({
+                "prefix": prefix,
+                "draining": draining.contains(&prefix),
+                "mappings": count_mappings(state, prefix),
+            })
+        })
+        .collect();
+    serde_json::json!({ "pools": pools })
+}
+
+/// Make a new prefix allocatable, routing it to us
+async fn pool_add_response(
+    state: &StateDumpSource,
+    failover: &Failover,
+    prefix: Ipv4Net,
+) -> serde_json::Value {
+    if let Err(error) = state.prefix_tables.add_pool(prefix) {
+        return serde_json::json!({ "error": error });
+    }
+    if let Err(error) = failover.add_pool_route(IpNet::V4(prefix)).await {
+        state.prefix_tables.remove_pool(prefix);
+        return serde_json::json!({ "error": error });
+    }
+    state.config.lock().unwrap().pool_prefixes.push(prefix);
+    log::info!("Added {} to the pool via control socket", prefix);
+    serde_json::json!({ "prefix": prefix })
+}
+
+/// Start draining a prefix, removing it in the background once its mappings have expired
+fn pool_remove_response(
+    state: &Arc<StateDumpSource>,
+    failover: &Arc<Failover>,
+    prefix: Ipv4Net,
+) -> serde_json::Value {
+    // Static mappings never expire, so would hold the prefix forever
+    let has_static = state.prefix_tables.tables().any(|table| {
+        table
+            .lock()
+            .unwrap()
+            .mappings()
+            .any(|(ipv4, _, remaining)| remaining.is_none() && prefix.contains(&ipv4))
+    });
+    if has_static {
+        return serde_json::json!({
+            "error": format!("{} has static mappings, which must be deleted first", prefix)
+        });
+    }
+    if let Err(error) = state.prefix_tables.drain_pool(prefix) {
+        return serde_json::json!({ "error": error });
+    }
+    log::info!("Draining {} out of the pool via control socket", prefix);
+    let remaining = count_mappings(state, prefix);
+
+    let state = Arc::clone(state);
+    let failover = Arc::clone(failover);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POOL_DRAIN_INTERVAL);
+        while count_mappings(&state, prefix) > 0 {
+            interval.tick().await;
+        }
+        if let Err(error) = failover.remove_pool_route(IpNet::V4(prefix)).await {
+            log::error!(
+                "Failed to withdraw route for drained pool {}: {}",
+                prefix,
+                error
+            );
+        }
+        state.prefix_tables.remove_pool(prefix);
+        let mut config = state.config.lock().unwrap();
+        config.pool_prefixes.retain(|existing| *existing != prefix);
+        for additional in &mut config.additional_prefixes {
+            additional.pool.retain(|existing| *existing != prefix);
+        }
+        for tenant in &mut config.tenants {
+            tenant.pool.retain(|existing| *existing != prefix);
+        }
+        log::info!("Removed drained prefix {} from the pool", prefix);
+    });
+
+    serde_json::json!({ "prefix": prefix, "mappings": remaining })
+}
+
+/// Count the mappings that use an address in a pool prefix
+fn count_mappings(state: &StateDumpSource, prefix: Ipv4Net) -> usize {
+    state
+        .prefix_tables
+        .tables()
+        .map(|table| {
+            table
+                .lock()
+                .unwrap()
+                .mappings()
+                .filter(|(ipv4, ..)| prefix.contains(ipv4))
+                .count()
+        })
+        .sum()
+}
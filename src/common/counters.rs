@@ -0,0 +1,397 @@
+//! Lightweight per-queue and per-mapping packet counters
+//!
+//! Unlike the prometheus metrics, these are always collected and can be read at any time for introspection.
+
+use super::{
+    packet_handler::{get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto},
+    prefix_tables::PrefixTables,
+};
+use ipnet::Ipv6Net;
+use protomask_metrics::metrics::{
+    label_values::{
+        DIRECTION_INBOUND, DIRECTION_OUTBOUND, PROTOCOL_ICMP, PROTOCOL_ICMPV6, PROTOCOL_IPV4,
+        PROTOCOL_IPV6, PROTOCOL_OTHER, PROTOCOL_TCP, PROTOCOL_UDP,
+    },
+    MAPPING_BYTE_COUNTER, MAPPING_PACKET_COUNTER, PACKET_SIZE_BYTES, SUBSCRIBER_BYTE_COUNTER,
+    SUBSCRIBER_PACKET_COUNTER, TENANT_BYTE_COUNTER, TENANT_LIMITED, TENANT_MAPPINGS,
+    TENANT_PACKET_COUNTER, TENANT_POOL_ADDRESSES,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// How often per-mapping metrics are brought up to date
+const MAPPING_METRICS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Packet counters for a single TUN queue
+#[derive(Debug, Default)]
+pub struct QueueCounters {
+    /// Packets read from the TUN queue
+    pub packets_received: AtomicU64,
+    /// Packets written back to the TUN queue after translation
+    pub packets_sent: AtomicU64,
+    /// Packets that could not be translated
+    pub packets_dropped: AtomicU64,
+    /// TCP options that were translated, but matched a `flag` policy
+    pub tcp_options_flagged: AtomicU64,
+    /// TCP options that were replaced with padding
+    pub tcp_options_stripped: AtomicU64,
+    /// Dropped packets, indexed by `DropReason`
+    drops: [AtomicU64; DropReason::ALL.len()],
+}
+
+/// Why a packet was dropped
+#[derive(Debug, Clone, Copy)]
+pub enum DropReason {
+    /// Dropped while acting as a router hop (eg. its hop limit ran out)
+    Hop,
+    /// Neither IPv4 nor IPv6
+    UnknownProtocol,
+    /// Too short for its headers, or otherwise inconsistent
+    Malformed,
+    /// No mapping could be found or created for it
+    Unmapped,
+    /// The packet itself could not be translated
+    Untranslatable,
+    /// Refused by the policy script
+    Policy,
+    /// The ICMP error it should have caused was rate limited
+    RateLimited,
+    /// Sent to an IPv4 or IPv6 multicast group
+    Multicast,
+    /// Sent to the IPv4 broadcast address
+    Broadcast,
+    /// An internal queue between processing stages was full
+    QueueFull,
+    /// Needs a lease the lease store hasn't answered for yet
+    LeasePending,
+    /// Has no port, but needs one to share a mapping (such as a non-initial fragment)
+    NoPort,
+}
+
+impl DropReason {
+    const ALL: [Self; 12] = [
+        Self::Hop,
+        Self::UnknownProtocol,
+        Self::Malformed,
+        Self::Unmapped,
+        Self::Untranslatable,
+        Self::Policy,
+        Self::RateLimited,
+        Self::Multicast,
+        Self::Broadcast,
+        Self::QueueFull,
+        Self::LeasePending,
+        Self::NoPort,
+    ];
+
+    /// Get the name used when reporting this reason
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hop => "hop",
+            Self::UnknownProtocol => "unknown_protocol",
+            Self::Malformed => "malformed",
+            Self::Unmapped => "unmapped",
+            Self::Untranslatable => "untranslatable",
+            Self::Policy => "policy",
+            Self::RateLimited => "rate_limited",
+            Self::Multicast => "multicast",
+            Self::Broadcast => "broadcast",
+            Self::QueueFull => "queue_full",
+            Self::LeasePending => "lease_pending",
+            Self::NoPort => "no_port",
+        }
+    }
+}
+
+impl QueueCounters {
+    /// Count a dropped packet
+    pub fn record_drop(&self, reason: DropReason) {
+        self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        self.drops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a consistent-enough snapshot of the counters for reporting
+    pub fn snapshot(&self) -> serde_json::Value {
+        let drops: serde_json::Map<_, _> = DropReason::ALL
+            .into_iter()
+            .map(|reason| {
+                (
+                    reason.name().to_string(),
+                    self.drops[reason as usize].load(Ordering::Relaxed).into(),
+                )
+            })
+            .collect();
+        serde_json::json!({
+            "packets_received": self.packets_received.load(Ordering::Relaxed),
+            "packets_sent": self.packets_sent.load(Ordering::Relaxed),
+            "packets_dropped": self.packets_dropped.load(Ordering::Relaxed),
+            "tcp_options_flagged": self.tcp_options_flagged.load(Ordering::Relaxed),
+            "tcp_options_stripped": self.tcp_options_stripped.load(Ordering::Relaxed),
+            "drops": drops,
+        })
+    }
+}
+
+/// Packet and byte counters for each mapped IPv4 pool address
+#[derive(Debug, Default)]
+pub struct MappingTraffic {
+    counters: Mutex<Counters>,
+}
+
+/// Everything guarded by the `MappingTraffic` lock
+#[derive(Debug, Default)]
+struct Counters {
+    mappings: HashMap<Ipv4Addr, TrafficCount>,
+    /// Traffic across all mappings since startup, including ones that have since expired
+    total: TrafficCount,
+}
+
+/// Traffic seen by a single mapping
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct TrafficCount {
+    /// Packets from the IPv6 client, towards the IPv4 internet
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    /// Packets from the IPv4 internet, towards the IPv6 client
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    /// Transport protocols seen in either direction (see `TrafficCount::TCP` and friends)
+    #[serde(skip)]
+    pub protocols: u8,
+}
+
+impl TrafficCount {
+    pub const TCP: u8 = 1 << 0;
+    pub const UDP: u8 = 1 << 1;
+    pub const ICMP: u8 = 1 << 2;
+
+    /// Get the number of packets in both directions
+    pub fn packets(&self) -> u64 {
+        self.packets_out + self.packets_in
+    }
+
+    /// Get the number of bytes in both directions
+    pub fn bytes(&self) -> u64 {
+        self.bytes_out + self.bytes_in
+    }
+
+    /// Get the names of the transport protocols seen
+    pub fn protocol_names(&self) -> Vec<&'static str> {
+        [(Self::TCP, "tcp"), (Self::UDP, "udp"), (Self::ICMP, "icmp")]
+            .into_iter()
+            .filter(|(bit, _)| self.protocols & bit != 0)
+            .map(|(_, name)| name)
+            .collect()
+    }
+}
+
+impl MappingTraffic {
+    /// Account a translated packet against the pool address it was translated to or from
+    pub fn record(&self, input: &[u8], output: &[u8]) {
+        let (ipv4_packet, outbound) = match get_layer_3_proto(output) {
+            Some(4) => (output, true),
+            Some(6) => (input, false),
+            _ => return,
+        };
+        let (source, destination) = get_ipv4_src_dst(ipv4_packet);
+        let pool_address = if outbound { source } else { destination };
+        let protocol = match ipv4_packet.get(9) {
+            Some(6) => TrafficCount::TCP,
+            Some(17) => TrafficCount::UDP,
+            Some(1) => TrafficCount::ICMP,
+            _ => 0,
+        };
+        let mut counters = self.counters.lock().unwrap();
+        let Counters { mappings, total } = &mut *counters;
+        for count in [mappings.entry(pool_address).or_default(), total] {
+            count.protocols |= protocol;
+            if outbound {
+                count.packets_out += 1;
+                count.bytes_out += input.len() as u64;
+            } else {
+                count.packets_in += 1;
+                count.bytes_in += input.len() as u64;
+            }
+        }
+    }
+
+    /// Get the traffic seen across all mappings since startup
+    pub fn total(&self) -> TrafficCount {
+        self.counters.lock().unwrap().total
+    }
+
+    /// Get the traffic seen by a pool address
+    pub fn get(&self, ipv4: Ipv4Addr) -> TrafficCount {
+        self.counters
+            .lock()
+            .unwrap()
+            .mappings
+            .get(&ipv4)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Forget the counters of every address that is no longer mapped
+    pub fn retain(&self, is_mapped: impl Fn(Ipv4Addr) -> bool) {
+        self.counters
+            .lock()
+            .unwrap()
+            .mappings
+            .retain(|ipv4, _| is_mapped(*ipv4));
+    }
+}
+
+/// Keep per-mapping prometheus counters in step with the traffic seen by each mapping
+pub async fn export_mapping_metrics(
+    prefix_tables: Arc<PrefixTables>,
+    traffic: Arc<MappingTraffic>,
+) {
+    let mut exported = HashSet::new();
+    let mut interval = tokio::time::interval(MAPPING_METRICS_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        // Copy out the mappings so the tables aren't held while updating metrics
+        let mappings: Vec<_> = prefix_tables
+            .tables()
+            .flat_map(|table| {
+                table
+                    .lock()
+                    .unwrap()
+                    .mappings()
+                    .map(|(ipv4, ipv6, _)| (ipv4, ipv6.to_string(), traffic.get(ipv4)))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let mut current = HashSet::new();
+        let mut mapped = HashSet::new();
+        for (address, ipv6, count) in mappings {
+            mapped.insert(address);
+            let ipv4 = address.to_string();
+            for (direction, packets, bytes) in [
+                (DIRECTION_OUTBOUND, count.packets_out, count.bytes_out),
+                (DIRECTION_INBOUND, count.packets_in, count.bytes_in),
+            ] {
+                let labels = [ipv4.as_str(), ipv6.as_str(), direction];
+                for (metric, value) in [
+                    (&*MAPPING_PACKET_COUNTER, packets),
+                    (&*MAPPING_BYTE_COUNTER, bytes),
+                ] {
+                    // Counters start over when an address is mapped again
+                    let counter = metric.with_label_values(&labels);
+                    if value < counter.get() {
+                        counter.reset();
+                    }
+                    counter.inc_by(value - counter.get());
+                }
+            }
+            current.insert((ipv4, ipv6));
+        }
+
+        // Stop reporting mappings that have expired
+        for (ipv4, ipv6) in exported.difference(&current) {
+            for direction in [DIRECTION_OUTBOUND, DIRECTION_INBOUND] {
+                let labels = [ipv4.as_str(), ipv6.as_str(), direction];
+                let _ = MAPPING_PACKET_COUNTER.remove_label_values(&labels);
+                let _ = MAPPING_BYTE_COUNTER.remove_label_values(&labels);
+            }
+        }
+        traffic.retain(|ipv4| mapped.contains(&ipv4));
+        exported = current;
+    }
+}
+
+/// Account a translated packet against the subscriber (IPv6 prefix) it was sent by or to
+pub fn record_subscriber_traffic(input: &[u8], output: &[u8], subscriber_prefix_len: u8) {
+    let (protocol, ipv6) = match get_layer_3_proto(input) {
+        Some(6) => (PROTOCOL_IPV6, get_ipv6_src_dst(input).0),
+        Some(4) => (PROTOCOL_IPV4, get_ipv6_src_dst(output).1),
+        _ => return,
+    };
+    let subscriber = Ipv6Net::new(ipv6, subscriber_prefix_len)
+        .unwrap()
+        .trunc()
+        .to_string();
+    SUBSCRIBER_PACKET_COUNTER
+        .with_label_values(&[&subscriber, protocol])
+        .inc();
+    SUBSCRIBER_BYTE_COUNTER
+        .with_label_values(&[&subscriber, protocol])
+        .inc_by(input.len() as u64);
+}
+
+/// Record the size of a translated packet, by direction and the transport protocol it arrived with
+pub fn record_packet_size(input: &[u8], output: &[u8]) {
+    let (direction, protocol) = match get_layer_3_proto(input) {
+        Some(6) => (DIRECTION_OUTBOUND, input[6]),
+        Some(4) => (DIRECTION_INBOUND, input[9]),
+        _ => return,
+    };
+    let protocol = match protocol {
+        1 => PROTOCOL_ICMP,
+        6 => PROTOCOL_TCP,
+        17 => PROTOCOL_UDP,
+        58 => PROTOCOL_ICMPV6,
+        _ => PROTOCOL_OTHER,
+    };
+    PACKET_SIZE_BYTES
+        .with_label_values(&[direction, protocol])
+        .observe(output.len() as f64);
+}
+
+/// Account a translated packet against the tenant it was sent by or to
+pub fn record_tenant_traffic(input: &[u8], output: &[u8], prefix_tables: &PrefixTables) {
+    let (direction, ipv6) = match get_layer_3_proto(input) {
+        Some(6) => (DIRECTION_OUTBOUND, get_ipv6_src_dst(input).0),
+        Some(4) => (DIRECTION_INBOUND, get_ipv6_src_dst(output).1),
+        _ => return,
+    };
+    if let Some(tenant) = prefix_tables.tenant_of(ipv6) {
+        TENANT_PACKET_COUNTER
+            .with_label_values(&[&tenant.name, direction])
+            .inc();
+        TENANT_BYTE_COUNTER
+            .with_label_values(&[&tenant.name, direction])
+            .inc_by(input.len() as u64);
+    }
+}
+
+/// Count a packet dropped because the tenant of an IPv6 source reached one of its limits
+pub fn record_tenant_limited(prefix_tables: &PrefixTables, source: Ipv6Addr, limit: &str) {
+    if let Some(tenant) = prefix_tables.tenant_of(source) {
+        TENANT_LIMITED
+            .with_label_values(&[&tenant.name, limit])
+            .inc();
+    }
+}
+
+/// Keep the per-tenant mapping and pool size gauges up to date
+pub async fn export_tenant_metrics(prefix_tables: Arc<PrefixTables>) {
+    let mut interval = tokio::time::interval(MAPPING_METRICS_INTERVAL);
+    loop {
+        interval.tick().await;
+        for tenant in prefix_tables.tenants() {
+            let Some(table) = prefix_tables.table_for_prefix(tenant.prefix) else {
+                continue;
+            };
+            let (mappings, pool_size) = {
+                let table = table.lock().unwrap();
+                (table.len(), table.pool_size())
+            };
+            TENANT_MAPPINGS
+                .with_label_values(&[&tenant.name])
+                .set(mappings as i64);
+            TENANT_POOL_ADDRESSES
+                .with_label_values(&[&tenant.name])
+                .set(pool_size as i64);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! D-Bus interface for controlling a CLAT
+//!
+//! Exposes the CLAT's state and PLAT prefix on the system bus, and lets desktop applets and
+//! NetworkManager dispatcher scripts turn translation on and off.
+
+use cfg_if::cfg_if;
+use ipnet::Ipv6Net;
+use std::sync::{atomic::AtomicBool, Arc, RwLock};
+
+/// Well-known name to claim on the system bus
+pub const BUS_NAME: &str = "io.github.ewpratten.Protomask";
+
+/// Path of the CLAT object
+pub const OBJECT_PATH: &str = "/io/github/ewpratten/Protomask/Clat";
+
+/// Everything exposed over D-Bus
+pub struct ClatStatus {
+    pub interface: String,
+    /// May change at runtime, if the CLAT follows the network's NAT64 prefix
+    pub plat_prefix: Arc<RwLock<Ipv6Net>>,
+    pub customer_pool: Vec<String>,
+    /// Packets are only translated while this is set
+    pub enabled: Arc<AtomicBool>,
+}
+
+cfg_if! {
+    if #[cfg(feature = "dbus")] {
+        use std::sync::atomic::Ordering;
+        use zbus::{dbus_interface, ConnectionBuilder, SignalContext};
+
+        /// Serve the CLAT interface on the system bus until the process exits
+        pub async fn serve_dbus(status: ClatStatus) {
+            match connect(status).await {
+                Ok(_connection) => {
+                    log::info!("Serving CLAT status on the system bus as {}", BUS_NAME);
+                    std::future::pending::<()>().await;
+                }
+                Err(error) => log::error!("Failed to register on the system bus: {}", error),
+            }
+        }
+
+        /// Claim our bus name and publish the CLAT object
+        async fn connect(status: ClatStatus) -> zbus::Result<zbus::Connection> {
+            ConnectionBuilder::system()?
+                .name(BUS_NAME)?
+                .serve_at(OBJECT_PATH, ClatInterface { status })?
+                .build()
+                .await
+        }
+
+        /// The `io.github.ewpratten.Protomask.Clat1` interface
+        struct ClatInterface {
+            status: ClatStatus,
+        }
+
+        impl ClatInterface {
+            /// Turn translation on or off, notifying listeners if anything changed
+            async fn set_enabled(
+                &self,
+                enabled: bool,
+                context: &SignalContext<'_>,
+            ) -> zbus::fdo::Result<()> {
+                if self.status.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+                    log::info!(
+                        "CLAT {} over D-Bus",
+                        if enabled { "enabled" } else { "disabled" }
+                    );
+                    self.state_changed(context).await?;
+                }
+                Ok(())
+            }
+        }
+
+        #[dbus_interface(name = "io.github.ewpratten.Protomask.Clat1")]
+        impl ClatInterface {
+            /// Start translating packets
+            async fn enable(
+                &self,
+                #[zbus(signal_context)] context: SignalContext<'_>,
+            ) -> zbus::fdo::Result<()> {
+                self.set_enabled(true, &context).await
+            }
+
+            /// Stop translating packets. IPv4 traffic is dropped until the CLAT is enabled again.
+            async fn disable(
+                &self,
+                #[zbus(signal_context)] context: SignalContext<'_>,
+            ) -> zbus::fdo::Result<()> {
+                self.set_enabled(false, &context).await
+            }
+
+            /// Either `enabled` or `disabled`
+            #[dbus_interface(property)]
+            fn state(&self) -> &str {
+                if self.status.enabled.load(Ordering::Relaxed) {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            }
+
+            /// Prefix that IPv4 addresses are embedded in on their way to the PLAT
+            #[dbus_interface(property)]
+            fn plat_prefix(&self) -> String {
+                self.status.plat_prefix.read().unwrap().to_string()
+            }
+
+            /// Name of the CLAT's TUN interface
+            #[dbus_interface(property)]
+            fn interface(&self) -> &str {
+                &self.status.interface
+            }
+
+            /// Customer-side IPv4 prefixes being translated
+            #[dbus_interface(property)]
+            fn customer_pool(&self) -> Vec<String> {
+                self.status.customer_pool.clone()
+            }
+        }
+    } else {
+        /// D-Bus is not available in this build
+        pub async fn serve_dbus(_status: ClatStatus) {
+            log::warn!("D-Bus is enabled, but this build of protomask does not support it. Rebuild with the `dbus` feature to enable it.");
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! DNS proxy for NAT46 clients (DNS46)
+//!
+//! IPv4-only clients can't use the AAAA records of IPv6-only servers. The proxy forwards queries to a resolver, and
+//! when an A query comes back without any addresses, asks for the name's AAAA records instead. Each IPv6 address is
+//! mapped to an IPv4 address from the server pool (reusing an existing mapping if there is one), and the client is
+//! answered with A records for the mapped addresses.
+//!
+//! Answers for names with A records of their own are passed through untouched. Synthesized answers are given a TTL
+//! of at most `MAX_TTL`, so that clients come back for a fresh answer well before a mapping could expire.
+
+use fast_nat::CrossProtocolNetworkAddressTableWithIpv4Pool;
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+/// How long to wait for the upstream resolver
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest DNS message we handle over UDP (RFC6891 recommends EDNS buffers of around this size)
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Length of a DNS message header
+const HEADER_LEN: usize = 12;
+
+/// Record type of an A record
+const TYPE_A: u16 = 1;
+
+/// Record type of an AAAA record
+const TYPE_AAAA: u16 = 28;
+
+/// Longest TTL given to a synthesized A record, in seconds
+const MAX_TTL: u32 = 300;
+
+/// Answer DNS queries on `bind`, forwarding them to `upstream` and synthesizing A records for IPv6-only names
+pub async fn serve_dns46(
+    bind: SocketAddr,
+    upstream: SocketAddr,
+    table: Arc<Mutex<CrossProtocolNetworkAddressTableWithIpv4Pool>>,
+) {
+    let socket = match UdpSocket::bind(bind).await {
+        Ok(socket) => Arc::new(socket),
+        Err(error) => {
+            log::error!("Failed to bind DNS46 proxy to {}: {}", bind, error);
+            return;
+        }
+    };
+    log::info!("Proxying DNS46 queries on {} to {}", bind, upstream);
+
+    let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(error) => {
+                log::warn!("Failed to receive DNS query: {}", error);
+                continue;
+            }
+        };
+
+        // Each query is forwarded from its own socket, so that answers can't be mixed up
+        let query = buffer[..len].to_vec();
+        let socket = Arc::clone(&socket);
+        let table = Arc::clone(&table);
+        tokio::spawn(async move {
+            match answer(&query, upstream, &table).await {
+                Ok(response) => {
+                    if let Err(error) = socket.send_to(&response, client).await {
+                        log::debug!("Failed to answer DNS query from {}: {}", client, error);
+                    }
+                }
+                Err(error) => log::debug!("Failed to forward DNS query from {}: {}", client, error),
+            }
+        });
+    }
+}
+
+/// Get the response to a query, synthesizing A records if the name only has AAAA records
+async fn answer(
+    query: &[u8],
+    upstream: SocketAddr,
+    table: &Mutex<CrossProtocolNetworkAddressTableWithIpv4Pool>,
+) -> io::Result<Vec<u8>> {
+    let response = forward(query, upstream).await?;
+    let Some(question_end) = single_question_end(query, TYPE_A) else {
+        return Ok(response);
+    };
+    if !records(&response, TYPE_A).is_some_and(|records| records.is_empty()) {
+        return Ok(response);
+    }
+
+    // Ask for the name's IPv6 addresses instead
+    let mut aaaa_query = query.to_vec();
+    aaaa_query[question_end - 4..question_end - 2].copy_from_slice(&TYPE_AAAA.to_be_bytes());
+    let aaaa_response = forward(&aaaa_query, upstream).await?;
+    let aaaa_records: Vec<_> = records(&aaaa_response, TYPE_AAAA)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(data, ttl)| Some((Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?), ttl)))
+        .collect();
+
+    // Map each of them into the server pool
+    let mut mapped = Vec::new();
+    {
+        let mut table = table.lock().unwrap();
+        for (ipv6, ttl) in aaaa_records {
+            match table.get_or_create_ipv4(&ipv6) {
+                Ok(ipv4) => mapped.push((ipv4, ttl.min(MAX_TTL))),
+                Err(error) => log::warn!("Failed to map {} for a DNS46 answer: {}", ipv6, error),
+            }
+        }
+    }
+    if mapped.is_empty() {
+        return Ok(response);
+    }
+
+    // Answer the original question with the mapped addresses, pointing each record back at its name
+    let mut synthesized = aaaa_response[..HEADER_LEN].to_vec();
+    synthesized[4..6].copy_from_slice(&1u16.to_be_bytes());
+    synthesized[6..8].copy_from_slice(&(mapped.len() as u16).to_be_bytes());
+    synthesized[8..12].fill(0);
+    synthesized.extend_from_slice(&query[HEADER_LEN..question_end]);
+    for (ipv4, ttl) in mapped {
+        synthesized.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        synthesized.extend_from_slice(&TYPE_A.to_be_bytes());
+        synthesized.extend_from_slice(&1u16.to_be_bytes());
+        synthesized.extend_from_slice(&ttl.to_be_bytes());
+        synthesized.extend_from_slice(&4u16.to_be_bytes());
+        synthesized.extend_from_slice(&ipv4.octets());
+    }
+    Ok(synthesized)
+}
+
+/// Send a query to the upstream resolver and wait for its response
+async fn forward(query: &[u8], upstream: SocketAddr) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(match upstream {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    })
+    .await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+    loop {
+        let len = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buffer))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        // Ignore anything that isn't the response to our query
+        if len >= HEADER_LEN && query.len() >= 2 && buffer[..2] == query[..2] {
+            buffer.truncate(len);
+            return Ok(buffer);
+        }
+    }
+}
+
+/// Get the offset just past the question of a query asking a single question of the given type in class IN
+fn single_question_end(query: &[u8], record_type: u16) -> Option<usize> {
+    if query.len() < HEADER_LEN || query[4..6] != [0, 1] {
+        return None;
+    }
+    let name_end = skip_name(query, HEADER_LEN)?;
+    let question = query.get(name_end..name_end + 4)?;
+    (question[..2] == record_type.to_be_bytes() && question[2..] == [0, 1]).then_some(name_end + 4)
+}
+
+/// Get the data of every record of a type in a successful response, along with its TTL. Returns `None` if the
+/// response was not successful.
+fn records(response: &[u8], record_type: u16) -> Option<Vec<(&[u8], u32)>> {
+    // Only look at answers without errors
+    if response.len() < HEADER_LEN || response[3] & 0x0f != 0 {
+        return None;
+    }
+    let count = |offset: usize| u16::from_be_bytes([response[offset], response[offset + 1]]);
+    let (questions, answers) = (count(4), count(6));
+
+    // Skip over the questions
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(response, offset)? + 4;
+    }
+
+    // Collect the matching answers
+    let mut found = Vec::new();
+    for _ in 0..answers {
+        let name_end = skip_name(response, offset)?;
+        let header = response.get(name_end..name_end + 10)?;
+        let this_type = u16::from_be_bytes([header[0], header[1]]);
+        let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let data_len = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        let data = response.get(name_end + 10..name_end + 10 + data_len)?;
+        offset = name_end + 10 + data_len;
+
+        if this_type == record_type {
+            found.push((data, ttl));
+        }
+    }
+    Some(found)
+}
+
+/// Get the offset just past a (possibly compressed) name
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            // The root label ends the name
+            0 => return Some(offset + 1),
+            // A pointer to the rest of the name ends it too
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + usize::from(len),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! DNS proxy for CLAT clients
+//!
+//! On a network with DNS64, IPv4-only names resolve to AAAA records synthesized inside the PLAT prefix. The proxy
+//! forwards queries to the network's resolver and removes these records from its answers, so clients fall back to
+//! the name's real A records and connect over IPv4 through the CLAT instead.
+//!
+//! DNS64 only synthesizes AAAA records for names that have none of their own, so a synthesized answer never needs to
+//! be mixed with real ones. Answers are cut off at the first synthesized record (anything before it, such as a CNAME
+//! chain, is kept), leaving an empty answer for the name.
+
+use ipnet::Ipv6Net;
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+/// How long to wait for the upstream resolver
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest DNS message we handle over UDP (RFC6891 recommends EDNS buffers of around this size)
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Length of a DNS message header
+const HEADER_LEN: usize = 12;
+
+/// Record type of an AAAA record
+const TYPE_AAAA: u16 = 28;
+
+/// Answer DNS queries on `bind`, forwarding them to `upstream` and removing AAAA records synthesized in `plat_prefix`
+pub async fn serve_dns_proxy(
+    bind: SocketAddr,
+    upstream: SocketAddr,
+    plat_prefix: Arc<RwLock<Ipv6Net>>,
+) {
+    let socket = match UdpSocket::bind(bind).await {
+        Ok(socket) => Arc::new(socket),
+        Err(error) => {
+            log::error!("Failed to bind DNS proxy to {}: {}", bind, error);
+            return;
+        }
+    };
+    log::info!("Proxying DNS queries on {} to {}", bind, upstream);
+
+    let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(error) => {
+                log::warn!("Failed to receive DNS query: {}", error);
+                continue;
+            }
+        };
+
+        // Each query is forwarded from its own socket, so that answers can't be mixed up
+        let query = buffer[..len].to_vec();
+        let socket = Arc::clone(&socket);
+        let plat_prefix = Arc::clone(&plat_prefix);
+        tokio::spawn(async move {
+            match forward(&query, upstream).await {
+                Ok(mut response) => {
+                    let prefix = *plat_prefix.read().unwrap();
+                    if let Some(filtered) = remove_synthesized(&response, prefix) {
+                        log::trace!("Removed synthesized answers for {}", client);
+                        response = filtered;
+                    }
+                    if let Err(error) = socket.send_to(&response, client).await {
+                        log::debug!("Failed to answer DNS query from {}: {}", client, error);
+                    }
+                }
+                Err(error) => log::debug!("Failed to forward DNS query from {}: {}", client, error),
+            }
+        });
+    }
+}
+
+/// Send a query to the upstream resolver and wait for its response
+async fn forward(query: &[u8], upstream: SocketAddr) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(match upstream {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    })
+    .await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+    loop {
+        let len = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buffer))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        // Ignore anything that isn't the response to our query
+        if len >= HEADER_LEN && query.len() >= 2 && buffer[..2] == query[..2] {
+            buffer.truncate(len);
+            return Ok(buffer);
+        }
+    }
+}
+
+/// Remove synthesized AAAA records from a response. Returns `None` if there were none.
+fn remove_synthesized(response: &[u8], plat_prefix: Ipv6Net) -> Option<Vec<u8>> {
+    if response.len() < HEADER_LEN {
+        return None;
+    }
+    let count = |offset: usize| u16::from_be_bytes([response[offset], response[offset + 1]]);
+    let (questions, answers) = (count(4), count(6));
+
+    // Skip over the questions
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(response, offset)? + 4;
+    }
+
+    // Find the first synthesized answer
+    for kept in 0..answers {
+        let record_start = offset;
+        let name_end = skip_name(response, offset)?;
+        let header = response.get(name_end..name_end + 10)?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let data_len = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        let data = response.get(name_end + 10..name_end + 10 + data_len)?;
+        offset = name_end + 10 + data_len;
+
+        if record_type == TYPE_AAAA && data_len == 16 {
+            let address = Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?);
+            if plat_prefix.contains(&address) {
+                // Keep everything before it. Later records may point back into what is kept, but never forwards.
+                let mut filtered = response[..record_start].to_vec();
+                filtered[6..8].copy_from_slice(&kept.to_be_bytes());
+                filtered[8..12].fill(0);
+                return Some(filtered);
+            }
+        }
+    }
+    None
+}
+
+/// Get the offset just past a (possibly compressed) name
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            // The root label ends the name
+            0 => return Some(offset + 1),
+            // A pointer to the rest of the name ends it too
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + usize::from(len),
+        }
+    }
+}
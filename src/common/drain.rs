@@ -0,0 +1,61 @@
+//! Graceful termination
+//!
+//! When a drain timeout is configured, SIGTERM stops the translator from accepting new clients (and reports it as
+//! not ready) while existing mappings keep working. The process exits once every dynamic mapping has expired or
+//! the timeout is reached, whichever comes first.
+
+use super::{nftables, prefix_tables::PrefixTables};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::Instant,
+};
+
+/// How often to check whether draining has finished
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wait for SIGTERM, drain, then exit the process
+pub async fn drain_on_sigterm(prefix_tables: Arc<PrefixTables>, timeout: Duration) {
+    let mut signals = signal(SignalKind::terminate()).unwrap();
+    signals.recv().await;
+
+    log::info!(
+        "Received SIGTERM. Draining for up to {}s",
+        timeout.as_secs()
+    );
+    protomask_metrics::health::set_draining();
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = dynamic_mappings(&prefix_tables);
+        if remaining == 0 {
+            log::info!("All mappings have expired. Exiting");
+            break;
+        }
+        if Instant::now() >= deadline {
+            log::info!(
+                "Drain timeout reached with {} mappings left. Exiting",
+                remaining
+            );
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    nftables::remove_installed();
+    std::process::exit(0);
+}
+
+/// Count the mappings that will eventually expire, pruning any that already have
+fn dynamic_mappings(prefix_tables: &PrefixTables) -> usize {
+    prefix_tables
+        .tables()
+        .map(|table| {
+            let mut table = table.lock().unwrap();
+            table.prune();
+            table
+                .mappings()
+                .filter(|(.., remaining)| remaining.is_some())
+                .count()
+        })
+        .sum()
+}
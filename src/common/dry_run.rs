@@ -0,0 +1,70 @@
+//! Running a capture through a translator's decisions without bringing the translator up
+
+use super::{packet_trace::describe, pcap::PcapReader};
+use std::path::Path;
+
+/// What a translator would do with a packet
+pub enum Outcome {
+    /// Translated into this packet
+    Translated(Vec<u8>),
+    /// Answered by the translator itself with this packet
+    Replied(Vec<u8>),
+    /// Dropped for this reason
+    Dropped(String),
+    /// Dropped for this reason, with this packet sent back to the sender
+    Rejected(String, Vec<u8>),
+}
+
+/// Decide what would become of each packet of a capture with `handle`, printing a tab-separated line for each:
+/// `<packet number> <verdict> <input addresses> <output addresses or drop reason>`. Returns the process exit code.
+///
+/// Packets are handled back to back, regardless of their timestamps.
+pub fn run(input: &Path, mut handle: impl FnMut(&mut Vec<u8>) -> Outcome) -> i32 {
+    let mut reader = match PcapReader::open(input) {
+        Ok(reader) => reader,
+        Err(error) => {
+            log::error!("{}: {}", input.display(), error);
+            return 1;
+        }
+    };
+
+    // Handle every packet, counting the verdicts
+    let (mut translated, mut replied, mut dropped) = (0, 0, 0);
+    for number in 1.. {
+        let mut packet = match reader.next_packet() {
+            Ok(Some((_, packet))) => packet,
+            Ok(None) => break,
+            Err(error) => {
+                log::error!("{}: {}", input.display(), error);
+                return 1;
+            }
+        };
+        let input_description = describe(&packet);
+        let (verdict, result) = match handle(&mut packet) {
+            Outcome::Translated(output) => {
+                translated += 1;
+                ("translated", describe(&output))
+            }
+            Outcome::Replied(reply) => {
+                replied += 1;
+                ("replied", describe(&reply))
+            }
+            Outcome::Dropped(reason) => {
+                dropped += 1;
+                ("dropped", reason)
+            }
+            Outcome::Rejected(reason, reply) => {
+                dropped += 1;
+                ("rejected", format!("{} ({})", reason, describe(&reply)))
+            }
+        };
+        println!("{}\t{}\t{}\t{}", number, verdict, input_description, result);
+    }
+    log::info!(
+        "{} packets translated, {} replied to, and {} dropped",
+        translated,
+        replied,
+        dropped
+    );
+    0
+}
@@ -0,0 +1,198 @@
+//! DSCP remarking of translated packets
+//!
+//! Translation carries the Traffic Class or Type of Service over unchanged, which rarely lines up with the QoS classes
+//! used on the other side of the NAT64. Remarking rules match on the packet as it arrived (its transport protocol,
+//! either of its ports, its source prefix, and its current DSCP), and the first rule that matches sets the DSCP of the
+//! translated packet. Packets that match no rule are left alone.
+
+use ipnet::IpNet;
+use pnet_packet::{
+    ipv4::{self, MutableIpv4Packet},
+    ipv6::MutableIpv6Packet,
+};
+use std::{net::IpAddr, str::FromStr};
+
+/// Highest possible DSCP value
+pub const MAX_DSCP: u8 = 63;
+
+/// Transport protocols a rule can match
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DscpProtocol {
+    Tcp,
+    Udp,
+    /// Either ICMP or ICMPv6
+    Icmp,
+}
+
+impl DscpProtocol {
+    fn matches(self, next_header: u8) -> bool {
+        match self {
+            Self::Tcp => next_header == 6,
+            Self::Udp => next_header == 17,
+            Self::Icmp => next_header == 1 || next_header == 58,
+        }
+    }
+}
+
+/// Sets the DSCP of translated packets matching every one of its conditions
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct DscpRule {
+    /// Transport protocol of the packet
+    #[serde(default)]
+    pub protocol: Option<DscpProtocol>,
+    /// Source or destination port of a TCP or UDP packet
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Prefix the packet's source address is in
+    #[serde(default)]
+    pub source: Option<IpNet>,
+    /// DSCP the packet arrived with
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// DSCP to give the translated packet
+    pub set: u8,
+}
+
+impl DscpRule {
+    /// Check if a rule applies to a packet as it arrived
+    fn matches(&self, packet: &PacketSummary) -> bool {
+        self.protocol
+            .is_none_or(|protocol| protocol.matches(packet.next_header))
+            && self
+                .port
+                .is_none_or(|port| packet.ports.is_some_and(|(a, b)| port == a || port == b))
+            && self
+                .source
+                .is_none_or(|source| source.contains(&packet.source))
+            && self.dscp.is_none_or(|dscp| dscp == packet.dscp)
+    }
+}
+
+impl FromStr for DscpRule {
+    type Err = String;
+
+    /// Parses `[<condition>+...]=<dscp>`, where each condition is one of `protocol:<tcp|udp|icmp>`, `port:<port>`,
+    /// `source:<prefix>`, or `dscp:<dscp>`. Conditions are joined with `+`, since commas separate repeated options
+    /// given through environment variables.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            "Expected a rule in the form [protocol:<tcp|udp|icmp>+port:<port>+source:<prefix>+dscp:<dscp>]=<dscp>"
+                .to_string()
+        };
+        let (conditions, set) = string.rsplit_once('=').ok_or_else(error)?;
+        let mut rule = Self {
+            protocol: None,
+            port: None,
+            source: None,
+            dscp: None,
+            set: set
+                .trim()
+                .parse()
+                .map_err(|err| format!("{}: {}", set.trim(), err))?,
+        };
+        for condition in conditions.split('+').filter(|c| !c.trim().is_empty()) {
+            let (key, value) = condition.split_once(':').ok_or_else(error)?;
+            let value = value.trim();
+            let invalid = |err: &dyn std::fmt::Display| format!("{}: {}", value, err);
+            match key.trim() {
+                "protocol" => {
+                    rule.protocol = Some(match value {
+                        "tcp" => DscpProtocol::Tcp,
+                        "udp" => DscpProtocol::Udp,
+                        "icmp" => DscpProtocol::Icmp,
+                        _ => return Err(error()),
+                    });
+                }
+                "port" => rule.port = Some(value.parse().map_err(|err| invalid(&err))?),
+                "source" => rule.source = Some(value.parse().map_err(|err| invalid(&err))?),
+                "dscp" => rule.dscp = Some(value.parse().map_err(|err| invalid(&err))?),
+                _ => return Err(error()),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+/// The parts of a packet that rules match on
+struct PacketSummary {
+    next_header: u8,
+    ports: Option<(u16, u16)>,
+    source: IpAddr,
+    dscp: u8,
+}
+
+impl PacketSummary {
+    /// Pick out the parts of a validated IPv4 or IPv6 packet that rules match on
+    fn of(packet: &[u8]) -> Option<Self> {
+        let (next_header, transport, source, dscp) = match packet.first()? >> 4 {
+            4 => {
+                let header_len = usize::from(packet[0] & 0x0f) * 4;
+                let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+
+                // Only the first fragment has ports
+                let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+                let transport = (fragment_offset == 0).then_some(header_len);
+                (packet[9], transport, IpAddr::from(source), packet[1] >> 2)
+            }
+            6 => {
+                let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+                let traffic_class = (packet[0] << 4) | (packet[1] >> 4);
+                (
+                    packet[6],
+                    Some(40),
+                    IpAddr::from(source),
+                    traffic_class >> 2,
+                )
+            }
+            _ => return None,
+        };
+        let ports = match (next_header, transport) {
+            (6 | 17, Some(transport)) => packet.get(transport..transport + 4).map(|ports| {
+                (
+                    u16::from_be_bytes([ports[0], ports[1]]),
+                    u16::from_be_bytes([ports[2], ports[3]]),
+                )
+            }),
+            _ => None,
+        };
+        Some(Self {
+            next_header,
+            ports,
+            source,
+            dscp,
+        })
+    }
+}
+
+/// Set the DSCP of a translated packet according to the first rule matching the packet it was translated from.
+/// Returns `true` if the packet was remarked.
+pub fn remark(rules: &[DscpRule], input: &[u8], output: &mut [u8]) -> bool {
+    let Some(summary) = PacketSummary::of(input) else {
+        return false;
+    };
+    let Some(rule) = rules.iter().find(|rule| rule.matches(&summary)) else {
+        return false;
+    };
+    set_dscp(output, rule.set);
+    true
+}
+
+/// Set the DSCP of an IPv4 or IPv6 packet, keeping its ECN bits
+fn set_dscp(packet: &mut [u8], dscp: u8) {
+    match packet.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            if let Some(mut ipv4) = MutableIpv4Packet::new(packet) {
+                ipv4.set_dscp(dscp);
+                ipv4.set_checksum(ipv4::checksum(&ipv4.to_immutable()));
+            }
+        }
+        Some(6) => {
+            if let Some(mut ipv6) = MutableIpv6Packet::new(packet) {
+                let ecn = ipv6.get_traffic_class() & 0x03;
+                ipv6.set_traffic_class((dscp << 2) | ecn);
+            }
+        }
+        _ => {}
+    }
+}
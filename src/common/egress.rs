@@ -0,0 +1,145 @@
+//! Egress uplink binding
+//!
+//! On a multi-homed host, translated packets leaving the TUN interface would otherwise follow whichever default route
+//! the main table prefers. When bound to an uplink, the uplink's default routes are copied into a dedicated routing
+//! table, and policy rules send traffic arriving from the TUN interfaces there:
+//!
+//! ```text
+//! <priority>:     from all iif <tun> lookup main suppress_prefixlength 0
+//! <priority + 1>: from all iif <tun> lookup <table>
+//! ```
+//!
+//! The first rule keeps everything the main table routes more specifically (such as the translator's own routes, or
+//! routes towards clients) where it is, so only traffic that would have followed a default route is redirected. The
+//! copied routes are kept in line with the uplink's as it changes (for example, when DHCP renews with a new gateway).
+
+use rtnl::{
+    route::DefaultRoute,
+    rule::{Family, InputInterfaceRule},
+};
+use std::time::Duration;
+
+/// Priority of the first rule. Anything below the main table's rule (32766) is matched before it.
+const RULE_PRIORITY: u32 = 6464;
+
+/// The main routing table (from `<linux/rtnetlink.h>`)
+const MAIN_TABLE: u32 = 254;
+
+/// How long to wait for the network to settle after a change before copying routes again
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Where to send traffic leaving the translator
+pub struct EgressBinding {
+    /// Interfaces translated traffic leaves through
+    pub interfaces: Vec<String>,
+    pub uplink: String,
+    /// Routing table holding the uplink's default routes
+    pub table: u32,
+}
+
+/// Install the rules and routes binding traffic from the translator to its uplink
+pub async fn bind(binding: &EgressBinding) -> Result<(), String> {
+    let rt_handle = rtnl::new_handle().map_err(|error| error.to_string())?;
+    for interface in &binding.interfaces {
+        for family in [Family::Ipv4, Family::Ipv6] {
+            for rule in [
+                InputInterfaceRule {
+                    family,
+                    input_interface: interface.clone(),
+                    table: MAIN_TABLE,
+                    priority: RULE_PRIORITY,
+                    suppress_prefix_len: Some(0),
+                },
+                InputInterfaceRule {
+                    family,
+                    input_interface: interface.clone(),
+                    table: binding.table,
+                    priority: RULE_PRIORITY + 1,
+                    suppress_prefix_len: None,
+                },
+            ] {
+                rtnl::rule::rule_replace(&rule, &rt_handle)
+                    .await
+                    .map_err(|error| format!("Failed to add rule for {}: {}", interface, error))?;
+            }
+        }
+        log::info!(
+            "Sending traffic from {} out of {} (table {})",
+            interface,
+            binding.uplink,
+            binding.table
+        );
+    }
+    copy_default_routes(&binding.uplink, binding.table).await
+}
+
+/// Keep the table's routes in line with the uplink's default routes for as long as the process runs
+pub async fn follow(binding: EgressBinding) {
+    let mut watcher = match rtnl::monitor::ChangeWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(error) => {
+            log::warn!(
+                "Can't watch for route changes, so table {} won't follow {}: {}",
+                binding.table,
+                binding.uplink,
+                error
+            );
+            return;
+        }
+    };
+    while watcher.changed().await {
+        // Changes usually come in bursts
+        while let Ok(true) = tokio::time::timeout(SETTLE_TIME, watcher.changed()).await {}
+        if let Err(error) = copy_default_routes(&binding.uplink, binding.table).await {
+            log::warn!("{}", error);
+        }
+    }
+    log::warn!(
+        "Stopped receiving route changes, so table {} no longer follows {}",
+        binding.table,
+        binding.uplink
+    );
+}
+
+/// Make the table's default routes match the uplink's default routes in the main table.
+///
+/// Routes that are already in place are left alone, so that copying doesn't cause more changes to be noticed.
+async fn copy_default_routes(uplink: &str, table: u32) -> Result<(), String> {
+    let rt_handle = rtnl::new_handle().map_err(|error| error.to_string())?;
+    let link_idx = rtnl::link::get_link_index(&rt_handle, uplink)
+        .await
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("{} does not exist", uplink))?;
+    let wanted = rtnl::route::default_routes(&rt_handle, link_idx, MAIN_TABLE)
+        .await
+        .map_err(|error| format!("Failed to read routes of {}: {}", uplink, error))?;
+    let current = rtnl::route::default_routes(&rt_handle, link_idx, table)
+        .await
+        .map_err(|error| format!("Failed to read routes of table {}: {}", table, error))?;
+
+    for route in current.iter().filter(|route| !wanted.contains(route)) {
+        log::debug!("Removing {} from table {}", describe(route), table);
+        rtnl::route::route_del_default(*route, &rt_handle, link_idx, table)
+            .await
+            .map_err(|error| format!("Failed to remove default route: {}", error))?;
+    }
+    for route in wanted.iter().filter(|route| !current.contains(route)) {
+        log::info!(
+            "Routing translated traffic {} out of {}",
+            describe(route),
+            uplink
+        );
+        rtnl::route::route_add_default(*route, &rt_handle, link_idx, table)
+            .await
+            .map_err(|error| format!("Failed to add default route: {}", error))?;
+    }
+    Ok(())
+}
+
+/// Describe a default route like `ip route` does
+fn describe(route: &DefaultRoute) -> String {
+    match route.gateway {
+        Some(gateway) => format!("{} via {}", route.destination, gateway),
+        None => route.destination.to_string(),
+    }
+}
@@ -0,0 +1,195 @@
+//! Active-standby failover
+//!
+//! A standby keeps its TUN interface up and its mapping table warm, but withdraws its routes so that no traffic
+//! reaches it. Promoting it installs the routes and demoting it withdraws them again. Transitions are requested
+//! over the control socket (`protomaskctl promote` and `protomaskctl demote`), which keepalived notify scripts
+//! can call directly.
+//!
+//! Routes are also withdrawn while upstream health probes are failing, even when active, and restored once they pass.
+
+use super::interface;
+use ipnet::IpNet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Mutex;
+
+/// Tracks whether this translator is active, and moves it between active and standby
+pub struct Failover {
+    /// Each of our interfaces, along with the routes towards it. The last one carries the pool routes.
+    interfaces: Mutex<Vec<(String, Vec<IpNet>)>>,
+    mtu: u32,
+    configure_netlink: bool,
+    on_promote: Option<String>,
+    on_demote: Option<String>,
+    /// Whether we are active. Held for the whole of a transition so that transitions never overlap.
+    active: Mutex<bool>,
+    /// Whether upstream health probes are passing. Only changed while holding `active`.
+    healthy: AtomicBool,
+}
+
+impl Failover {
+    /// Track a translator whose routes were installed if and only if `active` is set
+    pub fn new(
+        interfaces: Vec<(String, Vec<IpNet>)>,
+        mtu: u32,
+        configure_netlink: bool,
+        on_promote: Option<String>,
+        on_demote: Option<String>,
+        active: bool,
+    ) -> Self {
+        if !active {
+            log::info!("Starting as a standby. Routes will be installed once promoted");
+            protomask_metrics::health::clear_routes_installed();
+        }
+        Self {
+            interfaces: Mutex::new(interfaces),
+            mtu,
+            configure_netlink,
+            on_promote,
+            on_demote,
+            active: Mutex::new(active),
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    /// Check if we are active
+    pub async fn is_active(&self) -> bool {
+        *self.active.lock().await
+    }
+
+    /// Either `active` or `standby`
+    pub async fn role(&self) -> &'static str {
+        if self.is_active().await {
+            "active"
+        } else {
+            "standby"
+        }
+    }
+
+    /// Install routes and start translating. Does nothing if already active.
+    pub async fn promote(&self) -> Result<(), String> {
+        self.transition(true).await
+    }
+
+    /// Withdraw routes, keeping the mapping table. Does nothing if already on standby.
+    pub async fn demote(&self) -> Result<(), String> {
+        self.transition(false).await
+    }
+
+    /// Withdraw routes while upstream health probes are failing, and restore them once they pass (if active)
+    pub async fn set_upstream_healthy(&self, healthy: bool) -> Result<(), String> {
+        let active = self.active.lock().await;
+        if self.healthy.load(Ordering::Relaxed) == healthy {
+            return Ok(());
+        }
+        if *active {
+            self.move_routes(healthy).await?;
+        }
+        self.healthy.store(healthy, Ordering::Relaxed);
+        protomask_metrics::health::set_upstream_healthy(healthy);
+        log::info!(
+            "Upstream is {}{}",
+            if healthy { "healthy" } else { "unhealthy" },
+            match (*active, healthy) {
+                (false, _) => "",
+                (true, true) => ". Routes restored",
+                (true, false) => ". Routes withdrawn",
+            }
+        );
+        Ok(())
+    }
+
+    /// Check if routes should be installed in a role, given the health of the upstream
+    fn is_routed(&self, active: bool) -> bool {
+        active && self.healthy.load(Ordering::Relaxed)
+    }
+
+    async fn transition(&self, active: bool) -> Result<(), String> {
+        let mut current = self.active.lock().await;
+        if *current == active {
+            return Ok(());
+        }
+
+        // Move our routes, unless the upstream is keeping them withdrawn
+        if self.is_routed(active) != self.is_routed(*current) {
+            self.move_routes(active).await?;
+        }
+        *current = active;
+        log::info!(
+            "{} is now {}",
+            self.interfaces
+                .lock()
+                .await
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(" and "),
+            if active { "active" } else { "on standby" }
+        );
+        Ok(())
+    }
+
+    /// Install or withdraw all of our routes, then let the rest of the system know
+    async fn move_routes(&self, install: bool) -> Result<(), String> {
+        let interfaces = self.interfaces.lock().await;
+        if self.configure_netlink {
+            for (name, routes) in interfaces.iter() {
+                interface::set_routes(name, routes, self.mtu, None, install).await?;
+            }
+        }
+        if install {
+            protomask_metrics::health::set_routes_installed();
+        } else {
+            protomask_metrics::health::clear_routes_installed();
+        }
+        drop(interfaces);
+
+        let hook = if install {
+            &self.on_promote
+        } else {
+            &self.on_demote
+        };
+        if let Some(command) = hook {
+            run_hook(command).await;
+        }
+        Ok(())
+    }
+
+    /// Route a new pool prefix to us. It is only installed right away if we are active.
+    pub async fn add_pool_route(&self, route: IpNet) -> Result<(), String> {
+        let active = self.active.lock().await;
+        let mut interfaces = self.interfaces.lock().await;
+        let (name, routes) = interfaces.last_mut().unwrap();
+        if self.is_routed(*active) && self.configure_netlink {
+            interface::set_routes(name, &[route], self.mtu, None, true).await?;
+        }
+        routes.push(route);
+        Ok(())
+    }
+
+    /// Stop routing a pool prefix to us
+    pub async fn remove_pool_route(&self, route: IpNet) -> Result<(), String> {
+        let active = self.active.lock().await;
+        let mut interfaces = self.interfaces.lock().await;
+        let (name, routes) = interfaces.last_mut().unwrap();
+        if self.is_routed(*active) && self.configure_netlink {
+            interface::set_routes(name, &[route], self.mtu, None, false).await?;
+        }
+        routes.retain(|existing| *existing != route);
+        Ok(())
+    }
+}
+
+/// Run a hook command through the shell
+async fn run_hook(command: &str) {
+    log::debug!("Running hook: {}", command);
+    match tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .await
+    {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("Hook `{}` exited with {}", command, status),
+        Err(error) => log::warn!("Failed to run hook `{}`: {}", command, error),
+    }
+}
@@ -0,0 +1,280 @@
+//! gRPC control API
+//!
+//! Lets external provisioning systems manage mappings, read pool statistics, and reload the config
+//! of a running NAT64. The service is defined in `proto/control.proto`.
+
+// Every handler returns `tonic::Status`, which is large by design
+#![allow(clippy::result_large_err)]
+
+use crate::args::protomask::ConfigReloader;
+use cfg_if::cfg_if;
+use std::{net::SocketAddr, sync::Arc};
+
+use super::state_dump::StateDumpSource;
+
+cfg_if! {
+    if #[cfg(feature = "grpc")] {
+        use super::prefix_tables::AddressTable;
+        use crate::args::protomask::StaticMap;
+        use proto::{
+            control_server::{Control, ControlServer},
+            mapping_address::Address,
+            CreateMappingRequest, GetPoolStatsRequest, ListMappingsRequest, ListMappingsResponse,
+            Mapping, MappingAddress, PoolStats, ReloadConfigRequest, ReloadConfigResponse,
+        };
+        use std::net::{Ipv4Addr, Ipv6Addr};
+        use tonic::{Request, Response, Status};
+
+        mod proto {
+            tonic::include_proto!("protomask.control");
+        }
+
+        /// Start serving the gRPC control API
+        pub fn start_grpc_server(
+            bind_addr: SocketAddr,
+            state: Arc<StateDumpSource>,
+            reloader: Option<ConfigReloader>,
+        ) {
+            log::info!("Starting gRPC control API on {}", bind_addr);
+            let service = ControlServer::new(ControlService { state, reloader });
+            tokio::spawn(async move {
+                tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve(bind_addr)
+                    .await
+                    .unwrap();
+            });
+        }
+
+        /// Implementation of the `Control` service
+        struct ControlService {
+            state: Arc<StateDumpSource>,
+            reloader: Option<ConfigReloader>,
+        }
+
+        impl ControlService {
+            /// Build the API representation of a mapping
+            fn mapping(&self, table: &AddressTable, ipv4: Ipv4Addr) -> Option<Mapping> {
+                let table = table.lock().unwrap();
+                let (_, ipv6, remaining) = table.mappings().find(|(other, ..)| *other == ipv4)?;
+                let traffic = self.state.traffic.get(ipv4);
+                Some(Mapping {
+                    ipv4: ipv4.to_string(),
+                    ipv6: ipv6.to_string(),
+                    expires_in_secs: remaining.map(|remaining| remaining.as_secs()),
+                    packets: traffic.packets(),
+                    bytes: traffic.bytes(),
+                })
+            }
+
+            /// Find the table and IPv4 address of the mapping identified by an address
+            fn find(&self, address: Option<Address>) -> Result<(&AddressTable, Ipv4Addr), Status> {
+                let found = match address {
+                    Some(Address::Ipv4(ipv4)) => {
+                        let ipv4: Ipv4Addr = parse_address(&ipv4)?;
+                        self.state
+                            .prefix_tables
+                            .table_for_ipv4(ipv4)
+                            .filter(|table| table.lock().unwrap().get_ipv6(&ipv4).is_some())
+                            .map(|table| (table, ipv4))
+                    }
+                    Some(Address::Ipv6(ipv6)) => {
+                        let ipv6: Ipv6Addr = parse_address(&ipv6)?;
+                        self.find_ipv6(ipv6)
+                    }
+                    None => return Err(Status::invalid_argument("No address given")),
+                };
+                found.ok_or_else(|| Status::not_found("No such mapping"))
+            }
+
+            /// Find the table and IPv4 address that an IPv6 address is mapped to
+            fn find_ipv6(&self, ipv6: Ipv6Addr) -> Option<(&AddressTable, Ipv4Addr)> {
+                self.state.prefix_tables.tables().find_map(|table| {
+                    let ipv4 = table.lock().unwrap().get_ipv4(&ipv6)?;
+                    Some((table, ipv4))
+                })
+            }
+
+            /// Add a static mapping, replacing anything either address was previously mapped to
+            fn insert_static(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<(), Status> {
+                let table = self.state.prefix_tables.table_for_ipv4(ipv4).ok_or_else(|| {
+                    Status::invalid_argument(format!("{} is outside of all pools", ipv4))
+                })?;
+                self.state.prefix_tables.remove_conflicting(ipv4, ipv6);
+                table
+                    .lock()
+                    .unwrap()
+                    .insert_static(ipv4, ipv6)
+                    .map_err(|error| Status::internal(error.to_string()))
+            }
+
+            /// Collect every static mapping across all tables
+            fn static_mappings(&self) -> Vec<StaticMap> {
+                self.state
+                    .prefix_tables
+                    .tables()
+                    .flat_map(|table| {
+                        table
+                            .lock()
+                            .unwrap()
+                            .mappings()
+                            .filter(|(.., remaining)| remaining.is_none())
+                            .map(|(ipv4, ipv6, _)| StaticMap { ipv4, ipv6 })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect()
+            }
+        }
+
+        #[tonic::async_trait]
+        impl Control for ControlService {
+            async fn list_mappings(
+                &self,
+                _request: Request<ListMappingsRequest>,
+            ) -> Result<Response<ListMappingsResponse>, Status> {
+                let mappings = self
+                    .state
+                    .prefix_tables
+                    .tables()
+                    .flat_map(|table| {
+                        let ipv4s: Vec<_> =
+                            table.lock().unwrap().mappings().map(|(ipv4, ..)| ipv4).collect();
+                        ipv4s
+                            .into_iter()
+                            .filter_map(|ipv4| self.mapping(table, ipv4))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                Ok(Response::new(ListMappingsResponse { mappings }))
+            }
+
+            async fn get_mapping(
+                &self,
+                request: Request<MappingAddress>,
+            ) -> Result<Response<Mapping>, Status> {
+                let (table, ipv4) = self.find(request.into_inner().address)?;
+                self.mapping(table, ipv4)
+                    .map(Response::new)
+                    .ok_or_else(|| Status::not_found("No such mapping"))
+            }
+
+            async fn create_mapping(
+                &self,
+                request: Request<CreateMappingRequest>,
+            ) -> Result<Response<Mapping>, Status> {
+                let request = request.into_inner();
+                let ipv4: Ipv4Addr = parse_address(&request.ipv4)?;
+                let ipv6: Ipv6Addr = parse_address(&request.ipv6)?;
+
+                // Never silently steal an address from an existing mapping
+                let table = self.state.prefix_tables.table_for_ipv4(ipv4).ok_or_else(|| {
+                    Status::invalid_argument(format!("{} is outside of all pools", ipv4))
+                })?;
+                if table.lock().unwrap().get_ipv6(&ipv4).is_some() || self.find_ipv6(ipv6).is_some()
+                {
+                    return Err(Status::already_exists("Address is already mapped"));
+                }
+
+                self.insert_static(ipv4, ipv6)?;
+                log::info!("Created static mapping {} -> {} via gRPC", ipv6, ipv4);
+                self.mapping(table, ipv4)
+                    .map(Response::new)
+                    .ok_or_else(|| Status::internal("Mapping disappeared after creation"))
+            }
+
+            async fn delete_mapping(
+                &self,
+                request: Request<MappingAddress>,
+            ) -> Result<Response<Mapping>, Status> {
+                let (table, ipv4) = self.find(request.into_inner().address)?;
+                let mapping = self
+                    .mapping(table, ipv4)
+                    .ok_or_else(|| Status::not_found("No such mapping"))?;
+                table.lock().unwrap().remove_ipv4(&ipv4);
+                log::info!("Deleted mapping {} -> {} via gRPC", mapping.ipv6, mapping.ipv4);
+                Ok(Response::new(mapping))
+            }
+
+            async fn get_pool_stats(
+                &self,
+                _request: Request<GetPoolStatsRequest>,
+            ) -> Result<Response<PoolStats>, Status> {
+                let mut stats = PoolStats {
+                    prefixes: self
+                        .state
+                        .prefix_tables
+                        .pools()
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect(),
+                    ..Default::default()
+                };
+                for table in self.state.prefix_tables.tables() {
+                    let table = table.lock().unwrap();
+                    stats.total_addresses += table.pool_size() as u64;
+                    stats.mapped_addresses += table.len() as u64;
+                    stats.static_mappings += table
+                        .mappings()
+                        .filter(|(.., remaining)| remaining.is_none())
+                        .count() as u64;
+                }
+                Ok(Response::new(stats))
+            }
+
+            async fn reload_config(
+                &self,
+                _request: Request<ReloadConfigRequest>,
+            ) -> Result<Response<ReloadConfigResponse>, Status> {
+                let reloader = self.reloader.as_ref().ok_or_else(|| {
+                    Status::failed_precondition("protomask was not started from a config file")
+                })?;
+                let config = reloader.reload().map_err(Status::invalid_argument)?;
+                let mut response = ReloadConfigResponse::default();
+
+                // Drop static mappings that are no longer configured (those from the static mapping file are managed by its watcher)
+                let current = self.static_mappings();
+                let from_file = self.state.file_static_map.lock().unwrap().clone();
+                for mapping in &current {
+                    if !config.static_map.contains(mapping) && !from_file.contains(mapping) {
+                        if let Some(table) = self.state.prefix_tables.table_for_ipv4(mapping.ipv4) {
+                            table.lock().unwrap().remove_ipv4(&mapping.ipv4);
+                            response.static_mappings_removed += 1;
+                        }
+                    }
+                }
+
+                // Add new ones
+                for mapping in &config.static_map {
+                    if !current.contains(mapping) {
+                        self.insert_static(mapping.ipv4, mapping.ipv6)?;
+                        response.static_mappings_added += 1;
+                    }
+                }
+
+                log::info!(
+                    "Reloaded config via gRPC ({} static mappings added, {} removed)",
+                    response.static_mappings_added,
+                    response.static_mappings_removed
+                );
+                self.state.config.lock().unwrap().static_map = config.static_map;
+                Ok(Response::new(response))
+            }
+        }
+
+        /// Parse an address from a request
+        fn parse_address<T: std::str::FromStr>(address: &str) -> Result<T, Status> {
+            address
+                .parse()
+                .map_err(|_| Status::invalid_argument(format!("Invalid address: {}", address)))
+        }
+    } else {
+        /// The gRPC control API is not available in this build
+        pub fn start_grpc_server(
+            _bind_addr: SocketAddr,
+            _state: Arc<StateDumpSource>,
+            _reloader: Option<ConfigReloader>,
+        ) {
+            log::warn!("A gRPC bind address is configured, but this build of protomask does not support gRPC. Rebuild with the `grpc` feature to enable it.");
+        }
+    }
+}
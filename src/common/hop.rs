@@ -0,0 +1,134 @@
+//! Router hop behaviour
+//!
+//! When a translator address is configured, the NAT64 behaves like any other router on the path: it decrements the
+//! TTL (or hop limit) of every packet it translates, sends Time Exceeded errors from the translator address once that
+//! runs out, and answers pings and traceroutes addressed to itself. Traceroutes through the NAT64 then show it as a
+//! hop instead of a silent gap. On the IPv6 side, the translator address appears embedded in the translation prefix.
+//!
+//! Time Exceeded errors are subject to an [`ErrorRateLimiter`], so that they can't be used for reflection.
+
+use super::{
+    icmp_rate_limit::ErrorRateLimiter,
+    packet_handler::{get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto},
+    prefix_tables::PrefixTables,
+};
+use interproto::{
+    error::Result,
+    protocols::icmp::generate::{answer_ipv4, answer_ipv6, time_exceeded_ipv4, time_exceeded_ipv6},
+};
+use rfc6052::embed_ipv4_addr_unchecked;
+use std::net::Ipv4Addr;
+
+/// What to do with a packet
+pub enum Hop {
+    /// Translate the packet as usual
+    Forward,
+    /// Send this back out of the interface instead of translating the packet
+    Reply(Vec<u8>),
+    /// Drop the packet
+    Drop,
+    /// Drop the packet, since the error it would have caused was rate limited
+    RateLimited,
+}
+
+impl From<Option<Vec<u8>>> for Hop {
+    fn from(reply: Option<Vec<u8>>) -> Self {
+        reply.map_or(Self::Drop, Self::Reply)
+    }
+}
+
+/// Decide what to do with a packet before translating it, decrementing its TTL or hop limit if it is to be forwarded
+pub fn handle(
+    packet: &mut [u8],
+    translator_address: Ipv4Addr,
+    prefix_tables: &PrefixTables,
+    error_limiter: &ErrorRateLimiter,
+) -> Result<Hop> {
+    match get_layer_3_proto(packet) {
+        // Malformed packets are left for the translator to report
+        Some(4) if packet.len() >= 20 => {
+            let (source, destination) = get_ipv4_src_dst(packet);
+            if destination == translator_address {
+                return answer_ipv4(packet).map(Hop::from);
+            }
+            if packet[8] <= 1 {
+                if !error_limiter.allow(source.into()) {
+                    return Ok(Hop::RateLimited);
+                }
+                return time_exceeded_ipv4(packet, translator_address).map(Hop::from);
+            }
+
+            // The header is quoted in ICMP errors about the packet, so its checksum must stay valid
+            decrement_ttl(packet);
+            Ok(Hop::Forward)
+        }
+        Some(6) if packet.len() >= 40 => {
+            let (source, destination) = get_ipv6_src_dst(packet);
+            let Some((prefix, _)) = prefix_tables.match_ipv6(source, destination) else {
+                return Ok(Hop::Forward);
+            };
+            let local_address = unsafe { embed_ipv4_addr_unchecked(translator_address, prefix) };
+            if destination == local_address {
+                return answer_ipv6(packet).map(Hop::from);
+            }
+            if packet[7] <= 1 {
+                if !error_limiter.allow(source.into()) {
+                    return Ok(Hop::RateLimited);
+                }
+                return time_exceeded_ipv6(packet, local_address).map(Hop::from);
+            }
+
+            packet[7] -= 1;
+            Ok(Hop::Forward)
+        }
+        _ => Ok(Hop::Forward),
+    }
+}
+
+/// Decrement the TTL of an IPv4 packet, updating its header checksum incrementally (RFC 1624)
+fn decrement_ttl(packet: &mut [u8]) {
+    let old = u16::from_be_bytes([packet[8], packet[9]]);
+    packet[8] -= 1;
+    let new = u16::from_be_bytes([packet[8], packet[9]]);
+
+    // HC' = ~(~HC + ~m + m')
+    let checksum = u16::from_be_bytes([packet[10], packet[11]]);
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sum the 16-bit words of a header, which gives `0xffff` when its checksum is valid
+    fn ones_complement_sum(header: &[u8]) -> u16 {
+        let mut sum: u32 = header
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    #[test]
+    fn test_decrement_ttl_keeps_checksum_valid() {
+        for ttl in [2, 64, 128, 255] {
+            let mut header = [
+                0x45, 0x00, 0x00, 0x54, 0x12, 0x34, 0x40, 0x00, ttl, 0x01, 0x00, 0x00, 192, 0, 2,
+                1, 198, 51, 100, 1,
+            ];
+            let checksum = !ones_complement_sum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            decrement_ttl(&mut header);
+            assert_eq!(header[8], ttl - 1);
+            assert_eq!(ones_complement_sum(&header), 0xffff);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! HTTP servers shared by all translators, and a minimal client for calling out to other systems
+
+use super::runtime::export_runtime_metrics;
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// Start the prometheus and health check servers, if configured
+pub fn start_servers(prom_bind_addr: Option<SocketAddr>, health_bind_addr: Option<SocketAddr>) {
+    // If we are configured to serve prometheus metrics, start the server
+    if let Some(bind_addr) = prom_bind_addr {
+        log::info!("Starting prometheus server on {}", bind_addr);
+        tokio::spawn(protomask_metrics::http::serve_metrics(bind_addr));
+        tokio::spawn(export_runtime_metrics());
+    }
+
+    // If we are configured to serve health checks separately, start that server too
+    if let Some(bind_addr) = health_bind_addr {
+        log::info!("Starting health check server on {}", bind_addr);
+        tokio::spawn(protomask_metrics::http::serve_health(bind_addr));
+    }
+}
+
+/// A plain `http://` URL
+#[derive(Debug, Clone)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    /// Path, including any query string
+    pub path: String,
+}
+
+impl HttpUrl {
+    /// Parse a URL. Only plain `http://` URLs are supported.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(if url.starts_with("https://") {
+                "Only plain http:// URLs are supported".to_string()
+            } else {
+                format!("{} is not an http:// URL", url)
+            });
+        };
+        let (authority, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+        let (host, port) = match authority.rsplit_once(':') {
+            // IPv6 literals are bracketed, and may have colons of their own
+            Some((host, port)) if !authority.ends_with(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("{} is not a valid port", port))?,
+            ),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("{} has no host", url));
+        }
+        Ok(Self {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Send a request to a host, returning the status code and body.
+///
+/// `body` is sent along with its content type, if given. Each step of the request gives up after `timeout`.
+pub fn request(
+    url: &HttpUrl,
+    method: &str,
+    path: &str,
+    body: Option<(&str, &[u8])>,
+    timeout: Duration,
+) -> Result<(u16, String), String> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .map_err(|error| error.to_string())?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", url.host))?;
+    let mut stream =
+        TcpStream::connect_timeout(&address, timeout).map_err(|error| error.to_string())?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|()| stream.set_write_timeout(Some(timeout)))
+        .map_err(|error| error.to_string())?;
+
+    // HTTP/1.0 keeps the response simple: no chunked encoding, and the server closes the connection when done
+    let mut head = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: protomask\r\n",
+        method, path, url.host
+    );
+    if let Some((content_type, body)) = body {
+        head.push_str(&format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            content_type,
+            body.len()
+        ));
+    }
+    head.push_str("\r\n");
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|()| stream.write_all(body.map_or(&[], |(_, body)| body)))
+        .map_err(|error| error.to_string())?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|error| error.to_string())?;
+
+    // Pick out the status code and body
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "Malformed HTTP response".to_string())?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| "Malformed HTTP status line".to_string())?;
+    Ok((status, body.to_string()))
+}
@@ -0,0 +1,130 @@
+//! Rate limiting of ICMP and ICMPv6 errors generated by the NAT64 itself
+//!
+//! Errors are sent in response to packets from anyone, so without a limit a flood of expiring packets with spoofed
+//! sources turns the NAT64 into a reflector. Each destination gets a token bucket of its own, and all destinations
+//! share a global one, so neither a single victim nor the NAT64 itself can be flooded with errors.
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Instant};
+
+/// Most destinations tracked at once. Idle buckets are forgotten once this many are in use.
+const MAX_DESTINATIONS: usize = 65536;
+
+/// A token bucket's refill rate and size
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    /// Tokens added per second (zero disables the limit)
+    pub per_second: u32,
+    /// Most tokens the bucket can hold
+    pub burst: u32,
+}
+
+impl Rate {
+    fn is_unlimited(self) -> bool {
+        self.per_second == 0
+    }
+}
+
+/// A single token bucket
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    /// Add the tokens earned since the last update
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * f64::from(rate.per_second)).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+
+    /// Check if the bucket has been refilled to capacity, meaning forgetting it changes nothing
+    fn is_full(&self, rate: Rate) -> bool {
+        self.tokens >= f64::from(rate.burst)
+    }
+}
+
+/// Decides which generated errors may be sent
+#[derive(Debug)]
+pub struct ErrorRateLimiter {
+    per_destination: Rate,
+    global: Rate,
+    state: Mutex<State>,
+}
+
+/// Everything guarded by the `ErrorRateLimiter` lock
+#[derive(Debug)]
+struct State {
+    global: Bucket,
+    destinations: HashMap<IpAddr, Bucket>,
+}
+
+impl ErrorRateLimiter {
+    pub fn new(per_destination: Rate, global: Rate) -> Self {
+        let now = Instant::now();
+        Self {
+            per_destination,
+            global,
+            state: Mutex::new(State {
+                global: Bucket::new(global, now),
+                destinations: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Take a token for an error to `destination`, returning `false` if the error should not be sent
+    pub fn allow(&self, destination: IpAddr) -> bool {
+        self.allow_at(destination, Instant::now())
+    }
+
+    fn allow_at(&self, destination: IpAddr, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            global,
+            destinations,
+        } = &mut *state;
+
+        if !self.global.is_unlimited() {
+            global.refill(self.global, now);
+            if global.tokens < 1.0 {
+                return false;
+            }
+        }
+
+        if !self.per_destination.is_unlimited() {
+            if destinations.len() >= MAX_DESTINATIONS && !destinations.contains_key(&destination) {
+                let rate = self.per_destination;
+                destinations.retain(|_, bucket| {
+                    bucket.refill(rate, now);
+                    !bucket.is_full(rate)
+                });
+                // Everything is busy, so there is no better choice than starting over
+                if destinations.len() >= MAX_DESTINATIONS {
+                    destinations.clear();
+                }
+            }
+            let bucket = destinations
+                .entry(destination)
+                .or_insert_with(|| Bucket::new(self.per_destination, now));
+            bucket.refill(self.per_destination, now);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+            bucket.tokens -= 1.0;
+        }
+
+        if !self.global.is_unlimited() {
+            global.tokens -= 1.0;
+        }
+        true
+    }
+}
@@ -0,0 +1,272 @@
+//! TUN interface setup shared by all translators
+
+use easy_tun::Tun;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use rtnl::{
+    route::DiscardKind,
+    route_set::{Owned, RouteSet},
+};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::Arc,
+};
+
+/// How much larger an IPv4 packet gets when translated to IPv6
+const TRANSLATION_OVERHEAD: u32 = 20;
+
+/// Create a TUN interface, bring it up with the given MTU, and route each of `routes` towards it (with `metric`, if
+/// set)
+///
+/// If `configure_netlink` is false, the interface is only created (or attached to, if it already exists)
+/// and bringing it up, setting its MTU, and routing to it are left to something else, such as a container
+/// orchestrator.
+pub async fn bring_up(
+    name: &str,
+    num_queues: usize,
+    mtu: u32,
+    routes: &[IpNet],
+    metric: Option<u32>,
+    configure_netlink: bool,
+) -> Arc<Tun> {
+    // Bring up a TUN interface
+    log::debug!("Creating new TUN interface");
+    let tun = Arc::new(Tun::new(name, num_queues).unwrap());
+    log::debug!("Created TUN interface: {}", tun.name());
+
+    // Leave link and route setup to the environment if asked to
+    if !configure_netlink {
+        log::info!(
+            "Not configuring {} via netlink. The interface must be brought up and routed to externally",
+            tun.name()
+        );
+        for route in routes {
+            log::info!("Expecting {} to be routed to {}", route, tun.name());
+        }
+        protomask_metrics::health::set_tun_up();
+        protomask_metrics::health::set_routes_installed();
+        return tun;
+    }
+
+    // Get the interface index
+    let rt_handle = rtnl::new_handle().unwrap();
+    let tun_link_idx = rtnl::link::get_link_index(&rt_handle, tun.name())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Bring the interface up
+    tun.set_mtu(mtu).unwrap();
+    rtnl::link::link_up(&rt_handle, tun_link_idx).await.unwrap();
+    protomask_metrics::health::set_tun_up();
+
+    // Add all routes
+    for route in routes {
+        log::debug!("Adding route for {} to {}", route, tun.name());
+        rtnl::route::route_add(
+            *route,
+            &rt_handle,
+            tun_link_idx,
+            Some(route_mtu(route, mtu)),
+            metric,
+        )
+        .await
+        .unwrap();
+    }
+    protomask_metrics::health::set_routes_installed();
+
+    tun
+}
+
+/// Add (or with `install` false, remove) routes towards an existing interface
+pub async fn set_routes(
+    name: &str,
+    routes: &[IpNet],
+    mtu: u32,
+    metric: Option<u32>,
+    install: bool,
+) -> Result<(), String> {
+    let rt_handle = rtnl::new_handle().map_err(|error| error.to_string())?;
+    let link_idx = rtnl::link::get_link_index(&rt_handle, name)
+        .await
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("{} does not exist", name))?;
+
+    for route in routes {
+        if install {
+            log::debug!("Adding route for {} to {}", route, name);
+            rtnl::route::route_add(
+                *route,
+                &rt_handle,
+                link_idx,
+                Some(route_mtu(route, mtu)),
+                metric,
+            )
+            .await
+            .map_err(|error| format!("Failed to add route {}: {}", route, error))?;
+        } else {
+            log::debug!("Removing route for {} from {}", route, name);
+            rtnl::route::route_del(*route, &rt_handle, link_idx)
+                .await
+                .map_err(|error| format!("Failed to remove route {}: {}", route, error))?;
+        }
+    }
+    Ok(())
+}
+
+/// Discard traffic for each of `prefixes` whenever nothing else routes it, rather than letting it follow the default
+/// route. The discard routes have the lowest possible priority, so they never get in the way of real routes.
+///
+/// With a `journal`, the routes are recorded in it. Any routes a previous run recorded there that are no longer wanted
+/// (including all of them, if `kind` is `None`) are removed first.
+pub async fn add_discard_routes(
+    prefixes: &[Ipv4Net],
+    kind: Option<DiscardKind>,
+    journal: Option<&Path>,
+) -> Result<(), String> {
+    let rt_handle = rtnl::new_handle().map_err(|error| error.to_string())?;
+    let mut route_set = match journal {
+        Some(path) => RouteSet::with_journal(path)
+            .map_err(|error| format!("Failed to read {}: {}", path.display(), error))?,
+        None => RouteSet::new(),
+    };
+    let wanted: Vec<_> = match kind {
+        Some(kind) => prefixes
+            .iter()
+            .map(|prefix| Owned::Discard {
+                destination: *prefix,
+                kind,
+                metric: u32::MAX,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Clean up after a previous run
+    for entry in route_set.entries() {
+        if !wanted.contains(entry) {
+            log::info!("Removing {} left behind by a previous run", entry);
+        }
+    }
+    route_set
+        .retain(&rt_handle, |entry| wanted.contains(entry))
+        .await
+        .map_err(|error| format!("Failed to remove old routes: {}", error))?;
+
+    for entry in wanted {
+        log::debug!("Adding fallback route: {}", entry);
+        route_set
+            .add(&rt_handle, entry.clone())
+            .await
+            .map_err(|error| format!("Failed to add fallback route {}: {}", entry, error))?;
+    }
+    Ok(())
+}
+
+/// Get the MTU to give a route towards an interface with the given MTU.
+///
+/// IPv4 packets grow when translated to IPv6, so IPv4 routes get a smaller MTU to leave room for the larger header.
+fn route_mtu(route: &IpNet, mtu: u32) -> u32 {
+    match route {
+        IpNet::V4(_) => mtu - TRANSLATION_OVERHEAD,
+        IpNet::V6(_) => mtu,
+    }
+}
+
+/// Assign each of `addresses` (with their prefix lengths) to an existing interface
+pub async fn add_addresses(name: &str, addresses: &[IpNet]) -> Result<(), String> {
+    let rt_handle = rtnl::new_handle().map_err(|error| error.to_string())?;
+    let link_idx = rtnl::link::get_link_index(&rt_handle, name)
+        .await
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("{} does not exist", name))?;
+
+    for address in addresses {
+        log::debug!("Adding address {} to {}", address, name);
+        rtnl::ip::addr_add(address.addr(), address.prefix_len(), &rt_handle, link_idx)
+            .await
+            .map_err(|error| format!("Failed to add address {}: {}", address, error))?;
+    }
+    Ok(())
+}
+
+/// Make an interface accept all multicast traffic, rather than only the groups it has joined
+pub async fn enable_allmulticast(name: &str) -> Result<(), String> {
+    let rt_handle = rtnl::new_handle().map_err(|error| error.to_string())?;
+    let link_idx = rtnl::link::get_link_index(&rt_handle, name)
+        .await
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("{} does not exist", name))?;
+    rtnl::link::set_allmulticast(&rt_handle, link_idx, true)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+/// Answer ARP requests on an interface for each of `addresses`, as long as they are routed somewhere else
+pub async fn add_proxy_arp(name: &str, addresses: &[Ipv4Addr]) -> Result<(), String> {
+    let rt_handle = rtnl::new_handle().map_err(|error| error.to_string())?;
+    let link_idx = rtnl::link::get_link_index(&rt_handle, name)
+        .await
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("{} does not exist", name))?;
+
+    for address in addresses {
+        rtnl::neighbor::proxy_add(&rt_handle, link_idx, (*address).into())
+            .await
+            .map_err(|error| format!("Failed to proxy ARP for {}: {}", address, error))?;
+    }
+    Ok(())
+}
+
+/// Read an interface's MAC address from sysfs
+pub fn mac_address(name: &str) -> Result<[u8; 6], String> {
+    let path = format!("/sys/class/net/{}/address", name);
+    let data = std::fs::read_to_string(&path)
+        .map_err(|error| format!("Failed to read {}: {}", path, error))?;
+
+    let octets = data
+        .trim()
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| format!("{} has an invalid MAC address: {}", name, error))?;
+    octets
+        .try_into()
+        .map_err(|_| format!("{} does not have an Ethernet address", name))
+}
+
+/// Find the global IPv6 prefix assigned to an interface.
+///
+/// This reads `/proc/net/if_inet6` rather than using netlink, so it works inside restricted containers.
+pub fn global_ipv6_prefix(name: &str) -> Result<Ipv6Net, String> {
+    let data = std::fs::read_to_string("/proc/net/if_inet6")
+        .map_err(|error| format!("Failed to read /proc/net/if_inet6: {}", error))?;
+
+    // Each line is `<address> <index> <prefix length> <scope> <flags> <name>`, with numbers in hex
+    for line in data.lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let [address, _, prefix_len, scope, flags, interface] = fields[..] else {
+            continue;
+        };
+        if interface != name || scope != "00" {
+            continue;
+        }
+
+        // Skip temporary, deprecated, and tentative addresses
+        let flags = u8::from_str_radix(flags, 16).unwrap_or_default();
+        if flags & (0x01 | 0x20 | 0x40) != 0 {
+            continue;
+        }
+
+        if let (Ok(address), Ok(prefix_len)) = (
+            u128::from_str_radix(address, 16),
+            u8::from_str_radix(prefix_len, 16),
+        ) {
+            if let Ok(network) = Ipv6Net::new(Ipv6Addr::from(address), prefix_len) {
+                return Ok(network.trunc());
+            }
+        }
+    }
+
+    Err(format!("{} has no global IPv6 address", name))
+}
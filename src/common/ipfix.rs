@@ -0,0 +1,303 @@
+//! IPFIX ([RFC7011](https://datatracker.ietf.org/doc/html/rfc7011)) flow export
+//!
+//! Every translated packet is accounted against a flow keyed by its pre-translation 5-tuple.
+//! Periodically, the accumulated flows are exported as IPFIX data records (including the post-translation addresses
+//! and ports) to a collector over UDP, and the counters are reset.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::packet_handler::{get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto};
+
+/// IPFIX protocol version number
+const IPFIX_VERSION: u16 = 10;
+
+/// Set ID used for template sets
+const TEMPLATE_SET_ID: u16 = 2;
+
+/// Template describing flows that arrived as IPv6 and left as IPv4
+const TEMPLATE_ID_6_TO_4: u16 = 256;
+
+/// Template describing flows that arrived as IPv4 and left as IPv6
+const TEMPLATE_ID_4_TO_6: u16 = 257;
+
+/// Observation domain to report flows under
+const OBSERVATION_DOMAIN_ID: u32 = 1;
+
+/// Maximum number of data records to pack into a single message (keeps messages well under a 1500 byte MTU)
+const MAX_RECORDS_PER_MESSAGE: usize = 15;
+
+/// Information elements shared by both templates, following the address fields
+#[rustfmt::skip]
+const COMMON_FIELDS: [(u16, u16); 9] = [
+    (4, 1),   // protocolIdentifier
+    (7, 2),   // sourceTransportPort
+    (11, 2),  // destinationTransportPort
+    (227, 2), // postNAPTSourceTransportPort
+    (228, 2), // postNAPTDestinationTransportPort
+    (1, 8),   // octetDeltaCount
+    (2, 8),   // packetDeltaCount
+    (152, 8), // flowStartMilliseconds
+    (153, 8), // flowEndMilliseconds
+];
+
+/// Address information elements for IPv6 to IPv4 flows
+#[rustfmt::skip]
+const ADDRESS_FIELDS_6_TO_4: [(u16, u16); 4] = [
+    (27, 16),  // sourceIPv6Address
+    (28, 16),  // destinationIPv6Address
+    (225, 4),  // postNATSourceIPv4Address
+    (226, 4),  // postNATDestinationIPv4Address
+];
+
+/// Address information elements for IPv4 to IPv6 flows
+#[rustfmt::skip]
+const ADDRESS_FIELDS_4_TO_6: [(u16, u16); 4] = [
+    (8, 4),    // sourceIPv4Address
+    (12, 4),   // destinationIPv4Address
+    (281, 16), // postNATSourceIPv6Address
+    (282, 16), // postNATDestinationIPv6Address
+];
+
+/// Identifies a single flow by its pre-translation 5-tuple
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    source_port: u16,
+    destination_port: u16,
+}
+
+/// Accumulated counters for a single flow
+#[derive(Debug, Clone, Copy)]
+struct FlowStats {
+    post_source: IpAddr,
+    post_destination: IpAddr,
+    /// Ports may be rewritten when clients share an address
+    post_source_port: u16,
+    post_destination_port: u16,
+    bytes: u64,
+    packets: u64,
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+}
+
+/// Collects per-flow statistics and periodically exports them to an IPFIX collector
+pub struct FlowExporter {
+    flows: Mutex<HashMap<FlowKey, FlowStats>>,
+}
+
+impl FlowExporter {
+    /// Create a new exporter and spawn a thread that sends flows to `collector` every `interval`
+    pub fn new(collector: SocketAddr, interval: Duration) -> std::io::Result<Arc<Self>> {
+        let socket = UdpSocket::bind(match collector {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        })?;
+        socket.connect(collector)?;
+
+        let exporter = Arc::new(Self {
+            flows: Mutex::new(HashMap::new()),
+        });
+
+        {
+            let exporter = Arc::clone(&exporter);
+            std::thread::Builder::new()
+                .name("ipfix-export".to_string())
+                .spawn(move || {
+                    let mut sequence_number = 0u32;
+                    loop {
+                        std::thread::sleep(interval);
+                        if let Err(error) = exporter.export(&socket, &mut sequence_number) {
+                            log::warn!("Failed to export IPFIX flow records: {}", error);
+                        }
+                    }
+                })?;
+        }
+
+        Ok(exporter)
+    }
+
+    /// Account for a packet that was successfully translated from `input` to `output`
+    #[profiling::function]
+    pub fn record(&self, input: &[u8], output: &[u8]) {
+        let (Some(key), Some(post)) = (parse_flow_key(input), parse_flow_key(output)) else {
+            return;
+        };
+        let now = SystemTime::now();
+
+        self.flows
+            .lock()
+            .unwrap()
+            .entry(key)
+            .and_modify(|stats| {
+                stats.bytes += input.len() as u64;
+                stats.packets += 1;
+                stats.last_seen = now;
+            })
+            .or_insert(FlowStats {
+                post_source: post.source,
+                post_destination: post.destination,
+                post_source_port: post.source_port,
+                post_destination_port: post.destination_port,
+                bytes: input.len() as u64,
+                packets: 1,
+                first_seen: now,
+                last_seen: now,
+            });
+    }
+
+    /// Send all accumulated flows to the collector and reset the counters
+    fn export(&self, socket: &UdpSocket, sequence_number: &mut u32) -> std::io::Result<()> {
+        // Swap out the flow table so packet processing is not blocked while we encode
+        let flows = std::mem::take(&mut *self.flows.lock().unwrap());
+        log::debug!("Exporting {} IPFIX flow records", flows.len());
+
+        let (flows_6_to_4, flows_4_to_6): (Vec<_>, Vec<_>) =
+            flows.into_iter().partition(|(key, _)| key.source.is_ipv6());
+
+        for (template_id, flows) in [
+            (TEMPLATE_ID_6_TO_4, flows_6_to_4),
+            (TEMPLATE_ID_4_TO_6, flows_4_to_6),
+        ] {
+            for chunk in flows.chunks(MAX_RECORDS_PER_MESSAGE) {
+                socket.send(&build_message(template_id, chunk, *sequence_number))?;
+                *sequence_number = sequence_number.wrapping_add(chunk.len() as u32);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Extract the addresses, protocol, and ports from an IP packet
+fn parse_flow_key(packet: &[u8]) -> Option<FlowKey> {
+    let (source, destination, protocol, payload) = match get_layer_3_proto(packet) {
+        Some(4) if packet.len() >= 20 => {
+            let (source, destination) = get_ipv4_src_dst(packet);
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            (
+                IpAddr::V4(source),
+                IpAddr::V4(destination),
+                packet[9],
+                packet.get(header_len..)?,
+            )
+        }
+        Some(6) if packet.len() >= 40 => {
+            let (source, destination) = get_ipv6_src_dst(packet);
+            (
+                IpAddr::V6(source),
+                IpAddr::V6(destination),
+                packet[6],
+                &packet[40..],
+            )
+        }
+        _ => return None,
+    };
+
+    // TCP and UDP both start with the source and destination ports
+    let (source_port, destination_port) = match protocol {
+        6 | 17 if payload.len() >= 4 => (
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        ),
+        _ => (0, 0),
+    };
+
+    Some(FlowKey {
+        source,
+        destination,
+        protocol,
+        source_port,
+        destination_port,
+    })
+}
+
+/// Build a complete IPFIX message containing the template and a set of data records
+fn build_message(
+    template_id: u16,
+    flows: &[(FlowKey, FlowStats)],
+    sequence_number: u32,
+) -> Vec<u8> {
+    let address_fields = match template_id {
+        TEMPLATE_ID_6_TO_4 => &ADDRESS_FIELDS_6_TO_4,
+        _ => &ADDRESS_FIELDS_4_TO_6,
+    };
+
+    let mut message = Vec::with_capacity(1500);
+
+    // Message header (length is filled in at the end)
+    message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&(unix_millis(SystemTime::now()) / 1000).to_be_bytes()[4..]);
+    message.extend_from_slice(&sequence_number.to_be_bytes());
+    message.extend_from_slice(&OBSERVATION_DOMAIN_ID.to_be_bytes());
+
+    // Template set. This is re-sent with every message since UDP transport gives no delivery guarantees.
+    let field_count = address_fields.len() + COMMON_FIELDS.len();
+    let set_start = start_set(&mut message, TEMPLATE_SET_ID);
+    message.extend_from_slice(&template_id.to_be_bytes());
+    message.extend_from_slice(&(field_count as u16).to_be_bytes());
+    for (element_id, length) in address_fields.iter().chain(COMMON_FIELDS.iter()) {
+        message.extend_from_slice(&element_id.to_be_bytes());
+        message.extend_from_slice(&length.to_be_bytes());
+    }
+    finish_set(&mut message, set_start);
+
+    // Data set
+    let set_start = start_set(&mut message, template_id);
+    for (key, stats) in flows {
+        for address in [
+            key.source,
+            key.destination,
+            stats.post_source,
+            stats.post_destination,
+        ] {
+            match address {
+                IpAddr::V4(address) => message.extend_from_slice(&address.octets()),
+                IpAddr::V6(address) => message.extend_from_slice(&address.octets()),
+            }
+        }
+        message.push(key.protocol);
+        message.extend_from_slice(&key.source_port.to_be_bytes());
+        message.extend_from_slice(&key.destination_port.to_be_bytes());
+        message.extend_from_slice(&stats.post_source_port.to_be_bytes());
+        message.extend_from_slice(&stats.post_destination_port.to_be_bytes());
+        message.extend_from_slice(&stats.bytes.to_be_bytes());
+        message.extend_from_slice(&stats.packets.to_be_bytes());
+        message.extend_from_slice(&unix_millis(stats.first_seen).to_be_bytes());
+        message.extend_from_slice(&unix_millis(stats.last_seen).to_be_bytes());
+    }
+    finish_set(&mut message, set_start);
+
+    // Fill in the total message length
+    let length = message.len() as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+    message
+}
+
+/// Write a set header with a placeholder length, returning the offset of the set
+fn start_set(message: &mut Vec<u8>, set_id: u16) -> usize {
+    let start = message.len();
+    message.extend_from_slice(&set_id.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    start
+}
+
+/// Fill in the length of a set started with `start_set`
+fn finish_set(message: &mut [u8], start: usize) {
+    let length = (message.len() - start) as u16;
+    message[start + 2..start + 4].copy_from_slice(&length.to_be_bytes());
+}
+
+/// Convert a timestamp to milliseconds since the UNIX epoch
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
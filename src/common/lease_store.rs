@@ -0,0 +1,543 @@
+//! Shared lease storage for clustered NAT64s
+//!
+//! When several NAT64s share a pool behind ECMP, any of them may see a client's packets (or the replies to them), so
+//! all of them must agree on every mapping. Each instance still keeps its own address table, but fills it from a
+//! shared store whenever it sees an address it knows nothing about, and claims new addresses in the store before
+//! handing them out. Leases in the store expire along with the mappings they describe, so the leases of an instance
+//! that goes away are kept by the others until they time out. Leases of mappings that are still in use are renewed
+//! periodically, so that they can't expire from the store while they are still handed out.
+//!
+//! The store is only ever spoken to from a background thread. A packet that needs an answer from the store is
+//! dropped while the question is asked, and the packets that follow it use the answer.
+//!
+//! Redis (`redis://[[user]:password@]host[:port][/db]`) is currently the only backend, and is only available when
+//! protomask is built with the `redis` feature. Each direction of a mapping has its own key, and leases are claimed and
+//! renewed by a script, so that other instances never see them half-written.
+//!
+//! If the store can't be reached, addresses are handed out from the local table alone, and the store is left alone for
+//! a while, so that an outage of the store doesn't take down the NAT64.
+
+use super::prefix_tables::{AddressTable, PrefixTables};
+use cfg_if::cfg_if;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long to leave the store alone after it couldn't be reached
+const STORE_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long to remember that the store had no lease for an IPv4 address
+const MISS_TTL: Duration = Duration::from_secs(1);
+
+/// How many taken addresses to skip over before giving up on claiming one
+const MAX_CLAIM_ATTEMPTS: usize = 16;
+
+/// Most questions that may be waiting for the store at once
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A place to share leases between NAT64 instances
+pub trait LeaseBackend: Send {
+    /// Claim an IPv4 address for an IPv6 address, unless it is already leased to someone else.
+    /// Returns the existing lease (and its remaining lifetime) if it is.
+    fn claim(
+        &mut self,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        ttl: Duration,
+    ) -> io::Result<Option<(Ipv6Addr, Duration)>>;
+
+    /// Extend a lease to `ttl` from now, claiming it again if it has already expired.
+    /// Returns `false` if the address is leased to someone else.
+    fn renew(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr, ttl: Duration) -> io::Result<bool>;
+
+    /// Find the lease held by an IPv6 address, along with its remaining lifetime
+    fn lease_for_ipv6(&mut self, ipv6: Ipv6Addr) -> io::Result<Option<(Ipv4Addr, Duration)>>;
+
+    /// Find the lease on an IPv4 address, along with its remaining lifetime
+    fn lease_for_ipv4(&mut self, ipv4: Ipv4Addr) -> io::Result<Option<(Ipv6Addr, Duration)>>;
+}
+
+/// The store hasn't answered yet, so the packet asking has to be dropped
+#[derive(Debug)]
+pub struct LeasePending;
+
+/// A question for the store, along with the table to put the answer in
+enum Request {
+    /// Find the lease on a pool address
+    Lookup(AddressTable, Ipv4Addr),
+    /// Take over a source's lease, or claim a new one for it
+    Claim(AddressTable, Ipv6Addr),
+}
+
+/// State shared between the packet path and the thread speaking to the store
+struct Shared {
+    /// Lifetime of new leases
+    ttl: Duration,
+    /// IPv4 addresses recently found to have no lease, so that scans of the pool don't all end up at the store
+    misses: Mutex<HashMap<Ipv4Addr, Instant>>,
+    /// Addresses with a question waiting for the store, so that each is only asked about once
+    pending: Mutex<HashSet<IpAddr>>,
+    /// When the store may next be spoken to, after it couldn't be reached
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Shared {
+    /// Check if the store is being left alone after an outage
+    fn is_down(&self) -> bool {
+        self.down_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+/// Shared leases, backing the local address tables
+pub struct LeaseStore {
+    shared: Arc<Shared>,
+    requests: SyncSender<Request>,
+}
+
+impl LeaseStore {
+    /// Check that a store URL is one this build knows how to use, without connecting to it
+    pub fn check(url: &str) -> Result<(), String> {
+        backend(url).map(drop)
+    }
+
+    /// Set up a store from its URL, renewing the leases of live mappings in `prefix_tables`.
+    /// Nothing is connected to until the store is first needed.
+    pub fn new(url: &str, ttl: Duration, prefix_tables: Arc<PrefixTables>) -> Result<Self, String> {
+        let shared = Arc::new(Shared {
+            ttl,
+            misses: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashSet::new()),
+            down_until: Mutex::new(None),
+        });
+        let worker = Worker {
+            backend: backend(url)?,
+            shared: Arc::clone(&shared),
+            prefix_tables,
+        };
+        let (requests, receiver) = sync_channel(QUEUE_CAPACITY);
+        std::thread::Builder::new()
+            .name("lease-store".to_string())
+            .spawn(move || worker.run(&receiver))
+            .map_err(|error| format!("Failed to start the lease store thread: {}", error))?;
+        Ok(Self { shared, requests })
+    }
+
+    /// Get the IPv4 address for an IPv6 source, taking over its lease or claiming a new one if it isn't known locally.
+    ///
+    /// Returns `None` while the store is being asked.
+    pub fn get_or_claim_ipv4(
+        &self,
+        table: &AddressTable,
+        source: Ipv6Addr,
+    ) -> Result<Option<Ipv4Addr>, String> {
+        if let Some(ipv4) = table.lock().unwrap().get_ipv4(&source) {
+            return Ok(Some(ipv4));
+        }
+        if self.shared.is_down() {
+            return table
+                .lock()
+                .unwrap()
+                .get_or_create_ipv4(&source)
+                .map(Some)
+                .map_err(|error| error.to_string());
+        }
+        self.ask(source.into(), Request::Claim(Arc::clone(table), source));
+        Ok(None)
+    }
+
+    /// Get the IPv6 address an IPv4 pool address is mapped to, looking in the store if it isn't known locally
+    pub fn get_ipv6(
+        &self,
+        table: &AddressTable,
+        ipv4: Ipv4Addr,
+    ) -> Result<Option<Ipv6Addr>, LeasePending> {
+        if let Some(ipv6) = table.lock().unwrap().get_ipv6(&ipv4) {
+            return Ok(Some(ipv6));
+        }
+        if self.shared.is_down()
+            || self
+                .shared
+                .misses
+                .lock()
+                .unwrap()
+                .get(&ipv4)
+                .is_some_and(|since| since.elapsed() < MISS_TTL)
+        {
+            return Ok(None);
+        }
+        self.ask(ipv4.into(), Request::Lookup(Arc::clone(table), ipv4));
+        Err(LeasePending)
+    }
+
+    /// Hand a question to the store's thread, unless the same address is already being asked about
+    fn ask(&self, address: IpAddr, request: Request) {
+        if !self.shared.pending.lock().unwrap().insert(address) {
+            return;
+        }
+        if self.requests.try_send(request).is_err() {
+            // The next packet will ask again
+            self.shared.pending.lock().unwrap().remove(&address);
+        }
+    }
+}
+
+/// The thread speaking to the store
+struct Worker {
+    backend: Box<dyn LeaseBackend>,
+    shared: Arc<Shared>,
+    prefix_tables: Arc<PrefixTables>,
+}
+
+impl Worker {
+    /// Answer questions until the store is dropped, renewing leases in between
+    fn run(mut self, receiver: &Receiver<Request>) {
+        let renew_interval = (self.shared.ttl / 3).max(Duration::from_secs(1));
+        let mut last_renewal = Instant::now();
+        loop {
+            match receiver.recv_timeout(renew_interval.saturating_sub(last_renewal.elapsed())) {
+                Ok(request) => self.handle(request),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if last_renewal.elapsed() >= renew_interval {
+                self.renew_all();
+                last_renewal = Instant::now();
+            }
+        }
+    }
+
+    /// Answer a question, putting the answer in its table
+    fn handle(&mut self, request: Request) {
+        let address = match request {
+            Request::Lookup(table, ipv4) => {
+                if !self.shared.is_down() {
+                    match self.query(|backend| backend.lease_for_ipv4(ipv4)) {
+                        Ok(Some((ipv6, ttl))) => adopt(&table, ipv4, ipv6, ttl),
+                        Ok(None) => {
+                            let mut misses = self.shared.misses.lock().unwrap();
+                            misses.retain(|_, since| since.elapsed() < MISS_TTL);
+                            misses.insert(ipv4, Instant::now());
+                        }
+                        Err(_) => {}
+                    }
+                }
+                IpAddr::V4(ipv4)
+            }
+            Request::Claim(table, source) => {
+                let claimed = if self.shared.is_down() {
+                    Err("Lease store unavailable".to_string())
+                } else {
+                    self.claim(&table, source)
+                };
+                if let Err(error) = claimed {
+                    log::warn!("Assigning {} locally: {}", source, error);
+                    if let Err(error) = table.lock().unwrap().get_or_create_ipv4(&source) {
+                        log::error!("Error getting IPv4 address: {}", error);
+                    }
+                }
+                IpAddr::V6(source)
+            }
+        };
+        self.shared.pending.lock().unwrap().remove(&address);
+    }
+
+    /// Take over a source's existing lease, or claim the first free address for it
+    fn claim(&mut self, table: &AddressTable, source: Ipv6Addr) -> Result<Ipv4Addr, String> {
+        if let Some((ipv4, ttl)) = self.query(|backend| backend.lease_for_ipv6(source))? {
+            adopt(table, ipv4, source, ttl);
+            return Ok(ipv4);
+        }
+
+        let ttl = self.shared.ttl;
+        for _ in 0..MAX_CLAIM_ATTEMPTS {
+            let candidate = table
+                .lock()
+                .unwrap()
+                .next_free_ipv4(&source)
+                .map_err(|error| error.to_string())?;
+            match self.query(|backend| backend.claim(candidate, source, ttl))? {
+                Some((owner, ttl)) if owner != source => {
+                    // Another instance got there first. Learning about its lease keeps us from trying it again.
+                    log::debug!("{} is already leased to {}", candidate, owner);
+                    adopt(table, candidate, owner, ttl);
+                }
+                _ => {
+                    self.shared.misses.lock().unwrap().remove(&candidate);
+                    return table
+                        .lock()
+                        .unwrap()
+                        .insert_dynamic(candidate, source)
+                        .map(|()| candidate)
+                        .map_err(|error| error.to_string());
+                }
+            }
+        }
+        Err("Every free address tried was already leased".to_string())
+    }
+
+    /// Renew the lease of every live dynamic mapping, so that none expire from the store while still handed out
+    fn renew_all(&mut self) {
+        if self.shared.is_down() {
+            return;
+        }
+        let mappings: Vec<(Ipv4Addr, Ipv6Addr)> = self
+            .prefix_tables
+            .tables()
+            .flat_map(|table| {
+                table
+                    .lock()
+                    .unwrap()
+                    .mappings()
+                    .filter(|(_, _, remaining)| {
+                        remaining.is_some_and(|remaining| !remaining.is_zero())
+                    })
+                    .map(|(ipv4, ipv6, _)| (ipv4, ipv6))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let ttl = self.shared.ttl;
+        for (ipv4, ipv6) in mappings {
+            match self.query(|backend| backend.renew(ipv4, ipv6, ttl)) {
+                Ok(true) => {}
+                Ok(false) => log::warn!(
+                    "{} is mapped to {} here, but leased to someone else in the store",
+                    ipv4,
+                    ipv6
+                ),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Ask the store something, leaving it alone for a while if it can't be reached
+    fn query<T>(
+        &mut self,
+        question: impl FnOnce(&mut dyn LeaseBackend) -> io::Result<T>,
+    ) -> Result<T, String> {
+        question(self.backend.as_mut()).map_err(|error| {
+            log::warn!(
+                "Lease store unavailable, assigning addresses locally for the next {:?}: {}",
+                STORE_BACKOFF,
+                error
+            );
+            *self.shared.down_until.lock().unwrap() = Some(Instant::now() + STORE_BACKOFF);
+            format!("Lease store unavailable: {}", error)
+        })
+    }
+}
+
+/// Copy a lease from the store into a local table
+fn adopt(table: &AddressTable, ipv4: Ipv4Addr, ipv6: Ipv6Addr, ttl: Duration) {
+    if let Err(error) = table.lock().unwrap().insert_with_ttl(ipv4, ipv6, ttl) {
+        log::warn!("Ignoring lease from the store: {}", error);
+    }
+}
+
+/// Set up the backend named by a store URL
+fn backend(url: &str) -> Result<Box<dyn LeaseBackend>, String> {
+    match url.split_once("://") {
+        Some(("redis", _)) => redis_backend(url),
+        Some((scheme, _)) => Err(format!("Unsupported lease store: {}", scheme)),
+        None => Err(format!("{} is not a URL", url)),
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "redis")] {
+        use redis::{Client, Connection, RedisError, Script};
+
+        /// How long the store may take to answer
+        const STORE_TIMEOUT: Duration = Duration::from_secs(1);
+
+        /// Prefix of every key written to the store
+        const KEY_PREFIX: &str = "protomask:lease";
+
+        /// Leases an IPv4 address to an IPv6 address in one step, so that no other instance can come in between.
+        ///
+        /// Keys: the lease on the IPv4 address, the lease held by the IPv6 address.
+        /// Arguments: the IPv6 address, the IPv4 address, the lifetime in milliseconds, and whether to extend a lease
+        /// the IPv6 address already holds (`1`) rather than leave it alone (`0`).
+        ///
+        /// Returns nothing if the lease was made, or the current owner and the remaining lifetime of its lease.
+        const CLAIM_SCRIPT: &str = r#"
+            local owner = redis.call('GET', KEYS[1])
+            if not owner or (owner == ARGV[1] and ARGV[4] == '1') then
+                redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[3])
+                redis.call('SET', KEYS[2], ARGV[2], 'PX', ARGV[3])
+                return false
+            end
+            return {owner, redis.call('PTTL', KEYS[1])}
+        "#;
+
+        /// Set up a Redis backend from its URL
+        fn redis_backend(url: &str) -> Result<Box<dyn LeaseBackend>, String> {
+            let client = Client::open(url).map_err(|error| format!("Invalid lease store URL: {}", error))?;
+            Ok(Box::new(Redis {
+                client,
+                connection: None,
+                claim: Script::new(CLAIM_SCRIPT),
+            }))
+        }
+
+        /// A Redis server
+        struct Redis {
+            client: Client,
+            connection: Option<Connection>,
+            claim: Script,
+        }
+
+        impl Redis {
+            /// Run a command, connecting first if needed. The connection is dropped after any error.
+            fn command<T>(
+                &mut self,
+                command: impl FnOnce(&mut Connection) -> Result<T, RedisError>,
+            ) -> io::Result<T> {
+                if self.connection.is_none() {
+                    self.connection = Some(self.connect().map_err(io::Error::other)?);
+                }
+                let result = command(self.connection.as_mut().unwrap());
+                if result.is_err() {
+                    self.connection = None;
+                }
+                result.map_err(io::Error::other)
+            }
+
+            /// Connect, with timeouts on everything sent over the connection
+            fn connect(&self) -> Result<Connection, RedisError> {
+                let connection = self.client.get_connection_with_timeout(STORE_TIMEOUT)?;
+                connection.set_read_timeout(Some(STORE_TIMEOUT))?;
+                connection.set_write_timeout(Some(STORE_TIMEOUT))?;
+                log::info!("Connected to lease store at {}", self.client.get_connection_info().addr);
+                Ok(connection)
+            }
+
+            /// Lease an IPv4 address to an IPv6 address, returning the current owner if it is leased to someone else
+            /// (or to the same address, when `extend` isn't set)
+            fn run_claim(
+                &mut self,
+                ipv4: Ipv4Addr,
+                ipv6: Ipv6Addr,
+                ttl: Duration,
+                extend: bool,
+            ) -> io::Result<Option<(Ipv6Addr, Duration)>> {
+                let script = self.claim.clone();
+                let owner: Option<(String, i64)> = self.command(|connection| {
+                    script
+                        .key(ipv4_key(ipv4))
+                        .key(ipv6_key(ipv6))
+                        .arg(ipv6.to_string())
+                        .arg(ipv4.to_string())
+                        .arg(ttl.as_millis().max(1).to_string())
+                        .arg(u8::from(extend))
+                        .invoke(connection)
+                })?;
+                Ok(owner.and_then(|(owner, ttl)| lease(&owner, ttl)))
+            }
+
+            /// Get a key along with its remaining lifetime, in one step
+            fn get_with_ttl(&mut self, key: &str) -> io::Result<Option<(String, i64)>> {
+                let (value, ttl): (Option<String>, i64) = self.command(|connection| {
+                    redis::pipe().atomic().get(key).pttl(key).query(connection)
+                })?;
+                Ok(value.map(|value| (value, ttl)))
+            }
+        }
+
+        impl LeaseBackend for Redis {
+            fn claim(
+                &mut self,
+                ipv4: Ipv4Addr,
+                ipv6: Ipv6Addr,
+                ttl: Duration,
+            ) -> io::Result<Option<(Ipv6Addr, Duration)>> {
+                self.run_claim(ipv4, ipv6, ttl, false)
+            }
+
+            fn renew(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr, ttl: Duration) -> io::Result<bool> {
+                Ok(self
+                    .run_claim(ipv4, ipv6, ttl, true)?
+                    .is_none_or(|(owner, _)| owner == ipv6))
+            }
+
+            fn lease_for_ipv6(&mut self, ipv6: Ipv6Addr) -> io::Result<Option<(Ipv4Addr, Duration)>> {
+                let Some((ipv4, ttl)) = self
+                    .get_with_ttl(&ipv6_key(ipv6))?
+                    .and_then(|(ipv4, ttl)| lease(&ipv4, ttl))
+                else {
+                    return Ok(None);
+                };
+
+                // Only trust the lease if the address hasn't since been leased to someone else
+                Ok(match self.lease_for_ipv4(ipv4)? {
+                    Some((owner, _)) if owner == ipv6 => Some((ipv4, ttl)),
+                    _ => None,
+                })
+            }
+
+            fn lease_for_ipv4(&mut self, ipv4: Ipv4Addr) -> io::Result<Option<(Ipv6Addr, Duration)>> {
+                Ok(self
+                    .get_with_ttl(&ipv4_key(ipv4))?
+                    .and_then(|(ipv6, ttl)| lease(&ipv6, ttl)))
+            }
+        }
+
+        /// Key of the lease on an IPv4 address
+        fn ipv4_key(ipv4: Ipv4Addr) -> String {
+            format!("{}:ipv4:{}", KEY_PREFIX, ipv4)
+        }
+
+        /// Key of the lease held by an IPv6 address
+        fn ipv6_key(ipv6: Ipv6Addr) -> String {
+            format!("{}:ipv6:{}", KEY_PREFIX, ipv6)
+        }
+
+        /// Read a lease's address and remaining lifetime (in milliseconds) from the store
+        fn lease<T: std::str::FromStr>(address: &str, ttl: i64) -> Option<(T, Duration)> {
+            // Keys without a lifetime (which we never create) or that have just expired report a negative one
+            let ttl = u64::try_from(ttl).ok().filter(|ttl| *ttl > 0)?;
+            Some((address.parse().ok()?, Duration::from_millis(ttl)))
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn test_urls() {
+                assert!(backend("redis://localhost").is_ok());
+                assert!(backend("redis://user:p@ss@redis.example:6380/3").is_ok());
+                assert!(backend("redis://:secret@[2001:db8::1]:6380/1").is_ok());
+                assert!(backend("redis://localhost:port").is_err());
+                assert!(backend("redis://localhost/db").is_err());
+            }
+
+            #[test]
+            fn test_lease() {
+                assert_eq!(
+                    lease::<Ipv4Addr>("192.0.2.1", 1500),
+                    Some(("192.0.2.1".parse().unwrap(), Duration::from_millis(1500)))
+                );
+                assert_eq!(lease::<Ipv4Addr>("192.0.2.1", -1), None);
+                assert_eq!(lease::<Ipv4Addr>("192.0.2.1", -2), None);
+                assert_eq!(lease::<Ipv4Addr>("192.0.2.1", 0), None);
+                assert_eq!(lease::<Ipv6Addr>("not an address", 1500), None);
+            }
+        }
+    } else {
+        /// Redis is not available in this build
+        fn redis_backend(_url: &str) -> Result<Box<dyn LeaseBackend>, String> {
+            Err("This build of protomask does not support Redis lease stores. Rebuild with the `redis` feature to enable them.".to_string())
+        }
+    }
+}
@@ -1,7 +1,6 @@
 use owo_colors::{OwoColorize, Stream::Stdout};
 
 /// Enable the logger
-#[allow(dead_code)]
 pub fn enable_logger(verbose: bool) {
     fern::Dispatch::new()
         .format(move |out, message, record| {
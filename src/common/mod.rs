@@ -1,7 +1,56 @@
 //! Common code used across all protomask binaries
 
+pub mod address_hook;
+pub mod agentx;
+pub mod busy_poll;
+pub mod capture;
+pub mod control;
+pub mod counters;
+pub mod dbus;
+pub mod dns46;
+pub mod dns_proxy;
+pub mod drain;
+pub mod dry_run;
+pub mod dscp;
+pub mod egress;
+pub mod failover;
+pub mod grpc;
+pub mod hop;
+pub mod icmp_rate_limit;
+pub mod http;
+pub mod interface;
+pub mod ipfix;
+pub mod lease_store;
 pub mod logging;
+pub mod ndp_proxy;
+pub mod network_monitor;
+pub mod nftables;
+pub mod numa;
+pub mod packet_buffer;
 pub mod packet_handler;
+pub mod packet_queue;
+pub mod packet_trace;
+pub mod pcap;
 pub mod permissions;
+pub mod plat_probe;
+pub mod pref64;
+pub mod policy;
+pub mod prefix_tables;
 pub mod profiler;
+pub mod rdns;
+pub mod recent_drops;
+pub mod replication;
 pub mod rfc6052;
+pub mod rfc6791;
+pub mod runtime;
+pub mod session_log;
+pub mod stage_timer;
+pub mod state_dump;
+pub mod static_map_file;
+pub mod telemetry;
+pub mod upgrade;
+pub mod upstream_health;
+pub mod validation;
+pub mod webhook;
+pub mod worker;
+pub mod worker_scaling;
@@ -0,0 +1,134 @@
+//! Neighbor Discovery proxy
+//!
+//! Normally the translation prefix is routed to the NAT64. When it must instead be on-link (for example, when it is
+//! carved out of a LAN's /64), hosts on that link look up every address inside it with a Neighbor Solicitation. The
+//! proxy answers each of these with the uplink's own MAC address, so the traffic is delivered to this machine and
+//! routed into the TUN interface.
+//!
+//! The kernel's own proxy NDP only works one address at a time, so solicitations are answered from a raw ICMPv6 socket
+//! instead. The uplink is put into all-multicast mode, since every address in the prefix has its own solicited-node
+//! multicast group.
+
+use super::interface;
+use ipnet::Ipv6Net;
+use nix::sys::socket::{recvfrom, sendto, MsgFlags, SockaddrIn6};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddrV6},
+    os::fd::AsRawFd,
+};
+
+/// ICMPv6 type of a Neighbor Solicitation
+const NEIGHBOR_SOLICITATION: u8 = 135;
+
+/// ICMPv6 type of a Neighbor Advertisement
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// Neighbor Advertisement flags (RFC4861 section 4.4)
+const FLAG_ROUTER: u8 = 0x80;
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+
+/// NDP option carrying the target's link-layer address
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+
+/// Answer Neighbor Solicitations on `uplink` for every address inside `prefixes`
+pub async fn proxy_ndp(uplink: String, prefixes: Vec<Ipv6Net>) {
+    if let Err(error) = interface::enable_allmulticast(&uplink).await {
+        log::error!(
+            "Failed to enable all-multicast mode on {}: {}",
+            uplink,
+            error
+        );
+        return;
+    }
+    let mac = match interface::mac_address(&uplink) {
+        Ok(mac) => mac,
+        Err(error) => {
+            log::error!("Can't proxy NDP: {}", error);
+            return;
+        }
+    };
+    let socket = match open_socket(&uplink) {
+        Ok(socket) => socket,
+        Err(error) => {
+            log::error!("Failed to open an ICMPv6 socket on {}: {}", uplink, error);
+            return;
+        }
+    };
+    for prefix in &prefixes {
+        log::info!("Proxying NDP for {} on {}", prefix, uplink);
+    }
+
+    // The socket is blocking, so it gets its own thread
+    std::thread::spawn(move || loop {
+        if let Err(error) = answer_solicitation(&socket, &prefixes, mac) {
+            log::warn!("Failed to answer Neighbor Solicitation: {}", error);
+        }
+    });
+}
+
+/// Open a raw ICMPv6 socket on the uplink
+fn open_socket(uplink: &str) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
+    socket.bind_device(Some(uplink.as_bytes()))?;
+
+    // Neighbor Discovery messages are only accepted with a hop limit of 255
+    socket.set_multicast_hops_v6(255)?;
+    socket.set_unicast_hops_v6(255)?;
+    Ok(socket)
+}
+
+/// Wait for a single Neighbor Solicitation, and answer it if it is for an address in one of our prefixes
+fn answer_solicitation(socket: &Socket, prefixes: &[Ipv6Net], mac: [u8; 6]) -> io::Result<()> {
+    // Raw ICMPv6 sockets receive messages without their IPv6 header
+    let mut buffer = [0u8; 1500];
+    let (len, source) = recvfrom::<SockaddrIn6>(socket.as_raw_fd(), &mut buffer)?;
+    let message = &buffer[..len];
+    let Some(source) = source else {
+        return Ok(());
+    };
+
+    // Ignore everything that isn't a solicitation for one of our addresses
+    if len < 24 || message[0] != NEIGHBOR_SOLICITATION || message[1] != 0 {
+        return Ok(());
+    }
+    let target = Ipv6Addr::from(<[u8; 16]>::try_from(&message[8..24]).unwrap());
+    if target.is_multicast() || !prefixes.iter().any(|prefix| prefix.contains(&target)) {
+        return Ok(());
+    }
+
+    // Only attract traffic while it can be routed to the TUN interface (a standby has no routes)
+    if !protomask_metrics::health::HealthReport::current().routes_installed {
+        return Ok(());
+    }
+    log::trace!("Answering Neighbor Solicitation for {}", target);
+
+    // Duplicate Address Detection probes come from the unspecified address, and are answered to all nodes
+    let (destination, flags) = if source.ip().is_unspecified() {
+        (
+            Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1),
+            FLAG_ROUTER | FLAG_OVERRIDE,
+        )
+    } else {
+        (source.ip(), FLAG_ROUTER | FLAG_SOLICITED | FLAG_OVERRIDE)
+    };
+
+    // The kernel fills in the checksum
+    let mut advertisement = [0u8; 32];
+    advertisement[0] = NEIGHBOR_ADVERTISEMENT;
+    advertisement[4] = flags;
+    advertisement[8..24].copy_from_slice(&target.octets());
+    advertisement[24] = OPTION_TARGET_LINK_LAYER_ADDRESS;
+    advertisement[25] = 1;
+    advertisement[26..].copy_from_slice(&mac);
+
+    sendto(
+        socket.as_raw_fd(),
+        &advertisement,
+        &SockaddrIn6::from(SocketAddrV6::new(destination, 0, 0, source.scope_id())),
+        MsgFlags::empty(),
+    )?;
+    Ok(())
+}
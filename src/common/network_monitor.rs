@@ -0,0 +1,263 @@
+//! Automatic CLAT activation
+//!
+//! A CLAT is only useful on networks that have a NAT64 but no native IPv4. When roaming between networks, the monitor
+//! re-checks both whenever links, addresses, or routes change (and regularly, since DNS64 appearing or disappearing
+//! doesn't change anything locally), installing the CLAT's IPv4 routes and enabling translation only while needed.
+//!
+//! A NAT64 is detected as described in RFC7050: by looking up `ipv4only.arpa`, which only has IPv4 addresses, and
+//! checking whether any IPv6 addresses were synthesized for it. The synthesized addresses also reveal the NAT64's
+//! prefix, which the CLAT can follow as it changes. A prefix announced by a router in its Router Advertisements
+//! (RFC8781) takes priority over the lookup, which is then skipped.
+
+use super::{interface, pref64::Pref64};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use rfc6052::{embed_ipv4_addr_unchecked, extract_ipv4_addr, ALLOWED_PREFIX_LENS};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// The well-known name that only has IPv4 addresses (RFC7050)
+const WELL_KNOWN_NAME: &str = "ipv4only.arpa";
+
+/// The addresses `ipv4only.arpa` resolves to
+const WELL_KNOWN_ADDRESSES: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// How often to check for a NAT64 even if nothing has changed
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for the network to settle after a change before checking it
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Everything the monitor needs to switch the CLAT on and off, and to change its prefix
+pub struct MonitoredClat {
+    pub interface: String,
+    pub customer_pool: Vec<Ipv4Net>,
+    /// IPv4 routes towards the CLAT, installed only while it is enabled
+    pub ipv4_routes: Vec<IpNet>,
+    pub mtu: u32,
+    pub route_metric: Option<u32>,
+    pub configure_netlink: bool,
+    /// Whether to switch the CLAT on and off
+    pub auto: bool,
+    /// Whether to follow the network's NAT64 prefix
+    pub follow_prefix: bool,
+    /// Packets are only translated while this is set
+    pub enabled: Arc<AtomicBool>,
+    /// Prefix IPv4 addresses are currently embedded in
+    pub plat_prefix: Arc<RwLock<Ipv6Net>>,
+    /// Woken to check the network again right away, such as when the PLAT stops answering probes
+    pub rediscover: Arc<Notify>,
+    /// NAT64 prefix announced by routers, if Router Advertisements are being listened to
+    pub pref64: Option<Arc<Pref64>>,
+}
+
+/// Get the IPv6 routes towards a CLAT, one for each customer prefix embedded in the PLAT prefix
+pub fn customer_routes(customer_pool: &[Ipv4Net], plat_prefix: Ipv6Net) -> Vec<IpNet> {
+    customer_pool
+        .iter()
+        .map(|customer_prefix| {
+            IpNet::V6(unsafe {
+                Ipv6Net::new(
+                    embed_ipv4_addr_unchecked(customer_prefix.addr(), plat_prefix),
+                    plat_prefix.prefix_len() + customer_prefix.prefix_len(),
+                )
+                .unwrap_unchecked()
+            })
+        })
+        .collect()
+}
+
+/// Enable the CLAT while the network needs it and disable it otherwise, and keep its prefix up to date (as configured)
+pub async fn monitor_network(clat: MonitoredClat) {
+    let mut watcher = match rtnl::monitor::ChangeWatcher::new() {
+        Ok(watcher) => Some(watcher),
+        Err(error) => {
+            log::warn!(
+                "Can't watch for network changes, so only checking every {:?}: {}",
+                PROBE_INTERVAL,
+                error
+            );
+            None
+        }
+    };
+
+    let mut active = clat.enabled.load(Ordering::Relaxed);
+    loop {
+        // Look around the network. A NAT64 only matters to a CLAT that would be enabled, or that follows its prefix.
+        let native_ipv4 = has_native_ipv4(&clat.interface);
+        let plat_prefix = match clat.pref64.as_ref().and_then(|pref64| pref64.prefix()) {
+            Some(prefix) => Some(prefix),
+            None if native_ipv4 && !clat.follow_prefix => None,
+            None => discover_plat_prefix().await,
+        };
+        log::debug!(
+            "Native IPv4: {}, NAT64 prefix: {:?}",
+            native_ipv4,
+            plat_prefix
+        );
+
+        // Move to a new prefix
+        if let (true, Some(prefix)) = (clat.follow_prefix, plat_prefix) {
+            let current = *clat.plat_prefix.read().unwrap();
+            if prefix != current {
+                match set_plat_prefix(&clat, current, prefix).await {
+                    Ok(()) => log::info!("NAT64 prefix changed from {} to {}", current, prefix),
+                    Err(error) => log::warn!("Failed to change the NAT64 prefix: {}", error),
+                }
+            }
+        }
+
+        // Switch on or off
+        let needed = !native_ipv4 && plat_prefix.is_some();
+        if clat.auto && needed != active {
+            match set_active(&clat, needed).await {
+                Ok(()) => {
+                    active = needed;
+                    log::info!(
+                        "{} the CLAT ({})",
+                        if needed { "Enabled" } else { "Disabled" },
+                        match plat_prefix {
+                            Some(prefix) => format!("found a NAT64 at {}", prefix),
+                            None if native_ipv4 => "native IPv4 is available".to_string(),
+                            None => "no NAT64 was found".to_string(),
+                        }
+                    );
+                }
+                Err(error) => log::warn!("Failed to switch the CLAT: {}", error),
+            }
+        }
+
+        // Wait for something to change, to be asked to check again, or for the next probe to be due
+        match &mut watcher {
+            Some(changes) => {
+                tokio::select! {
+                    changed = changes.changed() => {
+                        if !changed {
+                            log::warn!(
+                                "Stopped receiving network changes, so only checking every {:?}",
+                                PROBE_INTERVAL
+                            );
+                            watcher = None;
+                            continue;
+                        }
+
+                        // Changes usually come in bursts
+                        while let Ok(true) =
+                            tokio::time::timeout(SETTLE_TIME, changes.changed()).await
+                        {}
+                    }
+                    () = clat.rediscover.notified() => {}
+                    () = tokio::time::sleep(PROBE_INTERVAL) => {}
+                }
+            }
+            None => {
+                tokio::select! {
+                    () = clat.rediscover.notified() => {}
+                    () = tokio::time::sleep(PROBE_INTERVAL) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Install or withdraw the CLAT's IPv4 routes, and enable or disable translation to match
+async fn set_active(clat: &MonitoredClat, active: bool) -> Result<(), String> {
+    // Stop translating before the routes go away, and only start again once they are back
+    if !active {
+        clat.enabled.store(false, Ordering::Relaxed);
+    }
+    if clat.configure_netlink {
+        interface::set_routes(
+            &clat.interface,
+            &clat.ipv4_routes,
+            clat.mtu,
+            clat.route_metric,
+            active,
+        )
+        .await?;
+    }
+    clat.enabled.store(active, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Route the customer pool through a new prefix, and start embedding addresses in it
+async fn set_plat_prefix(
+    clat: &MonitoredClat,
+    current: Ipv6Net,
+    prefix: Ipv6Net,
+) -> Result<(), String> {
+    // Return traffic may use either prefix while switching over
+    if clat.configure_netlink {
+        interface::set_routes(
+            &clat.interface,
+            &customer_routes(&clat.customer_pool, prefix),
+            clat.mtu,
+            clat.route_metric,
+            true,
+        )
+        .await?;
+    }
+    *clat.plat_prefix.write().unwrap() = prefix;
+    if clat.configure_netlink {
+        interface::set_routes(
+            &clat.interface,
+            &customer_routes(&clat.customer_pool, current),
+            clat.mtu,
+            clat.route_metric,
+            false,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Check if any interface other than ours has an IPv4 default route
+fn has_native_ipv4(interface: &str) -> bool {
+    let Ok(data) = std::fs::read_to_string("/proc/net/route") else {
+        return false;
+    };
+
+    // Each line (after the header) is `<name> <destination> <gateway> <flags> ... <mask> ...`, with numbers in hex
+    data.lines().skip(1).any(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        fields.len() > 7
+            && fields[0] != interface
+            && fields[1] == "00000000"
+            && fields[7] == "00000000"
+    })
+}
+
+/// Find the NAT64 prefix of the network we are on, if it has one
+pub async fn discover_plat_prefix() -> Option<Ipv6Net> {
+    let addresses = match tokio::net::lookup_host((WELL_KNOWN_NAME, 0)).await {
+        Ok(addresses) => addresses,
+        Err(error) => {
+            log::debug!("Failed to look up {}: {}", WELL_KNOWN_NAME, error);
+            return None;
+        }
+    };
+
+    // Synthesized addresses have one of the well-known addresses embedded in them.
+    // Longer prefixes are more common, so they are tried first.
+    addresses
+        .filter_map(|address| match address {
+            SocketAddr::V6(address) => Some(*address.ip()),
+            SocketAddr::V4(_) => None,
+        })
+        .find_map(|address| {
+            ALLOWED_PREFIX_LENS.iter().rev().find_map(|&prefix_len| {
+                extract_ipv4_addr(address, prefix_len)
+                    .ok()
+                    .filter(|embedded| WELL_KNOWN_ADDRESSES.contains(embedded))
+                    .and_then(|_| Ipv6Net::new(address, prefix_len).ok())
+                    .map(|prefix| prefix.trunc())
+            })
+        })
+}
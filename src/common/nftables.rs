@@ -0,0 +1,344 @@
+//! Kernel-side protection of the translator using nftables
+//!
+//! Every packet routed into the TUN interface costs a trip through userspace, even when it will only be dropped there.
+//! When enabled, an nftables table is installed to turn away the most common of these before they reach the
+//! interface: traffic for pool addresses that aren't mapped to anyone, and floods of ICMP.
+//!
+//! The set of mapped addresses is kept up to date from mapping events. New addresses are added to it before the packet
+//! that created their mapping is translated, since replies to it would otherwise be dropped. Expired addresses are
+//! removed in batches by a background thread, and if an address could not be added the whole set is rebuilt from the
+//! address tables. The table is removed
+//! again when protomask exits on SIGINT or SIGTERM, and replaced if a previous run left it behind.
+
+use super::prefix_tables::PrefixTables;
+use cfg_if::cfg_if;
+use ipnet::Ipv4Net;
+use std::{net::Ipv4Addr, sync::Arc};
+
+/// Everything needed to protect one translator
+pub struct Protection {
+    /// Interface IPv6 traffic is routed to
+    pub interface: String,
+    /// Interface pool traffic is routed to, if not the same one
+    pub ipv4_interface: Option<String>,
+    /// Pool prefixes to drop unmapped traffic for
+    pub pools: Vec<Ipv4Net>,
+    /// Pool addresses to always let through, even though they aren't mapped
+    pub always_allowed: Vec<Ipv4Addr>,
+    /// ICMP and ICMPv6 packets per second allowed towards the translator, or 0 for no limit
+    pub icmp_rate: u32,
+    pub prefix_tables: Arc<PrefixTables>,
+}
+
+cfg_if! {
+    if #[cfg(feature = "nftables")] {
+        use fast_nat::MappingEvent;
+        use std::{
+            collections::HashSet,
+            io::Write,
+            process::{Command, Stdio},
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Mutex,
+            },
+            time::Duration,
+        };
+        use tokio::signal::unix::{signal, SignalKind};
+
+        /// How often the background thread removes expired addresses from the set
+        const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+        /// Tables installed by this process, to remove on exit
+        static INSTALLED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        /// Name of the nftables table protecting a translator
+        fn table_name(interface: &str) -> String {
+            let name: String = interface
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            format!("protomask_{}", name)
+        }
+
+        /// Build an nftables script (re)creating the table for a translator
+        fn ruleset(protection: &Protection) -> String {
+            let table = table_name(&protection.interface);
+            let mut interfaces = vec![format!("\"{}\"", protection.interface)];
+            let pool_interface = match &protection.ipv4_interface {
+                Some(name) => {
+                    interfaces.push(format!("\"{}\"", name));
+                    name
+                }
+                None => &protection.interface,
+            };
+            let pools = protection
+                .pools
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            // Packets for the translator are either forwarded to it or sent by local processes
+            let mut rules = Vec::new();
+            if !pools.is_empty() {
+                rules.push(format!(
+                    "oifname \"{}\" ip daddr @pool ip daddr != @mapped counter drop",
+                    pool_interface
+                ));
+            }
+            if protection.icmp_rate > 0 {
+                rules.push(format!(
+                    "oifname {{ {} }} meta l4proto {{ icmp, ipv6-icmp }} limit rate over {}/second counter drop",
+                    interfaces.join(", "),
+                    protection.icmp_rate
+                ));
+            }
+            let rules = rules.join("\n        ");
+
+            let mut script = String::new();
+            // Declaring the table first means deleting it can't fail, even if it doesn't exist yet
+            script += &format!("table inet {table}\ndelete table inet {table}\n");
+            script += &format!("table inet {table} {{\n");
+            script += "    set pool {\n        type ipv4_addr\n        flags interval\n";
+            if !pools.is_empty() {
+                script += &format!("        elements = {{ {} }}\n", pools);
+            }
+            script += "    }\n    set mapped {\n        type ipv4_addr\n    }\n";
+            for (chain, hook) in [("forward", "forward"), ("output", "output")] {
+                script += &format!(
+                    "    chain {chain} {{\n        type filter hook {hook} priority filter - 10; policy accept;\n        {rules}\n    }}\n"
+                );
+            }
+            script += "}\n";
+            script
+        }
+
+        /// What is known about the set of mapped addresses
+        #[derive(Default)]
+        struct MappedSet {
+            /// Addresses in the set
+            current: HashSet<Ipv4Addr>,
+            /// Addresses whose mappings have expired, but that are still in the set
+            expired: HashSet<Ipv4Addr>,
+        }
+
+        /// Handle used to keep the set of mapped addresses up to date
+        #[derive(Clone)]
+        pub struct ProtectionRules {
+            table: Arc<String>,
+            mapped: Arc<Mutex<MappedSet>>,
+            /// Set when the set may be missing mapped addresses, so it must be rebuilt
+            out_of_sync: Arc<AtomicBool>,
+        }
+
+        impl ProtectionRules {
+            /// Install the rules protecting a translator, and start keeping them up to date
+            pub fn install(protection: Protection) -> Result<Self, String> {
+                let table = table_name(&protection.interface);
+                run_nft(&ruleset(&protection))?;
+                INSTALLED.lock().unwrap().push(table.clone());
+                log::info!("Installed nftables table inet {}", table);
+
+                let rules = Self {
+                    table: Arc::new(table),
+                    mapped: Arc::new(Mutex::new(MappedSet::default())),
+                    out_of_sync: Arc::new(AtomicBool::new(true)),
+                };
+                {
+                    let rules = rules.clone();
+                    std::thread::Builder::new()
+                        .name("nftables".to_string())
+                        .spawn(move || rules.follow_mappings(&protection))
+                        .map_err(|error| error.to_string())?;
+                }
+                Ok(rules)
+            }
+
+            /// Apply a mapping change to the set of mapped addresses.
+            ///
+            /// New addresses are added before this returns, so that replies to the first translated packet aren't
+            /// dropped. Expired addresses are removed later by the background thread, all at once.
+            pub fn notify(&self, event: MappingEvent) {
+                let mut mapped = self.mapped.lock().unwrap();
+                match event {
+                    MappingEvent::Created { ipv4, .. } => {
+                        mapped.expired.remove(&ipv4);
+                        if mapped.current.contains(&ipv4) {
+                            return;
+                        }
+                        match run_nft(&element_command("add", &self.table, [ipv4])) {
+                            Ok(()) => {
+                                mapped.current.insert(ipv4);
+                            }
+                            Err(error) => {
+                                log::warn!("Failed to add {} to the nftables set of mapped addresses: {}", ipv4, error);
+                                self.out_of_sync.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    MappingEvent::Expired { ipv4, .. } => {
+                        if mapped.current.contains(&ipv4) {
+                            mapped.expired.insert(ipv4);
+                        }
+                    }
+                }
+            }
+
+            /// Keep the set of mapped addresses up to date for as long as protomask runs
+            fn follow_mappings(&self, protection: &Protection) {
+                loop {
+                    // Rebuild the set from scratch if an address may be missing (and at first, to pick up existing
+                    // mappings). The address tables are read before taking the lock, since they are locked before it
+                    // when mappings are created.
+                    if self.out_of_sync.swap(false, Ordering::Relaxed) {
+                        let tables: HashSet<_> = protection
+                            .prefix_tables
+                            .tables()
+                            .flat_map(|table| {
+                                table
+                                    .lock()
+                                    .unwrap()
+                                    .mappings()
+                                    .map(|(ipv4, ..)| ipv4)
+                                    .collect::<Vec<_>>()
+                            })
+                            .collect();
+
+                        // Addresses added since the tables were read are kept
+                        let mut mapped = self.mapped.lock().unwrap();
+                        let wanted: HashSet<_> = tables
+                            .into_iter()
+                            .chain(mapped.current.iter().copied())
+                            .chain(protection.always_allowed.iter().copied())
+                            .collect();
+                        let mut script = format!("flush set inet {} mapped\n", self.table);
+                        script.push_str(&element_command("add", &self.table, wanted.iter().copied()));
+                        match run_nft(&script) {
+                            Ok(()) => mapped.current = wanted,
+                            Err(error) => {
+                                log::warn!("Failed to rebuild the nftables set of mapped addresses: {}", error);
+                                self.out_of_sync.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+
+                    // Remove whatever has expired since, all at once
+                    let mut mapped = self.mapped.lock().unwrap();
+                    let expired: Vec<_> = std::mem::take(&mut mapped.expired)
+                        .into_iter()
+                        .filter(|ipv4| !protection.always_allowed.contains(ipv4))
+                        .collect();
+                    if expired.is_empty() {
+                        continue;
+                    }
+                    match run_nft(&element_command("delete", &self.table, expired.iter().copied())) {
+                        Ok(()) => {
+                            for ipv4 in &expired {
+                                mapped.current.remove(ipv4);
+                            }
+                        }
+                        Err(error) => {
+                            log::warn!("Failed to update the nftables set of mapped addresses: {}", error);
+                            mapped.expired.extend(expired);
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Build a command adding or deleting addresses from the set of mapped addresses
+        fn element_command(
+            verb: &str,
+            table: &str,
+            addresses: impl IntoIterator<Item = Ipv4Addr>,
+        ) -> String {
+            let addresses = addresses
+                .into_iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if addresses.is_empty() {
+                return String::new();
+            }
+            format!("{} element inet {} mapped {{ {} }}\n", verb, table, addresses)
+        }
+
+        /// Run an nftables script as a single transaction
+        fn run_nft(script: &str) -> Result<(), String> {
+            let mut child = Command::new("nft")
+                .args(["-f", "-"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|error| format!("Failed to run nft: {}", error))?;
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(script.as_bytes())
+                .map_err(|error| format!("Failed to write to nft: {}", error))?;
+            let output = child
+                .wait_with_output()
+                .map_err(|error| format!("Failed to run nft: {}", error))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+            }
+        }
+
+        /// Remove every table installed by this process
+        pub fn remove_installed() {
+            for table in INSTALLED.lock().unwrap().drain(..) {
+                match run_nft(&format!("delete table inet {}\n", table)) {
+                    Ok(()) => log::info!("Removed nftables table inet {}", table),
+                    Err(error) => log::warn!("Failed to remove nftables table inet {}: {}", table, error),
+                }
+            }
+        }
+
+        /// Remove the installed tables and exit when interrupted. SIGTERM is left alone when draining handles it.
+        pub async fn remove_on_shutdown(handle_sigterm: bool) {
+            // Listening for a signal replaces its default action, so SIGTERM is only listened for when handled here
+            let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+            let mut terminate = handle_sigterm.then(|| signal(SignalKind::terminate()).unwrap());
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                Some(_) = async {
+                    match &mut terminate {
+                        Some(terminate) => terminate.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+            }
+            remove_installed();
+            std::process::exit(0);
+        }
+    } else {
+        use fast_nat::MappingEvent;
+
+        /// nftables rules are not available in this build
+        #[derive(Clone)]
+        pub enum ProtectionRules {}
+
+        impl ProtectionRules {
+            /// Always fails, since this build can't install nftables rules
+            pub fn install(_protection: Protection) -> Result<Self, String> {
+                Err("This build of protomask does not support nftables rules. Rebuild with the `nftables` feature to enable them.".to_string())
+            }
+
+            /// Queue a mapping change to be applied to the set of mapped addresses
+            pub fn notify(&self, _event: MappingEvent) {
+                match *self {}
+            }
+        }
+
+        /// Nothing is ever installed, so there is nothing to remove
+        pub fn remove_installed() {}
+
+        /// Nothing is ever installed, so there is nothing to remove on shutdown
+        pub async fn remove_on_shutdown(_handle_sigterm: bool) {}
+    }
+}
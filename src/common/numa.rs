@@ -0,0 +1,97 @@
+//! Placement of packet handling threads on a NUMA node
+//!
+//! On machines with more than one socket, reaching memory attached to another socket is slower, and packets arrive in
+//! memory near the NIC that received them. When configured, every thread that handles packets is pinned to the CPUs of
+//! a single node (usually the NIC's), and prefers that node's memory for anything it allocates. Each thread allocates
+//! its own packet buffer after being placed, so buffers end up local to the CPUs using them.
+
+use nix::{libc, sched::CpuSet, unistd::Pid};
+
+/// Linux's `MPOL_PREFERRED` memory policy: allocate from the given node when it has memory to spare
+const MPOL_PREFERRED: libc::c_int = 1;
+
+/// Where to run packet handling threads
+#[derive(Debug, Clone)]
+pub struct NumaPlacement {
+    node: usize,
+    cpus: CpuSet,
+}
+
+impl NumaPlacement {
+    /// Place threads on a node, by its number
+    pub fn for_node(node: usize) -> Result<Self, std::io::Error> {
+        let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+        let list = std::fs::read_to_string(&path)?;
+        let mut cpus = CpuSet::new();
+        for cpu in parse_cpu_list(&list)? {
+            cpus.set(cpu)?;
+        }
+        Ok(Self { node, cpus })
+    }
+
+    /// Place threads on the node a network interface's device is attached to.
+    /// Returns `None` if the interface isn't attached to a particular node, such as on single-socket machines.
+    pub fn for_interface(interface: &str) -> Result<Option<Self>, std::io::Error> {
+        let path = format!("/sys/class/net/{}/device/numa_node", interface);
+        let node = std::fs::read_to_string(&path)?;
+        match node.trim().parse::<isize>() {
+            Ok(node) if node >= 0 => Self::for_node(node.unsigned_abs()).map(Some),
+            Ok(_) => Ok(None),
+            Err(error) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid NUMA node in {}: {}", path, error),
+            )),
+        }
+    }
+
+    /// Get the node threads are placed on
+    pub fn node(&self) -> usize {
+        self.node
+    }
+
+    /// Pin the calling thread to the node's CPUs, and prefer the node's memory for its allocations
+    pub fn apply(&self) {
+        if let Err(error) = nix::sched::sched_setaffinity(Pid::from_raw(0), &self.cpus) {
+            log::warn!("Failed to pin thread to NUMA node {}: {}", self.node, error);
+        }
+
+        // The node mask is a bitmap of `unsigned long`s, and the kernel expects one more than the number of bits in it
+        let bits_per_word = libc::c_ulong::BITS as usize;
+        let mut nodemask = vec![0 as libc::c_ulong; self.node / bits_per_word + 1];
+        nodemask[self.node / bits_per_word] |= 1 << (self.node % bits_per_word);
+        let maxnode = nodemask.len() * bits_per_word + 1;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_set_mempolicy,
+                MPOL_PREFERRED,
+                nodemask.as_ptr(),
+                maxnode,
+            )
+        };
+        if result < 0 {
+            log::warn!(
+                "Failed to prefer memory from NUMA node {}: {}",
+                self.node,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Parse a kernel CPU list, such as `0-7,16-23`
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>, std::io::Error> {
+    let invalid = |part: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid CPU list entry: {}", part),
+        )
+    };
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let first: usize = first.parse().map_err(|_| invalid(part))?;
+        let last: usize = last.parse().map_err(|_| invalid(part))?;
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
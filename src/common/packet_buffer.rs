@@ -0,0 +1,151 @@
+//! Packet buffers sized to fit the interface MTU
+//!
+//! A packet read into a buffer smaller than itself is cut short, so buffers are sized from the MTU of the interfaces
+//! being read, with room to spare for a virtio-net header. The MTU is checked again every few seconds, and workers grow
+//! their buffers to match when it is raised (for example, to start using jumbo frames) without a restart. A packet
+//! that arrives before its worker has noticed the change is dropped, and that worker's buffer grows to fit the next one.
+//!
+//! Buffers may instead be given a fixed size, to save memory on small devices. Packets that don't fit are dropped.
+
+use easy_tun::Tun;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Room left at the end of buffers for a virtio-net header (`struct virtio_net_hdr_mrg_rxbuf`)
+pub const VNET_HEADER_ROOM: usize = 12;
+
+/// How often interface MTUs are checked for changes
+const MTU_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Follows the largest MTU of a set of interfaces
+pub struct InterfaceMtu {
+    tuns: Vec<Arc<Tun>>,
+    mtu: AtomicUsize,
+}
+
+impl InterfaceMtu {
+    pub fn new(tuns: Vec<Arc<Tun>>) -> Self {
+        let mtu = largest_mtu(&tuns).unwrap();
+        Self {
+            tuns,
+            mtu: AtomicUsize::new(mtu),
+        }
+    }
+
+    /// Get the largest MTU of the interfaces, as of the last check
+    pub fn get(&self) -> usize {
+        self.mtu.load(Ordering::Relaxed)
+    }
+
+    /// Check the interfaces' MTUs for changes, forever
+    pub async fn follow(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(MTU_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            match largest_mtu(&self.tuns) {
+                Ok(mtu) => {
+                    let previous = self.mtu.swap(mtu, Ordering::Relaxed);
+                    if mtu != previous {
+                        log::info!("Interface MTU changed from {} to {}", previous, mtu);
+                    }
+                }
+                Err(error) => log::warn!("Failed to check interface MTU: {}", error),
+            }
+        }
+    }
+}
+
+/// Get the largest MTU of some interfaces
+fn largest_mtu(tuns: &[Arc<Tun>]) -> Result<usize, std::io::Error> {
+    let mut largest = 0;
+    for tun in tuns {
+        largest = largest.max(tun.mtu()? as usize);
+    }
+    Ok(largest)
+}
+
+/// How packet buffers are sized
+#[derive(Clone)]
+pub enum BufferSize {
+    /// Fit any packet on the interfaces, following changes to their MTU
+    Mtu(Arc<InterfaceMtu>),
+    /// Always this many bytes
+    Fixed(usize),
+}
+
+impl BufferSize {
+    /// Create a buffer of this size
+    pub fn buffer(&self) -> PacketBuffer {
+        match self {
+            Self::Mtu(mtu) => PacketBuffer {
+                data: vec![0u8; mtu.get() + VNET_HEADER_ROOM],
+                fixed: false,
+            },
+            Self::Fixed(size) => PacketBuffer {
+                data: vec![0u8; *size],
+                fixed: true,
+            },
+        }
+    }
+
+    /// Grow a buffer to fit any packet on the interfaces, if their MTU was raised
+    pub fn fit(&self, buffer: &mut PacketBuffer) {
+        if let Self::Mtu(mtu) = self {
+            buffer.fit(mtu.get() + VNET_HEADER_ROOM);
+        }
+    }
+}
+
+/// A reusable buffer to read packets into. Unless it has a fixed size, it only ever grows.
+pub struct PacketBuffer {
+    data: Vec<u8>,
+    fixed: bool,
+}
+
+impl PacketBuffer {
+    /// Note that a packet of `len` bytes was dropped because it didn't fit, growing so that the next one does
+    pub fn dropped_oversized(&mut self, len: usize) {
+        if self.fixed {
+            log::debug!(
+                "Dropped a {} byte packet larger than the {} byte packet buffer",
+                len,
+                self.data.len()
+            );
+        } else {
+            log::warn!(
+                "Dropped a {} byte packet larger than the {} byte packet buffer",
+                len,
+                self.data.len()
+            );
+            self.fit(len + VNET_HEADER_ROOM);
+        }
+    }
+
+    /// Grow to hold at least `len` bytes, unless the buffer has a fixed size
+    pub fn fit(&mut self, len: usize) {
+        if !self.fixed && len > self.data.len() {
+            log::debug!("Growing packet buffer from {} to {} bytes", self.data.len(), len);
+            self.data.resize(len, 0);
+        }
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
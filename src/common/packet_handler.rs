@@ -37,6 +37,34 @@ pub fn get_ipv6_src_dst(packet: &[u8]) -> (Ipv6Addr, Ipv6Addr) {
     (source_addr, destination_addr)
 }
 
+/// A destination that stands for a group of hosts rather than a single one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupDestination {
+    /// The IPv4 limited broadcast address
+    Broadcast,
+    /// An IPv4 or IPv6 multicast group
+    Multicast,
+}
+
+/// Check if an IPv4 or IPv6 packet is addressed to a group of hosts
+pub fn get_group_destination(packet: &[u8]) -> Option<GroupDestination> {
+    match get_layer_3_proto(packet)? {
+        4 if packet.len() >= 20 => {
+            let (_, destination) = get_ipv4_src_dst(packet);
+            if destination.is_broadcast() {
+                Some(GroupDestination::Broadcast)
+            } else {
+                destination.is_multicast().then_some(GroupDestination::Multicast)
+            }
+        }
+        6 if packet.len() >= 40 => {
+            let (_, destination) = get_ipv6_src_dst(packet);
+            destination.is_multicast().then_some(GroupDestination::Multicast)
+        }
+        _ => None,
+    }
+}
+
 /// Appropriately handle a translation error
 pub fn handle_translation_error(
     result: Result<Option<Vec<u8>>, PacketHandlingError>,
@@ -58,6 +86,17 @@ pub fn handle_translation_error(
                 );
                 None
             }
+            PacketHandlingError::InterprotoError(interproto::error::Error::PacketTooLong {
+                maximum,
+                actual,
+            }) => {
+                log::warn!(
+                    "Got packet that would be {} bytes once translated, more than the maximum of {} bytes",
+                    actual,
+                    maximum
+                );
+                None
+            }
             PacketHandlingError::InterprotoError(
                 interproto::error::Error::UnsupportedIcmpType(icmp_type),
             ) => {
@@ -73,6 +112,10 @@ pub fn handle_translation_error(
                 );
                 None
             }
+            PacketHandlingError::InterprotoError(interproto::error::Error::FragmentedPacket) => {
+                log::debug!("Got a fragmented IPv6 packet, which can't be translated");
+                None
+            }
             PacketHandlingError::FastNatError(fast_nat::error::Error::Ipv4PoolExhausted) => {
                 log::warn!("IPv4 pool exhausted. Dropping packet.");
                 None
@@ -81,6 +124,38 @@ pub fn handle_translation_error(
                 log::warn!("Invalid IPv4 address: {}", addr);
                 None
             }
+            PacketHandlingError::FastNatError(fast_nat::error::Error::Ipv4AddressInUse(addr)) => {
+                log::warn!("IPv4 address already in use: {}", addr);
+                None
+            }
+            PacketHandlingError::FastNatError(fast_nat::error::Error::NotEnoughPortBlocks {
+                ..
+            }) => {
+                log::warn!("Not enough port blocks. Dropping packet.");
+                None
+            }
+            PacketHandlingError::FastNatError(fast_nat::error::Error::EmptyPortBlocks) => {
+                log::warn!("Port blocks are empty. Dropping packet.");
+                None
+            }
+            PacketHandlingError::FastNatError(fast_nat::error::Error::UnknownSubscriber(addr)) => {
+                log::warn!("Not a subscriber: {}", addr);
+                None
+            }
+            PacketHandlingError::FastNatError(fast_nat::error::Error::PortsExhausted(addr)) => {
+                log::warn!("No free ports left for {}. Dropping packet.", addr);
+                None
+            }
+            PacketHandlingError::FastNatError(fast_nat::error::Error::MappingLimitReached) => {
+                log::debug!("Mapping limit reached. Dropping packet.");
+                None
+            }
+            PacketHandlingError::FastNatError(fast_nat::error::Error::SessionLimitReached(
+                addr,
+            )) => {
+                log::debug!("Session limit reached for {}. Dropping packet.", addr);
+                None
+            }
         },
     }
 }
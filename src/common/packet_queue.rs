@@ -0,0 +1,226 @@
+//! Bounded queues between the stages of packet processing
+//!
+//! By default, each worker reads a packet, translates it, and writes it out before reading the next one, so a slow
+//! write holds up reading and packets back up in the kernel's TUN queue. When queues are enabled, reading and writing
+//! happen on threads of their own, connected to the worker by queues of a fixed capacity. Once a queue is full, a
+//! packet is dropped to make room according to the configured [`DropPolicy`], rather than blocking the stage before
+//! it. Queue depths and drops are exported as the `protomask_channel_depth` and `protomask_channel_dropped` metrics.
+//!
+//! Each queue keeps a pool of spare buffers, so packets passing through it don't each need an allocation.
+
+use super::{
+    busy_poll::TunReader,
+    numa::NumaPlacement,
+    packet_buffer::{BufferSize, PacketBuffer},
+};
+use easy_tun::Tun;
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Which packet is dropped when a queue is full
+#[derive(
+    Debug,
+    serde::Deserialize,
+    serde::Serialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DropPolicy {
+    /// Drop the packet that has waited longest, favouring fresh traffic
+    Oldest,
+    /// Drop the packet that just arrived (tail drop)
+    #[default]
+    Newest,
+}
+
+/// A bounded first-in, first-out queue of packets
+pub struct PacketQueue {
+    /// Label used for this queue's metrics
+    name: &'static str,
+    capacity: usize,
+    policy: DropPolicy,
+    /// Most spare buffers to keep for reuse
+    pool_size: usize,
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+/// Everything guarded by the `PacketQueue` lock
+struct QueueState {
+    packets: VecDeque<Vec<u8>>,
+    /// Buffers of packets that have left the queue, ready to hold new ones
+    spare: Vec<Vec<u8>>,
+}
+
+impl PacketQueue {
+    pub fn new(name: &'static str, capacity: usize, policy: DropPolicy, pool_size: usize) -> Self {
+        Self {
+            name,
+            capacity,
+            policy,
+            pool_size,
+            state: Mutex::new(QueueState {
+                packets: VecDeque::with_capacity(capacity),
+                spare: Vec::with_capacity(pool_size),
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Copy a packet into the queue. This never blocks, and returns `false` if a packet had to be dropped.
+    pub fn push(&self, packet: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let accepted = if state.packets.len() < self.capacity {
+            protomask_metrics::metrics::CHANNEL_DEPTH
+                .with_label_values(&[self.name])
+                .inc();
+            let mut buffer = state.spare.pop().unwrap_or_default();
+            buffer.clear();
+            buffer.extend_from_slice(packet);
+            state.packets.push_back(buffer);
+            true
+        } else {
+            protomask_metrics::metrics::CHANNEL_DROPPED
+                .with_label_values(&[self.name])
+                .inc();
+            if self.policy == DropPolicy::Oldest {
+                let mut buffer = state.packets.pop_front().unwrap();
+                buffer.clear();
+                buffer.extend_from_slice(packet);
+                state.packets.push_back(buffer);
+            }
+            false
+        };
+        drop(state);
+        self.ready.notify_one();
+        accepted
+    }
+
+    /// Wait for the next packet and hand it to `f`, after which its buffer is reused
+    pub fn pop_with<T>(&self, f: impl FnOnce(&[u8]) -> T) -> T {
+        let packet = {
+            let mut state = self
+                .ready
+                .wait_while(self.state.lock().unwrap(), |state| state.packets.is_empty())
+                .unwrap();
+            protomask_metrics::metrics::CHANNEL_DEPTH
+                .with_label_values(&[self.name])
+                .dec();
+            state.packets.pop_front().unwrap()
+        };
+        let result = f(&packet);
+
+        let mut state = self.state.lock().unwrap();
+        if state.spare.len() < self.pool_size {
+            state.spare.push(packet);
+        }
+        result
+    }
+}
+
+/// Where a worker reads packets from
+pub enum PacketSource {
+    /// Straight from a TUN queue
+    Tun(TunReader),
+    /// From a queue filled by a reader thread
+    Queue(Arc<PacketQueue>),
+}
+
+impl PacketSource {
+    /// Wait for the next packet, copying it into `buffer` and returning its length.
+    ///
+    /// A packet too large for `buffer` is dropped, and `buffer` grows so the next one fits, unless its size is fixed.
+    pub fn read(&self, buffer: &mut PacketBuffer) -> usize {
+        match self {
+            Self::Tun(reader) => reader.read(buffer),
+            Self::Queue(queue) => loop {
+                let len = queue.pop_with(|packet| {
+                    if let Some(destination) = buffer.get_mut(..packet.len()) {
+                        destination.copy_from_slice(packet);
+                    }
+                    packet.len()
+                });
+                if len <= buffer.len() {
+                    return len;
+                }
+                buffer.dropped_oversized(len);
+            },
+        }
+    }
+}
+
+/// Where a worker writes packets to
+#[derive(Clone)]
+pub enum PacketSink {
+    /// Straight to a TUN queue
+    Tun { tun: Arc<Tun>, queue_id: usize },
+    /// To a queue drained by a writer thread
+    Queue(Arc<PacketQueue>),
+}
+
+impl PacketSink {
+    /// Send a packet on its way, returning `false` if a packet had to be dropped
+    pub fn write(&self, packet: &[u8]) -> bool {
+        match self {
+            Self::Tun { tun, queue_id } => {
+                tun.fd(*queue_id).unwrap().write_all(packet).unwrap();
+                true
+            }
+            Self::Queue(queue) => queue.push(packet),
+        }
+    }
+}
+
+/// Spawn a thread that moves packets read by `reader` into `queue`, calling `on_read` with whether each was accepted.
+/// If given a NUMA placement, the thread runs there.
+pub fn spawn_reader(
+    reader: TunReader,
+    buffer_size: BufferSize,
+    queue: Arc<PacketQueue>,
+    numa: Option<NumaPlacement>,
+    on_read: impl Fn(bool) + Send + 'static,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        log::debug!(
+            "Starting reader thread for queue {} of {}",
+            reader.queue_id(),
+            reader.tun().name()
+        );
+        if let Some(numa) = &numa {
+            numa.apply();
+        }
+        let mut buffer = buffer_size.buffer();
+        loop {
+            buffer_size.fit(&mut buffer);
+            let len = reader.read(&mut buffer);
+            on_read(queue.push(&buffer[..len]));
+        }
+    })
+}
+
+/// Spawn a thread that writes the packets from `queue` to a TUN queue.
+/// If given a NUMA placement, the thread runs there.
+pub fn spawn_writer(
+    tun: Arc<Tun>,
+    queue_id: usize,
+    queue: Arc<PacketQueue>,
+    numa: Option<NumaPlacement>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        log::debug!("Starting writer thread for queue {} of {}", queue_id, tun.name());
+        if let Some(numa) = &numa {
+            numa.apply();
+        }
+        loop {
+            queue.pop_with(|packet| tun.fd(queue_id).unwrap().write_all(packet).unwrap());
+        }
+    })
+}
@@ -0,0 +1,126 @@
+//! Sampled tracing of individual packets
+//!
+//! For one in every so many packets, a `tracing` span is opened while the packet is handled. Each stage of processing
+//! emits an event with the time it took, and a final event summarizes the packet's addresses, what became of it, and
+//! how long it took overall. This is meant for chasing latency anomalies in production without logging every packet.
+//!
+//! Unless another `tracing` subscriber has been installed, spans and events are passed on to the regular logger under
+//! the `protomask::packet_trace` target.
+
+use super::{
+    packet_handler::{get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto},
+    stage_timer::StageSampler,
+};
+use std::time::Instant;
+use tracing::Span;
+
+/// Decides which packets handled by a worker get traced. Each worker thread has its own.
+pub struct PacketTracer {
+    sampler: StageSampler,
+    queue_id: usize,
+}
+
+impl PacketTracer {
+    /// Trace roughly `sample_rate` (0 to 1) of the packets read from a queue
+    pub fn new(sample_rate: f64, queue_id: usize) -> Self {
+        Self {
+            sampler: StageSampler::new(sample_rate),
+            queue_id,
+        }
+    }
+
+    /// Called as a packet is read. Returns a trace if this packet should be traced.
+    pub fn start(&mut self, len: usize) -> Option<PacketTrace> {
+        if !self.sampler.sample() {
+            return None;
+        }
+        let now = Instant::now();
+        Some(PacketTrace {
+            span: tracing::info_span!(
+                target: "protomask::packet_trace",
+                "packet",
+                queue = self.queue_id,
+                len
+            ),
+            started: now,
+            last: now,
+            input: None,
+            output: None,
+        })
+    }
+}
+
+/// The trace of a single packet. The summary is emitted when it is dropped.
+pub struct PacketTrace {
+    span: Span,
+    started: Instant,
+    last: Instant,
+    input: Option<String>,
+    output: Option<String>,
+}
+
+impl PacketTrace {
+    /// Emit an event with the time since the previous stage ended (or the packet was read)
+    pub fn stage(&mut self, stage: &str) {
+        let now = Instant::now();
+        let elapsed_us = now.duration_since(self.last).as_micros();
+        self.last = now;
+        self.span.in_scope(|| {
+            tracing::info!(target: "protomask::packet_trace", stage, elapsed_us);
+        });
+    }
+
+    /// Note the addresses of the packet that was read
+    pub fn input(&mut self, packet: &[u8]) {
+        self.input = Some(describe(packet));
+    }
+
+    /// Note the addresses of the packet that was sent
+    pub fn output(&mut self, packet: &[u8]) {
+        self.output = Some(describe(packet));
+    }
+}
+
+impl Drop for PacketTrace {
+    fn drop(&mut self) {
+        let total_us = self.started.elapsed().as_micros();
+        let input = self.input.as_deref().unwrap_or("unparsed");
+        let outcome = if self.output.is_some() {
+            "sent"
+        } else {
+            "dropped"
+        };
+        let output = self.output.as_deref().unwrap_or("none");
+        self.span.in_scope(|| {
+            tracing::info!(
+                target: "protomask::packet_trace",
+                input,
+                output,
+                outcome,
+                total_us
+            );
+        });
+    }
+}
+
+/// Record the end of a stage, if the packet is being traced
+pub fn trace_stage(trace: &mut Option<PacketTrace>, stage: &str) {
+    if let Some(trace) = trace {
+        trace.stage(stage);
+    }
+}
+
+/// Summarize the addresses and protocol of a packet
+pub fn describe(packet: &[u8]) -> String {
+    match get_layer_3_proto(packet) {
+        Some(4) if packet.len() >= 20 => {
+            let (source, destination) = get_ipv4_src_dst(packet);
+            format!("{} -> {} (protocol {})", source, destination, packet[9])
+        }
+        Some(6) if packet.len() >= 40 => {
+            let (source, destination) = get_ipv6_src_dst(packet);
+            format!("{} -> {} (next header {})", source, destination, packet[6])
+        }
+        _ => format!("{} bytes", packet.len()),
+    }
+}
@@ -0,0 +1,218 @@
+//! Minimal reading and writing of classic pcap files
+//!
+//! Files are always written with the raw IP link type, so both IPv4 and IPv6 can share one file. When reading, the
+//! common link layers (Ethernet, Linux cooked capture, BSD loopback) are stripped so that only IP packets are returned.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// pcap magic number (microsecond timestamps)
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// pcap magic number (nanosecond timestamps)
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+/// Largest packet that will be captured in full
+const SNAPLEN: u32 = 65535;
+
+/// Link types, and the size of their headers where fixed
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// Ethertypes that may wrap IP packets
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+/// Writes IP packets to a pcap file
+pub struct PcapWriter {
+    writer: BufWriter<File>,
+}
+
+impl PcapWriter {
+    /// Create (or truncate) a pcap file
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        // Global pcap header
+        writer.write_all(&PCAP_MAGIC.to_ne_bytes())?;
+        writer.write_all(&2u16.to_ne_bytes())?;
+        writer.write_all(&4u16.to_ne_bytes())?;
+        writer.write_all(&0i32.to_ne_bytes())?;
+        writer.write_all(&0u32.to_ne_bytes())?;
+        writer.write_all(&SNAPLEN.to_ne_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_ne_bytes())?;
+        writer.flush()?;
+
+        Ok(Self { writer })
+    }
+
+    /// Append a packet, timestamped with the time since the unix epoch
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn write(&mut self, packet: &[u8], timestamp: Duration) -> io::Result<()> {
+        let captured_len = packet.len().min(SNAPLEN as usize);
+
+        // Per-packet record header followed by the packet itself
+        self.writer
+            .write_all(&(timestamp.as_secs() as u32).to_ne_bytes())?;
+        self.writer
+            .write_all(&timestamp.subsec_micros().to_ne_bytes())?;
+        self.writer
+            .write_all(&(captured_len as u32).to_ne_bytes())?;
+        self.writer
+            .write_all(&(packet.len() as u32).to_ne_bytes())?;
+        self.writer.write_all(&packet[..captured_len])
+    }
+
+    /// Make everything written so far visible to readers of the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads IP packets from a pcap file
+pub struct PcapReader {
+    reader: BufReader<File>,
+    /// The file was written on a machine of the opposite endianness
+    swapped: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl PcapReader {
+    /// Open a pcap file, checking that its link type is one we can get IP packets out of
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+
+        // The magic number tells us both the byte order and the timestamp resolution
+        let magic = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+        let (swapped, nanos) = match magic {
+            PCAP_MAGIC => (false, false),
+            PCAP_MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == PCAP_MAGIC => (true, false),
+            _ if magic.swap_bytes() == PCAP_MAGIC_NANOS => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Not a pcap file (pcapng is not supported)",
+                ))
+            }
+        };
+        let mut reader = Self {
+            reader,
+            swapped,
+            nanos,
+            linktype: 0,
+        };
+        reader.linktype = reader.u32_at(&header, 20) & 0x0fff_ffff;
+        if ![
+            LINKTYPE_NULL,
+            LINKTYPE_ETHERNET,
+            LINKTYPE_RAW,
+            LINKTYPE_LINUX_SLL,
+            LINKTYPE_IPV4,
+            LINKTYPE_IPV6,
+            LINKTYPE_LINUX_SLL2,
+        ]
+        .contains(&reader.linktype)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported link type {}", reader.linktype),
+            ));
+        }
+        Ok(reader)
+    }
+
+    /// Read the next packet and its timestamp, with any link layer header removed.
+    ///
+    /// Frames that don't carry IP (such as ARP) are returned empty, so that packet numbers still line up with the file.
+    pub fn next_packet(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+        let seconds = self.u32_at(&header, 0);
+        let fraction = self.u32_at(&header, 4);
+        let captured_len = self.u32_at(&header, 8);
+        if captured_len > SNAPLEN * 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Packet record of {} bytes is too large", captured_len),
+            ));
+        }
+        let mut frame = vec![0u8; captured_len as usize];
+        self.reader.read_exact(&mut frame)?;
+
+        let timestamp = Duration::new(
+            u64::from(seconds),
+            if self.nanos {
+                fraction
+            } else {
+                fraction * 1000
+            }
+            .min(999_999_999),
+        );
+        Ok(Some((timestamp, self.strip_link_layer(frame))))
+    }
+
+    /// Remove the link layer header from a frame, leaving only the IP packet
+    fn strip_link_layer(&self, mut frame: Vec<u8>) -> Vec<u8> {
+        let offset = match self.linktype {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(0),
+            LINKTYPE_NULL => Some(4),
+            LINKTYPE_LINUX_SLL => ethertype_offset(&frame, 14, 16),
+            LINKTYPE_LINUX_SLL2 => ethertype_offset(&frame, 0, 20),
+            LINKTYPE_ETHERNET => {
+                // Skip over any VLAN tags
+                let mut offset = 12;
+                while matches!(
+                    read_u16(&frame, offset),
+                    Some(ETHERTYPE_VLAN | ETHERTYPE_QINQ)
+                ) {
+                    offset += 4;
+                }
+                ethertype_offset(&frame, offset, offset + 2)
+            }
+            _ => None,
+        };
+        match offset {
+            Some(offset) if offset <= frame.len() => frame.split_off(offset),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Read a header field in the file's byte order
+    fn u32_at(&self, data: &[u8], offset: usize) -> u32 {
+        let value = u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+}
+
+/// Get where the payload starts if the ethertype at `ethertype` is IPv4 or IPv6
+fn ethertype_offset(frame: &[u8], ethertype: usize, payload: usize) -> Option<usize> {
+    matches!(read_u16(frame, ethertype), Some(0x0800 | 0x86dd)).then_some(payload)
+}
+
+/// Read a big-endian u16, if the frame is long enough
+fn read_u16(frame: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        frame.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
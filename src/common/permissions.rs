@@ -1,4 +1,11 @@
 use nix::unistd::Uid;
+use std::{
+    fs::{DirBuilder, Permissions},
+    io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::Path,
+};
+use tokio::net::UnixListener;
 
 /// Ensures the binary is being exxecuted as root
 pub fn ensure_root() {
@@ -7,3 +14,39 @@ pub fn ensure_root() {
         std::process::exit(1);
     }
 }
+
+/// Listen on a unix socket that only root may connect to.
+///
+/// The socket is bound inside a directory only root can enter, and only moved to `path` once its permissions have
+/// been narrowed, so it is never reachable with the permissions the umask would give it.
+pub fn bind_private_socket(path: &Path) -> io::Result<UnixListener> {
+    // Clean up after a previous run that didn't exit cleanly
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Socket path has no file name")
+    })?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let bind = || {
+        let staged = staging.join("socket");
+        let listener = std::os::unix::net::UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener)
+    };
+    let result = bind();
+    std::fs::remove_dir_all(&staging)?;
+    result
+}
@@ -0,0 +1,103 @@
+//! PLAT reachability probes
+//!
+//! A CLAT can look perfectly healthy while the NAT64 behind it has stopped working, or has moved to a new prefix
+//! without DNS64 having caught up. When configured, an IPv4 anchor is pinged through the PLAT prefix at a regular
+//! interval, exactly as translated traffic would reach it. After enough consecutive failures, the CLAT reports itself
+//! as not ready and (when following the network's prefix) asks the network monitor to look for the prefix again.
+//! The first answered probe marks it as ready again.
+
+use super::upstream_health::ping;
+use ipnet::Ipv6Net;
+use rfc6052::embed_ipv4_addr_unchecked;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// How to probe the PLAT
+pub struct PlatProbes {
+    /// IPv4 address that must answer pings sent through the PLAT prefix
+    pub anchor: Ipv4Addr,
+    pub interval: Duration,
+    /// How long to wait for each answer
+    pub timeout: Duration,
+    /// Consecutive failed probes before the PLAT is considered unreachable
+    pub failures: u32,
+    /// Prefix the anchor is embedded in
+    pub plat_prefix: Arc<RwLock<Ipv6Net>>,
+    /// Probes are skipped while this is cleared
+    pub enabled: Arc<AtomicBool>,
+    /// Woken to look for the PLAT prefix again once the PLAT is unreachable
+    pub rediscover: Option<Arc<Notify>>,
+}
+
+/// Probe the PLAT forever, reporting its reachability
+pub async fn monitor(probes: PlatProbes) {
+    let mut interval = tokio::time::interval(probes.interval);
+    let mut reachable = true;
+    let mut streak = 0;
+    let mut sequence: u16 = 0;
+    loop {
+        interval.tick().await;
+
+        // A disabled CLAT has nothing to reach
+        if !probes.enabled.load(Ordering::Relaxed) {
+            streak = 0;
+            if !reachable {
+                reachable = true;
+                protomask_metrics::health::set_upstream_healthy(true);
+            }
+            continue;
+        }
+        sequence = sequence.wrapping_add(1);
+
+        // Ping the anchor through whichever prefix is currently in use
+        let plat_prefix = *probes.plat_prefix.read().unwrap();
+        let target = unsafe { embed_ipv4_addr_unchecked(probes.anchor, plat_prefix) };
+        let timeout = probes.timeout;
+        let result =
+            tokio::task::spawn_blocking(move || ping(IpAddr::V6(target), sequence, timeout))
+                .await
+                .unwrap();
+        match result {
+            Ok(rtt) => {
+                log::trace!("PLAT probe {} answered in {:?}", target, rtt);
+                protomask_metrics::metric!(PLAT_PROBE_COUNTER, RESULT_ANSWERED).inc();
+                protomask_metrics::metrics::PLAT_PROBE_RTT_SECONDS.observe(rtt.as_secs_f64());
+                streak = 0;
+                if !reachable {
+                    reachable = true;
+                    log::info!("The PLAT at {} is reachable again", plat_prefix);
+                    protomask_metrics::health::set_upstream_healthy(true);
+                }
+            }
+            Err(error) => {
+                log::debug!("PLAT probe {} failed: {}", target, error);
+                protomask_metrics::metric!(PLAT_PROBE_COUNTER, RESULT_FAILED).inc();
+                streak += 1;
+
+                // Keep looking for a new prefix for as long as the PLAT can't be reached
+                if streak >= probes.failures {
+                    streak = 0;
+                    if reachable {
+                        reachable = false;
+                        log::warn!(
+                            "The PLAT at {} has not answered {} probes in a row",
+                            plat_prefix,
+                            probes.failures
+                        );
+                        protomask_metrics::health::set_upstream_healthy(false);
+                    }
+                    if let Some(rediscover) = &probes.rediscover {
+                        rediscover.notify_one();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! Scriptable policy hooks
+//!
+//! When configured, a [Rhai](https://rhai.rs) script is consulted to make policy decisions that are awkward to express
+//! in the config. A script may define either or both of these functions:
+//!
+//! - `on_new_mapping(source, prefix)` is called whenever an IPv6 source without a mapping sends a packet through a
+//!   translation prefix. Returning `true` (or nothing) leaves the choice of address to the pool, `false` refuses the
+//!   source, and a string naming an IPv4 address or a pool prefix maps the source to that address, or to a free
+//!   address inside that prefix.
+//! - `classify(packet)` is called for every packet before it is translated, with a map holding its `family`, `source`,
+//!   `destination`, `protocol`, `source_port` and `destination_port` (ports are `()` when the packet has none).
+//!   Returning `false` drops the packet.
+//!
+//! A script that fails or answers with something unexpected is reported and ignored, so that a bug in it doesn't
+//! take down the NAT64. Scripting is only available when protomask is built with the `scripting` feature.
+
+use super::prefix_tables::AddressTable;
+use cfg_if::cfg_if;
+use ipnet::Ipv6Net;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+cfg_if! {
+    if #[cfg(feature = "scripting")] {
+        use ipnet::Ipv4Net;
+        use rhai::{Dynamic, Engine, Map, Scope, AST};
+        use std::{
+            net::IpAddr,
+            path::Path,
+            sync::atomic::{AtomicU64, Ordering},
+        };
+
+        /// What the script decided for a new source
+        #[derive(Debug, PartialEq, Eq)]
+        enum Decision {
+            Pool,
+            Deny,
+            Address(Ipv4Addr),
+            Prefix(Ipv4Net),
+        }
+
+        /// The parts of a packet that are shown to `classify`
+        #[derive(Debug)]
+        struct PacketSummary {
+            source: IpAddr,
+            destination: IpAddr,
+            protocol: u8,
+            ports: Option<(u16, u16)>,
+        }
+
+        impl PacketSummary {
+            /// Summarize an IPv4 or IPv6 packet. IPv6 extension headers are not followed, so packets carrying them are
+            /// reported with the protocol of the first extension header and no ports.
+            fn parse(packet: &[u8]) -> Option<Self> {
+                let (source, destination, protocol, payload): (IpAddr, IpAddr, _, _) =
+                    match packet.first()? >> 4 {
+                        4 if packet.len() >= 20 => {
+                            let header_len = usize::from(packet[0] & 0x0f) * 4;
+                            let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+                            (
+                                <[u8; 4]>::try_from(&packet[12..16]).unwrap().into(),
+                                <[u8; 4]>::try_from(&packet[16..20]).unwrap().into(),
+                                packet[9],
+                                // Only the first fragment has ports
+                                (fragment_offset == 0)
+                                    .then(|| packet.get(header_len..))
+                                    .flatten(),
+                            )
+                        }
+                        6 if packet.len() >= 40 => (
+                            <[u8; 16]>::try_from(&packet[8..24]).unwrap().into(),
+                            <[u8; 16]>::try_from(&packet[24..40]).unwrap().into(),
+                            packet[6],
+                            packet.get(40..),
+                        ),
+                        _ => return None,
+                    };
+
+                // Only TCP and UDP have ports
+                let ports = payload
+                    .filter(|payload| matches!(protocol, 6 | 17) && payload.len() >= 4)
+                    .map(|payload| {
+                        (
+                            u16::from_be_bytes([payload[0], payload[1]]),
+                            u16::from_be_bytes([payload[2], payload[3]]),
+                        )
+                    });
+                Some(Self {
+                    source,
+                    destination,
+                    protocol,
+                    ports,
+                })
+            }
+        }
+
+        /// Turn a script's answer to `on_new_mapping` into a decision
+        fn parse_decision(answer: &str) -> Result<Decision, String> {
+            if let Ok(address) = answer.parse() {
+                return Ok(Decision::Address(address));
+            }
+            answer
+                .parse()
+                .map(Decision::Prefix)
+                .map_err(|_| format!("{:?} is neither an IPv4 address nor a prefix", answer))
+        }
+
+        /// Most operations a single call into the script may take, so that a runaway script can't stall a worker
+        const MAX_OPERATIONS: u64 = 100_000;
+
+        /// A loaded policy script
+        pub struct PolicyScript {
+            engine: Engine,
+            ast: AST,
+            has_on_new_mapping: bool,
+            has_classify: bool,
+            /// Number of times `classify` has failed
+            classify_errors: AtomicU64,
+        }
+
+        impl PolicyScript {
+            /// Compile a script, making sure it defines at least one hook
+            pub fn load(path: &Path) -> Result<Self, String> {
+                let mut engine = Engine::new();
+                engine.set_max_operations(MAX_OPERATIONS);
+                let ast = engine
+                    .compile_file(path.to_path_buf())
+                    .map_err(|error| format!("{}: {}", path.display(), error))?;
+
+                let defines = |name: &str, params: usize| {
+                    ast.iter_functions()
+                        .any(|function| function.name == name && function.params.len() == params)
+                };
+                let has_on_new_mapping = defines("on_new_mapping", 2);
+                let has_classify = defines("classify", 1);
+                if !has_on_new_mapping && !has_classify {
+                    return Err(format!(
+                        "{} defines neither on_new_mapping(source, prefix) nor classify(packet)",
+                        path.display()
+                    ));
+                }
+                Ok(Self {
+                    engine,
+                    ast,
+                    has_on_new_mapping,
+                    has_classify,
+                    classify_errors: AtomicU64::new(0),
+                })
+            }
+
+            /// Ask the script whether a packet may be translated
+            pub fn classify(&self, packet: &[u8]) -> bool {
+                if !self.has_classify {
+                    return true;
+                }
+                let Some(summary) = PacketSummary::parse(packet) else {
+                    return true;
+                };
+
+                let mut map = Map::new();
+                map.insert("family".into(), Dynamic::from_int(if summary.source.is_ipv4() { 4 } else { 6 }));
+                map.insert("source".into(), summary.source.to_string().into());
+                map.insert("destination".into(), summary.destination.to_string().into());
+                map.insert("protocol".into(), Dynamic::from_int(summary.protocol.into()));
+                let (source_port, destination_port) = summary.ports.map_or((Dynamic::UNIT, Dynamic::UNIT), |(source, destination)| {
+                    (Dynamic::from_int(source.into()), Dynamic::from_int(destination.into()))
+                });
+                map.insert("source_port".into(), source_port);
+                map.insert("destination_port".into(), destination_port);
+
+                match self
+                    .engine
+                    .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, "classify", (map,))
+                    .map_err(|error| error.to_string())
+                    .and_then(|answer| {
+                        answer
+                            .as_bool()
+                            .map_err(|kind| format!("classify returned {} rather than a bool", kind))
+                    }) {
+                    Ok(accept) => accept,
+                    Err(error) => {
+                        // Only complain occasionally, since this can happen for every packet
+                        let errors = self.classify_errors.fetch_add(1, Ordering::Relaxed);
+                        if errors.is_multiple_of(1000) {
+                            log::warn!(
+                                "Policy script failed to classify a packet ({} failure(s) so far), accepting it: {}",
+                                errors + 1,
+                                error
+                            );
+                        }
+                        true
+                    }
+                }
+            }
+
+            /// Ask the script what to do with a new source
+            fn decide_mapping(&self, source: Ipv6Addr, prefix: Ipv6Net) -> Result<Decision, String> {
+                if !self.has_on_new_mapping {
+                    return Ok(Decision::Pool);
+                }
+                let answer = self
+                    .engine
+                    .call_fn::<Dynamic>(
+                        &mut Scope::new(),
+                        &self.ast,
+                        "on_new_mapping",
+                        (source.to_string(), prefix.to_string()),
+                    )
+                    .map_err(|error| error.to_string())?;
+
+                if answer.is_unit() {
+                    Ok(Decision::Pool)
+                } else if let Ok(accept) = answer.as_bool() {
+                    Ok(if accept { Decision::Pool } else { Decision::Deny })
+                } else if let Ok(answer) = answer.into_immutable_string() {
+                    parse_decision(&answer)
+                } else {
+                    Err("on_new_mapping must return a bool, an IPv4 address or a pool prefix".to_string())
+                }
+            }
+        }
+
+        impl PolicyScript {
+            /// Get the IPv4 address for an IPv6 source that was just seen through `prefix`, asking the script about new ones.
+            ///
+            /// Returns `None` when the script leaves the choice to the usual address assignment.
+            pub fn get_or_assign_ipv4(
+                &self,
+                table: &AddressTable,
+                source: Ipv6Addr,
+                prefix: Ipv6Net,
+            ) -> Result<Option<Ipv4Addr>, String> {
+                if let Some(ipv4) = table.lock().unwrap().get_ipv4(&source) {
+                    return Ok(Some(ipv4));
+                }
+
+                let decision = match self.decide_mapping(source, prefix) {
+                    Ok(decision) => decision,
+                    Err(error) => {
+                        log::warn!(
+                            "Policy script failed for {}, using the pool: {}",
+                            source,
+                            error
+                        );
+                        Decision::Pool
+                    }
+                };
+                log::debug!("Policy script decided {:?} for {}", decision, source);
+
+                let mut table = table.lock().unwrap();
+                match decision {
+                    Decision::Pool => Ok(None),
+                    Decision::Deny => Err("Denied by the policy script".to_string()),
+                    Decision::Address(ipv4) => table
+                        .insert_dynamic(ipv4, source)
+                        .map(|()| Some(ipv4))
+                        .map_err(|error| {
+                            format!("Can't use the address from the policy script: {}", error)
+                        }),
+                    Decision::Prefix(pool) => table
+                        .get_or_create_ipv4_in(&source, pool)
+                        .map(Some)
+                        .map_err(|error| format!("Can't use the pool from the policy script: {}", error)),
+                }
+            }
+        }
+    } else {
+        use std::path::Path;
+
+        /// Policy scripts are not available in this build
+        pub enum PolicyScript {}
+
+        impl PolicyScript {
+            /// Always fails, since this build can't run scripts
+            pub fn load(_path: &Path) -> Result<Self, String> {
+                Err("This build of protomask does not support policy scripts. Rebuild with the `scripting` feature to enable them.".to_string())
+            }
+
+            /// Ask the script whether a packet may be translated
+            pub fn classify(&self, _packet: &[u8]) -> bool {
+                match *self {}
+            }
+
+            /// Get the IPv4 address for an IPv6 source that was just seen through `prefix`, asking the script about new ones
+            pub fn get_or_assign_ipv4(
+                &self,
+                _table: &AddressTable,
+                _source: Ipv6Addr,
+                _prefix: Ipv6Net,
+            ) -> Result<Option<Ipv4Addr>, String> {
+                match *self {}
+            }
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! NAT64 prefix discovery from Router Advertisements
+//!
+//! Routers can announce their network's NAT64 prefix in a PREF64 option (RFC8781) of their Router Advertisements,
+//! which is quicker and harder to spoof off-link than looking up `ipv4only.arpa`. When configured, Router
+//! Advertisements received on the uplink are watched for the option. The announced prefix is kept for as long as its
+//! lifetime, and the network monitor is woken up whenever it appears, changes, or is withdrawn.
+
+use ipnet::Ipv6Net;
+use nix::libc;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io, mem,
+    net::Ipv6Addr,
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+/// ICMPv6 type of a Router Advertisement
+const ROUTER_ADVERTISEMENT: u8 = 134;
+
+/// Length of a Router Advertisement before its options (RFC4861 section 4.2)
+const ROUTER_ADVERTISEMENT_LEN: usize = 16;
+
+/// Hop limit of every genuine Neighbor Discovery message. Anything lower has been forwarded by a router.
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// NDP option carrying a NAT64 prefix (RFC8781)
+const OPTION_PREF64: u8 = 38;
+
+/// Prefix lengths, indexed by the option's Prefix Length Code
+const PREFIX_LENS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+
+/// The NAT64 prefix most recently announced by a router, if it is still valid
+pub struct Pref64 {
+    announced: Mutex<Option<(Ipv6Net, Instant)>>,
+    /// Woken whenever the announced prefix changes
+    changed: Arc<Notify>,
+}
+
+impl Pref64 {
+    /// Keep track of announced prefixes, waking `changed` whenever they change
+    pub fn new(changed: Arc<Notify>) -> Self {
+        Self {
+            announced: Mutex::new(None),
+            changed,
+        }
+    }
+
+    /// Get the announced prefix, unless its lifetime has run out
+    pub fn prefix(&self) -> Option<Ipv6Net> {
+        self.announced
+            .lock()
+            .unwrap()
+            .filter(|(_, expires)| Instant::now() < *expires)
+            .map(|(prefix, _)| prefix)
+    }
+
+    /// Record a prefix announced for `lifetime`. A zero lifetime withdraws it.
+    fn announce(&self, prefix: Ipv6Net, lifetime: Duration) {
+        let previous = self.prefix();
+        let mut announced = self.announced.lock().unwrap();
+        if lifetime.is_zero() {
+            if previous == Some(prefix) {
+                *announced = None;
+            }
+        } else {
+            *announced = Some((prefix, Instant::now() + lifetime));
+        }
+        let current = announced.map(|(prefix, _)| prefix);
+        drop(announced);
+
+        if current != previous {
+            match current {
+                Some(prefix) => log::info!("Router announced NAT64 prefix {}", prefix),
+                None => log::info!("Router withdrew NAT64 prefix {}", prefix),
+            }
+            self.changed.notify_one();
+        }
+    }
+}
+
+/// Listen for Router Advertisements on `uplink` for as long as the process runs, recording the PREF64 options in them
+pub fn listen(uplink: String, pref64: Arc<Pref64>) {
+    let socket = match open_socket(&uplink) {
+        Ok(socket) => socket,
+        Err(error) => {
+            log::error!("Failed to open an ICMPv6 socket on {}: {}", uplink, error);
+            return;
+        }
+    };
+    log::info!(
+        "Listening for NAT64 prefixes in Router Advertisements on {}",
+        uplink
+    );
+
+    // The socket is blocking, so it gets its own thread
+    std::thread::Builder::new()
+        .name("pref64-listener".to_string())
+        .spawn(move || loop {
+            match receive_advertisement(&socket) {
+                Ok(Some((prefix, lifetime))) => pref64.announce(prefix, lifetime),
+                Ok(None) => {}
+                Err(error) => log::warn!("Failed to receive Router Advertisement: {}", error),
+            }
+        })
+        .unwrap();
+}
+
+/// Open a raw ICMPv6 socket on the uplink, asking to be told each message's hop limit
+fn open_socket(uplink: &str) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
+    socket.bind_device(Some(uplink.as_bytes()))?;
+    socket.set_recv_hoplimit_v6(true)?;
+    Ok(socket)
+}
+
+/// Wait for a single ICMPv6 message, returning the PREF64 option's prefix and lifetime if it is a Router Advertisement
+/// carrying one
+fn receive_advertisement(socket: &Socket) -> io::Result<Option<(Ipv6Net, Duration)>> {
+    // Raw ICMPv6 sockets receive messages without their IPv6 header
+    let mut buffer = [0u8; 1500];
+    let (len, source, hop_limit) = receive(socket, &mut buffer)?;
+    Ok(parse_advertisement(&buffer[..len], source, hop_limit))
+}
+
+/// Receive a single message, along with its source address and the hop limit it arrived with
+fn receive(
+    socket: &Socket,
+    buffer: &mut [u8],
+) -> io::Result<(usize, Option<Ipv6Addr>, Option<u8>)> {
+    let mut source: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    // Plenty of room for the single hop limit message. Kept in `u64`s so that it is aligned for `cmsghdr`.
+    let mut control = [0u64; 8];
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    header.msg_name = std::ptr::addr_of_mut!(source).cast();
+    header.msg_namelen = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+    header.msg_controllen = mem::size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The kernel passes the hop limit as an `int` in an `IPV6_HOPLIMIT` control message
+    let mut hop_limit = None;
+    let mut message = unsafe { libc::CMSG_FIRSTHDR(&header) };
+    while !message.is_null() {
+        let (level, kind) = unsafe { ((*message).cmsg_level, (*message).cmsg_type) };
+        if level == libc::IPPROTO_IPV6 && kind == libc::IPV6_HOPLIMIT {
+            let value = unsafe {
+                libc::CMSG_DATA(message)
+                    .cast::<libc::c_int>()
+                    .read_unaligned()
+            };
+            hop_limit = u8::try_from(value).ok();
+        }
+        message = unsafe { libc::CMSG_NXTHDR(&header, message) };
+    }
+
+    let source = (source.sin6_family == libc::AF_INET6 as libc::sa_family_t)
+        .then(|| Ipv6Addr::from(source.sin6_addr.s6_addr));
+    Ok((len as usize, source, hop_limit))
+}
+
+/// Check that an ICMPv6 message is a valid Router Advertisement (RFC4861 section 6.1.2), returning its PREF64 option's
+/// prefix and lifetime if it carries one
+fn parse_advertisement(
+    message: &[u8],
+    source: Option<Ipv6Addr>,
+    hop_limit: Option<u8>,
+) -> Option<(Ipv6Net, Duration)> {
+    // Routers always advertise from their link-local address, and the message must not have been forwarded
+    let source = source?;
+    if message.len() < ROUTER_ADVERTISEMENT_LEN
+        || message[0] != ROUTER_ADVERTISEMENT
+        || message[1] != 0
+        || hop_limit != Some(NDP_HOP_LIMIT)
+        || !is_link_local(source)
+    {
+        return None;
+    }
+    find_pref64(&message[ROUTER_ADVERTISEMENT_LEN..])
+}
+
+/// Find the first valid PREF64 option in a list of NDP options
+fn find_pref64(mut options: &[u8]) -> Option<(Ipv6Net, Duration)> {
+    while options.len() >= 8 {
+        // Option lengths are counted in units of 8 bytes, and may not be zero
+        let len = usize::from(options[1]) * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        let (option, rest) = options.split_at(len);
+        options = rest;
+        if option[0] != OPTION_PREF64 || len != 16 {
+            continue;
+        }
+
+        // The lifetime is given in units of 8 seconds, next to a code for the prefix length (RFC8781 section 4)
+        let scaled_lifetime_plc = u16::from_be_bytes([option[2], option[3]]);
+        let Some(&prefix_len) = PREFIX_LENS.get(usize::from(scaled_lifetime_plc & 0x7)) else {
+            log::debug!("Ignoring PREF64 option with an invalid prefix length code");
+            continue;
+        };
+        let lifetime = Duration::from_secs(u64::from(scaled_lifetime_plc >> 3) * 8);
+
+        // Only the top 96 bits of the prefix are sent
+        let mut address = [0u8; 16];
+        address[..12].copy_from_slice(&option[4..16]);
+        let prefix = Ipv6Net::new(Ipv6Addr::from(address), prefix_len)
+            .unwrap()
+            .trunc();
+        return Some((prefix, lifetime));
+    }
+    None
+}
+
+/// Check if an address is inside `fe80::/10`
+fn is_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a PREF64 option for `prefix`, with a raw prefix length code and a lifetime in units of 8 seconds
+    fn pref64_option(prefix: &str, code: u16, scaled_lifetime: u16) -> Vec<u8> {
+        let address: Ipv6Addr = prefix.parse().unwrap();
+        let mut option = vec![OPTION_PREF64, 2];
+        option.extend_from_slice(&(scaled_lifetime << 3 | code).to_be_bytes());
+        option.extend_from_slice(&address.octets()[..12]);
+        option
+    }
+
+    /// Build a Router Advertisement carrying `options`
+    fn advertisement(options: &[u8]) -> Vec<u8> {
+        let mut message = vec![0u8; ROUTER_ADVERTISEMENT_LEN];
+        message[0] = ROUTER_ADVERTISEMENT;
+        message.extend_from_slice(options);
+        message
+    }
+
+    #[test]
+    fn test_find_pref64() {
+        // Skips over other options to find the prefix
+        let mut options = vec![1, 1, 0x02, 0x00, 0x5e, 0x00, 0x53, 0x01];
+        options.extend(pref64_option("64:ff9b::", 0, 225));
+        assert_eq!(
+            find_pref64(&options),
+            Some(("64:ff9b::/96".parse().unwrap(), Duration::from_secs(1800)))
+        );
+
+        // Bits past the prefix length are cleared
+        assert_eq!(
+            find_pref64(&pref64_option("2001:db8:1:2:3::", 3, 1)),
+            Some(("2001:db8:1::/48".parse().unwrap(), Duration::from_secs(8)))
+        );
+    }
+
+    #[test]
+    fn test_find_pref64_truncated() {
+        let option = pref64_option("64:ff9b::", 0, 225);
+        assert_eq!(find_pref64(&option[..12]), None);
+        assert_eq!(find_pref64(&option[..7]), None);
+
+        // An option claiming to be longer than what's left
+        let mut options = vec![1, 4, 0, 0, 0, 0, 0, 0];
+        options.extend(pref64_option("64:ff9b::", 0, 225));
+        assert_eq!(find_pref64(&options), None);
+    }
+
+    #[test]
+    fn test_find_pref64_zero_length() {
+        // A zero-length option would loop forever, so nothing after it can be trusted
+        let mut options = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        options.extend(pref64_option("64:ff9b::", 0, 225));
+        assert_eq!(find_pref64(&options), None);
+    }
+
+    #[test]
+    fn test_find_pref64_invalid_prefix_length_code() {
+        assert_eq!(find_pref64(&pref64_option("64:ff9b::", 6, 225)), None);
+        assert_eq!(find_pref64(&pref64_option("64:ff9b::", 7, 225)), None);
+
+        // A later valid option is still used
+        let mut options = pref64_option("2001:db8::", 7, 225);
+        options.extend(pref64_option("64:ff9b::", 0, 225));
+        assert_eq!(
+            find_pref64(&options),
+            Some(("64:ff9b::/96".parse().unwrap(), Duration::from_secs(1800)))
+        );
+    }
+
+    #[test]
+    fn test_zero_lifetime_withdraws() {
+        let (prefix, lifetime) = find_pref64(&pref64_option("64:ff9b::", 0, 0)).unwrap();
+        assert_eq!(prefix, "64:ff9b::/96".parse::<Ipv6Net>().unwrap());
+        assert!(lifetime.is_zero());
+
+        let pref64 = Pref64::new(Arc::new(Notify::new()));
+        pref64.announce(prefix, Duration::from_secs(1800));
+        assert_eq!(pref64.prefix(), Some(prefix));
+
+        // Withdrawing some other prefix leaves ours alone
+        pref64.announce("2001:db8::/96".parse().unwrap(), lifetime);
+        assert_eq!(pref64.prefix(), Some(prefix));
+
+        pref64.announce(prefix, lifetime);
+        assert_eq!(pref64.prefix(), None);
+    }
+
+    #[test]
+    fn test_parse_advertisement_hop_limit() {
+        let message = advertisement(&pref64_option("64:ff9b::", 0, 225));
+        let router = Some("fe80::1".parse().unwrap());
+        assert!(parse_advertisement(&message, router, Some(255)).is_some());
+        assert_eq!(parse_advertisement(&message, router, Some(254)), None);
+        assert_eq!(parse_advertisement(&message, router, None), None);
+    }
+
+    #[test]
+    fn test_parse_advertisement_source() {
+        let message = advertisement(&pref64_option("64:ff9b::", 0, 225));
+        assert_eq!(
+            parse_advertisement(&message, Some("2001:db8::1".parse().unwrap()), Some(255)),
+            None
+        );
+        assert_eq!(parse_advertisement(&message, None, Some(255)), None);
+    }
+}
@@ -0,0 +1,408 @@
+//! Routing of traffic between RFC6052 translation prefixes and the address tables used to translate it
+//!
+//! Every translation prefix is associated with an address table. Prefixes configured with their own IPv4 pool get a
+//! dedicated table, while all others share the main pool. For shared tables, the prefix each remote IPv4 address was
+//! last reached through from each pool address is remembered, so that return traffic is sourced from the same prefix
+//! the client used to reach that remote, even when the client reaches other remotes through other prefixes.
+//!
+//! Prefixes may also be restricted to a set of IPv6 sources, so that different clients can be steered through
+//! different prefixes (for example, the Well-Known Prefix for most clients and a local-use prefix for others).
+//!
+//! Tenants go further, binding a set of IPv6 sources to a translation prefix and pool of their own in both directions.
+//! Nobody else may use a tenant's prefix, and the tenant's sources may not use any other prefix. Tenants may also be
+//! limited in how many mappings they hold, so that one tenant can't take over resources shared with the others.
+//!
+//! Pool prefixes can be added to the main pool and drained out of any pool at runtime.
+
+use fast_nat::CrossProtocolNetworkAddressTableWithIpv4Pool;
+use ipnet::{Ipv4Net, Ipv6Net};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of locks the reply prefixes are spread over, so that workers rarely wait on each other
+const REPLY_PREFIX_SHARDS: usize = 64;
+
+/// A shareable address table
+pub type AddressTable = Arc<Mutex<CrossProtocolNetworkAddressTableWithIpv4Pool>>;
+
+/// A single address table and the traffic it is responsible for
+struct TableEntry {
+    table: AddressTable,
+    /// Copy of the table's pool, so lookups don't need to lock the table
+    pool: RwLock<Vec<Ipv4Net>>,
+    /// Every translation prefix using this table. The first is used when no better choice is known.
+    prefixes: Vec<Ipv6Net>,
+    /// Prefix each remote was last reached through from each pool address (only tracked when there is more than one
+    /// prefix)
+    reply_prefixes: ReplyPrefixes,
+}
+
+/// Prefix a remote was last reached through from a pool address, and when, keyed by pool address and remote
+type ReplyPrefixShard = Mutex<HashMap<(Ipv4Addr, Ipv4Addr), (Ipv6Net, Instant)>>;
+
+/// Prefixes to source return traffic from
+struct ReplyPrefixes {
+    shards: Vec<ReplyPrefixShard>,
+}
+
+impl ReplyPrefixes {
+    fn new() -> Self {
+        Self {
+            shards: (0..REPLY_PREFIX_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    /// Find the shard holding a pair of addresses
+    fn shard(&self, pool: Ipv4Addr, remote: Ipv4Addr) -> &ReplyPrefixShard {
+        let hash = (u32::from(pool) ^ u32::from(remote).rotate_left(16)).wrapping_mul(0x9e37_79b9);
+        &self.shards[hash as usize % REPLY_PREFIX_SHARDS]
+    }
+
+    fn get(&self, pool: Ipv4Addr, remote: Ipv4Addr) -> Option<Ipv6Net> {
+        self.shard(pool, remote)
+            .lock()
+            .unwrap()
+            .get(&(pool, remote))
+            .map(|(prefix, _)| *prefix)
+    }
+
+    fn insert(&self, pool: Ipv4Addr, remote: Ipv4Addr, prefix: Ipv6Net) {
+        self.shard(pool, remote)
+            .lock()
+            .unwrap()
+            .insert((pool, remote), (prefix, Instant::now()));
+    }
+
+    /// Forget pairs that haven't been used for longer than `timeout`
+    fn prune(&self, timeout: Duration) {
+        for shard in &self.shards {
+            shard
+                .lock()
+                .unwrap()
+                .retain(|_, (_, last_used)| last_used.elapsed() < timeout);
+        }
+    }
+}
+
+/// A customer whose sources are kept to a translation prefix of their own
+pub struct Tenant {
+    pub name: String,
+    pub prefix: Ipv6Net,
+    pub sources: Vec<Ipv6Net>,
+    /// Most mappings the tenant may hold at once
+    pub max_mappings: Option<usize>,
+    /// Most sessions of shared addresses the tenant may have open at once
+    pub max_sessions: Option<usize>,
+}
+
+/// All translation prefixes and their address tables
+pub struct PrefixTables {
+    entries: Vec<TableEntry>,
+    /// IPv6 sources allowed to use each restricted prefix. Prefixes not listed here may be used by anyone.
+    allowed_sources: HashMap<Ipv6Net, Vec<Ipv6Net>>,
+    tenants: Vec<Tenant>,
+    /// Pool prefixes that no longer accept new mappings, and are removed once their mappings are gone
+    draining: Mutex<Vec<Ipv4Net>>,
+    /// Set while no new mappings may be made anywhere, such as while handing over to a new process
+    frozen: AtomicBool,
+    /// How long a remote's prefix is remembered without being used
+    timeout: Duration,
+}
+
+impl PrefixTables {
+    /// Build the tables. `additional_prefixes` without a pool share `main_pool` with `main_prefix`.
+    pub fn new(
+        main_prefix: Ipv6Net,
+        main_pool: &[Ipv4Net],
+        additional_prefixes: &[(Ipv6Net, Vec<Ipv4Net>)],
+        timeout: Duration,
+    ) -> Self {
+        let new_entry = |pool: &[Ipv4Net], prefix: Ipv6Net| TableEntry {
+            table: Arc::new(Mutex::new(
+                CrossProtocolNetworkAddressTableWithIpv4Pool::new(pool, timeout),
+            )),
+            pool: RwLock::new(pool.to_vec()),
+            prefixes: vec![prefix],
+            reply_prefixes: ReplyPrefixes::new(),
+        };
+
+        let mut entries = vec![new_entry(main_pool, main_prefix)];
+        for (prefix, pool) in additional_prefixes {
+            if pool.is_empty() {
+                entries[0].prefixes.push(*prefix);
+            } else {
+                entries.push(new_entry(pool, *prefix));
+            }
+        }
+
+        Self {
+            entries,
+            allowed_sources: HashMap::new(),
+            tenants: Vec::new(),
+            draining: Mutex::new(Vec::new()),
+            frozen: AtomicBool::new(false),
+            timeout,
+        }
+    }
+
+    /// Only let traffic from `sources` use a translation prefix
+    pub fn restrict_sources(&mut self, prefix: Ipv6Net, sources: &[Ipv6Net]) {
+        if !sources.is_empty() {
+            self.allowed_sources.insert(prefix, sources.to_vec());
+        }
+    }
+
+    /// Keep a tenant's sources to its prefix, and its prefix to its sources.
+    /// The prefix must have been given its own pool when building the tables.
+    pub fn add_tenant(&mut self, tenant: Tenant) {
+        self.restrict_sources(tenant.prefix, &tenant.sources);
+        if let Some(table) = self.table_for_prefix(tenant.prefix) {
+            table.lock().unwrap().set_mapping_limit(tenant.max_mappings);
+        }
+        self.tenants.push(tenant);
+    }
+
+    /// Get every tenant
+    pub fn tenants(&self) -> &[Tenant] {
+        &self.tenants
+    }
+
+    /// Find the tenant an IPv6 source belongs to, if any
+    pub fn tenant_of(&self, source: Ipv6Addr) -> Option<&Tenant> {
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.sources.iter().any(|net| net.contains(&source)))
+    }
+
+    /// Iterate over every address table
+    pub fn tables(&self) -> impl Iterator<Item = &AddressTable> {
+        self.entries.iter().map(|entry| &entry.table)
+    }
+
+    /// Iterate over every translation prefix
+    pub fn prefixes(&self) -> impl Iterator<Item = Ipv6Net> + '_ {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.prefixes.iter().copied())
+    }
+
+    /// Get every pool prefix
+    pub fn pools(&self) -> Vec<Ipv4Net> {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.pool.read().unwrap().clone())
+            .collect()
+    }
+
+    /// Get the pool prefixes that are being drained
+    pub fn draining_pools(&self) -> Vec<Ipv4Net> {
+        self.draining.lock().unwrap().clone()
+    }
+
+    /// Add a prefix to the main pool, making it available for new mappings straight away
+    pub fn add_pool(&self, prefix: Ipv4Net) -> Result<(), String> {
+        if let Some(existing) = self
+            .pools()
+            .into_iter()
+            .find(|existing| existing.contains(&prefix) || prefix.contains(existing))
+        {
+            return Err(format!(
+                "{} overlaps the existing pool {}",
+                prefix, existing
+            ));
+        }
+        let entry = &self.entries[0];
+        entry.table.lock().unwrap().add_pool_prefix(prefix);
+        entry.pool.write().unwrap().push(prefix);
+        Ok(())
+    }
+
+    /// Stop making new mappings in a pool prefix, returning the table it belongs to.
+    /// Existing mappings keep working until they expire.
+    pub fn drain_pool(&self, prefix: Ipv4Net) -> Result<&AddressTable, String> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.pool.read().unwrap().contains(&prefix))
+            .ok_or_else(|| format!("{} is not a pool prefix", prefix))?;
+        let mut draining = self.draining.lock().unwrap();
+        if draining.contains(&prefix) {
+            return Err(format!("{} is already being drained", prefix));
+        }
+        if entry.pool.read().unwrap().len() == 1 {
+            return Err(format!("{} is the last prefix of its pool", prefix));
+        }
+        draining.push(prefix);
+        entry.table.lock().unwrap().exclude(prefix);
+        Ok(&entry.table)
+    }
+
+    /// Stop or resume making new mappings in every table. Existing mappings keep working either way.
+    pub fn set_frozen(&self, frozen: bool) {
+        self.frozen.store(frozen, Ordering::Relaxed);
+    }
+
+    /// Check if new mappings may not be made
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Relaxed)
+    }
+
+    /// Remove a drained prefix from its pool
+    pub fn remove_pool(&self, prefix: Ipv4Net) {
+        self.draining
+            .lock()
+            .unwrap()
+            .retain(|draining| *draining != prefix);
+        for entry in &self.entries {
+            entry
+                .pool
+                .write()
+                .unwrap()
+                .retain(|existing| *existing != prefix);
+            entry.table.lock().unwrap().remove_pool_prefix(prefix);
+        }
+    }
+
+    /// Find the address table responsible for an IPv4 address
+    pub fn table_for_ipv4(&self, ipv4: Ipv4Addr) -> Option<&AddressTable> {
+        self.entry_for_ipv4(ipv4).map(|entry| &entry.table)
+    }
+
+    /// Remove any mappings of either address, so that a new mapping between them doesn't leave a stale one behind
+    pub fn remove_conflicting(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) {
+        for table in self.tables() {
+            Self::remove_conflicting_in(&mut table.lock().unwrap(), ipv4, ipv6);
+        }
+    }
+
+    /// Remove any mappings of either address from a table that is already locked. Every table must be cleaned up
+    /// this way for the result to match [`Self::remove_conflicting`].
+    pub fn remove_conflicting_in(
+        table: &mut CrossProtocolNetworkAddressTableWithIpv4Pool,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+    ) {
+        if let Some(other_ipv4) = table.get_ipv4(&ipv6) {
+            table.remove_ipv4(&other_ipv4);
+        }
+        table.remove_ipv4(&ipv4);
+    }
+
+    /// Find the address table used by a translation prefix
+    pub fn table_for_prefix(&self, prefix: Ipv6Net) -> Option<&AddressTable> {
+        self.entries
+            .iter()
+            .find(|entry| entry.prefixes.contains(&prefix))
+            .map(|entry| &entry.table)
+    }
+
+    /// Find the translation prefix and address table for traffic from an IPv6 source to an IPv6 address.
+    /// When prefixes overlap, the most specific one the source may use wins.
+    #[profiling::function]
+    pub fn match_ipv6(
+        &self,
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+    ) -> Option<(Ipv6Net, &AddressTable)> {
+        let tenant = self.tenant_of(source);
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.prefixes.iter().map(move |prefix| (*prefix, entry)))
+            .filter(|(prefix, _)| prefix.contains(&destination))
+            .filter(|(prefix, _)| {
+                self.allowed_sources
+                    .get(prefix)
+                    .is_none_or(|sources| sources.iter().any(|net| net.contains(&source)))
+            })
+            .filter(|(prefix, _)| tenant.is_none_or(|tenant| tenant.prefix == *prefix))
+            .max_by_key(|(prefix, _)| prefix.prefix_len())
+            .map(|(prefix, entry)| (prefix, &entry.table))
+    }
+
+    /// Find the translation prefix and address table for traffic from a remote IPv4 source to a pool address
+    #[profiling::function]
+    pub fn match_ipv4(
+        &self,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> Option<(Ipv6Net, &AddressTable)> {
+        let entry = self.entry_for_ipv4(destination)?;
+        let prefix = match entry.prefixes.len() {
+            1 => entry.prefixes[0],
+            _ => entry
+                .reply_prefixes
+                .get(destination, source)
+                .unwrap_or(entry.prefixes[0]),
+        };
+        Some((prefix, &entry.table))
+    }
+
+    /// Remember that a pool address reached a remote IPv4 address through a specific prefix
+    #[profiling::function]
+    pub fn record_prefix(&self, ipv4: Ipv4Addr, remote: Ipv4Addr, prefix: Ipv6Net) {
+        if let Some(entry) = self.entry_for_ipv4(ipv4) {
+            if entry.prefixes.len() > 1 {
+                entry.reply_prefixes.insert(ipv4, remote, prefix);
+            }
+        }
+    }
+
+    /// Forget the prefixes of remotes that haven't been reached for longer than the mapping timeout
+    pub fn prune_reply_prefixes(&self) {
+        for entry in self.entries.iter().filter(|entry| entry.prefixes.len() > 1) {
+            entry.reply_prefixes.prune(self.timeout);
+        }
+    }
+
+    /// Find the table entry whose pool contains an IPv4 address
+    fn entry_for_ipv4(&self, ipv4: Ipv4Addr) -> Option<&TableEntry> {
+        self.entries.iter().find(|entry| {
+            entry
+                .pool
+                .read()
+                .unwrap()
+                .iter()
+                .any(|prefix| prefix.contains(&ipv4))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_conflicting() {
+        let tables = PrefixTables::new(
+            "64:ff9b::/96".parse().unwrap(),
+            &["192.0.2.0/24".parse().unwrap()],
+            &[],
+            Duration::from_secs(7200),
+        );
+        let table = tables.tables().next().unwrap();
+        let ipv6 = "2001:db8::1".parse().unwrap();
+        let (old_ipv4, new_ipv4) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        table
+            .lock()
+            .unwrap()
+            .insert_with_ttl(old_ipv4, ipv6, Duration::from_millis(20))
+            .unwrap();
+
+        // Taking over the IPv6 address must not leave the old mapping's timeout behind
+        tables.remove_conflicting(new_ipv4, ipv6);
+        table.lock().unwrap().insert_static(new_ipv4, ipv6).unwrap();
+        std::thread::sleep(Duration::from_millis(40));
+        table.lock().unwrap().prune();
+        assert_eq!(table.lock().unwrap().get_ipv4(&ipv6), Some(new_ipv4));
+        assert_eq!(table.lock().unwrap().get_ipv6(&old_ipv4), None);
+    }
+}
@@ -4,6 +4,13 @@ use crate::args::ProfilerArgs;
 
 cfg_if! {
     if #[cfg(feature = "profiler")] {
+        use std::{
+            path::{Path, PathBuf},
+            sync::{Arc, Mutex},
+            time::{Duration, SystemTime, UNIX_EPOCH},
+        };
+        use tokio::signal::unix::{signal, SignalKind};
+
         pub fn start_puffin_server(args: &ProfilerArgs) -> Option<puffin_http::Server> {
             if let Some(endpoint) = args.puffin_endpoint {
                 log::info!("Starting puffin server on {}", endpoint);
@@ -13,8 +20,77 @@ cfg_if! {
                 None
             }
         }
+
+        /// Write puffin captures to a directory whenever `SIGUSR2` is received (and on a schedule, if configured)
+        pub fn start_puffin_capture(args: &ProfilerArgs) {
+            let Some(directory) = args.puffin_capture_dir.clone() else {
+                return;
+            };
+            log::info!("Writing puffin captures to {} on SIGUSR2", directory.display());
+            puffin::set_scopes_on(true);
+
+            // Keep the recent and slowest frames around until they are written out
+            let frames = Arc::new(Mutex::new(puffin::FrameView::default()));
+            {
+                let frames = Arc::clone(&frames);
+                puffin::GlobalProfiler::lock().add_sink(Box::new(move |frame| {
+                    frames.lock().unwrap().add_frame(frame);
+                }));
+            }
+
+            let mut schedule = args
+                .puffin_capture_interval
+                .map(|interval| tokio::time::interval(Duration::from_secs(interval)));
+            tokio::spawn(async move {
+                let mut signals = signal(SignalKind::user_defined2()).unwrap();
+                loop {
+                    tokio::select! {
+                        _ = signals.recv() => log::info!("Received SIGUSR2. Writing puffin capture"),
+                        () = next_tick(&mut schedule) => {}
+                    }
+
+                    // Each capture only covers what happened since the last one
+                    let capture = std::mem::take(&mut *frames.lock().unwrap());
+                    if capture.is_empty() {
+                        continue;
+                    }
+                    match write_capture(&capture, &directory) {
+                        Ok(path) => log::info!("Wrote puffin capture to {}", path.display()),
+                        Err(error) => log::error!("Failed to write puffin capture: {}", error),
+                    }
+                }
+            });
+        }
+
+        /// Wait for the next scheduled capture, or forever if there is no schedule
+        async fn next_tick(schedule: &mut Option<tokio::time::Interval>) {
+            match schedule {
+                Some(schedule) => {
+                    schedule.tick().await;
+                }
+                None => std::future::pending().await,
+            }
+        }
+
+        /// Write a capture to a new file in `directory`, returning its path
+        fn write_capture(
+            capture: &puffin::FrameView,
+            directory: &Path,
+        ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+            let path = directory.join(format!("protomask-{}.puffin", timestamp));
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+            // The `.puffin` file format: a magic header followed by every frame
+            std::io::Write::write_all(&mut file, b"PUF0")?;
+            for frame in capture.all_uniq() {
+                frame.write_into(&mut file)?;
+            }
+            std::io::Write::flush(&mut file)?;
+            Ok(path)
+        }
     } else {
-        #[allow(dead_code)]
         pub fn start_puffin_server(_args: &ProfilerArgs){}
+
+        pub fn start_puffin_capture(_args: &ProfilerArgs){}
     }
 }
@@ -0,0 +1,136 @@
+//! Reverse DNS zone generation
+//!
+//! Abuse desks and mail servers look up the reverse DNS of the pool addresses they see traffic from. When configured,
+//! protomask regularly writes a zone fragment with a `PTR` record for every pool address, naming the IPv6 client it is
+//! currently mapped to (or giving it a generic name). Owner names are fully qualified, so the fragment can be pulled
+//! into the pool's `in-addr.arpa` zone with `$INCLUDE` and served by any authoritative server.
+
+use super::prefix_tables::PrefixTables;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Hostnames to give pool addresses
+#[derive(Debug, Clone)]
+pub struct HostnameTemplates {
+    /// Name for addresses that are mapped to a client
+    pub mapped: String,
+    /// Name for addresses that aren't mapped. They are left out of the zone if this isn't set.
+    pub unmapped: Option<String>,
+}
+
+impl HostnameTemplates {
+    /// Fill in a template, giving a fully qualified name.
+    /// `{ipv4}` and `{ipv6}` become the addresses with their separators replaced by dashes.
+    pub fn render(template: &str, ipv4: Ipv4Addr, ipv6: Option<Ipv6Addr>) -> String {
+        let ipv4 = ipv4.to_string().replace('.', "-");
+        let ipv6 = ipv6.map_or_else(String::new, |ipv6| {
+            // Fully expanded, so that a label never starts or ends with a dash
+            ipv6.segments()
+                .iter()
+                .map(|segment| format!("{:04x}", segment))
+                .collect::<Vec<_>>()
+                .join("-")
+        });
+        let mut name = template.replace("{ipv4}", &ipv4).replace("{ipv6}", &ipv6);
+        if !name.ends_with('.') {
+            name.push('.');
+        }
+        name
+    }
+
+    /// Check that a template always produces a valid hostname, for mapped addresses or otherwise
+    pub fn validate(template: &str, mapped: bool) -> Result<(), String> {
+        let example = Self::render(
+            template,
+            Ipv4Addr::new(192, 0, 2, 1),
+            mapped.then_some(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        );
+        let labels: Vec<_> = example.trim_end_matches('.').split('.').collect();
+        if labels.iter().any(|label| {
+            label.is_empty()
+                || label.len() > 63
+                || label.starts_with('-')
+                || label.ends_with('-')
+                || !label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }) {
+            return Err(format!("{} is not a valid hostname", example));
+        }
+        if example.len() > 254 {
+            return Err(format!("{} is too long to be a hostname", example));
+        }
+        Ok(())
+    }
+}
+
+/// Rewrite the zone fragment every `interval` until the process exits
+pub async fn write_zone_periodically(
+    path: PathBuf,
+    templates: HostnameTemplates,
+    interval: Duration,
+    prefix_tables: Arc<PrefixTables>,
+) {
+    log::info!(
+        "Writing reverse DNS for the pool to {} every {:?}",
+        path.display(),
+        interval
+    );
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let zone = build_zone(&prefix_tables, &templates);
+        if let Err(error) = write_zone(&zone, &path) {
+            log::error!(
+                "Failed to write reverse DNS zone to {}: {}",
+                path.display(),
+                error
+            );
+        }
+    }
+}
+
+/// Build a `PTR` record for every pool address
+fn build_zone(prefix_tables: &PrefixTables, templates: &HostnameTemplates) -> String {
+    let mapped: HashMap<Ipv4Addr, Ipv6Addr> = prefix_tables
+        .tables()
+        .flat_map(|table| {
+            table
+                .lock()
+                .unwrap()
+                .mappings()
+                .map(|(ipv4, ipv6, _)| (ipv4, ipv6))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut zone = format!(
+        "; Reverse DNS for the protomask pool, generated at {}\n",
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    );
+    for ipv4 in prefix_tables.pools().iter().flat_map(|pool| pool.hosts()) {
+        let name = match mapped.get(&ipv4) {
+            Some(ipv6) => HostnameTemplates::render(&templates.mapped, ipv4, Some(*ipv6)),
+            None => match &templates.unmapped {
+                Some(template) => HostnameTemplates::render(template, ipv4, None),
+                None => continue,
+            },
+        };
+        let [a, b, c, d] = ipv4.octets();
+        writeln!(zone, "{}.{}.{}.{}.in-addr.arpa.\tIN\tPTR\t{}", d, c, b, a, name).unwrap();
+    }
+    zone
+}
+
+/// Write the zone to a temporary file and move it into place so the DNS server never sees a partial file
+fn write_zone(zone: &str, path: &Path) -> std::io::Result<()> {
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    std::fs::write(&temp_path, zone)?;
+    std::fs::rename(temp_path, path)
+}
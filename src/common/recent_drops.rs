@@ -0,0 +1,90 @@
+//! Ring of recently dropped packets
+//!
+//! The last few dropped packets are kept in memory with the reason each was dropped and the start of its headers, so
+//! that a transient translation failure can still be looked into (with `protomaskctl drops`) after the fact, without
+//! having started protomask with `--capture-drops`.
+
+use super::{counters::DropReason, packet_trace::describe};
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How much of each packet is kept. This covers an IPv6 header and a TCP header without options.
+const HEADER_BYTES: usize = 60;
+
+/// A single dropped packet
+struct DroppedPacket {
+    time: SystemTime,
+    reason: DropReason,
+    detail: Option<String>,
+    len: usize,
+    headers: Vec<u8>,
+}
+
+/// The most recently dropped packets, oldest first
+pub struct RecentDrops {
+    capacity: usize,
+    drops: Mutex<VecDeque<DroppedPacket>>,
+}
+
+impl RecentDrops {
+    /// Keep up to `capacity` dropped packets. Nothing is kept if it is zero.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            drops: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Remember a dropped packet, forgetting the oldest one if the ring is full
+    #[profiling::function]
+    pub fn record(&self, packet: &[u8], reason: DropReason, detail: Option<&str>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let dropped = DroppedPacket {
+            time: SystemTime::now(),
+            reason,
+            detail: detail.map(str::to_string),
+            len: packet.len(),
+            headers: packet[..packet.len().min(HEADER_BYTES)].to_vec(),
+        };
+        let mut drops = self.drops.lock().unwrap();
+        if drops.len() == self.capacity {
+            drops.pop_front();
+        }
+        drops.push_back(dropped);
+    }
+
+    /// Describe up to `limit` of the remembered packets as JSON, newest first
+    pub fn snapshot(&self, limit: Option<usize>) -> serde_json::Value {
+        let drops = self.drops.lock().unwrap();
+        let drops: Vec<_> = drops
+            .iter()
+            .rev()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|dropped| {
+                let mut headers = String::with_capacity(dropped.headers.len() * 2);
+                for byte in &dropped.headers {
+                    write!(headers, "{:02x}", byte).unwrap();
+                }
+                serde_json::json!({
+                    "time": dropped
+                        .time
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs_f64(),
+                    "reason": dropped.reason.name(),
+                    "detail": dropped.detail,
+                    "packet": describe(&dropped.headers),
+                    "len": dropped.len,
+                    "headers": headers,
+                })
+            })
+            .collect();
+        serde_json::json!({ "capacity": self.capacity, "drops": drops })
+    }
+}
@@ -0,0 +1,463 @@
+//! Active-standby state replication
+//!
+//! A primary streams every mapping it creates or expires to its standbys over TCP, so that a standby taking over
+//! already knows every subscriber's mapping. Standbys are sent a full copy of the table when they connect, and again
+//! periodically to correct any drift (anti-entropy).
+//!
+//! Before any state is exchanged, both ends prove that they know a shared secret through an HMAC-SHA256
+//! challenge-response. The connection is not encrypted, so replication should be run over a trusted network.
+
+use super::prefix_tables::PrefixTables;
+use fast_nat::MappingEvent;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::broadcast::{self, error::RecvError},
+};
+
+/// How long to wait before reconnecting to the primary
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long the other end has to authenticate itself
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A single line sent between primary and standby
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Message {
+    /// A random challenge for the other end to prove it knows the secret
+    Hello { challenge: String },
+    /// The HMAC of the other end's challenge
+    Proof { response: String },
+    /// Every mapping held by the primary
+    Sync { mappings: Vec<ReplicatedMapping> },
+    /// The primary created a mapping
+    Created {
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        indefinite: bool,
+    },
+    /// A mapping on the primary expired or was removed
+    Expired { ipv4: Ipv4Addr, ipv6: Ipv6Addr },
+}
+
+impl From<MappingEvent> for Message {
+    fn from(event: MappingEvent) -> Self {
+        match event {
+            MappingEvent::Created {
+                ipv4,
+                ipv6,
+                indefinite,
+            } => Self::Created {
+                ipv4,
+                ipv6,
+                indefinite,
+            },
+            MappingEvent::Expired { ipv4, ipv6 } => Self::Expired { ipv4, ipv6 },
+        }
+    }
+}
+
+/// A mapping as sent in a full sync
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ReplicatedMapping {
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    /// Not set for static mappings
+    expires_in_secs: Option<u64>,
+}
+
+/// Which end of the connection we are. Mixed into every proof so that a challenge can't be reflected back.
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    Primary,
+    Standby,
+}
+
+impl Role {
+    fn peer(self) -> Self {
+        match self {
+            Self::Primary => Self::Standby,
+            Self::Standby => Self::Primary,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Standby => "standby",
+        }
+    }
+}
+
+/// Handle used to pass mapping events on to every connected standby
+#[derive(Clone)]
+pub struct ReplicationSender {
+    events: broadcast::Sender<MappingEvent>,
+}
+
+impl ReplicationSender {
+    /// Queue an event for every connected standby. This will never block.
+    pub fn publish(&self, event: MappingEvent) {
+        // This only fails when no standbys are connected
+        let _ = self.events.send(event);
+    }
+}
+
+/// Start accepting standbys, returning a handle to publish mapping events through.
+/// Up to `queue_capacity` events may be waiting to be sent to each standby.
+pub async fn start_primary(
+    bind_addr: SocketAddr,
+    secret: String,
+    sync_interval: Duration,
+    prefix_tables: Arc<PrefixTables>,
+    queue_capacity: usize,
+) -> io::Result<ReplicationSender> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    log::info!("Accepting replication connections on {}", bind_addr);
+
+    let (events, _) = broadcast::channel(queue_capacity);
+    let sender = ReplicationSender {
+        events: events.clone(),
+    };
+
+    // Keep track of how far behind the slowest standby is
+    {
+        let events = events.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                protomask_metrics::metric!(CHANNEL_DEPTH, CHANNEL_REPLICATION)
+                    .set(events.len() as i64);
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        let secret = Arc::new(secret);
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    // Subscribe before the initial sync is taken so that no events are missed
+                    let events = events.subscribe();
+                    let secret = Arc::clone(&secret);
+                    let prefix_tables = Arc::clone(&prefix_tables);
+                    tokio::spawn(async move {
+                        if let Err(error) =
+                            feed_standby(stream, &secret, sync_interval, &prefix_tables, events)
+                                .await
+                        {
+                            log::warn!("Replication to {} ended: {}", peer, error);
+                        }
+                    });
+                }
+                Err(error) => log::warn!("Failed to accept replication connection: {}", error),
+            }
+        }
+    });
+
+    Ok(sender)
+}
+
+/// Send a full sync to a standby, then stream events to it until the connection fails
+async fn feed_standby(
+    stream: TcpStream,
+    secret: &str,
+    sync_interval: Duration,
+    prefix_tables: &PrefixTables,
+    mut events: broadcast::Receiver<MappingEvent>,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut connection = Connection::new(stream);
+    connection.handshake(secret, Role::Primary).await?;
+    log::info!("Standby {} connected. Sending full sync", peer);
+
+    // The first tick completes immediately and doubles as the initial sync
+    let mut sync_timer = tokio::time::interval(sync_interval);
+    loop {
+        tokio::select! {
+            _ = sync_timer.tick() => connection.send(&snapshot(prefix_tables)).await?,
+            event = events.recv() => match event {
+                Ok(event) => connection.send(&Message::from(event)).await?,
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Standby {} fell {} events behind. Sending full sync", peer, missed);
+                    connection.send(&snapshot(prefix_tables)).await?;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Copy mappings from a primary, reconnecting whenever the connection is lost
+pub async fn follow_primary(
+    primary: String,
+    secret: String,
+    prefix_tables: Arc<PrefixTables>,
+    reservation_timeout: Duration,
+) {
+    loop {
+        if let Err(error) = follow(&primary, &secret, &prefix_tables, reservation_timeout).await {
+            log::warn!(
+                "Replication from {} ended: {}. Reconnecting in {}s",
+                primary,
+                error,
+                RECONNECT_DELAY.as_secs()
+            );
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Apply everything the primary sends until the connection fails
+async fn follow(
+    primary: &str,
+    secret: &str,
+    prefix_tables: &PrefixTables,
+    reservation_timeout: Duration,
+) -> io::Result<()> {
+    let mut connection = Connection::new(TcpStream::connect(primary).await?);
+    connection.handshake(secret, Role::Standby).await?;
+    log::info!("Replicating mappings from {}", primary);
+
+    loop {
+        match connection.receive().await? {
+            Message::Sync { mappings } => apply_sync(prefix_tables, &mappings),
+            Message::Created {
+                ipv4,
+                ipv6,
+                indefinite,
+            } => apply_mapping(
+                prefix_tables,
+                ipv4,
+                ipv6,
+                (!indefinite).then_some(reservation_timeout),
+            ),
+            Message::Expired { ipv4, ipv6 } => {
+                if let Some(table) = prefix_tables.table_for_ipv4(ipv4) {
+                    let mut table = table.lock().unwrap();
+                    if table.get_ipv6(&ipv4) == Some(ipv6) {
+                        table.remove_ipv4(&ipv4);
+                    }
+                }
+            }
+            Message::Hello { .. } | Message::Proof { .. } => {
+                return Err(invalid_data("unexpected handshake message"))
+            }
+        }
+    }
+}
+
+/// Build a full sync message
+fn snapshot(prefix_tables: &PrefixTables) -> Message {
+    Message::Sync {
+        mappings: prefix_tables
+            .tables()
+            .flat_map(|table| {
+                table
+                    .lock()
+                    .unwrap()
+                    .mappings()
+                    .map(|(ipv4, ipv6, remaining)| ReplicatedMapping {
+                        ipv4,
+                        ipv6,
+                        // Rounded up, so that the standby never inserts a mapping with a zero TTL
+                        expires_in_secs: remaining.map(|remaining| {
+                            remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+                        }),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+    }
+}
+
+/// Make our tables match a full sync from the primary
+fn apply_sync(prefix_tables: &PrefixTables, mappings: &[ReplicatedMapping]) {
+    // Drop anything the primary doesn't have
+    let wanted: HashMap<_, _> = mappings
+        .iter()
+        .map(|mapping| (mapping.ipv4, mapping.ipv6))
+        .collect();
+    for table in prefix_tables.tables() {
+        let mut table = table.lock().unwrap();
+        let stale: Vec<_> = table
+            .mappings()
+            .filter(|(ipv4, ipv6, _)| wanted.get(ipv4) != Some(ipv6))
+            .map(|(ipv4, ..)| ipv4)
+            .collect();
+        for ipv4 in stale {
+            table.remove_ipv4(&ipv4);
+        }
+    }
+
+    // Add anything we are missing
+    for mapping in mappings {
+        apply_mapping(
+            prefix_tables,
+            mapping.ipv4,
+            mapping.ipv6,
+            mapping.expires_in_secs.map(Duration::from_secs),
+        );
+    }
+}
+
+/// Insert a mapping from the primary, replacing anything that conflicts with it. `ttl` is `None` for static mappings.
+fn apply_mapping(
+    prefix_tables: &PrefixTables,
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    ttl: Option<Duration>,
+) {
+    let Some(table) = prefix_tables.table_for_ipv4(ipv4) else {
+        log::warn!(
+            "Primary sent a mapping for {}, which is outside of all pools",
+            ipv4
+        );
+        return;
+    };
+
+    // Free up both addresses if either is mapped to something else
+    if table.lock().unwrap().get_ipv6(&ipv4) == Some(ipv6) {
+        return;
+    }
+    prefix_tables.remove_conflicting(ipv4, ipv6);
+
+    let mut table = table.lock().unwrap();
+    let result = match ttl {
+        Some(ttl) => table.insert_with_ttl(ipv4, ipv6, ttl),
+        None => table.insert_static(ipv4, ipv6),
+    };
+    if let Err(error) = result {
+        log::warn!("Failed to apply replicated mapping: {}", error);
+    }
+}
+
+/// A replication connection carrying one JSON message per line
+struct Connection {
+    reader: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self {
+            reader: BufReader::new(reader).lines(),
+            writer,
+        }
+    }
+
+    async fn send(&mut self, message: &Message) -> io::Result<()> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await
+    }
+
+    async fn receive(&mut self) -> io::Result<Message> {
+        let line = self
+            .reader
+            .next_line()
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))?;
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Prove to the other end that we know the secret, and make sure it does too
+    async fn handshake(&mut self, secret: &str, role: Role) -> io::Result<()> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            let challenge = new_challenge()?;
+            self.send(&Message::Hello {
+                challenge: challenge.clone(),
+            })
+            .await?;
+            let Message::Hello {
+                challenge: peer_challenge,
+            } = self.receive().await?
+            else {
+                return Err(invalid_data("expected a challenge"));
+            };
+
+            self.send(&Message::Proof {
+                response: to_hex(&prove(secret, role, &peer_challenge)),
+            })
+            .await?;
+            let Message::Proof { response } = self.receive().await? else {
+                return Err(invalid_data("expected a proof"));
+            };
+
+            // Check the proof in constant time
+            let valid = from_hex(&response).is_some_and(|response| {
+                new_mac(secret, role.peer(), &challenge)
+                    .verify_slice(&response)
+                    .is_ok()
+            });
+            if valid {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the other end does not know the replication secret",
+                ))
+            }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+    }
+}
+
+/// Generate a random challenge
+fn new_challenge() -> io::Result<String> {
+    let mut challenge = [0u8; 32];
+    getrandom::getrandom(&mut challenge).map_err(|error| io::Error::other(error.to_string()))?;
+    Ok(to_hex(&challenge))
+}
+
+/// Start an HMAC over a challenge on behalf of `role`
+fn new_mac(secret: &str, role: Role, challenge: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(role.as_str().as_bytes());
+    mac.update(b":");
+    mac.update(challenge.as_bytes());
+    mac
+}
+
+/// Answer a challenge
+fn prove(secret: &str, role: Role, challenge: &str) -> Vec<u8> {
+    new_mac(secret, role, challenge)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Error for a message that doesn't belong
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
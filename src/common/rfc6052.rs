@@ -3,6 +3,7 @@
 use std::str::FromStr;
 
 use ipnet::Ipv6Net;
+use std::net::Ipv6Addr;
 
 /// Parses an [RFC6052 Section 2.2](https://datatracker.ietf.org/doc/html/rfc6052#section-2.2)-compliant IPv6 prefix from a string
 pub fn parse_network_specific_prefix(string: &str) -> Result<Ipv6Net, String> {
@@ -20,3 +21,35 @@ pub fn parse_network_specific_prefix(string: &str) -> Result<Ipv6Net, String> {
     // Return the parsed network struct
     Ok(net)
 }
+
+/// Serializes an RFC6052 IPv6 prefix to a string
+pub fn serialize_network_specific_prefix<S>(
+    prefix: &Ipv6Net,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&prefix.to_string())
+}
+
+/// Carve a /96 translation prefix out of a larger routed prefix.
+///
+/// The highest /96 with a zero "u" octet (bits 64 through 71) is used, which keeps it clear of the low addresses
+/// typically assigned to hosts within the prefix.
+pub fn carve_translation_prefix(network: Ipv6Net) -> Result<Ipv6Net, String> {
+    if network.prefix_len() > 96 {
+        return Err(format!(
+            "{} is too small to hold a /96 translation prefix",
+            network
+        ));
+    }
+
+    // Set every bit between the end of the network and the end of the /96, except for the "u" octet
+    let network_bits = u128::from(network.network());
+    let host_bits = (u128::MAX >> network.prefix_len()) & !(u128::MAX >> 96);
+    let u_octet = 0xffu128 << 56;
+    let prefix = (network_bits | host_bits) & !(u_octet & host_bits);
+
+    Ok(Ipv6Net::new(Ipv6Addr::from(prefix), 96).unwrap())
+}
@@ -0,0 +1,32 @@
+//! Source selection for translated ICMPv6 errors from addresses that can't be translated ([RFC6791](https://datatracker.ietf.org/doc/html/rfc6791))
+//!
+//! Routers on the IPv6 side (such as those reporting Time Exceeded or Packet Too Big) don't have an IPv4 address of
+//! their own. Their errors are given one of a few dedicated IPv4 addresses as a source instead, so that traceroute
+//! and path MTU discovery keep working.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// ICMPv6's next header value
+const NEXT_HEADER_ICMPV6: u8 = 58;
+
+/// Check if an IPv6 packet carries an ICMPv6 error message.
+///
+/// Extension headers are not followed, so errors behind them aren't recognised.
+pub fn is_icmpv6_error(packet: &[u8]) -> bool {
+    // ICMPv6 error types are all below 128
+    packet.len() > 40 && packet[6] == NEXT_HEADER_ICMPV6 && packet[40] < 128
+}
+
+/// Pick the IPv4 source for an error from `origin`. The same origin always gets the same address.
+pub fn error_source(sources: &[Ipv4Addr], origin: Ipv6Addr) -> Option<Ipv4Addr> {
+    if sources.is_empty() {
+        return None;
+    }
+    let hash = origin
+        .segments()
+        .iter()
+        .fold(0usize, |hash, segment| {
+            hash.rotate_left(5) ^ usize::from(*segment)
+        });
+    Some(sources[hash % sources.len()])
+}
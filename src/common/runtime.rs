@@ -0,0 +1,47 @@
+//! Instrumentation of the tokio runtime
+//!
+//! Packets are translated on dedicated threads, but everything around them (mapping expiry, replication, the control
+//! APIs, etc.) runs on tokio. Stalls there are exported as Prometheus metrics, and can be inspected live with
+//! tokio-console when built with the `tokio-console` feature.
+
+use std::time::Duration;
+
+/// How often runtime metrics are updated
+const RUNTIME_METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keep the runtime metrics up to date
+pub async fn export_runtime_metrics() {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let mut interval = tokio::time::interval(RUNTIME_METRICS_INTERVAL);
+    loop {
+        // Any time past the scheduled tick was spent waiting for the runtime to get to this task
+        let scheduled = interval.tick().await;
+        protomask_metrics::metrics::TOKIO_SCHEDULER_DELAY_SECONDS
+            .observe(scheduled.elapsed().as_secs_f64());
+
+        let workers = metrics.num_workers();
+        let (busy, parks) = (0..workers).fold((Duration::ZERO, 0), |(busy, parks), worker| {
+            (
+                busy + metrics.worker_total_busy_duration(worker),
+                parks + metrics.worker_park_count(worker),
+            )
+        });
+        protomask_metrics::metrics::TOKIO_WORKERS.set(workers as i64);
+        protomask_metrics::metrics::TOKIO_ALIVE_TASKS.set(metrics.num_alive_tasks() as i64);
+        protomask_metrics::metrics::TOKIO_GLOBAL_QUEUE_DEPTH
+            .set(metrics.global_queue_depth() as i64);
+        protomask_metrics::metrics::TOKIO_BUSY_SECONDS.set(busy.as_secs_f64());
+        protomask_metrics::metrics::TOKIO_PARK_COUNT.set(parks as i64);
+    }
+}
+
+/// Serve tokio-console, if built with the `tokio-console` feature
+#[cfg(feature = "tokio-console")]
+pub fn start_console() {
+    log::info!("Serving tokio-console");
+    console_subscriber::init();
+}
+
+/// Serve tokio-console, if built with the `tokio-console` feature
+#[cfg(not(feature = "tokio-console"))]
+pub fn start_console() {}
@@ -0,0 +1,309 @@
+//! NAT session event logging
+//!
+//! Many jurisdictions require operators to be able to answer "which subscriber held this public IPv4 address at this time".
+//! This module records every mapping creation and expiry to either a size-rotated log file or a remote syslog collector.
+//! When devices share addresses (aggregation or deterministic NAT), every session is recorded too, with its original and
+//! translated ports, since the address alone no longer identifies a subscriber.
+//!
+//! Events are handed to a background thread through a bounded queue so that a slow disk or network can never stall packet translation.
+//! If the queue fills up, events are dropped and counted rather than blocking the caller.
+
+use fast_nat::{MappingEvent, SessionEvent};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Syslog priority value for `local0.info`
+const SYSLOG_PRIORITY: u8 = (16 * 8) + 6;
+
+/// Where session events should be written to
+#[derive(Debug, Clone)]
+pub enum SessionLogTarget {
+    /// Append to a local file, rotating once it grows past `max_size` bytes
+    File {
+        path: PathBuf,
+        max_size: u64,
+        max_files: usize,
+    },
+    /// Send each event as an RFC5424 message to a remote syslog server over UDP
+    Syslog(SocketAddr),
+}
+
+/// Anything that gets logged
+enum LoggedEvent {
+    Mapping(MappingEvent),
+    Session(SessionEvent),
+}
+
+/// A single timestamped event
+struct SessionRecord {
+    timestamp: SystemTime,
+    event: LoggedEvent,
+}
+
+impl std::fmt::Display for SessionRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timestamp = humantime::format_rfc3339_millis(self.timestamp);
+        match self.event {
+            LoggedEvent::Mapping(MappingEvent::Created {
+                ipv4,
+                ipv6,
+                indefinite,
+            }) => write!(
+                f,
+                "{} CREATE ipv6={} ipv4={} static={}",
+                timestamp, ipv6, ipv4, indefinite
+            ),
+            LoggedEvent::Mapping(MappingEvent::Expired { ipv4, ipv6 }) => {
+                write!(f, "{} EXPIRE ipv6={} ipv4={}", timestamp, ipv6, ipv4)
+            }
+            LoggedEvent::Session(SessionEvent::Created {
+                ipv6,
+                protocol,
+                port,
+                ipv4,
+                translated_port,
+            }) => write!(
+                f,
+                "{} SESSION-CREATE protocol={} ipv6={} port={} ipv4={} translated_port={}",
+                timestamp,
+                protocol_name(protocol),
+                ipv6,
+                port,
+                ipv4,
+                translated_port
+            ),
+            LoggedEvent::Session(SessionEvent::Expired {
+                ipv6,
+                protocol,
+                port,
+                ipv4,
+                translated_port,
+            }) => write!(
+                f,
+                "{} SESSION-EXPIRE protocol={} ipv6={} port={} ipv4={} translated_port={}",
+                timestamp,
+                protocol_name(protocol),
+                ipv6,
+                port,
+                ipv4,
+                translated_port
+            ),
+        }
+    }
+}
+
+/// Name a transport protocol the way it is written in session log entries
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        1 | 58 => "icmp".to_string(),
+        6 => "tcp".to_string(),
+        17 => "udp".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Handle used to submit events to the session log writer thread
+#[derive(Clone)]
+pub struct SessionLogger {
+    sender: SyncSender<SessionRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SessionLogger {
+    /// Spawn a background writer for the given target, with up to `queue_capacity` events waiting to be written
+    pub fn new(target: SessionLogTarget, queue_capacity: usize) -> std::io::Result<Self> {
+        let mut sink = SessionLogSink::open(target)?;
+        let (sender, receiver) = sync_channel::<SessionRecord>(queue_capacity);
+
+        std::thread::Builder::new()
+            .name("session-log".to_string())
+            .spawn(move || loop {
+                match receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(record) => {
+                        protomask_metrics::metric!(CHANNEL_DEPTH, CHANNEL_SESSION_LOG).dec();
+                        if let Err(error) = sink.write(&record) {
+                            log::error!("Failed to write session log entry: {}", error);
+                        }
+                    }
+                    // Flush whenever the queue goes idle so entries are not held in memory indefinitely
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(error) = sink.flush() {
+                            log::error!("Failed to flush session log: {}", error);
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        let _ = sink.flush();
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Queue a mapping event for logging. This will never block.
+    pub fn log(&self, event: MappingEvent) {
+        self.queue(LoggedEvent::Mapping(event));
+    }
+
+    /// Queue a session event for logging. This will never block.
+    pub fn log_session(&self, event: SessionEvent) {
+        self.queue(LoggedEvent::Session(event));
+    }
+
+    /// Hand an event to the writer thread, dropping it if the queue is full
+    fn queue(&self, event: LoggedEvent) {
+        let record = SessionRecord {
+            timestamp: SystemTime::now(),
+            event,
+        };
+
+        // Count the event as queued before the writer can possibly take it off the queue
+        let depth = protomask_metrics::metric!(CHANNEL_DEPTH, CHANNEL_SESSION_LOG);
+        depth.inc();
+        match self.sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(record)) => {
+                depth.dec();
+
+                // Only complain occasionally, since this will happen under sustained load
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+                if dropped.is_multiple_of(1000) {
+                    log::warn!(
+                        "Session log queue is full. Dropped {} event(s) so far. Latest: {}",
+                        dropped + 1,
+                        record
+                    );
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => depth.dec(),
+        }
+    }
+}
+
+/// The writing end of the session log
+enum SessionLogSink {
+    File {
+        path: PathBuf,
+        writer: BufWriter<File>,
+        size: u64,
+        max_size: u64,
+        max_files: usize,
+    },
+    Syslog {
+        socket: UdpSocket,
+        hostname: String,
+    },
+}
+
+impl SessionLogSink {
+    /// Open the underlying file or socket
+    fn open(target: SessionLogTarget) -> std::io::Result<Self> {
+        match target {
+            SessionLogTarget::File {
+                path,
+                max_size,
+                max_files,
+            } => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                let size = file.metadata()?.len();
+                Ok(Self::File {
+                    path,
+                    writer: BufWriter::new(file),
+                    size,
+                    max_size,
+                    max_files,
+                })
+            }
+            SessionLogTarget::Syslog(server) => {
+                let socket = UdpSocket::bind(match server {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                })?;
+                socket.connect(server)?;
+                let hostname = nix::unistd::gethostname()
+                    .ok()
+                    .and_then(|name| name.into_string().ok())
+                    .unwrap_or_else(|| "-".to_string());
+                Ok(Self::Syslog { socket, hostname })
+            }
+        }
+    }
+
+    /// Write a single record
+    fn write(&mut self, record: &SessionRecord) -> std::io::Result<()> {
+        match self {
+            Self::File {
+                path,
+                writer,
+                size,
+                max_size,
+                max_files,
+            } => {
+                let line = format!("{}\n", record);
+                writer.write_all(line.as_bytes())?;
+                *size += line.len() as u64;
+
+                // Rotate the file once it gets too big
+                if *size >= *max_size {
+                    writer.flush()?;
+                    rotate_files(path, *max_files)?;
+                    *writer =
+                        BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+                    *size = 0;
+                }
+                Ok(())
+            }
+            Self::Syslog { socket, hostname } => {
+                // RFC5424: <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG
+                let message = format!(
+                    "<{}>1 {} {} protomask {} nat-session - {}",
+                    SYSLOG_PRIORITY,
+                    humantime::format_rfc3339_millis(record.timestamp),
+                    hostname,
+                    std::process::id(),
+                    record
+                );
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+        }
+    }
+
+    /// Flush any buffered data
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File { writer, .. } => writer.flush(),
+            Self::Syslog { .. } => Ok(()),
+        }
+    }
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2`, and so on, discarding anything past `max_files`
+fn rotate_files(path: &Path, max_files: usize) -> std::io::Result<()> {
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+
+    // With no history to keep, just start the file over
+    if max_files == 0 {
+        return std::fs::remove_file(path);
+    }
+
+    for n in (1..max_files).rev() {
+        if numbered(n).exists() {
+            std::fs::rename(numbered(n), numbered(n + 1))?;
+        }
+    }
+    std::fs::rename(path, numbered(1))
+}
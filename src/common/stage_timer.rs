@@ -0,0 +1,73 @@
+//! Sampled timing of each stage of packet processing
+//!
+//! Timing every packet would cost more than some of the stages being timed, so only one in every so many packets is
+//! timed. The time spent in each stage is exported as a Prometheus histogram (`protomask_packet_stage_seconds`).
+
+use std::time::Instant;
+
+/// Decides which packets handled by a worker get timed. Each worker thread has its own.
+pub struct StageSampler {
+    /// Time one in every this many packets (zero disables timing)
+    interval: u64,
+    count: u64,
+}
+
+impl StageSampler {
+    /// Time roughly `sample_rate` (0 to 1) of all packets
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    pub fn new(sample_rate: f64) -> Self {
+        let interval = if sample_rate > 0.0 {
+            (1.0 / sample_rate.min(1.0)).round() as u64
+        } else {
+            0
+        };
+        Self { interval, count: 0 }
+    }
+
+    /// Called as a packet starts being processed. Returns a timer if this packet should be timed.
+    pub fn start(&mut self) -> Option<StageTimer> {
+        self.sample().then(|| StageTimer {
+            last: Instant::now(),
+        })
+    }
+
+    /// Count a packet, returning `true` if it is one of the sampled ones
+    pub fn sample(&mut self) -> bool {
+        if self.interval == 0 {
+            return false;
+        }
+        self.count += 1;
+        if self.count < self.interval {
+            return false;
+        }
+        self.count = 0;
+        true
+    }
+}
+
+/// Times the stages of a single packet
+pub struct StageTimer {
+    last: Instant,
+}
+
+impl StageTimer {
+    /// Record the time since the previous stage ended (or the packet was read) against `stage`
+    pub fn stage(&mut self, stage: &str) {
+        let now = Instant::now();
+        protomask_metrics::metrics::PACKET_STAGE_SECONDS
+            .with_label_values(&[stage])
+            .observe(now.duration_since(self.last).as_secs_f64());
+        self.last = now;
+    }
+}
+
+/// Record the end of a stage, if the packet is being timed
+pub fn end_stage(timer: &mut Option<StageTimer>, stage: &str) {
+    if let Some(timer) = timer {
+        timer.stage(stage);
+    }
+}
@@ -0,0 +1,104 @@
+//! On-demand dumps of internal state
+//!
+//! Sending `SIGUSR1` to a running protomask process will cause it to write a JSON snapshot of its
+//! NAT table, pool utilization, per-queue counters, and configuration to a file.
+
+use crate::{
+    args::protomask::{Config, StaticMap},
+    common::{
+        counters::{MappingTraffic, QueueCounters},
+        prefix_tables::PrefixTables,
+        recent_drops::RecentDrops,
+    },
+};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Everything needed to build a state dump
+pub struct StateDumpSource {
+    pub interface: String,
+    pub config: Mutex<Config>,
+    /// Static mappings currently loaded from `static_map_file`
+    pub file_static_map: Mutex<Vec<StaticMap>>,
+    pub prefix_tables: Arc<PrefixTables>,
+    pub queue_counters: Arc<Vec<QueueCounters>>,
+    pub traffic: Arc<MappingTraffic>,
+    pub recent_drops: Arc<RecentDrops>,
+    pub start_time: Instant,
+}
+
+impl StateDumpSource {
+    /// Build a JSON representation of the current state
+    #[allow(clippy::cast_precision_loss)]
+    pub fn snapshot(&self) -> serde_json::Value {
+        // Hold each table lock only as long as it takes to copy out the mappings
+        let mut mappings = Vec::new();
+        let mut mapped = HashSet::new();
+        let mut pool_size = 0;
+        for table in self.prefix_tables.tables() {
+            let table = table.lock().unwrap();
+            mappings.extend(table.mappings().map(|(ipv4, ipv6, remaining)| {
+                mapped.insert(ipv4);
+                let traffic = self.traffic.get(ipv4);
+                serde_json::json!({
+                    "ipv4": ipv4,
+                    "ipv6": ipv6,
+                    "static": remaining.is_none(),
+                    "expires_in_secs": remaining.map(|remaining| remaining.as_secs()),
+                    "packets": traffic.packets(),
+                    "bytes": traffic.bytes(),
+                    "packets_out": traffic.packets_out,
+                    "bytes_out": traffic.bytes_out,
+                    "packets_in": traffic.packets_in,
+                    "bytes_in": traffic.bytes_in,
+                })
+            }));
+            pool_size += table.pool_size();
+        }
+        let pool_prefixes = self.prefix_tables.pools();
+
+        // Counters for expired mappings are no longer useful
+        self.traffic.retain(|ipv4| mapped.contains(&ipv4));
+
+        serde_json::json!({
+            "timestamp": humantime::format_rfc3339(SystemTime::now()).to_string(),
+            "uptime_secs": self.start_time.elapsed().as_secs(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "interface": self.interface,
+            "pool": {
+                "prefixes": pool_prefixes,
+                "draining": self.prefix_tables.draining_pools(),
+                "total_addresses": pool_size,
+                "mapped_addresses": mappings.len(),
+                "utilization": if pool_size == 0 { 0.0 } else { mappings.len() as f64 / pool_size as f64 },
+            },
+            "traffic": self.traffic.total(),
+            "queues": self.queue_counters.iter().map(QueueCounters::snapshot).collect::<Vec<_>>(),
+            "config": *self.config.lock().unwrap(),
+            "mappings": mappings,
+        })
+    }
+}
+
+/// Write a state dump every time `SIGUSR1` is received
+pub async fn dump_on_sigusr1(source: Arc<StateDumpSource>, path: PathBuf) {
+    let mut signals = signal(SignalKind::user_defined1()).unwrap();
+    while signals.recv().await.is_some() {
+        log::info!("Received SIGUSR1. Writing state dump to {}", path.display());
+        if let Err(error) = write_dump(&source.snapshot(), &path) {
+            log::error!("Failed to write state dump: {}", error);
+        }
+    }
+}
+
+/// Write the dump to a temporary file and move it into place so readers never see a partial file
+fn write_dump(snapshot: &serde_json::Value, path: &Path) -> std::io::Result<()> {
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(snapshot)?)?;
+    std::fs::rename(temp_path, path)
+}
@@ -0,0 +1,352 @@
+//! Static mappings kept in their own file
+//!
+//! Long or frequently changing lists of static mappings can be kept out of the main config, in a JSON or YAML file
+//! holding a list of `{ "ipv4": ..., "ipv6": ... }` objects, or in a hosts-style `.txt` file with one
+//! `<ipv4> <ipv6>` pair per line (as IPAM systems tend to export them). The file is watched with inotify, and whenever it changes
+//! the address tables are brought in line with it in one step. A file that fails to parse or validate is reported and
+//! otherwise ignored, leaving the previous mappings in place until it is fixed.
+
+use super::{prefix_tables::PrefixTables, state_dump::StateDumpSource};
+use crate::args::{
+    protomask::{Config, ConfigIssue, StaticMap},
+    read_config_file,
+};
+use ipnet::Ipv6Net;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// How long to let the file settle after a change before reading it, so that a burst of writes is read once
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Read the mappings listed in a static mapping file
+pub fn read(path: &Path) -> Result<Vec<StaticMap>, String> {
+    if path.extension().and_then(|extension| extension.to_str()) == Some("txt") {
+        let data = std::fs::read_to_string(path)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        return parse_hosts(&data).map_err(|error| format!("{}: {}", path.display(), error));
+    }
+    read_config_file(path, None).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+/// Parse a hosts-style list of mappings, with one `<ipv4> <ipv6>` pair per line.
+///
+/// Anything after a `#` is a comment, and blank lines are ignored. Addresses that are mapped more than once are
+/// reported with the lines they appear on, which the generic checks can't do.
+fn parse_hosts(data: &str) -> Result<Vec<StaticMap>, String> {
+    let mut mappings = Vec::new();
+    let mut ipv4_lines = HashMap::new();
+    let mut ipv6_lines = HashMap::new();
+    for (index, line) in data.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split_once('#').map_or(line, |(line, _)| line);
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (ipv4, ipv6) = match fields[..] {
+            [] => continue,
+            [ipv4, ipv6] => (ipv4, ipv6),
+            _ => {
+                return Err(format!(
+                    "line {}: expected `<ipv4> <ipv6>`, found {} fields",
+                    line_number,
+                    fields.len()
+                ))
+            }
+        };
+        let mapping = StaticMap {
+            ipv4: ipv4
+                .parse()
+                .map_err(|_| format!("line {}: {} is not an IPv4 address", line_number, ipv4))?,
+            ipv6: ipv6
+                .parse()
+                .map_err(|_| format!("line {}: {} is not an IPv6 address", line_number, ipv6))?,
+        };
+
+        // Each address may only be mapped once
+        if let Some(other_line) = ipv4_lines.insert(mapping.ipv4, line_number) {
+            return Err(format!(
+                "line {}: {} is already mapped on line {}",
+                line_number, mapping.ipv4, other_line
+            ));
+        }
+        if let Some(other_line) = ipv6_lines.insert(mapping.ipv6, line_number) {
+            return Err(format!(
+                "line {}: {} is already mapped on line {}",
+                line_number, mapping.ipv6, other_line
+            ));
+        }
+        mappings.push(mapping);
+    }
+    Ok(mappings)
+}
+
+/// Check the mappings from a static mapping file against the rest of the config
+pub fn check(config: &Config, mappings: &[StaticMap]) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    let mut issue = |location: String, message: String| {
+        issues.push(ConfigIssue { location, message });
+    };
+
+    let pools: Vec<_> = config
+        .pool_prefixes
+        .iter()
+        .chain(
+            config
+                .additional_prefixes
+                .iter()
+                .flat_map(|additional| &additional.pool),
+        )
+        .chain(config.tenants.iter().flat_map(|tenant| &tenant.pool))
+        .collect();
+    for (i, mapping) in mappings.iter().enumerate() {
+        let location = format!("static_map_file[{}]", i);
+
+        // Static mappings must come out of the pool address space
+        if !pools.iter().any(|prefix| prefix.contains(&mapping.ipv4)) {
+            issue(
+                format!("{}.ipv4", location),
+                format!("{} is not inside any pool prefix", mapping.ipv4),
+            );
+        }
+
+        // Static mappings are made for whole subscribers
+        if let Some(prefix_len) = config
+            .aggregation
+            .subscriber_prefix_len
+            .filter(|prefix_len| *prefix_len <= 128)
+        {
+            let subscriber = Ipv6Net::new(mapping.ipv6, prefix_len).unwrap().trunc();
+            if subscriber.network() != mapping.ipv6 {
+                issue(
+                    format!("{}.ipv6", location),
+                    format!(
+                        "{} is inside subscriber {}. Map {} instead",
+                        mapping.ipv6,
+                        subscriber,
+                        subscriber.network()
+                    ),
+                );
+            }
+        }
+
+        // Each address may only be mapped once, whether here or in the main config
+        let earlier = mappings
+            .iter()
+            .take(i)
+            .enumerate()
+            .map(|(j, other)| (format!("static_map_file[{}]", j), other))
+            .chain(
+                config
+                    .static_map
+                    .iter()
+                    .enumerate()
+                    .map(|(j, other)| (format!("static_map[{}]", j), other)),
+            );
+        for (other_location, other) in earlier {
+            if mapping.ipv4 == other.ipv4 {
+                issue(
+                    format!("{}.ipv4", location),
+                    format!("{} is already mapped by {}", mapping.ipv4, other_location),
+                );
+            }
+            if mapping.ipv6 == other.ipv6 {
+                issue(
+                    format!("{}.ipv6", location),
+                    format!("{} is already mapped by {}", mapping.ipv6, other_location),
+                );
+            }
+        }
+
+        // The translator address can't be handed out to clients
+        if config.translator_address == Some(mapping.ipv4) {
+            issue(
+                format!("{}.ipv4", location),
+                format!("{} is the translator address", mapping.ipv4),
+            );
+        }
+    }
+    issues
+}
+
+/// Re-read the file and bring the address tables in line with it, returning how many mappings were added and removed.
+///
+/// Nothing is changed if the file can't be read or is invalid.
+pub fn reload(path: &Path, state: &StateDumpSource) -> Result<(usize, usize), String> {
+    let mappings = read(path)?;
+    let issues = check(&state.config.lock().unwrap(), &mappings);
+    if !issues.is_empty() {
+        return Err(issues
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; "));
+    }
+
+    let mut loaded = state.file_static_map.lock().unwrap();
+    let changes = apply(&state.prefix_tables, &loaded, &mappings);
+    *loaded = mappings;
+    Ok(changes)
+}
+
+/// Swap one set of static mappings for another, holding every table lock so that the change is seen all at once
+fn apply(prefix_tables: &PrefixTables, old: &[StaticMap], new: &[StaticMap]) -> (usize, usize) {
+    let tables: Vec<_> = prefix_tables.tables().collect();
+    let mut locked: Vec<_> = tables.iter().map(|table| table.lock().unwrap()).collect();
+    let table_index = |mapping: &StaticMap| {
+        let table = prefix_tables.table_for_ipv4(mapping.ipv4)?;
+        tables.iter().position(|other| Arc::ptr_eq(table, other))
+    };
+
+    // Drop mappings that are no longer listed, unless they have since been replaced by something else
+    let mut removed = 0;
+    for mapping in old.iter().filter(|mapping| !new.contains(mapping)) {
+        if let Some(index) = table_index(mapping) {
+            if locked[index].get_ipv6(&mapping.ipv4) == Some(mapping.ipv6) {
+                locked[index].remove_ipv4(&mapping.ipv4);
+                removed += 1;
+            }
+        }
+    }
+
+    // Add new ones, replacing anything either address was previously mapped to
+    let mut added = 0;
+    for mapping in new.iter().filter(|mapping| !old.contains(mapping)) {
+        let Some(index) = table_index(mapping) else {
+            log::warn!("Static mapping for {} is outside of all pools", mapping.ipv4);
+            continue;
+        };
+        for table in &mut locked {
+            PrefixTables::remove_conflicting_in(table, mapping.ipv4, mapping.ipv6);
+        }
+        match locked[index].insert_static(mapping.ipv4, mapping.ipv6) {
+            Ok(()) => added += 1,
+            Err(error) => log::warn!(
+                "Failed to add static mapping {} -> {}: {}",
+                mapping.ipv6,
+                mapping.ipv4,
+                error
+            ),
+        }
+    }
+    (added, removed)
+}
+
+/// Watch the file for changes in the background, reloading it every time it is written or replaced
+pub fn watch(path: PathBuf, state: Arc<StateDumpSource>) {
+    std::thread::Builder::new()
+        .name("static-map-watcher".to_string())
+        .spawn(move || {
+            if let Err(error) = watch_forever(&path, &state) {
+                log::error!(
+                    "Stopped watching {} for changes: {}",
+                    path.display(),
+                    error
+                );
+            }
+        })
+        .unwrap();
+}
+
+/// Block on inotify events for the file, reloading it after each change
+fn watch_forever(path: &Path, state: &StateDumpSource) -> nix::Result<()> {
+    // Editors often replace a file rather than writing to it, so its directory is watched instead
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(
+        directory,
+        AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CREATE,
+    )?;
+    log::info!("Watching {} for static mapping changes", path.display());
+
+    loop {
+        let events = inotify.read_events()?;
+        if !events
+            .iter()
+            .any(|event| event.name.as_deref() == path.file_name())
+        {
+            continue;
+        }
+
+        std::thread::sleep(SETTLE_TIME);
+        match reload(path, state) {
+            Ok((0, 0)) => log::debug!("{} changed, but its mappings did not", path.display()),
+            Ok((added, removed)) => log::info!(
+                "Reloaded {} ({} static mappings added, {} removed)",
+                path.display(),
+                added,
+                removed
+            ),
+            Err(error) => log::error!(
+                "Ignoring invalid static mapping file. Keeping the previous mappings: {}",
+                error
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hosts() {
+        let data =
+            "# Exported from IPAM\n\n192.0.2.1 2001:db8::1\n  \n192.0.2.2\t2001:db8::2 # printer\n";
+        assert_eq!(
+            parse_hosts(data),
+            Ok(vec![
+                StaticMap {
+                    ipv4: "192.0.2.1".parse().unwrap(),
+                    ipv6: "2001:db8::1".parse().unwrap(),
+                },
+                StaticMap {
+                    ipv4: "192.0.2.2".parse().unwrap(),
+                    ipv6: "2001:db8::2".parse().unwrap(),
+                },
+            ])
+        );
+        assert_eq!(parse_hosts("# Nothing yet\n"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_parse_hosts_field_count() {
+        assert_eq!(
+            parse_hosts("192.0.2.1 2001:db8::1\n192.0.2.2\n"),
+            Err("line 2: expected `<ipv4> <ipv6>`, found 1 fields".to_string())
+        );
+        assert_eq!(
+            parse_hosts("192.0.2.1 2001:db8::1 printer\n"),
+            Err("line 1: expected `<ipv4> <ipv6>`, found 3 fields".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_hosts_bad_addresses() {
+        assert_eq!(
+            parse_hosts("2001:db8::1 192.0.2.1\n"),
+            Err("line 1: 2001:db8::1 is not an IPv4 address".to_string())
+        );
+        assert_eq!(
+            parse_hosts("192.0.2.1 printer.example\n"),
+            Err("line 1: printer.example is not an IPv6 address".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_hosts_duplicates() {
+        assert_eq!(
+            parse_hosts("192.0.2.1 2001:db8::1\n\n192.0.2.1 2001:db8::2\n"),
+            Err("line 3: 192.0.2.1 is already mapped on line 1".to_string())
+        );
+        assert_eq!(
+            parse_hosts("192.0.2.1 2001:db8::1\n# Moved\n192.0.2.2 2001:db8::1\n"),
+            Err("line 3: 2001:db8::1 is already mapped on line 1".to_string())
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Opt-in crash reporting
+
+use cfg_if::cfg_if;
+
+use crate::args::TelemetryConfig;
+
+cfg_if! {
+    if #[cfg(feature = "sentry")] {
+        /// Start reporting crashes to Sentry, if a DSN is configured. Reporting stops when the guard is dropped.
+        pub fn init_crash_reporting(config: &TelemetryConfig) -> Option<sentry::ClientInitGuard> {
+            let dsn = config.dsn.as_ref()?;
+            let dsn = match dsn.parse() {
+                Ok(dsn) => dsn,
+                Err(error) => {
+                    log::error!("Invalid Sentry DSN: {}", error);
+                    std::process::exit(1);
+                }
+            };
+
+            log::info!("Reporting crashes to Sentry");
+            Some(sentry::init(sentry::ClientOptions {
+                dsn: Some(dsn),
+                environment: config.environment.clone().map(Into::into),
+                release: config
+                    .release
+                    .clone()
+                    .map(Into::into)
+                    .or_else(|| sentry::release_name!()),
+                ..Default::default()
+            }))
+        }
+    } else {
+        /// Crash reporting is not available in this build
+        pub fn init_crash_reporting(config: &TelemetryConfig) {
+            if config.dsn.is_some() {
+                log::warn!("A Sentry DSN is configured, but this build of protomask does not support crash reporting. Rebuild with the `sentry` feature to enable it.");
+            }
+        }
+    }
+}
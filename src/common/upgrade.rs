@@ -0,0 +1,221 @@
+//! Zero-downtime upgrades
+//!
+//! A NAT64 with an upgrade socket hands everything it needs to keep translating over to a new process started with
+//! `--take-over`: the file descriptors of its TUN queues (passed with `SCM_RIGHTS`) and its mapping table. The TUN
+//! interface and its routes stay in place throughout, and packets that arrive mid-handoff wait in the kernel's queues
+//! until the new process starts reading them.
+//!
+//! The handoff goes as follows:
+//! 1. The new process connects to the upgrade socket
+//! 2. The old process stops making new mappings, then sends its queues alongside the first byte of a line of JSON
+//!    describing its state
+//! 3. The new process replies with `ready`
+//! 4. The old process exits, closing the connection
+//! 5. Seeing the connection close, the new process starts binding the sockets the old one held
+
+use super::{failover::Failover, permissions::bind_private_socket, prefix_tables::PrefixTables};
+use easy_tun::Tun;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, IoSlice, IoSliceMut, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::Path,
+    sync::Arc,
+};
+
+/// The most file descriptors Linux allows in a single message (`SCM_MAX_FD`)
+const MAX_QUEUES: usize = 253;
+
+/// Everything sent from the old process to the new one (other than the queues themselves)
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct State {
+    interface: String,
+    active: bool,
+    mappings: Vec<Mapping>,
+}
+
+/// A dynamic mapping being handed over
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Mapping {
+    pub ipv4: Ipv4Addr,
+    pub ipv6: Ipv6Addr,
+    pub expires_in_secs: u64,
+}
+
+/// Everything received from the old process
+pub struct Handoff {
+    pub tun: Tun,
+    /// Whether the old process was active (and so whether routes are installed)
+    pub active: bool,
+    pub mappings: Vec<Mapping>,
+}
+
+/// Wait for a new process to take over, then hand over to it and exit. Only returns if the socket can't be set up.
+pub async fn serve_upgrades(
+    path: &Path,
+    tun: Arc<Tun>,
+    prefix_tables: Arc<PrefixTables>,
+    failover: Arc<Failover>,
+) -> io::Result<()> {
+    // Only root may take over the translator
+    let listener = bind_private_socket(path)?;
+    log::info!("Listening for upgrades on {}", path.display());
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                log::warn!("Failed to accept upgrade connection: {}", error);
+                continue;
+            }
+        };
+
+        // Workers keep translating until we exit, so stop them from making mappings the snapshot would miss
+        log::info!("A new process is taking over");
+        prefix_tables.set_frozen(true);
+        let state = State {
+            interface: tun.name().to_string(),
+            active: failover.is_active().await,
+            mappings: dynamic_mappings(&prefix_tables),
+        };
+        let tun = Arc::clone(&tun);
+        let result = match stream.into_std() {
+            Ok(stream) => tokio::task::spawn_blocking(move || hand_over(stream, &tun, &state))
+                .await
+                .unwrap_or_else(|error| Err(io::Error::other(error))),
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(()) => {
+                log::info!("Handed over to the new process. Exiting");
+                std::process::exit(0);
+            }
+            Err(error) => {
+                prefix_tables.set_frozen(false);
+                log::warn!(
+                    "Upgrade failed, so this process will keep running: {}",
+                    error
+                );
+            }
+        }
+    }
+}
+
+/// Take over from the process listening on an upgrade socket
+pub fn take_over(path: &Path, num_queues: usize) -> Result<Handoff, String> {
+    log::info!("Taking over from the process at {}", path.display());
+    let stream = UnixStream::connect(path)
+        .map_err(|error| format!("Failed to connect to {}: {}", path.display(), error))?;
+    receive_handoff(stream, num_queues).map_err(|error| format!("Failed to take over: {}", error))
+}
+
+/// Send our queues and state, then wait for the new process to be ready
+fn hand_over(mut stream: UnixStream, tun: &Tun, state: &State) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let fds: Vec<RawFd> = (0..tun.num_queues())
+        .filter_map(|queue| tun.fd(queue))
+        .map(AsRawFd::as_raw_fd)
+        .collect();
+    let mut message = serde_json::to_vec(state)?;
+    message.push(b'\n');
+
+    // The queues travel alongside the first byte of state
+    sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&message[..1])],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    stream.write_all(&message[1..])?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    if reply.trim() == "ready" {
+        Ok(())
+    } else {
+        Err(io::Error::other("the new process gave up"))
+    }
+}
+
+/// Receive queues and state from the old process, then let it know we are ready
+fn receive_handoff(stream: UnixStream, num_queues: usize) -> io::Result<Handoff> {
+    // Receive the queues along with the first byte of state
+    let mut first = [0u8; 1];
+    let mut fds = Vec::new();
+    {
+        let mut iov = [IoSliceMut::new(&mut first)];
+        let mut control = nix::cmsg_space!([RawFd; MAX_QUEUES]);
+        let message = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut control),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        for control in message.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = control {
+                fds.extend(received);
+            }
+        }
+    }
+    let fds: Vec<File> = fds
+        .into_iter()
+        .map(|fd| unsafe { File::from_raw_fd(fd) })
+        .collect();
+
+    // Read the rest of the state
+    let mut reader = BufReader::new(&stream);
+    let mut line = first.to_vec();
+    reader.read_until(b'\n', &mut line)?;
+    let state: State = serde_json::from_slice(&line)?;
+    if fds.len() != num_queues {
+        return Err(io::Error::other(format!(
+            "the old process has {} queues, but {} are configured",
+            fds.len(),
+            num_queues
+        )));
+    }
+
+    // The old process exits once we are ready, closing the connection
+    (&stream).write_all(b"ready\n")?;
+    reader.read_to_end(&mut Vec::new())?;
+
+    log::info!(
+        "Took over {} with {} mappings",
+        state.interface,
+        state.mappings.len()
+    );
+    Ok(Handoff {
+        tun: Tun::from_files(state.interface, fds),
+        active: state.active,
+        mappings: state.mappings,
+    })
+}
+
+/// Collect every mapping that isn't static. Static mappings come from the new process' own config.
+fn dynamic_mappings(prefix_tables: &PrefixTables) -> Vec<Mapping> {
+    prefix_tables
+        .tables()
+        .flat_map(|table| {
+            table
+                .lock()
+                .unwrap()
+                .mappings()
+                .filter_map(|(ipv4, ipv6, remaining)| {
+                    remaining.map(|remaining| Mapping {
+                        ipv4,
+                        ipv6,
+                        // Rounded up, so that mappings about to expire aren't handed over already expired
+                        expires_in_secs: remaining.as_secs()
+                            + u64::from(remaining.subsec_nanos() > 0),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
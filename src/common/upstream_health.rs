@@ -0,0 +1,153 @@
+//! Upstream health probes
+//!
+//! A translator whose IPv4 or IPv6 uplink has failed still attracts traffic with its routes, and blackholes it. When
+//! configured, targets on either side are pinged at a regular interval. After enough consecutive rounds with a target
+//! not answering, the translator withdraws its routes and reports itself as not ready, so traffic fails over to
+//! another translator. Once enough consecutive rounds pass again, the routes are restored.
+
+use super::failover::Failover;
+use pnet_packet::icmp::{self, IcmpPacket};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How to probe the upstream
+pub struct UpstreamProbes {
+    /// Addresses that must all answer pings
+    pub targets: Vec<IpAddr>,
+    pub interval: Duration,
+    /// How long to wait for each answer
+    pub timeout: Duration,
+    /// Consecutive failed rounds before routes are withdrawn
+    pub failures: u32,
+    /// Consecutive passed rounds before routes are restored
+    pub successes: u32,
+}
+
+/// Probe the upstream forever, moving routes as its health changes
+pub async fn monitor(probes: UpstreamProbes, failover: Arc<Failover>) {
+    let probes = Arc::new(probes);
+    let mut interval = tokio::time::interval(probes.interval);
+    let mut healthy = true;
+    let mut streak = 0;
+    let mut sequence: u16 = 0;
+    loop {
+        interval.tick().await;
+        sequence = sequence.wrapping_add(1);
+
+        // Ping every target, one after the other
+        let passed = {
+            let probes = Arc::clone(&probes);
+            tokio::task::spawn_blocking(move || {
+                probes
+                    .targets
+                    .iter()
+                    .all(|target| match ping(*target, sequence, probes.timeout) {
+                        Ok(rtt) => {
+                            log::trace!("Upstream probe {} answered in {:?}", target, rtt);
+                            true
+                        }
+                        Err(error) => {
+                            log::debug!("Upstream probe {} failed: {}", target, error);
+                            false
+                        }
+                    })
+            })
+            .await
+            .unwrap()
+        };
+
+        // Count rounds going against the current state, and switch once there have been enough in a row
+        if passed == healthy {
+            streak = 0;
+            continue;
+        }
+        streak += 1;
+        let needed = if healthy {
+            probes.failures
+        } else {
+            probes.successes
+        };
+        if streak >= needed {
+            streak = 0;
+            healthy = passed;
+            if let Err(error) = failover.set_upstream_healthy(healthy).await {
+                log::error!(
+                    "Failed to move routes after upstream health changed: {}",
+                    error
+                );
+            }
+        }
+    }
+}
+
+/// Send an ICMP or ICMPv6 echo request, returning the round-trip time once it is answered
+pub fn ping(target: IpAddr, sequence: u16, timeout: Duration) -> Result<Duration, std::io::Error> {
+    let (domain, protocol, request_type, reply_type) = match target {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4, 8, 0),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6, 128, 129),
+    };
+    let socket = Socket::new(domain, Type::RAW, Some(protocol))?;
+    socket.connect(&SocketAddr::new(target, 0).into())?;
+
+    // The kernel fills in the checksum of ICMPv6 packets, but not of ICMP ones
+    let identifier = (std::process::id() as u16).to_be_bytes();
+    let sequence = sequence.to_be_bytes();
+    let mut request = [
+        request_type,
+        0,
+        0,
+        0,
+        identifier[0],
+        identifier[1],
+        sequence[0],
+        sequence[1],
+    ];
+    if target.is_ipv4() {
+        let checksum = icmp::checksum(&IcmpPacket::new(&request).unwrap()).to_be_bytes();
+        request[2..4].copy_from_slice(&checksum);
+    }
+    let sent = Instant::now();
+    socket.send(&request)?;
+
+    // Raw sockets see every reply, so wait for ours
+    let mut buffer = [0u8; 1500];
+    loop {
+        let remaining = timeout.saturating_sub(sent.elapsed());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let len = match (&socket).read(&mut buffer) {
+            Ok(len) => len,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(std::io::ErrorKind::TimedOut.into())
+            }
+            Err(error) => return Err(error),
+        };
+
+        // IPv4 raw sockets also hand over the IP header
+        let reply = match target {
+            IpAddr::V4(_) => buffer
+                .get(usize::from(buffer[0] & 0x0f) * 4..len)
+                .unwrap_or_default(),
+            IpAddr::V6(_) => &buffer[..len],
+        };
+        if reply.len() >= 8
+            && reply[0] == reply_type
+            && reply[4..6] == identifier
+            && reply[6..8] == sequence
+        {
+            return Ok(sent.elapsed());
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Sanity checks on packets read from the TUN interface
+//!
+//! Everything after this point reads addresses and headers at fixed offsets, so packets are checked here first.
+//! Anything too short for the headers it claims to have is turned into an error (and a counted drop) instead of a
+//! panic somewhere deeper in a worker.
+
+use super::counters::DropReason;
+
+/// Smallest possible IPv4 header
+const IPV4_HEADER_LEN: usize = 20;
+
+/// Length of the fixed IPv6 header
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Why a packet can't be handled
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MalformedPacket {
+    #[error("Empty packet")]
+    Empty,
+    #[error("Unknown IP version: {0}")]
+    UnknownVersion(u8),
+    #[error("IPv{version} packet is {actual} bytes, shorter than its {expected} byte header")]
+    TruncatedHeader {
+        version: u8,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("IPv4 header length of {0} bytes is less than the minimum of 20")]
+    InvalidHeaderLength(usize),
+    #[error("IPv{version} packet is {actual} bytes, but claims to be {claimed}")]
+    LengthMismatch {
+        version: u8,
+        claimed: usize,
+        actual: usize,
+    },
+}
+
+impl MalformedPacket {
+    /// Get the reason to count a drop of this packet under
+    pub fn drop_reason(&self) -> DropReason {
+        match self {
+            Self::UnknownVersion(_) => DropReason::UnknownProtocol,
+            _ => DropReason::Malformed,
+        }
+    }
+}
+
+/// Check that a packet is a well-formed IPv4 or IPv6 packet, returning its IP version and the length given in its
+/// header.
+///
+/// Trailing bytes beyond that length are allowed, since some links pad short frames, but must be cut off with the
+/// returned length before the packet is handled. Otherwise they would be translated as part of the payload.
+pub fn validate_packet(packet: &[u8]) -> Result<(u8, usize), MalformedPacket> {
+    let version = packet.first().ok_or(MalformedPacket::Empty)? >> 4;
+    match version {
+        4 => {
+            if packet.len() < IPV4_HEADER_LEN {
+                return Err(MalformedPacket::TruncatedHeader {
+                    version,
+                    expected: IPV4_HEADER_LEN,
+                    actual: packet.len(),
+                });
+            }
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            if header_len < IPV4_HEADER_LEN {
+                return Err(MalformedPacket::InvalidHeaderLength(header_len));
+            }
+            if packet.len() < header_len {
+                return Err(MalformedPacket::TruncatedHeader {
+                    version,
+                    expected: header_len,
+                    actual: packet.len(),
+                });
+            }
+            let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+            if total_len < header_len || total_len > packet.len() {
+                return Err(MalformedPacket::LengthMismatch {
+                    version,
+                    claimed: total_len,
+                    actual: packet.len(),
+                });
+            }
+            Ok((version, total_len))
+        }
+        6 => {
+            if packet.len() < IPV6_HEADER_LEN {
+                return Err(MalformedPacket::TruncatedHeader {
+                    version,
+                    expected: IPV6_HEADER_LEN,
+                    actual: packet.len(),
+                });
+            }
+            let total_len =
+                IPV6_HEADER_LEN + usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+            if total_len > packet.len() {
+                return Err(MalformedPacket::LengthMismatch {
+                    version,
+                    claimed: total_len,
+                    actual: packet.len(),
+                });
+            }
+            Ok((version, total_len))
+        }
+        _ => Err(MalformedPacket::UnknownVersion(version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an IPv4 packet with a header of `header_len` bytes, claiming to be `total_len` bytes long
+    fn ipv4(header_len: usize, total_len: u16, actual_len: usize) -> Vec<u8> {
+        let mut packet = vec![0; actual_len];
+        packet[0] = 0x40 | (header_len / 4) as u8;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet
+    }
+
+    /// Build an IPv6 packet claiming a payload of `payload_len` bytes
+    fn ipv6(payload_len: u16, actual_len: usize) -> Vec<u8> {
+        let mut packet = vec![0; actual_len];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_valid_packets() {
+        assert_eq!(validate_packet(&ipv4(20, 28, 28)), Ok((4, 28)));
+        assert_eq!(validate_packet(&ipv4(24, 24, 24)), Ok((4, 24)));
+        assert_eq!(validate_packet(&ipv6(8, 48)), Ok((6, 48)));
+        assert_eq!(validate_packet(&ipv6(0, 40)), Ok((6, 40)));
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        // Padding is left out of the length to handle
+        assert_eq!(validate_packet(&ipv4(20, 40, 60)), Ok((4, 40)));
+        assert_eq!(validate_packet(&ipv6(8, 64)), Ok((6, 48)));
+    }
+
+    #[test]
+    fn test_short_headers() {
+        assert_eq!(validate_packet(&[]), Err(MalformedPacket::Empty));
+        assert_eq!(
+            validate_packet(&ipv4(20, 20, 19)),
+            Err(MalformedPacket::TruncatedHeader {
+                version: 4,
+                expected: 20,
+                actual: 19
+            })
+        );
+        assert_eq!(
+            validate_packet(&ipv4(60, 60, 40)),
+            Err(MalformedPacket::TruncatedHeader {
+                version: 4,
+                expected: 60,
+                actual: 40
+            })
+        );
+        assert_eq!(
+            validate_packet(&ipv6(0, 39)),
+            Err(MalformedPacket::TruncatedHeader {
+                version: 6,
+                expected: 40,
+                actual: 39
+            })
+        );
+    }
+
+    #[test]
+    fn test_bad_version_and_header_length() {
+        let mut packet = ipv4(20, 20, 20);
+        packet[0] = 0x55;
+        assert_eq!(
+            validate_packet(&packet),
+            Err(MalformedPacket::UnknownVersion(5))
+        );
+        assert_eq!(
+            validate_packet(&ipv4(16, 20, 20)),
+            Err(MalformedPacket::InvalidHeaderLength(16))
+        );
+    }
+
+    #[test]
+    fn test_length_mismatches() {
+        // Longer than what was read
+        assert_eq!(
+            validate_packet(&ipv4(20, 40, 30)),
+            Err(MalformedPacket::LengthMismatch {
+                version: 4,
+                claimed: 40,
+                actual: 30
+            })
+        );
+        assert_eq!(
+            validate_packet(&ipv6(20, 50)),
+            Err(MalformedPacket::LengthMismatch {
+                version: 6,
+                claimed: 60,
+                actual: 50
+            })
+        );
+
+        // Shorter than its own header
+        assert_eq!(
+            validate_packet(&ipv4(24, 20, 24)),
+            Err(MalformedPacket::LengthMismatch {
+                version: 4,
+                claimed: 20,
+                actual: 24
+            })
+        );
+    }
+}
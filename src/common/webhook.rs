@@ -0,0 +1,151 @@
+//! Webhook notifications for mapping events
+//!
+//! External systems (billing, abuse handling, a SIEM) often need to know which client held an address, and when,
+//! without scraping logs. When configured, every mapping creation and expiry is POSTed to a webhook as JSON.
+//!
+//! Events are batched by a background thread, which sends them once enough have built up or a short time has passed.
+//! Like the session log, events are handed over through a bounded queue, so a slow or unreachable receiver never stalls
+//! packet translation. Batches that can't be delivered are dropped after a few attempts.
+
+use super::http::{self, HttpUrl};
+use fast_nat::MappingEvent;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How long the receiver may take to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of times a batch is sent before giving up on it
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Handle used to submit events to the webhook thread
+#[derive(Clone)]
+pub struct Webhook {
+    sender: SyncSender<serde_json::Value>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Webhook {
+    /// Spawn a background sender, POSTing batches of up to `batch_size` events at least every `flush_interval`.
+    /// Up to `queue_capacity` events may be waiting to be sent.
+    pub fn new(
+        url: &str,
+        batch_size: usize,
+        flush_interval: Duration,
+        queue_capacity: usize,
+    ) -> Result<Self, String> {
+        let url = HttpUrl::parse(url)?;
+        let (sender, receiver) = sync_channel::<serde_json::Value>(queue_capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        {
+            let dropped = Arc::clone(&dropped);
+            std::thread::Builder::new()
+                .name("webhook".to_string())
+                .spawn(move || {
+                    let mut batch = Vec::new();
+                    let mut deadline = Instant::now() + flush_interval;
+                    loop {
+                        let timeout = deadline.saturating_duration_since(Instant::now());
+                        let disconnected = match receiver.recv_timeout(timeout) {
+                            Ok(event) => {
+                                batch.push(event);
+                                false
+                            }
+                            Err(RecvTimeoutError::Timeout) => false,
+                            Err(RecvTimeoutError::Disconnected) => true,
+                        };
+
+                        if batch.len() >= batch_size || Instant::now() >= deadline || disconnected
+                        {
+                            if !batch.is_empty() {
+                                send_batch(&url, &batch, &dropped);
+                                batch.clear();
+                            }
+                            deadline = Instant::now() + flush_interval;
+                        }
+                        if disconnected {
+                            break;
+                        }
+                    }
+                })
+                .map_err(|error| error.to_string())?;
+        }
+
+        Ok(Self { sender, dropped })
+    }
+
+    /// Queue an event to be sent. This will never block.
+    pub fn notify(&self, event: MappingEvent) {
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        let event = match event {
+            MappingEvent::Created {
+                ipv4,
+                ipv6,
+                indefinite,
+            } => serde_json::json!({
+                "event": "created",
+                "timestamp": timestamp,
+                "ipv4": ipv4,
+                "ipv6": ipv6,
+                "static": indefinite,
+            }),
+            MappingEvent::Expired { ipv4, ipv6 } => serde_json::json!({
+                "event": "expired",
+                "timestamp": timestamp,
+                "ipv4": ipv4,
+                "ipv6": ipv6,
+            }),
+        };
+
+        match self.sender.try_send(event) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                // Only complain occasionally, since this will happen under sustained load
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+                if dropped.is_multiple_of(1000) {
+                    log::warn!(
+                        "Webhook queue is full. Dropped {} event(s) so far",
+                        dropped + 1
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// POST a batch of events, retrying a few times before dropping it
+fn send_batch(url: &HttpUrl, batch: &[serde_json::Value], dropped: &AtomicU64) {
+    let body = serde_json::json!({ "events": batch }).to_string();
+    let mut error = String::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        match http::request(
+            url,
+            "POST",
+            &url.path,
+            Some(("application/json", body.as_bytes())),
+            REQUEST_TIMEOUT,
+        ) {
+            Ok((status, _)) if (200..300).contains(&status) => return,
+            Ok((status, _)) => error = format!("Webhook answered with status {}", status),
+            Err(message) => error = message,
+        }
+        if attempt < MAX_ATTEMPTS {
+            std::thread::sleep(Duration::from_secs(u64::from(attempt)));
+        }
+    }
+
+    let total = dropped.fetch_add(batch.len() as u64, Ordering::Relaxed) + batch.len() as u64;
+    log::warn!(
+        "Dropped {} webhook event(s) ({} so far): {}",
+        batch.len(),
+        total,
+        error
+    );
+}
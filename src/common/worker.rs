@@ -0,0 +1,280 @@
+//! The packet handling loop shared by the translators
+//!
+//! Every translator reads a packet, checks that it is safe to pick apart, decides what becomes of it, and writes out
+//! whatever it turned into. Only the decision differs between translators, so that is left to a [`PacketProcessor`],
+//! and everything around it (queues, busy polling, NUMA placement, drop accounting, stage timing and packet traces)
+//! is handled here the same way for all of them.
+
+use super::{
+    busy_poll::{set_nonblocking, TunReader},
+    capture::DropCapture,
+    counters::{DropReason, QueueCounters},
+    numa::NumaPlacement,
+    packet_buffer::InterfaceMtu,
+    packet_queue::{spawn_reader, spawn_writer, PacketSink, PacketSource},
+    packet_trace::{trace_stage, PacketTracer},
+    recent_drops::RecentDrops,
+    stage_timer::{end_stage, StageSampler},
+    validation::validate_packet,
+};
+use crate::args::protomask::PipelineConfig;
+use easy_tun::Tun;
+use protomask_metrics::metrics::label_values::{
+    CHANNEL_EGRESS, CHANNEL_INGRESS, STAGE_ACCOUNTING, STAGE_PARSE, STAGE_WRITE,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread::JoinHandle,
+};
+
+/// What becomes of a packet
+pub enum Verdict {
+    /// Translated, and to be sent out of the interface for its new address family
+    Translated(Vec<u8>),
+    /// Answered by the translator itself, with a reply to be sent back the way the packet came
+    Reply(Vec<u8>),
+    /// Dropped, with the reason recorded in drop captures (for drops that are captured)
+    Dropped(DropReason, Option<String>),
+    /// Dropped like `Dropped`, but with a refusal to be sent back the way the packet came
+    Rejected(DropReason, Option<String>, Vec<u8>),
+}
+
+/// Decides what becomes of the packets read by workers
+pub trait PacketProcessor: Send + Sync + 'static {
+    /// Decide what becomes of a packet that has passed validation, calling `stage` as each stage of handling ends
+    fn process(
+        &self,
+        packet: &mut [u8],
+        counters: &QueueCounters,
+        stage: impl FnMut(&'static str),
+    ) -> Verdict;
+}
+
+/// Everything the workers of a translator share
+pub struct WorkerPool<'a> {
+    /// Pairs of an interface to read packets from, and the interface to write their translations to
+    pub directions: &'a [(Arc<Tun>, Arc<Tun>)],
+    pub num_queues: usize,
+    pub pipeline: &'a PipelineConfig,
+    pub interface_mtu: &'a Arc<InterfaceMtu>,
+    pub numa: Option<NumaPlacement>,
+    /// Counters for each queue, indexed by queue ID
+    pub queue_counters: Arc<Vec<QueueCounters>>,
+    pub recent_drops: Arc<RecentDrops>,
+    pub drop_capture: Option<Arc<DropCapture>>,
+    pub stage_timing_sample_rate: f64,
+    pub packet_trace_sample_rate: f64,
+}
+
+impl WorkerPool<'_> {
+    /// Start a worker for every queue of every direction, along with any reader and writer threads, returning the
+    /// handles of all of them. `account` is called with each packet and its translation just before it is written.
+    pub fn spawn<P: PacketProcessor>(
+        self,
+        processor: Arc<P>,
+        account: impl Fn(&[u8], &[u8]) + Send + Sync + 'static,
+    ) -> Vec<JoinHandle<()>> {
+        let account = Arc::new(account);
+        if let Some(numa) = &self.numa {
+            log::info!(
+                "Placing packet handling threads on NUMA node {}",
+                numa.node()
+            );
+        }
+        let mut worker_threads = Vec::new();
+
+        // If configured, spin on the interface's queues rather than sleeping until packets arrive
+        let pipeline = self.pipeline;
+        let busy_poll_budget = pipeline.busy_poll_budget();
+        let buffer_size = pipeline.buffer_size(self.interface_mtu);
+        if let Some(budget) = busy_poll_budget {
+            log::info!("Busy polling for up to {:?} before sleeping", budget);
+        }
+        for (tun, _) in self.directions {
+            set_nonblocking(tun, busy_poll_budget.is_some()).unwrap();
+        }
+
+        // If configured, write through a queue and writer thread for each interface queue, shared by both directions
+        let sinks: Vec<(Arc<Tun>, Vec<PacketSink>)> = self
+            .directions
+            .iter()
+            .map(|(tun, _)| {
+                let sinks = (0..self.num_queues)
+                    .map(|queue_id| {
+                        if !pipeline.is_enabled() {
+                            return PacketSink::Tun {
+                                tun: Arc::clone(tun),
+                                queue_id,
+                            };
+                        }
+                        let queue = Arc::new(pipeline.queue(CHANNEL_EGRESS));
+                        worker_threads.push(spawn_writer(
+                            Arc::clone(tun),
+                            queue_id,
+                            Arc::clone(&queue),
+                            self.numa.clone(),
+                        ));
+                        PacketSink::Queue(queue)
+                    })
+                    .collect();
+                (Arc::clone(tun), sinks)
+            })
+            .collect();
+        let sink_for = |tun: &Arc<Tun>, queue_id: usize| {
+            sinks
+                .iter()
+                .find(|(sink_tun, _)| Arc::ptr_eq(sink_tun, tun))
+                .map(|(_, sinks)| sinks[queue_id].clone())
+                .unwrap()
+        };
+
+        for (queue_id, (tun, egress)) in (0..self.num_queues).flat_map(|queue_id| {
+            self.directions
+                .iter()
+                .map(move |direction| (queue_id, direction.clone()))
+        }) {
+            let queue_counters = Arc::clone(&self.queue_counters);
+
+            // If configured, read through a queue filled by a reader thread
+            let reply_sink = sink_for(&tun, queue_id);
+            let output_sink = sink_for(&egress, queue_id);
+            let reader = TunReader::new(Arc::clone(&tun), queue_id, busy_poll_budget);
+            let source = if pipeline.is_enabled() {
+                let queue = Arc::new(pipeline.queue(CHANNEL_INGRESS));
+                let queue_counters = Arc::clone(&queue_counters);
+                worker_threads.push(spawn_reader(
+                    reader,
+                    buffer_size.clone(),
+                    Arc::clone(&queue),
+                    self.numa.clone(),
+                    move |accepted| {
+                        let counters = &queue_counters[queue_id];
+                        counters.packets_received.fetch_add(1, Ordering::Relaxed);
+                        if !accepted {
+                            counters.record_drop(DropReason::QueueFull);
+                        }
+                    },
+                ));
+                PacketSource::Queue(queue)
+            } else {
+                PacketSource::Tun(reader)
+            };
+            let recent_drops = Arc::clone(&self.recent_drops);
+            let drop_capture = self.drop_capture.clone();
+            let processor = Arc::clone(&processor);
+            let account = Arc::clone(&account);
+            let numa = self.numa.clone();
+            let buffer_size = buffer_size.clone();
+            let stage_timing_sample_rate = self.stage_timing_sample_rate;
+            let packet_trace_sample_rate = self.packet_trace_sample_rate;
+            worker_threads.push(std::thread::spawn(move || {
+                log::debug!(
+                    "Starting worker thread for queue {} of {}",
+                    queue_id,
+                    tun.name()
+                );
+                if let Some(numa) = &numa {
+                    numa.apply();
+                }
+                let _health_guard = protomask_metrics::health::WorkerGuard::new();
+
+                let mut buffer = buffer_size.buffer();
+                let mut sampler = StageSampler::new(stage_timing_sample_rate);
+                let mut tracer = PacketTracer::new(packet_trace_sample_rate, queue_id);
+                loop {
+                    // Indicate to the profiler that we are starting a new packet
+                    profiling::finish_frame!();
+                    profiling::scope!("packet");
+
+                    // Read a packet. Reader threads count the packets they queue themselves.
+                    buffer_size.fit(&mut buffer);
+                    let len = source.read(&mut buffer);
+                    let counters = &queue_counters[queue_id];
+                    if let PacketSource::Tun(_) = source {
+                        counters.packets_received.fetch_add(1, Ordering::Relaxed);
+                    }
+                    let mut timer = sampler.start();
+                    let mut trace = tracer.start(len);
+
+                    // Make sure the packet is safe to pick apart, and cut off any padding
+                    let len = match validate_packet(&buffer[..len]) {
+                        Ok((_, len)) => len,
+                        Err(error) => {
+                            log::debug!("Dropping malformed packet: {}", error);
+                            counters.record_drop(error.drop_reason());
+                            let detail = error.to_string();
+                            recent_drops.record(&buffer[..len], error.drop_reason(), Some(&detail));
+                            if let Some(capture) = &drop_capture {
+                                capture.record(&buffer[..len], &detail);
+                            }
+                            continue;
+                        }
+                    };
+                    end_stage(&mut timer, STAGE_PARSE);
+                    if let Some(trace) = &mut trace {
+                        trace.input(&buffer[..len]);
+                        trace.stage(STAGE_PARSE);
+                    }
+
+                    // Decide what becomes of the packet
+                    let verdict = processor.process(&mut buffer[..len], counters, |stage| {
+                        end_stage(&mut timer, stage);
+                        trace_stage(&mut trace, stage);
+                    });
+                    let output = match verdict {
+                        Verdict::Translated(output) => output,
+                        Verdict::Reply(reply) => {
+                            if !reply_sink.write(&reply) {
+                                counters.record_drop(DropReason::QueueFull);
+                                continue;
+                            }
+                            if let Some(trace) = &mut trace {
+                                trace.output(&reply);
+                            }
+                            counters.packets_sent.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                        Verdict::Dropped(reason, detail) => {
+                            counters.record_drop(reason);
+                            recent_drops.record(&buffer[..len], reason, detail.as_deref());
+                            if let (Some(capture), Some(detail)) = (&drop_capture, detail) {
+                                capture.record(&buffer[..len], &detail);
+                            }
+                            continue;
+                        }
+                        Verdict::Rejected(reason, detail, reply) => {
+                            counters.record_drop(reason);
+                            recent_drops.record(&buffer[..len], reason, detail.as_deref());
+                            if let (Some(capture), Some(detail)) = (&drop_capture, detail) {
+                                capture.record(&buffer[..len], &detail);
+                            }
+                            if reply_sink.write(&reply) {
+                                if let Some(trace) = &mut trace {
+                                    trace.output(&reply);
+                                }
+                            }
+                            continue;
+                        }
+                    };
+
+                    // Account for the translated packet and write it
+                    account(&buffer[..len], &output);
+                    end_stage(&mut timer, STAGE_ACCOUNTING);
+                    trace_stage(&mut trace, STAGE_ACCOUNTING);
+                    let written = output_sink.write(&output);
+                    end_stage(&mut timer, STAGE_WRITE);
+                    trace_stage(&mut trace, STAGE_WRITE);
+                    if !written {
+                        counters.record_drop(DropReason::QueueFull);
+                        continue;
+                    }
+                    if let Some(trace) = &mut trace {
+                        trace.output(&output);
+                    }
+                    counters.packets_sent.fetch_add(1, Ordering::Relaxed);
+                }
+            }));
+        }
+        worker_threads
+    }
+}
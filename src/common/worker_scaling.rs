@@ -0,0 +1,175 @@
+//! Adaptive scaling of translation workers
+//!
+//! Every queue of the TUN interface has a worker thread of its own. With scaling enabled, only a minimum number of
+//! queues are attached to the interface at first, and the workers of the others wait. Once a second, the share of its
+//! time each active worker spent handling packets (rather than waiting for one) is measured. When the active workers
+//! are busy, another queue is attached. When the load would have fit on one fewer worker for a while, the last queue
+//! is detached again. Any packets still waiting in a queue are dropped by the kernel when it is detached.
+
+use easy_tun::Tun;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often utilization is measured
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Average utilization of the active workers above which another queue is attached
+const SCALE_UP_UTILIZATION: f64 = 0.75;
+
+/// Utilization the remaining workers must stay below for a queue to be detached
+const SCALE_DOWN_UTILIZATION: f64 = 0.5;
+
+/// Number of consecutive intervals the load must fit on fewer workers before a queue is detached
+const SCALE_DOWN_INTERVALS: u32 = 10;
+
+/// Keeps track of which queues are active and how busy their workers are
+pub struct WorkerScaler {
+    tun: Arc<Tun>,
+    min_active: usize,
+    /// Total time each queue's worker has spent handling packets, in nanoseconds
+    busy_ns: Vec<AtomicU64>,
+    /// Queues `0..active` are attached
+    active: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl WorkerScaler {
+    /// Scale between `min_active` and all of the interface's queues, detaching all but the first `min_active` for now
+    pub fn new(tun: Arc<Tun>, min_active: usize) -> Self {
+        let num_queues = tun.num_queues();
+        let min_active = min_active.clamp(1, num_queues);
+        for queue_id in min_active..num_queues {
+            tun.set_queue_enabled(queue_id, false).unwrap();
+        }
+        protomask_metrics::metrics::ACTIVE_QUEUES.set(tun.num_attached_queues() as i64);
+        log::info!(
+            "Scaling {} between {} and {} queues",
+            tun.name(),
+            min_active,
+            num_queues
+        );
+        Self {
+            min_active,
+            busy_ns: (0..num_queues).map(|_| AtomicU64::new(0)).collect(),
+            active: Mutex::new(min_active),
+            changed: Condvar::new(),
+            tun,
+        }
+    }
+
+    /// Check if a queue is currently attached
+    pub fn is_active(&self, queue_id: usize) -> bool {
+        queue_id < *self.active.lock().unwrap()
+    }
+
+    /// Block until a queue is attached
+    pub fn wait_until_active(&self, queue_id: usize) {
+        let _active = self
+            .changed
+            .wait_while(self.active.lock().unwrap(), |active| queue_id >= *active)
+            .unwrap();
+    }
+
+    /// Count the time until the returned guard is dropped as time a queue's worker spent busy
+    pub fn busy(&self, queue_id: usize) -> BusyGuard<'_> {
+        BusyGuard {
+            busy_ns: &self.busy_ns[queue_id],
+            started: Instant::now(),
+        }
+    }
+
+    /// Measure utilization and scale accordingly, forever
+    pub async fn monitor(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(INTERVAL);
+        let mut last_busy_ns = vec![0u64; self.busy_ns.len()];
+        let mut last_tick = Instant::now();
+        let mut quiet_intervals = 0;
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            let elapsed_ns = now.duration_since(last_tick).as_nanos() as f64;
+            last_tick = now;
+
+            // Work out how busy the active workers were since the last tick
+            let active = *self.active.lock().unwrap();
+            let mut busy_ns = 0;
+            for (queue_id, last) in last_busy_ns.iter_mut().enumerate() {
+                let total = self.busy_ns[queue_id].load(Ordering::Relaxed);
+                if queue_id < active {
+                    busy_ns += total - *last;
+                }
+                *last = total;
+            }
+            let utilization = busy_ns as f64 / (elapsed_ns * active as f64);
+
+            // How busy the workers would have been with one fewer of them
+            let utilization_without_one = if active > 1 {
+                utilization * active as f64 / (active - 1) as f64
+            } else {
+                f64::INFINITY
+            };
+
+            if utilization > SCALE_UP_UTILIZATION && active < self.busy_ns.len() {
+                quiet_intervals = 0;
+                self.set_active(active + 1);
+            } else if active > self.min_active && utilization_without_one < SCALE_DOWN_UTILIZATION {
+                quiet_intervals += 1;
+                if quiet_intervals >= SCALE_DOWN_INTERVALS {
+                    quiet_intervals = 0;
+                    self.set_active(active - 1);
+                }
+            } else {
+                quiet_intervals = 0;
+            }
+        }
+    }
+
+    /// Attach or detach the last queue to reach `count` active queues
+    fn set_active(&self, count: usize) {
+        let mut active = self.active.lock().unwrap();
+        let scaling_up = count > *active;
+
+        // A queue is attached before its worker is woken, and its worker is stopped before it is detached
+        let queue_id = if scaling_up { *active } else { count };
+        if scaling_up {
+            if let Err(error) = self.tun.set_queue_enabled(queue_id, true) {
+                log::warn!("Failed to attach queue {}: {}", queue_id, error);
+                return;
+            }
+        }
+        *active = count;
+        drop(active);
+        self.changed.notify_all();
+        if !scaling_up {
+            if let Err(error) = self.tun.set_queue_enabled(queue_id, false) {
+                log::warn!("Failed to detach queue {}: {}", queue_id, error);
+            }
+        }
+
+        protomask_metrics::metrics::ACTIVE_QUEUES.set(self.tun.num_attached_queues() as i64);
+        log::info!(
+            "Scaled {} to {} of {} queues",
+            self.tun.name(),
+            count,
+            self.busy_ns.len()
+        );
+    }
+}
+
+/// Adds the time it was alive to a worker's busy time when dropped
+pub struct BusyGuard<'a> {
+    busy_ns: &'a AtomicU64,
+    started: Instant,
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        let elapsed = u64::try_from(self.started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.busy_ns.fetch_add(elapsed, Ordering::Relaxed);
+    }
+}
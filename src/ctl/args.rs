@@ -0,0 +1,149 @@
+//! Commandline arguments for `protomaskctl`
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use std::{net::IpAddr, path::PathBuf};
+
+#[derive(Debug, clap::Parser)]
+#[clap(author, version, about="Control a running protomask process", long_about = None)]
+pub struct Args {
+    /// Path to the control socket of the protomask process (see `--control-socket`)
+    #[clap(short, long, default_value = "/run/protomask.sock")]
+    pub socket: PathBuf,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Show live counters and the busiest mappings
+    Top {
+        /// Number of seconds between updates
+        #[clap(short, long, default_value = "1")]
+        interval: u64,
+    },
+
+    /// Print a summary of traffic, drops, and pool usage
+    Stats,
+
+    /// List the traffic of every mapping, busiest first
+    Traffic {
+        /// Only show this many mappings
+        #[clap(short = 'n', long)]
+        limit: Option<usize>,
+    },
+
+    /// List the most recently dropped packets and why they were dropped, newest first
+    Drops {
+        /// Only show this many packets
+        #[clap(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Also print the start of each packet's headers, in hex
+        #[clap(short = 'x', long)]
+        headers: bool,
+    },
+
+    /// Make this translator active, installing its routes
+    Promote,
+
+    /// Put this translator on standby, withdrawing its routes
+    Demote,
+
+    /// Manage the IPv4 pool
+    Pool {
+        #[command(subcommand)]
+        command: PoolCommand,
+    },
+
+    /// Export and import mappings, for migrating between instances or syncing with an IPAM system
+    Mappings {
+        #[command(subcommand)]
+        command: MappingsCommand,
+    },
+
+    /// Inspect and expire mappings
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum SessionsCommand {
+    /// List mappings, optionally filtered
+    List {
+        /// Only list clients in this prefix
+        #[clap(long)]
+        source: Option<Ipv6Net>,
+
+        /// Only list dynamic mappings created at least this many seconds ago
+        #[clap(long)]
+        older_than: Option<u64>,
+
+        /// Only list mappings that have carried this protocol
+        #[clap(long)]
+        protocol: Option<Protocol>,
+    },
+
+    /// Forcibly expire the mapping of an IPv4 or IPv6 address
+    Delete {
+        /// Either side of the mapping
+        address: IpAddr,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum PoolCommand {
+    /// List the pool prefixes and how many mappings each holds
+    List,
+
+    /// Add a prefix to the main pool, routing it here and allocating from it straight away
+    Add { prefix: Ipv4Net },
+
+    /// Stop allocating from a prefix, and withdraw its route once its mappings have expired
+    Remove { prefix: Ipv4Net },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum MappingsCommand {
+    /// Write out every current mapping
+    Export {
+        /// Format to write the mappings in
+        #[clap(short, long, value_enum, default_value = "json")]
+        format: MappingFormat,
+
+        /// Only export static mappings
+        #[clap(long)]
+        static_only: bool,
+
+        /// Write the mappings to this file instead of STDOUT
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Add the mappings listed in a file. Mappings without an expiry are added as static mappings.
+    Import {
+        /// File to read, as written by `export`
+        file: PathBuf,
+
+        /// Format of the file (detected from the file extension by default)
+        #[clap(short, long, value_enum)]
+        format: Option<MappingFormat>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MappingFormat {
+    /// A list of `{ "ipv4", "ipv6", "expires_in_secs" }` objects
+    Json,
+    /// `ipv4,ipv6,expires_in_secs` rows, with an empty expiry for static mappings
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
@@ -0,0 +1,52 @@
+//! Client side of the control socket
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+/// A connection to a protomask control socket
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl ControlClient {
+    /// Connect to a control socket
+    pub fn connect(path: &Path) -> std::io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    /// Send a command and wait for its response
+    pub fn request(
+        &mut self,
+        command: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        self.request_with(command, serde_json::Map::new())
+    }
+
+    /// Send a command with arguments and wait for its response
+    pub fn request_with(
+        &mut self,
+        command: &str,
+        mut arguments: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        arguments.insert("command".to_string(), command.into());
+        let mut request = serde_json::Value::Object(arguments).to_string();
+        request.push('\n');
+        self.writer.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        if self.reader.read_line(&mut response)? == 0 {
+            return Err("protomask closed the control connection".into());
+        }
+        let response: serde_json::Value = serde_json::from_str(&response)?;
+        match response.get("error").and_then(serde_json::Value::as_str) {
+            Some(error) => Err(error.into()),
+            None => Ok(response),
+        }
+    }
+}
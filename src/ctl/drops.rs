@@ -0,0 +1,42 @@
+//! `protomaskctl drops`: recently dropped packets
+
+use super::client::ControlClient;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Print the most recently dropped packets, newest first
+pub fn run(
+    client: &mut ControlClient,
+    limit: Option<usize>,
+    headers: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut arguments = serde_json::Map::new();
+    if let Some(limit) = limit {
+        arguments.insert("limit".to_string(), limit.into());
+    }
+    let response = client.request_with("drops", arguments)?;
+    if response["capacity"].as_u64() == Some(0) {
+        return Err("protomask is not keeping recent drops (see --recent-drops)".into());
+    }
+    let drops = response["drops"].as_array().cloned().unwrap_or_default();
+
+    println!(
+        "{:<20} {:<16} {:>5}  {:<70} DETAIL",
+        "TIME", "REASON", "LEN", "PACKET"
+    );
+    for dropped in &drops {
+        let time =
+            UNIX_EPOCH + Duration::from_secs_f64(dropped["time"].as_f64().unwrap_or_default());
+        println!(
+            "{:<20} {:<16} {:>5}  {:<70} {}",
+            humantime::format_rfc3339_seconds(time).to_string(),
+            dropped["reason"].as_str().unwrap_or_default(),
+            dropped["len"].as_u64().unwrap_or_default(),
+            dropped["packet"].as_str().unwrap_or_default(),
+            dropped["detail"].as_str().unwrap_or_default(),
+        );
+        if headers {
+            println!("    {}", dropped["headers"].as_str().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,10 @@
+//! `protomaskctl promote` and `protomaskctl demote`
+
+use super::client::ControlClient;
+
+/// Ask the translator to change roles, then print the role it ends up in
+pub fn run(client: &mut ControlClient, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.request(command)?;
+    println!("{}", response["role"].as_str().unwrap_or("unknown"));
+    Ok(())
+}
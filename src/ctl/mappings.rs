@@ -0,0 +1,145 @@
+//! `protomaskctl mappings`: export and import mappings
+
+use super::{
+    args::{MappingFormat, MappingsCommand},
+    client::ControlClient,
+};
+use std::{
+    io::Write,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+/// Export or import mappings
+pub fn run(
+    client: &mut ControlClient,
+    command: MappingsCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        MappingsCommand::Export {
+            format,
+            static_only,
+            output,
+        } => {
+            let response = client.request("sessions-list")?;
+            let mappings: Vec<_> = response["sessions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|session| !static_only || session["static"].as_bool().unwrap_or_default())
+                .map(|session| {
+                    serde_json::json!({
+                        "ipv4": session["ipv4"],
+                        "ipv6": session["ipv6"],
+                        "expires_in_secs": session["expires_in_secs"],
+                    })
+                })
+                .collect();
+
+            let data = match format {
+                MappingFormat::Json => serde_json::to_string_pretty(&mappings)? + "\n",
+                MappingFormat::Csv => to_csv(&mappings),
+            };
+            match output {
+                Some(path) => std::fs::write(&path, data)?,
+                None => std::io::stdout().write_all(data.as_bytes())?,
+            }
+            Ok(())
+        }
+        MappingsCommand::Import { file, format } => {
+            let data = std::fs::read_to_string(&file)?;
+            let mappings = match format.unwrap_or_else(|| format_from_path(&file)) {
+                MappingFormat::Json => from_json(&data)?,
+                MappingFormat::Csv => from_csv(&data)?,
+            };
+
+            let mut arguments = serde_json::Map::new();
+            arguments.insert("mappings".to_string(), mappings.into());
+            let response = client.request_with("mappings-import", arguments)?;
+            println!(
+                "Imported {} mappings",
+                response["imported"].as_u64().unwrap_or_default()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Guess the format of a file from its extension, falling back to JSON
+fn format_from_path(path: &Path) -> MappingFormat {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => MappingFormat::Csv,
+        _ => MappingFormat::Json,
+    }
+}
+
+/// Write mappings as CSV, leaving the expiry of static mappings empty
+fn to_csv(mappings: &[serde_json::Value]) -> String {
+    let mut csv = "ipv4,ipv6,expires_in_secs\n".to_string();
+    for mapping in mappings {
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            mapping["ipv4"].as_str().unwrap_or_default(),
+            mapping["ipv6"].as_str().unwrap_or_default(),
+            mapping["expires_in_secs"]
+                .as_u64()
+                .map(|secs| secs.to_string())
+                .unwrap_or_default()
+        ));
+    }
+    csv
+}
+
+/// Read mappings from a JSON list, ignoring any fields other than the addresses and expiry
+fn from_json(data: &str) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    let mappings: Vec<serde_json::Value> = serde_json::from_str(data)?;
+    Ok(mappings
+        .iter()
+        .map(|mapping| {
+            serde_json::json!({
+                "ipv4": mapping["ipv4"],
+                "ipv6": mapping["ipv6"],
+                "expires_in_secs": mapping["expires_in_secs"],
+            })
+        })
+        .collect())
+}
+
+/// Read mappings from `ipv4,ipv6[,expires_in_secs]` rows, skipping a header row if there is one
+fn from_csv(data: &str) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    let mut mappings = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line.starts_with("ipv4")) {
+            continue;
+        }
+
+        let error = |message: String| format!("Line {}: {}", i + 1, message);
+        let fields: Vec<_> = line.split(',').map(str::trim).collect();
+        let (ipv4, ipv6, expires_in_secs) = match fields.as_slice() {
+            [ipv4, ipv6] => (ipv4, ipv6, None),
+            [ipv4, ipv6, ""] => (ipv4, ipv6, None),
+            [ipv4, ipv6, secs] => (
+                ipv4,
+                ipv6,
+                Some(
+                    secs.parse::<u64>()
+                        .map_err(|err| error(format!("{}: {}", secs, err)))?,
+                ),
+            ),
+            _ => return Err(error("Expected ipv4,ipv6[,expires_in_secs]".to_string()).into()),
+        };
+        let ipv4: Ipv4Addr = ipv4
+            .parse()
+            .map_err(|err| error(format!("{}: {}", ipv4, err)))?;
+        let ipv6: Ipv6Addr = ipv6
+            .parse()
+            .map_err(|err| error(format!("{}: {}", ipv6, err)))?;
+        mappings.push(serde_json::json!({
+            "ipv4": ipv4,
+            "ipv6": ipv6,
+            "expires_in_secs": expires_in_secs,
+        }));
+    }
+    Ok(mappings)
+}
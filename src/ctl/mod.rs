@@ -0,0 +1,12 @@
+//! Everything needed by `protomaskctl` to talk to a running protomask process
+
+pub mod args;
+pub mod client;
+pub mod drops;
+pub mod failover;
+pub mod mappings;
+pub mod pool;
+pub mod sessions;
+pub mod stats;
+pub mod top;
+pub mod traffic;
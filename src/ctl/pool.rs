@@ -0,0 +1,45 @@
+//! `protomaskctl pool`: add and drain pool prefixes at runtime
+
+use super::{args::PoolCommand, client::ControlClient};
+
+/// List, add, or remove pool prefixes
+pub fn run(
+    client: &mut ControlClient,
+    command: PoolCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        PoolCommand::List => {
+            let response = client.request("pool-list")?;
+            println!("{:<18} {:<9} {:>8}", "PREFIX", "STATE", "MAPPINGS");
+            for pool in response["pools"].as_array().into_iter().flatten() {
+                println!(
+                    "{:<18} {:<9} {:>8}",
+                    pool["prefix"].as_str().unwrap_or_default(),
+                    if pool["draining"].as_bool().unwrap_or_default() {
+                        "draining"
+                    } else {
+                        "active"
+                    },
+                    pool["mappings"].as_u64().unwrap_or_default()
+                );
+            }
+        }
+        PoolCommand::Add { prefix } => {
+            let mut arguments = serde_json::Map::new();
+            arguments.insert("prefix".to_string(), prefix.to_string().into());
+            client.request_with("pool-add", arguments)?;
+            println!("Added {}", prefix);
+        }
+        PoolCommand::Remove { prefix } => {
+            let mut arguments = serde_json::Map::new();
+            arguments.insert("prefix".to_string(), prefix.to_string().into());
+            let response = client.request_with("pool-remove", arguments)?;
+            println!(
+                "Draining {}. It will be removed once its {} remaining mappings expire",
+                prefix,
+                response["mappings"].as_u64().unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+//! `protomaskctl sessions`: list and expire mappings
+
+use super::{args::SessionsCommand, client::ControlClient};
+use clap::ValueEnum;
+
+/// List or delete mappings
+pub fn run(
+    client: &mut ControlClient,
+    command: SessionsCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        SessionsCommand::List {
+            source,
+            older_than,
+            protocol,
+        } => {
+            let mut arguments = serde_json::Map::new();
+            if let Some(source) = source {
+                arguments.insert("source".to_string(), source.to_string().into());
+            }
+            if let Some(older_than) = older_than {
+                arguments.insert("older_than".to_string(), older_than.into());
+            }
+            if let Some(protocol) = protocol.and_then(|protocol| protocol.to_possible_value()) {
+                arguments.insert("protocol".to_string(), protocol.get_name().into());
+            }
+            list(&client.request_with("sessions-list", arguments)?);
+            Ok(())
+        }
+        SessionsCommand::Delete { address } => {
+            let mut arguments = serde_json::Map::new();
+            arguments.insert("address".to_string(), address.to_string().into());
+            let response = client.request_with("sessions-delete", arguments)?;
+            println!(
+                "Deleted {} -> {}",
+                response["ipv6"].as_str().unwrap_or_default(),
+                response["ipv4"].as_str().unwrap_or_default()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Print a table of sessions
+fn list(response: &serde_json::Value) {
+    let sessions = response["sessions"].as_array().cloned().unwrap_or_default();
+
+    println!(
+        "{:<39} {:<15} {:<7} {:>10} {:>10} {:<14} {:>12} {:>14}",
+        "IPV6", "IPV4", "TYPE", "AGE", "EXPIRES", "PROTOCOLS", "PACKETS", "BYTES"
+    );
+    for session in &sessions {
+        let seconds = |field: &str| {
+            session[field]
+                .as_u64()
+                .map_or_else(|| "-".to_string(), |secs| format!("{}s", secs))
+        };
+        let protocols: Vec<_> = session["protocols"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_str)
+            .collect();
+        println!(
+            "{:<39} {:<15} {:<7} {:>10} {:>10} {:<14} {:>12} {:>14}",
+            session["ipv6"].as_str().unwrap_or_default(),
+            session["ipv4"].as_str().unwrap_or_default(),
+            if session["static"].as_bool().unwrap_or_default() {
+                "static"
+            } else {
+                "dynamic"
+            },
+            seconds("age_secs"),
+            seconds("expires_in_secs"),
+            if protocols.is_empty() {
+                "-".to_string()
+            } else {
+                protocols.join(",")
+            },
+            session["packets"].as_u64().unwrap_or_default(),
+            session["bytes"].as_u64().unwrap_or_default(),
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! `protomaskctl stats`: a one-off summary of a running translator
+
+use super::client::ControlClient;
+use std::time::Duration;
+
+/// Print traffic totals, drops, and pool usage
+pub fn run(client: &mut ControlClient) -> Result<(), Box<dyn std::error::Error>> {
+    let status = client.request("status")?;
+    let queues: Vec<_> = status["queues"].as_array().into_iter().flatten().collect();
+    let queue_total = |counter: &str| -> u64 {
+        queues
+            .iter()
+            .filter_map(|queue| queue[counter].as_u64())
+            .sum()
+    };
+    let traffic = |field: &str| status["traffic"][field].as_u64().unwrap_or_default();
+
+    println!(
+        "Uptime:    {}",
+        humantime::format_duration(Duration::from_secs(
+            status["uptime_secs"].as_u64().unwrap_or_default()
+        ))
+    );
+    println!(
+        "Pool:      {}/{} addresses mapped ({:.1}%)",
+        status["pool"]["mapped_addresses"]
+            .as_u64()
+            .unwrap_or_default(),
+        status["pool"]["total_addresses"]
+            .as_u64()
+            .unwrap_or_default(),
+        status["pool"]["utilization"].as_f64().unwrap_or_default() * 100.0
+    );
+    println!(
+        "Outbound:  {} packets, {} bytes",
+        traffic("packets_out"),
+        traffic("bytes_out")
+    );
+    println!(
+        "Inbound:   {} packets, {} bytes",
+        traffic("packets_in"),
+        traffic("bytes_in")
+    );
+    println!(
+        "Packets:   {} received, {} sent, {} dropped",
+        queue_total("packets_received"),
+        queue_total("packets_sent"),
+        queue_total("packets_dropped")
+    );
+    println!(
+        "TCP opts:  {} flagged, {} stripped",
+        queue_total("tcp_options_flagged"),
+        queue_total("tcp_options_stripped")
+    );
+
+    // Sum each drop reason across all queues
+    let mut drops: Vec<(&str, u64)> = Vec::new();
+    for queue in &queues {
+        for (reason, count) in queue["drops"].as_object().into_iter().flatten() {
+            let count = count.as_u64().unwrap_or_default();
+            match drops.iter_mut().find(|(name, _)| name == reason) {
+                Some((_, total)) => *total += count,
+                None => drops.push((reason, count)),
+            }
+        }
+    }
+    for (reason, count) in drops {
+        println!("  {:<18} {}", reason, count);
+    }
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! `protomaskctl top`: a live view of a running translator
+
+use super::client::ControlClient;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, Gauge, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// A status response and when it was received
+struct Sample {
+    status: serde_json::Value,
+    time: Instant,
+}
+
+impl Sample {
+    /// Sum a counter across all queues
+    fn queue_total(&self, counter: &str) -> u64 {
+        self.status["queues"]
+            .as_array()
+            .map(|queues| {
+                queues
+                    .iter()
+                    .filter_map(|queue| queue[counter].as_u64())
+                    .sum()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Packet and byte counts of every mapping, keyed by IPv4 address
+    fn mapping_counts(&self) -> HashMap<&str, (u64, u64)> {
+        self.mappings()
+            .filter_map(|mapping| {
+                Some((
+                    mapping["ipv4"].as_str()?,
+                    (
+                        mapping["packets"].as_u64().unwrap_or_default(),
+                        mapping["bytes"].as_u64().unwrap_or_default(),
+                    ),
+                ))
+            })
+            .collect()
+    }
+
+    fn mappings(&self) -> impl Iterator<Item = &serde_json::Value> {
+        self.status["mappings"].as_array().into_iter().flatten()
+    }
+}
+
+/// A single line in the mapping table
+struct MappingRow {
+    ipv6: String,
+    ipv4: String,
+    packet_rate: f64,
+    byte_rate: f64,
+    packets: u64,
+    bytes: u64,
+    expires_in_secs: Option<u64>,
+}
+
+/// Everything shown on screen, derived from the latest two samples
+struct View {
+    title: String,
+    pool_mapped: u64,
+    pool_total: u64,
+    counters: String,
+    mappings: Vec<MappingRow>,
+}
+
+impl View {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(current: &Sample, previous: Option<&Sample>) -> Self {
+        let status = &current.status;
+        let elapsed = previous.map(|previous| (current.time - previous.time).as_secs_f64());
+
+        // Rate of change of a counter since the previous sample
+        let rate = |now: u64, before: Option<u64>| match (elapsed, before) {
+            (Some(elapsed), Some(before)) if elapsed > 0.0 => {
+                now.saturating_sub(before) as f64 / elapsed
+            }
+            _ => 0.0,
+        };
+
+        // Per-direction counters
+        let counters = ["packets_received", "packets_sent", "packets_dropped"]
+            .iter()
+            .map(|counter| {
+                let total = current.queue_total(counter);
+                let before = previous.map(|previous| previous.queue_total(counter));
+                format!(
+                    "{}: {} ({:.0}/s)",
+                    counter.trim_start_matches("packets_"),
+                    total,
+                    rate(total, before)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("    ");
+
+        // Busiest mappings first
+        let previous_counts = previous.map(Sample::mapping_counts).unwrap_or_default();
+        let mut mappings: Vec<_> = current
+            .mappings()
+            .map(|mapping| {
+                let ipv4 = mapping["ipv4"].as_str().unwrap_or_default();
+                let packets = mapping["packets"].as_u64().unwrap_or_default();
+                let bytes = mapping["bytes"].as_u64().unwrap_or_default();
+                let before =
+                    previous.map(|_| previous_counts.get(ipv4).copied().unwrap_or_default());
+                MappingRow {
+                    ipv6: mapping["ipv6"].as_str().unwrap_or_default().to_string(),
+                    ipv4: ipv4.to_string(),
+                    packet_rate: rate(packets, before.map(|(packets, _)| packets)),
+                    byte_rate: rate(bytes, before.map(|(_, bytes)| bytes)),
+                    packets,
+                    bytes,
+                    expires_in_secs: mapping["expires_in_secs"].as_u64(),
+                }
+            })
+            .collect();
+        mappings.sort_by(|a, b| {
+            b.packet_rate
+                .total_cmp(&a.packet_rate)
+                .then(b.packets.cmp(&a.packets))
+        });
+
+        Self {
+            title: format!(
+                "protomask {} on {} (up {})",
+                status["version"].as_str().unwrap_or("unknown"),
+                status["interface"].as_str().unwrap_or("unknown"),
+                humantime::format_duration(Duration::from_secs(
+                    status["uptime_secs"].as_u64().unwrap_or_default()
+                ))
+            ),
+            pool_mapped: status["pool"]["mapped_addresses"]
+                .as_u64()
+                .unwrap_or_default(),
+            pool_total: status["pool"]["total_addresses"]
+                .as_u64()
+                .unwrap_or_default(),
+            counters,
+            mappings,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn render(&self, frame: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Length(3),
+                Constraint::Min(0),
+            ])
+            .split(frame.size());
+
+        // Traffic counters
+        frame.render_widget(
+            Paragraph::new(self.counters.as_str()).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(self.title.as_str()),
+            ),
+            chunks[0],
+        );
+
+        // Pool utilization
+        let ratio = if self.pool_total == 0 {
+            0.0
+        } else {
+            self.pool_mapped as f64 / self.pool_total as f64
+        };
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::default().borders(Borders::ALL).title("Pool"))
+                .ratio(ratio.clamp(0.0, 1.0))
+                .label(format!(
+                    "{} / {} addresses ({:.1}%)",
+                    self.pool_mapped,
+                    self.pool_total,
+                    ratio * 100.0
+                )),
+            chunks[1],
+        );
+
+        // Busiest mappings
+        let rows = self.mappings.iter().map(|mapping| {
+            Row::new(vec![
+                mapping.ipv6.clone(),
+                mapping.ipv4.clone(),
+                format!("{:.0}", mapping.packet_rate),
+                format_bytes(mapping.byte_rate),
+                mapping.packets.to_string(),
+                format_bytes(mapping.bytes as f64),
+                mapping
+                    .expires_in_secs
+                    .map_or_else(|| "static".to_string(), |secs| format!("{}s", secs)),
+            ])
+        });
+        let widths = [
+            Constraint::Min(39),
+            Constraint::Length(15),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(12),
+            Constraint::Length(9),
+            Constraint::Length(8),
+        ];
+        frame.render_widget(
+            Table::new(rows)
+                .header(
+                    Row::new(vec![
+                        "IPv6", "IPv4", "Pkts/s", "Bytes/s", "Packets", "Bytes", "Expires",
+                    ])
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+                )
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Busiest mappings (q to quit)"),
+                )
+                .widths(&widths),
+            chunks[2],
+        );
+    }
+}
+
+/// Format a byte count with a binary unit suffix
+fn format_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            return format!("{:.0}{}", value, unit);
+        }
+        value /= 1024.0;
+    }
+    format!("{:.0}TiB", value)
+}
+
+/// Show a live view of the translator until the user quits
+pub fn run(
+    client: &mut ControlClient,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Take over the terminal
+    enable_raw_mode()?;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = draw_loop(&mut terminal, client, interval);
+
+    // Always give the terminal back, even if something went wrong
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+/// Redraw every `interval` until a quit key is pressed
+fn draw_loop<B: Backend>(
+    terminal: &mut Terminal<B>,
+    client: &mut ControlClient,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut previous: Option<Sample> = None;
+    loop {
+        let sample = Sample {
+            status: client.request("status")?,
+            time: Instant::now(),
+        };
+        let view = View::new(&sample, previous.as_ref());
+        terminal.draw(|frame| view.render(frame))?;
+        previous = Some(sample);
+
+        // Wait for the next update, leaving early if asked to quit
+        let deadline = Instant::now() + interval;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            if event::poll(remaining)? {
+                if let Event::Key(key) = event::read()? {
+                    let ctrl_c = key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
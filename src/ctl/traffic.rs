@@ -0,0 +1,35 @@
+//! `protomaskctl traffic`: per-mapping packet and byte counts
+
+use super::client::ControlClient;
+
+/// Print the traffic of each mapping, busiest first
+pub fn run(
+    client: &mut ControlClient,
+    limit: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.request("traffic")?;
+    let mappings = response["mappings"].as_array().cloned().unwrap_or_default();
+
+    println!(
+        "{:<39} {:<15} {:<7} {:>12} {:>12} {:>14} {:>14}",
+        "IPV6", "IPV4", "TYPE", "PACKETS OUT", "PACKETS IN", "BYTES OUT", "BYTES IN"
+    );
+    for mapping in mappings.iter().take(limit.unwrap_or(usize::MAX)) {
+        let count = |field: &str| mapping[field].as_u64().unwrap_or_default();
+        println!(
+            "{:<39} {:<15} {:<7} {:>12} {:>12} {:>14} {:>14}",
+            mapping["ipv6"].as_str().unwrap_or_default(),
+            mapping["ipv4"].as_str().unwrap_or_default(),
+            if mapping["static"].as_bool().unwrap_or_default() {
+                "static"
+            } else {
+                "dynamic"
+            },
+            count("packets_out"),
+            count("packets_in"),
+            count("bytes_out"),
+            count("bytes_in"),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,324 @@
+//! Explain how a single packet would be translated
+//!
+//! The packet is given as hex, as base64, or as a frame of a pcap file. Its headers are decoded, and it is run through
+//! the same translation as `protomask-replay`, printing each decision along the way: which prefix and pool it
+//! matched, which mapping it used, and which addresses were embedded in or extracted from the translation prefix.
+//! Finally, the translated packet's headers are printed, or the reason the packet would be dropped.
+//!
+//! Nothing is learned from a running translator. Only static mappings are known, and any other IPv6 client is shown
+//! being given a new mapping from the pool.
+
+use crate::{
+    args::ConfigFormat,
+    common::{
+        packet_handler::{get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto},
+        pcap::PcapReader,
+    },
+    replay::{self, Translator},
+};
+use base64::Engine;
+use ipnet::Ipv6Net;
+use rfc6052::{embed_ipv4_addr, extract_ipv4_addr};
+use std::path::Path;
+
+/// Inspect a packet, returning the process exit code
+pub fn run(
+    packet: &str,
+    frame: usize,
+    config_file: Option<&Path>,
+    config_format: Option<ConfigFormat>,
+    prefix: Ipv6Net,
+) -> i32 {
+    let packet = match load_packet(packet, frame) {
+        Ok(packet) => packet,
+        Err(error) => {
+            log::error!("{}", error);
+            return 1;
+        }
+    };
+    let mut translator = match Translator::new(config_file, config_format, prefix) {
+        Ok(translator) => translator,
+        Err(error) => {
+            log::error!("{}", error);
+            return 1;
+        }
+    };
+
+    println!("Input ({} bytes)", packet.len());
+    print_headers(&packet);
+    println!();
+    println!("Translation");
+    for step in explain(&translator, &packet) {
+        println!("  {}", step);
+    }
+    println!();
+    match replay::translate(&mut translator, &packet) {
+        Ok(output) => {
+            println!("Verdict: translated");
+            println!();
+            println!("Output ({} bytes)", output.len());
+            print_headers(&output);
+            0
+        }
+        Err(reason) => {
+            println!("Verdict: dropped ({})", reason);
+            1
+        }
+    }
+}
+
+/// Read a packet from a pcap file, or decode it from hex or base64
+fn load_packet(input: &str, frame: usize) -> Result<Vec<u8>, String> {
+    // Anything naming a file is a capture
+    let path = Path::new(input);
+    if path.is_file() {
+        let mut reader =
+            PcapReader::open(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        for number in 1.. {
+            match reader
+                .next_packet()
+                .map_err(|error| format!("{}: {}", path.display(), error))?
+            {
+                Some((_, packet)) if number == frame => return Ok(packet),
+                Some(_) => continue,
+                None => break,
+            }
+        }
+        return Err(format!("{} has no frame {}", path.display(), frame));
+    }
+
+    // Hex dumps are often split into words or bytes
+    let hex: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if let Ok(packet) = hex::decode(hex.trim_start_matches("0x")) {
+        return Ok(packet);
+    }
+    let base64: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(base64)
+        .map_err(|_| "Packet is not a capture file, hex, or base64".to_string())
+}
+
+/// Describe each decision made while translating a packet, without changing any state
+fn explain(translator: &Translator, packet: &[u8]) -> Vec<String> {
+    let mut steps = Vec::new();
+    match (translator, get_layer_3_proto(packet)) {
+        (Translator::Stateless(translator), Some(4)) if packet.len() >= 20 => {
+            let (source, dest) = get_ipv4_src_dst(packet);
+            for (role, address) in [("Source", source), ("Destination", dest)] {
+                steps.push(match embed_ipv4_addr(address, translator.prefix()) {
+                    Ok(embedded) => format!(
+                        "{} {} is embedded in {} as {}",
+                        role,
+                        address,
+                        translator.prefix(),
+                        embedded
+                    ),
+                    Err(error) => format!("{} {} can't be embedded: {}", role, address, error),
+                });
+            }
+        }
+        (Translator::Stateless(translator), Some(6)) if packet.len() >= 40 => {
+            let (source, dest) = get_ipv6_src_dst(packet);
+            let prefix = translator.prefix();
+            for (role, address) in [("Source", source), ("Destination", dest)] {
+                steps.push(if prefix.contains(&address) {
+                    match extract_ipv4_addr(address, prefix.prefix_len()) {
+                        Ok(extracted) => {
+                            format!("{} {} embeds {}", role, address, extracted)
+                        }
+                        Err(error) => format!("{} {}: {}", role, address, error),
+                    }
+                } else {
+                    format!("{} {} is not inside {}", role, address, prefix)
+                });
+            }
+        }
+        (Translator::Nat64(prefix_tables), Some(4)) if packet.len() >= 20 => {
+            let (source, dest) = get_ipv4_src_dst(packet);
+            let Some((prefix, table)) = prefix_tables.match_ipv4(source, dest) else {
+                steps.push(format!("Destination {} is not inside any pool", dest));
+                return steps;
+            };
+            steps.push(format!(
+                "Destination {} is in the pool of prefix {}",
+                dest, prefix
+            ));
+            let table = table.lock().unwrap();
+            match table.get_ipv6(&dest) {
+                Some(client) => steps.push(format!(
+                    "Destination {} is {} mapped to {}",
+                    dest,
+                    if table.age(&dest).is_some() {
+                        "dynamically"
+                    } else {
+                        "statically"
+                    },
+                    client
+                )),
+                None => steps.push(format!("Destination {} is not mapped to any client", dest)),
+            }
+            if let Ok(embedded) = embed_ipv4_addr(source, prefix) {
+                steps.push(format!(
+                    "Source {} is embedded in {} as {}",
+                    source, prefix, embedded
+                ));
+            }
+        }
+        (Translator::Nat64(prefix_tables), Some(6)) if packet.len() >= 40 => {
+            let (source, dest) = get_ipv6_src_dst(packet);
+            if let Some(tenant) = prefix_tables.tenant_of(source) {
+                steps.push(format!(
+                    "Source {} belongs to tenant {}",
+                    source, tenant.name
+                ));
+            }
+            let Some((prefix, table)) = prefix_tables.match_ipv6(source, dest) else {
+                steps.push(format!(
+                    "Destination {} is not inside any translation prefix the source may use",
+                    dest
+                ));
+                return steps;
+            };
+            match extract_ipv4_addr(dest, prefix.prefix_len()) {
+                Ok(extracted) => steps.push(format!(
+                    "Destination {} is inside {} and embeds {}",
+                    dest, prefix, extracted
+                )),
+                Err(error) => steps.push(format!("Destination {}: {}", dest, error)),
+            }
+            let table = table.lock().unwrap();
+            match table.get_ipv4(&source) {
+                Some(mapped) => steps.push(format!(
+                    "Source {} is {} mapped to {}",
+                    source,
+                    if table.age(&mapped).is_some() {
+                        "dynamically"
+                    } else {
+                        "statically"
+                    },
+                    mapped
+                )),
+                None => steps.push(format!(
+                    "Source {} has no mapping, and is given a new one from the pool",
+                    source
+                )),
+            }
+        }
+        _ => steps.push(format!("Not an IP packet ({})", replay::describe(packet))),
+    }
+    steps
+}
+
+/// Print the decoded IP and transport headers of a packet
+fn print_headers(packet: &[u8]) {
+    let (protocol, payload) = match get_layer_3_proto(packet) {
+        Some(4) if packet.len() >= 20 => {
+            let (source, dest) = get_ipv4_src_dst(packet);
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let flags_fragment = u16::from_be_bytes([packet[6], packet[7]]);
+            println!("  IPv4 {} -> {}", source, dest);
+            println!(
+                "    header length {}, total length {}, TTL {}, DSCP {}, ECN {}",
+                header_len,
+                u16::from_be_bytes([packet[2], packet[3]]),
+                packet[8],
+                packet[1] >> 2,
+                packet[1] & 0x03
+            );
+            println!(
+                "    identification {:#06x}, DF {}, MF {}, fragment offset {}",
+                u16::from_be_bytes([packet[4], packet[5]]),
+                flags_fragment & 0x4000 != 0,
+                flags_fragment & 0x2000 != 0,
+                (flags_fragment & 0x1fff) * 8
+            );
+            // Only the first fragment carries the transport header
+            let payload = match flags_fragment & 0x1fff {
+                0 => packet.get(header_len..).unwrap_or_default(),
+                _ => &[],
+            };
+            (packet[9], payload)
+        }
+        Some(6) if packet.len() >= 40 => {
+            let (source, dest) = get_ipv6_src_dst(packet);
+            let first_word = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+            println!("  IPv6 {} -> {}", source, dest);
+            println!(
+                "    payload length {}, hop limit {}, traffic class {:#04x}, flow label {:#07x}",
+                u16::from_be_bytes([packet[4], packet[5]]),
+                packet[7],
+                (first_word >> 20) & 0xff,
+                first_word & 0x000f_ffff
+            );
+            (packet[6], &packet[40..])
+        }
+        _ => {
+            println!("  {}", replay::describe(packet));
+            return;
+        }
+    };
+
+    match protocol {
+        6 if payload.len() >= 20 => {
+            let flags = payload[13];
+            let names: Vec<&str> = [
+                (0x01, "FIN"),
+                (0x02, "SYN"),
+                (0x04, "RST"),
+                (0x08, "PSH"),
+                (0x10, "ACK"),
+                (0x20, "URG"),
+            ]
+            .iter()
+            .filter(|(bit, _)| flags & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+            println!(
+                "  TCP {} -> {} [{}]",
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+                names.join(" ")
+            );
+            println!(
+                "    seq {}, ack {}, window {}, checksum {:#06x}",
+                u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+                u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]),
+                u16::from_be_bytes([payload[14], payload[15]]),
+                u16::from_be_bytes([payload[16], payload[17]])
+            );
+        }
+        17 if payload.len() >= 8 => {
+            println!(
+                "  UDP {} -> {}",
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]])
+            );
+            println!(
+                "    length {}, checksum {:#06x}",
+                u16::from_be_bytes([payload[4], payload[5]]),
+                u16::from_be_bytes([payload[6], payload[7]])
+            );
+        }
+        1 | 58 if payload.len() >= 8 => {
+            println!(
+                "  {} type {}, code {}, checksum {:#06x}",
+                if protocol == 1 { "ICMP" } else { "ICMPv6" },
+                payload[0],
+                payload[1],
+                u16::from_be_bytes([payload[2], payload[3]])
+            );
+            // Echo requests and replies
+            if matches!((protocol, payload[0]), (1, 0 | 8) | (58, 128 | 129)) {
+                println!(
+                    "    identifier {}, sequence {}",
+                    u16::from_be_bytes([payload[4], payload[5]]),
+                    u16::from_be_bytes([payload[6], payload[7]])
+                );
+            }
+        }
+        protocol => println!("  Protocol {} ({} bytes)", protocol, payload.len()),
+    }
+}
@@ -0,0 +1,9 @@
+//! Code shared by the protomask binaries.
+//!
+//! The translators, their configuration, and everything they are built from live here, so that each binary only has
+//! to pull in what it uses.
+
+pub mod args;
+pub mod common;
+pub mod replay;
+pub mod translators;
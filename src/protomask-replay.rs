@@ -0,0 +1,168 @@
+//! Entrypoint for the `protomask-replay` binary.
+//!
+//! This binary translates packets from a capture file without bringing up a translator, for offline conformance testing.
+//!
+//! Every packet in the input capture is translated as if it had arrived on the NAT64's TUN interface. The results are
+//! written to an output capture, and a tab-separated report line is written for each packet:
+//! `<packet number> <verdict> <input addresses> <output addresses or drop reason>`.
+//!
+//! When an expected capture is given, each output packet is compared byte-for-byte with the expected packet at the
+//! same position. Empty records stand for packets that should be dropped, which is also how drops are written to the
+//! output capture, so a reviewed output capture can be used as the expected capture of later runs.
+
+use crate::args::protomask_replay::Args;
+use clap::Parser;
+use common::{
+    logging::enable_logger,
+    pcap::{PcapReader, PcapWriter},
+};
+use protomask::{args, common, replay};
+use replay::{describe, translate, Translator};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+pub fn main() {
+    // Parse CLI args
+    let args = Args::parse();
+
+    // Initialize logging
+    enable_logger(args.verbose);
+
+    std::process::exit(run(&args))
+}
+
+/// Replay a capture and write a report, returning the process exit code
+pub fn run(args: &Args) -> i32 {
+    match replay(args) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(error) => {
+            log::error!("{}", error);
+            1
+        }
+    }
+}
+
+/// Replay a capture, returning whether every packet matched what was expected
+fn replay(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut translator =
+        Translator::new(args.config_file.as_deref(), args.config_format, args.prefix)?;
+
+    // Open every file up front so mistakes are caught before any work is done
+    let mut input = PcapReader::open(&args.input)
+        .map_err(|error| format!("{}: {}", args.input.display(), error))?;
+    let mut output = match &args.output {
+        Some(path) => Some(
+            PcapWriter::create(path).map_err(|error| format!("{}: {}", path.display(), error))?,
+        ),
+        None => None,
+    };
+    let mut expected = match &args.expected {
+        Some(path) => {
+            Some(PcapReader::open(path).map_err(|error| format!("{}: {}", path.display(), error))?)
+        }
+        None => None,
+    };
+    let mut report: Box<dyn Write> = match &args.report {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|error| format!("{}: {}", path.display(), error))?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let mut count = 0;
+    let mut translated = 0;
+    let mut mismatched = 0;
+    while let Some((timestamp, packet)) = input.next_packet()? {
+        // Packet numbers are 1-indexed to match what Wireshark displays
+        count += 1;
+        let result = translate(&mut translator, &packet);
+        if let Ok(output_packet) = &result {
+            translated += 1;
+            log::debug!(
+                "Packet {} translated to {} bytes",
+                count,
+                output_packet.len()
+            );
+        }
+        if let Some(output) = &mut output {
+            output.write(result.as_deref().unwrap_or_default(), timestamp)?;
+        }
+
+        // Compare against the expected packet, if there is one
+        let verdict = match &mut expected {
+            Some(expected) => {
+                let expected = expected.next_packet()?.map(|(_, packet)| packet);
+                match compare(result.as_deref().ok(), expected.as_deref()) {
+                    Ok(()) => "match".to_string(),
+                    Err(difference) => {
+                        mismatched += 1;
+                        format!("mismatch ({})", difference)
+                    }
+                }
+            }
+            None if result.is_ok() => "translated".to_string(),
+            None => "dropped".to_string(),
+        };
+        writeln!(
+            report,
+            "{}\t{}\t{}\t{}",
+            count,
+            verdict,
+            describe(&packet),
+            match &result {
+                Ok(output_packet) => describe(output_packet),
+                Err(reason) => reason.clone(),
+            }
+        )?;
+    }
+
+    // Any expected packets left over were never produced
+    if let Some(expected) = &mut expected {
+        while expected.next_packet()?.is_some() {
+            mismatched += 1;
+        }
+    }
+    report.flush()?;
+    if let Some(output) = &mut output {
+        output.flush()?;
+    }
+
+    log::info!(
+        "Replayed {} packets: {} translated, {} dropped",
+        count,
+        translated,
+        count - translated
+    );
+    if expected.is_some() {
+        if mismatched == 0 {
+            log::info!("All packets matched the expected capture");
+        } else {
+            log::error!("{} packets did not match the expected capture", mismatched);
+        }
+    }
+    Ok(mismatched == 0)
+}
+
+/// Compare a translated packet with the expected one, describing the first difference
+fn compare(actual: Option<&[u8]>, expected: Option<&[u8]>) -> Result<(), String> {
+    match (actual, expected) {
+        (_, None) => Err("no expected packet".to_string()),
+        (None, Some([])) => Ok(()),
+        (None, Some(_)) => Err("expected a translated packet".to_string()),
+        (Some(_), Some([])) => Err("expected a drop".to_string()),
+        (Some(actual), Some(expected)) => {
+            match actual.iter().zip(expected).position(|(a, b)| a != b) {
+                Some(offset) => Err(format!("first difference at byte {}", offset)),
+                None if actual.len() != expected.len() => Err(format!(
+                    "{} bytes, expected {}",
+                    actual.len(),
+                    expected.len()
+                )),
+                None => Ok(()),
+            }
+        }
+    }
+}
@@ -1,187 +1,144 @@
-use crate::common::{
-    packet_handler::{
-        get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto, handle_translation_error,
-        PacketHandlingError,
-    },
-    permissions::ensure_root,
-    profiler::start_puffin_server,
+//! Entrypoint for the `protomask` binary.
+//!
+//! This binary can run any of the translators via subcommands. When no subcommand is given, it runs a NAT64.
+
+use crate::args::{
+    protomask::{Cli, Command, Config, ConfigKind},
+    protomask_clat, protomask_nat46, ConfigFormat,
 };
-use clap::Parser;
 use common::logging::enable_logger;
-use easy_tun::Tun;
-use fast_nat::CrossProtocolNetworkAddressTableWithIpv4Pool;
-use interproto::protocols::ip::{translate_ipv4_to_ipv6, translate_ipv6_to_ipv4};
-use ipnet::IpNet;
-use rfc6052::{embed_ipv4_addr_unchecked, extract_ipv4_addr_unchecked};
-use std::{
-    io::{Read, Write},
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use protomask::{args, common, replay, translators};
+use std::path::Path;
 
-mod args;
-mod common;
+mod inspect;
+mod selftest;
+mod templates;
 
 #[tokio::main]
 pub async fn main() {
     // Parse CLI args
-    let args = args::protomask::Args::parse();
-
-    // Initialize logging
-    enable_logger(args.verbose);
-
-    // Load config data
-    let config = args.data().unwrap();
-
-    // We must be root to continue program execution
-    ensure_root();
-
-    // Start profiling
-    #[allow(clippy::let_unit_value)]
-    let _server = start_puffin_server(&args.profiler_args);
-
-    // Bring up a TUN interface
-    log::debug!("Creating new TUN interface");
-    let tun = Arc::new(Tun::new(&args.interface, config.num_queues).unwrap());
-    log::debug!("Created TUN interface: {}", tun.name());
-
-    // Get the interface index
-    let rt_handle = rtnl::new_handle().unwrap();
-    let tun_link_idx = rtnl::link::get_link_index(&rt_handle, tun.name())
-        .await
-        .unwrap()
-        .unwrap();
-
-    // Bring the interface up
-    rtnl::link::link_up(&rt_handle, tun_link_idx).await.unwrap();
-
-    // Add a route for the translation prefix
-    log::debug!(
-        "Adding route for {} to {}",
-        config.translation_prefix,
-        tun.name()
-    );
-    rtnl::route::route_add(
-        IpNet::V6(config.translation_prefix),
-        &rt_handle,
-        tun_link_idx,
-    )
-    .await
-    .unwrap();
-
-    // Add a route for each NAT pool prefix
-    for pool_prefix in &config.pool_prefixes {
-        log::debug!("Adding route for {} to {}", pool_prefix, tun.name());
-        rtnl::route::route_add(IpNet::V4(*pool_prefix), &rt_handle, tun_link_idx)
-            .await
-            .unwrap();
+    let cli = Cli::parse_cli();
+
+    match cli.command {
+        Some(Command::Nat64(args)) => {
+            enable_logger(args.verbose);
+            translators::nat64::run(args).await;
+        }
+        Some(Command::Clat(args)) => {
+            enable_logger(args.verbose);
+            translators::clat::run(args).await;
+        }
+        Some(Command::Nat46(args)) => {
+            enable_logger(args.verbose);
+            translators::nat46::run(args).await;
+        }
+        Some(Command::Multi { config }) => {
+            enable_logger(cli.nat64.verbose);
+            translators::multi::run(&config, cli.nat64.config_format).await;
+        }
+        // Subcommands that don't need to bring up a translator
+        Some(Command::Check { kind, config }) => {
+            enable_logger(cli.nat64.verbose);
+            std::process::exit(check_config(kind, &config, cli.nat64.config_format))
+        }
+        Some(Command::InitConfig { kind, output }) => {
+            enable_logger(cli.nat64.verbose);
+            std::process::exit(init_config(
+                kind,
+                output.as_deref(),
+                cli.nat64.config_format,
+            ))
+        }
+        Some(Command::Selftest {
+            prefix,
+            target,
+            mtu,
+        }) => {
+            enable_logger(cli.nat64.verbose);
+            std::process::exit(selftest::run(prefix, target, mtu).await)
+        }
+        Some(Command::Inspect {
+            packet,
+            frame,
+            config,
+            prefix,
+        }) => {
+            enable_logger(cli.nat64.verbose);
+            std::process::exit(inspect::run(
+                &packet,
+                frame,
+                config.as_deref(),
+                cli.nat64.config_format,
+                prefix,
+            ))
+        }
+        None => {
+            enable_logger(cli.nat64.verbose);
+            translators::nat64::run(cli.nat64).await;
+        }
     }
+}
 
-    // Set up the address table
-    let addr_table = Arc::new(Mutex::new(
-        CrossProtocolNetworkAddressTableWithIpv4Pool::new(
-            &config.pool_prefixes,
-            Duration::from_secs(config.reservation_timeout),
-        ),
-    ));
-    for (v4_addr, v6_addr) in &config.static_map {
-        addr_table
-            .lock()
-            .unwrap()
-            .insert_static(*v4_addr, *v6_addr)
-            .unwrap();
+/// Validate a config file, returning the process exit code
+fn check_config(kind: ConfigKind, path: &Path, format: Option<ConfigFormat>) -> i32 {
+    // Parse the file and check the parsed values. Syntax errors carry their own line and column numbers.
+    let issues = match kind {
+        ConfigKind::Nat64 => {
+            args::read_config_file::<Config>(path, format).map(|config| config.validate())
+        }
+        ConfigKind::Clat => args::read_config_file::<protomask_clat::Config>(path, format)
+            .map(|config| config.validate()),
+        ConfigKind::Nat46 => args::read_config_file::<protomask_nat46::Config>(path, format)
+            .map(|config| config.validate()),
+    };
+    let issues = match issues {
+        Ok(issues) => issues,
+        Err(error) => {
+            log::error!("{}: {}", path.display(), error);
+            return 1;
+        }
+    };
+
+    if issues.is_empty() {
+        log::info!("{}: OK", path.display());
+        return 0;
     }
-
-    // If we are configured to serve prometheus metrics, start the server
-    if let Some(bind_addr) = config.prom_bind_addr {
-        log::info!("Starting prometheus server on {}", bind_addr);
-        tokio::spawn(protomask_metrics::http::serve_metrics(bind_addr));
+    for issue in &issues {
+        log::error!("{}: {}", path.display(), issue);
     }
+    1
+}
 
-    // Translate all incoming packets
-    log::info!("Translating packets on {}", tun.name());
-    let mut worker_threads = Vec::new();
-    for queue_id in 0..config.num_queues {
-        let tun = Arc::clone(&tun);
-        let addr_table = Arc::clone(&addr_table);
-        worker_threads.push(std::thread::spawn(move || {
-            log::debug!("Starting worker thread for queue {}", queue_id);
-
-            let mut buffer = vec![0u8; 1500];
-            loop {
-                // Indicate to the profiler that we are starting a new packet
-                profiling::finish_frame!();
-                profiling::scope!("packet");
-
-                // Read a packet
-                let len = tun.fd(queue_id).unwrap().read(&mut buffer).unwrap();
-
-                // Translate it based on the Layer 3 protocol number
-                let translation_result: Result<Option<Vec<u8>>, PacketHandlingError> =
-                    match get_layer_3_proto(&buffer[..len]) {
-                        Some(4) => {
-                            let (source, dest) = get_ipv4_src_dst(&buffer[..len]);
-                            match addr_table.lock().unwrap().get_ipv6(&dest) {
-                                Some(new_destination) => translate_ipv4_to_ipv6(
-                                    &buffer[..len],
-                                    unsafe {
-                                        embed_ipv4_addr_unchecked(source, config.translation_prefix)
-                                    },
-                                    new_destination,
-                                )
-                                .map(Some)
-                                .map_err(PacketHandlingError::from),
-                                None => {
-                                    protomask_metrics::metric!(
-                                        PACKET_COUNTER,
-                                        PROTOCOL_IPV4,
-                                        STATUS_DROPPED
-                                    );
-                                    Ok(None)
-                                }
-                            }
-                        }
-                        Some(6) => {
-                            let (source, dest) = get_ipv6_src_dst(&buffer[..len]);
-                            match addr_table.lock().unwrap().get_or_create_ipv4(&source) {
-                                Ok(new_source) => {
-                                    translate_ipv6_to_ipv4(&buffer[..len], new_source, unsafe {
-                                        extract_ipv4_addr_unchecked(
-                                            dest,
-                                            config.translation_prefix.prefix_len(),
-                                        )
-                                    })
-                                    .map(Some)
-                                    .map_err(PacketHandlingError::from)
-                                }
-                                Err(error) => {
-                                    log::error!("Error getting IPv4 address: {}", error);
-                                    protomask_metrics::metric!(
-                                        PACKET_COUNTER,
-                                        PROTOCOL_IPV6,
-                                        STATUS_DROPPED
-                                    );
-                                    Ok(None)
-                                }
-                            }
-                        }
-                        Some(proto) => {
-                            log::warn!("Unknown Layer 3 protocol: {}", proto);
-                            continue;
-                        }
-                        None => {
-                            continue;
-                        }
-                    };
-
-                // Handle any errors and write
-                if let Some(output) = handle_translation_error(translation_result) {
-                    tun.fd(queue_id).unwrap().write_all(&output).unwrap();
-                }
+/// Write an example config file, returning the process exit code.
+///
+/// Unless a format is given, the file is written as YAML if its extension asks for it, and as JSON otherwise.
+fn init_config(kind: ConfigKind, output: Option<&Path>, format: Option<ConfigFormat>) -> i32 {
+    let format = format
+        .or_else(|| output.map(ConfigFormat::from_path))
+        .unwrap_or(ConfigFormat::Json);
+    let template = match (kind, format) {
+        (ConfigKind::Nat64, ConfigFormat::Json) => templates::NAT64,
+        (ConfigKind::Nat64, ConfigFormat::Yaml) => templates::NAT64_YAML,
+        (ConfigKind::Clat, ConfigFormat::Json) => templates::CLAT,
+        (ConfigKind::Clat, ConfigFormat::Yaml) => templates::CLAT_YAML,
+        (ConfigKind::Nat46, ConfigFormat::Json) => templates::NAT46,
+        (ConfigKind::Nat46, ConfigFormat::Yaml) => templates::NAT46_YAML,
+    };
+
+    match output {
+        Some(path) => {
+            // Never clobber an existing config
+            if path.exists() {
+                log::error!("{} already exists", path.display());
+                return 1;
             }
-        }));
-    }
-    for worker in worker_threads {
-        worker.join().unwrap();
+            if let Err(error) = std::fs::write(path, template) {
+                log::error!("Failed to write {}: {}", path.display(), error);
+                return 1;
+            }
+            log::info!("Wrote example config to {}", path.display());
+        }
+        None => print!("{}", template),
     }
+    0
 }
@@ -0,0 +1,42 @@
+//! Entrypoint for the `protomaskctl` binary.
+//!
+//! Talks to a running protomask process over its control socket.
+
+use clap::Parser;
+use ctl::{
+    args::{Args, Command},
+    client::ControlClient,
+};
+use std::time::Duration;
+
+mod ctl;
+
+pub fn main() {
+    // Parse CLI args
+    let args = Args::parse();
+
+    // Connect to the translator
+    let mut client = match ControlClient::connect(&args.socket) {
+        Ok(client) => client,
+        Err(error) => {
+            eprintln!("Failed to connect to {}: {}", args.socket.display(), error);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match args.command {
+        Command::Top { interval } => ctl::top::run(&mut client, Duration::from_secs(interval)),
+        Command::Stats => ctl::stats::run(&mut client),
+        Command::Traffic { limit } => ctl::traffic::run(&mut client, limit),
+        Command::Drops { limit, headers } => ctl::drops::run(&mut client, limit, headers),
+        Command::Promote => ctl::failover::run(&mut client, "promote"),
+        Command::Demote => ctl::failover::run(&mut client, "demote"),
+        Command::Pool { command } => ctl::pool::run(&mut client, command),
+        Command::Mappings { command } => ctl::mappings::run(&mut client, command),
+        Command::Sessions { command } => ctl::sessions::run(&mut client, command),
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
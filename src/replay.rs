@@ -0,0 +1,150 @@
+//! Offline translation of packets from captures
+//!
+//! Packets are translated one at a time as if they had arrived on the NAT64's TUN interface, without bringing up a
+//! translator. This is shared by `protomask-replay` and `protomask inspect`.
+
+use crate::{
+    args::{protomask::Config, read_config_file, ConfigFormat},
+    common::{
+        packet_handler::{get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto},
+        prefix_tables::PrefixTables,
+        static_map_file,
+        validation::validate_packet,
+    },
+};
+use interproto::protocols::{
+    icmp::set_type_code_overrides,
+    ip::{translate_ipv4_to_ipv6, translate_ipv6_to_ipv4},
+};
+use ipnet::Ipv6Net;
+use rfc6052::{embed_ipv4_addr, extract_ipv4_addr};
+use std::path::Path;
+
+/// How packets are translated
+pub enum Translator {
+    /// Both addresses are embedded in a translation prefix
+    Stateless(Box<protomask_translator::Translator>),
+    /// IPv6 clients are mapped into IPv4 pools, as in a running NAT64
+    Nat64(PrefixTables),
+}
+
+impl Translator {
+    /// Translate like a NAT64 started with a config file, or statelessly through `prefix` without one
+    pub fn new(
+        config_file: Option<&Path>,
+        config_format: Option<ConfigFormat>,
+        prefix: Ipv6Net,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(match config_file {
+            Some(path) => {
+                let config: Config = read_config_file(path, config_format)
+                    .map_err(|error| format!("{}: {}", path.display(), error))?;
+                if let Some(issue) = config.validate().first() {
+                    return Err(format!("{}: {}", path.display(), issue).into());
+                }
+                let (icmp_overrides, icmpv6_overrides) = config.icmp_type_code_overrides();
+                set_type_code_overrides(icmp_overrides, icmpv6_overrides);
+                Self::Nat64(build_tables(&config))
+            }
+            None => Self::Stateless(Box::new(protomask_translator::Translator::stateless(prefix)?)),
+        })
+    }
+}
+
+/// Set up address tables the same way a NAT64 started with this config would
+fn build_tables(config: &Config) -> PrefixTables {
+    let prefix_tables = config.prefix_tables();
+    for table in prefix_tables.tables() {
+        table
+            .lock()
+            .unwrap()
+            .set_address_selection(config.address_selection.into());
+    }
+    for prefix in config.excluded_networks() {
+        for table in prefix_tables.tables() {
+            table.lock().unwrap().exclude(prefix);
+        }
+    }
+    let from_file = match &config.static_map_file {
+        Some(path) => static_map_file::read(path).unwrap_or_else(|error| {
+            log::warn!("Ignoring static mapping file: {}", error);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    for mapping in config.static_map.iter().chain(&from_file) {
+        prefix_tables.remove_conflicting(mapping.ipv4, mapping.ipv6);
+        if let Some(table) = prefix_tables.table_for_ipv4(mapping.ipv4) {
+            table
+                .lock()
+                .unwrap()
+                .insert_static(mapping.ipv4, mapping.ipv6)
+                .unwrap();
+        }
+    }
+
+    // Port sharing happens outside of the address tables, and isn't replayed
+    if config.subscriber_prefix_len().is_some() {
+        log::warn!("Subscriber aggregation is not replayed. Clients are mapped individually.");
+    }
+    prefix_tables
+}
+
+/// Translate a single packet, or explain why it was dropped
+pub fn translate(translator: &mut Translator, packet: &[u8]) -> Result<Vec<u8>, String> {
+    let prefix_tables = match translator {
+        Translator::Stateless(translator) => {
+            return translator
+                .translate(packet)
+                .map_err(|error| error.to_string())
+        }
+        Translator::Nat64(prefix_tables) => prefix_tables,
+    };
+    let (version, len) = validate_packet(packet).map_err(|error| error.to_string())?;
+    let packet = &packet[..len];
+    match version {
+        4 => {
+            let (source, dest) = get_ipv4_src_dst(packet);
+            let (prefix, table) = prefix_tables
+                .match_ipv4(source, dest)
+                .ok_or("Destination is not inside any pool")?;
+            let new_dest = table
+                .lock()
+                .unwrap()
+                .get_ipv6(&dest)
+                .ok_or("No mapping for destination address")?;
+            let new_source = embed_ipv4_addr(source, prefix).map_err(|error| error.to_string())?;
+            translate_ipv4_to_ipv6(packet, new_source, new_dest).map_err(|error| error.to_string())
+        }
+        _ => {
+            let (source, dest) = get_ipv6_src_dst(packet);
+            let (prefix, table) = prefix_tables
+                .match_ipv6(source, dest)
+                .ok_or("Destination is not inside any translation prefix the source may use")?;
+            let new_source = table
+                .lock()
+                .unwrap()
+                .get_or_create_ipv4(&source)
+                .map_err(|error| error.to_string())?;
+            let new_dest =
+                extract_ipv4_addr(dest, prefix.prefix_len()).map_err(|error| error.to_string())?;
+            prefix_tables.record_prefix(new_source, new_dest, prefix);
+            translate_ipv6_to_ipv4(packet, new_source, new_dest).map_err(|error| error.to_string())
+        }
+    }
+}
+
+/// Summarize the addresses of a packet for the report
+pub fn describe(packet: &[u8]) -> String {
+    match get_layer_3_proto(packet) {
+        Some(4) if packet.len() >= 20 => {
+            let (source, dest) = get_ipv4_src_dst(packet);
+            format!("{} -> {}", source, dest)
+        }
+        Some(6) if packet.len() >= 40 => {
+            let (source, dest) = get_ipv6_src_dst(packet);
+            format!("{} -> {}", source, dest)
+        }
+        _ => format!("{} bytes", packet.len()),
+    }
+}
@@ -0,0 +1,339 @@
+//! `protomask selftest`: end-to-end checks of a running NAT64
+//!
+//! Everything is tested from this host, as an IPv6-only client would see it: DNS64, reachability of an IPv4 address
+//! through the translation prefix, translation of ICMP errors coming back from the IPv4 side, and whether full-size
+//! packets make it through (or are at least reported as too big).
+
+use crate::common::network_monitor::discover_plat_prefix;
+use ipnet::Ipv6Net;
+use nix::sys::socket::{recvfrom, sendto, setsockopt, sockopt, MsgFlags, SockaddrIn6};
+use owo_colors::{OwoColorize, Stream::Stdout};
+use rfc6052::{embed_ipv4_addr, extract_ipv4_addr};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV6, UdpSocket},
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for each reply
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to wait for an ICMP error about each traceroute-style probe
+const HOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How far away the IPv4 side is allowed to be before giving up on an ICMP error
+const MAX_HOPS: u32 = 30;
+
+/// First UDP port probed when looking for ICMP errors (the traditional traceroute port)
+const PROBE_PORT: u16 = 33434;
+
+/// ICMPv6 message types
+const DESTINATION_UNREACHABLE: u8 = 1;
+const PACKET_TOO_BIG: u8 = 2;
+const TIME_EXCEEDED: u8 = 3;
+const ECHO_REQUEST: u8 = 128;
+const ECHO_REPLY: u8 = 129;
+
+/// Outcome of a single check
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+/// Run every check and print a report, returning the process exit code
+pub async fn run(prefix: Option<Ipv6Net>, target: Ipv4Addr, mtu: u16) -> i32 {
+    let mut results = Vec::new();
+
+    // DNS64 should synthesize addresses in the prefix being tested
+    let discovered = discover_plat_prefix().await;
+    results.push((
+        "DNS64",
+        match (discovered, prefix) {
+            (Some(discovered), Some(prefix)) if discovered != prefix => Outcome::Fail(format!(
+                "ipv4only.arpa was synthesized in {}, not {}",
+                discovered, prefix
+            )),
+            (Some(discovered), _) => {
+                Outcome::Pass(format!("ipv4only.arpa was synthesized in {}", discovered))
+            }
+            (None, _) => Outcome::Fail("ipv4only.arpa has no synthesized AAAA records".to_string()),
+        },
+    ));
+
+    // Everything else goes through the translation prefix
+    match prefix.or(discovered) {
+        Some(prefix) => {
+            let probes = tokio::task::spawn_blocking(move || run_probes(prefix, target, mtu))
+                .await
+                .unwrap();
+            results.extend(probes);
+        }
+        None => {
+            for name in ["Echo", "ICMP errors", "MTU"] {
+                results.push((
+                    name,
+                    Outcome::Skip("no translation prefix (pass --prefix)".to_string()),
+                ));
+            }
+        }
+    }
+
+    // Print the report
+    println!(
+        "protomask selftest to {} through {}",
+        target,
+        prefix.or(discovered).map_or_else(
+            || "an unknown prefix".to_string(),
+            |prefix| prefix.to_string()
+        )
+    );
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (name, outcome) in &results {
+        let (label, detail) = match outcome {
+            Outcome::Pass(detail) => {
+                passed += 1;
+                (
+                    "PASS"
+                        .if_supports_color(Stdout, |text| text.green())
+                        .to_string(),
+                    detail,
+                )
+            }
+            Outcome::Fail(detail) => {
+                failed += 1;
+                (
+                    "FAIL"
+                        .if_supports_color(Stdout, |text| text.red())
+                        .to_string(),
+                    detail,
+                )
+            }
+            Outcome::Skip(detail) => {
+                skipped += 1;
+                (
+                    "SKIP"
+                        .if_supports_color(Stdout, |text| text.yellow())
+                        .to_string(),
+                    detail,
+                )
+            }
+        };
+        println!("  {}  {:<12} {}", label, name, detail);
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+
+    i32::from(failed > 0)
+}
+
+/// Run the checks that send packets through the NAT64
+fn run_probes(prefix: Ipv6Net, target: Ipv4Addr, mtu: u16) -> Vec<(&'static str, Outcome)> {
+    let destination = match embed_ipv4_addr(target, prefix) {
+        Ok(destination) => destination,
+        Err(error) => {
+            return vec![(
+                "Echo",
+                Outcome::Fail(format!(
+                    "{} can't be embedded in {}: {}",
+                    target, prefix, error
+                )),
+            )]
+        }
+    };
+    let socket = match open_icmp_socket() {
+        Ok(socket) => socket,
+        Err(error) => {
+            return vec![(
+                "Echo",
+                Outcome::Fail(format!("Failed to open an ICMPv6 socket: {}", error)),
+            )]
+        }
+    };
+
+    vec![
+        ("Echo", check_echo(&socket, destination, target)),
+        (
+            "ICMP errors",
+            check_icmp_errors(&socket, prefix, destination),
+        ),
+        ("MTU", check_mtu(&socket, destination, mtu)),
+    ]
+}
+
+/// Ping the target through the prefix
+fn check_echo(socket: &Socket, destination: Ipv6Addr, target: Ipv4Addr) -> Outcome {
+    let start = Instant::now();
+    match ping(socket, destination, 1, 56) {
+        Ok(Some(_)) => Outcome::Pass(format!(
+            "reply from {} in {}ms",
+            target,
+            start.elapsed().as_millis()
+        )),
+        Ok(None) => Outcome::Fail(format!("no reply from {} ({})", target, destination)),
+        Err(error) => Outcome::Fail(format!("failed to ping {}: {}", destination, error)),
+    }
+}
+
+/// Send UDP probes with increasing hop limits until an ICMP error comes back from the IPv4 side
+fn check_icmp_errors(socket: &Socket, prefix: Ipv6Net, destination: Ipv6Addr) -> Outcome {
+    let probe = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)) {
+        Ok(probe) => Socket::from(probe),
+        Err(error) => return Outcome::Fail(format!("failed to open a UDP socket: {}", error)),
+    };
+
+    for hops in 1..=MAX_HOPS {
+        let port = PROBE_PORT + u16::try_from(hops).unwrap_or_default();
+        let sent = probe.set_unicast_hops_v6(hops).and_then(|()| {
+            probe.send_to(
+                b"protomask selftest",
+                &SocketAddrV6::new(destination, port, 0, 0).into(),
+            )
+        });
+        if let Err(error) = sent {
+            return Outcome::Fail(format!("failed to send a probe: {}", error));
+        }
+
+        // Only errors about our probe count, and only translated ones (from inside the prefix) say anything about
+        // the NAT64
+        let error = wait_for(socket, HOP_TIMEOUT, |message, source| {
+            let quoted = message.get(8..8 + 40 + 4)?;
+            let about_probe = quoted[6] == 17
+                && quoted[24..40] == destination.octets()
+                && quoted[42..44] == port.to_be_bytes();
+            (about_probe && matches!(message[0], DESTINATION_UNREACHABLE | TIME_EXCEEDED))
+                .then_some((message[0], message[1], source))
+        });
+        match error {
+            Ok(Some((kind, code, source))) if prefix.contains(&source) => {
+                let router = extract_ipv4_addr(source, prefix.prefix_len())
+                    .map_or_else(|_| source.to_string(), |router| router.to_string());
+                return Outcome::Pass(format!(
+                    "{} (code {}) from {} after {} hops",
+                    if kind == TIME_EXCEEDED {
+                        "Time Exceeded"
+                    } else {
+                        "Destination Unreachable"
+                    },
+                    code,
+                    router,
+                    hops
+                ));
+            }
+            // Errors from IPv6 routers on the way to the NAT64 just mean we haven't reached it yet
+            Ok(_) => {}
+            Err(error) => return Outcome::Fail(format!("failed to receive: {}", error)),
+        }
+    }
+    Outcome::Fail(format!(
+        "no translated ICMP errors within {} hops",
+        MAX_HOPS
+    ))
+}
+
+/// Send a full-size ping, which should either make it through or be reported as too big
+fn check_mtu(socket: &Socket, destination: Ipv6Addr, mtu: u16) -> Outcome {
+    // The ping is padded out to fill an IPv6 packet of exactly `mtu` bytes
+    let Some(payload_len) = usize::from(mtu).checked_sub(40 + 8) else {
+        return Outcome::Skip(format!("{} is too small to test", mtu));
+    };
+    if let Err(error) = setsockopt(socket.as_raw_fd(), sockopt::Ipv6DontFrag, &true) {
+        return Outcome::Fail(format!("failed to disable fragmentation: {}", error));
+    }
+    let result = ping(socket, destination, 2, payload_len);
+    let _ = setsockopt(socket.as_raw_fd(), sockopt::Ipv6DontFrag, &false);
+
+    match result {
+        Ok(Some((ECHO_REPLY, _))) => Outcome::Pass(format!("{} byte packets make it through", mtu)),
+        Ok(Some((_, path_mtu))) => Outcome::Pass(format!(
+            "{} byte packets are too big, but this was reported (path MTU {})",
+            mtu, path_mtu
+        )),
+        Ok(None) => Outcome::Fail(format!(
+            "{} byte packets vanish without a Packet Too Big",
+            mtu
+        )),
+        Err(error) if error.raw_os_error() == Some(nix::libc::EMSGSIZE) => Outcome::Skip(format!(
+            "{} byte packets are too big to leave this host",
+            mtu
+        )),
+        Err(error) => Outcome::Fail(format!("failed to ping {}: {}", destination, error)),
+    }
+}
+
+/// Open a raw ICMPv6 socket. The kernel fills in checksums on these.
+fn open_icmp_socket() -> io::Result<Socket> {
+    Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
+}
+
+/// Send an Echo Request and wait for its Echo Reply (or a Packet Too Big about it).
+///
+/// Returns the type of the message that came back, along with the MTU it reported (if it is a Packet Too Big).
+fn ping(
+    socket: &Socket,
+    destination: Ipv6Addr,
+    sequence: u16,
+    payload_len: usize,
+) -> io::Result<Option<(u8, u32)>> {
+    #[allow(clippy::cast_possible_truncation)]
+    let identifier = std::process::id() as u16;
+    let mut request = vec![0u8; 8 + payload_len];
+    request[0] = ECHO_REQUEST;
+    request[4..6].copy_from_slice(&identifier.to_be_bytes());
+    request[6..8].copy_from_slice(&sequence.to_be_bytes());
+    sendto(
+        socket.as_raw_fd(),
+        &request,
+        &SockaddrIn6::from(SocketAddrV6::new(destination, 0, 0, 0)),
+        MsgFlags::empty(),
+    )?;
+
+    wait_for(socket, REPLY_TIMEOUT, |message, source| match message[0] {
+        ECHO_REPLY => (source == destination && message.get(4..8)? == &request[4..8])
+            .then_some((ECHO_REPLY, 0)),
+        PACKET_TOO_BIG => {
+            // The offending packet is quoted after the MTU
+            let quoted = message.get(8..8 + 40 + 8)?;
+            (quoted[24..40] == destination.octets() && quoted[44..48] == request[4..8]).then(|| {
+                (
+                    PACKET_TOO_BIG,
+                    u32::from_be_bytes([message[4], message[5], message[6], message[7]]),
+                )
+            })
+        }
+        _ => None,
+    })
+    .map_err(io::Error::from)
+}
+
+/// Receive ICMPv6 messages until `matcher` accepts one, or until `timeout` passes
+fn wait_for<T>(
+    socket: &Socket,
+    timeout: Duration,
+    mut matcher: impl FnMut(&[u8], Ipv6Addr) -> Option<T>,
+) -> nix::Result<Option<T>> {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = vec![0u8; 65535];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|_| nix::Error::EINVAL)?;
+
+        // Raw ICMPv6 sockets receive messages without their IPv6 header
+        match recvfrom::<SockaddrIn6>(socket.as_raw_fd(), &mut buffer) {
+            Ok((len, Some(source))) if len >= 8 => {
+                if let Some(result) = matcher(&buffer[..len], source.ip()) {
+                    return Ok(Some(result));
+                }
+            }
+            Ok(_) => {}
+            Err(nix::Error::EAGAIN | nix::Error::EINTR) => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
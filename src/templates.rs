@@ -0,0 +1,239 @@
+//! Commented example configs written by `protomask init-config`, in both JSON and YAML
+
+/// Example NAT64 config
+pub const NAT64: &str = r#"{
+    // RFC6052 prefix that IPv4 addresses are embedded in. This is the Well-Known Prefix.
+    // Allowed lengths are /32, /40, /48, /56, /64, and /96.
+    "prefix": "64:ff9b::/96",
+
+    // Extra translation prefixes to serve alongside the main one. Each may optionally have its own IPv4 pool,
+    // otherwise the main pool is shared, and may be limited to some IPv6 sources.
+    "additional_prefixes": [
+        // { "prefix": "2001:db8:64::/96", "pool": ["198.51.100.0/24"] },
+        // { "prefix": "64:ff9b:1::/96", "sources": ["2001:db8:1::/48"] }
+    ],
+
+    // IPv4 prefixes to translate IPv6 clients into. These must be routed to this machine.
+    // 192.0.2.0/24 is reserved for documentation and should be replaced.
+    "pool": [
+        "192.0.2.0/24"
+    ],
+
+    // Addresses and prefixes inside the pool that should never be handed out dynamically (eg. gateways)
+    "excluded_addresses": [],
+    "excluded_prefixes": [],
+
+    // Permanent IPv4 to IPv6 mappings. Each IPv4 address must be inside one of the pool prefixes.
+    "static_map": [
+        // {
+        //     "ipv4": "192.0.2.1",
+        //     "ipv6": "2001:db8::1"
+        // }
+    ],
+
+    // File listing more static mappings in the same form (or, in a `.txt` file, as `<ipv4> <ipv6>` lines). It is
+    // watched, and changes are applied without a restart.
+    // "static_map_file": "/etc/protomask/static-map.json",
+
+    // Serve prometheus metrics (and health checks) on this address
+    // "prometheus_bind_addr": "[::1]:8999",
+
+    // Seconds an unused dynamic mapping is kept before its IPv4 address is returned to the pool
+    "reservation_timeout": 7200,
+
+    // Give each client an address picked by hashing its IPv6 address, so it keeps the same one across restarts
+    // "address_selection": "hashed",
+
+    // Remove TCP MD5 signatures and TCP-AO, which can't be verified once translated
+    // "tcp_options": { "signatures": "strip" },
+
+    // On multi-socket machines, run packet handling on the NUMA node of the NIC that carries the traffic
+    // "numa": { "nic": "eth0" },
+
+    // Drop traffic for unmapped pool addresses and excess ICMP in the kernel (needs the `nftables` feature)
+    // "nftables": { "enabled": true },
+
+    // Number of TUN queues (and worker threads) to use. Roughly one per CPU core is a good start.
+    "queues": 10
+}
+"#;
+
+/// Example CLAT config
+pub const CLAT: &str = r#"{
+    // RFC6052 prefix of the NAT64 that IPv4 traffic will be sent through. This is the Well-Known Prefix.
+    // Allowed lengths are /32, /40, /48, /56, /64, and /96.
+    "via": "64:ff9b::/96",
+
+    // Customer-side IPv4 prefixes that are allowed through the CLAT.
+    // 192.0.2.0/24 is reserved for documentation and should be replaced.
+    "customer_pool": [
+        "192.0.2.0/24"
+    ],
+
+    // Serve prometheus metrics (and health checks) on this address
+    // "prometheus_bind_addr": "[::1]:8999",
+
+    // Number of TUN queues (and worker threads) to use. Roughly one per CPU core is a good start.
+    "queues": 10
+}
+"#;
+
+/// Example NAT46 config
+pub const NAT46: &str = r#"{
+    // IPv4 prefixes that IPv6-only servers are mapped into. These must be routed to this machine.
+    // 192.0.2.0/24 is reserved for documentation and should be replaced.
+    "server_pool": [
+        "192.0.2.0/24"
+    ],
+
+    // RFC6052 prefix that client IPv4 addresses are embedded in. It must be routed to this machine.
+    // 2001:db8::/32 is reserved for documentation and should be replaced.
+    "client_prefix": "2001:db8:46::/96",
+
+    // Permanent mappings of IPv4 addresses in the server pool to IPv6 servers
+    "static_map": [
+        // {
+        //     "ipv4": "192.0.2.1",
+        //     "ipv6": "2001:db8::1"
+        // }
+    ],
+
+    // Answer A queries for IPv6-only names with addresses mapped to their AAAA records (DNS46)
+    // "dns_proxy": "192.0.2.53:53",
+    // "dns_upstream": "[2001:db8::53]:53",
+
+    // Seconds a mapping made for a DNS answer is kept before its IPv4 address is returned to the pool
+    "reservation_timeout": 7200,
+
+    // Serve prometheus metrics (and health checks) on this address
+    // "prometheus_bind_addr": "[::1]:8999",
+
+    // Number of TUN queues (and worker threads) to use. Roughly one per CPU core is a good start.
+    "queues": 10
+}
+"#;
+
+/// Example NAT64 config, in YAML
+pub const NAT64_YAML: &str = r#"# RFC6052 prefix that IPv4 addresses are embedded in. This is the Well-Known Prefix.
+# Allowed lengths are /32, /40, /48, /56, /64, and /96.
+prefix: "64:ff9b::/96"
+
+# Extra translation prefixes to serve alongside the main one. Each may optionally have its own IPv4 pool,
+# otherwise the main pool is shared, and may be limited to some IPv6 sources.
+additional_prefixes: []
+#  - prefix: "2001:db8:64::/96"
+#    pool: ["198.51.100.0/24"]
+#  - prefix: "64:ff9b:1::/96"
+#    sources: ["2001:db8:1::/48"]
+
+# IPv4 prefixes to translate IPv6 clients into. These must be routed to this machine.
+# 192.0.2.0/24 is reserved for documentation and should be replaced.
+pool:
+  - "192.0.2.0/24"
+
+# Addresses and prefixes inside the pool that should never be handed out dynamically (eg. gateways)
+excluded_addresses: []
+excluded_prefixes: []
+
+# Permanent IPv4 to IPv6 mappings. Each IPv4 address must be inside one of the pool prefixes.
+static_map: []
+#  - ipv4: "192.0.2.1"
+#    ipv6: "2001:db8::1"
+
+# File listing more static mappings in the same form (or, in a `.txt` file, as `<ipv4> <ipv6>` lines). It is
+# watched, and changes are applied without a restart.
+# static_map_file: "/etc/protomask/static-map.yaml"
+
+# Serve prometheus metrics (and health checks) on this address
+# prometheus_bind_addr: "[::1]:8999"
+
+# Seconds an unused dynamic mapping is kept before its IPv4 address is returned to the pool
+reservation_timeout: 7200
+
+# Give each client an address picked by hashing its IPv6 address, so it keeps the same one across restarts
+# address_selection: hashed
+
+# Remove TCP MD5 signatures and TCP-AO, which can't be verified once translated
+# tcp_options:
+#   signatures: strip
+
+# On multi-socket machines, run packet handling on the NUMA node of the NIC that carries the traffic
+# numa:
+#   nic: eth0
+
+# Drop traffic for unmapped pool addresses and excess ICMP in the kernel (needs the `nftables` feature)
+# nftables:
+#   enabled: true
+
+# Number of TUN queues (and worker threads) to use. Roughly one per CPU core is a good start.
+queues: 10
+"#;
+
+/// Example CLAT config, in YAML
+pub const CLAT_YAML: &str = r#"# RFC6052 prefix of the NAT64 that IPv4 traffic will be sent through. This is the Well-Known Prefix.
+# Allowed lengths are /32, /40, /48, /56, /64, and /96.
+via: "64:ff9b::/96"
+
+# Customer-side IPv4 prefixes that are allowed through the CLAT.
+# 192.0.2.0/24 is reserved for documentation and should be replaced.
+customer_pool:
+  - "192.0.2.0/24"
+
+# Serve prometheus metrics (and health checks) on this address
+# prometheus_bind_addr: "[::1]:8999"
+
+# Number of TUN queues (and worker threads) to use. Roughly one per CPU core is a good start.
+queues: 10
+"#;
+
+/// Example NAT46 config, in YAML
+pub const NAT46_YAML: &str = r#"# IPv4 prefixes that IPv6-only servers are mapped into. These must be routed to this machine.
+# 192.0.2.0/24 is reserved for documentation and should be replaced.
+server_pool:
+  - "192.0.2.0/24"
+
+# RFC6052 prefix that client IPv4 addresses are embedded in. It must be routed to this machine.
+# 2001:db8::/32 is reserved for documentation and should be replaced.
+client_prefix: "2001:db8:46::/96"
+
+# Permanent mappings of IPv4 addresses in the server pool to IPv6 servers
+static_map: []
+#  - ipv4: "192.0.2.1"
+#    ipv6: "2001:db8::1"
+
+# Answer A queries for IPv6-only names with addresses mapped to their AAAA records (DNS46)
+# dns_proxy: "192.0.2.53:53"
+# dns_upstream: "[2001:db8::53]:53"
+
+# Seconds a mapping made for a DNS answer is kept before its IPv4 address is returned to the pool
+reservation_timeout: 7200
+
+# Serve prometheus metrics (and health checks) on this address
+# prometheus_bind_addr: "[::1]:8999"
+
+# Number of TUN queues (and worker threads) to use. Roughly one per CPU core is a good start.
+queues: 10
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::{protomask, protomask_clat, protomask_nat46};
+
+    #[test]
+    fn test_templates_parse() {
+        for (json, yaml) in [(NAT64, NAT64_YAML), (CLAT, CLAT_YAML), (NAT46, NAT46_YAML)] {
+            let json = json
+                .lines()
+                .filter(|line| !line.trim_start().starts_with("//"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+            let yaml: serde_json::Value = serde_yaml::from_str(yaml).unwrap();
+            assert_eq!(json, yaml);
+        }
+        serde_yaml::from_str::<protomask::Config>(NAT64_YAML).unwrap();
+        serde_yaml::from_str::<protomask_clat::Config>(CLAT_YAML).unwrap();
+        serde_yaml::from_str::<protomask_nat46::Config>(NAT46_YAML).unwrap();
+    }
+}
@@ -0,0 +1,446 @@
+//! Customer-side transLATor (CLAT)
+//!
+//! Translates all native IPv4 traffic to IPv6 traffic for transmission over an IPv6-only ISP network.
+
+use crate::args::{
+    protomask_clat::{Args, Config},
+    MulticastHandling,
+};
+use crate::common::{
+    capture::DropCapture,
+    dbus::{serve_dbus, ClatStatus},
+    dns_proxy::serve_dns_proxy,
+    dry_run::{self, Outcome},
+    egress::{self, EgressBinding},
+    http, interface,
+    network_monitor::{customer_routes, monitor_network, MonitoredClat},
+    packet_buffer::{BufferSize, InterfaceMtu},
+    packet_handler::{
+        get_group_destination, get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto,
+        handle_translation_error, PacketHandlingError,
+    },
+    packet_trace::{trace_stage, PacketTracer},
+    permissions::ensure_root,
+    plat_probe::{self, PlatProbes},
+    pref64::{self, Pref64},
+    profiler::{start_puffin_capture, start_puffin_server},
+    rfc6791::{error_source, is_icmpv6_error},
+    runtime::start_console,
+    stage_timer::{end_stage, StageSampler},
+    telemetry,
+    validation::validate_packet,
+    worker_scaling::WorkerScaler,
+};
+use interproto::protocols::{
+    icmp::generate::{answer_ipv4, answer_ipv6},
+    ip::{translate_ipv4_to_ipv6, translate_ipv6_to_ipv4},
+};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use protomask_metrics::metrics::label_values::{STAGE_TRANSLATE, STAGE_WRITE};
+use rfc6052::{embed_ipv4_addr_unchecked, extract_ipv4_addr_unchecked};
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Run a CLAT until all of its workers exit
+pub async fn run(args: Args) {
+    // Load config data
+    let config = args.data().unwrap();
+
+    // A dry run only needs the config
+    if args.dry_run {
+        std::process::exit(dry_run(&config, args.input.as_deref().unwrap()));
+    }
+
+    // If configured, report crashes
+    #[allow(clippy::let_unit_value)]
+    let _crash_reporting = telemetry::init_crash_reporting(&config.telemetry);
+
+    // We must be root to continue program execution
+    ensure_root();
+
+    // If built with tokio-console support, serve it
+    start_console();
+
+    // Start profiling
+    #[allow(clippy::let_unit_value)]
+    let _server = start_puffin_server(&args.profiler_args);
+    start_puffin_capture(&args.profiler_args);
+
+    // Start the metrics and health check servers
+    http::start_servers(config.prom_bind_addr, config.health_bind_addr);
+
+    // Translate packets until all workers exit
+    for worker in spawn(config, &args.interface, args.capture_drops.as_deref()).await {
+        worker.join().unwrap();
+    }
+}
+
+/// Bring up a CLAT on its own TUN interface, returning the handles of its worker threads
+pub async fn spawn(
+    config: Config,
+    interface_name: &str,
+    capture_drops: Option<&Path>,
+) -> Vec<JoinHandle<()>> {
+    // Route IPv4 traffic towards the interface (all of it, unless configured otherwise),
+    // and add an IPv6 route for each customer prefix
+    let ipv4_routes = if config.no_default_route {
+        Vec::new()
+    } else if config.ipv4_routes.is_empty() {
+        vec![IpNet::V4(Ipv4Net::default())]
+    } else {
+        config.ipv4_routes.iter().copied().map(IpNet::V4).collect()
+    };
+    let ipv6_routes = customer_routes(&config.customer_pool, config.embed_prefix);
+
+    // Bring up a TUN interface. In automatic mode, IPv4 routes are only installed once the CLAT is needed.
+    let tun = interface::bring_up(
+        interface_name,
+        config.num_queues,
+        config.mtu,
+        &if config.auto {
+            ipv6_routes
+        } else {
+            [ipv4_routes.clone(), ipv6_routes].concat()
+        },
+        config.route_metric,
+        !config.no_netlink,
+    )
+    .await;
+
+    // If configured, give the CLAT addresses of its own
+    if let (Some(address), false) = (config.clat_address, config.no_netlink) {
+        let addresses = [
+            IpNet::V4(Ipv4Net::from(address)),
+            IpNet::V6(Ipv6Net::from(unsafe {
+                embed_ipv4_addr_unchecked(address, config.embed_prefix)
+            })),
+        ];
+        if let Err(error) = interface::add_addresses(tun.name(), &addresses).await {
+            log::warn!("Failed to assign the CLAT's addresses: {}", error);
+        }
+    }
+
+    // If configured, send translated traffic out of a specific uplink
+    if let Some(uplink) = config.egress_interface.clone() {
+        let binding = EgressBinding {
+            interfaces: vec![tun.name().to_string()],
+            uplink,
+            table: config.egress_table,
+        };
+        egress::bind(&binding).await.unwrap();
+        tokio::spawn(egress::follow(binding));
+    }
+
+    // If requested, capture all dropped packets to a file
+    let drop_capture = capture_drops.map(|path| {
+        log::info!("Capturing dropped packets to {}", path.display());
+        Arc::new(DropCapture::new(path).unwrap())
+    });
+
+    // If configured, only translate while the network needs it, and follow the network's NAT64 prefix
+    let enabled = Arc::new(AtomicBool::new(!config.auto));
+    let plat_prefix = Arc::new(RwLock::new(config.embed_prefix));
+    let rediscover = Arc::new(Notify::new());
+    let follow_prefix = config.discover_prefix || config.ra_interface.is_some();
+    let pref64 = config.ra_interface.clone().map(|uplink| {
+        let pref64 = Arc::new(Pref64::new(Arc::clone(&rediscover)));
+        pref64::listen(uplink, Arc::clone(&pref64));
+        pref64
+    });
+    if config.auto || follow_prefix {
+        tokio::spawn(monitor_network(MonitoredClat {
+            interface: tun.name().to_string(),
+            customer_pool: config.customer_pool.clone(),
+            ipv4_routes,
+            mtu: config.mtu,
+            route_metric: config.route_metric,
+            configure_netlink: !config.no_netlink,
+            auto: config.auto,
+            follow_prefix,
+            enabled: Arc::clone(&enabled),
+            plat_prefix: Arc::clone(&plat_prefix),
+            rediscover: Arc::clone(&rediscover),
+            pref64,
+        }));
+    }
+
+    // If configured, check that the NAT64 can be reached through the prefix in use
+    if let Some(anchor) = config.plat_probe {
+        tokio::spawn(plat_probe::monitor(PlatProbes {
+            anchor,
+            interval: Duration::from_secs(config.plat_probe_interval),
+            timeout: Duration::from_secs(config.plat_probe_timeout),
+            failures: config.plat_probe_failures,
+            plat_prefix: Arc::clone(&plat_prefix),
+            enabled: Arc::clone(&enabled),
+            rediscover: follow_prefix.then_some(rediscover),
+        }));
+    }
+
+    // If configured, keep clients from bypassing the CLAT with synthesized AAAA records
+    if let (Some(bind), Some(upstream)) = (config.dns_proxy, config.dns_upstream) {
+        tokio::spawn(serve_dns_proxy(bind, upstream, Arc::clone(&plat_prefix)));
+    }
+
+    // If configured, allow the CLAT to be inspected and toggled over D-Bus
+    if config.dbus {
+        tokio::spawn(serve_dbus(ClatStatus {
+            interface: tun.name().to_string(),
+            plat_prefix: Arc::clone(&plat_prefix),
+            customer_pool: config
+                .customer_pool
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            enabled: Arc::clone(&enabled),
+        }));
+    }
+
+    // Packet buffers must fit anything the interface can carry, even after its MTU is raised
+    let interface_mtu = Arc::new(InterfaceMtu::new(vec![Arc::clone(&tun)]));
+    tokio::spawn(Arc::clone(&interface_mtu).follow());
+    let buffer_size = match config.packet_buffer_size {
+        Some(size) => BufferSize::Fixed(size),
+        None => BufferSize::Mtu(Arc::clone(&interface_mtu)),
+    };
+    let stage_timing_sample_rate = config.stage_timing_sample_rate;
+    let packet_trace_sample_rate = config.packet_trace_sample_rate;
+    let icmp_error_sources = Arc::new(config.icmp_error_sources.clone());
+    let drop_multicast = config.multicast == MulticastHandling::Drop;
+    let clat_address = config.clat_address;
+
+    // If configured, only keep as many queues active as the load needs
+    let scaler = config.min_workers.map(|min_workers| {
+        let scaler = Arc::new(WorkerScaler::new(Arc::clone(&tun), min_workers));
+        tokio::spawn(Arc::clone(&scaler).monitor());
+        scaler
+    });
+
+    // Translate all incoming packets
+    log::info!(
+        "Translating packets on {} (MTU {})",
+        tun.name(),
+        interface_mtu.get()
+    );
+    let mut worker_threads = Vec::new();
+    for queue_id in 0..config.num_queues {
+        let tun = Arc::clone(&tun);
+        let drop_capture = drop_capture.clone();
+        let enabled = Arc::clone(&enabled);
+        let plat_prefix = Arc::clone(&plat_prefix);
+        let icmp_error_sources = Arc::clone(&icmp_error_sources);
+        let scaler = scaler.clone();
+        let buffer_size = buffer_size.clone();
+        worker_threads.push(std::thread::spawn(move || {
+            log::debug!("Starting worker thread for queue {}", queue_id);
+            let _health_guard = protomask_metrics::health::WorkerGuard::new();
+            let mut buffer = buffer_size.buffer();
+            let mut sampler = StageSampler::new(stage_timing_sample_rate);
+            let mut tracer = PacketTracer::new(packet_trace_sample_rate, queue_id);
+            loop {
+                // Indicate to the profiler that we are starting a new packet
+                profiling::finish_frame!();
+                profiling::scope!("packet");
+
+                // Wait for this queue to be needed
+                if let Some(scaler) = &scaler {
+                    scaler.wait_until_active(queue_id);
+                }
+
+                // Read a packet. Reading fails if the queue was detached since it was last checked.
+                buffer_size.fit(&mut buffer);
+                let len = match tun.fd(queue_id).unwrap().read(&mut buffer) {
+                    Ok(len) if len > buffer.len() => {
+                        buffer.dropped_oversized(len);
+                        continue;
+                    }
+                    Ok(len) => len,
+                    Err(_) if scaler.as_ref().is_some_and(|s| !s.is_active(queue_id)) => continue,
+                    Err(error) => panic!("Failed to read from queue {}: {}", queue_id, error),
+                };
+                let _busy = scaler.as_ref().map(|scaler| scaler.busy(queue_id));
+
+                // Drop everything while disabled
+                if !enabled.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                // Make sure the packet is safe to pick apart, and cut off any padding
+                let len = match validate_packet(&buffer[..len]) {
+                    Ok((_, len)) => len,
+                    Err(error) => {
+                        log::debug!("Dropping malformed packet: {}", error);
+                        if let Some(capture) = &drop_capture {
+                            capture.record(&buffer[..len], &error.to_string());
+                        }
+                        continue;
+                    }
+                };
+
+                // Nothing is translated for groups of hosts yet
+                if drop_multicast && get_group_destination(&buffer[..len]).is_some() {
+                    continue;
+                }
+                let mut timer = sampler.start();
+                let mut trace = tracer.start(len);
+                if let Some(trace) = &mut trace {
+                    trace.input(&buffer[..len]);
+                }
+                let embed_prefix = *plat_prefix.read().unwrap();
+
+                // Packets for the CLAT itself are answered rather than translated
+                if let Some(answer) = clat_address
+                    .and_then(|address| answer_local(&buffer[..len], address, embed_prefix))
+                {
+                    match answer {
+                        Ok(Some(reply)) => tun.fd(queue_id).unwrap().write_all(&reply).unwrap(),
+                        Ok(None) => {}
+                        Err(error) => {
+                            log::debug!("Failed to answer a packet for the CLAT: {}", error)
+                        }
+                    }
+                    continue;
+                }
+
+                // Translate it based on the Layer 3 protocol number
+                let translation_result: Result<Option<Vec<u8>>, PacketHandlingError> =
+                    match get_layer_3_proto(&buffer[..len]) {
+                        Some(4) => translate_outbound(&buffer[..len], embed_prefix).map(Some),
+                        Some(6) => {
+                            translate_inbound(&buffer[..len], embed_prefix, &icmp_error_sources)
+                                .map(Some)
+                        }
+                        Some(proto) => {
+                            log::warn!("Unknown Layer 3 protocol: {}", proto);
+                            if let Some(capture) = &drop_capture {
+                                capture.record(
+                                    &buffer[..len],
+                                    &format!("Unknown Layer 3 protocol: {}", proto),
+                                );
+                            }
+                            continue;
+                        }
+                        None => {
+                            continue;
+                        }
+                    };
+
+                end_stage(&mut timer, STAGE_TRANSLATE);
+                trace_stage(&mut trace, STAGE_TRANSLATE);
+
+                // Capture the packet if translation failed
+                if let (Some(capture), Err(error)) = (&drop_capture, &translation_result) {
+                    capture.record(&buffer[..len], &error.to_string());
+                }
+
+                // Handle any errors and write
+                if let Some(output) = handle_translation_error(translation_result) {
+                    tun.fd(queue_id).unwrap().write_all(&output).unwrap();
+                    end_stage(&mut timer, STAGE_WRITE);
+                    if let Some(trace) = &mut trace {
+                        trace.stage(STAGE_WRITE);
+                        trace.output(&output);
+                    }
+                }
+            }
+        }));
+    }
+    worker_threads
+}
+
+/// Translate an IPv4 packet from a customer into an IPv6 packet towards the PLAT
+fn translate_outbound(
+    packet: &[u8],
+    embed_prefix: Ipv6Net,
+) -> Result<Vec<u8>, PacketHandlingError> {
+    let (source, dest) = get_ipv4_src_dst(packet);
+    Ok(translate_ipv4_to_ipv6(
+        packet,
+        unsafe { embed_ipv4_addr_unchecked(source, embed_prefix) },
+        unsafe { embed_ipv4_addr_unchecked(dest, embed_prefix) },
+    )?)
+}
+
+/// Translate an IPv6 packet from the PLAT back into an IPv4 packet towards a customer
+fn translate_inbound(
+    packet: &[u8],
+    embed_prefix: Ipv6Net,
+    icmp_error_sources: &[Ipv4Addr],
+) -> Result<Vec<u8>, PacketHandlingError> {
+    let (source, dest) = get_ipv6_src_dst(packet);
+
+    // Errors from routers outside the PLAT prefix have no IPv4 address to extract (RFC6791)
+    let error_source = (!embed_prefix.contains(&source) && is_icmpv6_error(packet))
+        .then(|| error_source(icmp_error_sources, source))
+        .flatten();
+    Ok(translate_ipv6_to_ipv4(
+        packet,
+        error_source.unwrap_or_else(|| unsafe {
+            extract_ipv4_addr_unchecked(source, embed_prefix.prefix_len())
+        }),
+        unsafe { extract_ipv4_addr_unchecked(dest, embed_prefix.prefix_len()) },
+    )?)
+}
+
+/// Decide what a CLAT with this config would do with each packet of a capture (see [`dry_run::run`]). Returns the
+/// process exit code.
+///
+/// No interface is brought up, so packets are always translated with the configured prefix, even if the CLAT would
+/// discover another one or stay disabled in automatic mode.
+pub fn dry_run(config: &Config, input: &Path) -> i32 {
+    let drop_multicast = config.multicast == MulticastHandling::Drop;
+    dry_run::run(input, |packet| {
+        match validate_packet(packet) {
+            Ok((_, len)) => packet.truncate(len),
+            Err(error) => return Outcome::Dropped(error.to_string()),
+        }
+        if drop_multicast && get_group_destination(packet).is_some() {
+            return Outcome::Dropped("Multicast destination".to_string());
+        }
+
+        // Packets for the CLAT itself are answered rather than translated
+        if let Some(answer) = config
+            .clat_address
+            .and_then(|address| answer_local(packet, address, config.embed_prefix))
+        {
+            return match answer {
+                Ok(Some(reply)) => Outcome::Replied(reply),
+                Ok(None) => Outcome::Dropped("Not answered by the CLAT".to_string()),
+                Err(error) => Outcome::Dropped(error.to_string()),
+            };
+        }
+
+        let result = match get_layer_3_proto(packet) {
+            Some(4) => translate_outbound(packet, config.embed_prefix),
+            Some(6) => translate_inbound(packet, config.embed_prefix, &config.icmp_error_sources),
+            Some(proto) => return Outcome::Dropped(format!("Unknown Layer 3 protocol: {}", proto)),
+            None => return Outcome::Dropped("Not an IP packet".to_string()),
+        };
+        match result {
+            Ok(output) => Outcome::Translated(output),
+            Err(error) => Outcome::Dropped(error.to_string()),
+        }
+    })
+}
+
+/// Answer a packet addressed to the CLAT's own IPv4 address, or its embedded IPv6 address. Returns `None` for packets
+/// addressed to anything else.
+fn answer_local(
+    packet: &[u8],
+    address: Ipv4Addr,
+    embed_prefix: Ipv6Net,
+) -> Option<interproto::error::Result<Option<Vec<u8>>>> {
+    match get_layer_3_proto(packet) {
+        Some(4) => (get_ipv4_src_dst(packet).1 == address).then(|| answer_ipv4(packet)),
+        Some(6) => (get_ipv6_src_dst(packet).1
+            == unsafe { embed_ipv4_addr_unchecked(address, embed_prefix) })
+        .then(|| answer_ipv6(packet)),
+        _ => None,
+    }
+}
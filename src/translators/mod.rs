@@ -0,0 +1,6 @@
+//! Translator implementations that can be run by the protomask binaries
+
+pub mod clat;
+pub mod multi;
+pub mod nat46;
+pub mod nat64;
@@ -0,0 +1,61 @@
+//! Multiple translators running side by side in one process
+//!
+//! Every instance gets its own TUN interface and worker threads, while metrics and health checks are shared.
+
+use super::{clat, nat64};
+use crate::args::{
+    multi::{InstanceConfig, MultiConfig},
+    ConfigFormat,
+};
+use crate::common::{http, permissions::ensure_root, runtime::start_console, telemetry};
+use std::path::Path;
+
+/// Run every instance described by a config file until all of their workers exit
+pub async fn run(config_path: &Path, config_format: Option<ConfigFormat>) {
+    // Load config data
+    let config = MultiConfig::load(config_path, config_format);
+
+    // If configured, report crashes
+    #[allow(clippy::let_unit_value)]
+    let _crash_reporting = telemetry::init_crash_reporting(&config.telemetry);
+
+    // We must be root to continue program execution
+    ensure_root();
+
+    // If built with tokio-console support, serve it
+    start_console();
+
+    // Metrics are global, so a single server covers every instance
+    http::start_servers(config.prom_bind_addr, config.health_bind_addr);
+
+    // Bring up each instance
+    let mut worker_threads = Vec::new();
+    for instance in config.instances {
+        match instance {
+            InstanceConfig::Nat64 {
+                interface,
+                capture_drops,
+                config,
+            } => {
+                log::info!("Starting NAT64 instance on {}", interface);
+                worker_threads.extend(
+                    nat64::spawn(config, &interface, capture_drops.as_deref(), None, None).await,
+                );
+            }
+            InstanceConfig::Clat {
+                interface,
+                capture_drops,
+                config,
+            } => {
+                log::info!("Starting CLAT instance on {}", interface);
+                worker_threads
+                    .extend(clat::spawn(config, &interface, capture_drops.as_deref()).await);
+            }
+        }
+    }
+
+    // Translate packets until all workers exit
+    for worker in worker_threads {
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+//! NAT46
+//!
+//! Gives IPv4-only clients access to IPv6-only servers, in the opposite direction from NAT64. Each server is mapped to
+//! an IPv4 address from the server pool, either statically or when a client looks it up through the DNS46 proxy.
+//! Clients are represented on the IPv6 side by embedding their addresses in the client prefix, so servers can answer
+//! them without any state of their own.
+
+use crate::args::{
+    protomask::PipelineConfig,
+    protomask_nat46::{Args, Config},
+};
+use crate::common::{
+    capture::DropCapture,
+    counters::{DropReason, QueueCounters},
+    dns46::serve_dns46,
+    dry_run::{self, Outcome},
+    http, interface,
+    packet_buffer::InterfaceMtu,
+    packet_handler::{
+        get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto, handle_translation_error,
+        PacketHandlingError,
+    },
+    permissions::ensure_root,
+    profiler::{start_puffin_capture, start_puffin_server},
+    recent_drops::RecentDrops,
+    runtime::start_console,
+    telemetry,
+    validation::validate_packet,
+    worker::{PacketProcessor, Verdict, WorkerPool},
+};
+use fast_nat::CrossProtocolNetworkAddressTableWithIpv4Pool;
+use interproto::protocols::ip::{translate_ipv4_to_ipv6, translate_ipv6_to_ipv4};
+use ipnet::{IpNet, Ipv6Net};
+use rfc6052::{embed_ipv4_addr_unchecked, extract_ipv4_addr_unchecked};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often expired mappings are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Run a NAT46 until all of its workers exit
+pub async fn run(args: Args) {
+    // Load config data
+    let config = args.data().unwrap();
+
+    // A dry run only needs the config
+    if args.dry_run {
+        std::process::exit(dry_run(&config, args.input.as_deref().unwrap()));
+    }
+
+    // If configured, report crashes
+    #[allow(clippy::let_unit_value)]
+    let _crash_reporting = telemetry::init_crash_reporting(&config.telemetry);
+
+    // We must be root to continue program execution
+    ensure_root();
+
+    // If built with tokio-console support, serve it
+    start_console();
+
+    // Start profiling
+    #[allow(clippy::let_unit_value)]
+    let _server = start_puffin_server(&args.profiler_args);
+    start_puffin_capture(&args.profiler_args);
+
+    // Start the metrics and health check servers
+    http::start_servers(config.prom_bind_addr, config.health_bind_addr);
+
+    // Translate packets until all workers exit
+    for worker in spawn(config, &args.interface, args.capture_drops.as_deref()).await {
+        worker.join().unwrap();
+    }
+}
+
+/// Bring up a NAT46 on its own TUN interface, returning the handles of its worker threads
+pub async fn spawn(
+    config: Config,
+    interface_name: &str,
+    capture_drops: Option<&Path>,
+) -> Vec<JoinHandle<()>> {
+    let client_prefix = config.client_prefix.unwrap();
+
+    // Route the server pool and the client prefix towards the interface
+    let routes: Vec<IpNet> = config
+        .server_pool
+        .iter()
+        .copied()
+        .map(IpNet::V4)
+        .chain(std::iter::once(IpNet::V6(client_prefix)))
+        .collect();
+    let tun = interface::bring_up(
+        interface_name,
+        config.num_queues,
+        config.mtu,
+        &routes,
+        None,
+        !config.no_netlink,
+    )
+    .await;
+
+    // If requested, capture all dropped packets to a file
+    let drop_capture = capture_drops.map(|path| {
+        log::info!("Capturing dropped packets to {}", path.display());
+        Arc::new(DropCapture::new(path).unwrap())
+    });
+
+    // Servers are mapped into the pool statically, or on demand by the DNS46 proxy
+    let table = Arc::new(Mutex::new(server_table(&config)));
+    {
+        let table = Arc::clone(&table);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                table.lock().unwrap().prune();
+            }
+        });
+    }
+    if let (Some(bind), Some(upstream)) = (config.dns_proxy, config.dns_upstream) {
+        tokio::spawn(serve_dns46(bind, upstream, Arc::clone(&table)));
+    }
+
+    // Packet buffers must fit anything the interface can carry, even after its MTU is raised
+    let interface_mtu = Arc::new(InterfaceMtu::new(vec![Arc::clone(&tun)]));
+    tokio::spawn(Arc::clone(&interface_mtu).follow());
+
+    // Translate all incoming packets
+    log::info!(
+        "Translating packets on {} (MTU {})",
+        tun.name(),
+        interface_mtu.get()
+    );
+    let pool = WorkerPool {
+        directions: &[(Arc::clone(&tun), Arc::clone(&tun))],
+        num_queues: config.num_queues,
+        pipeline: &PipelineConfig::default(),
+        interface_mtu: &interface_mtu,
+        numa: None,
+        queue_counters: Arc::new(
+            (0..config.num_queues)
+                .map(|_| QueueCounters::default())
+                .collect(),
+        ),
+        recent_drops: Arc::new(RecentDrops::new(0)),
+        drop_capture,
+        stage_timing_sample_rate: 0.0,
+        packet_trace_sample_rate: 0.0,
+    };
+    let decisions = Arc::new(ServerDecisions {
+        table,
+        client_prefix,
+    });
+    pool.spawn(decisions, |_, _| {})
+}
+
+/// Decides what becomes of packets between clients and the servers mapped for them
+struct ServerDecisions {
+    table: Arc<Mutex<CrossProtocolNetworkAddressTableWithIpv4Pool>>,
+    client_prefix: Ipv6Net,
+}
+
+impl PacketProcessor for ServerDecisions {
+    fn process(
+        &self,
+        packet: &mut [u8],
+        _counters: &QueueCounters,
+        _stage: impl FnMut(&'static str),
+    ) -> Verdict {
+        // Translate it based on the Layer 3 protocol number
+        let translation_result = match get_layer_3_proto(packet) {
+            // Clients reach servers through the addresses they were mapped to
+            Some(4) => {
+                let (source, dest) = get_ipv4_src_dst(packet);
+                let server = self.table.lock().unwrap().get_ipv6(&dest);
+                match server {
+                    Some(server) => {
+                        translate_from_client(packet, source, server, self.client_prefix)
+                    }
+                    None => {
+                        log::debug!("No server is mapped to {}", dest);
+                        protomask_metrics::metric!(PACKET_COUNTER, PROTOCOL_IPV4, STATUS_DROPPED)
+                            .inc();
+                        return Verdict::Dropped(
+                            DropReason::Unmapped,
+                            Some("No server is mapped to the destination".to_string()),
+                        );
+                    }
+                }
+            }
+            // Servers answer clients from their own addresses
+            Some(6) => {
+                let (source, dest) = get_ipv6_src_dst(packet);
+                let mapped = self.table.lock().unwrap().get_ipv4(&source);
+                match mapped.filter(|_| self.client_prefix.contains(&dest)) {
+                    Some(mapped) => translate_from_server(packet, mapped, dest, self.client_prefix),
+                    None => {
+                        log::debug!("{} is not a mapped server", source);
+                        protomask_metrics::metric!(PACKET_COUNTER, PROTOCOL_IPV6, STATUS_DROPPED)
+                            .inc();
+                        return Verdict::Dropped(
+                            DropReason::Unmapped,
+                            Some("Source is not a mapped server".to_string()),
+                        );
+                    }
+                }
+            }
+            Some(proto) => {
+                log::warn!("Unknown Layer 3 protocol: {}", proto);
+                return Verdict::Dropped(
+                    DropReason::UnknownProtocol,
+                    Some(format!("Unknown Layer 3 protocol: {}", proto)),
+                );
+            }
+            None => return Verdict::Dropped(DropReason::UnknownProtocol, None),
+        };
+
+        // Handle any errors
+        match translation_result {
+            Ok(output) => Verdict::Translated(output),
+            Err(error) => {
+                let detail = error.to_string();
+                handle_translation_error(Err(error));
+                Verdict::Dropped(DropReason::Untranslatable, Some(detail))
+            }
+        }
+    }
+}
+
+/// Build the table of servers mapped into the server pool, starting with the static mappings
+fn server_table(config: &Config) -> CrossProtocolNetworkAddressTableWithIpv4Pool {
+    let mut table = CrossProtocolNetworkAddressTableWithIpv4Pool::new(
+        &config.server_pool,
+        config.reservation_timeout(),
+    );
+    for mapping in &config.static_map {
+        log::info!("Mapping server {} to {}", mapping.ipv6, mapping.ipv4);
+        table.insert_static(mapping.ipv4, mapping.ipv6).unwrap();
+    }
+    table
+}
+
+/// Translate an IPv4 packet from a client into an IPv6 packet towards the server it reached
+fn translate_from_client(
+    packet: &[u8],
+    source: Ipv4Addr,
+    server: Ipv6Addr,
+    client_prefix: Ipv6Net,
+) -> Result<Vec<u8>, PacketHandlingError> {
+    Ok(translate_ipv4_to_ipv6(
+        packet,
+        unsafe { embed_ipv4_addr_unchecked(source, client_prefix) },
+        server,
+    )?)
+}
+
+/// Translate an IPv6 packet from a server into an IPv4 packet from the address it is mapped to
+fn translate_from_server(
+    packet: &[u8],
+    mapped: Ipv4Addr,
+    dest: Ipv6Addr,
+    client_prefix: Ipv6Net,
+) -> Result<Vec<u8>, PacketHandlingError> {
+    Ok(translate_ipv6_to_ipv4(packet, mapped, unsafe {
+        extract_ipv4_addr_unchecked(dest, client_prefix.prefix_len())
+    })?)
+}
+
+/// Decide what a NAT46 with this config would do with each packet of a capture (see [`dry_run::run`]). Returns the
+/// process exit code.
+///
+/// No interface is brought up and the DNS46 proxy isn't started, so only statically mapped servers can be reached.
+pub fn dry_run(config: &Config, input: &Path) -> i32 {
+    let decisions = ServerDecisions {
+        table: Arc::new(Mutex::new(server_table(config))),
+        client_prefix: config.client_prefix.unwrap(),
+    };
+    let counters = QueueCounters::default();
+    dry_run::run(input, |packet| {
+        let verdict = match validate_packet(packet) {
+            Ok((_, len)) => decisions.process(&mut packet[..len], &counters, |_| {}),
+            Err(error) => Verdict::Dropped(error.drop_reason(), Some(error.to_string())),
+        };
+        match verdict {
+            Verdict::Translated(output) => Outcome::Translated(output),
+            Verdict::Reply(reply) => Outcome::Replied(reply),
+            Verdict::Dropped(reason, detail) | Verdict::Rejected(reason, detail, _) => {
+                Outcome::Dropped(detail.unwrap_or_else(|| reason.name().to_string()))
+            }
+        }
+    })
+}
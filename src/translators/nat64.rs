@@ -0,0 +1,1189 @@
+//! NAT64 translator
+//!
+//! Translates IPv6 clients into a pool of IPv4 addresses, allowing them to reach the IPv4 internet
+//! through an RFC6052 translation prefix.
+
+use crate::args::{
+    protomask::{Args, Config, ConfigReloader, PoolFallback, UnmappedReject},
+    MulticastHandling,
+};
+use crate::common::{
+    address_hook::AddressHook,
+    agentx::run_subagent,
+    capture::DropCapture,
+    control::serve_control,
+    counters::{
+        export_mapping_metrics, export_tenant_metrics, record_packet_size,
+        record_subscriber_traffic, record_tenant_limited, record_tenant_traffic, DropReason,
+        MappingTraffic, QueueCounters,
+    },
+    drain::drain_on_sigterm,
+    dry_run::{self, Outcome},
+    dscp::{self, DscpRule},
+    egress::{self, EgressBinding},
+    failover::Failover,
+    grpc::start_grpc_server,
+    hop::{self, Hop},
+    http,
+    icmp_rate_limit::ErrorRateLimiter,
+    interface,
+    ipfix::FlowExporter,
+    lease_store::LeaseStore,
+    ndp_proxy::proxy_ndp,
+    nftables::{self, Protection, ProtectionRules},
+    packet_buffer::InterfaceMtu,
+    packet_handler::{
+        get_group_destination, get_ipv4_src_dst, get_ipv6_src_dst, get_layer_3_proto,
+        handle_translation_error, GroupDestination, PacketHandlingError,
+    },
+    permissions::ensure_root,
+    policy::PolicyScript,
+    prefix_tables::PrefixTables,
+    profiler::{start_puffin_capture, start_puffin_server},
+    rdns::{write_zone_periodically, HostnameTemplates},
+    recent_drops::RecentDrops,
+    rfc6791::{error_source, is_icmpv6_error},
+    replication::{follow_primary, start_primary},
+    runtime::start_console,
+    session_log::SessionLogger,
+    state_dump::{dump_on_sigusr1, StateDumpSource},
+    static_map_file,
+    telemetry,
+    upgrade::{serve_upgrades, take_over, Handoff},
+    upstream_health,
+    validation::validate_packet,
+    webhook::Webhook,
+    worker::{PacketProcessor, Verdict, WorkerPool},
+};
+use fast_nat::{PortBlockTable, SessionLimit, SessionTable};
+use interproto::protocols::{
+    icmp::{
+        errors::{icmp_error, ErrorKind},
+        set_type_code_overrides,
+    },
+    ip::{translate_ipv4_to_ipv6, translate_ipv6_to_ipv4},
+    ports::{get_ipv4_port, set_ipv4_port, Direction},
+    tcp::tcp_reset_ipv4,
+    tcp_options::{sanitize_tcp_options, OptionPolicy},
+};
+use ipnet::{IpNet, Ipv6Net};
+use protomask_metrics::metrics::label_values::{
+    LIMIT_MAPPINGS, LIMIT_SESSIONS, STAGE_HOP, STAGE_TRANSLATE,
+};
+use rfc6052::{embed_ipv4_addr_unchecked, extract_ipv4_addr_unchecked};
+use rtnl::route::DiscardKind;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Lowest port handed out when devices share a mapping
+const FIRST_SHARED_PORT: u16 = 1024;
+
+/// Run a NAT64 until all of its workers exit
+pub async fn run(args: Args) {
+    // Load config data
+    let config = args.data().unwrap();
+
+    // A dry run only needs the config
+    if args.dry_run {
+        std::process::exit(dry_run(&config, args.input.as_deref().unwrap()));
+    }
+
+    // If configured, report crashes
+    #[allow(clippy::let_unit_value)]
+    let _crash_reporting = telemetry::init_crash_reporting(&config.telemetry);
+
+    // We must be root to continue program execution
+    ensure_root();
+
+    // If built with tokio-console support, serve it
+    start_console();
+
+    // If asked to, take over from a running protomask. This must happen before binding any sockets it holds.
+    let handoff = args.take_over.then(|| {
+        let Some(path) = config.upgrade_socket.as_deref() else {
+            log::error!("--take-over needs an upgrade socket to take over from");
+            std::process::exit(1);
+        };
+        take_over(path, config.num_queues).unwrap_or_else(|error| {
+            log::error!("{}", error);
+            std::process::exit(1);
+        })
+    });
+
+    // Start profiling
+    #[allow(clippy::let_unit_value)]
+    let _server = start_puffin_server(&args.profiler_args);
+    start_puffin_capture(&args.profiler_args);
+
+    // Start the metrics and health check servers
+    http::start_servers(config.prom_bind_addr, config.health_bind_addr);
+
+    // Translate packets until all workers exit
+    let workers = spawn(
+        config,
+        &args.interface,
+        args.capture_drops.as_deref(),
+        args.reloader(),
+        handoff,
+    )
+    .await;
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+/// Bring up a NAT64 on its own TUN interface, returning the handles of its worker threads
+pub async fn spawn(
+    config: Config,
+    interface_name: &str,
+    capture_drops: Option<&Path>,
+    reloader: Option<ConfigReloader>,
+    handoff: Option<Handoff>,
+) -> Vec<JoinHandle<()>> {
+    let start_time = Instant::now();
+
+    // Set up an address table for each pool, keeping prefixes with source ACLs (and tenants) to their clients
+    let prefix_tables = config.prefix_tables();
+    let prefix_tables = Arc::new(prefix_tables);
+
+    // Bring up a TUN interface with routes for each translation prefix and pool prefix.
+    // With a separate IPv4 interface, the pool prefixes are routed there instead.
+    // A standby only installs its routes once promoted.
+    let mut routes = prefix_tables.prefixes().map(IpNet::V6).collect::<Vec<_>>();
+    let pool_routes = prefix_tables
+        .pools()
+        .into_iter()
+        .map(IpNet::V4)
+        .collect::<Vec<_>>();
+    if config.ipv4_interface.is_none() {
+        routes.extend(pool_routes.iter().copied());
+    }
+    // When taking over, the interface and its routes are already in place
+    let (tun, active, handed_over) = match handoff {
+        Some(handoff) => {
+            protomask_metrics::health::set_tun_up();
+            protomask_metrics::health::set_routes_installed();
+            (Arc::new(handoff.tun), handoff.active, handoff.mappings)
+        }
+        None => (
+            interface::bring_up(
+                interface_name,
+                config.num_queues,
+                config.mtu,
+                if config.standby { &[] } else { &routes },
+                None,
+                !config.no_netlink,
+            )
+            .await,
+            !config.standby,
+            Vec::new(),
+        ),
+    };
+    let ipv4_tun = match &config.ipv4_interface {
+        Some(name) => Some(
+            interface::bring_up(
+                name,
+                config.num_queues,
+                config.mtu,
+                if config.standby { &[] } else { &pool_routes },
+                None,
+                !config.no_netlink,
+            )
+            .await,
+        ),
+        None => None,
+    };
+    let egress_interfaces: Vec<String> = std::iter::once(&tun)
+        .chain(&ipv4_tun)
+        .map(|tun| tun.name().to_string())
+        .collect();
+    let mut interfaces = vec![(tun.name().to_string(), routes)];
+    if let Some(ipv4_tun) = &ipv4_tun {
+        interfaces.push((ipv4_tun.name().to_string(), pool_routes));
+    }
+    let failover = Arc::new(Failover::new(
+        interfaces,
+        config.mtu,
+        !config.no_netlink,
+        config.on_promote.clone(),
+        config.on_demote.clone(),
+        active,
+    ));
+
+    // If configured, withdraw our routes while the upstream isn't working
+    if let Some(probes) = config.upstream_health.probes() {
+        tokio::spawn(upstream_health::monitor(probes, Arc::clone(&failover)));
+    }
+
+    // If configured, keep pool traffic from looping back upstream while the pool isn't routed to us.
+    // These routes stay in place on standby, which is when they matter most. With a route journal, any left behind by a
+    // previous run that are no longer wanted are removed.
+    if config.pool_fallback.is_some() || config.route_journal.is_some() {
+        interface::add_discard_routes(
+            &prefix_tables.pools(),
+            config.pool_fallback.map(|fallback| match fallback {
+                PoolFallback::Blackhole => DiscardKind::Blackhole,
+                PoolFallback::Unreachable => DiscardKind::Unreachable,
+            }),
+            config.route_journal.as_deref(),
+        )
+        .await
+        .unwrap();
+    }
+
+    // If configured, send translated traffic out of a specific uplink
+    if let Some(uplink) = config.egress_interface.clone() {
+        let binding = EgressBinding {
+            interfaces: egress_interfaces,
+            uplink,
+            table: config.egress_table,
+        };
+        egress::bind(&binding).await.unwrap();
+        tokio::spawn(egress::follow(binding));
+    }
+
+    // If configured, attract traffic for on-link translation prefixes
+    if let Some(uplink) = config.ndp_proxy.clone() {
+        tokio::spawn(proxy_ndp(uplink, prefix_tables.prefixes().collect()));
+    }
+
+    // Give returning clients their previous address when possible
+    for table in prefix_tables.tables() {
+        let mut table = table.lock().unwrap();
+        table.remember_expired(
+            config.recent_mappings,
+            Duration::from_secs(config.recent_mapping_ttl),
+        );
+        table.set_address_selection(config.address_selection.into());
+    }
+
+    // Translate ICMP types and codes the way the operator asked, where it differs from the defaults
+    let (icmp_overrides, icmpv6_overrides) = config.icmp_type_code_overrides();
+    set_type_code_overrides(icmp_overrides, icmpv6_overrides);
+
+    // Keep excluded addresses out of dynamic allocation
+    for prefix in config.excluded_networks() {
+        log::debug!("Excluding {} from dynamic allocation", prefix);
+        for table in prefix_tables.tables() {
+            table.lock().unwrap().exclude(prefix);
+        }
+    }
+
+    // Carry on with the mappings of the process we took over from
+    for mapping in handed_over {
+        prefix_tables.remove_conflicting(mapping.ipv4, mapping.ipv6);
+        let result = match prefix_tables.table_for_ipv4(mapping.ipv4) {
+            Some(table) => table.lock().unwrap().insert_with_ttl(
+                mapping.ipv4,
+                mapping.ipv6,
+                Duration::from_secs(mapping.expires_in_secs),
+            ),
+            None => Err(fast_nat::error::Error::InvalidIpv4Address(mapping.ipv4)),
+        };
+        if let Err(error) = result {
+            log::warn!("Dropping handed over mapping: {}", error);
+        }
+    }
+
+    // If configured, stream mapping changes to standbys
+    let replication = match config.replication.listen {
+        Some(bind_addr) => match start_primary(
+            bind_addr,
+            config.replication.secret.clone().unwrap_or_default(),
+            Duration::from_secs(config.replication.sync_interval),
+            Arc::clone(&prefix_tables),
+            config.replication.replication_queue_capacity,
+        )
+        .await
+        {
+            Ok(replication) => Some(replication),
+            Err(error) => {
+                log::error!(
+                    "Failed to listen for replication connections on {}: {}. Replication is disabled",
+                    bind_addr,
+                    error
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    // If configured, answer ARP for every address that may be mapped
+    if let Some(uplink) = &config.arp_proxy {
+        let addresses: Vec<_> = prefix_tables
+            .pools()
+            .into_iter()
+            .flat_map(|pool| pool.hosts())
+            .filter(|address| {
+                !config.excluded_addresses.contains(address)
+                    && !config
+                        .excluded_prefixes
+                        .iter()
+                        .any(|excluded| excluded.contains(address))
+            })
+            .chain(config.static_map.iter().map(|mapping| mapping.ipv4))
+            .chain(config.translator_address)
+            .collect();
+        log::info!(
+            "Proxying ARP for {} addresses on {}",
+            addresses.len(),
+            uplink
+        );
+        interface::add_proxy_arp(uplink, &addresses).await.unwrap();
+    }
+
+    // If configured, record all NAT session events
+    let session_logger = config.session_log.target().map(|target| {
+        log::info!("Logging NAT session events to {:?}", target);
+        SessionLogger::new(target, config.session_log.session_log_queue_capacity).unwrap()
+    });
+
+    // If configured, notify a webhook of NAT session events
+    let webhook = config.webhook.url.as_deref().map(|url| {
+        log::info!("Sending NAT session events to {}", url);
+        Webhook::new(
+            url,
+            config.webhook.batch_size,
+            Duration::from_secs(config.webhook.flush_interval),
+            config.webhook.webhook_queue_capacity,
+        )
+        .unwrap()
+    });
+
+    // If configured, turn away traffic that would only be dropped before it reaches the interface
+    let protection_rules = config.nftables.nftables.then(|| {
+        ProtectionRules::install(Protection {
+            interface: tun.name().to_string(),
+            ipv4_interface: ipv4_tun.as_ref().map(|tun| tun.name().to_string()),
+            pools: prefix_tables.pools(),
+            always_allowed: config.translator_address.into_iter().collect(),
+            icmp_rate: config.nftables.nftables_icmp_rate,
+            prefix_tables: Arc::clone(&prefix_tables),
+        })
+        .unwrap()
+    });
+    if protection_rules.is_some() {
+        tokio::spawn(nftables::remove_on_shutdown(config.drain_timeout == 0));
+    }
+
+    // Pass mapping events on to everything that needs them
+    if session_logger.is_some()
+        || replication.is_some()
+        || webhook.is_some()
+        || protection_rules.is_some()
+    {
+        for table in prefix_tables.tables() {
+            let session_logger = session_logger.clone();
+            let replication = replication.clone();
+            let webhook = webhook.clone();
+            let protection_rules = protection_rules.clone();
+            table.lock().unwrap().set_event_handler(move |event| {
+                if let Some(logger) = &session_logger {
+                    logger.log(event);
+                }
+                if let Some(replication) = &replication {
+                    replication.publish(event);
+                }
+                if let Some(webhook) = &webhook {
+                    webhook.notify(event);
+                }
+                if let Some(rules) = &protection_rules {
+                    rules.notify(event);
+                }
+            });
+        }
+    }
+
+    // Translation never scans the tables, so expired mappings are pruned here
+    {
+        let prefix_tables = Arc::clone(&prefix_tables);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                for table in prefix_tables.tables() {
+                    table.lock().unwrap().prune();
+                }
+                prefix_tables.prune_reply_prefixes();
+            }
+        });
+    }
+    for mapping in &config.static_map {
+        prefix_tables.remove_conflicting(mapping.ipv4, mapping.ipv6);
+        prefix_tables
+            .table_for_ipv4(mapping.ipv4)
+            .expect("Static mapping is outside of all pools")
+            .lock()
+            .unwrap()
+            .insert_static(mapping.ipv4, mapping.ipv6)
+            .unwrap();
+    }
+
+    // If configured, copy mappings from a primary
+    if let Some(primary) = config.replication.primary.clone() {
+        tokio::spawn(follow_primary(
+            primary,
+            config.replication.secret.clone().unwrap_or_default(),
+            Arc::clone(&prefix_tables),
+            Duration::from_secs(config.reservation_timeout),
+        ));
+    }
+
+    // If configured, export per-flow statistics to an IPFIX collector
+    let flow_exporter = config.flow_export.collector.map(|collector| {
+        log::info!("Exporting IPFIX flow records to {}", collector);
+        FlowExporter::new(collector, Duration::from_secs(config.flow_export.interval)).unwrap()
+    });
+
+    // If configured, publish reverse DNS for the pool
+    if let (Some(path), Some(template)) = (&config.rdns.zone_file, &config.rdns.template) {
+        tokio::spawn(write_zone_periodically(
+            path.clone(),
+            HostnameTemplates {
+                mapped: template.clone(),
+                unmapped: config.rdns.unmapped_template.clone(),
+            },
+            Duration::from_secs(config.rdns.rdns_interval),
+            Arc::clone(&prefix_tables),
+        ));
+    }
+
+    // If requested, capture all dropped packets to a file
+    let drop_capture = capture_drops.map(|path| {
+        log::info!("Capturing dropped packets to {}", path.display());
+        Arc::new(DropCapture::new(path).unwrap())
+    });
+
+    // Dump internal state whenever SIGUSR1 is received
+    let queue_counters = Arc::new(
+        (0..config.num_queues)
+            .map(|_| QueueCounters::default())
+            .collect::<Vec<_>>(),
+    );
+    let traffic = Arc::new(MappingTraffic::default());
+    let recent_drops = Arc::new(RecentDrops::new(config.recent_drops));
+    let state = Arc::new(StateDumpSource {
+        interface: tun.name().to_string(),
+        config: Mutex::new(config.clone()),
+        file_static_map: Mutex::new(Vec::new()),
+        prefix_tables: Arc::clone(&prefix_tables),
+        queue_counters: Arc::clone(&queue_counters),
+        traffic: Arc::clone(&traffic),
+        recent_drops: Arc::clone(&recent_drops),
+        start_time,
+    });
+    tokio::spawn(dump_on_sigusr1(
+        Arc::clone(&state),
+        config.state_dump_path.clone(),
+    ));
+
+    // If configured, load more static mappings from their own file, and keep them in line with it
+    if let Some(path) = config.static_map_file.clone() {
+        match static_map_file::reload(&path, &state) {
+            Ok((added, _)) => log::info!("Loaded {} static mappings from {}", added, path.display()),
+            Err(error) => log::error!("Failed to load static mappings: {}", error),
+        }
+        static_map_file::watch(path, Arc::clone(&state));
+    }
+
+    // If configured, export the traffic of every mapping as metrics
+    if config.mapping_metrics {
+        tokio::spawn(export_mapping_metrics(
+            Arc::clone(&prefix_tables),
+            Arc::clone(&traffic),
+        ));
+    }
+
+    // Export the traffic and pool usage of every tenant as metrics
+    if !prefix_tables.tenants().is_empty() {
+        tokio::spawn(export_tenant_metrics(Arc::clone(&prefix_tables)));
+    }
+
+    // If configured, serve the gRPC control API
+    if let Some(bind_addr) = config.grpc_bind_addr {
+        start_grpc_server(bind_addr, Arc::clone(&state), reloader);
+    }
+
+    // If configured, shut down gracefully on SIGTERM
+    if config.drain_timeout > 0 {
+        tokio::spawn(drain_on_sigterm(
+            Arc::clone(&prefix_tables),
+            Duration::from_secs(config.drain_timeout),
+        ));
+    }
+
+    // If configured, answer SNMP queries through the system's master agent
+    if let Some(master) = config.agentx_master.clone() {
+        tokio::spawn(run_subagent(master, Arc::clone(&state)));
+    }
+
+    // If configured, hand over to a new process when asked
+    if let Some(path) = config.upgrade_socket.clone() {
+        let tun = Arc::clone(&tun);
+        let prefix_tables = Arc::clone(&prefix_tables);
+        let failover = Arc::clone(&failover);
+        tokio::spawn(async move {
+            if let Err(error) = serve_upgrades(&path, tun, prefix_tables, failover).await {
+                log::error!(
+                    "Failed to listen for upgrades on {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        });
+    }
+
+    // If configured, accept requests from protomaskctl
+    if let Some(path) = config.control_socket.clone() {
+        tokio::spawn(async move {
+            if let Err(error) = serve_control(&path, state, failover).await {
+                log::error!(
+                    "Failed to listen for control requests on {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        });
+    }
+
+    // Translate all incoming packets. Translations leave through the interface for their address family, which
+    // is the one they arrived on unless there is a separate IPv4 interface.
+    let mtu = tun.mtu().unwrap();
+    let directions = match &ipv4_tun {
+        Some(ipv4_tun) => {
+            log::info!(
+                "Translating packets between {} and {} (MTU {})",
+                tun.name(),
+                ipv4_tun.name(),
+                mtu
+            );
+            vec![
+                (Arc::clone(&tun), Arc::clone(ipv4_tun)),
+                (Arc::clone(ipv4_tun), Arc::clone(&tun)),
+            ]
+        }
+        None => {
+            log::info!("Translating packets on {} (MTU {})", tun.name(), mtu);
+            vec![(Arc::clone(&tun), Arc::clone(&tun))]
+        }
+    };
+
+    // Packet buffers must fit anything the interfaces can carry, even after their MTU is raised
+    let interface_mtu = Arc::new(InterfaceMtu::new(
+        directions.iter().map(|(tun, _)| Arc::clone(tun)).collect(),
+    ));
+    tokio::spawn(Arc::clone(&interface_mtu).follow());
+    let decisions = Arc::new(PacketDecisions::new(&config, Arc::clone(&prefix_tables)));
+
+    // With shared addresses, only the ports tell subscribers apart, so every session is logged as well
+    if let Some(logger) = &session_logger {
+        if let Some(port_blocks) = &decisions.port_blocks {
+            let logger = logger.clone();
+            port_blocks
+                .lock()
+                .unwrap()
+                .set_event_handler(move |event| logger.log_session(event));
+        }
+        if let Some(sessions) = &decisions.sessions {
+            let logger = logger.clone();
+            sessions
+                .lock()
+                .unwrap()
+                .set_event_handler(move |event| logger.log_session(event));
+        }
+    }
+
+    // Ports are otherwise only reclaimed when they run out
+    if decisions.port_blocks.is_some() || decisions.sessions.is_some() {
+        let port_blocks = decisions.port_blocks.clone();
+        let sessions = decisions.sessions.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                if let Some(port_blocks) = &port_blocks {
+                    port_blocks.lock().unwrap().prune();
+                }
+                if let Some(sessions) = &sessions {
+                    sessions.lock().unwrap().prune();
+                }
+            }
+        });
+    }
+
+    // Translate packets, accounting for each one on its way out
+    let subscriber_prefix_len = decisions.subscriber_prefix_len;
+    let has_tenants = !prefix_tables.tenants().is_empty();
+    let pool = WorkerPool {
+        directions: &directions,
+        num_queues: config.num_queues,
+        pipeline: &config.pipeline,
+        interface_mtu: &interface_mtu,
+        numa: config.numa.placement().unwrap(),
+        queue_counters,
+        recent_drops,
+        drop_capture,
+        stage_timing_sample_rate: config.stage_timing_sample_rate,
+        packet_trace_sample_rate: config.packet_trace_sample_rate,
+    };
+    pool.spawn(decisions, move |input, output| {
+        if let Some(flow_exporter) = &flow_exporter {
+            flow_exporter.record(input, output);
+        }
+        traffic.record(input, output);
+        record_packet_size(input, output);
+        if let Some(prefix_len) = subscriber_prefix_len {
+            record_subscriber_traffic(input, output, prefix_len);
+        }
+        if has_tenants {
+            record_tenant_traffic(input, output, &prefix_tables);
+        }
+    })
+}
+
+/// Decide what a NAT64 with this config would do with each packet of a capture (see [`dry_run::run`]). Returns the
+/// process exit code.
+///
+/// No interface is brought up and nothing outside of the process is touched, so neither the address hook nor the
+/// lease store are consulted.
+pub fn dry_run(config: &Config, input: &Path) -> i32 {
+    let mut config = config.clone();
+    if config.address_hook.take().is_some() {
+        log::warn!(
+            "The address hook is not run during a dry run. Mappings are made from the pool."
+        );
+    }
+    if config.lease_store.take().is_some() {
+        log::warn!(
+            "The lease store is not consulted during a dry run. Mappings are made from the pool."
+        );
+    }
+
+    // Set up the address tables the same way as a running NAT64
+    let prefix_tables = Arc::new(config.prefix_tables());
+    for table in prefix_tables.tables() {
+        let mut table = table.lock().unwrap();
+        table.remember_expired(
+            config.recent_mappings,
+            Duration::from_secs(config.recent_mapping_ttl),
+        );
+        table.set_address_selection(config.address_selection.into());
+    }
+    let (icmp_overrides, icmpv6_overrides) = config.icmp_type_code_overrides();
+    set_type_code_overrides(icmp_overrides, icmpv6_overrides);
+    for prefix in config.excluded_networks() {
+        for table in prefix_tables.tables() {
+            table.lock().unwrap().exclude(prefix);
+        }
+    }
+    let from_file = match &config.static_map_file {
+        Some(path) => match static_map_file::read(path) {
+            Ok(mappings) => mappings,
+            Err(error) => {
+                log::error!("Failed to load static mappings: {}", error);
+                return 1;
+            }
+        },
+        None => Vec::new(),
+    };
+    for mapping in config.static_map.iter().chain(&from_file) {
+        prefix_tables.remove_conflicting(mapping.ipv4, mapping.ipv6);
+        if let Some(table) = prefix_tables.table_for_ipv4(mapping.ipv4) {
+            table
+                .lock()
+                .unwrap()
+                .insert_static(mapping.ipv4, mapping.ipv6)
+                .unwrap();
+        }
+    }
+    let decisions = PacketDecisions::new(&config, Arc::clone(&prefix_tables));
+
+    // Handle every packet
+    let counters = QueueCounters::default();
+    dry_run::run(input, |packet| {
+        let verdict = match validate_packet(packet) {
+            Ok((_, len)) => decisions.process(&mut packet[..len], &counters, |_| {}),
+            Err(error) => Verdict::Dropped(error.drop_reason(), Some(error.to_string())),
+        };
+        match verdict {
+            Verdict::Translated(output) => Outcome::Translated(output),
+            Verdict::Reply(reply) => Outcome::Replied(reply),
+            Verdict::Dropped(reason, detail) => Outcome::Dropped(describe_drop(reason, detail)),
+            Verdict::Rejected(reason, detail, reply) => {
+                Outcome::Rejected(describe_drop(reason, detail), reply)
+            }
+        }
+    })
+}
+
+/// Summarize why a packet was dropped for a dry run
+fn describe_drop(reason: DropReason, detail: Option<String>) -> String {
+    match detail {
+        Some(detail) => format!("{}: {}", reason.name(), detail),
+        None => reason.name().to_string(),
+    }
+}
+
+/// Everything that decides what becomes of a validated packet, shared by all workers
+pub struct PacketDecisions {
+    prefix_tables: Arc<PrefixTables>,
+    translator_address: Option<Ipv4Addr>,
+    icmp_error_sources: Vec<Ipv4Addr>,
+    tcp_option_policy: OptionPolicy,
+    dscp_rules: Vec<DscpRule>,
+    drop_multicast: bool,
+    error_limiter: ErrorRateLimiter,
+    unmapped_reject: UnmappedReject,
+    address_hook: Option<AddressHook>,
+    lease_store: Option<LeaseStore>,
+    policy: Option<PolicyScript>,
+    port_blocks: Option<Arc<Mutex<PortBlockTable>>>,
+    sessions: Option<Arc<Mutex<SessionTable>>>,
+    subscriber_prefix_len: Option<u8>,
+}
+
+impl PacketDecisions {
+    /// Set up everything a NAT64 with this config consults while handling packets
+    pub fn new(config: &Config, prefix_tables: Arc<PrefixTables>) -> Self {
+        let address_hook = config
+            .address_hook
+            .as_deref()
+            .map(|hook| AddressHook::new(hook).unwrap());
+        let lease_store = config.lease_store.as_deref().map(|url| {
+            LeaseStore::new(
+                url,
+                Duration::from_secs(config.reservation_timeout),
+                Arc::clone(&prefix_tables),
+            )
+            .unwrap()
+        });
+        let policy = config.policy_script.as_deref().map(|path| {
+            log::info!("Consulting policy script {}", path.display());
+            PolicyScript::load(path).unwrap()
+        });
+
+        // If configured, hand out fixed port blocks instead of whole addresses
+        let port_blocks = config.deterministic_nat.is_enabled().then(|| {
+            log::info!(
+                "Using deterministic NAT for {} subscriber prefixes",
+                config.deterministic_nat.subscribers.len()
+            );
+            Arc::new(Mutex::new(config.port_block_table().unwrap()))
+        });
+
+        // If configured, share each mapping between all devices of a subscriber
+        let subscriber_prefix_len = config.subscriber_prefix_len();
+        let sessions = subscriber_prefix_len
+            .filter(|_| port_blocks.is_none())
+            .map(|prefix_len| {
+                log::info!(
+                    "Sharing mappings between the devices of each /{}",
+                    prefix_len
+                );
+                let mut sessions = SessionTable::new(config.aggregation.session_timeouts());
+                sessions.set_limits(
+                    prefix_tables
+                        .tenants()
+                        .iter()
+                        .filter_map(|tenant| {
+                            tenant.max_sessions.map(|max_sessions| SessionLimit {
+                                sources: tenant.sources.clone(),
+                                max_sessions,
+                            })
+                        })
+                        .collect(),
+                );
+                Arc::new(Mutex::new(sessions))
+            });
+
+        Self {
+            prefix_tables,
+            translator_address: config.translator_address,
+            icmp_error_sources: config.icmp_error_sources.clone(),
+            tcp_option_policy: config.tcp_options.policy(),
+            dscp_rules: config.dscp_rules.clone(),
+            drop_multicast: config.multicast == MulticastHandling::Drop,
+            error_limiter: config.icmp_rate_limit.limiter(),
+            unmapped_reject: config.unmapped_reject,
+            address_hook,
+            lease_store,
+            policy,
+            port_blocks,
+            sessions,
+            subscriber_prefix_len,
+        }
+    }
+
+    /// Build the refusal of an IPv4 packet sent to a pool address without a mapping, if one should be sent
+    fn reject_unmapped(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let (source, dest) = get_ipv4_src_dst(packet);
+        let reply = match self.unmapped_reject {
+            UnmappedReject::Drop => return None,
+            UnmappedReject::Unreachable => icmp_error(packet, dest, ErrorKind::HostUnreachable),
+            UnmappedReject::TcpReset => tcp_reset_ipv4(packet),
+        };
+
+        // Refusals are rate limited like any other error the translator sends, so they can't be used for reflection
+        let reply = reply.ok().flatten()?;
+        self.error_limiter.allow(source.into()).then_some(reply)
+    }
+
+    /// Find or create the mappings a packet needs and translate it
+    fn translate(&self, packet: &mut [u8]) -> Result<Vec<u8>, (DropReason, Option<String>)> {
+        let prefix_tables = &self.prefix_tables;
+        let translation_result: Result<Option<Vec<u8>>, PacketHandlingError> =
+            match get_layer_3_proto(packet) {
+                Some(4) => {
+                    let (source, dest) = get_ipv4_src_dst(packet);
+                    let matched = prefix_tables
+                        .match_ipv4(source, dest)
+                        .ok_or(DropReason::Unmapped);
+                    match matched.and_then(|(prefix, table)| {
+                        if let Some(port_blocks) = &self.port_blocks {
+                            // Send the packet back to the port it came from
+                            let (protocol, port) = get_ipv4_port(packet, Direction::Inbound)
+                                .ok_or(DropReason::NoPort)?;
+                            let (new_destination, new_port) = port_blocks
+                                .lock()
+                                .unwrap()
+                                .translate_inbound(dest, protocol, port)
+                                .ok_or(DropReason::Unmapped)?;
+                            set_ipv4_port(packet, Direction::Inbound, new_port);
+                            return Ok((prefix, new_destination));
+                        }
+                        let mapped = match &self.lease_store {
+                            Some(store) => store
+                                .get_ipv6(table, dest)
+                                .map_err(|_| DropReason::LeasePending)?,
+                            None => table.lock().unwrap().get_ipv6(&dest),
+                        }
+                        .ok_or(DropReason::Unmapped)?;
+                        let Some(sessions) = &self.sessions else {
+                            return Ok((prefix, mapped));
+                        };
+
+                        // The mapping belongs to a subscriber, so find the device behind the port
+                        let (protocol, port) =
+                            get_ipv4_port(packet, Direction::Inbound).ok_or(DropReason::NoPort)?;
+                        let (new_destination, new_port) = sessions
+                            .lock()
+                            .unwrap()
+                            .translate_inbound(dest, protocol, port)
+                            .ok_or(DropReason::Unmapped)?;
+                        if self
+                            .subscriber_prefix_len
+                            .map(|prefix_len| subscriber_of(new_destination, prefix_len))
+                            != Some(mapped)
+                        {
+                            return Err(DropReason::Unmapped);
+                        }
+                        set_ipv4_port(packet, Direction::Inbound, new_port);
+                        Ok((prefix, new_destination))
+                    }) {
+                        Ok((prefix, new_destination)) => translate_ipv4_to_ipv6(
+                            packet,
+                            unsafe { embed_ipv4_addr_unchecked(source, prefix) },
+                            new_destination,
+                        )
+                        .map(Some)
+                        .map_err(PacketHandlingError::from),
+                        Err(reason) => {
+                            protomask_metrics::metric!(
+                                PACKET_COUNTER,
+                                PROTOCOL_IPV4,
+                                STATUS_DROPPED
+                            );
+                            let detail = match reason {
+                                DropReason::LeasePending => "Waiting for the lease store",
+                                DropReason::NoPort => {
+                                    "No port to find the device sharing the mapping by"
+                                }
+                                _ => "No mapping for destination address",
+                            };
+                            return Err((reason, Some(detail.to_string())));
+                        }
+                    }
+                }
+                Some(6) => {
+                    let (source, dest) = get_ipv6_src_dst(packet);
+                    let mut lease_pending = false;
+                    match prefix_tables
+                        .match_ipv6(source, dest)
+                        .ok_or_else(|| {
+                            "Destination is not inside any translation prefix the source may use"
+                                .to_string()
+                        })
+                        .and_then(|(prefix, table)| {
+                            // Mappings are made for the whole subscriber, when aggregating
+                            let source = self
+                                .subscriber_prefix_len
+                                .map_or(source, |prefix_len| subscriber_of(source, prefix_len));
+
+                            // Errors from IPv6 routers are sent from a dedicated address rather than given a mapping (RFC6791)
+                            if is_icmpv6_error(packet)
+                                && match &self.port_blocks {
+                                    Some(port_blocks) => {
+                                        port_blocks.lock().unwrap().port_block(source).is_none()
+                                    }
+                                    None => table.lock().unwrap().get_ipv4(&source).is_none(),
+                                }
+                            {
+                                if let Some(error_source) =
+                                    error_source(&self.icmp_error_sources, source)
+                                {
+                                    return Ok((prefix, error_source));
+                                }
+                            }
+                            let new_source = if let Some(port_blocks) = &self.port_blocks {
+                                port_blocks
+                                    .lock()
+                                    .unwrap()
+                                    .port_block(source)
+                                    .map(|(address, _)| address)
+                                    .ok_or_else(|| "Source is not a subscriber".to_string())?
+                            } else if protomask_metrics::health::is_draining() {
+                                // Only existing clients are served while draining
+                                table.lock().unwrap().get_ipv4(&source).ok_or_else(|| {
+                                    "Draining, so no new mappings are created".to_string()
+                                })?
+                            } else if prefix_tables.is_frozen() {
+                                // Mappings made now would be missing from the state handed to a new process
+                                table.lock().unwrap().get_ipv4(&source).ok_or_else(|| {
+                                    "Handing over, so no new mappings are created".to_string()
+                                })?
+                            } else if let Some(ipv4) = match &self.policy {
+                                Some(policy) => policy.get_or_assign_ipv4(table, source, prefix)?,
+                                None => None,
+                            } {
+                                ipv4
+                            } else if let Some(hook) = &self.address_hook {
+                                hook.get_or_assign_ipv4(table, source)?
+                            } else if let Some(store) = &self.lease_store {
+                                store.get_or_claim_ipv4(table, source)?.ok_or_else(|| {
+                                    lease_pending = true;
+                                    "Waiting for the lease store".to_string()
+                                })?
+                            } else {
+                                table.lock().unwrap().get_or_create_ipv4(&source).map_err(
+                                    |error| {
+                                        if matches!(
+                                            error,
+                                            fast_nat::error::Error::MappingLimitReached
+                                        ) {
+                                            record_tenant_limited(
+                                                prefix_tables,
+                                                source,
+                                                LIMIT_MAPPINGS,
+                                            );
+                                        } else {
+                                            log::error!("Error getting IPv4 address: {}", error);
+                                        }
+                                        error.to_string()
+                                    },
+                                )?
+                            };
+                            prefix_tables.record_prefix(
+                                new_source,
+                                unsafe { extract_ipv4_addr_unchecked(dest, prefix.prefix_len()) },
+                                prefix,
+                            );
+                            Ok((prefix, new_source))
+                        }) {
+                        Ok((prefix, new_source)) => {
+                            translate_ipv6_to_ipv4(packet, new_source, unsafe {
+                                extract_ipv4_addr_unchecked(dest, prefix.prefix_len())
+                            })
+                            .map_err(PacketHandlingError::from)
+                            .and_then(|mut output| {
+                                // When devices share the mapping, give the packet a port of its own
+                                if (self.port_blocks.is_some() || self.sessions.is_some())
+                                    && !self.icmp_error_sources.contains(&new_source)
+                                {
+                                    // Packets without a port (such as non-initial fragments) have nothing to
+                                    // tell the devices apart by
+                                    let Some((protocol, port)) =
+                                        get_ipv4_port(&output, Direction::Outbound)
+                                    else {
+                                        return Ok(None);
+                                    };
+                                    let new_port = match &self.port_blocks {
+                                        Some(port_blocks) => {
+                                            port_blocks
+                                                .lock()
+                                                .unwrap()
+                                                .translate_outbound(source, protocol, port)?
+                                                .1
+                                        }
+                                        None => self
+                                            .sessions
+                                            .as_ref()
+                                            .unwrap()
+                                            .lock()
+                                            .unwrap()
+                                            .translate_outbound(
+                                                source,
+                                                protocol,
+                                                port,
+                                                new_source,
+                                                &(FIRST_SHARED_PORT..=u16::MAX),
+                                            )
+                                            .inspect_err(|error| {
+                                                if matches!(
+                                                    error,
+                                                    fast_nat::error::Error::SessionLimitReached(_)
+                                                ) {
+                                                    record_tenant_limited(
+                                                        prefix_tables,
+                                                        source,
+                                                        LIMIT_SESSIONS,
+                                                    );
+                                                }
+                                            })?,
+                                    };
+                                    set_ipv4_port(&mut output, Direction::Outbound, new_port);
+                                }
+                                Ok(Some(output))
+                            })
+                        }
+                        Err(reason) => {
+                            protomask_metrics::metric!(
+                                PACKET_COUNTER,
+                                PROTOCOL_IPV6,
+                                STATUS_DROPPED
+                            );
+                            let drop_reason = if lease_pending {
+                                DropReason::LeasePending
+                            } else {
+                                DropReason::Unmapped
+                            };
+                            return Err((drop_reason, Some(reason)));
+                        }
+                    }
+                }
+                Some(proto) => {
+                    log::warn!("Unknown Layer 3 protocol: {}", proto);
+                    return Err((
+                        DropReason::UnknownProtocol,
+                        Some(format!("Unknown Layer 3 protocol: {}", proto)),
+                    ));
+                }
+                None => return Err((DropReason::UnknownProtocol, None)),
+            };
+
+        // Handle any errors
+        match translation_result {
+            Ok(Some(output)) => Ok(output),
+            Ok(None) => {
+                protomask_metrics::metric!(PACKET_COUNTER, PROTOCOL_IPV6, STATUS_DROPPED);
+                Err((
+                    DropReason::NoPort,
+                    Some("No port to share the mapping by".to_string()),
+                ))
+            }
+            Err(error) => {
+                let detail = error.to_string();
+                handle_translation_error(Err(error));
+                Err((DropReason::Untranslatable, Some(detail)))
+            }
+        }
+    }
+}
+
+impl PacketProcessor for PacketDecisions {
+    /// Decide what becomes of a packet that has passed validation, calling `stage` as each stage of handling ends
+    fn process(
+        &self,
+        packet: &mut [u8],
+        counters: &QueueCounters,
+        mut stage: impl FnMut(&'static str),
+    ) -> Verdict {
+        // Nothing is translated for groups of hosts yet
+        if self.drop_multicast {
+            match get_group_destination(packet) {
+                Some(GroupDestination::Multicast) => {
+                    return Verdict::Dropped(DropReason::Multicast, None)
+                }
+                Some(GroupDestination::Broadcast) => {
+                    return Verdict::Dropped(DropReason::Broadcast, None)
+                }
+                None => {}
+            }
+        }
+
+        // If configured, behave like a router hop
+        if let Some(translator_address) = self.translator_address {
+            let hop = hop::handle(
+                packet,
+                translator_address,
+                &self.prefix_tables,
+                &self.error_limiter,
+            );
+            stage(STAGE_HOP);
+            match hop {
+                Ok(Hop::Forward) => {}
+                Ok(Hop::Reply(reply)) => return Verdict::Reply(reply),
+                Ok(Hop::Drop) => return Verdict::Dropped(DropReason::Hop, None),
+                Ok(Hop::RateLimited) => return Verdict::Dropped(DropReason::RateLimited, None),
+                Err(error) => {
+                    handle_translation_error(Err(error.into()));
+                    return Verdict::Dropped(DropReason::Hop, None);
+                }
+            }
+        }
+
+        // If configured, let the policy script turn the packet away
+        if let Some(policy) = &self.policy {
+            if !policy.classify(packet) {
+                return Verdict::Dropped(
+                    DropReason::Policy,
+                    Some("Dropped by the policy script".to_string()),
+                );
+            }
+        }
+
+        // Translate it based on the Layer 3 protocol number
+        let translated = self.translate(packet);
+        stage(STAGE_TRANSLATE);
+        let mut output = match translated {
+            Ok(output) => output,
+            // Only IPv4 packets go without a mapping because of their destination
+            Err((DropReason::Unmapped, detail)) if get_layer_3_proto(packet) == Some(4) => {
+                return match self.reject_unmapped(packet) {
+                    Some(reply) => Verdict::Rejected(DropReason::Unmapped, detail, reply),
+                    None => Verdict::Dropped(DropReason::Unmapped, detail),
+                };
+            }
+            Err((reason, detail)) => return Verdict::Dropped(reason, detail),
+        };
+
+        // Clean up the translated packet
+        let report = sanitize_tcp_options(&mut output, self.tcp_option_policy);
+        if report.flagged > 0 {
+            log::debug!(
+                "Translated a TCP segment carrying {} flagged option(s)",
+                report.flagged
+            );
+            counters
+                .tcp_options_flagged
+                .fetch_add(report.flagged as u64, Ordering::Relaxed);
+        }
+        counters
+            .tcp_options_stripped
+            .fetch_add(report.stripped as u64, Ordering::Relaxed);
+        if !self.dscp_rules.is_empty() {
+            dscp::remark(&self.dscp_rules, packet, &mut output);
+        }
+        Verdict::Translated(output)
+    }
+}
+
+/// Get the subscriber (the first address of its prefix) that an IPv6 address belongs to
+fn subscriber_of(address: Ipv6Addr, prefix_len: u8) -> Ipv6Addr {
+    Ipv6Net::new(address, prefix_len).unwrap().network()
+}